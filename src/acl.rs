@@ -0,0 +1,192 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+
+use anyhow::Context;
+use async_trait::async_trait;
+use pingora::listeners::ConnectionFilter;
+use tracing::debug;
+
+use crate::{config::AccessControlConfig, metrics};
+
+/// An IPv4 or IPv6 network; a bare address is a single-host network.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => prefix_matches(
+                network.to_bits().into(),
+                ip.to_bits().into(),
+                32,
+                self.prefix,
+            ),
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                prefix_matches(network.to_bits(), ip.to_bits(), 128, self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(network: u128, ip: u128, bits: u8, prefix: u8) -> bool {
+    // A /0 shifts by the full width, which `checked_shr` maps to `None` on
+    // both sides.
+    let shift = u32::from(bits - prefix);
+    network.checked_shr(shift) == ip.checked_shr(shift)
+}
+
+impl FromStr for Cidr {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> anyhow::Result<Self> {
+        let (addr, prefix) = match value.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (value, None),
+        };
+        let network = addr
+            .parse::<IpAddr>()
+            .map_err(|_| anyhow::anyhow!("{value} is not an IP address or CIDR"))?
+            .to_canonical();
+        let bits = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix| *prefix <= bits)
+                .with_context(|| format!("{value} has an invalid prefix length"))?,
+            None => bits,
+        };
+        Ok(Self { network, prefix })
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AccessList {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+}
+
+impl AccessList {
+    pub fn from_config(config: &AccessControlConfig) -> anyhow::Result<Self> {
+        let parse = |field: &str, entries: &[String]| {
+            entries
+                .iter()
+                .map(|entry| {
+                    entry
+                        .parse()
+                        .with_context(|| format!("invalid {field} entry"))
+                })
+                .collect::<anyhow::Result<Vec<Cidr>>>()
+        };
+        Ok(Self {
+            allow: parse("allow", &config.allow)?,
+            deny: parse("deny", &config.deny)?,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+
+    pub fn admits(&self, ip: IpAddr) -> bool {
+        if self.deny.iter().any(|cidr| cidr.contains(ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(ip))
+    }
+}
+
+/// Drops connections of one proxy service whose peer fails any of its lists.
+#[derive(Debug)]
+pub struct ConnectionAcl {
+    app: String,
+    lists: Vec<AccessList>,
+}
+
+impl ConnectionAcl {
+    /// Returns `None` when no list restricts anything, so the listeners skip
+    /// the filter altogether.
+    pub fn new(app: Option<&str>, lists: Vec<AccessList>) -> Option<Self> {
+        let lists: Vec<_> = lists.into_iter().filter(|list| !list.is_empty()).collect();
+        (!lists.is_empty()).then(|| Self {
+            app: app.unwrap_or_default().to_string(),
+            lists,
+        })
+    }
+
+    fn admits(&self, ip: IpAddr) -> bool {
+        self.lists.iter().all(|list| list.admits(ip))
+    }
+}
+
+#[async_trait]
+impl ConnectionFilter for ConnectionAcl {
+    async fn should_accept(&self, addr: Option<&SocketAddr>) -> bool {
+        let Some(addr) = addr else {
+            return true;
+        };
+        if self.admits(addr.ip()) {
+            return true;
+        }
+        debug!(peer = %addr, "connection denied by acl");
+        metrics::inc_connection_denied(&self.app);
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn list(allow: &[&str], deny: &[&str]) -> AccessList {
+        AccessList::from_config(&AccessControlConfig {
+            allow: allow.iter().map(|entry| entry.to_string()).collect(),
+            deny: deny.iter().map(|entry| entry.to_string()).collect(),
+        })
+        .expect("valid acl")
+    }
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().expect("ip")
+    }
+
+    #[test]
+    fn parses_and_matches_networks() {
+        let net: Cidr = "10.1.0.0/16".parse().expect("cidr");
+        assert!(net.contains(ip("10.1.200.3")));
+        assert!(!net.contains(ip("10.2.0.1")));
+        assert!(net.contains(ip("::ffff:10.1.0.9")));
+        assert!(!net.contains(ip("2001:db8::1")));
+
+        let everything: Cidr = "::/0".parse().expect("cidr");
+        assert!(everything.contains(ip("2001:db8::1")));
+        let host: Cidr = "2001:db8::1".parse().expect("cidr");
+        assert!(host.contains(ip("2001:db8::1")));
+        assert!(!host.contains(ip("2001:db8::2")));
+
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("example.com".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn deny_wins_and_allow_admits_nothing_else() {
+        let open = list(&[], &["192.0.2.0/24"]);
+        assert!(!open.admits(ip("192.0.2.7")));
+        assert!(open.admits(ip("198.51.100.1")));
+
+        let closed = list(&["10.0.0.0/8"], &["10.9.0.0/16"]);
+        assert!(closed.admits(ip("10.1.2.3")));
+        assert!(!closed.admits(ip("10.9.0.1")));
+        assert!(!closed.admits(ip("198.51.100.1")));
+
+        let acl = ConnectionAcl::new(None, vec![closed, open]).expect("restricting");
+        assert!(acl.admits(ip("10.1.2.3")));
+        assert!(ConnectionAcl::new(Some("internal"), vec![AccessList::default()]).is_none());
+    }
+}
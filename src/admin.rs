@@ -1,7 +1,8 @@
 use std::{
+    collections::BTreeMap,
     fs::{self, File, OpenOptions},
     io::Write,
-    net::TcpListener,
+    net::{SocketAddr, TcpListener},
     path::{Path, PathBuf},
     sync::{Arc, Mutex},
     time::{SystemTime, UNIX_EPOCH},
@@ -13,10 +14,11 @@ use async_trait::async_trait;
 use axum::{
     Router,
     body::{self, Body},
-    extract::{Path as AxumPath, Query, State},
-    http::{HeaderValue, StatusCode, header},
+    extract::{ConnectInfo, Extension, Path as AxumPath, Query, Request, State},
+    http::{HeaderMap, HeaderValue, Method, StatusCode, header},
+    middleware::{self, Next},
     response::Response,
-    routing::get,
+    routing::{delete, get, post},
 };
 use include_dir::{Dir, include_dir};
 use pingora::services::Service;
@@ -24,18 +26,48 @@ use serde::{Deserialize, Serialize};
 use tracing::{error, info};
 
 use crate::{
-    config::{LbStrategy, PrxConfig},
-    runtime::RuntimeConfig,
+    admin_auth::{AdminAuth, tokens_match},
+    audit::{self, AuditEntry, AuditLog, AuditRecord},
+    config::{
+        AdminAccess, ConfigErrors, ConfigProblem, LbStrategy, PrxConfig, RouteConfig,
+        ValidationReport,
+    },
+    diff::ConfigDiff,
+    drain,
+    history::{self, ConfigVersion},
+    load::Load,
+    metrics,
+    purge::{CACHE_TAGS_HEADER, Purge},
+    runtime::{RuntimeConfig, UpstreamOperationalState},
+    unmatched,
 };
 
 pub const ADMIN_CONFIG_PATH: &str = "/web/config";
+pub const ADMIN_CONFIG_VALIDATE_PATH: &str = "/web/config/validate";
+pub const ADMIN_CONFIG_HISTORY_PATH: &str = "/web/config/history";
+pub const ADMIN_CONFIG_ROLLBACK_PATH: &str = "/web/config/rollback/{version}";
 pub const ADMIN_ROUTE_HEALTH_PATH: &str = "/web/health/routes";
+pub const ADMIN_STATE_EXPORT_PATH: &str = "/web/state/export";
+pub const ADMIN_STATE_IMPORT_PATH: &str = "/web/state/import";
+pub const ADMIN_CACHE_PATH: &str = "/web/cache";
+pub const ADMIN_AUDIT_PATH: &str = "/web/audit";
+pub const ADMIN_STATUS_PATH: &str = "/web/status";
+const DEFAULT_AUDIT_LIMIT: usize = 50;
+const MAX_AUDIT_LIMIT: usize = 500;
 pub const DEFAULT_ADMIN_LISTEN: &str = "127.0.0.1:9090";
 const MAX_ADMIN_CONFIG_BODY_BYTES: usize = 10 * 1024 * 1024;
 pub const ADMIN_SERVICES_PATH: &str = "/admin/services";
-pub const ADMIN_SERVICES_NAME_PATH: &str = "/admin/services/:name";
+pub const ADMIN_SERVICES_NAME_PATH: &str = "/admin/services/{name}";
 pub const ADMIN_ROUTES_PATH: &str = "/admin/routes";
-pub const ADMIN_ROUTES_NAME_PATH: &str = "/admin/routes/:name";
+pub const ADMIN_ROUTES_NAME_PATH: &str = "/admin/routes/{name}";
+pub const ADMIN_WEB_ROUTES_PATH: &str = "/web/routes";
+pub const ADMIN_WEB_ROUTES_NAME_PATH: &str = "/web/routes/{name}";
+pub const ADMIN_UPSTREAM_DRAIN_PATH: &str = "/web/routes/{name}/upstreams/{addr}/drain";
+pub const ADMIN_UPSTREAM_ENABLE_PATH: &str = "/web/routes/{name}/upstreams/{addr}/enable";
+pub const ADMIN_STATS_PATH: &str = "/admin/stats";
+pub const ADMIN_STATS_RESET_PATH: &str = "/admin/stats/reset";
+pub const ADMIN_UNMATCHED_HOSTS_PATH: &str = "/admin/unmatched-hosts";
+const DEFAULT_UNMATCHED_HOSTS_LIMIT: usize = 20;
 const WEBUI_INDEX_PATH: &str = "index.html";
 static WEBUI_DIST: Dir<'_> = include_dir!("$CARGO_MANIFEST_DIR/webui/dist");
 
@@ -90,9 +122,15 @@ impl ConfigAdmin {
             )
         })?;
 
-        match PrxConfig::from_file(&self.config_path) {
-            Ok(verified) => {
-                active_config.store(Arc::new(RuntimeConfig::from_config(verified)));
+        let applied = PrxConfig::from_file(&self.config_path).and_then(|verified| {
+            let keep = history::kept(&verified);
+            let (next, _) = active_config.load().rebuild(verified)?;
+            Ok((next, keep))
+        });
+        match applied {
+            Ok((next, keep)) => {
+                self.save_version(&previous_bytes, toml_text.as_bytes(), keep);
+                active_config.store(Arc::new(next));
                 Ok(())
             }
             Err(err) => {
@@ -110,8 +148,10 @@ impl ConfigAdmin {
                     );
                 }
 
-                if let Ok(rolled_back) = PrxConfig::from_file(&self.config_path) {
-                    active_config.store(Arc::new(RuntimeConfig::from_config(rolled_back)));
+                if let Ok((rolled_back, _)) = PrxConfig::from_file(&self.config_path)
+                    .and_then(|config| active_config.load().rebuild(config))
+                {
+                    active_config.store(Arc::new(rolled_back));
                 }
 
                 bail!("config write verification failed, rolled back previous config: {err:#}");
@@ -119,7 +159,13 @@ impl ConfigAdmin {
         }
     }
 
-    pub fn modify_config<F>(&self, active_config: &Arc<ArcSwap<RuntimeConfig>>, f: F) -> anyhow::Result<()>
+    /// Edits the config in place and applies it, answering with its warnings.
+    /// A config that does not validate fails with [`ConfigErrors`].
+    pub fn modify_config<F>(
+        &self,
+        active_config: &Arc<ArcSwap<RuntimeConfig>>,
+        f: F,
+    ) -> anyhow::Result<Vec<ConfigProblem>>
     where
         F: FnOnce(&mut PrxConfig) -> anyhow::Result<()>,
     {
@@ -128,32 +174,31 @@ impl ConfigAdmin {
             .lock()
             .map_err(|_| anyhow::anyhow!("config write lock is poisoned"))?;
 
-        let previous_bytes = fs::read(&self.config_path).with_context(|| {
-            format!(
-                "failed to read previous config at {}",
-                self.config_path.to_string_lossy()
-            )
-        })?;
-
         // Read, modify, and validate
-        let mut config = PrxConfig::from_file(&self.config_path).with_context(|| {
-            format!(
-                "failed to read config at {}",
-                self.config_path.to_string_lossy()
-            )
-        })?;
+        let previous_text = self.read_config_text()?;
+        let (mut config, included) = PrxConfig::parse_at(&previous_text, &self.config_path)
+            .with_context(|| {
+                format!(
+                    "failed to read config at {}",
+                    self.config_path.to_string_lossy()
+                )
+            })?;
 
         f(&mut config)?;
 
-        config.validate().with_context(|| {
-            format!(
-                "modified config at {} failed validation",
-                self.config_path.to_string_lossy()
-            )
-        })?;
+        let report = config.report_with(&included);
+        if !report.errors.is_empty() {
+            return Err(anyhow::Error::new(ConfigErrors(report.errors))).with_context(|| {
+                format!(
+                    "modified config at {} failed validation",
+                    self.config_path.to_string_lossy()
+                )
+            });
+        }
 
-        // Serialize to TOML
-        let toml_text = toml::to_string(&config).context("failed to serialize config to TOML")?;
+        // Serialize to TOML; included files are left as they are.
+        let toml_text = toml::to_string(&config.without_included(&included)?)
+            .context("failed to serialize config to TOML")?;
 
         // Atomically write the new config
         Self::atomic_replace(&self.config_path, toml_text.as_bytes()).with_context(|| {
@@ -163,13 +208,40 @@ impl ConfigAdmin {
             )
         })?;
 
-        // Update the active config
-        active_config.store(Arc::new(RuntimeConfig::from_config(config)));
+        // Update the active config, putting the previous file back if it
+        // cannot be applied.
+        let keep = history::kept(&config);
+        let next = match active_config.load().rebuild(config) {
+            Ok((next, _)) => next,
+            Err(err) => {
+                Self::atomic_replace(&self.config_path, previous_text.as_bytes()).with_context(
+                    || {
+                        format!(
+                            "failed to rollback config at {}",
+                            self.config_path.to_string_lossy()
+                        )
+                    },
+                )?;
+                return Err(err.context("modified config could not be applied, rolled back"));
+            }
+        };
+        self.save_version(previous_text.as_bytes(), toml_text.as_bytes(), keep);
+        active_config.store(Arc::new(next));
+
+        Ok(report.warnings)
+    }
 
-        Ok(())
+    /// Keeps the config a write replaced. The write stands if that fails.
+    fn save_version(&self, previous: &[u8], written: &[u8], keep: usize) {
+        if previous == written {
+            return;
+        }
+        if let Err(err) = history::save(&self.config_path, previous, keep) {
+            error!("failed to keep the previous config version: {err:#}");
+        }
     }
 
-    fn atomic_replace(path: &Path, bytes: &[u8]) -> anyhow::Result<()> {
+    pub(crate) fn atomic_replace(path: &Path, bytes: &[u8]) -> anyhow::Result<()> {
         let parent = path
             .parent()
             .map(Path::to_path_buf)
@@ -237,6 +309,42 @@ struct RouteHealthQuery {
     timeout_ms: Option<u64>,
 }
 
+#[derive(Debug, Default, Deserialize)]
+struct UnmatchedHostsQuery {
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct AuditQuery {
+    limit: Option<usize>,
+    /// Only entries older than this `id`.
+    before: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+struct ConfigHistoryPayload {
+    versions: Vec<ConfigVersion>,
+}
+
+#[derive(Debug, Serialize)]
+struct AuditPayload {
+    entries: Vec<AuditRecord>,
+    /// `before` for the next page; `None` on the last one.
+    next_before: Option<usize>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct CachePurgeQuery {
+    route: Option<String>,
+    path: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct CachePurgePayload {
+    route: String,
+    purged: usize,
+}
+
 #[derive(Debug, Serialize)]
 struct AdminConfigPayload {
     server: AdminServerPayload,
@@ -272,6 +380,27 @@ struct AdminObservabilityPayload {
     prometheus_listen: String,
 }
 
+#[derive(Debug, Serialize)]
+struct ConfigValidatePayload {
+    valid: bool,
+    problems: Vec<ConfigProblem>,
+    warnings: Vec<ConfigProblem>,
+    /// What applying the candidate would change, from `POST /web/config/validate`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    diff: Option<ConfigDiff>,
+}
+
+impl From<ValidationReport> for ConfigValidatePayload {
+    fn from(report: ValidationReport) -> Self {
+        Self {
+            valid: report.errors.is_empty(),
+            problems: report.errors,
+            warnings: report.warnings,
+            diff: None,
+        }
+    }
+}
+
 #[derive(Debug, Serialize)]
 struct AdminServicePayload {
     name: String,
@@ -285,9 +414,12 @@ struct AdminServicePayload {
 #[derive(Debug, Serialize)]
 struct AdminRoutePayload {
     name: String,
+    app: String,
+    tenant: String,
     service: String,
     host: String,
     path_prefix: String,
+    headers: BTreeMap<String, String>,
     methods: Vec<String>,
     is_default: bool,
 }
@@ -304,14 +436,169 @@ struct AdminUpstreamPayload {
     addr: String,
     tls: bool,
     sni: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    verify_hostname_as: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    host: Option<String>,
+    preserve_host: bool,
+    http2: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    http_version: Option<crate::config::UpstreamHttpVersion>,
     weight: u16,
     verify_cert: Option<bool>,
     verify_hostname: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client_cert_path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client_key_path: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pinned_cert_sha256: Vec<String>,
     connect_timeout_ms: Option<u64>,
     total_connect_timeout_ms: Option<u64>,
     read_timeout_ms: Option<u64>,
     write_timeout_ms: Option<u64>,
     idle_timeout_ms: Option<u64>,
+    max_requests_per_connection: Option<u64>,
+    max_connection_lifetime_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    health_check: Option<crate::config::HealthCheckConfig>,
+    #[serde(skip_serializing_if = "crate::config::EgressConfig::is_empty")]
+    egress: crate::config::EgressConfig,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    proxy_protocol: Option<crate::config::ProxyProtocolVersion>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    drain_hook: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    resolve_interval_s: Option<u64>,
+}
+
+// In-memory state of the active snapshot, for `/admin/stats`
+#[derive(Debug, Serialize)]
+struct AdminStatsPayload {
+    services: Vec<AdminServiceStatsPayload>,
+    routes: Vec<AdminRouteStatsPayload>,
+}
+
+#[derive(Debug, Serialize)]
+struct AdminServiceStatsPayload {
+    name: String,
+    round_robin_cursor: usize,
+    upstreams: Vec<AdminUpstreamStatsPayload>,
+}
+
+#[derive(Debug, Serialize)]
+struct AdminUpstreamStatsPayload {
+    addr: String,
+    healthy: bool,
+    circuit_open: bool,
+    /// Taken out of rotation through the admin API.
+    disabled: bool,
+    consecutive_failures: usize,
+    tracked_connections: usize,
+    /// Estimated connections idle in the pool.
+    idle_connections: usize,
+    /// Moving average of attempt latencies under `lb = "ewma"`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ewma_latency_us: Option<u64>,
+    /// Records `addr` resolved to under `resolve_interval_s`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    resolved_addrs: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct AdminRouteStatsPayload {
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    upload_tokens: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    download_tokens: Option<i64>,
+}
+
+// Live state of the running config, as opposed to what `Prx.toml` says
+#[derive(Debug, Serialize)]
+struct AdminStatusPayload {
+    ready: bool,
+    draining: bool,
+    routes: Vec<AdminRouteStatusPayload>,
+    services: Vec<AdminServiceStatusPayload>,
+}
+
+#[derive(Debug, Serialize)]
+struct AdminRouteStatusPayload {
+    name: String,
+    service: String,
+    in_flight: usize,
+    websockets: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct AdminServiceStatusPayload {
+    name: String,
+    upstreams: Vec<AdminUpstreamStatusPayload>,
+}
+
+#[derive(Debug, Serialize)]
+struct AdminUpstreamStatusPayload {
+    addr: String,
+    healthy: bool,
+    /// `closed`, `open` or `half_open`.
+    circuit: &'static str,
+    /// Time left until an open breaker lets probes through.
+    circuit_open_ms: u64,
+    consecutive_failures: usize,
+    disabled: bool,
+    /// Removed by a reload; requests in flight are finishing.
+    draining: bool,
+    in_flight: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_selected_epoch_ms: Option<u64>,
+}
+
+// Operational state carried across a restart or blue/green swap
+#[derive(Debug, Serialize, Deserialize)]
+struct AdminStatePayload {
+    services: Vec<AdminServiceStatePayload>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct AdminServiceStatePayload {
+    name: String,
+    upstreams: Vec<AdminUpstreamStatePayload>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct AdminUpstreamStatePayload {
+    addr: String,
+    healthy: bool,
+    #[serde(default)]
+    consecutive_failures: usize,
+    #[serde(default)]
+    circuit_open_ms: u64,
+    #[serde(default)]
+    overloaded_ms: u64,
+    #[serde(default)]
+    http1_fallback: bool,
+    #[serde(default)]
+    disabled: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct UpstreamMaintenancePayload {
+    route: String,
+    addr: String,
+    disabled: bool,
+    /// The route's services that have the upstream; every route using them is
+    /// affected.
+    services: Vec<String>,
+    /// Requests still going to the upstream.
+    in_flight: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct AdminStateImportPayload {
+    restored: usize,
+    // `service/addr` entries with no matching upstream in the active config.
+    skipped: Vec<String>,
 }
 
 // Request payloads for Service CRUD
@@ -348,12 +635,28 @@ struct UpstreamRequestPayload {
     #[serde(default)]
     pub sni: Option<String>,
     #[serde(default)]
+    pub verify_hostname_as: Option<String>,
+    #[serde(default)]
+    pub host: Option<String>,
+    #[serde(default)]
+    pub preserve_host: Option<bool>,
+    #[serde(default)]
+    pub http2: Option<bool>,
+    #[serde(default)]
+    pub http_version: Option<crate::config::UpstreamHttpVersion>,
+    #[serde(default)]
     pub weight: Option<u16>,
     #[serde(default)]
     pub verify_cert: Option<bool>,
     #[serde(default)]
     pub verify_hostname: Option<bool>,
     #[serde(default)]
+    pub client_cert_path: Option<String>,
+    #[serde(default)]
+    pub client_key_path: Option<String>,
+    #[serde(default)]
+    pub pinned_cert_sha256: Vec<String>,
+    #[serde(default)]
     pub connect_timeout_ms: Option<u64>,
     #[serde(default)]
     pub total_connect_timeout_ms: Option<u64>,
@@ -363,12 +666,30 @@ struct UpstreamRequestPayload {
     pub write_timeout_ms: Option<u64>,
     #[serde(default)]
     pub idle_timeout_ms: Option<u64>,
+    #[serde(default)]
+    pub max_requests_per_connection: Option<u64>,
+    #[serde(default)]
+    pub max_connection_lifetime_ms: Option<u64>,
+    #[serde(default)]
+    pub health_check: Option<crate::config::HealthCheckConfig>,
+    #[serde(default)]
+    pub egress: crate::config::EgressConfig,
+    #[serde(default)]
+    pub proxy_protocol: Option<crate::config::ProxyProtocolVersion>,
+    #[serde(default)]
+    pub drain_hook: Option<String>,
+    #[serde(default)]
+    pub resolve_interval_s: Option<u64>,
 }
 
 // Request payloads for Route CRUD
 #[derive(Debug, Deserialize)]
 struct RouteRequestPayload {
     pub name: String,
+    #[serde(default)]
+    pub app: Option<String>,
+    #[serde(default)]
+    pub tenant: Option<String>,
     pub service: String,
     #[serde(default)]
     pub host: Option<String>,
@@ -377,6 +698,8 @@ struct RouteRequestPayload {
     #[serde(default)]
     pub methods: Option<Vec<String>>,
     #[serde(default)]
+    pub headers: Option<BTreeMap<String, String>>,
+    #[serde(default)]
     pub is_default: Option<bool>,
 }
 
@@ -453,14 +776,29 @@ impl From<PrxConfig> for AdminConfigPayload {
                         addr: upstream.addr.clone(),
                         tls: upstream.tls,
                         sni: upstream.sni.clone().unwrap_or_default(),
+                        verify_hostname_as: upstream.verify_hostname_as.clone(),
+                        host: upstream.host.clone(),
+                        preserve_host: upstream.preserve_host,
+                        http2: upstream.http2,
+                        http_version: upstream.http_version,
                         weight: upstream.weight,
                         verify_cert: upstream.verify_cert,
                         verify_hostname: upstream.verify_hostname,
+                        client_cert_path: upstream.client_cert_path.clone(),
+                        client_key_path: upstream.client_key_path.clone(),
+                        pinned_cert_sha256: upstream.pinned_cert_sha256.clone(),
                         connect_timeout_ms: upstream.connect_timeout_ms,
                         total_connect_timeout_ms: upstream.total_connect_timeout_ms,
                         read_timeout_ms: upstream.read_timeout_ms,
                         write_timeout_ms: upstream.write_timeout_ms,
                         idle_timeout_ms: upstream.idle_timeout_ms,
+                        max_requests_per_connection: upstream.max_requests_per_connection,
+                        max_connection_lifetime_ms: upstream.max_connection_lifetime_ms,
+                        health_check: upstream.health_check.clone(),
+                        egress: upstream.egress.clone(),
+                        proxy_protocol: upstream.proxy_protocol,
+                        drain_hook: upstream.drain_hook.clone(),
+                        resolve_interval_s: upstream.resolve_interval_s,
                     })
                     .collect(),
             })
@@ -471,9 +809,12 @@ impl From<PrxConfig> for AdminConfigPayload {
             .iter()
             .map(|route| AdminRoutePayload {
                 name: route.name.clone(),
+                app: route.app.clone().unwrap_or_default(),
+                tenant: route.tenant.clone().unwrap_or_default(),
                 service: route.service.clone(),
                 host: route.host.clone().unwrap_or_default(),
                 path_prefix: route.path_prefix.clone(),
+                headers: route.headers.clone(),
                 methods: route.methods.clone(),
                 is_default: route.is_default,
             })
@@ -544,6 +885,8 @@ async fn check_upstream_health(addr: String, timeout_ms: u64) -> RouteHealthUpst
 }
 
 async fn render_route_health_payload(config: PrxConfig, timeout_ms: u64) -> RouteHealthPayload {
+    // Probe the plaintext addresses; the config was validated so decryption succeeds
+    let config = crate::secret::reveal(&config).unwrap_or(config);
     // Build a service lookup map
     let service_map: std::collections::HashMap<String, _> = config
         .services
@@ -558,8 +901,9 @@ async fn render_route_health_payload(config: PrxConfig, timeout_ms: u64) -> Rout
         if let Some(service) = service_map.get(&route.service) {
             for upstream in &service.upstreams {
                 let per_upstream_timeout_ms = health_timeout_ms(upstream.connect_timeout_ms);
-                upstream_payloads
-                    .push(check_upstream_health(upstream.addr.clone(), per_upstream_timeout_ms).await);
+                upstream_payloads.push(
+                    check_upstream_health(upstream.addr.clone(), per_upstream_timeout_ms).await,
+                );
             }
         }
 
@@ -658,6 +1002,7 @@ fn lb_to_string(lb: LbStrategy) -> &'static str {
         LbStrategy::RoundRobin => "round_robin",
         LbStrategy::Random => "random",
         LbStrategy::Hash => "hash",
+        LbStrategy::Ewma => "ewma",
     }
 }
 
@@ -777,6 +1122,7 @@ fn handle_webui_get(path: &str) -> Response<Body> {
 
 async fn get_config(
     State(state): State<AdminState>,
+    Extension(scope): Extension<AdminScope>,
     Query(query): Query<ConfigQuery>,
 ) -> Response<Body> {
     if query
@@ -793,7 +1139,12 @@ async fn get_config(
         };
     }
 
-    match state.config_admin.read_config_text() {
+    let content = if scope == AdminScope::ReadOnly {
+        redacted_config_text(&state.config_admin)
+    } else {
+        state.config_admin.read_config_text()
+    };
+    match content {
         Ok(content) => text_response(StatusCode::OK, content.into_bytes()),
         Err(err) => text_response(
             StatusCode::INTERNAL_SERVER_ERROR,
@@ -802,7 +1153,38 @@ async fn get_config(
     }
 }
 
-async fn put_config(State(state): State<AdminState>, body: Body) -> Response<Body> {
+/// `Prx.toml` for read-only credentials, re-serialized without comments:
+/// admin and tenant credentials are replaced, since they grant more. Tokens
+/// stay distinct, so the text still validates.
+fn redacted_config_text(config_admin: &ConfigAdmin) -> anyhow::Result<String> {
+    let text = config_admin.read_config_text()?;
+    let (config, included) = PrxConfig::parse_at(&text, &config_admin.config_path)?;
+    let mut config = config.without_included(&included)?;
+    for (index, tenant) in config.tenants.iter_mut().enumerate() {
+        tenant.admin_token = format!("redacted-tenant-{index}");
+    }
+    if let Some(auth) = config.admin.as_mut().and_then(|admin| admin.auth.as_mut()) {
+        for (index, token) in auth.tokens.iter_mut().enumerate() {
+            token.token = format!("redacted-token-{index}");
+        }
+        for basic in &mut auth.basic {
+            basic.password = "redacted".to_string();
+        }
+    }
+    toml::to_string(&config).context("failed to serialize config to TOML")
+}
+
+/// Applies a new `Prx.toml`. With `?format=json` the answer is the
+/// validation report of [`post_config_validate`], applied or not.
+async fn put_config(
+    State(state): State<AdminState>,
+    Query(query): Query<ConfigQuery>,
+    body: Body,
+) -> Response<Body> {
+    let json = query
+        .format
+        .as_deref()
+        .is_some_and(|value| value.eq_ignore_ascii_case("json"));
     let body = match body::to_bytes(body, MAX_ADMIN_CONFIG_BODY_BYTES).await {
         Ok(body) => body,
         Err(err) => {
@@ -829,11 +1211,40 @@ async fn put_config(State(state): State<AdminState>, body: Body) -> Response<Bod
             return text_response(StatusCode::BAD_REQUEST, b"invalid_utf8_body\n".to_vec());
         }
     };
+    apply_config_response(&state, text, json)
+}
 
-    if let Err(err) = PrxConfig::from_toml_str(text) {
+/// Validates `text` as the new `Prx.toml` and applies it, answering as
+/// [`put_config`] does.
+fn apply_config_response(state: &AdminState, text: &str, json: bool) -> Response<Body> {
+    let report = match PrxConfig::parse_at(text, &state.config_admin.config_path) {
+        Ok((config, included)) => config.report_with(&included),
+        Err(err) if json => {
+            return json_response(
+                StatusCode::BAD_REQUEST,
+                &ConfigValidatePayload::from(ValidationReport {
+                    errors: vec![load_problem(&err)],
+                    warnings: Vec::new(),
+                }),
+            );
+        }
+        Err(err) => {
+            return text_response(
+                StatusCode::BAD_REQUEST,
+                format!("invalid_config: {err:#}\n"),
+            );
+        }
+    };
+    if !report.errors.is_empty() {
+        if json {
+            return json_response(
+                StatusCode::BAD_REQUEST,
+                &ConfigValidatePayload::from(report),
+            );
+        }
         return text_response(
             StatusCode::BAD_REQUEST,
-            format!("invalid_config: {err:#}\n"),
+            format!("invalid_config: {}\n", ConfigErrors(report.errors)),
         );
     }
 
@@ -841,7 +1252,14 @@ async fn put_config(State(state): State<AdminState>, body: Body) -> Response<Bod
         .config_admin
         .apply_config_text(text, &state.active_config)
     {
-        Ok(()) => text_response(StatusCode::OK, b"config_applied\n".to_vec()),
+        Ok(()) if json => json_response(StatusCode::OK, &ConfigValidatePayload::from(report)),
+        Ok(()) => {
+            let mut body = "config_applied\n".to_string();
+            for warning in &report.warnings {
+                body.push_str(&format!("warning: {}\n", warning.message));
+            }
+            text_response(StatusCode::OK, body.into_bytes())
+        }
         Err(err) => text_response(
             StatusCode::INTERNAL_SERVER_ERROR,
             format!("failed_to_apply_config: {err:#}\n"),
@@ -849,109 +1267,49 @@ async fn put_config(State(state): State<AdminState>, body: Body) -> Response<Bod
     }
 }
 
-async fn get_webui_root() -> Response<Body> {
-    handle_webui_get("")
-}
-
-async fn get_webui_path(AxumPath(path): AxumPath<String>) -> Response<Body> {
-    handle_webui_get(path.as_str())
-}
-
-// ==================== Service CRUD Handlers ====================
-
-async fn list_services(
-    State(state): State<AdminState>,
-) -> Response<Body> {
-    match state.config_admin.read_parsed_config() {
-        Ok(config) => {
-            let services: Vec<AdminServicePayload> = config.services.iter().map(|s| {
-                AdminServicePayload {
-                    name: s.name.clone(),
-                    lb: lb_to_string(s.lb.clone()).to_string(),
-                    max_retries: s.max_retries,
-                    retry_backoff_ms: s.retry_backoff_ms,
-                    circuit_breaker: AdminCircuitBreakerPayload {
-                        enabled: s.circuit_breaker.enabled,
-                        consecutive_failures: s.circuit_breaker.consecutive_failures,
-                        open_ms: s.circuit_breaker.open_ms,
-                    },
-                    upstreams: s.upstreams.iter().map(|u| {
-                        AdminUpstreamPayload {
-                            addr: u.addr.clone(),
-                            tls: u.tls,
-                            sni: u.sni.clone().unwrap_or_default(),
-                            weight: u.weight,
-                            verify_cert: u.verify_cert,
-                            verify_hostname: u.verify_hostname,
-                            connect_timeout_ms: u.connect_timeout_ms,
-                            total_connect_timeout_ms: u.total_connect_timeout_ms,
-                            read_timeout_ms: u.read_timeout_ms,
-                            write_timeout_ms: u.write_timeout_ms,
-                            idle_timeout_ms: u.idle_timeout_ms,
-                        }
-                    }).collect(),
-                }
-            }).collect();
-            json_response(StatusCode::OK, &services)
-        }
+/// Configs the admin API replaced, newest first.
+async fn get_config_history(State(state): State<AdminState>) -> Response<Body> {
+    match history::list(&state.config_admin.config_path) {
+        Ok(versions) => json_response(StatusCode::OK, &ConfigHistoryPayload { versions }),
         Err(err) => text_response(
             StatusCode::INTERNAL_SERVER_ERROR,
-            format!("failed_to_read_config: {err:#}\n"),
+            format!("failed_to_read_config_history: {err:#}\n"),
         ),
     }
 }
 
-async fn get_service(
+/// Applies a saved version as the new `Prx.toml`; the config it replaces is
+/// kept as the next version, so a rollback can be undone the same way.
+async fn post_config_rollback(
     State(state): State<AdminState>,
-    AxumPath(name): AxumPath<String>,
+    AxumPath(version): AxumPath<u64>,
+    Query(query): Query<ConfigQuery>,
 ) -> Response<Body> {
-    match state.config_admin.read_parsed_config() {
-        Ok(config) => {
-            if let Some(service) = config.services.iter().find(|s| s.name == name) {
-                let service_payload = AdminServicePayload {
-                    name: service.name.clone(),
-                    lb: lb_to_string(service.lb.clone()).to_string(),
-                    max_retries: service.max_retries,
-                    retry_backoff_ms: service.retry_backoff_ms,
-                    circuit_breaker: AdminCircuitBreakerPayload {
-                        enabled: service.circuit_breaker.enabled,
-                        consecutive_failures: service.circuit_breaker.consecutive_failures,
-                        open_ms: service.circuit_breaker.open_ms,
-                    },
-                    upstreams: service.upstreams.iter().map(|u| {
-                        AdminUpstreamPayload {
-                            addr: u.addr.clone(),
-                            tls: u.tls,
-                            sni: u.sni.clone().unwrap_or_default(),
-                            weight: u.weight,
-                            verify_cert: u.verify_cert,
-                            verify_hostname: u.verify_hostname,
-                            connect_timeout_ms: u.connect_timeout_ms,
-                            total_connect_timeout_ms: u.total_connect_timeout_ms,
-                            read_timeout_ms: u.read_timeout_ms,
-                            write_timeout_ms: u.write_timeout_ms,
-                            idle_timeout_ms: u.idle_timeout_ms,
-                        }
-                    }).collect(),
-                };
-                json_response(StatusCode::OK, &service_payload)
-            } else {
-                text_response(StatusCode::NOT_FOUND, b"service_not_found\n".to_vec())
-            }
+    let json = query
+        .format
+        .as_deref()
+        .is_some_and(|value| value.eq_ignore_ascii_case("json"));
+    match history::read(&state.config_admin.config_path, version) {
+        Ok(Some(text)) => {
+            info!(version, "admin rolling back config");
+            apply_config_response(&state, &text, json)
         }
+        Ok(None) => text_response(
+            StatusCode::NOT_FOUND,
+            b"config_version_not_found\n".to_vec(),
+        ),
         Err(err) => text_response(
             StatusCode::INTERNAL_SERVER_ERROR,
-            format!("failed_to_read_config: {err:#}\n"),
+            format!("failed_to_read_config_version: {err:#}\n"),
         ),
     }
 }
 
-async fn create_service(
-    State(state): State<AdminState>,
-    body: Body,
-) -> Response<Body> {
-    let bytes = match body::to_bytes(body, MAX_ADMIN_CONFIG_BODY_BYTES).await {
-        Ok(bytes) => bytes,
+/// Lists every problem of a TOML config without applying it, so the WebUI
+/// can mark all of them at once.
+async fn post_config_validate(State(state): State<AdminState>, body: Body) -> Response<Body> {
+    let body = match body::to_bytes(body, MAX_ADMIN_CONFIG_BODY_BYTES).await {
+        Ok(body) => body,
         Err(err) => {
             if err.to_string().to_ascii_lowercase().contains("limit") {
                 return text_response(
@@ -966,12 +1324,600 @@ async fn create_service(
         }
     };
 
-    if bytes.is_empty() {
-        return text_response(StatusCode::BAD_REQUEST, b"request_body_is_empty\n".to_vec());
-    }
+    let Ok(text) = std::str::from_utf8(&body) else {
+        return text_response(StatusCode::BAD_REQUEST, b"invalid_utf8_body\n".to_vec());
+    };
 
-    let payload = match serde_json::from_slice::<ServiceRequestPayload>(&bytes) {
-        Ok(p) => p,
+    let (report, diff) = match PrxConfig::parse_at(text, &state.config_admin.config_path) {
+        Ok((config, included)) => {
+            // Against the file, which the running config was loaded from.
+            let diff = state
+                .config_admin
+                .read_parsed_config()
+                .ok()
+                .map(|active| ConfigDiff::between(&active, &config));
+            (config.report_with(&included), diff)
+        }
+        Err(err) => {
+            let report = ValidationReport {
+                errors: vec![load_problem(&err)],
+                warnings: Vec::new(),
+            };
+            (report, None)
+        }
+    };
+    let status = if report.errors.is_empty() {
+        StatusCode::OK
+    } else {
+        StatusCode::UNPROCESSABLE_ENTITY
+    };
+    let payload = ConfigValidatePayload {
+        diff,
+        ..ConfigValidatePayload::from(report)
+    };
+    json_response(status, &payload)
+}
+
+/// A config that does not parse, or whose included files do not load.
+fn load_problem(err: &anyhow::Error) -> ConfigProblem {
+    if err.downcast_ref::<toml::de::Error>().is_some() {
+        return ConfigProblem {
+            field: String::new(),
+            code: "syntax",
+            message: format!("{err:#}"),
+            file: None,
+        };
+    }
+    ConfigProblem {
+        field: "include".to_string(),
+        code: "invalid",
+        message: format!("{err:#}"),
+        file: None,
+    }
+}
+
+async fn get_stats(State(state): State<AdminState>) -> Response<Body> {
+    json_response(
+        StatusCode::OK,
+        &render_stats_payload(&state.active_config.load()),
+    )
+}
+
+async fn post_stats_reset(State(state): State<AdminState>) -> Response<Body> {
+    let snapshot = state.active_config.load();
+    let before = render_stats_payload(&snapshot);
+    snapshot.reset_stats();
+    unmatched::reset();
+    info!("admin reset in-memory stats");
+    json_response(StatusCode::OK, &before)
+}
+
+/// What the running config is doing: breakers, requests in flight and when
+/// each upstream was last picked.
+async fn get_status(State(state): State<AdminState>) -> Response<Body> {
+    let snapshot = state.active_config.load();
+    let services = snapshot.services();
+    let payload = AdminStatusPayload {
+        ready: snapshot.is_ready(),
+        draining: drain::is_draining(),
+        routes: snapshot
+            .routes()
+            .iter()
+            .map(|route| AdminRouteStatusPayload {
+                name: route.name.to_string(),
+                service: services
+                    .get(route.service_idx)
+                    .map(|service| service.name.clone())
+                    .unwrap_or_default(),
+                in_flight: route.in_flight(),
+                websockets: route.websockets(),
+            })
+            .collect(),
+        services: services
+            .iter()
+            .map(|service| AdminServiceStatusPayload {
+                name: service.name.clone(),
+                upstreams: service
+                    .upstreams
+                    .iter()
+                    .map(|upstream| AdminUpstreamStatusPayload {
+                        addr: upstream.addr.to_string(),
+                        healthy: upstream.health().is_healthy(),
+                        circuit: upstream.circuit_state(),
+                        circuit_open_ms: upstream.operational_state().circuit_open_ms,
+                        consecutive_failures: upstream.consecutive_failures(),
+                        disabled: upstream.is_disabled(),
+                        draining: upstream.is_draining(),
+                        in_flight: upstream.requests_in_flight(),
+                        last_selected_epoch_ms: Some(upstream.last_selected_epoch_ms())
+                            .filter(|at| *at != 0),
+                    })
+                    .collect(),
+            })
+            .collect(),
+    };
+    json_response(StatusCode::OK, &payload)
+}
+
+/// Recent `admin.audit_log` entries, newest first.
+async fn get_audit(
+    State(state): State<AdminState>,
+    Query(query): Query<AuditQuery>,
+) -> Response<Body> {
+    let Some(configured) = state
+        .active_config
+        .load()
+        .admin_audit_log()
+        .map(str::to_string)
+    else {
+        return text_response(
+            StatusCode::NOT_FOUND,
+            b"audit_log_not_configured\n".to_vec(),
+        );
+    };
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_AUDIT_LIMIT)
+        .clamp(1, MAX_AUDIT_LIMIT);
+    match AuditLog::at(&state.config_admin.config_path, &configured).page(query.before, limit) {
+        Ok(entries) => {
+            let next_before = entries
+                .last()
+                .map(|record| record.id)
+                .filter(|id| entries.len() == limit && *id > 1);
+            json_response(
+                StatusCode::OK,
+                &AuditPayload {
+                    entries,
+                    next_before,
+                },
+            )
+        }
+        Err(err) => text_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("failed_to_read_audit_log: {err:#}\n"),
+        ),
+    }
+}
+
+async fn get_unmatched_hosts(Query(query): Query<UnmatchedHostsQuery>) -> Response<Body> {
+    json_response(
+        StatusCode::OK,
+        &unmatched::top(query.limit.unwrap_or(DEFAULT_UNMATCHED_HOSTS_LIMIT)),
+    )
+}
+
+async fn get_probe_health() -> Response<Body> {
+    text_response(StatusCode::OK, b"ok\n".to_vec())
+}
+
+async fn get_probe_ready(State(state): State<AdminState>) -> Response<Body> {
+    if drain::is_draining() {
+        text_response(StatusCode::SERVICE_UNAVAILABLE, b"draining\n".to_vec())
+    } else if state.active_config.load().is_ready() {
+        text_response(StatusCode::OK, b"ready\n".to_vec())
+    } else {
+        text_response(StatusCode::SERVICE_UNAVAILABLE, b"not_ready\n".to_vec())
+    }
+}
+
+async fn get_probe_load(State(state): State<AdminState>) -> Response<Body> {
+    let in_flight = metrics::in_flight().max(0) as u64;
+    json_response(
+        StatusCode::OK,
+        &Load::measure(&state.active_config.load(), in_flight),
+    )
+}
+
+async fn delete_cache(
+    State(state): State<AdminState>,
+    Query(query): Query<CachePurgeQuery>,
+    headers: HeaderMap,
+) -> Response<Body> {
+    let Some(route) = query.route else {
+        return text_response(StatusCode::BAD_REQUEST, b"missing_route\n".to_vec());
+    };
+    if query
+        .path
+        .as_deref()
+        .is_some_and(|path| !path.starts_with('/'))
+    {
+        return text_response(StatusCode::BAD_REQUEST, b"invalid_path\n".to_vec());
+    }
+    let tags = headers
+        .get(CACHE_TAGS_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    let purge = Purge::new(query.path.as_deref(), tags);
+
+    // Requests still served by the previous snapshot of a rollout or shadow
+    // evaluation use its caches when the route changed.
+    let snapshot = state.active_config.load_full();
+    let previous = snapshot
+        .rollout()
+        .map(|rollout| rollout.stable().clone())
+        .or_else(|| snapshot.shadow().map(|shadow| shadow.stable().clone()));
+    let Some(mut purged) = snapshot.purge_route_caches(&route, &purge) else {
+        return text_response(StatusCode::NOT_FOUND, b"route_not_found\n".to_vec());
+    };
+    purged += previous
+        .and_then(|previous| previous.purge_route_caches(&route, &purge))
+        .unwrap_or(0);
+    info!(route = %route, path = ?query.path, tags, purged, "admin purged cached responses");
+    json_response(StatusCode::OK, &CachePurgePayload { route, purged })
+}
+
+async fn get_state_export(State(state): State<AdminState>) -> Response<Body> {
+    let snapshot = state.active_config.load();
+    let payload = AdminStatePayload {
+        services: snapshot
+            .services()
+            .iter()
+            .map(|service| AdminServiceStatePayload {
+                name: service.name.clone(),
+                upstreams: service
+                    .upstreams
+                    .iter()
+                    .map(|upstream| {
+                        let exported = upstream.operational_state();
+                        AdminUpstreamStatePayload {
+                            addr: upstream.addr.to_string(),
+                            healthy: exported.healthy,
+                            consecutive_failures: exported.consecutive_failures,
+                            circuit_open_ms: exported.circuit_open_ms,
+                            overloaded_ms: exported.overloaded_ms,
+                            http1_fallback: exported.http1_fallback,
+                            disabled: exported.disabled,
+                        }
+                    })
+                    .collect(),
+            })
+            .collect(),
+    };
+    json_response(StatusCode::OK, &payload)
+}
+
+async fn post_state_import(State(state): State<AdminState>, body: Body) -> Response<Body> {
+    let bytes = match body::to_bytes(body, MAX_ADMIN_CONFIG_BODY_BYTES).await {
+        Ok(bytes) => bytes,
+        Err(err) => {
+            if err.to_string().to_ascii_lowercase().contains("limit") {
+                return text_response(
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    b"request_body_too_large\n".to_vec(),
+                );
+            }
+            return text_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("failed_to_read_request_body: {err:#}\n"),
+            );
+        }
+    };
+    let payload = match serde_json::from_slice::<AdminStatePayload>(&bytes) {
+        Ok(payload) => payload,
+        Err(err) => {
+            return text_response(
+                StatusCode::BAD_REQUEST,
+                format!("invalid_request_body: {err:#}\n"),
+            );
+        }
+    };
+
+    let snapshot = state.active_config.load();
+    let mut result = AdminStateImportPayload {
+        restored: 0,
+        skipped: Vec::new(),
+    };
+    for imported in payload.services {
+        let service = snapshot
+            .services()
+            .iter()
+            .find(|service| service.name == imported.name);
+        for entry in imported.upstreams {
+            let Some(upstream) = service.and_then(|service| {
+                service
+                    .upstreams
+                    .iter()
+                    .find(|upstream| *upstream.addr == entry.addr)
+            }) else {
+                result
+                    .skipped
+                    .push(format!("{}/{}", imported.name, entry.addr));
+                continue;
+            };
+            upstream.restore_operational_state(UpstreamOperationalState {
+                healthy: entry.healthy,
+                consecutive_failures: entry.consecutive_failures,
+                circuit_open_ms: entry.circuit_open_ms,
+                overloaded_ms: entry.overloaded_ms,
+                http1_fallback: entry.http1_fallback,
+                disabled: entry.disabled,
+            });
+            result.restored += 1;
+        }
+    }
+    info!(
+        restored = result.restored,
+        skipped = result.skipped.len(),
+        "admin imported upstream state"
+    );
+    json_response(StatusCode::OK, &result)
+}
+
+async fn post_upstream_drain(
+    State(state): State<AdminState>,
+    AxumPath((name, addr)): AxumPath<(String, String)>,
+) -> Response<Body> {
+    set_upstream_disabled(&state, &name, &addr, true)
+}
+
+async fn post_upstream_enable(
+    State(state): State<AdminState>,
+    AxumPath((name, addr)): AxumPath<(String, String)>,
+) -> Response<Body> {
+    set_upstream_disabled(&state, &name, &addr, false)
+}
+
+/// Takes `addr` out of rotation in the services of route `name`, or puts it
+/// back. The flag lives in the runtime only and survives reloads that keep
+/// the upstream.
+fn set_upstream_disabled(
+    state: &AdminState,
+    name: &str,
+    addr: &str,
+    disabled: bool,
+) -> Response<Body> {
+    let snapshot = state.active_config.load();
+    let Some(route) = snapshot.routes().iter().find(|route| *route.name == *name) else {
+        return text_response(StatusCode::NOT_FOUND, b"route_not_found\n".to_vec());
+    };
+    let mut service_indices: Vec<usize> = std::iter::once(route.service_idx)
+        .chain(route.groups.iter().map(|group| group.service_idx))
+        .collect();
+    service_indices.sort_unstable();
+    service_indices.dedup();
+
+    let mut payload = UpstreamMaintenancePayload {
+        route: name.to_string(),
+        addr: addr.to_string(),
+        disabled,
+        services: Vec::new(),
+        in_flight: 0,
+    };
+    for service in service_indices
+        .into_iter()
+        .filter_map(|idx| snapshot.service(idx))
+    {
+        for upstream in service
+            .upstreams
+            .iter()
+            .filter(|upstream| *upstream.addr == *addr)
+        {
+            if upstream.set_disabled(disabled) {
+                info!(
+                    route = name,
+                    service = %service.name,
+                    upstream = addr,
+                    disabled,
+                    "admin changed upstream rotation"
+                );
+            }
+            payload.in_flight += upstream.requests_in_flight();
+            if !payload.services.contains(&service.name) {
+                payload.services.push(service.name.clone());
+            }
+        }
+    }
+    if payload.services.is_empty() {
+        return text_response(StatusCode::NOT_FOUND, b"upstream_not_found\n".to_vec());
+    }
+    json_response(StatusCode::OK, &payload)
+}
+
+fn render_stats_payload(snapshot: &RuntimeConfig) -> AdminStatsPayload {
+    AdminStatsPayload {
+        services: snapshot
+            .services()
+            .iter()
+            .map(|service| AdminServiceStatsPayload {
+                name: service.name.clone(),
+                round_robin_cursor: service.round_robin_cursor(),
+                upstreams: service
+                    .upstreams
+                    .iter()
+                    .map(|upstream| AdminUpstreamStatsPayload {
+                        addr: upstream.addr.to_string(),
+                        healthy: upstream.health().is_healthy(),
+                        circuit_open: upstream.is_circuit_open(),
+                        disabled: upstream.is_disabled(),
+                        consecutive_failures: upstream.consecutive_failures(),
+                        tracked_connections: upstream.tracked_connections(),
+                        idle_connections: upstream.idle_connections(),
+                        ewma_latency_us: Some(upstream.ewma_latency_us())
+                            .filter(|latency| *latency != 0),
+                        resolved_addrs: upstream
+                            .resolved_addrs()
+                            .iter()
+                            .map(ToString::to_string)
+                            .collect(),
+                    })
+                    .collect(),
+            })
+            .collect(),
+        routes: snapshot
+            .routes()
+            .iter()
+            .map(|route| AdminRouteStatsPayload {
+                name: route.name.to_string(),
+                upload_tokens: route
+                    .bandwidth
+                    .as_ref()
+                    .and_then(|bandwidth| bandwidth.upload())
+                    .map(|bucket| bucket.available()),
+                download_tokens: route
+                    .bandwidth
+                    .as_ref()
+                    .and_then(|bandwidth| bandwidth.download())
+                    .map(|bucket| bucket.available()),
+            })
+            .collect(),
+    }
+}
+
+async fn get_webui_root() -> Response<Body> {
+    handle_webui_get("")
+}
+
+async fn get_webui_path(AxumPath(path): AxumPath<String>) -> Response<Body> {
+    handle_webui_get(path.as_str())
+}
+
+// ==================== Service CRUD Handlers ====================
+
+async fn list_services(State(state): State<AdminState>) -> Response<Body> {
+    match state.config_admin.read_parsed_config() {
+        Ok(config) => {
+            let services: Vec<AdminServicePayload> = config
+                .services
+                .iter()
+                .map(|s| AdminServicePayload {
+                    name: s.name.clone(),
+                    lb: lb_to_string(s.lb.clone()).to_string(),
+                    max_retries: s.max_retries,
+                    retry_backoff_ms: s.retry_backoff_ms,
+                    circuit_breaker: AdminCircuitBreakerPayload {
+                        enabled: s.circuit_breaker.enabled,
+                        consecutive_failures: s.circuit_breaker.consecutive_failures,
+                        open_ms: s.circuit_breaker.open_ms,
+                    },
+                    upstreams: s
+                        .upstreams
+                        .iter()
+                        .map(|u| AdminUpstreamPayload {
+                            addr: u.addr.clone(),
+                            tls: u.tls,
+                            sni: u.sni.clone().unwrap_or_default(),
+                            verify_hostname_as: u.verify_hostname_as.clone(),
+                            host: u.host.clone(),
+                            preserve_host: u.preserve_host,
+                            http2: u.http2,
+                            http_version: u.http_version,
+                            weight: u.weight,
+                            verify_cert: u.verify_cert,
+                            verify_hostname: u.verify_hostname,
+                            client_cert_path: u.client_cert_path.clone(),
+                            client_key_path: u.client_key_path.clone(),
+                            pinned_cert_sha256: u.pinned_cert_sha256.clone(),
+                            connect_timeout_ms: u.connect_timeout_ms,
+                            total_connect_timeout_ms: u.total_connect_timeout_ms,
+                            read_timeout_ms: u.read_timeout_ms,
+                            write_timeout_ms: u.write_timeout_ms,
+                            idle_timeout_ms: u.idle_timeout_ms,
+                            max_requests_per_connection: u.max_requests_per_connection,
+                            max_connection_lifetime_ms: u.max_connection_lifetime_ms,
+                            health_check: u.health_check.clone(),
+                            egress: u.egress.clone(),
+                            proxy_protocol: u.proxy_protocol,
+                            drain_hook: u.drain_hook.clone(),
+                            resolve_interval_s: u.resolve_interval_s,
+                        })
+                        .collect(),
+                })
+                .collect();
+            json_response(StatusCode::OK, &services)
+        }
+        Err(err) => text_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("failed_to_read_config: {err:#}\n"),
+        ),
+    }
+}
+
+async fn get_service(
+    State(state): State<AdminState>,
+    AxumPath(name): AxumPath<String>,
+) -> Response<Body> {
+    match state.config_admin.read_parsed_config() {
+        Ok(config) => {
+            if let Some(service) = config.services.iter().find(|s| s.name == name) {
+                let service_payload = AdminServicePayload {
+                    name: service.name.clone(),
+                    lb: lb_to_string(service.lb.clone()).to_string(),
+                    max_retries: service.max_retries,
+                    retry_backoff_ms: service.retry_backoff_ms,
+                    circuit_breaker: AdminCircuitBreakerPayload {
+                        enabled: service.circuit_breaker.enabled,
+                        consecutive_failures: service.circuit_breaker.consecutive_failures,
+                        open_ms: service.circuit_breaker.open_ms,
+                    },
+                    upstreams: service
+                        .upstreams
+                        .iter()
+                        .map(|u| AdminUpstreamPayload {
+                            addr: u.addr.clone(),
+                            tls: u.tls,
+                            sni: u.sni.clone().unwrap_or_default(),
+                            verify_hostname_as: u.verify_hostname_as.clone(),
+                            host: u.host.clone(),
+                            preserve_host: u.preserve_host,
+                            http2: u.http2,
+                            http_version: u.http_version,
+                            weight: u.weight,
+                            verify_cert: u.verify_cert,
+                            verify_hostname: u.verify_hostname,
+                            client_cert_path: u.client_cert_path.clone(),
+                            client_key_path: u.client_key_path.clone(),
+                            pinned_cert_sha256: u.pinned_cert_sha256.clone(),
+                            connect_timeout_ms: u.connect_timeout_ms,
+                            total_connect_timeout_ms: u.total_connect_timeout_ms,
+                            read_timeout_ms: u.read_timeout_ms,
+                            write_timeout_ms: u.write_timeout_ms,
+                            idle_timeout_ms: u.idle_timeout_ms,
+                            max_requests_per_connection: u.max_requests_per_connection,
+                            max_connection_lifetime_ms: u.max_connection_lifetime_ms,
+                            health_check: u.health_check.clone(),
+                            egress: u.egress.clone(),
+                            proxy_protocol: u.proxy_protocol,
+                            drain_hook: u.drain_hook.clone(),
+                            resolve_interval_s: u.resolve_interval_s,
+                        })
+                        .collect(),
+                };
+                json_response(StatusCode::OK, &service_payload)
+            } else {
+                text_response(StatusCode::NOT_FOUND, b"service_not_found\n".to_vec())
+            }
+        }
+        Err(err) => text_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("failed_to_read_config: {err:#}\n"),
+        ),
+    }
+}
+
+async fn create_service(State(state): State<AdminState>, body: Body) -> Response<Body> {
+    let bytes = match body::to_bytes(body, MAX_ADMIN_CONFIG_BODY_BYTES).await {
+        Ok(bytes) => bytes,
+        Err(err) => {
+            if err.to_string().to_ascii_lowercase().contains("limit") {
+                return text_response(
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    b"request_body_too_large\n".to_vec(),
+                );
+            }
+            return text_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("failed_to_read_request_body: {err:#}\n"),
+            );
+        }
+    };
+
+    if bytes.is_empty() {
+        return text_response(StatusCode::BAD_REQUEST, b"request_body_is_empty\n".to_vec());
+    }
+
+    let payload = match serde_json::from_slice::<ServiceRequestPayload>(&bytes) {
+        Ok(p) => p,
         Err(err) => {
             return text_response(
                 StatusCode::BAD_REQUEST,
@@ -981,7 +1927,10 @@ async fn create_service(
     };
 
     if payload.name.is_empty() {
-        return text_response(StatusCode::BAD_REQUEST, b"service_name_cannot_be_empty\n".to_vec());
+        return text_response(
+            StatusCode::BAD_REQUEST,
+            b"service_name_cannot_be_empty\n".to_vec(),
+        );
     }
 
     if payload.upstreams.is_empty() {
@@ -1002,58 +1951,93 @@ async fn create_service(
     }
 
     // Validate circuit breaker if provided
-    if let Some(cb) = &payload.circuit_breaker {
-        if cb.enabled.unwrap_or(false) {
-            if cb.consecutive_failures.unwrap_or(0) == 0 {
-                return text_response(
-                    StatusCode::BAD_REQUEST,
-                    b"circuit_breaker_consecutive_failures_must_be_gt_0_when_enabled\n".to_vec(),
-                );
-            }
-            if cb.open_ms.unwrap_or(0) == 0 {
-                return text_response(
-                    StatusCode::BAD_REQUEST,
-                    b"circuit_breaker_open_ms_must_be_gt_0_when_enabled\n".to_vec(),
-                );
-            }
+    if let Some(cb) = &payload.circuit_breaker
+        && cb.enabled.unwrap_or(false)
+    {
+        if cb.consecutive_failures.unwrap_or(0) == 0 {
+            return text_response(
+                StatusCode::BAD_REQUEST,
+                b"circuit_breaker_consecutive_failures_must_be_gt_0_when_enabled\n".to_vec(),
+            );
+        }
+        if cb.open_ms.unwrap_or(0) == 0 {
+            return text_response(
+                StatusCode::BAD_REQUEST,
+                b"circuit_breaker_open_ms_must_be_gt_0_when_enabled\n".to_vec(),
+            );
         }
     }
 
-    match state.config_admin.modify_config(&state.active_config, |config| {
-        // Check for duplicate service name
-        if config.services.iter().any(|s| s.name == payload.name) {
-            return Err(anyhow::anyhow!("service '{}' already exists", payload.name));
-        }
-
-        let service = crate::config::ServiceConfig {
-            name: payload.name.clone(),
-            lb: payload.lb.as_deref().map(|s| s.parse().unwrap_or_default())
-                .unwrap_or_default(),
-            max_retries: payload.max_retries.unwrap_or(0),
-            retry_backoff_ms: payload.retry_backoff_ms.unwrap_or(0),
-            circuit_breaker: payload.circuit_breaker.map(|cb| crate::config::CircuitBreakerConfig {
-                enabled: cb.enabled.unwrap_or(false),
-                consecutive_failures: cb.consecutive_failures.unwrap_or_default(),
-                open_ms: cb.open_ms.unwrap_or_default(),
-            }).unwrap_or_default(),
-            upstreams: payload.upstreams.into_iter().map(|u| crate::config::UpstreamConfig {
-                addr: u.addr,
-                tls: u.tls.unwrap_or(false),
-                sni: u.sni,
-                weight: u.weight.unwrap_or(1),
-                verify_cert: u.verify_cert,
-                verify_hostname: u.verify_hostname,
-                connect_timeout_ms: u.connect_timeout_ms,
-                total_connect_timeout_ms: u.total_connect_timeout_ms,
-                read_timeout_ms: u.read_timeout_ms,
-                write_timeout_ms: u.write_timeout_ms,
-                idle_timeout_ms: u.idle_timeout_ms,
-            }).collect(),
-        };
+    match state
+        .config_admin
+        .modify_config(&state.active_config, |config| {
+            // Check for duplicate service name
+            if config.services.iter().any(|s| s.name == payload.name) {
+                return Err(anyhow::anyhow!("service '{}' already exists", payload.name));
+            }
 
-        config.services.push(service);
-        Ok(())
-    }) {
+            let service = crate::config::ServiceConfig {
+                name: payload.name.clone(),
+                lb: payload
+                    .lb
+                    .as_deref()
+                    .map(|s| s.parse().unwrap_or_default())
+                    .unwrap_or_default(),
+                max_retries: payload.max_retries.unwrap_or(0),
+                retry_backoff_ms: payload.retry_backoff_ms.unwrap_or(0),
+                retry_backoff: None,
+                retry_on: Vec::new(),
+                retry_methods: Vec::new(),
+                outlier_detection: None,
+                slow_start_s: None,
+                discover: None,
+                circuit_breaker: payload
+                    .circuit_breaker
+                    .map(|cb| crate::config::CircuitBreakerConfig {
+                        enabled: cb.enabled.unwrap_or(false),
+                        consecutive_failures: cb.consecutive_failures.unwrap_or_default(),
+                        open_ms: cb.open_ms.unwrap_or_default(),
+                        ..Default::default()
+                    })
+                    .unwrap_or_default(),
+                upstreams: payload
+                    .upstreams
+                    .into_iter()
+                    .map(|u| crate::config::UpstreamConfig {
+                        addr: u.addr,
+                        tls: u.tls.unwrap_or(false),
+                        sni: u.sni,
+                        verify_hostname_as: u.verify_hostname_as,
+                        host: u.host,
+                        preserve_host: u.preserve_host.unwrap_or(false),
+                        http2: u.http2.unwrap_or(false),
+                        http_version: u.http_version,
+                        weight: u.weight.unwrap_or(1),
+                        verify_cert: u.verify_cert,
+                        verify_hostname: u.verify_hostname,
+                        client_cert_path: u.client_cert_path,
+                        client_key_path: u.client_key_path,
+                        pinned_cert_sha256: u.pinned_cert_sha256,
+                        connect_timeout_ms: u.connect_timeout_ms,
+                        total_connect_timeout_ms: u.total_connect_timeout_ms,
+                        read_timeout_ms: u.read_timeout_ms,
+                        write_timeout_ms: u.write_timeout_ms,
+                        idle_timeout_ms: u.idle_timeout_ms,
+                        max_requests_per_connection: u.max_requests_per_connection,
+                        max_connection_lifetime_ms: u.max_connection_lifetime_ms,
+                        health_check: u.health_check,
+                        egress: u.egress,
+                        proxy_protocol: u.proxy_protocol,
+                        drain_hook: u.drain_hook,
+                        resolve_interval_s: u.resolve_interval_s,
+                        tier: 0,
+                    })
+                    .collect(),
+            };
+
+            config.services.push(service);
+            Ok(())
+        }) {
         Ok(_) => text_response(StatusCode::CREATED, b"service_created\n".to_vec()),
         Err(err) => {
             if err.to_string().contains("already exists") {
@@ -1125,56 +2109,104 @@ async fn update_service(
     }
 
     // Validate circuit breaker if provided
-    if let Some(cb) = &payload.circuit_breaker {
-        if cb.enabled.unwrap_or(false) {
-            if cb.consecutive_failures.unwrap_or(0) == 0 {
-                return text_response(
-                    StatusCode::BAD_REQUEST,
-                    b"circuit_breaker_consecutive_failures_must_be_gt_0_when_enabled\n".to_vec(),
-                );
-            }
-            if cb.open_ms.unwrap_or(0) == 0 {
-                return text_response(
-                    StatusCode::BAD_REQUEST,
-                    b"circuit_breaker_open_ms_must_be_gt_0_when_enabled\n".to_vec(),
-                );
-            }
+    if let Some(cb) = &payload.circuit_breaker
+        && cb.enabled.unwrap_or(false)
+    {
+        if cb.consecutive_failures.unwrap_or(0) == 0 {
+            return text_response(
+                StatusCode::BAD_REQUEST,
+                b"circuit_breaker_consecutive_failures_must_be_gt_0_when_enabled\n".to_vec(),
+            );
+        }
+        if cb.open_ms.unwrap_or(0) == 0 {
+            return text_response(
+                StatusCode::BAD_REQUEST,
+                b"circuit_breaker_open_ms_must_be_gt_0_when_enabled\n".to_vec(),
+            );
         }
     }
 
-    match state.config_admin.modify_config(&state.active_config, |config| {
-        let index = config.services.iter().position(|s| s.name == name)
-            .ok_or_else(|| anyhow::anyhow!("service '{}' not found", name))?;
-
-        let service = crate::config::ServiceConfig {
-            name: payload.name.clone(),
-            lb: payload.lb.as_deref().map(|s| s.parse().unwrap_or_default())
-                .unwrap_or_else(|| config.services[index].lb.clone()),
-            max_retries: payload.max_retries.unwrap_or(config.services[index].max_retries),
-            retry_backoff_ms: payload.retry_backoff_ms.unwrap_or(config.services[index].retry_backoff_ms),
-            circuit_breaker: payload.circuit_breaker.map(|cb| crate::config::CircuitBreakerConfig {
-                enabled: cb.enabled.unwrap_or(config.services[index].circuit_breaker.enabled),
-                consecutive_failures: cb.consecutive_failures.unwrap_or(config.services[index].circuit_breaker.consecutive_failures),
-                open_ms: cb.open_ms.unwrap_or(config.services[index].circuit_breaker.open_ms),
-            }).unwrap_or_else(|| config.services[index].circuit_breaker.clone()),
-            upstreams: payload.upstreams.into_iter().map(|u| crate::config::UpstreamConfig {
-                addr: u.addr,
-                tls: u.tls.unwrap_or(false),
-                sni: u.sni,
-                weight: u.weight.unwrap_or(1),
-                verify_cert: u.verify_cert,
-                verify_hostname: u.verify_hostname,
-                connect_timeout_ms: u.connect_timeout_ms,
-                total_connect_timeout_ms: u.total_connect_timeout_ms,
-                read_timeout_ms: u.read_timeout_ms,
-                write_timeout_ms: u.write_timeout_ms,
-                idle_timeout_ms: u.idle_timeout_ms,
-            }).collect(),
-        };
+    match state
+        .config_admin
+        .modify_config(&state.active_config, |config| {
+            let index = config
+                .services
+                .iter()
+                .position(|s| s.name == name)
+                .ok_or_else(|| anyhow::anyhow!("service '{}' not found", name))?;
+
+            let service = crate::config::ServiceConfig {
+                name: payload.name.clone(),
+                lb: payload
+                    .lb
+                    .as_deref()
+                    .map(|s| s.parse().unwrap_or_default())
+                    .unwrap_or_else(|| config.services[index].lb.clone()),
+                max_retries: payload
+                    .max_retries
+                    .unwrap_or(config.services[index].max_retries),
+                retry_backoff_ms: payload
+                    .retry_backoff_ms
+                    .unwrap_or(config.services[index].retry_backoff_ms),
+                retry_backoff: config.services[index].retry_backoff.clone(),
+                retry_on: config.services[index].retry_on.clone(),
+                retry_methods: config.services[index].retry_methods.clone(),
+                outlier_detection: config.services[index].outlier_detection.clone(),
+                slow_start_s: config.services[index].slow_start_s,
+                discover: config.services[index].discover.clone(),
+                circuit_breaker: payload
+                    .circuit_breaker
+                    .map(|cb| crate::config::CircuitBreakerConfig {
+                        enabled: cb
+                            .enabled
+                            .unwrap_or(config.services[index].circuit_breaker.enabled),
+                        consecutive_failures: cb
+                            .consecutive_failures
+                            .unwrap_or(config.services[index].circuit_breaker.consecutive_failures),
+                        open_ms: cb
+                            .open_ms
+                            .unwrap_or(config.services[index].circuit_breaker.open_ms),
+                        ..config.services[index].circuit_breaker.clone()
+                    })
+                    .unwrap_or_else(|| config.services[index].circuit_breaker.clone()),
+                upstreams: payload
+                    .upstreams
+                    .into_iter()
+                    .map(|u| crate::config::UpstreamConfig {
+                        addr: u.addr,
+                        tls: u.tls.unwrap_or(false),
+                        sni: u.sni,
+                        verify_hostname_as: u.verify_hostname_as,
+                        host: u.host,
+                        preserve_host: u.preserve_host.unwrap_or(false),
+                        http2: u.http2.unwrap_or(false),
+                        http_version: u.http_version,
+                        weight: u.weight.unwrap_or(1),
+                        verify_cert: u.verify_cert,
+                        verify_hostname: u.verify_hostname,
+                        client_cert_path: u.client_cert_path,
+                        client_key_path: u.client_key_path,
+                        pinned_cert_sha256: u.pinned_cert_sha256,
+                        connect_timeout_ms: u.connect_timeout_ms,
+                        total_connect_timeout_ms: u.total_connect_timeout_ms,
+                        read_timeout_ms: u.read_timeout_ms,
+                        write_timeout_ms: u.write_timeout_ms,
+                        idle_timeout_ms: u.idle_timeout_ms,
+                        max_requests_per_connection: u.max_requests_per_connection,
+                        max_connection_lifetime_ms: u.max_connection_lifetime_ms,
+                        health_check: u.health_check,
+                        egress: u.egress,
+                        proxy_protocol: u.proxy_protocol,
+                        drain_hook: u.drain_hook,
+                        resolve_interval_s: u.resolve_interval_s,
+                        tier: 0,
+                    })
+                    .collect(),
+            };
 
-        config.services[index] = service;
-        Ok(())
-    }) {
+            config.services[index] = service;
+            Ok(())
+        }) {
         Ok(_) => text_response(StatusCode::OK, b"service_updated\n".to_vec()),
         Err(err) => {
             if err.to_string().contains("not found") {
@@ -1190,22 +2222,30 @@ async fn delete_service(
     State(state): State<AdminState>,
     AxumPath(name): AxumPath<String>,
 ) -> Response<Body> {
-    match state.config_admin.modify_config(&state.active_config, |config| {
-        let index = config.services.iter().position(|s| s.name == name)
-            .ok_or_else(|| anyhow::anyhow!("service '{}' not found", name))?;
-
-        // Check if any routes reference this service
-        let referenced = config.routes.iter().any(|r| r.service == name);
-        if referenced {
-            return Err(anyhow::anyhow!(
-                "service '{}' is referenced by one or more routes",
-                name
-            ));
-        }
+    match state
+        .config_admin
+        .modify_config(&state.active_config, |config| {
+            let index = config
+                .services
+                .iter()
+                .position(|s| s.name == name)
+                .ok_or_else(|| anyhow::anyhow!("service '{}' not found", name))?;
+
+            // Check if any routes reference this service
+            let referenced = config
+                .routes
+                .iter()
+                .any(|r| r.service == name || r.groups.iter().any(|group| group.service == name));
+            if referenced {
+                return Err(anyhow::anyhow!(
+                    "service '{}' is referenced by one or more routes",
+                    name
+                ));
+            }
 
-        config.services.remove(index);
-        Ok(())
-    }) {
+            config.services.remove(index);
+            Ok(())
+        }) {
         Ok(_) => text_response(StatusCode::OK, b"service_deleted\n".to_vec()),
         Err(err) => {
             if err.to_string().contains("not found") {
@@ -1223,19 +2263,26 @@ async fn delete_service(
 
 async fn list_routes(
     State(state): State<AdminState>,
+    Extension(scope): Extension<AdminScope>,
 ) -> Response<Body> {
     match state.config_admin.read_parsed_config() {
         Ok(config) => {
-            let routes: Vec<AdminRoutePayload> = config.routes.iter().map(|r| {
-                AdminRoutePayload {
+            let routes: Vec<AdminRoutePayload> = config
+                .routes
+                .iter()
+                .filter(|r| scope.allows(r))
+                .map(|r| AdminRoutePayload {
                     name: r.name.clone(),
+                    app: r.app.clone().unwrap_or_default(),
+                    tenant: r.tenant.clone().unwrap_or_default(),
                     service: r.service.clone(),
                     host: r.host.clone().unwrap_or_default(),
                     path_prefix: r.path_prefix.clone(),
+                    headers: r.headers.clone(),
                     methods: r.methods.clone(),
                     is_default: r.is_default,
-                }
-            }).collect();
+                })
+                .collect();
             json_response(StatusCode::OK, &routes)
         }
         Err(err) => text_response(
@@ -1247,16 +2294,24 @@ async fn list_routes(
 
 async fn get_route(
     State(state): State<AdminState>,
+    Extension(scope): Extension<AdminScope>,
     AxumPath(name): AxumPath<String>,
 ) -> Response<Body> {
     match state.config_admin.read_parsed_config() {
         Ok(config) => {
-            if let Some(route) = config.routes.iter().find(|r| r.name == name) {
+            if let Some(route) = config
+                .routes
+                .iter()
+                .find(|r| r.name == name && scope.allows(r))
+            {
                 let route_payload = AdminRoutePayload {
                     name: route.name.clone(),
+                    app: route.app.clone().unwrap_or_default(),
+                    tenant: route.tenant.clone().unwrap_or_default(),
                     service: route.service.clone(),
                     host: route.host.clone().unwrap_or_default(),
                     path_prefix: route.path_prefix.clone(),
+                    headers: route.headers.clone(),
                     methods: route.methods.clone(),
                     is_default: route.is_default,
                 };
@@ -1274,6 +2329,7 @@ async fn get_route(
 
 async fn create_route(
     State(state): State<AdminState>,
+    Extension(scope): Extension<AdminScope>,
     body: Body,
 ) -> Response<Body> {
     let bytes = match body::to_bytes(body, MAX_ADMIN_CONFIG_BODY_BYTES).await {
@@ -1307,11 +2363,17 @@ async fn create_route(
     };
 
     if payload.name.is_empty() {
-        return text_response(StatusCode::BAD_REQUEST, b"route_name_cannot_be_empty\n".to_vec());
+        return text_response(
+            StatusCode::BAD_REQUEST,
+            b"route_name_cannot_be_empty\n".to_vec(),
+        );
     }
 
     if payload.service.is_empty() {
-        return text_response(StatusCode::BAD_REQUEST, b"service_cannot_be_empty\n".to_vec());
+        return text_response(
+            StatusCode::BAD_REQUEST,
+            b"service_cannot_be_empty\n".to_vec(),
+        );
     }
 
     let path_prefix = payload.path_prefix.as_deref().unwrap_or("/");
@@ -1322,38 +2384,80 @@ async fn create_route(
         );
     }
 
-    match state.config_admin.modify_config(&state.active_config, |config| {
-        // Check for duplicate route name
-        if config.routes.iter().any(|r| r.name == payload.name) {
-            return Err(anyhow::anyhow!("route '{}' already exists", payload.name));
-        }
+    let Some(tenant) = scope.tenant_for(payload.tenant) else {
+        return tenant_forbidden();
+    };
 
-        // Check if service exists
-        if !config.services.iter().any(|s| s.name == payload.service) {
-            return Err(anyhow::anyhow!("service '{}' not found", payload.service));
-        }
+    match state
+        .config_admin
+        .modify_config(&state.active_config, |config| {
+            // Check for duplicate route name
+            if config.routes.iter().any(|r| r.name == payload.name) {
+                return Err(anyhow::anyhow!("route '{}' already exists", payload.name));
+            }
 
-        // Check for duplicate default route
-        if payload.is_default.unwrap_or(false) {
-            if config.routes.iter().any(|r| r.is_default) {
-                return Err(anyhow::anyhow!("only one route can be marked as default"));
+            // Check if service exists
+            if !config.services.iter().any(|s| s.name == payload.service) {
+                return Err(anyhow::anyhow!("service '{}' not found", payload.service));
             }
-        }
 
-        let route = crate::config::RouteConfig {
-            name: payload.name.clone(),
-            service: payload.service.clone(),
-            host: payload.host,
-            path_prefix: payload.path_prefix.unwrap_or_else(|| "/".to_string()),
-            methods: payload.methods.unwrap_or_default(),
-            is_default: payload.is_default.unwrap_or(false),
-        };
+            let route = crate::config::RouteConfig {
+                name: payload.name.clone(),
+                app: payload.app,
+                tenant,
+                service: payload.service.clone(),
+                host: payload.host,
+                path_prefix: payload.path_prefix.unwrap_or_else(|| "/".to_string()),
+                methods: payload.methods.unwrap_or_default(),
+                is_default: payload.is_default.unwrap_or(false),
+                observability: Default::default(),
+                bandwidth: None,
+                max_response_bytes: None,
+                max_request_body_bytes: None,
+                retry_body_bytes: None,
+                timeout_ms: None,
+                strip_prefix: false,
+                rewrite_path: None,
+                forwarded_headers: Default::default(),
+                rate_limit: None,
+                max_concurrent_requests: None,
+                preflight_cache: None,
+                idempotency: None,
+                groups: Vec::new(),
+                group_key: None,
+                group_header: None,
+                egress: Default::default(),
+                outbound_proxy: None,
+                protocol: Default::default(),
+                websocket: false,
+                websocket_idle_timeout_ms: None,
+                status_map: Vec::new(),
+                response_validation: None,
+                header_case: Default::default(),
+                merge_duplicate_headers: false,
+                hash_affinity: Default::default(),
+                headers: payload.headers.unwrap_or_default(),
+            };
+            scope.check_write(&route, config)?;
+
+            // Check for duplicate default route
+            if route.is_default
+                && config
+                    .routes
+                    .iter()
+                    .any(|r| r.is_default && r.app == route.app)
+            {
+                return Err(anyhow::anyhow!("only one route can be marked as default"));
+            }
 
-        config.routes.push(route);
-        Ok(())
-    }) {
+            config.routes.push(route);
+            Ok(())
+        }) {
         Ok(_) => text_response(StatusCode::CREATED, b"route_created\n".to_vec()),
         Err(err) => {
+            if let Some(TenantForbidden(code)) = err.downcast_ref() {
+                return text_response(StatusCode::FORBIDDEN, format!("{code}\n"));
+            }
             let err_str = err.to_string();
             if err_str.contains("already exists") {
                 text_response(StatusCode::CONFLICT, format!("{err:#}\n"))
@@ -1370,6 +2474,7 @@ async fn create_route(
 
 async fn update_route(
     State(state): State<AdminState>,
+    Extension(scope): Extension<AdminScope>,
     AxumPath(name): AxumPath<String>,
     body: Body,
 ) -> Response<Body> {
@@ -1411,7 +2516,10 @@ async fn update_route(
     }
 
     if payload.service.is_empty() {
-        return text_response(StatusCode::BAD_REQUEST, b"service_cannot_be_empty\n".to_vec());
+        return text_response(
+            StatusCode::BAD_REQUEST,
+            b"service_cannot_be_empty\n".to_vec(),
+        );
     }
 
     let path_prefix = payload.path_prefix.as_deref().unwrap_or("/");
@@ -1422,92 +2530,669 @@ async fn update_route(
         );
     }
 
-    match state.config_admin.modify_config(&state.active_config, |config| {
-        let index = config.routes.iter().position(|r| r.name == name)
-            .ok_or_else(|| anyhow::anyhow!("route '{}' not found", name))?;
+    let requested_tenant = payload.tenant.clone();
+    if scope.tenant_for(payload.tenant).is_none() {
+        return tenant_forbidden();
+    }
 
-        // Check if service exists
-        if !config.services.iter().any(|s| s.name == payload.service) {
-            return Err(anyhow::anyhow!("service '{}' not found", payload.service));
-        }
+    match state
+        .config_admin
+        .modify_config(&state.active_config, |config| {
+            let index = config
+                .routes
+                .iter()
+                .position(|r| r.name == name && scope.allows(r))
+                .ok_or_else(|| anyhow::anyhow!("route '{}' not found", name))?;
+
+            // Check if service exists
+            if !config.services.iter().any(|s| s.name == payload.service) {
+                return Err(anyhow::anyhow!("service '{}' not found", payload.service));
+            }
 
-        // Check for duplicate default route
-        let current_default = config.routes[index].is_default;
-        let new_default = payload.is_default.unwrap_or(current_default);
-        if new_default && !current_default {
-            if config.routes.iter().any(|r| r.is_default && r.name != name) {
+            let route = crate::config::RouteConfig {
+                name: payload.name.clone(),
+                app: payload.app.or_else(|| config.routes[index].app.clone()),
+                tenant: requested_tenant.or_else(|| config.routes[index].tenant.clone()),
+                service: payload.service.clone(),
+                host: payload.host,
+                path_prefix: payload.path_prefix.unwrap_or_else(|| "/".to_string()),
+                methods: payload
+                    .methods
+                    .unwrap_or_else(|| config.routes[index].methods.clone()),
+                is_default: payload
+                    .is_default
+                    .unwrap_or(config.routes[index].is_default),
+                observability: config.routes[index].observability.clone(),
+                bandwidth: config.routes[index].bandwidth.clone(),
+                max_response_bytes: config.routes[index].max_response_bytes,
+                max_request_body_bytes: config.routes[index].max_request_body_bytes,
+                retry_body_bytes: config.routes[index].retry_body_bytes,
+                timeout_ms: config.routes[index].timeout_ms,
+                strip_prefix: config.routes[index].strip_prefix,
+                rewrite_path: config.routes[index].rewrite_path.clone(),
+                forwarded_headers: config.routes[index].forwarded_headers,
+                rate_limit: config.routes[index].rate_limit.clone(),
+                max_concurrent_requests: config.routes[index].max_concurrent_requests,
+                preflight_cache: config.routes[index].preflight_cache.clone(),
+                idempotency: config.routes[index].idempotency.clone(),
+                groups: config.routes[index].groups.clone(),
+                group_key: config.routes[index].group_key.clone(),
+                group_header: config.routes[index].group_header.clone(),
+                egress: config.routes[index].egress.clone(),
+                outbound_proxy: config.routes[index].outbound_proxy.clone(),
+                protocol: config.routes[index].protocol,
+                websocket: config.routes[index].websocket,
+                websocket_idle_timeout_ms: config.routes[index].websocket_idle_timeout_ms,
+                status_map: config.routes[index].status_map.clone(),
+                response_validation: config.routes[index].response_validation.clone(),
+                header_case: config.routes[index].header_case,
+                merge_duplicate_headers: config.routes[index].merge_duplicate_headers,
+                hash_affinity: config.routes[index].hash_affinity,
+                headers: payload
+                    .headers
+                    .unwrap_or_else(|| config.routes[index].headers.clone()),
+            };
+
+            scope.check_write(&route, config)?;
+
+            // Check for duplicate default route
+            if route.is_default
+                && !config.routes[index].is_default
+                && config
+                    .routes
+                    .iter()
+                    .any(|r| r.is_default && r.name != name && r.app == route.app)
+            {
                 return Err(anyhow::anyhow!("only one route can be marked as default"));
             }
+
+            config.routes[index] = route;
+            Ok(())
+        }) {
+        Ok(_) => text_response(StatusCode::OK, b"route_updated\n".to_vec()),
+        Err(err) => {
+            if let Some(TenantForbidden(code)) = err.downcast_ref() {
+                return text_response(StatusCode::FORBIDDEN, format!("{code}\n"));
+            }
+            let err_str = err.to_string();
+            if err_str.contains("not found") {
+                text_response(StatusCode::NOT_FOUND, format!("{err:#}\n"))
+            } else if err_str.contains("only one route can be marked as default") {
+                text_response(StatusCode::CONFLICT, format!("{err:#}\n"))
+            } else {
+                text_response(StatusCode::INTERNAL_SERVER_ERROR, format!("{err:#}\n"))
+            }
         }
+    }
+}
+
+async fn delete_route(
+    State(state): State<AdminState>,
+    Extension(scope): Extension<AdminScope>,
+    AxumPath(name): AxumPath<String>,
+) -> Response<Body> {
+    match state
+        .config_admin
+        .modify_config(&state.active_config, |config| {
+            let index = config
+                .routes
+                .iter()
+                .position(|r| r.name == name && scope.allows(r))
+                .ok_or_else(|| anyhow::anyhow!("route '{}' not found", name))?;
+
+            config.routes.remove(index);
+            Ok(())
+        }) {
+        Ok(_) => text_response(StatusCode::OK, b"route_deleted\n".to_vec()),
+        Err(err) => {
+            if err.to_string().contains("not found") {
+                text_response(StatusCode::NOT_FOUND, format!("{err:#}\n"))
+            } else {
+                text_response(StatusCode::INTERNAL_SERVER_ERROR, format!("{err:#}\n"))
+            }
+        }
+    }
+}
+
+/// A whole `[[route]]` as JSON, with every setting the TOML block takes.
+async fn get_web_route(
+    State(state): State<AdminState>,
+    Extension(scope): Extension<AdminScope>,
+    AxumPath(name): AxumPath<String>,
+) -> Response<Body> {
+    match state.config_admin.read_parsed_config() {
+        Ok(config) => match config
+            .routes
+            .iter()
+            .find(|r| r.name == name && scope.allows(r))
+        {
+            Some(route) => json_response(StatusCode::OK, route),
+            None => text_response(StatusCode::NOT_FOUND, b"route_not_found\n".to_vec()),
+        },
+        Err(err) => text_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("failed_to_read_config: {err:#}\n"),
+        ),
+    }
+}
+
+async fn create_web_route(
+    State(state): State<AdminState>,
+    Extension(scope): Extension<AdminScope>,
+    AxumPath(name): AxumPath<String>,
+    body: Body,
+) -> Response<Body> {
+    let mut route = match web_route_from_body(&name, body).await {
+        Ok(route) => route,
+        Err(response) => return response,
+    };
+    let Some(tenant) = scope.tenant_for(route.tenant.take()) else {
+        return tenant_forbidden();
+    };
+    route.tenant = tenant;
+
+    let result = state
+        .config_admin
+        .modify_config(&state.active_config, |config| {
+            if config.routes.iter().any(|r| r.name == name) {
+                bail!("route '{name}' already exists");
+            }
+            scope.check_write(&route, config)?;
+            config.routes.push(route);
+            Ok(())
+        });
+    web_route_change_response(StatusCode::CREATED, result)
+}
+
+/// Replaces the route as a whole; settings left out of the body go back to
+/// their defaults.
+async fn replace_web_route(
+    State(state): State<AdminState>,
+    Extension(scope): Extension<AdminScope>,
+    AxumPath(name): AxumPath<String>,
+    body: Body,
+) -> Response<Body> {
+    let mut route = match web_route_from_body(&name, body).await {
+        Ok(route) => route,
+        Err(response) => return response,
+    };
+    let Some(tenant) = scope.tenant_for(route.tenant.take()) else {
+        return tenant_forbidden();
+    };
+    route.tenant = tenant;
+
+    let result = state
+        .config_admin
+        .modify_config(&state.active_config, |config| {
+            let index = config
+                .routes
+                .iter()
+                .position(|r| r.name == name && scope.allows(r))
+                .ok_or_else(|| anyhow::anyhow!("route '{name}' not found"))?;
+            scope.check_write(&route, config)?;
+            config.routes[index] = route;
+            Ok(())
+        });
+    web_route_change_response(StatusCode::OK, result)
+}
+
+async fn delete_web_route(
+    State(state): State<AdminState>,
+    Extension(scope): Extension<AdminScope>,
+    AxumPath(name): AxumPath<String>,
+) -> Response<Body> {
+    let result = state
+        .config_admin
+        .modify_config(&state.active_config, |config| {
+            let index = config
+                .routes
+                .iter()
+                .position(|r| r.name == name && scope.allows(r))
+                .ok_or_else(|| anyhow::anyhow!("route '{name}' not found"))?;
+            config.routes.remove(index);
+            Ok(())
+        });
+    web_route_change_response(StatusCode::OK, result)
+}
+
+/// The route in a request body. `name` may be left out, and otherwise has to
+/// match the one in the path.
+async fn web_route_from_body(name: &str, body: Body) -> Result<RouteConfig, Response<Body>> {
+    let bytes = match body::to_bytes(body, MAX_ADMIN_CONFIG_BODY_BYTES).await {
+        Ok(bytes) => bytes,
+        Err(err) => {
+            if err.to_string().to_ascii_lowercase().contains("limit") {
+                return Err(text_response(
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    b"request_body_too_large\n".to_vec(),
+                ));
+            }
+            return Err(text_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("failed_to_read_request_body: {err:#}\n"),
+            ));
+        }
+    };
+    if bytes.is_empty() {
+        return Err(text_response(
+            StatusCode::BAD_REQUEST,
+            b"request_body_is_empty\n".to_vec(),
+        ));
+    }
+
+    let invalid = |err: &dyn std::fmt::Display| {
+        text_response(
+            StatusCode::BAD_REQUEST,
+            format!("invalid_request_body: {err}\n"),
+        )
+    };
+    let mut value =
+        serde_json::from_slice::<serde_json::Value>(&bytes).map_err(|err| invalid(&err))?;
+    let Some(fields) = value.as_object_mut() else {
+        return Err(invalid(&"expected a JSON object"));
+    };
+    match fields.get("name") {
+        None => {
+            fields.insert("name".to_string(), name.into());
+        }
+        Some(given) if given == name => {}
+        Some(_) => {
+            return Err(text_response(
+                StatusCode::BAD_REQUEST,
+                b"route_name_in_path_must_match_name_in_body\n".to_vec(),
+            ));
+        }
+    }
+    serde_json::from_value(value).map_err(|err| invalid(&err))
+}
+
+/// Answers a `/web/routes/{name}` change with the validate payload, as
+/// `PUT /web/config?format=json` does.
+fn web_route_change_response(
+    status: StatusCode,
+    result: anyhow::Result<Vec<ConfigProblem>>,
+) -> Response<Body> {
+    let err = match result {
+        Ok(warnings) => {
+            let report = ValidationReport {
+                errors: Vec::new(),
+                warnings,
+            };
+            return json_response(status, &ConfigValidatePayload::from(report));
+        }
+        Err(err) => err,
+    };
+    if let Some(ConfigErrors(errors)) = err.downcast_ref::<ConfigErrors>() {
+        let report = ValidationReport {
+            errors: errors.clone(),
+            warnings: Vec::new(),
+        };
+        return json_response(
+            StatusCode::BAD_REQUEST,
+            &ConfigValidatePayload::from(report),
+        );
+    }
+    if let Some(TenantForbidden(code)) = err.downcast_ref() {
+        return text_response(StatusCode::FORBIDDEN, format!("{code}\n"));
+    }
+    let err_str = err.to_string();
+    if err_str.contains("not found") {
+        text_response(StatusCode::NOT_FOUND, format!("{err:#}\n"))
+    } else if err_str.contains("already exists") || err_str.contains("edit that file instead") {
+        text_response(StatusCode::CONFLICT, format!("{err:#}\n"))
+    } else {
+        text_response(StatusCode::INTERNAL_SERVER_ERROR, format!("{err:#}\n"))
+    }
+}
+
+/// Which routes an admin request may see and change, from its bearer token.
+#[derive(Debug, Clone, PartialEq)]
+enum AdminScope {
+    /// No token without `[admin.auth]`, where the admin listener itself is the
+    /// access boundary, or a read-write credential.
+    All,
+    /// A read-only `[admin.auth]` credential.
+    ReadOnly,
+    Tenant(String),
+}
+
+impl AdminScope {
+    fn allows(&self, route: &RouteConfig) -> bool {
+        match self {
+            Self::All | Self::ReadOnly => true,
+            Self::Tenant(tenant) => route.tenant.as_deref() == Some(tenant),
+        }
+    }
+
+    /// The tenant a created or updated route must carry; `None` when the
+    /// request names a tenant outside the scope.
+    fn tenant_for(&self, requested: Option<String>) -> Option<Option<String>> {
+        match self {
+            Self::All | Self::ReadOnly => Some(requested),
+            Self::Tenant(tenant)
+                if requested
+                    .as_ref()
+                    .is_none_or(|requested| requested == tenant) =>
+            {
+                Some(Some(tenant.clone()))
+            }
+            Self::Tenant(_) => None,
+        }
+    }
+
+    /// Fails with [`TenantForbidden`] when a tenant token may not write
+    /// `route`: tenants only send hosts they own to services they own, and
+    /// leave the default route, apps and outbound proxies to full admins.
+    fn check_write(&self, route: &RouteConfig, config: &PrxConfig) -> anyhow::Result<()> {
+        let Self::Tenant(name) = self else {
+            return Ok(());
+        };
+        let Some(tenant) = config.tenants.iter().find(|tenant| &tenant.name == name) else {
+            return Err(TenantForbidden("route_host_not_owned_by_tenant").into());
+        };
+        if route.is_default || route.app.is_some() || route.outbound_proxy.is_some() {
+            return Err(TenantForbidden("route_setting_reserved_for_admins").into());
+        }
+        // A route without a host would take requests for every host.
+        if !route
+            .host
+            .as_deref()
+            .is_some_and(|host| tenant.hosts.iter().any(|owned| host_covers(owned, host)))
+        {
+            return Err(TenantForbidden("route_host_not_owned_by_tenant").into());
+        }
+        if !std::iter::once(&route.service)
+            .chain(route.groups.iter().map(|group| &group.service))
+            .all(|service| tenant.services.contains(service))
+        {
+            return Err(TenantForbidden("route_service_not_owned_by_tenant").into());
+        }
+        Ok(())
+    }
+
+    fn resolve(token: &str, config: &PrxConfig) -> anyhow::Result<Option<Self>> {
+        let revealed = crate::secret::reveal(config)?;
+        Ok(revealed
+            .tenants
+            .iter()
+            .find(|tenant| tokens_match(tenant.admin_token.as_bytes(), token.as_bytes()))
+            .map(|tenant| Self::Tenant(tenant.name.clone())))
+    }
+}
+
+/// Whether every host the route `host` pattern matches is matched by the
+/// tenant's `owned` pattern too.
+fn host_covers(owned: &str, host: &str) -> bool {
+    let host = host.to_ascii_lowercase();
+    let owned = owned.to_ascii_lowercase();
+    match owned.strip_prefix("*.") {
+        Some(domain) => {
+            let base = host.strip_prefix("*.").unwrap_or(&host);
+            base == domain || base.ends_with(&format!(".{domain}"))
+        }
+        None => host == owned,
+    }
+}
 
-        let route = crate::config::RouteConfig {
-            name: payload.name.clone(),
-            service: payload.service.clone(),
-            host: payload.host,
-            path_prefix: payload.path_prefix.unwrap_or_else(|| "/".to_string()),
-            methods: payload.methods.unwrap_or_else(|| config.routes[index].methods.clone()),
-            is_default: payload.is_default.unwrap_or(config.routes[index].is_default),
-        };
+/// A route write a tenant token may not make, answered with `403` and the
+/// code.
+#[derive(Debug)]
+struct TenantForbidden(&'static str);
 
-        config.routes[index] = route;
-        Ok(())
-    }) {
-        Ok(_) => text_response(StatusCode::OK, b"route_updated\n".to_vec()),
-        Err(err) => {
-            let err_str = err.to_string();
-            if err_str.contains("not found") {
-                text_response(StatusCode::NOT_FOUND, format!("{err:#}\n"))
-            } else if err_str.contains("only one route can be marked as default") {
-                text_response(StatusCode::CONFLICT, format!("{err:#}\n"))
-            } else {
-                text_response(StatusCode::INTERNAL_SERVER_ERROR, format!("{err:#}\n"))
-            }
-        }
+impl std::fmt::Display for TenantForbidden {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.0)
     }
 }
 
-async fn delete_route(
+impl std::error::Error for TenantForbidden {}
+
+fn tenant_forbidden() -> Response<Body> {
+    text_response(
+        StatusCode::FORBIDDEN,
+        b"route_tenant_outside_token_scope\n".to_vec(),
+    )
+}
+
+/// Authorizes the request, and appends it to `admin.audit_log` when it may
+/// change something.
+async fn scope_request(
     State(state): State<AdminState>,
-    AxumPath(name): AxumPath<String>,
+    request: Request,
+    next: Next,
 ) -> Response<Body> {
-    match state.config_admin.modify_config(&state.active_config, |config| {
-        let index = config.routes.iter().position(|r| r.name == name)
-            .ok_or_else(|| anyhow::anyhow!("route '{}' not found", name))?;
+    let audit_log = state
+        .active_config
+        .load()
+        .admin_audit_log()
+        .map(|configured| AuditLog::at(&state.config_admin.config_path, configured));
+    let Some(audit_log) =
+        audit_log.filter(|_| !is_read_request(request.method(), request.uri().path()))
+    else {
+        return authorize(&state, request, next).await.0;
+    };
 
-        config.routes.remove(index);
-        Ok(())
-    }) {
-        Ok(_) => text_response(StatusCode::OK, b"route_deleted\n".to_vec()),
-        Err(err) => {
-            if err.to_string().contains("not found") {
-                text_response(StatusCode::NOT_FOUND, format!("{err:#}\n"))
-            } else {
-                text_response(StatusCode::INTERNAL_SERVER_ERROR, format!("{err:#}\n"))
+    let source = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.to_string());
+    let method = request.method().to_string();
+    let path = request.uri().path().to_string();
+    let before = state.config_admin.read_parsed_config().ok();
+    let (response, principal) = authorize(&state, request, next).await;
+    let status = response.status();
+    let (response, error, changes) = if status.is_success() {
+        let changes = before
+            .zip(state.config_admin.read_parsed_config().ok())
+            .map(|(before, after)| ConfigDiff::between(&before, &after).summary())
+            .unwrap_or_default();
+        (response, None, changes)
+    } else {
+        let (parts, body) = response.into_parts();
+        let body = body::to_bytes(body, MAX_ADMIN_CONFIG_BODY_BYTES)
+            .await
+            .unwrap_or_default();
+        let error = audit::error_summary(&body);
+        (
+            Response::from_parts(parts, Body::from(body)),
+            error,
+            Vec::new(),
+        )
+    };
+    let entry = AuditEntry {
+        at_epoch_ms: now_epoch_ms(),
+        source,
+        principal,
+        method,
+        path,
+        status: status.as_u16(),
+        changes,
+        error,
+    };
+    if let Err(err) = audit_log.append(&entry) {
+        error!(error = %format!("{err:#}"), "failed to write admin audit log");
+    }
+    response
+}
+
+/// Resolves the request's credentials into its scope, answering with who
+/// sent it (see [`AuditEntry::principal`]).
+async fn authorize(
+    state: &AdminState,
+    mut request: Request,
+    next: Next,
+) -> (Response<Body>, String) {
+    let authorization = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    let auth = state.active_config.load().admin_auth().cloned();
+    let access = auth
+        .as_ref()
+        .zip(authorization.as_deref())
+        .and_then(|(auth, authorization)| auth.access(authorization));
+    let (scope, principal) = match access {
+        Some((principal, AdminAccess::ReadWrite)) => (AdminScope::All, principal.to_string()),
+        Some((principal, AdminAccess::ReadOnly)) => (AdminScope::ReadOnly, principal.to_string()),
+        None => {
+            let token = authorization
+                .as_deref()
+                .and_then(|value| value.strip_prefix("Bearer "))
+                .map(|token| token.trim().to_string());
+            let anonymous = || "anonymous".to_string();
+            match token {
+                None if auth.is_none() => (AdminScope::All, anonymous()),
+                None => {
+                    return (
+                        unauthorized(auth.as_deref(), "admin_auth_required"),
+                        anonymous(),
+                    );
+                }
+                Some(token) => {
+                    let resolved = state
+                        .config_admin
+                        .read_parsed_config()
+                        .and_then(|config| AdminScope::resolve(&token, &config));
+                    match resolved {
+                        Ok(Some(AdminScope::Tenant(tenant))) => {
+                            let principal = format!("tenant:{tenant}");
+                            (AdminScope::Tenant(tenant), principal)
+                        }
+                        Ok(Some(scope)) => (scope, anonymous()),
+                        Ok(None) => {
+                            let response = unauthorized(auth.as_deref(), "invalid_admin_token");
+                            return (response, anonymous());
+                        }
+                        Err(err) => {
+                            let response = text_response(
+                                StatusCode::INTERNAL_SERVER_ERROR,
+                                format!("failed_to_read_config: {err:#}\n"),
+                            );
+                            return (response, anonymous());
+                        }
+                    }
+                }
             }
         }
+    };
+    if matches!(scope, AdminScope::Tenant(_)) && !is_routes_path(request.uri().path()) {
+        let response = text_response(
+            StatusCode::FORBIDDEN,
+            b"tenant_token_only_grants_route_access\n".to_vec(),
+        );
+        return (response, principal);
+    }
+    if scope == AdminScope::ReadOnly && !is_read_request(request.method(), request.uri().path()) {
+        let response = text_response(
+            StatusCode::FORBIDDEN,
+            b"admin_credential_is_read_only\n".to_vec(),
+        );
+        return (response, principal);
+    }
+    request.extensions_mut().insert(scope);
+    (next.run(request).await, principal)
+}
+
+fn unauthorized(auth: Option<&AdminAuth>, reason: &str) -> Response<Body> {
+    let mut response = text_response(StatusCode::UNAUTHORIZED, format!("{reason}\n"));
+    if let Some(auth) = auth {
+        response.headers_mut().insert(
+            header::WWW_AUTHENTICATE,
+            HeaderValue::from_static(auth.challenge()),
+        );
     }
+    response
+}
+
+/// Requests that change nothing, which read-only credentials may send: reads,
+/// and checking a draft config before it is applied.
+fn is_read_request(method: &Method, path: &str) -> bool {
+    method == Method::GET
+        || method == Method::HEAD
+        || (method == Method::POST
+            && (path == ADMIN_CONFIG_VALIDATE_PATH || path == ADMIN_ROUTE_HEALTH_PATH))
+}
+
+/// Upstreams belong to services, which routes of other tenants may share, so
+/// their endpoints are not route paths.
+fn is_routes_path(path: &str) -> bool {
+    [ADMIN_ROUTES_PATH, ADMIN_WEB_ROUTES_PATH]
+        .into_iter()
+        .any(|routes| {
+            path == routes
+                || path
+                    .strip_prefix(routes)
+                    .is_some_and(|rest| rest.starts_with('/') && !rest.contains("/upstreams/"))
+        })
 }
 
 fn build_router(state: AdminState) -> Router {
     Router::new()
         // Config endpoints
         .route(ADMIN_CONFIG_PATH, get(get_config).put(put_config))
+        .route(ADMIN_CONFIG_VALIDATE_PATH, post(post_config_validate))
+        .route(ADMIN_CONFIG_HISTORY_PATH, get(get_config_history))
+        .route(ADMIN_CONFIG_ROLLBACK_PATH, post(post_config_rollback))
         .route(
             ADMIN_ROUTE_HEALTH_PATH,
             get(get_route_health).post(post_route_health),
         )
         // Service CRUD endpoints
         .route(ADMIN_SERVICES_PATH, get(list_services).post(create_service))
-        .route(ADMIN_SERVICES_NAME_PATH, get(get_service).put(update_service).delete(delete_service))
+        .route(
+            ADMIN_SERVICES_NAME_PATH,
+            get(get_service).put(update_service).delete(delete_service),
+        )
         // Route CRUD endpoints
         .route(ADMIN_ROUTES_PATH, get(list_routes).post(create_route))
-        .route(ADMIN_ROUTES_NAME_PATH, get(get_route).put(update_route).delete(delete_route))
+        .route(
+            ADMIN_ROUTES_NAME_PATH,
+            get(get_route).put(update_route).delete(delete_route),
+        )
+        .route(
+            ADMIN_WEB_ROUTES_NAME_PATH,
+            get(get_web_route)
+                .post(create_web_route)
+                .put(replace_web_route)
+                .delete(delete_web_route),
+        )
+        .route(ADMIN_UPSTREAM_DRAIN_PATH, post(post_upstream_drain))
+        .route(ADMIN_UPSTREAM_ENABLE_PATH, post(post_upstream_enable))
+        // Runtime stats
+        .route(ADMIN_STATS_PATH, get(get_stats))
+        .route(ADMIN_STATS_RESET_PATH, post(post_stats_reset))
+        .route(ADMIN_UNMATCHED_HOSTS_PATH, get(get_unmatched_hosts))
+        .route(ADMIN_STATE_EXPORT_PATH, get(get_state_export))
+        .route(ADMIN_STATE_IMPORT_PATH, post(post_state_import))
+        .route(ADMIN_CACHE_PATH, delete(delete_cache))
+        .route(ADMIN_AUDIT_PATH, get(get_audit))
+        .route(ADMIN_STATUS_PATH, get(get_status))
         // WebUI
         .route("/", get(get_webui_root))
         .route("/{*path}", get(get_webui_path))
+        .layer(middleware::from_fn_with_state(state.clone(), scope_request))
         .with_state(state)
 }
 
+/// The `[server]` health, ready and load paths, for `server.probes.admin`.
+#[derive(Debug, Clone)]
+pub struct AdminProbes {
+    pub health_path: String,
+    pub ready_path: String,
+    pub load_path: Option<String>,
+}
+
+fn probe_router(state: AdminState, probes: &AdminProbes) -> Router {
+    let mut router = Router::new()
+        .route(&probes.health_path, get(get_probe_health))
+        .route(&probes.ready_path, get(get_probe_ready));
+    if let Some(load_path) = &probes.load_path {
+        router = router.route(load_path, get(get_probe_load));
+    }
+    router.with_state(state)
+}
+
 pub fn bind_admin_listener(listen: &str) -> anyhow::Result<TcpListener> {
     TcpListener::bind(listen).with_context(|| format!("failed to bind admin listener on {listen}"))
 }
@@ -1517,6 +3202,8 @@ pub struct AdminAxumService {
     listen: String,
     listener: Option<TcpListener>,
     state: AdminState,
+    threads: usize,
+    probes: Option<AdminProbes>,
 }
 
 impl AdminAxumService {
@@ -1534,8 +3221,21 @@ impl AdminAxumService {
                 config_admin: ConfigAdmin::new(config_path),
                 active_config,
             },
+            threads: 1,
+            probes: None,
         }
     }
+
+    pub fn with_threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+
+    /// Answers the probe paths too, outside admin tokens.
+    pub fn with_probes(mut self, probes: Option<AdminProbes>) -> Self {
+        self.probes = probes;
+        self
+    }
 }
 
 #[async_trait]
@@ -1578,11 +3278,15 @@ impl Service for AdminAxumService {
             "admin config API is enabled"
         );
 
-        let app = build_router(self.state.clone());
+        let mut app = build_router(self.state.clone());
+        if let Some(probes) = &self.probes {
+            app = probe_router(self.state.clone(), probes).merge(app);
+        }
         let shutdown_signal = async move {
             let _ = shutdown.changed().await;
         };
 
+        let app = app.into_make_service_with_connect_info::<SocketAddr>();
         if let Err(err) = axum::serve(listener, app)
             .with_graceful_shutdown(shutdown_signal)
             .await
@@ -1600,7 +3304,7 @@ impl Service for AdminAxumService {
     }
 
     fn threads(&self) -> Option<usize> {
-        Some(1)
+        Some(self.threads)
     }
 }
 
@@ -1608,9 +3312,10 @@ impl Service for AdminAxumService {
 mod tests {
     use super::*;
     use arc_swap::ArcSwap;
+    use pingora::http::{RequestHeader, ResponseHeader};
     use tempfile::tempdir;
 
-    use crate::runtime::RuntimeConfig;
+    use crate::{preflight::PreflightCache, runtime::RuntimeConfig};
 
     fn sample_config(listen: &str) -> String {
         format!(
@@ -1637,6 +3342,890 @@ is_default = true
         )
     }
 
+    fn tenant_admin() -> (tempfile::TempDir, Router) {
+        let dir = tempdir().expect("tempdir should be created");
+        let config_path = dir.path().join("Prx.toml");
+        let config = format!(
+            r#"{}
+[[service]]
+name = "payments"
+
+[[service.upstream]]
+addr = "127.0.0.1:9001"
+
+[[service]]
+name = "search"
+
+[[service.upstream]]
+addr = "127.0.0.1:9002"
+
+[[tenant]]
+name = "payments"
+admin_token = "pay-token"
+hosts = ["pay.example.com", "*.pay.example.com"]
+services = ["payments"]
+
+[[tenant]]
+name = "search"
+admin_token = "search-token"
+hosts = ["search.example.com"]
+services = ["search"]
+
+[[route]]
+name = "payments"
+tenant = "payments"
+host = "pay.example.com"
+service = "payments"
+path_prefix = "/pay"
+
+[[route]]
+name = "search"
+tenant = "search"
+host = "search.example.com"
+service = "search"
+path_prefix = "/search"
+"#,
+            sample_config("127.0.0.1:8080")
+        );
+        fs::write(&config_path, &config).expect("seed config");
+        let runtime = Arc::new(ArcSwap::from_pointee(
+            RuntimeConfig::from_config(
+                PrxConfig::from_toml_str(&config).expect("seed config should be valid"),
+            )
+            .expect("runtime"),
+        ));
+        let router = build_router(AdminState {
+            config_admin: ConfigAdmin::new(config_path),
+            active_config: runtime,
+        });
+        (dir, router)
+    }
+
+    fn send(
+        router: &Router,
+        method: &str,
+        path: &str,
+        token: Option<&str>,
+        body: &str,
+    ) -> (StatusCode, String) {
+        let authorization = token.map(|token| format!("Bearer {token}"));
+        let response = call(router, method, path, authorization.as_deref(), body);
+        (response.status(), response_text(response))
+    }
+
+    fn call(
+        router: &Router,
+        method: &str,
+        path: &str,
+        authorization: Option<&str>,
+        body: &str,
+    ) -> Response<Body> {
+        use tower::ServiceExt;
+
+        let mut request = axum::http::Request::builder().method(method).uri(path);
+        if let Some(authorization) = authorization {
+            request = request.header(header::AUTHORIZATION, authorization);
+        }
+        let request = request
+            .body(Body::from(body.to_string()))
+            .expect("request should build");
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .expect("runtime")
+            .block_on(router.clone().oneshot(request))
+            .expect("router call")
+    }
+
+    fn response_text(response: Response<Body>) -> String {
+        let bytes = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .expect("runtime")
+            .block_on(body::to_bytes(response.into_body(), usize::MAX))
+            .expect("response body");
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+
+    #[test]
+    fn tenant_token_only_sees_and_creates_its_own_routes() {
+        let (dir, router) = tenant_admin();
+
+        let (status, body) = send(&router, "GET", ADMIN_ROUTES_PATH, Some("pay-token"), "");
+        assert_eq!(status, StatusCode::OK);
+        assert!(
+            body.contains("\"payments\"") && !body.contains("\"search\""),
+            "{body}"
+        );
+        let (status, body) = send(&router, "GET", ADMIN_ROUTES_PATH, None, "");
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains("\"search\""), "{body}");
+
+        let (status, _) = send(
+            &router,
+            "GET",
+            "/admin/routes/search",
+            Some("pay-token"),
+            "",
+        );
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = send(
+            &router,
+            "DELETE",
+            "/admin/routes/search",
+            Some("pay-token"),
+            "",
+        );
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = send(&router, "GET", ADMIN_CONFIG_PATH, Some("pay-token"), "");
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let drain = "/web/routes/payments/upstreams/127.0.0.1:9000/drain";
+        let (status, _) = send(&router, "POST", drain, Some("pay-token"), "");
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, _) = send(&router, "GET", ADMIN_ROUTES_PATH, Some("wrong"), "");
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let create = r#"{"name":"refunds","host":"refunds.pay.example.com","service":"payments","path_prefix":"/refunds"}"#;
+        let (status, body) = send(
+            &router,
+            "POST",
+            ADMIN_ROUTES_PATH,
+            Some("pay-token"),
+            create,
+        );
+        assert_eq!(status, StatusCode::CREATED, "{body}");
+        let saved = PrxConfig::from_file(&dir.path().join("Prx.toml")).expect("saved config");
+        let refunds = saved
+            .routes
+            .iter()
+            .find(|route| route.name == "refunds")
+            .expect("created");
+        assert_eq!(refunds.tenant.as_deref(), Some("payments"));
+
+        let stolen = r#"{"name":"other","tenant":"search","host":"pay.example.com","service":"payments","path_prefix":"/other"}"#;
+        let (status, _) = send(
+            &router,
+            "POST",
+            ADMIN_ROUTES_PATH,
+            Some("pay-token"),
+            stolen,
+        );
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+
+    #[test]
+    fn tenant_token_only_writes_routes_to_its_own_hosts_and_services() {
+        let (dir, router) = tenant_admin();
+        let saved = || PrxConfig::from_file(&dir.path().join("Prx.toml")).expect("saved config");
+        let before = saved();
+
+        let writes = [
+            (
+                "POST",
+                ADMIN_ROUTES_PATH,
+                r#"{"name":"claim","host":"search.example.com","service":"payments"}"#,
+                "route_host_not_owned_by_tenant",
+            ),
+            (
+                "POST",
+                ADMIN_ROUTES_PATH,
+                r#"{"name":"claim","service":"payments","path_prefix":"/claim"}"#,
+                "route_host_not_owned_by_tenant",
+            ),
+            (
+                "POST",
+                ADMIN_ROUTES_PATH,
+                r#"{"name":"claim","host":"pay.example.com","service":"search"}"#,
+                "route_service_not_owned_by_tenant",
+            ),
+            (
+                "POST",
+                ADMIN_ROUTES_PATH,
+                r#"{"name":"claim","host":"pay.example.com","service":"payments","is_default":true}"#,
+                "route_setting_reserved_for_admins",
+            ),
+            (
+                "PUT",
+                "/admin/routes/payments",
+                r#"{"name":"payments","host":"pay.example.com","service":"payments","app":"edge"}"#,
+                "route_setting_reserved_for_admins",
+            ),
+            (
+                "PUT",
+                "/web/routes/payments",
+                r#"{"host":"search.example.com","service":"payments"}"#,
+                "route_host_not_owned_by_tenant",
+            ),
+            (
+                "PUT",
+                "/web/routes/payments",
+                r#"{"host":"*.example.com","service":"payments"}"#,
+                "route_host_not_owned_by_tenant",
+            ),
+            (
+                "POST",
+                "/web/routes/claim",
+                r#"{"host":"pay.example.com","service":"payments","outbound_proxy":"http://127.0.0.1:3128"}"#,
+                "route_setting_reserved_for_admins",
+            ),
+        ];
+        for (method, path, body, code) in writes {
+            let (status, answer) = send(&router, method, path, Some("pay-token"), body);
+            assert_eq!(
+                status,
+                StatusCode::FORBIDDEN,
+                "{method} {path} {body}: {answer}"
+            );
+            assert_eq!(answer, format!("{code}\n"), "{method} {path} {body}");
+        }
+        assert_eq!(saved(), before);
+
+        // Full admins are not bound by tenant ownership.
+        let (status, body) = send(
+            &router,
+            "POST",
+            "/web/routes/shared",
+            None,
+            r#"{"host":"search.example.com","service":"payments","path_prefix":"/shared"}"#,
+        );
+        assert_eq!(status, StatusCode::CREATED, "{body}");
+    }
+
+    #[test]
+    fn web_routes_edit_one_whole_route_at_a_time() {
+        let (dir, router) = tenant_admin();
+        let saved = || PrxConfig::from_file(&dir.path().join("Prx.toml")).expect("saved config");
+
+        let (status, body) = send(&router, "GET", "/web/routes/payments", None, "");
+        assert_eq!(status, StatusCode::OK);
+        let payments: RouteConfig = serde_json::from_str(&body).expect("route json");
+        assert_eq!(payments, saved().routes[1]);
+
+        let create = r#"{"service":"default","path_prefix":"/api","strip_prefix":true}"#;
+        let (status, body) = send(&router, "POST", "/web/routes/api", None, create);
+        assert_eq!(status, StatusCode::CREATED, "{body}");
+        assert_eq!(body, r#"{"valid":true,"problems":[],"warnings":[]}"#);
+        let api = saved()
+            .routes
+            .into_iter()
+            .find(|r| r.name == "api")
+            .expect("created");
+        assert!(api.strip_prefix);
+        let (status, _) = send(&router, "POST", "/web/routes/api", None, create);
+        assert_eq!(status, StatusCode::CONFLICT);
+
+        let before = saved();
+        let broken = r#"{"service":"missing","path_prefix":"/api"}"#;
+        let (status, body) = send(&router, "PUT", "/web/routes/api", None, broken);
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body.contains(r#""code":"unknown_reference""#), "{body}");
+        assert_eq!(saved(), before);
+        let renamed = r#"{"name":"other","service":"default"}"#;
+        let (status, _) = send(&router, "PUT", "/web/routes/api", None, renamed);
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let replace = r#"{"service":"default","path_prefix":"/v2"}"#;
+        let (status, body) = send(&router, "PUT", "/web/routes/api", None, replace);
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let api = saved()
+            .routes
+            .into_iter()
+            .find(|r| r.name == "api")
+            .expect("replaced");
+        assert_eq!(api.path_prefix, "/v2");
+        assert!(!api.strip_prefix, "settings left out are reset");
+
+        let (status, _) = send(&router, "GET", "/web/routes/search", Some("pay-token"), "");
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let pay = r#"{"host":"pay.example.com","service":"payments","path_prefix":"/pay/v2"}"#;
+        let (status, body) = send(
+            &router,
+            "PUT",
+            "/web/routes/payments",
+            Some("pay-token"),
+            pay,
+        );
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(saved().routes[1].tenant.as_deref(), Some("payments"));
+
+        let (status, _) = send(&router, "DELETE", "/web/routes/api", None, "");
+        assert_eq!(status, StatusCode::OK);
+        assert!(saved().routes.iter().all(|r| r.name != "api"));
+        let (status, _) = send(&router, "DELETE", "/web/routes/api", None, "");
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn admin_auth_requires_credentials_and_enforces_read_only_scopes() {
+        use base64::Engine;
+
+        let dir = tempdir().expect("tempdir should be created");
+        let config_path = dir.path().join("Prx.toml");
+        let config = format!(
+            r#"{}
+[[tenant]]
+name = "payments"
+admin_token = "pay-token"
+
+[[admin.auth.token]]
+name = "ci"
+token = "ci-token"
+scope = "read_write"
+
+[[admin.auth.token]]
+name = "dashboards"
+token = "view-token"
+
+[[admin.auth.basic]]
+username = "ops"
+password = "s3cret"
+scope = "read_write"
+"#,
+            sample_config("127.0.0.1:8080")
+        );
+        fs::write(&config_path, &config).expect("seed config");
+        let runtime = Arc::new(ArcSwap::from_pointee(
+            RuntimeConfig::from_config(
+                PrxConfig::from_toml_str(&config).expect("seed config should be valid"),
+            )
+            .expect("runtime"),
+        ));
+        let router = build_router(AdminState {
+            config_admin: ConfigAdmin::new(config_path),
+            active_config: runtime,
+        });
+
+        let response = call(&router, "GET", ADMIN_CONFIG_PATH, None, "");
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            response.headers()[header::WWW_AUTHENTICATE],
+            "Basic realm=\"prx admin\""
+        );
+        assert_eq!(
+            send(&router, "GET", "/", None, "").0,
+            StatusCode::UNAUTHORIZED
+        );
+        let (status, body) = send(&router, "GET", ADMIN_CONFIG_PATH, Some("guess"), "");
+        assert_eq!(
+            (status, body.as_str()),
+            (StatusCode::UNAUTHORIZED, "invalid_admin_token\n")
+        );
+
+        let (status, body) = send(&router, "GET", ADMIN_CONFIG_PATH, Some("view-token"), "");
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains("[[admin.auth.token]]"), "{body}");
+        assert!(
+            !["ci-token", "s3cret", "pay-token"]
+                .iter()
+                .any(|secret| body.contains(secret))
+        );
+        let validate = ADMIN_CONFIG_VALIDATE_PATH;
+        let (status, _) = send(&router, "POST", validate, Some("view-token"), &body);
+        assert_eq!(status, StatusCode::OK);
+        for (method, path) in [("PUT", ADMIN_CONFIG_PATH), ("POST", ADMIN_STATS_RESET_PATH)] {
+            let (status, body) = send(&router, method, path, Some("view-token"), &config);
+            assert_eq!(status, StatusCode::FORBIDDEN, "{method} {path}");
+            assert_eq!(body, "admin_credential_is_read_only\n");
+        }
+
+        let (status, _) = send(
+            &router,
+            "POST",
+            ADMIN_STATS_RESET_PATH,
+            Some("ci-token"),
+            "",
+        );
+        assert_eq!(status, StatusCode::OK);
+        let basic = |credentials: &str| {
+            let encoded = base64::engine::general_purpose::STANDARD.encode(credentials);
+            call(
+                &router,
+                "POST",
+                ADMIN_STATS_RESET_PATH,
+                Some(&format!("Basic {encoded}")),
+                "",
+            )
+            .status()
+        };
+        assert_eq!(basic("ops:s3cret"), StatusCode::OK);
+        assert_eq!(basic("ops:wrong"), StatusCode::UNAUTHORIZED);
+
+        let (status, body) = send(&router, "GET", ADMIN_ROUTES_PATH, Some("pay-token"), "");
+        assert_eq!(status, StatusCode::OK, "{body}");
+    }
+
+    #[test]
+    fn audit_log_records_changes_with_their_principal() {
+        let dir = tempdir().expect("tempdir should be created");
+        let config_path = dir.path().join("Prx.toml");
+        let config = format!(
+            r#"{}
+[admin]
+audit_log = "audit.jsonl"
+
+[[admin.auth.token]]
+name = "ci"
+token = "ci-token"
+scope = "read_write"
+
+[[admin.auth.token]]
+name = "dashboards"
+token = "view-token"
+"#,
+            sample_config("127.0.0.1:8080")
+        );
+        fs::write(&config_path, &config).expect("seed config");
+        let runtime = Arc::new(ArcSwap::from_pointee(
+            RuntimeConfig::from_config(
+                PrxConfig::from_toml_str(&config).expect("seed config should be valid"),
+            )
+            .expect("runtime"),
+        ));
+        let router = build_router(AdminState {
+            config_admin: ConfigAdmin::new(config_path),
+            active_config: runtime,
+        });
+
+        let changed = config.replace("health_path = \"/healthz\"", "health_path = \"/health\"");
+        let (status, _) = send(
+            &router,
+            "PUT",
+            ADMIN_CONFIG_PATH,
+            Some("ci-token"),
+            &changed,
+        );
+        assert_eq!(status, StatusCode::OK);
+        let broken = changed.replace("service = \"default\"", "service = \"missing\"");
+        let (status, _) = send(&router, "PUT", ADMIN_CONFIG_PATH, Some("ci-token"), &broken);
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = send(
+            &router,
+            "DELETE",
+            "/web/routes/default",
+            Some("view-token"),
+            "",
+        );
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        send(&router, "GET", ADMIN_CONFIG_PATH, Some("view-token"), "");
+
+        let (status, body) = send(&router, "GET", "/web/audit?limit=2", Some("view-token"), "");
+        assert_eq!(status, StatusCode::OK);
+        let page: serde_json::Value = serde_json::from_str(&body).expect("audit json");
+        let entries = page["entries"].as_array().expect("entries");
+        assert_eq!(entries.len(), 2, "{body}");
+        assert_eq!(entries[0]["id"], 3);
+        assert_eq!(entries[0]["principal"], "token:dashboards");
+        assert_eq!(entries[0]["status"], 403);
+        assert_eq!(entries[1]["method"], "PUT");
+        assert!(
+            entries[1]["error"]
+                .as_str()
+                .is_some_and(|error| error.starts_with("invalid_config")),
+            "{body}"
+        );
+        assert_eq!(page["next_before"], 2);
+
+        let (_, body) = send(
+            &router,
+            "GET",
+            "/web/audit?before=2",
+            Some("view-token"),
+            "",
+        );
+        let page: serde_json::Value = serde_json::from_str(&body).expect("audit json");
+        let applied = &page["entries"][0];
+        assert_eq!(applied["principal"], "token:ci");
+        assert_eq!(applied["changes"], serde_json::json!(["[server] changed"]));
+        assert!(applied.get("error").is_none(), "{body}");
+        assert_eq!(page["next_before"], serde_json::Value::Null);
+    }
+
+    #[test]
+    fn config_rollback_restores_a_kept_version() {
+        let dir = tempdir().expect("tempdir should be created");
+        let config_path = dir.path().join("Prx.toml");
+        let first = sample_config("127.0.0.1:8080");
+        fs::write(&config_path, &first).expect("seed config");
+        let runtime = Arc::new(ArcSwap::from_pointee(
+            RuntimeConfig::from_config(
+                PrxConfig::from_toml_str(&first).expect("seed config should be valid"),
+            )
+            .expect("runtime"),
+        ));
+        let router = build_router(AdminState {
+            config_admin: ConfigAdmin::new(config_path.clone()),
+            active_config: runtime,
+        });
+
+        let (_, body) = send(&router, "GET", ADMIN_CONFIG_HISTORY_PATH, None, "");
+        assert_eq!(body, r#"{"versions":[]}"#);
+        for listen in ["127.0.0.1:8081", "127.0.0.1:8082"] {
+            let (status, _) = send(
+                &router,
+                "PUT",
+                ADMIN_CONFIG_PATH,
+                None,
+                &sample_config(listen),
+            );
+            assert_eq!(status, StatusCode::OK);
+        }
+        let versions = |router: &Router| -> Vec<u64> {
+            let (_, body) = send(router, "GET", ADMIN_CONFIG_HISTORY_PATH, None, "");
+            let payload: serde_json::Value = serde_json::from_str(&body).expect("history json");
+            payload["versions"]
+                .as_array()
+                .expect("versions")
+                .iter()
+                .map(|version| version["version"].as_u64().expect("version"))
+                .collect()
+        };
+        assert_eq!(versions(&router), [2, 1]);
+
+        let (status, body) = send(&router, "POST", "/web/config/rollback/1", None, "");
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(fs::read_to_string(&config_path).expect("config"), first);
+        assert_eq!(versions(&router), [3, 2, 1]);
+        assert_eq!(
+            fs::read_to_string(dir.path().join("Prx.toml.v3")).expect("version 3"),
+            sample_config("127.0.0.1:8082")
+        );
+
+        let (status, _) = send(&router, "POST", "/web/config/rollback/9", None, "");
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn drained_upstreams_leave_rotation_until_enabled() {
+        let dir = tempdir().expect("tempdir should be created");
+        let config_path = dir.path().join("Prx.toml");
+        let config = sample_config("127.0.0.1:8080").replace(
+            "[[route]]",
+            "[[service.upstream]]\naddr = \"127.0.0.1:9001\"\n\n[[route]]",
+        );
+        fs::write(&config_path, &config).expect("seed config");
+        let runtime = Arc::new(ArcSwap::from_pointee(
+            RuntimeConfig::from_config(
+                PrxConfig::from_toml_str(&config).expect("seed config should be valid"),
+            )
+            .expect("runtime"),
+        ));
+        let router = build_router(AdminState {
+            config_admin: ConfigAdmin::new(config_path),
+            active_config: runtime.clone(),
+        });
+        let drain = "/web/routes/default/upstreams/127.0.0.1:9000/drain";
+
+        let (status, body) = send(&router, "POST", drain, None, "");
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(
+            body,
+            r#"{"route":"default","addr":"127.0.0.1:9000","disabled":true,"services":["default"],"in_flight":0}"#
+        );
+        let snapshot = runtime.load();
+        let service = &snapshot.services()[0];
+        assert!(service.upstreams[0].is_disabled() && !service.upstreams[0].is_circuit_open());
+        for _ in 0..4 {
+            let (_, upstream) = service.next_upstream(0, &[]).expect("upstream");
+            assert_eq!(&*upstream.addr, "127.0.0.1:9001");
+        }
+        let (_, body) = send(&router, "GET", ADMIN_STATS_PATH, None, "");
+        assert!(
+            body.contains(r#""circuit_open":false,"disabled":true"#),
+            "{body}"
+        );
+
+        let unknown = "/web/routes/default/upstreams/127.0.0.1:9999/drain";
+        assert_eq!(
+            send(&router, "POST", unknown, None, "").0,
+            StatusCode::NOT_FOUND
+        );
+        let other = "/web/routes/missing/upstreams/127.0.0.1:9000/drain";
+        assert_eq!(
+            send(&router, "POST", other, None, "").0,
+            StatusCode::NOT_FOUND
+        );
+
+        let enable = "/web/routes/default/upstreams/127.0.0.1:9000/enable";
+        let (status, body) = send(&router, "POST", enable, None, "");
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains(r#""disabled":false"#), "{body}");
+        assert!(!service.upstreams[0].is_disabled());
+    }
+
+    #[test]
+    fn status_reports_live_upstream_state() {
+        let dir = tempdir().expect("tempdir should be created");
+        let config_path = dir.path().join("Prx.toml");
+        let config = sample_config("127.0.0.1:8080").replace(
+            "[[service.upstream]]\naddr = \"127.0.0.1:9000\"",
+            "[service.circuit_breaker]\nenabled = true\nconsecutive_failures = 1\n\n\
+             [[service.upstream]]\naddr = \"127.0.0.1:9000\"\n\n\
+             [[service.upstream]]\naddr = \"127.0.0.1:9001\"",
+        );
+        fs::write(&config_path, &config).expect("seed config");
+        let runtime = Arc::new(ArcSwap::from_pointee(
+            RuntimeConfig::from_config(
+                PrxConfig::from_toml_str(&config).expect("seed config should be valid"),
+            )
+            .expect("runtime"),
+        ));
+        let snapshot = runtime.load();
+        let service = &snapshot.services()[0];
+        service.mark_upstream_failure(0);
+        let _request = service.upstreams[1].start_request();
+        let router = build_router(AdminState {
+            config_admin: ConfigAdmin::new(config_path),
+            active_config: runtime.clone(),
+        });
+
+        let (status, body) = send(&router, "GET", ADMIN_STATUS_PATH, None, "");
+        assert_eq!(status, StatusCode::OK);
+        let payload: serde_json::Value = serde_json::from_str(&body).expect("status json");
+        assert_eq!(payload["routes"][0]["service"], "default");
+        let upstreams = &payload["services"][0]["upstreams"];
+        assert_eq!(upstreams[0]["circuit"], "open");
+        assert!(
+            upstreams[0]["circuit_open_ms"]
+                .as_u64()
+                .is_some_and(|left| left > 0)
+        );
+        assert!(
+            upstreams[0].get("last_selected_epoch_ms").is_none(),
+            "{body}"
+        );
+        assert_eq!(upstreams[1]["circuit"], "closed");
+        assert_eq!(upstreams[1]["in_flight"], 1);
+        assert!(
+            upstreams[1]["last_selected_epoch_ms"]
+                .as_u64()
+                .is_some_and(|at| at > 0)
+        );
+    }
+
+    #[test]
+    fn stats_reset_closes_circuits_and_returns_the_previous_snapshot() {
+        let dir = tempdir().expect("tempdir should be created");
+        let config_path = dir.path().join("Prx.toml");
+        let config = sample_config("127.0.0.1:8080").replace(
+            "[[service.upstream]]",
+            "[service.circuit_breaker]\nenabled = true\nconsecutive_failures = 1\n\n[[service.upstream]]",
+        );
+        fs::write(&config_path, &config).expect("seed config");
+        let runtime = Arc::new(ArcSwap::from_pointee(
+            RuntimeConfig::from_config(
+                PrxConfig::from_toml_str(&config).expect("seed config should be valid"),
+            )
+            .expect("runtime"),
+        ));
+        runtime.load().services()[0].mark_upstream_failure(0);
+        let router = build_router(AdminState {
+            config_admin: ConfigAdmin::new(config_path),
+            active_config: runtime.clone(),
+        });
+
+        let (status, body) = send(&router, "GET", ADMIN_STATS_PATH, None, "");
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains("\"circuit_open\":true"), "{body}");
+
+        let (status, body) = send(&router, "POST", ADMIN_STATS_RESET_PATH, None, "");
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains("\"circuit_open\":true"), "{body}");
+        assert!(!runtime.load().services()[0].upstreams[0].is_circuit_open());
+    }
+
+    #[test]
+    fn config_validate_lists_every_problem_without_applying() {
+        let dir = tempdir().expect("tempdir should be created");
+        let config_path = dir.path().join("Prx.toml");
+        let config = sample_config("127.0.0.1:8080");
+        fs::write(&config_path, &config).expect("seed config");
+        let router = build_router(AdminState {
+            config_admin: ConfigAdmin::new(config_path.clone()),
+            active_config: Arc::new(ArcSwap::from_pointee(
+                RuntimeConfig::from_config(
+                    PrxConfig::from_toml_str(&config).expect("seed config should be valid"),
+                )
+                .expect("runtime"),
+            )),
+        });
+
+        let (status, body) = send(&router, "POST", ADMIN_CONFIG_VALIDATE_PATH, None, &config);
+        assert_eq!(status, StatusCode::OK);
+        let payload: serde_json::Value = serde_json::from_str(&body).expect("json");
+        assert_eq!(payload["valid"], true);
+        assert_eq!(payload["diff"]["sections"], serde_json::json!([]));
+        assert_eq!(payload["diff"]["routes"]["changed"], serde_json::json!([]));
+
+        let draft = config
+            .replace("health_path = \"/healthz\"", "health_path = \"healthz\"")
+            .replace("service = \"default\"", "service = \"missing\"");
+        let (status, body) = send(&router, "POST", ADMIN_CONFIG_VALIDATE_PATH, None, &draft);
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        let payload: serde_json::Value = serde_json::from_str(&body).expect("json");
+        let fields: Vec<_> = payload["problems"]
+            .as_array()
+            .expect("problems")
+            .iter()
+            .map(|problem| (problem["field"].as_str(), problem["code"].as_str()))
+            .collect();
+        assert_eq!(
+            fields,
+            [
+                (Some("server.health_path"), Some("invalid")),
+                (Some("route[0].service"), Some("unknown_reference")),
+            ]
+        );
+        assert_eq!(payload["diff"]["sections"], serde_json::json!(["server"]));
+        assert_eq!(
+            payload["diff"]["routes"]["changed"],
+            serde_json::json!(["default"])
+        );
+        assert_eq!(fs::read_to_string(&config_path).expect("config"), config);
+
+        let renamed = config.replace("name = \"default\"\nservice", "name = \"web\"\nservice");
+        let (_, body) = send(&router, "POST", ADMIN_CONFIG_VALIDATE_PATH, None, &renamed);
+        let payload: serde_json::Value = serde_json::from_str(&body).expect("json");
+        assert_eq!(
+            payload["diff"]["routes"],
+            serde_json::json!({"added": ["web"], "removed": ["default"], "changed": []})
+        );
+
+        let (status, body) = send(
+            &router,
+            "POST",
+            ADMIN_CONFIG_VALIDATE_PATH,
+            None,
+            "[[route]",
+        );
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert!(body.contains(r#""code":"syntax""#), "{body}");
+        assert!(!body.contains("diff"), "{body}");
+
+        let put_json = format!("{ADMIN_CONFIG_PATH}?format=json");
+        let (status, body) = send(&router, "PUT", &put_json, None, &draft);
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let payload: serde_json::Value = serde_json::from_str(&body).expect("json");
+        assert_eq!(payload["problems"].as_array().map(Vec::len), Some(2));
+        assert_eq!(fs::read_to_string(&config_path).expect("config"), config);
+
+        let legacy = "[[service]]\nname = \"legacy\"\n\n\
+                      [[service.upstream]]\naddr = \"127.0.0.1:9001\"\n\n[[route]]";
+        let unused = config.replace("[[route]]", legacy);
+        let (status, body) = send(&router, "PUT", ADMIN_CONFIG_PATH, None, &unused);
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body,
+            "config_applied\nwarning: service 'legacy' is not used by any route\n"
+        );
+        let (status, body) = send(&router, "PUT", &put_json, None, &unused);
+        assert_eq!(status, StatusCode::OK);
+        let payload: serde_json::Value = serde_json::from_str(&body).expect("json");
+        assert_eq!(payload["valid"], true);
+        assert_eq!(payload["warnings"][0]["field"], "service[1]");
+        assert_eq!(payload["warnings"][0]["code"], "unused");
+    }
+
+    #[test]
+    fn cache_purge_drops_matching_preflight_answers_of_a_route() {
+        let dir = tempdir().expect("tempdir should be created");
+        let config_path = dir.path().join("Prx.toml");
+        let config = format!(
+            "{}\n[route.preflight_cache]\n",
+            sample_config("127.0.0.1:8080")
+        );
+        fs::write(&config_path, &config).expect("seed config");
+        let runtime = Arc::new(ArcSwap::from_pointee(
+            RuntimeConfig::from_config(
+                PrxConfig::from_toml_str(&config).expect("seed config should be valid"),
+            )
+            .expect("runtime"),
+        ));
+        let snapshot = runtime.load_full();
+        let cache = snapshot.routes()[0]
+            .preflight_cache
+            .as_ref()
+            .expect("preflight cache");
+        for path in ["/assets/app.js", "/api/items"] {
+            let mut request =
+                RequestHeader::build("OPTIONS", path.as_bytes(), None).expect("request");
+            request
+                .insert_header("origin", "https://a.example")
+                .expect("origin");
+            request
+                .insert_header("access-control-request-method", "PUT")
+                .expect("method");
+            let response = ResponseHeader::build(204, None).expect("response");
+            cache.insert(PreflightCache::key(&request).expect("preflight"), &response);
+        }
+        let router = build_router(AdminState {
+            config_admin: ConfigAdmin::new(config_path),
+            active_config: runtime,
+        });
+
+        let (status, body) = send(
+            &router,
+            "DELETE",
+            "/web/cache?route=default&path=/assets/*",
+            None,
+            "",
+        );
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, r#"{"route":"default","purged":1}"#);
+        let (status, body) = send(&router, "DELETE", "/web/cache?route=default", None, "");
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains("\"purged\":1"), "{body}");
+
+        let (status, _) = send(&router, "DELETE", "/web/cache?route=missing", None, "");
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = send(&router, "DELETE", ADMIN_CACHE_PATH, None, "");
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn state_import_restores_exported_circuits() {
+        let dir = tempdir().expect("tempdir should be created");
+        let config_path = dir.path().join("Prx.toml");
+        let config = sample_config("127.0.0.1:8080").replace(
+            "[[service.upstream]]",
+            "[service.circuit_breaker]\nenabled = true\nconsecutive_failures = 1\n\n[[service.upstream]]",
+        );
+        fs::write(&config_path, &config).expect("seed config");
+        let runtime = || {
+            Arc::new(ArcSwap::from_pointee(
+                RuntimeConfig::from_config(
+                    PrxConfig::from_toml_str(&config).expect("seed config should be valid"),
+                )
+                .expect("runtime"),
+            ))
+        };
+        let (old, new) = (runtime(), runtime());
+        old.load().services()[0].mark_upstream_failure(0);
+        let router = |active_config| {
+            build_router(AdminState {
+                config_admin: ConfigAdmin::new(config_path.clone()),
+                active_config,
+            })
+        };
+
+        let (status, exported) = send(&router(old), "GET", ADMIN_STATE_EXPORT_PATH, None, "");
+        assert_eq!(status, StatusCode::OK);
+        let exported = exported.replace(
+            "\"upstreams\":[",
+            "\"upstreams\":[{\"addr\":\"10.0.0.9:80\",\"healthy\":false},",
+        );
+        let (status, body) = send(
+            &router(new.clone()),
+            "POST",
+            ADMIN_STATE_IMPORT_PATH,
+            None,
+            &exported,
+        );
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert!(
+            body.contains("\"restored\":1") && body.contains("default/10.0.0.9:80"),
+            "{body}"
+        );
+        assert!(new.load().services()[0].upstreams[0].is_circuit_open());
+    }
+
     #[test]
     fn atomic_replace_overwrites_target() {
         let dir = tempdir().expect("tempdir should be created");
@@ -1658,9 +4247,9 @@ is_default = true
         fs::write(&config_path, &current).expect("seed config");
         let current_parsed =
             PrxConfig::from_file(&config_path).expect("seed config should be valid");
-        let runtime = Arc::new(ArcSwap::from_pointee(RuntimeConfig::from_config(
-            current_parsed,
-        )));
+        let runtime = Arc::new(ArcSwap::from_pointee(
+            RuntimeConfig::from_config(current_parsed).expect("runtime"),
+        ));
 
         let next = sample_config("127.0.0.1:8081");
         PrxConfig::from_toml_str(&next).expect("next config should be valid");
@@ -1673,4 +4262,4 @@ is_default = true
         let content = fs::read_to_string(&config_path).expect("config should be readable");
         assert_eq!(content, next);
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,125 @@
+use base64::{Engine, engine::general_purpose::STANDARD};
+
+use crate::config::{AdminAccess, AdminAuthConfig};
+
+/// `[admin.auth]` with its secrets revealed, as admin requests are checked
+/// against it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AdminAuth {
+    tokens: Vec<Credential>,
+    // Secrets are `username:password`, as a Basic credential decodes.
+    basic: Vec<Credential>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Credential {
+    secret: String,
+    principal: String,
+    access: AdminAccess,
+}
+
+impl AdminAuth {
+    pub fn from_config(config: &AdminAuthConfig) -> Self {
+        Self {
+            tokens: config
+                .tokens
+                .iter()
+                .map(|token| Credential {
+                    secret: token.token.clone(),
+                    principal: format!("token:{}", token.name),
+                    access: token.scope,
+                })
+                .collect(),
+            basic: config
+                .basic
+                .iter()
+                .map(|basic| Credential {
+                    secret: format!("{}:{}", basic.username, basic.password),
+                    principal: format!("basic:{}", basic.username),
+                    access: basic.scope,
+                })
+                .collect(),
+        }
+    }
+
+    /// Who an `Authorization` header value names (`token:<name>` or
+    /// `basic:<username>`) and what it grants; `None` when it carries no
+    /// configured credential.
+    pub fn access(&self, authorization: &str) -> Option<(&str, AdminAccess)> {
+        let (scheme, credential) = authorization.trim().split_once(' ')?;
+        let credential = credential.trim();
+        let (known, candidate) = if scheme.eq_ignore_ascii_case("bearer") {
+            (&self.tokens, credential.as_bytes().to_vec())
+        } else if scheme.eq_ignore_ascii_case("basic") {
+            (&self.basic, STANDARD.decode(credential).ok()?)
+        } else {
+            return None;
+        };
+        known
+            .iter()
+            .find(|known| tokens_match(known.secret.as_bytes(), &candidate))
+            .map(|known| (known.principal.as_str(), known.access))
+    }
+
+    /// `WWW-Authenticate` for a `401`; Basic makes browsers prompt on the WebUI.
+    pub fn challenge(&self) -> &'static str {
+        if self.basic.is_empty() {
+            "Bearer realm=\"prx admin\""
+        } else {
+            "Basic realm=\"prx admin\""
+        }
+    }
+}
+
+/// Compares without an early exit so response timing does not leak a token prefix.
+pub fn tokens_match(expected: &[u8], candidate: &[u8]) -> bool {
+    expected.len() == candidate.len()
+        && expected
+            .iter()
+            .zip(candidate)
+            .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{AdminBasicConfig, AdminTokenConfig};
+
+    #[test]
+    fn grants_the_scope_of_the_matching_credential() {
+        let auth = AdminAuth::from_config(&AdminAuthConfig {
+            tokens: vec![AdminTokenConfig {
+                name: "ci".to_string(),
+                token: "ci-token".to_string(),
+                scope: AdminAccess::ReadWrite,
+            }],
+            basic: vec![AdminBasicConfig {
+                username: "ops".to_string(),
+                password: "s3cret".to_string(),
+                scope: AdminAccess::ReadOnly,
+            }],
+        });
+
+        let ci = Some(("token:ci", AdminAccess::ReadWrite));
+        assert_eq!(auth.access("Bearer ci-token"), ci);
+        assert_eq!(auth.access("bearer ci-token"), ci);
+        let basic = format!("Basic {}", STANDARD.encode("ops:s3cret"));
+        assert_eq!(
+            auth.access(&basic),
+            Some(("basic:ops", AdminAccess::ReadOnly))
+        );
+
+        assert_eq!(auth.access("Bearer ci-tok"), None);
+        assert_eq!(
+            auth.access(&format!("Basic {}", STANDARD.encode("ops:wrong"))),
+            None
+        );
+        assert_eq!(
+            auth.access(&format!("Bearer {}", STANDARD.encode("ops:s3cret"))),
+            None
+        );
+        assert_eq!(auth.access("Basic not-base64!"), None);
+        assert_eq!(auth.challenge(), "Basic realm=\"prx admin\"");
+    }
+}
@@ -0,0 +1,223 @@
+use async_trait::async_trait;
+use pingora::services::Service;
+use tracing::{info, warn};
+
+/// Wraps a pingora service so its runtime threads are pinned to `cpus` once
+/// the service starts.
+///
+/// Pingora names every runtime thread after the service, so the threads are
+/// found by name; tokio spawns all workers before the service is started and
+/// later blocking threads inherit the mask from the worker that spawns them.
+pub struct PinnedService<S> {
+    inner: S,
+    cpus: Vec<usize>,
+}
+
+impl<S: Service> PinnedService<S> {
+    pub fn new(inner: S, cpus: Vec<usize>) -> Self {
+        Self { inner, cpus }
+    }
+}
+
+#[async_trait]
+impl<S: Service> Service for PinnedService<S> {
+    async fn start_service(
+        &mut self,
+        #[cfg(unix)] fds: Option<pingora::server::ListenFds>,
+        shutdown: pingora::server::ShutdownWatch,
+        listeners_per_fd: usize,
+    ) {
+        if !self.cpus.is_empty() {
+            match pin_threads_named(self.inner.name(), &self.cpus) {
+                Ok(pinned) => info!(
+                    service = self.inner.name(),
+                    cpus = ?self.cpus,
+                    threads = pinned,
+                    "pinned service threads"
+                ),
+                Err(err) => warn!(
+                    service = self.inner.name(),
+                    error = %err,
+                    "failed to pin service threads"
+                ),
+            }
+        }
+        self.inner
+            .start_service(
+                #[cfg(unix)]
+                fds,
+                shutdown,
+                listeners_per_fd,
+            )
+            .await
+    }
+
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn threads(&self) -> Option<usize> {
+        self.inner.threads()
+    }
+}
+
+/// Runs `spawn` with the calling thread temporarily pinned to `cpus`, so the
+/// threads it creates inherit that mask.
+pub fn with_inherited_affinity<T>(cpus: &[usize], spawn: impl FnOnce() -> T) -> T {
+    if cpus.is_empty() {
+        return spawn();
+    }
+    let previous = match imp::current_mask() {
+        Ok(mask) => mask,
+        Err(err) => {
+            warn!(error = %err, "failed to read thread affinity; background threads stay unpinned");
+            return spawn();
+        }
+    };
+    if let Err(err) = imp::set_mask(0, &imp::mask_for(cpus)) {
+        warn!(error = %err, "failed to pin background threads");
+        return spawn();
+    }
+    let spawned = spawn();
+    if let Err(err) = imp::set_mask(0, &previous) {
+        warn!(error = %err, "failed to restore thread affinity");
+    }
+    spawned
+}
+
+fn pin_threads_named(name: &str, cpus: &[usize]) -> anyhow::Result<usize> {
+    let mask = imp::mask_for(cpus);
+    let mut pinned = 0;
+    for tid in imp::threads_named(name)? {
+        imp::set_mask(tid, &mask)?;
+        pinned += 1;
+    }
+    Ok(pinned)
+}
+
+#[cfg(target_os = "linux")]
+mod imp {
+    use std::{fs, io, mem};
+
+    use anyhow::Context;
+
+    pub type Mask = libc::cpu_set_t;
+
+    pub fn mask_for(cpus: &[usize]) -> Mask {
+        // SAFETY: cpu_set_t is a plain bitset; all-zero is the empty set.
+        let mut mask: Mask = unsafe { mem::zeroed() };
+        for &cpu in cpus {
+            // SAFETY: `cpu` is validated to be below CPU_SETSIZE at config load.
+            unsafe { libc::CPU_SET(cpu, &mut mask) };
+        }
+        mask
+    }
+
+    pub fn current_mask() -> io::Result<Mask> {
+        let mut mask = mask_for(&[]);
+        // SAFETY: the pointer and size describe a valid cpu_set_t.
+        let rc = unsafe { libc::sched_getaffinity(0, mem::size_of::<Mask>(), &mut mask) };
+        if rc != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(mask)
+    }
+
+    pub fn set_mask(tid: libc::pid_t, mask: &Mask) -> io::Result<()> {
+        // SAFETY: the pointer and size describe a valid cpu_set_t.
+        let rc = unsafe { libc::sched_setaffinity(tid, mem::size_of::<Mask>(), mask) };
+        if rc != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    pub fn threads_named(name: &str) -> anyhow::Result<Vec<libc::pid_t>> {
+        // Linux truncates thread names to 15 bytes.
+        let comm = &name.as_bytes()[..name.len().min(15)];
+        let mut tids = Vec::new();
+        for entry in fs::read_dir("/proc/self/task").context("failed to list /proc/self/task")? {
+            let entry = entry.context("failed to read /proc/self/task entry")?;
+            let Some(tid) = entry
+                .file_name()
+                .to_str()
+                .and_then(|tid| tid.parse::<libc::pid_t>().ok())
+            else {
+                continue;
+            };
+            let Ok(thread_name) = fs::read(entry.path().join("comm")) else {
+                continue;
+            };
+            if thread_name.strip_suffix(b"\n").unwrap_or(&thread_name) == comm {
+                tids.push(tid);
+            }
+        }
+        Ok(tids)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod imp {
+    use std::io;
+
+    pub struct Mask;
+
+    pub fn mask_for(_cpus: &[usize]) -> Mask {
+        Mask
+    }
+
+    pub fn current_mask() -> io::Result<Mask> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "CPU affinity is only supported on Linux",
+        ))
+    }
+
+    pub fn set_mask(_tid: i32, _mask: &Mask) -> io::Result<()> {
+        current_mask().map(|_| ())
+    }
+
+    pub fn threads_named(_name: &str) -> anyhow::Result<Vec<i32>> {
+        Ok(current_mask().map(|_| Vec::new())?)
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn spawned_threads_inherit_affinity_and_caller_is_restored() {
+        let before = imp::current_mask().expect("read mask");
+        let child = with_inherited_affinity(&[0], || {
+            thread::spawn(|| imp::current_mask().expect("read child mask"))
+        })
+        .join()
+        .expect("join child");
+
+        assert!(unsafe { libc::CPU_ISSET(0, &child) });
+        assert_eq!(unsafe { libc::CPU_COUNT(&child) }, 1);
+        assert!(unsafe { libc::CPU_EQUAL(&before, &imp::current_mask().expect("read mask")) });
+    }
+
+    #[test]
+    fn finds_threads_by_truncated_name() {
+        let (ready_tx, ready_rx) = std::sync::mpsc::channel::<()>();
+        let (tx, rx) = std::sync::mpsc::channel::<()>();
+        let handle = thread::Builder::new()
+            .name("prx-affinity-test-thread".to_string())
+            .spawn(move || {
+                let _ = ready_tx.send(());
+                let _ = rx.recv();
+            })
+            .expect("spawn");
+        ready_rx.recv().expect("thread started");
+
+        let tids = imp::threads_named("prx-affinity-test-thread").expect("list threads");
+        assert_eq!(tids.len(), 1);
+        tx.send(()).expect("release thread");
+        handle.join().expect("join");
+    }
+}
@@ -0,0 +1,170 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+/// Longest `error` kept per entry.
+const MAX_ERROR_LEN: usize = 1024;
+
+// Keeps lines of concurrent admin requests from interleaving.
+static APPEND: Mutex<()> = Mutex::new(());
+
+/// One admin request that may have changed something.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub at_epoch_ms: u64,
+    /// Peer address of the admin connection, when known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    /// `token:<name>`, `basic:<username>`, `tenant:<name>` or `anonymous`.
+    pub principal: String,
+    pub method: String,
+    pub path: String,
+    pub status: u16,
+    /// What the request changed in the config, e.g. `route 'api' changed`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub changes: Vec<String>,
+    /// Why a request failed, from its response.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// An entry with its line number in the log, for paging.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AuditRecord {
+    pub id: usize,
+    #[serde(flatten)]
+    pub entry: AuditEntry,
+}
+
+/// The append-only file at `admin.audit_log`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditLog {
+    path: PathBuf,
+}
+
+impl AuditLog {
+    /// `configured` is relative to the directory of the config file.
+    pub fn at(config_path: &Path, configured: &str) -> Self {
+        let dir = config_path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        Self {
+            path: dir.join(configured),
+        }
+    }
+
+    pub fn append(&self, entry: &AuditEntry) -> anyhow::Result<()> {
+        let mut line = serde_json::to_string(entry).context("failed to encode audit entry")?;
+        line.push('\n');
+        let _guard = APPEND
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .with_context(|| format!("failed to open audit log {}", self.path.display()))?;
+        file.write_all(line.as_bytes())
+            .and_then(|()| file.sync_data())
+            .with_context(|| format!("failed to append to audit log {}", self.path.display()))
+    }
+
+    /// Up to `limit` entries older than `before` (all when `None`), newest
+    /// first. Lines that do not parse are skipped.
+    pub fn page(&self, before: Option<usize>, limit: usize) -> anyhow::Result<Vec<AuditRecord>> {
+        let content = match fs::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => {
+                return Err(err)
+                    .with_context(|| format!("failed to read audit log {}", self.path.display()));
+            }
+        };
+        let lines: Vec<&str> = content.lines().collect();
+        let end = before.map_or(lines.len(), |before| {
+            before.saturating_sub(1).min(lines.len())
+        });
+        Ok(lines[..end]
+            .iter()
+            .enumerate()
+            .rev()
+            .filter_map(|(index, line)| {
+                let entry = serde_json::from_str(line).ok()?;
+                Some(AuditRecord {
+                    id: index + 1,
+                    entry,
+                })
+            })
+            .take(limit)
+            .collect())
+    }
+}
+
+/// Cuts a failed response's body down to what is worth keeping.
+pub fn error_summary(body: &[u8]) -> Option<String> {
+    let text = String::from_utf8_lossy(body);
+    let text = text.trim();
+    if text.is_empty() {
+        return None;
+    }
+    let mut end = text.len().min(MAX_ERROR_LEN);
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    Some(text[..end].to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(path: &str) -> AuditEntry {
+        AuditEntry {
+            at_epoch_ms: 1,
+            source: None,
+            principal: "anonymous".to_string(),
+            method: "PUT".to_string(),
+            path: path.to_string(),
+            status: 200,
+            changes: Vec::new(),
+            error: None,
+        }
+    }
+
+    #[test]
+    fn pages_newest_first() {
+        let dir = tempfile::tempdir().expect("tempdir");
+        let log = AuditLog::at(&dir.path().join("Prx.toml"), "audit.jsonl");
+        assert!(log.page(None, 10).expect("missing log").is_empty());
+        for path in ["/a", "/b", "/c"] {
+            log.append(&entry(path)).expect("append");
+        }
+
+        let ids = |records: Vec<AuditRecord>| -> Vec<_> {
+            records
+                .iter()
+                .map(|record| (record.id, record.entry.path.clone()))
+                .collect()
+        };
+        assert_eq!(
+            ids(log.page(None, 2).expect("page")),
+            [(3, "/c".to_string()), (2, "/b".to_string())]
+        );
+        assert_eq!(
+            ids(log.page(Some(2), 2).expect("page")),
+            [(1, "/a".to_string())]
+        );
+
+        assert_eq!(
+            error_summary(b"  invalid_config: x\n").as_deref(),
+            Some("invalid_config: x")
+        );
+    }
+}
@@ -0,0 +1,159 @@
+use std::{fs, net::IpAddr, path::Path, sync::Arc, time::Duration};
+
+use anyhow::Context;
+use arc_swap::ArcSwap;
+use tracing::{error, info};
+
+use crate::{acl::Cidr, reload::spawn_file_watcher};
+
+/// Client networks and request paths denied on every listener, read from
+/// `server.blocklist_path` and swapped whenever that file changes.
+#[derive(Debug, Default)]
+pub struct Blocklist {
+    cidrs: Vec<Cidr>,
+    paths: Vec<String>,
+}
+
+/// Why a request was blocked, also the `reason` of its rejection metric.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Blocked {
+    Ip,
+    Path,
+}
+
+impl Blocked {
+    pub fn reason(self) -> &'static str {
+        match self {
+            Self::Ip => "blocklist_ip",
+            Self::Path => "blocklist_path",
+        }
+    }
+}
+
+impl Blocklist {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("failed to read blocklist file {}", path.to_string_lossy()))?;
+        Self::parse(&text)
+            .with_context(|| format!("invalid blocklist file {}", path.to_string_lossy()))
+    }
+
+    /// One entry per line: an IP address or CIDR, or a path pattern starting
+    /// with `/` in which `*` matches any run of characters. Blank lines and
+    /// `#` comments are skipped.
+    pub fn parse(text: &str) -> anyhow::Result<Self> {
+        let mut blocklist = Self::default();
+        for (number, line) in text.lines().enumerate() {
+            let entry = line.split('#').next().unwrap_or_default().trim();
+            if entry.is_empty() {
+                continue;
+            }
+            if entry.starts_with('/') {
+                blocklist.paths.push(entry.to_string());
+            } else {
+                blocklist.cidrs.push(
+                    entry
+                        .parse()
+                        .with_context(|| format!("line {}", number + 1))?,
+                );
+            }
+        }
+        Ok(blocklist)
+    }
+
+    pub fn len(&self) -> usize {
+        self.cidrs.len() + self.paths.len()
+    }
+
+    pub fn check(&self, client_ip: Option<IpAddr>, path: &str) -> Option<Blocked> {
+        if client_ip.is_some_and(|ip| self.cidrs.iter().any(|cidr| cidr.contains(ip))) {
+            return Some(Blocked::Ip);
+        }
+        self.paths
+            .iter()
+            .any(|pattern| glob_matches(pattern, path))
+            .then_some(Blocked::Path)
+    }
+}
+
+/// Reloads the blocklist on every change to `path`. A file that fails to
+/// parse keeps the previous list in place.
+pub fn spawn_blocklist_watcher(
+    path: &Path,
+    debounce: Duration,
+    blocklist: Arc<ArcSwap<Blocklist>>,
+) -> anyhow::Result<()> {
+    let path = path.to_path_buf();
+    spawn_file_watcher("prx-blocklist-watcher", path.clone(), debounce, move || {
+        match Blocklist::load(&path) {
+            Ok(next) => {
+                info!(
+                    file = %path.to_string_lossy(),
+                    entries = next.len(),
+                    "reloaded blocklist"
+                );
+                blocklist.store(Arc::new(next));
+            }
+            Err(err) => {
+                error!(
+                    error = format!("{err:#}"),
+                    file = %path.to_string_lossy(),
+                    "failed to reload blocklist, keeping previous version"
+                );
+            }
+        }
+    })
+}
+
+fn glob_matches(pattern: &str, value: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = value.strip_prefix(first) else {
+        return false;
+    };
+    let mut parts = parts.peekable();
+    while let Some(part) = parts.next() {
+        if parts.peek().is_none() {
+            // The last part is anchored at the end; a trailing `*` is empty
+            // and matches anything left.
+            return rest.ends_with(part);
+        }
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocks_listed_networks_and_path_patterns() {
+        let blocklist = Blocklist::parse(
+            "# scanners\n203.0.113.0/24\n2001:db8::1\n\n/wp-login.php\n/admin/*  # old panel\n/*.env\n",
+        )
+        .expect("blocklist");
+        assert_eq!(blocklist.len(), 5);
+
+        let ip = |value: &str| Some(value.parse::<IpAddr>().expect("ip"));
+        assert_eq!(blocklist.check(ip("203.0.113.9"), "/"), Some(Blocked::Ip));
+        assert_eq!(blocklist.check(ip("2001:db8::1"), "/"), Some(Blocked::Ip));
+        assert_eq!(blocklist.check(ip("198.51.100.1"), "/"), None);
+
+        assert_eq!(blocklist.check(None, "/wp-login.php"), Some(Blocked::Path));
+        assert_eq!(blocklist.check(None, "/wp-login.php/x"), None);
+        assert_eq!(blocklist.check(None, "/admin/users"), Some(Blocked::Path));
+        assert_eq!(blocklist.check(None, "/admin"), None);
+        assert_eq!(blocklist.check(None, "/app/.env"), Some(Blocked::Path));
+        assert_eq!(blocklist.check(None, "/app/.envoy"), None);
+    }
+
+    #[test]
+    fn reports_the_line_of_an_invalid_entry() {
+        let err = Blocklist::parse("10.0.0.0/8\nnot-an-ip\n").expect_err("invalid");
+        assert!(format!("{err:#}").contains("line 2"));
+    }
+}
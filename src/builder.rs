@@ -0,0 +1,696 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{Context, bail};
+use arc_swap::ArcSwap;
+use pingora::{
+    apps::{HttpServerOptions, ServerApp},
+    listeners::{TcpSocketOptions, tls::TlsSettings},
+    prelude::*,
+    protocols::TcpKeepalive,
+    proxy::http_proxy,
+    services::listening::Service,
+};
+use tracing::info;
+use tracing_subscriber::EnvFilter;
+
+#[cfg(windows)]
+use pingora::server::{RunArgs, ShutdownSignalWatch};
+
+#[cfg(unix)]
+use crate::shared_state::{SharedState, spawn_breaker_sync};
+use crate::{
+    acl::{AccessList, ConnectionAcl},
+    admin::{AdminAxumService, AdminProbes, bind_admin_listener},
+    affinity::{PinnedService, with_inherited_affinity},
+    blocklist::{Blocklist, spawn_blocklist_watcher},
+    config::{
+        ProxyProtocolVersion, PrxConfig, RouteConfig, ServerConfig, ServiceConfig, TlsConfig,
+    },
+    discovery::spawn_resolver,
+    drain::spawn_drain_watcher,
+    health::spawn_health_checker,
+    load, memory,
+    proxy::{DownstreamLimits, PrxProxy},
+    proxy_protocol::ProxyProtocolApp,
+    reload::spawn_config_watcher,
+    reputation::{Reputation, ReputationLookup},
+    runtime::{RebuildStats, RuntimeConfig, spawn_coarse_clock},
+    source::{BootstrapOutcome, RemoteSource, spawn_remote_poller},
+    strict::StrictHttp,
+    tcp::TcpProxy,
+};
+
+/// Builds an embeddable prx server.
+///
+/// A builder made with [`PrxBuilder::from_file`] behaves like the binary: the
+/// file is watched for changes and the admin API (if enabled) rewrites it.
+/// With [`PrxBuilder::from_config`] the config only changes through
+/// [`PrxHandle::update`].
+pub struct PrxBuilder {
+    config: PrxConfig,
+    config_path: Option<PathBuf>,
+    admin_listen: Option<String>,
+    pingora_opt: Option<Opt>,
+    remote_source: Option<(RemoteSource, Option<BootstrapOutcome>)>,
+    reputation_lookup: Option<Arc<dyn ReputationLookup>>,
+    #[cfg(windows)]
+    event_log: bool,
+}
+
+impl PrxBuilder {
+    pub fn from_config(config: PrxConfig) -> Self {
+        Self {
+            config,
+            config_path: None,
+            admin_listen: None,
+            pingora_opt: None,
+            remote_source: None,
+            reputation_lookup: None,
+            #[cfg(windows)]
+            event_log: false,
+        }
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let mut builder = Self::from_config(PrxConfig::from_file(path)?);
+        builder.config_path = Some(path.to_path_buf());
+        Ok(builder)
+    }
+
+    pub fn with_service(mut self, service: ServiceConfig) -> Self {
+        self.config.services.push(service);
+        self
+    }
+
+    /// Adds a route on top of the loaded config. It is not written back to the
+    /// config file, so a file reload drops it.
+    pub fn with_route(mut self, route: RouteConfig) -> Self {
+        self.config.routes.push(route);
+        self
+    }
+
+    /// Serves the admin API on `listen`; requires a builder made with
+    /// [`PrxBuilder::from_file`].
+    pub fn with_admin(mut self, listen: impl Into<String>) -> Self {
+        self.admin_listen = Some(listen.into());
+        self
+    }
+
+    /// Pingora process options (daemon, upgrade, conf file). Defaults to
+    /// [`Opt::default`], so the host binary's arguments are left alone.
+    pub fn with_pingora_opt(mut self, opt: Opt) -> Self {
+        self.pingora_opt = Some(opt);
+        self
+    }
+
+    /// Consults `lookup` for client IP verdicts instead of the `http` or
+    /// `redis` lookup of `[reputation]`, which must still be present.
+    pub fn with_reputation_lookup(mut self, lookup: Arc<dyn ReputationLookup>) -> Self {
+        self.reputation_lookup = Some(lookup);
+        self
+    }
+
+    /// Logs to the Windows Event Log instead of stdout.
+    #[cfg(windows)]
+    pub fn with_event_log(mut self) -> Self {
+        self.event_log = true;
+        self
+    }
+
+    pub(crate) fn with_remote_source(
+        mut self,
+        source: RemoteSource,
+        bootstrap: Option<BootstrapOutcome>,
+    ) -> Self {
+        self.remote_source = Some((source, bootstrap));
+        self
+    }
+
+    /// Validates the config and sets up every pingora service and background
+    /// thread, without serving yet.
+    pub fn build(self) -> anyhow::Result<Prx> {
+        let app_config = self.config;
+        app_config.validate()?;
+        #[cfg(windows)]
+        if self.event_log {
+            crate::event_log::init(tracing_filter(&app_config.observability.log_level))
+                .context("failed to open the Windows Event Log")?;
+        }
+        init_tracing(&app_config.observability.log_level);
+        if let Some((source, Some(outcome))) = &self.remote_source {
+            outcome.log(source);
+        }
+
+        let mut server =
+            Server::new(self.pingora_opt).context("failed to initialize pingora server")?;
+        tune_pingora_server(&mut server, &app_config);
+        server.bootstrap();
+
+        let runtime_config = Arc::new(ArcSwap::from_pointee(RuntimeConfig::from_config(
+            app_config.clone(),
+        )?));
+
+        let blocklist = match &app_config.server.blocklist_path {
+            Some(path) => Some(Arc::new(ArcSwap::from_pointee(Blocklist::load(
+                Path::new(path),
+            )?))),
+            None => None,
+        };
+        #[cfg(unix)]
+        let shared_state = match &app_config.server.shared_state_socket {
+            Some(socket) => Some(
+                SharedState::start(Path::new(socket))
+                    .with_context(|| format!("failed to start shared state on {socket}"))?,
+            ),
+            None => None,
+        };
+        let reputation = match &app_config.reputation {
+            Some(config) => Some(Arc::new(Reputation::from_config(
+                config,
+                self.reputation_lookup.clone(),
+            )?)),
+            None => None,
+        };
+        let new_proxy = || {
+            let proxy = PrxProxy::new(
+                runtime_config.clone(),
+                app_config.server.health_path.clone(),
+                app_config.server.ready_path.clone(),
+            )
+            .with_load_path(app_config.server.load_path.clone())
+            .with_upstream_write_buffer(app_config.server.upstream_write_buffer_bytes)
+            .with_downstream_limits(DownstreamLimits::from_config(&app_config.server))
+            .with_strict_http(
+                app_config
+                    .server
+                    .strict_http
+                    .as_ref()
+                    .map(StrictHttp::from_config),
+            )
+            .with_blocklist(blocklist.clone())
+            .with_reputation(reputation.clone());
+            #[cfg(unix)]
+            let proxy = proxy.with_shared_state(shared_state.clone());
+            proxy
+        };
+        let probes = &app_config.server.probes;
+        if !probes.public && probes.listen.is_empty() && self.admin_listen.is_none() {
+            bail!("server.probes only serves probes on the admin listener, which is not enabled");
+        }
+        let new_public_proxy = || {
+            let proxy = new_proxy();
+            if probes.public {
+                proxy
+            } else {
+                proxy.without_probes()
+            }
+        };
+        add_proxy_service(
+            &mut server,
+            "Pingora HTTP Proxy Service",
+            new_public_proxy(),
+            Listeners {
+                listen: &app_config.server.listen,
+                tls: app_config.server.tls.as_ref(),
+                proxy_protocol: app_config.server.proxy_protocol,
+                acl: ConnectionAcl::new(
+                    None,
+                    vec![AccessList::from_config(&app_config.server.acl)?],
+                ),
+            },
+            &app_config.server,
+        )?;
+        for app in &app_config.apps {
+            add_proxy_service(
+                &mut server,
+                &format!("prx app {}", app.name),
+                new_public_proxy().for_app(&app.name),
+                Listeners {
+                    listen: &app.listen,
+                    tls: app.tls.as_ref(),
+                    proxy_protocol: app.proxy_protocol,
+                    acl: ConnectionAcl::new(
+                        Some(&app.name),
+                        vec![
+                            AccessList::from_config(&app_config.server.acl)?,
+                            AccessList::from_config(&app.acl)?,
+                        ],
+                    ),
+                },
+                &app_config.server,
+            )?;
+        }
+        if !probes.listen.is_empty() {
+            // Left open to the probing agents, whatever `server.acl` admits.
+            add_proxy_service(
+                &mut server,
+                "prx probes",
+                new_proxy().probes_only(),
+                Listeners {
+                    listen: &probes.listen,
+                    tls: None,
+                    proxy_protocol: None,
+                    acl: None,
+                },
+                &app_config.server,
+            )?;
+        }
+        for tcp_route in &app_config.tcp_routes {
+            add_listening_service(
+                &mut server,
+                format!("prx tcp route {}", tcp_route.name),
+                TcpProxy::new(&tcp_route.name, runtime_config.clone()),
+                Listeners {
+                    listen: &tcp_route.listen,
+                    tls: None,
+                    proxy_protocol: tcp_route.proxy_protocol,
+                    acl: ConnectionAcl::new(
+                        Some(&tcp_route.name),
+                        vec![
+                            AccessList::from_config(&app_config.server.acl)?,
+                            AccessList::from_config(&tcp_route.acl)?,
+                        ],
+                    ),
+                },
+                &app_config.server,
+            )?;
+            info!(
+                route = tcp_route.name.as_str(),
+                listen = tcp_route.listen.join(", ").as_str(),
+                service = tcp_route.service.as_str(),
+                "tcp route listeners are enabled"
+            );
+        }
+        let workers = &app_config.server.workers;
+
+        if let Some(admin_listen) = &self.admin_listen {
+            let Some(config_path) = &self.config_path else {
+                bail!("the admin API needs a config file; build with PrxBuilder::from_file");
+            };
+            let admin_listener = bind_admin_listener(admin_listen)
+                .with_context(|| format!("failed to start admin server on {admin_listen}"))?;
+            let admin_service = AdminAxumService::new(
+                admin_listen.clone(),
+                admin_listener,
+                config_path.clone(),
+                runtime_config.clone(),
+            )
+            .with_threads(workers.admin.threads.unwrap_or(1))
+            .with_probes(probes.admin.then(|| AdminProbes {
+                health_path: app_config.server.health_path.clone(),
+                ready_path: app_config.server.ready_path.clone(),
+                load_path: app_config.server.load_path.clone(),
+            }));
+            server.add_service(PinnedService::new(
+                admin_service,
+                workers.admin.cpu_affinity.clone(),
+            ));
+        }
+        let handle = PrxHandle {
+            active_config: runtime_config.clone(),
+        };
+        with_inherited_affinity(&workers.background_cpu_affinity, || {
+            spawn_coarse_clock().context("failed to start coarse clock")?;
+            spawn_drain_watcher(server.watch_execution_phase())
+                .context("failed to start shutdown drain watcher")?;
+            spawn_health_checker(runtime_config.clone())
+                .context("failed to start upstream health checker")?;
+            spawn_resolver(runtime_config.clone()).context("failed to start upstream resolver")?;
+            #[cfg(unix)]
+            if let Some(shared_state) = shared_state {
+                spawn_breaker_sync(shared_state, runtime_config.clone())
+                    .context("failed to start shared circuit breaker sync")?;
+            }
+            if let Some(config_path) = &self.config_path {
+                spawn_config_watcher(
+                    config_path.clone(),
+                    &app_config.include,
+                    Duration::from_millis(app_config.server.config_reload_debounce_ms.max(50)),
+                    runtime_config,
+                )
+                .with_context(|| {
+                    format!(
+                        "failed to start config watcher for {}",
+                        config_path.to_string_lossy()
+                    )
+                })?;
+            }
+            if let (Some(path), Some(blocklist)) = (&app_config.server.blocklist_path, blocklist) {
+                spawn_blocklist_watcher(
+                    Path::new(path),
+                    Duration::from_millis(app_config.server.config_reload_debounce_ms.max(50)),
+                    blocklist,
+                )
+                .with_context(|| format!("failed to start blocklist watcher for {path}"))?;
+            }
+            if let Some((source, _)) = self.remote_source {
+                spawn_remote_poller(source).context("failed to start remote config poller")?;
+            }
+            anyhow::Ok(())
+        })?;
+
+        if let Some(metrics_addr) = &app_config.observability.prometheus_listen {
+            memory::register_collector().context("failed to register memory metrics")?;
+            load::register_collector(handle.active_config.clone())
+                .context("failed to register load metrics")?;
+            let mut metrics_service = Service::prometheus_http_service();
+            metrics_service.add_tcp(metrics_addr);
+            metrics_service.threads = workers.metrics.threads;
+            server.add_service(PinnedService::new(
+                metrics_service,
+                workers.metrics.cpu_affinity.clone(),
+            ));
+            info!(
+                listen = metrics_addr,
+                "prometheus metrics endpoint is enabled"
+            );
+        }
+
+        Ok(Prx {
+            server,
+            handle,
+            config_path: self.config_path,
+        })
+    }
+
+    /// [`PrxBuilder::build`] followed by [`Prx::serve`].
+    pub fn serve(self) -> anyhow::Result<()> {
+        self.build()?.serve()
+    }
+}
+
+/// A fully set up prx server; [`Prx::serve`] runs it until shutdown.
+pub struct Prx {
+    server: Server,
+    handle: PrxHandle,
+    config_path: Option<PathBuf>,
+}
+
+impl Prx {
+    pub fn handle(&self) -> PrxHandle {
+        self.handle.clone()
+    }
+
+    /// Runs pingora on the calling thread and exits the process on shutdown.
+    pub fn serve(self) -> ! {
+        match &self.config_path {
+            Some(path) => info!(config = %path.to_string_lossy(), "prx is starting"),
+            None => info!("prx is starting"),
+        }
+        self.server.run_forever();
+    }
+
+    /// Like [`Prx::serve`], but shuts down on `shutdown` and returns once
+    /// the server has stopped.
+    #[cfg(windows)]
+    pub fn serve_until(self, shutdown: Box<dyn ShutdownSignalWatch>) {
+        match &self.config_path {
+            Some(path) => info!(config = %path.to_string_lossy(), "prx is starting"),
+            None => info!("prx is starting"),
+        }
+        self.server.run(RunArgs {
+            shutdown_signal: shutdown,
+        });
+    }
+
+    /// Longest a graceful shutdown takes: the grace period, then the wait for
+    /// the runtimes to exit, at pingora's defaults unless configured.
+    #[cfg(windows)]
+    pub(crate) fn shutdown_time(&self) -> Duration {
+        let conf = &self.server.configuration;
+        Duration::from_secs(
+            conf.grace_period_seconds.unwrap_or(300)
+                + conf.graceful_shutdown_timeout_seconds.unwrap_or(5),
+        )
+    }
+}
+
+/// Swaps the routing config of a running [`Prx`], e.g. from a control plane.
+#[derive(Clone)]
+pub struct PrxHandle {
+    active_config: Arc<ArcSwap<RuntimeConfig>>,
+}
+
+impl PrxHandle {
+    /// Validates `config` and applies it like a file reload. Listener, worker
+    /// and other `[server]` settings only take effect on restart.
+    pub fn update(&self, config: PrxConfig) -> anyhow::Result<RebuildStats> {
+        config.validate()?;
+        let (next, stats) = self.active_config.load().rebuild(config)?;
+        self.active_config.store(Arc::new(next));
+        Ok(stats)
+    }
+}
+
+fn tracing_filter(level: &str) -> EnvFilter {
+    EnvFilter::try_from_default_env()
+        .or_else(|_| EnvFilter::try_new(level))
+        .unwrap_or_else(|_| EnvFilter::new("info"))
+}
+
+fn init_tracing(level: &str) {
+    // An embedding binary may already have installed its own subscriber.
+    let _ = tracing_subscriber::fmt()
+        .with_env_filter(tracing_filter(level))
+        .with_target(true)
+        .compact()
+        .try_init();
+}
+
+fn tune_pingora_server(server: &mut Server, app_config: &PrxConfig) {
+    if let Some(conf) = Arc::get_mut(&mut server.configuration) {
+        if let Some(threads) = app_config.server.threads {
+            conf.threads = threads;
+        }
+        if let Some(seconds) = app_config.server.grace_period_seconds {
+            conf.grace_period_seconds = Some(seconds);
+        }
+        if let Some(seconds) = app_config.server.graceful_shutdown_timeout_seconds {
+            conf.graceful_shutdown_timeout_seconds = Some(seconds);
+        }
+        if let Some(size) = app_config.server.upstream_keepalive_pool_size {
+            conf.upstream_keepalive_pool_size = size;
+        }
+    }
+}
+
+/// Listeners of one proxy service.
+struct Listeners<'a> {
+    listen: &'a [String],
+    tls: Option<&'a TlsConfig>,
+    proxy_protocol: Option<ProxyProtocolVersion>,
+    acl: Option<ConnectionAcl>,
+}
+
+/// Adds a proxy service on `listeners` to `server`.
+fn add_proxy_service(
+    server: &mut Server,
+    name: &str,
+    proxy: PrxProxy,
+    listeners: Listeners,
+    server_config: &ServerConfig,
+) -> anyhow::Result<()> {
+    let mut proxy = http_proxy(&server.configuration, proxy);
+    if server_config.h2c {
+        // Non-exhaustive, so no struct literal.
+        let mut options = HttpServerOptions::default();
+        options.h2c = true;
+        proxy.server_options = Some(options);
+    }
+    let proxy_listen = listeners.listen.join(", ");
+    let tls_listen = listeners
+        .tls
+        .map(|tls| tls.listen.clone())
+        .unwrap_or_else(|| "-".to_string());
+    add_listening_service(server, name.to_string(), proxy, listeners, server_config)?;
+    info!(
+        service = name,
+        listen = proxy_listen.as_str(),
+        tls_listen = tls_listen.as_str(),
+        "proxy server listeners are enabled"
+    );
+    Ok(())
+}
+
+/// Runs `app` on `listeners`. Behind a PROXY header the acl moves into the
+/// app, so it checks the client the header names instead of the balancer.
+fn add_listening_service<A: ServerApp + Send + Sync + 'static>(
+    server: &mut Server,
+    name: String,
+    app: A,
+    mut listeners: Listeners,
+    server_config: &ServerConfig,
+) -> anyhow::Result<()> {
+    match listeners.proxy_protocol {
+        Some(version) => {
+            let app = ProxyProtocolApp::new(app, version, listeners.acl.take());
+            add_service(server, Service::new(name, app), listeners, server_config)
+        }
+        None => add_service(server, Service::new(name, app), listeners, server_config),
+    }
+}
+
+fn add_service<A: ServerApp + Send + Sync + 'static>(
+    server: &mut Server,
+    mut service: Service<A>,
+    listeners: Listeners,
+    server_config: &ServerConfig,
+) -> anyhow::Result<()> {
+    if let Some(acl) = listeners.acl {
+        service.set_connection_filter(Arc::new(acl));
+    }
+    let socket_options = listener_socket_options(server_config);
+    for addr in listeners.listen {
+        service.add_tcp_with_settings(addr, socket_options.clone());
+    }
+
+    if let Some(tls) = listeners.tls {
+        service.add_tls_with_settings(
+            &tls.listen,
+            Some(socket_options),
+            tls_settings(tls, server_config)?,
+        );
+    }
+
+    let workers = &server_config.workers.proxy;
+    service.threads = workers.threads;
+    server.add_service(PinnedService::new(service, workers.cpu_affinity.clone()));
+    Ok(())
+}
+
+/// Certificates are reloaded when their files change.
+#[cfg(feature = "openssl")]
+fn tls_settings(tls: &TlsConfig, server_config: &ServerConfig) -> anyhow::Result<TlsSettings> {
+    let listener = crate::tls::ListenerTls::load(tls)
+        .with_context(|| format!("failed to initialize TLS listener {}", tls.listen))?;
+    with_inherited_affinity(&server_config.workers.background_cpu_affinity, || {
+        listener.watch(Duration::from_millis(
+            server_config.config_reload_debounce_ms.max(50),
+        ))
+    })
+    .with_context(|| format!("failed to start certificate watcher for {}", tls.listen))?;
+    listener
+        .settings()
+        .with_context(|| format!("failed to initialize TLS listener {}", tls.listen))
+}
+
+/// Pingora's stub TLS backend only takes a certificate and key; settings it
+/// cannot honour stop startup instead of being dropped.
+#[cfg(not(feature = "openssl"))]
+fn tls_settings(tls: &TlsConfig, _server_config: &ServerConfig) -> anyhow::Result<TlsSettings> {
+    // Without client verification a configured CA would silently accept
+    // unauthenticated clients.
+    if let Some(ca) = &tls.client_ca_path {
+        bail!(
+            "TLS listener {} sets client_ca_path={ca}, but client certificate verification needs prx built with the openssl feature",
+            tls.listen
+        );
+    }
+    if !tls.certificates.is_empty() {
+        bail!(
+            "TLS listener {} lists SNI certificates, but certificate selection needs prx built with the openssl feature",
+            tls.listen
+        );
+    }
+    let mut tls_settings =
+        TlsSettings::intermediate(&tls.cert_path, &tls.key_path).with_context(|| {
+            format!(
+                "failed to initialize TLS settings using cert={} key={}",
+                tls.cert_path, tls.key_path
+            )
+        })?;
+    if tls.enable_h2 {
+        tls_settings.enable_h2();
+    }
+    Ok(tls_settings)
+}
+
+fn listener_socket_options(server: &ServerConfig) -> TcpSocketOptions {
+    let config = &server.socket;
+    let mut options = TcpSocketOptions::default();
+    options.so_reuseport = config.so_reuseport;
+    options.backlog = config.backlog;
+    options.tcp_nodelay = config.tcp_nodelay;
+    options.tcp_fastopen = config.tcp_fastopen;
+    options.ipv6_only = config.ipv6_only;
+    options.read_buffer_size = server.downstream_read_buffer_bytes;
+    options.tcp_keepalive = config.tcp_keepalive.as_ref().map(|ka| TcpKeepalive {
+        idle: Duration::from_secs(ka.idle_secs),
+        interval: Duration::from_secs(ka.interval_secs),
+        count: ka.count,
+        #[cfg(target_os = "linux")]
+        user_timeout: Duration::ZERO,
+    });
+    options
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(route_name: &str) -> PrxConfig {
+        PrxConfig::from_toml_str(&format!(
+            r#"
+[[service]]
+name = "app"
+
+[[service.upstream]]
+addr = "127.0.0.1:9000"
+
+[[route]]
+name = "{route_name}"
+service = "app"
+is_default = true
+"#
+        ))
+        .expect("config")
+    }
+
+    #[test]
+    fn handle_update_swaps_valid_config_and_keeps_it_on_error() {
+        let handle = PrxHandle {
+            active_config: Arc::new(ArcSwap::from_pointee(
+                RuntimeConfig::from_config(config("first")).expect("runtime"),
+            )),
+        };
+
+        handle.update(config("second")).expect("valid update");
+        let route_name = |handle: &PrxHandle| {
+            let active = handle.active_config.load();
+            active.route(0).map(|route| route.name.to_string())
+        };
+        assert_eq!(route_name(&handle).as_deref(), Some("second"));
+
+        let mut invalid = config("third");
+        invalid.routes[0].service = "missing".to_string();
+        assert!(handle.update(invalid).is_err());
+        assert_eq!(route_name(&handle).as_deref(), Some("second"));
+    }
+
+    #[cfg(not(feature = "openssl"))]
+    #[test]
+    fn stub_tls_backend_refuses_client_certificate_verification() {
+        let tls = TlsConfig {
+            listen: "127.0.0.1:8443".to_string(),
+            cert_path: "/etc/prx/tls.crt".to_string(),
+            key_path: "/etc/prx/tls.key".to_string(),
+            enable_h2: true,
+            client_ca_path: Some("/etc/prx/clients.pem".to_string()),
+            require_client_cert: true,
+            certificates: Vec::new(),
+        };
+
+        let Err(err) = tls_settings(&tls, &ServerConfig::default()) else {
+            panic!("client_ca_path must not be dropped silently");
+        };
+        assert!(
+            err.to_string()
+                .contains("needs prx built with the openssl feature")
+        );
+    }
+}
@@ -0,0 +1,719 @@
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    net::{TcpListener, ToSocketAddrs},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, bail};
+use pingora::listeners::tls::TlsSettings;
+use serde::Serialize;
+
+use crate::{
+    config::{ConfigErrors, ConfigProblem, PrxConfig},
+    include::{IncludedFile, InvalidIncludedFile},
+};
+
+/// Returns true when `--json` was passed, for a machine-readable report.
+pub fn json_requested(args: impl IntoIterator<Item = String>) -> bool {
+    args.into_iter().any(|arg| arg == "--json")
+}
+
+/// Returns true when `--check` was passed or `PRX_CHECK` is set to a truthy value.
+pub fn requested(args: impl IntoIterator<Item = String>, env_flag: Option<&str>) -> bool {
+    let from_env = env_flag.is_some_and(|value| {
+        matches!(
+            value.to_ascii_lowercase().as_str(),
+            "1" | "true" | "yes" | "on"
+        )
+    });
+    from_env || args.into_iter().any(|arg| arg == "--check")
+}
+
+/// Outcome of a dry-run boot, equivalent to `nginx -t`.
+///
+/// Every step of the startup path runs without serving traffic: config parse
+/// and validation, TLS material load, a bind probe for each listener and DNS
+/// resolution of each upstream.
+#[derive(Debug, Default)]
+pub struct CheckReport {
+    items: Vec<CheckItem>,
+    problems: Vec<ConfigProblem>,
+    warnings: Vec<ConfigProblem>,
+}
+
+#[derive(Debug)]
+struct CheckItem {
+    step: String,
+    outcome: Result<String, String>,
+}
+
+impl CheckReport {
+    pub fn run(config_path: &Path, admin_listen: &str) -> Self {
+        let mut report = Self::default();
+        let loaded = fs::read_to_string(config_path)
+            .with_context(|| {
+                format!(
+                    "failed to read config file at {}",
+                    config_path.to_string_lossy()
+                )
+            })
+            .and_then(|text| PrxConfig::parse_at(&text, config_path))
+            .and_then(|(config, included)| {
+                let warnings = config.report_with(&included).warnings;
+                config.validate_with(&included)?;
+                Ok((config, warnings))
+            });
+        let config = match loaded {
+            Ok((config, warnings)) => {
+                report.warnings = warnings;
+                report.pass(
+                    format!("config {}", config_path.to_string_lossy()),
+                    format!(
+                        "{} services, {} routes",
+                        config.services.len(),
+                        config.routes.len()
+                    ),
+                );
+                config
+            }
+            Err(err) => {
+                let step = format!("config {}", config_path.to_string_lossy());
+                let Some(errors) = err.downcast_ref::<ConfigErrors>() else {
+                    report.fail(step, err);
+                    return report;
+                };
+                for problem in &errors.0 {
+                    report.items.push(CheckItem {
+                        step: format!("{step} {}", problem.field).trim_end().to_string(),
+                        outcome: Err(format!("{} ({})", problem.message, problem.code)),
+                    });
+                }
+                report.problems = errors.0.clone();
+                return report;
+            }
+        };
+
+        if let Some(tls) = &config.server.tls {
+            report.record(
+                format!("tls cert {}", tls.cert_path),
+                check_tls(&tls.cert_path, &tls.key_path),
+            );
+        }
+
+        if let Some(waf) = &config.waf {
+            report.record(
+                "waf rules".to_string(),
+                crate::waf::RuleSet::load(waf)
+                    .map(|rules| format!("{} rules loaded", rules.rule_count())),
+            );
+        }
+
+        if let Some(path) = &config.server.blocklist_path {
+            report.record(
+                format!("blocklist {path}"),
+                crate::blocklist::Blocklist::load(Path::new(path))
+                    .map(|blocklist| format!("{} entries loaded", blocklist.len())),
+            );
+        }
+
+        let mut listeners = config
+            .server
+            .listen
+            .iter()
+            .map(|addr| ("proxy", addr.as_str()))
+            .collect::<Vec<_>>();
+        if let Some(tls) = &config.server.tls {
+            listeners.push(("tls", tls.listen.as_str()));
+        }
+        for app in &config.apps {
+            listeners.extend(app.listen.iter().map(|addr| ("app", addr.as_str())));
+            if let Some(tls) = &app.tls {
+                listeners.push(("app tls", tls.listen.as_str()));
+            }
+        }
+        for tcp_route in &config.tcp_routes {
+            listeners.extend(
+                tcp_route
+                    .listen
+                    .iter()
+                    .map(|addr| ("tcp route", addr.as_str())),
+            );
+        }
+        if let Some(addr) = &config.observability.prometheus_listen {
+            listeners.push(("metrics", addr.as_str()));
+        }
+        listeners.push(("admin", admin_listen));
+        for (kind, addr) in listeners {
+            report.record(format!("{kind} listener {addr}"), probe_bind(addr));
+        }
+
+        for service in &config.services {
+            for upstream in &service.upstreams {
+                report.record(
+                    format!("service {} upstream {}", service.name, upstream.addr),
+                    resolve_upstream(&upstream.addr),
+                );
+            }
+        }
+
+        report
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.items.iter().all(|item| item.outcome.is_ok())
+    }
+
+    /// The report as JSON: each step, and the config problems with their
+    /// field paths and codes when validation failed.
+    pub fn to_json(&self) -> String {
+        let steps = self
+            .items
+            .iter()
+            .map(|item| match &item.outcome {
+                Ok(detail) => serde_json::json!({"step": item.step, "ok": true, "detail": detail}),
+                Err(err) => serde_json::json!({"step": item.step, "ok": false, "detail": err}),
+            })
+            .collect::<Vec<_>>();
+        serde_json::json!({
+            "ok": self.is_ok(),
+            "steps": steps,
+            "problems": self.problems,
+            "warnings": self.warnings,
+        })
+        .to_string()
+    }
+
+    fn record(&mut self, step: String, outcome: anyhow::Result<String>) {
+        match outcome {
+            Ok(detail) => self.pass(step, detail),
+            Err(err) => self.fail(step, err),
+        }
+    }
+
+    fn pass(&mut self, step: String, detail: String) {
+        self.items.push(CheckItem {
+            step,
+            outcome: Ok(detail),
+        });
+    }
+
+    fn fail(&mut self, step: String, err: anyhow::Error) {
+        self.items.push(CheckItem {
+            step,
+            outcome: Err(format!("{err:#}")),
+        });
+    }
+}
+
+impl fmt::Display for CheckReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for item in &self.items {
+            match &item.outcome {
+                Ok(detail) => writeln!(f, "ok   {}: {detail}", item.step)?,
+                Err(err) => writeln!(f, "FAIL {}: {err}", item.step)?,
+            }
+        }
+        for warning in &self.warnings {
+            writeln!(
+                f,
+                "warn {}: {} ({})",
+                warning.field, warning.message, warning.code
+            )?;
+        }
+        let failed = self
+            .items
+            .iter()
+            .filter(|item| item.outcome.is_err())
+            .count();
+        if failed == 0 {
+            write!(f, "configuration check passed")
+        } else {
+            write!(f, "configuration check failed ({failed} problems)")
+        }
+    }
+}
+
+/// `prx check [--config <path>] [--json]`: parses and validates the config
+/// like startup does, without binding listeners or resolving upstreams, so
+/// it can gate CI and deploys.
+#[derive(Debug, Default)]
+pub struct ConfigCheck {
+    pub config: Option<PathBuf>,
+    pub json: bool,
+}
+
+/// A config problem with the file, line and column it is at, when known.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LocatedProblem {
+    pub file: String,
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+    /// Path of the setting in TOML terms, counted within `file`; empty for
+    /// syntax errors.
+    pub field: String,
+    pub code: &'static str,
+    pub message: String,
+}
+
+#[derive(Debug)]
+pub struct ConfigCheckReport {
+    file: String,
+    summary: String,
+    problems: Vec<LocatedProblem>,
+    warnings: Vec<LocatedProblem>,
+}
+
+impl ConfigCheck {
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> anyhow::Result<Option<Self>> {
+        let mut args = args.into_iter();
+        if args.next().as_deref() != Some("check") {
+            return Ok(None);
+        }
+
+        let mut check = Self::default();
+        while let Some(flag) = args.next() {
+            match flag.as_str() {
+                "--config" => {
+                    check.config = Some(args.next().context("--config requires a value")?.into())
+                }
+                "--json" => check.json = true,
+                _ => bail!("unknown check argument {flag}"),
+            }
+        }
+        Ok(Some(check))
+    }
+
+    /// Checks `--config`, or `default_path` without one.
+    pub fn run(&self, default_path: &Path) -> ConfigCheckReport {
+        let path = self.config.as_deref().unwrap_or(default_path);
+        let mut report = ConfigCheckReport {
+            file: path.to_string_lossy().into_owned(),
+            summary: String::new(),
+            problems: Vec::new(),
+            warnings: Vec::new(),
+        };
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(err) => {
+                report.problems.push(LocatedProblem {
+                    file: report.file.clone(),
+                    line: None,
+                    column: None,
+                    field: String::new(),
+                    code: "invalid",
+                    message: format!("failed to read config file: {err}"),
+                });
+                return report;
+            }
+        };
+        let (config, included) = match PrxConfig::parse_at(&text, path) {
+            Ok(parsed) => parsed,
+            Err(err) => {
+                report.problems.push(load_problem(path, &text, &err));
+                return report;
+            }
+        };
+        let found = config.report_with(&included);
+        let locate = |problem| locate(path, &text, &config, &included, problem);
+        report.problems = found.errors.into_iter().map(locate).collect();
+        report.warnings = found.warnings.into_iter().map(locate).collect();
+        report.summary = format!(
+            "{} services, {} routes from {} files",
+            config.services.len(),
+            config.routes.len(),
+            included.len() + 1
+        );
+        report
+    }
+}
+
+impl ConfigCheckReport {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::json!({
+            "ok": self.is_ok(),
+            "file": self.file,
+            "problems": self.problems,
+            "warnings": self.warnings,
+        })
+        .to_string()
+    }
+}
+
+impl fmt::Display for ConfigCheckReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let errors = self.problems.iter().map(|problem| ("", problem));
+        let warnings = self.warnings.iter().map(|problem| ("warning: ", problem));
+        for (severity, problem) in errors.chain(warnings) {
+            write!(f, "{}", problem.file)?;
+            if let Some(line) = problem.line {
+                write!(f, ":{line}")?;
+            }
+            if let Some(column) = problem.column {
+                write!(f, ":{column}")?;
+            }
+            write!(f, ": {severity}")?;
+            if !problem.field.is_empty() {
+                write!(f, "{}: ", problem.field)?;
+            }
+            writeln!(f, "{} ({})", problem.message, problem.code)?;
+        }
+        if self.is_ok() {
+            write!(f, "{}: {}\nconfig check passed", self.file, self.summary)
+        } else {
+            write!(f, "config check failed ({} problems)", self.problems.len())
+        }
+    }
+}
+
+/// The TOML parser's error with its position when `Prx.toml` or an included
+/// file does not parse; any other failure to load includes as is.
+fn load_problem(path: &Path, text: &str, err: &anyhow::Error) -> LocatedProblem {
+    let (file, text) = match err.downcast_ref::<InvalidIncludedFile>() {
+        Some(InvalidIncludedFile(included)) => (
+            included.as_path(),
+            fs::read_to_string(included).unwrap_or_default(),
+        ),
+        None => (path, text.to_string()),
+    };
+    let Some(syntax) = err.downcast_ref::<toml::de::Error>() else {
+        return LocatedProblem {
+            file: path.to_string_lossy().into_owned(),
+            line: None,
+            column: None,
+            field: "include".to_string(),
+            code: "invalid",
+            message: format!("{err:#}"),
+        };
+    };
+    let (line, column) = syntax
+        .span()
+        .map(|span| line_column(&text, span.start))
+        .unzip();
+    LocatedProblem {
+        file: file.to_string_lossy().into_owned(),
+        line,
+        column,
+        field: String::new(),
+        code: "syntax",
+        message: syntax.message().to_string(),
+    }
+}
+
+fn locate(
+    path: &Path,
+    text: &str,
+    config: &PrxConfig,
+    included: &[IncludedFile],
+    problem: ConfigProblem,
+) -> LocatedProblem {
+    let in_file = problem.file.as_ref().and_then(|name| {
+        let file = included
+            .iter()
+            .find(|file| file.path.to_string_lossy() == name.as_str())?;
+        let field = local_field(&problem.field, config, file)?;
+        let message = problem.message.strip_prefix(&format!("{name}: "))?;
+        Some((file, field, message.to_string()))
+    });
+    let (file, text, field, message) = match in_file {
+        Some((file, field, message)) => (
+            file.path.as_path(),
+            fs::read_to_string(&file.path).unwrap_or_default(),
+            field,
+            message,
+        ),
+        None => (path, text.to_string(), problem.field, problem.message),
+    };
+    let (line, column) = field_position(&text, &field).unzip();
+    LocatedProblem {
+        file: file.to_string_lossy().into_owned(),
+        line,
+        column,
+        field,
+        code: problem.code,
+        message,
+    }
+}
+
+/// `field` of the merged config, e.g. `route[5].service`, as counted within
+/// the included `file`.
+fn local_field(field: &str, config: &PrxConfig, file: &IncludedFile) -> Option<String> {
+    let (section, rest) = field.split_once('[')?;
+    let (index, rest) = rest.split_once(']')?;
+    let index: usize = index.parse().ok()?;
+    let local = match section {
+        "route" => {
+            let route = config.routes.get(index)?;
+            file.routes.iter().position(|included| included == route)?
+        }
+        "service" => {
+            let service = config.services.get(index)?;
+            file.services
+                .iter()
+                .position(|included| included == service)?
+        }
+        _ => return None,
+    };
+    Some(format!("{section}[{local}]{rest}"))
+}
+
+/// Line and column of the setting `field` names in `text`, or of the
+/// innermost table holding it when the setting is not spelled out. Tables
+/// are followed through their headers; inline tables count as one setting.
+fn field_position(text: &str, field: &str) -> Option<(usize, usize)> {
+    // `[[a.b]]` tables seen per array, e.g. `service[0].upstream`.
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    let mut table = String::new();
+    let mut best: Option<(usize, (usize, usize))> = None;
+    for (index, raw) in text.lines().enumerate() {
+        let line = raw.trim_start();
+        let column = raw.len() - line.len() + 1;
+        let path = if let Some(header) = line.strip_prefix('[') {
+            let array = header.starts_with('[');
+            let Some(end) = header.find(']') else {
+                continue;
+            };
+            let names = header[usize::from(array)..end].split('.').map(str::trim);
+            let mut path = String::new();
+            let count = names.clone().count();
+            for (depth, name) in names.enumerate() {
+                if !path.is_empty() {
+                    path.push('.');
+                }
+                path.push_str(name.trim_matches('"'));
+                let seen = counts.get(&path).copied();
+                if array && depth + 1 == count {
+                    let next = seen.unwrap_or(0);
+                    counts.insert(path.clone(), next + 1);
+                    path = format!("{path}[{next}]");
+                } else if let Some(seen) = seen {
+                    path = format!("{path}[{}]", seen - 1);
+                }
+            }
+            table = path.clone();
+            path
+        } else if let Some((key, _)) = line.split_once('=')
+            && !line.starts_with('#')
+        {
+            let key = key.trim().trim_matches('"');
+            if table.is_empty() {
+                key.to_string()
+            } else {
+                format!("{table}.{key}")
+            }
+        } else {
+            continue;
+        };
+        let holds = field == path
+            || field
+                .strip_prefix(path.as_str())
+                .is_some_and(|rest| rest.starts_with(['.', '[']));
+        if holds && best.is_none_or(|(len, _)| path.len() > len) {
+            best = Some((path.len(), (index + 1, column)));
+        }
+    }
+    best.map(|(_, position)| position)
+}
+
+fn line_column(text: &str, offset: usize) -> (usize, usize) {
+    let before = &text[..offset.min(text.len())];
+    let line_start = before.rfind('\n').map_or(0, |newline| newline + 1);
+    (
+        before.matches('\n').count() + 1,
+        before[line_start..].chars().count() + 1,
+    )
+}
+
+fn check_tls(cert_path: &str, key_path: &str) -> anyhow::Result<String> {
+    TlsSettings::intermediate(cert_path, key_path)
+        .map_err(|err| anyhow::anyhow!("{err}"))
+        .context("failed to initialize TLS settings")?;
+    for (label, path) in [("cert", cert_path), ("key", key_path)] {
+        let pem = fs::read_to_string(path)
+            .with_context(|| format!("failed to read TLS {label} file {path}"))?;
+        if !pem.contains("-----BEGIN ") {
+            bail!("TLS {label} file {path} does not contain a PEM block");
+        }
+    }
+    Ok(format!("key {key_path} loaded"))
+}
+
+/// Binds and immediately releases `addr`. std sets SO_REUSEADDR on Unix
+/// listeners, so sockets lingering in TIME_WAIT do not cause false failures;
+/// an address held by a live process still does.
+fn probe_bind(addr: &str) -> anyhow::Result<String> {
+    let listener = TcpListener::bind(addr).with_context(|| format!("cannot bind {addr}"))?;
+    let local = listener
+        .local_addr()
+        .context("failed to read bound address")?;
+    Ok(format!("bindable ({local})"))
+}
+
+fn resolve_upstream(addr: &str) -> anyhow::Result<String> {
+    let resolved = addr
+        .to_socket_addrs()
+        .with_context(|| format!("failed to resolve {addr}"))?
+        .map(|addr| addr.to_string())
+        .collect::<Vec<_>>();
+    if resolved.is_empty() {
+        bail!("{addr} resolved to no addresses");
+    }
+    Ok(format!("resolves to {}", resolved.join(", ")))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    fn write_config(body: &str) -> tempfile::NamedTempFile {
+        let mut file = tempfile::NamedTempFile::new().expect("temp config");
+        file.write_all(body.as_bytes()).expect("write config");
+        file
+    }
+
+    fn config_with_upstream(upstream: &str) -> String {
+        format!(
+            r#"
+[server]
+listen = ["127.0.0.1:0"]
+
+[[service]]
+name = "app"
+
+[[service.upstream]]
+addr = "{upstream}"
+
+[[route]]
+name = "default"
+service = "app"
+path_prefix = "/"
+is_default = true
+"#
+        )
+    }
+
+    #[test]
+    fn config_check_locates_problems_in_the_config_and_included_files() {
+        let dir = tempfile::tempdir().expect("tempdir");
+        let path = dir.path().join("Prx.toml");
+        let config = config_with_upstream("127.0.0.1:9000")
+            .replace("[server]", "include = [\"routes/*.toml\"]\n\n[server]")
+            .replace("addr = ", "drain_hook = \"drain\"\naddr = ");
+        fs::write(&path, config).expect("write config");
+        fs::create_dir(dir.path().join("routes")).expect("routes");
+        let routes = dir.path().join("routes/api.toml");
+        fs::write(
+            &routes,
+            "[[route]]\nname = \"api\"\nservice = \"app\"\npath_prefix = \"/api\"\n\n\
+             [[route]]\nname = \"web\"\n  service = \"missing\"\npath_prefix = \"/web\"\n",
+        )
+        .expect("write routes");
+
+        let check = ConfigCheck::from_args(["check", "--json"].map(String::from))
+            .expect("args")
+            .expect("check");
+        assert!(check.json);
+        let report = check.run(&path);
+        assert!(!report.is_ok());
+        assert_eq!(
+            report.problems[0],
+            LocatedProblem {
+                file: path.to_string_lossy().into_owned(),
+                line: Some(11),
+                column: Some(1),
+                field: "service[0].upstream[0].drain_hook".to_string(),
+                code: "invalid",
+                message: report.problems[0].message.clone(),
+            }
+        );
+        let problem = &report.problems[1];
+        assert_eq!(problem.file, routes.to_string_lossy());
+        assert_eq!((problem.line, problem.column), (Some(8), Some(3)));
+        assert_eq!(problem.field, "route[1].service");
+        assert!(!problem.message.contains("api.toml"), "{}", problem.message);
+        assert!(
+            report
+                .to_string()
+                .contains("api.toml:8:3: route[1].service: ")
+        );
+
+        fs::write(&routes, "[[route]\nname = \"api\"\n").expect("write routes");
+        let report = check.run(&path);
+        assert_eq!(report.problems.len(), 1);
+        let problem = &report.problems[0];
+        assert_eq!(
+            (problem.code, problem.line, problem.column),
+            ("syntax", Some(1), Some(8))
+        );
+        assert_eq!(problem.file, routes.to_string_lossy());
+    }
+
+    #[test]
+    fn requested_via_flag_or_env() {
+        assert!(requested(["prx".to_string(), "--check".to_string()], None));
+        assert!(requested(["prx".to_string()], Some("1")));
+        assert!(!requested(["prx".to_string()], Some("0")));
+        assert!(!requested(["prx".to_string()], None));
+    }
+
+    #[test]
+    fn passes_for_bindable_listeners_and_resolvable_upstreams() {
+        let file = write_config(&config_with_upstream("127.0.0.1:9000"));
+        let report = CheckReport::run(file.path(), "127.0.0.1:0");
+        assert!(report.is_ok(), "{report}");
+        assert!(report.to_string().ends_with("configuration check passed"));
+    }
+
+    #[test]
+    fn reports_held_listener_and_unresolvable_upstream() {
+        let held = TcpListener::bind("127.0.0.1:0").expect("hold port");
+        let admin = held.local_addr().expect("held addr").to_string();
+        let file = write_config(&config_with_upstream("no-such-host.invalid:80"));
+
+        let report = CheckReport::run(file.path(), &admin);
+        let rendered = report.to_string();
+        assert!(!report.is_ok());
+        assert!(
+            rendered.contains(&format!("FAIL admin listener {admin}")),
+            "{rendered}"
+        );
+        assert!(rendered.contains("FAIL service app upstream"), "{rendered}");
+        assert!(rendered.ends_with("(2 problems)"), "{rendered}");
+    }
+
+    #[test]
+    fn stops_after_invalid_config() {
+        let file = write_config("[[route]]\nname = \"x\"\n");
+        let report = CheckReport::run(file.path(), "127.0.0.1:0");
+        assert!(!report.is_ok());
+        assert_eq!(report.items.len(), 1);
+    }
+
+    #[test]
+    fn lists_every_config_problem_with_its_field() {
+        let config = config_with_upstream("127.0.0.1:9000").replace(
+            "path_prefix = \"/\"",
+            "path_prefix = \"api\"\nmax_response_bytes = 0",
+        );
+        let file = write_config(&config);
+        let report = CheckReport::run(file.path(), "127.0.0.1:0");
+        assert!(!report.is_ok());
+        let rendered = report.to_string();
+        assert!(
+            rendered.contains("route[0].path_prefix: route 'default' path_prefix"),
+            "{rendered}"
+        );
+        assert!(rendered.ends_with("(2 problems)"), "{rendered}");
+
+        let json: serde_json::Value = serde_json::from_str(&report.to_json()).expect("json");
+        assert_eq!(json["ok"], false);
+        assert_eq!(json["problems"][1]["field"], "route[0].max_response_bytes");
+        assert_eq!(json["problems"][1]["code"], "out_of_range");
+    }
+}
@@ -0,0 +1,164 @@
+use std::{
+    env,
+    io::{self, Read},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::Context;
+use pingora::prelude::Opt;
+
+#[cfg(windows)]
+use crate::win_service;
+use crate::{
+    PrxBuilder,
+    admin::DEFAULT_ADMIN_LISTEN,
+    check::{self, CheckReport, ConfigCheck},
+    config::PrxConfig,
+    env_value,
+    healthcheck::{self, Healthcheck},
+    lookup::RouteQuery,
+    secret::ConfigKey,
+    selftest::Selftest,
+    source::{DEFAULT_POLL_INTERVAL_MS, DEFAULT_S3_ENDPOINT, RemoteSource},
+};
+
+/// Entry point of the `prx` binary: configuration comes from `PRX_*`
+/// environment variables and pingora's own command-line flags.
+pub fn run() -> anyhow::Result<()> {
+    if env::args().skip(1).any(|arg| arg == "--encrypt") {
+        return encrypt_stdin();
+    }
+
+    #[cfg(windows)]
+    if win_service::requested(env::args().skip(1)) {
+        return win_service::run();
+    }
+
+    let config_path = config_path();
+    let admin_listen = admin_listen();
+    if let Some(check) = Healthcheck::from_args(env::args().skip(1))? {
+        match check
+            .target(&admin_listen, || PrxConfig::from_file(&config_path))
+            .and_then(|target| healthcheck::probe(&target))
+        {
+            Ok(detail) => println!("healthy: {detail}"),
+            Err(err) => {
+                eprintln!("unhealthy: {err:#}");
+                std::process::exit(1);
+            }
+        }
+        return Ok(());
+    }
+    if let Some(query) = RouteQuery::from_args(env::args().skip(1))? {
+        let config = PrxConfig::from_file(&config_path)?;
+        let lookup = query.resolve(&config)?;
+        println!("{lookup}");
+        if !lookup.is_match() {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    if let Some(selftest) = Selftest::from_args(env::args().skip(1))? {
+        let path = selftest.config.clone().unwrap_or(config_path);
+        let config = PrxConfig::from_file(&path)?;
+        let binary = env::current_exe().context("failed to locate the prx binary")?;
+        let report = selftest.run(&config, binary)?;
+        println!("{report}");
+        if !report.is_ok() {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    if let Some(check) = ConfigCheck::from_args(env::args().skip(1))? {
+        let report = check.run(&config_path);
+        if check.json {
+            println!("{}", report.to_json());
+        } else {
+            println!("{report}");
+        }
+        if !report.is_ok() {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    if check::requested(env::args().skip(1), env_value("PRX_CHECK").as_deref()) {
+        let report = CheckReport::run(&config_path, &admin_listen);
+        if check::json_requested(env::args().skip(1)) {
+            println!("{}", report.to_json());
+        } else {
+            println!("{report}");
+        }
+        if !report.is_ok() {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    server_builder()?.serve()
+}
+
+/// The server the binary runs: the config file, admin listener and remote
+/// source from `PRX_*` variables, and pingora's flags.
+pub(crate) fn server_builder() -> anyhow::Result<PrxBuilder> {
+    let config_path = config_path();
+    let remote_source = remote_source_from_env(&config_path)?;
+    let bootstrap = remote_source
+        .as_ref()
+        .map(RemoteSource::bootstrap)
+        .transpose()?;
+    #[cfg(windows)]
+    let opt = Opt::parse_from_args(env::args().filter(|arg| arg != win_service::FLAG));
+    #[cfg(not(windows))]
+    let opt = Opt::parse_args();
+    let mut builder = PrxBuilder::from_file(&config_path)?
+        .with_admin(admin_listen())
+        .with_pingora_opt(opt);
+    if let Some(source) = remote_source {
+        builder = builder.with_remote_source(source, bootstrap);
+    }
+    Ok(builder)
+}
+
+fn config_path() -> PathBuf {
+    PathBuf::from(env_value("PRX_CONFIG").unwrap_or_else(|| "Prx.toml".to_string()))
+}
+
+fn admin_listen() -> String {
+    env_value("PRX_ADMIN_LISTEN").unwrap_or_else(|| DEFAULT_ADMIN_LISTEN.to_string())
+}
+
+/// Prints `enc:...` for the plaintext read from stdin, for pasting into `Prx.toml`.
+fn encrypt_stdin() -> anyhow::Result<()> {
+    let raw_key = env_value("PRX_CONFIG_KEY").context("PRX_CONFIG_KEY must be set to encrypt")?;
+    let key = ConfigKey::from_base64(&raw_key).context("invalid PRX_CONFIG_KEY")?;
+    let mut plaintext = String::new();
+    io::stdin()
+        .read_to_string(&mut plaintext)
+        .context("failed to read plaintext from stdin")?;
+    println!("{}", key.encrypt(plaintext.trim_end_matches(['\r', '\n'])));
+    Ok(())
+}
+
+fn remote_source_from_env(config_path: &Path) -> anyhow::Result<Option<RemoteSource>> {
+    let Some(url) = env_value("PRX_CONFIG_URL") else {
+        return Ok(None);
+    };
+    let s3_endpoint =
+        env_value("PRX_CONFIG_S3_ENDPOINT").unwrap_or_else(|| DEFAULT_S3_ENDPOINT.to_string());
+    let poll_ms = match env_value("PRX_CONFIG_POLL_MS") {
+        Some(raw) => raw
+            .parse::<u64>()
+            .with_context(|| format!("PRX_CONFIG_POLL_MS must be a number, got {raw}"))?,
+        None => DEFAULT_POLL_INTERVAL_MS,
+    };
+
+    RemoteSource::new(
+        &url,
+        &s3_endpoint,
+        config_path.to_path_buf(),
+        Duration::from_millis(poll_ms.max(1000)),
+    )
+    .map(Some)
+    .with_context(|| format!("invalid PRX_CONFIG_URL {url}"))
+}
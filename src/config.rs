@@ -1,25 +1,52 @@
-use std::{fs, path::Path};
+use std::{collections::BTreeMap, fs, path::Path};
 
 use anyhow::{Context, bail};
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+use crate::include::{self, IncludedFile};
+
+/// Upper bound for CPU ids in affinity lists (the size of a Linux `cpu_set_t`).
+const MAX_CPU: usize = 1024;
+/// Request body bytes pingora keeps for replaying a request on retry.
+const MAX_RETRY_BODY_BYTES: u64 = 64 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct PrxConfig {
+    /// Files whose `[[service]]` and `[[route]]` blocks are merged in after
+    /// this file's, relative to it; `*` and `?` match in file names.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<String>,
     #[serde(default)]
     pub server: ServerConfig,
     #[serde(default)]
     pub observability: ObservabilityConfig,
+    /// Extra proxy apps, each served by its own pingora service and listeners.
+    #[serde(rename = "app", default, skip_serializing_if = "Vec::is_empty")]
+    pub apps: Vec<AppConfig>,
+    /// Route owners whose admin token scopes the admin API to their routes.
+    #[serde(rename = "tenant", default, skip_serializing_if = "Vec::is_empty")]
+    pub tenants: Vec<TenantConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub admin: Option<AdminConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub waf: Option<WafConfig>,
+    /// Client IP lookup whose verdict can deny or tag requests.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reputation: Option<ReputationConfig>,
     #[serde(rename = "service", default)]
     pub services: Vec<ServiceConfig>,
     #[serde(rename = "route", default)]
     pub routes: Vec<RouteConfig>,
+    /// Raw TCP listeners forwarding to a service, e.g. for databases.
+    #[serde(rename = "tcp_route", default, skip_serializing_if = "Vec::is_empty")]
+    pub tcp_routes: Vec<TcpRouteConfig>,
 }
 
 impl PrxConfig {
     pub fn from_file(path: &Path) -> anyhow::Result<Self> {
         let content = fs::read_to_string(path)
             .with_context(|| format!("failed to read config file at {}", path.to_string_lossy()))?;
-        Self::from_toml_str(&content).with_context(|| {
+        Self::from_toml_str_at(&content, path).with_context(|| {
             format!(
                 "failed to parse TOML config from {}",
                 path.to_string_lossy()
@@ -29,157 +56,2401 @@ impl PrxConfig {
 
     pub fn from_toml_str(content: &str) -> anyhow::Result<Self> {
         let config: Self = toml::from_str(content).context("invalid TOML config")?;
+        if !config.include.is_empty() {
+            bail!("include is only supported in a config file");
+        }
         config.validate()?;
         Ok(config)
     }
 
+    /// Parses `content` as the config file at `path` and validates it with
+    /// the files it includes merged in.
+    pub fn from_toml_str_at(content: &str, path: &Path) -> anyhow::Result<Self> {
+        let (config, included) = Self::parse_at(content, path)?;
+        config.validate_with(&included)?;
+        Ok(config)
+    }
+
+    /// Parses `content` as the config file at `path` and merges in the
+    /// files it includes, without validating.
+    pub(crate) fn parse_at(
+        content: &str,
+        path: &Path,
+    ) -> anyhow::Result<(Self, Vec<IncludedFile>)> {
+        let mut config: Self = toml::from_str(content).context("invalid TOML config")?;
+        let included = include::load(path, &config.include)?;
+        config.merge_included(&included);
+        Ok((config, included))
+    }
+
+    pub(crate) fn merge_included(&mut self, included: &[IncludedFile]) {
+        for file in included {
+            self.services.extend(file.services.iter().cloned());
+            self.routes.extend(file.routes.iter().cloned());
+        }
+    }
+
+    /// Like [`Self::validate`], naming the included file a problem is in.
+    pub(crate) fn validate_with(&self, included: &[IncludedFile]) -> anyhow::Result<()> {
+        let problems = self.problems_with(included);
+        if problems.is_empty() {
+            return Ok(());
+        }
+        Err(ConfigErrors(problems).into())
+    }
+
+    pub(crate) fn problems_with(&self, included: &[IncludedFile]) -> Vec<ConfigProblem> {
+        let mut problems = self.problems();
+        self.attribute(&mut problems, included);
+        problems
+    }
+
+    /// Errors and warnings at once, naming the included file each is in.
+    pub(crate) fn report_with(&self, included: &[IncludedFile]) -> ValidationReport {
+        let mut report = self.report();
+        self.attribute(&mut report.errors, included);
+        self.attribute(&mut report.warnings, included);
+        report
+    }
+
+    fn attribute(&self, problems: &mut [ConfigProblem], included: &[IncludedFile]) {
+        for problem in problems {
+            let index = |section: &str| {
+                let rest = problem.field.strip_prefix(section)?.strip_prefix('[')?;
+                rest[..rest.find(']')?].parse::<usize>().ok()
+            };
+            let file = if let Some(route) = index("route").and_then(|i| self.routes.get(i)) {
+                included.iter().find(|file| file.routes.contains(route))
+            } else if let Some(service) = index("service").and_then(|i| self.services.get(i)) {
+                included.iter().find(|file| file.services.contains(service))
+            } else {
+                None
+            };
+            if let Some(file) = file {
+                let path = file.path.to_string_lossy().into_owned();
+                problem.message = format!("{path}: {}", problem.message);
+                problem.file = Some(path);
+            }
+        }
+    }
+
+    /// The config to write back to its file: `self` without the services
+    /// and routes that came from `included`, which must be unchanged.
+    pub(crate) fn without_included(&self, included: &[IncludedFile]) -> anyhow::Result<Self> {
+        let mut config = self.clone();
+        for file in included {
+            for route in &file.routes {
+                let Some(index) = config.routes.iter().position(|kept| kept == route) else {
+                    bail!(
+                        "route '{}' is defined in {}; edit that file instead",
+                        route.name,
+                        file.path.to_string_lossy()
+                    );
+                };
+                config.routes.remove(index);
+            }
+            for service in &file.services {
+                let Some(index) = config.services.iter().position(|kept| kept == service) else {
+                    bail!(
+                        "service '{}' is defined in {}; edit that file instead",
+                        service.name,
+                        file.path.to_string_lossy()
+                    );
+                };
+                config.services.remove(index);
+            }
+        }
+        Ok(config)
+    }
+
     pub fn validate(&self) -> anyhow::Result<()> {
+        let problems = self.problems();
+        if problems.is_empty() {
+            return Ok(());
+        }
+        Err(ConfigErrors(problems).into())
+    }
+
+    /// Every problem [`Self::validate`] rejects the config for, so tools can
+    /// show them all at once.
+    pub fn problems(&self) -> Vec<ConfigProblem> {
+        let mut problems = Problems::default();
         if self.routes.is_empty() {
-            bail!("config must include at least one [[route]] block");
+            problems.add(
+                "route",
+                "required",
+                "config must include at least one [[route]] block",
+            );
         }
 
         if !self.server.health_path.starts_with('/') {
-            bail!("server.health_path must start with '/'");
+            problems.add(
+                "server.health_path",
+                "invalid",
+                "server.health_path must start with '/'",
+            );
         }
         if !self.server.ready_path.starts_with('/') {
-            bail!("server.ready_path must start with '/'");
+            problems.add(
+                "server.ready_path",
+                "invalid",
+                "server.ready_path must start with '/'",
+            );
         }
         if self.server.health_path == self.server.ready_path {
-            bail!("server.health_path and server.ready_path must be different");
+            problems.add(
+                "server.ready_path",
+                "conflict",
+                "server.health_path and server.ready_path must be different",
+            );
+        }
+        if let Some(load_path) = &self.server.load_path {
+            if !load_path.starts_with('/') {
+                problems.add(
+                    "server.load_path",
+                    "invalid",
+                    "server.load_path must start with '/'",
+                );
+            } else if [&self.server.health_path, &self.server.ready_path].contains(&load_path) {
+                problems.add(
+                    "server.load_path",
+                    "conflict",
+                    "server.load_path must differ from server.health_path and server.ready_path",
+                );
+            }
+        }
+        let probes = &self.server.probes;
+        if !probes.public && probes.listen.is_empty() && !probes.admin {
+            problems.add(
+                "server.probes.public",
+                "conflict",
+                "server.probes.public = false needs server.probes.listen or server.probes.admin",
+            );
+        }
+        if probes.admin {
+            let paths = [&self.server.health_path, &self.server.ready_path]
+                .into_iter()
+                .chain(&self.server.load_path);
+            for path in paths {
+                if path == "/" || path.starts_with("/admin/") || path.starts_with("/web/") {
+                    problems.add(
+                        "server.probes.admin",
+                        "conflict",
+                        format!("server.probes.admin cannot serve '{path}' next to the admin API"),
+                    );
+                }
+            }
+        }
+        for (index, path) in self.server.noise_paths.iter().enumerate() {
+            if !path.starts_with('/') {
+                problems.add(
+                    format!("server.noise_paths[{index}]"),
+                    "invalid",
+                    format!("server.noise_paths entry '{path}' must start with '/'"),
+                );
+            }
+        }
+        let workers = &self.server.workers;
+        for (name, service) in [
+            ("proxy", &workers.proxy),
+            ("admin", &workers.admin),
+            ("metrics", &workers.metrics),
+        ] {
+            if service.threads == Some(0) {
+                problems.add(
+                    format!("server.workers.{name}.threads"),
+                    "out_of_range",
+                    format!("server.workers.{name}.threads must be > 0"),
+                );
+            }
+            if let Some(cpu) = service.cpu_affinity.iter().find(|cpu| **cpu >= MAX_CPU) {
+                problems.add(
+                    format!("server.workers.{name}.cpu_affinity"),
+                    "out_of_range",
+                    format!("server.workers.{name}.cpu_affinity includes cpu {cpu} (must be < {MAX_CPU})"),
+                );
+            }
+        }
+        if let Some(cpu) = workers
+            .background_cpu_affinity
+            .iter()
+            .find(|cpu| **cpu >= MAX_CPU)
+        {
+            problems.add(
+                "server.workers.background_cpu_affinity",
+                "out_of_range",
+                format!("server.workers.background_cpu_affinity includes cpu {cpu} (must be < {MAX_CPU})"),
+            );
+        }
+        for (field, value) in [
+            ("socket.backlog", self.server.socket.backlog.map(u64::from)),
+            (
+                "downstream_read_buffer_bytes",
+                self.server
+                    .downstream_read_buffer_bytes
+                    .map(|bytes| bytes as u64),
+            ),
+            (
+                "upstream_write_buffer_bytes",
+                self.server
+                    .upstream_write_buffer_bytes
+                    .map(|bytes| bytes as u64),
+            ),
+            (
+                "downstream_idle_timeout_seconds",
+                self.server.downstream_idle_timeout_seconds,
+            ),
+            (
+                "downstream_max_requests_per_connection",
+                self.server.downstream_max_requests_per_connection,
+            ),
+            (
+                "downstream_max_connection_lifetime_ms",
+                self.server.downstream_max_connection_lifetime_ms,
+            ),
+            (
+                "max_concurrent_requests",
+                self.server.max_concurrent_requests,
+            ),
+        ] {
+            if value == Some(0) {
+                problems.add(
+                    format!("server.{field}"),
+                    "out_of_range",
+                    format!("server.{field} must be > 0"),
+                );
+            }
+        }
+        problems.check(
+            "server.acl",
+            crate::acl::AccessList::from_config(&self.server.acl).context("invalid server.acl"),
+        );
+        for (index, entry) in self.server.trusted_proxies.iter().enumerate() {
+            problems.check(
+                format!("server.trusted_proxies[{index}]"),
+                entry
+                    .parse::<crate::acl::Cidr>()
+                    .context("invalid server.trusted_proxies entry"),
+            );
+        }
+        if let Some(socket) = &self.server.shared_state_socket {
+            // sun_path holds 108 bytes including the terminating NUL.
+            if socket.is_empty() || socket.len() > 107 {
+                problems.add(
+                    "server.shared_state_socket",
+                    "out_of_range",
+                    "server.shared_state_socket must be a path of 1 to 107 bytes",
+                );
+            }
+            #[cfg(not(unix))]
+            problems.add(
+                "server.shared_state_socket",
+                "unsupported",
+                "server.shared_state_socket needs a Unix domain socket, which this platform lacks",
+            );
+        }
+        if let Some(strict) = &self.server.strict_http
+            && let Some(name) = strict
+                .unique_headers
+                .iter()
+                .find(|name| http::HeaderName::from_bytes(name.as_bytes()).is_err())
+        {
+            problems.add(
+                "server.strict_http.unique_headers",
+                "invalid",
+                format!("server.strict_http.unique_headers includes invalid header name {name:?}"),
+            );
+        }
+        if let Some(rollout) = &self.server.rollout {
+            if rollout.ramp_ms == 0 {
+                problems.add(
+                    "server.rollout.ramp_ms",
+                    "out_of_range",
+                    "server.rollout.ramp_ms must be > 0",
+                );
+            }
+            if !(rollout.max_error_rate_increase > 0.0 && rollout.max_error_rate_increase <= 1.0) {
+                problems.add(
+                    "server.rollout.max_error_rate_increase",
+                    "out_of_range",
+                    "server.rollout.max_error_rate_increase must be > 0.0 and <= 1.0",
+                );
+            }
+        }
+        if let Some(shadow) = &self.server.shadow {
+            if shadow.duration_ms == 0 {
+                problems.add(
+                    "server.shadow.duration_ms",
+                    "out_of_range",
+                    "server.shadow.duration_ms must be > 0",
+                );
+            }
+            if self.server.rollout.is_some() {
+                problems.add(
+                    "server.shadow",
+                    "conflict",
+                    "server.shadow cannot be combined with server.rollout",
+                );
+            }
+        }
+        if let Some(keepalive) = &self.server.socket.tcp_keepalive
+            && (keepalive.idle_secs == 0 || keepalive.interval_secs == 0 || keepalive.count == 0)
+        {
+            problems.add(
+                "server.socket.tcp_keepalive",
+                "out_of_range",
+                "server.socket.tcp_keepalive idle_secs, interval_secs and count must be > 0",
+            );
+        }
+        if !(0.0..=1.0).contains(&self.observability.access_log_sample_rate) {
+            problems.add(
+                "observability.access_log_sample_rate",
+                "out_of_range",
+                "observability.access_log_sample_rate must be between 0.0 and 1.0",
+            );
+        }
+        if self.observability.max_metric_label_values == 0 {
+            problems.add(
+                "observability.max_metric_label_values",
+                "out_of_range",
+                "observability.max_metric_label_values must be greater than 0",
+            );
+        }
+        if let Some(header) = &self.observability.access_log_fields.geo_country_header
+            && http::HeaderName::from_bytes(header.as_bytes()).is_err()
+        {
+            problems.add(
+                "observability.access_log_fields.geo_country_header",
+                "invalid",
+                format!("observability.access_log_fields.geo_country_header {header:?} is not a valid header name"),
+            );
         }
 
         // Validate services
         let mut service_names = std::collections::HashSet::new();
-        for service in &self.services {
-            if service_names.contains(&service.name) {
-                bail!("duplicate service name '{}'", service.name);
+        for (index, service) in self.services.iter().enumerate() {
+            let field = |name: &str| format!("service[{index}].{name}");
+            if !service_names.insert(service.name.clone()) {
+                problems.add(
+                    field("name"),
+                    "duplicate",
+                    format!("duplicate service name '{}'", service.name),
+                );
             }
-            service_names.insert(service.name.clone());
 
-            if service.upstreams.is_empty() {
-                bail!(
-                    "service '{}' must include at least one [[service.upstream]]",
-                    service.name
+            if service.upstreams.is_empty() && service.discover.is_none() {
+                problems.add(
+                    field("upstream"),
+                    "required",
+                    format!(
+                        "service '{}' must include at least one [[service.upstream]]",
+                        service.name
+                    ),
+                );
+            }
+            for condition in &service.retry_on {
+                problems.check(
+                    field("retry_on"),
+                    condition
+                        .parse::<crate::runtime::RetryOn>()
+                        .with_context(|| {
+                            format!("service '{}' has an invalid retry_on entry", service.name)
+                        }),
                 );
             }
+            for method in &service.retry_methods {
+                if http::Method::from_bytes(method.as_bytes()).is_err() {
+                    problems.add(
+                        field("retry_methods"),
+                        "invalid",
+                        format!(
+                            "service '{}' retry_methods {method:?} is not a valid method",
+                            service.name
+                        ),
+                    );
+                }
+            }
+            if let Some(backoff) = &service.retry_backoff {
+                if service.retry_backoff_ms > 0 {
+                    problems.add(
+                        field("retry_backoff"),
+                        "conflict",
+                        format!(
+                            "service '{}' sets both retry_backoff_ms and retry_backoff",
+                            service.name
+                        ),
+                    );
+                }
+                if backoff.base_ms == 0 || backoff.max_ms < backoff.base_ms {
+                    problems.add(
+                        field("retry_backoff"),
+                        "out_of_range",
+                        format!(
+                            "service '{}' retry_backoff needs 0 < base_ms <= max_ms",
+                            service.name
+                        ),
+                    );
+                }
+            }
 
-            for upstream in &service.upstreams {
+            for (upstream_index, upstream) in service.upstreams.iter().enumerate() {
+                let field =
+                    |name: &str| format!("service[{index}].upstream[{upstream_index}].{name}");
+                let upstream_problem = |name: &str| {
+                    format!(
+                        "service '{}' upstream '{}' {name}",
+                        service.name, upstream.addr
+                    )
+                };
                 if upstream.addr.trim().is_empty() {
-                    bail!(
-                        "service '{}' includes upstream with empty addr",
-                        service.name
+                    problems.add(
+                        field("addr"),
+                        "required",
+                        format!(
+                            "service '{}' includes upstream with empty addr",
+                            service.name
+                        ),
+                    );
+                }
+                if upstream.max_requests_per_connection == Some(0) {
+                    problems.add(
+                        field("max_requests_per_connection"),
+                        "out_of_range",
+                        upstream_problem("max_requests_per_connection must be > 0"),
+                    );
+                }
+                if upstream.max_connection_lifetime_ms == Some(0) {
+                    problems.add(
+                        field("max_connection_lifetime_ms"),
+                        "out_of_range",
+                        upstream_problem("max_connection_lifetime_ms must be > 0"),
+                    );
+                }
+                if upstream.http2 && upstream.http_version.is_some() {
+                    problems.add(
+                        field("http_version"),
+                        "conflict",
+                        upstream_problem("sets both http2 and http_version"),
                     );
                 }
+                if upstream.preserve_host && upstream.host.is_some() {
+                    problems.add(
+                        field("host"),
+                        "conflict",
+                        upstream_problem("sets both host and preserve_host"),
+                    );
+                }
+                if let Some(hook) = &upstream.drain_hook {
+                    if !hook.starts_with('/') && !hook.starts_with("http://") {
+                        problems.add(
+                            field("drain_hook"),
+                            "invalid",
+                            upstream_problem("drain_hook must be a path or an http:// URL"),
+                        );
+                    }
+                    if upstream.tls && hook.starts_with('/') {
+                        problems.add(
+                            field("drain_hook"),
+                            "conflict",
+                            upstream_problem(
+                                "drain_hook is sent as plain HTTP; use an http:// URL for tls upstreams",
+                            ),
+                        );
+                    }
+                }
+                if let Some(interval) = upstream.resolve_interval_s {
+                    if interval == 0 {
+                        problems.add(
+                            field("resolve_interval_s"),
+                            "out_of_range",
+                            upstream_problem("resolve_interval_s must be > 0"),
+                        );
+                    }
+                    if !upstream
+                        .addr
+                        .rsplit_once(':')
+                        .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok())
+                    {
+                        problems.add(
+                            field("addr"),
+                            "invalid",
+                            upstream_problem("resolve_interval_s needs an addr of host:port"),
+                        );
+                    }
+                }
+                if upstream.client_cert_path.is_some() != upstream.client_key_path.is_some() {
+                    problems.add(
+                        field("client_key_path"),
+                        "required",
+                        upstream_problem("must set both client_cert_path and client_key_path"),
+                    );
+                }
+                if upstream.client_cert_path.is_some() && !upstream.tls {
+                    problems.add(
+                        field("client_cert_path"),
+                        "conflict",
+                        upstream_problem("client_cert_path needs tls = true"),
+                    );
+                }
+                // The stub TLS backend cannot load a certificate into
+                // HttpPeer::client_cert_key.
+                #[cfg(not(feature = "openssl"))]
+                if upstream.client_cert_path.is_some() {
+                    problems.add(
+                        field("client_cert_path"),
+                        "unsupported",
+                        upstream_problem(
+                            "client certificates need prx built with the openssl feature",
+                        ),
+                    );
+                }
+                if !upstream.pinned_cert_sha256.is_empty() {
+                    if !upstream.tls {
+                        problems.add(
+                            field("pinned_cert_sha256"),
+                            "conflict",
+                            upstream_problem("pinned_cert_sha256 needs tls = true"),
+                        );
+                    }
+                    for pin in &upstream.pinned_cert_sha256 {
+                        problems.check(
+                            field("pinned_cert_sha256"),
+                            parse_sha256_pin(pin).with_context(|| {
+                                upstream_problem("has an invalid pinned_cert_sha256 entry")
+                            }),
+                        );
+                    }
+                    // The stub TLS backend exposes neither the peer
+                    // certificate nor a verify callback to check it in.
+                    #[cfg(not(feature = "openssl"))]
+                    problems.add(
+                        field("pinned_cert_sha256"),
+                        "unsupported",
+                        upstream_problem(
+                            "certificate pinning needs prx built with the openssl feature",
+                        ),
+                    );
+                }
+                problems.check(
+                    field("egress"),
+                    upstream
+                        .egress
+                        .validate()
+                        .with_context(|| upstream_problem("has an invalid egress")),
+                );
+                if let Some(check) = &upstream.health_check {
+                    let context = upstream_problem("health_check");
+                    let field = |name: &str| field(&format!("health_check.{name}"));
+                    if check.interval_ms == 0 || check.timeout_ms == 0 {
+                        problems.add(
+                            field("interval_ms"),
+                            "out_of_range",
+                            format!("{context} interval_ms and timeout_ms must be > 0"),
+                        );
+                    }
+                    if check.unhealthy_threshold == 0 || check.healthy_threshold == 0 {
+                        problems.add(
+                            field("healthy_threshold"),
+                            "out_of_range",
+                            format!("{context} thresholds must be > 0"),
+                        );
+                    }
+                    if check.kind == HealthCheckKind::Http {
+                        if upstream.tls {
+                            problems.add(
+                                field("type"),
+                                "conflict",
+                                format!("{context} type \"http\" does not support tls upstreams; use \"tcp\""),
+                            );
+                        }
+                        if !check.path.starts_with('/') {
+                            problems.add(
+                                field("path"),
+                                "invalid",
+                                format!("{context} path must start with '/'"),
+                            );
+                        }
+                        if !(100..=599).contains(&check.expected_status) {
+                            problems.add(
+                                field("expected_status"),
+                                "out_of_range",
+                                format!("{context} expected_status must be a valid HTTP status"),
+                            );
+                        }
+                    }
+                }
             }
 
-            if service.circuit_breaker.enabled {
-                if service.circuit_breaker.consecutive_failures == 0 {
-                    bail!(
-                        "service '{}' circuit_breaker.consecutive_failures must be > 0",
-                        service.name
+            if let Some(outlier) = &service.outlier_detection {
+                let field = |name: &str| field(&format!("outlier_detection.{name}"));
+                if outlier.max_error_rate.is_none() && outlier.max_p99_latency_ms.is_none() {
+                    problems.add(
+                        field("max_error_rate"),
+                        "required",
+                        format!(
+                            "service '{}' outlier_detection needs max_error_rate or max_p99_latency_ms",
+                            service.name
+                        ),
                     );
                 }
-                if service.circuit_breaker.open_ms == 0 {
-                    bail!(
-                        "service '{}' circuit_breaker.open_ms must be > 0",
-                        service.name
+                if outlier.min_requests > crate::runtime::MAX_OUTLIER_SAMPLES {
+                    problems.add(
+                        field("min_requests"),
+                        "out_of_range",
+                        format!(
+                            "service '{}' outlier_detection.min_requests must be <= {}",
+                            service.name,
+                            crate::runtime::MAX_OUTLIER_SAMPLES
+                        ),
+                    );
+                }
+                if let Some(rate) = outlier.max_error_rate
+                    && !(rate > 0.0 && rate <= 1.0)
+                {
+                    problems.add(
+                        field("max_error_rate"),
+                        "out_of_range",
+                        format!(
+                            "service '{}' outlier_detection.max_error_rate must be > 0 and <= 1",
+                            service.name
+                        ),
                     );
                 }
+                for (name, value) in [
+                    ("window_ms", Some(outlier.window_ms)),
+                    ("min_requests", Some(outlier.min_requests as u64)),
+                    ("eject_ms", Some(outlier.eject_ms)),
+                    ("max_p99_latency_ms", outlier.max_p99_latency_ms),
+                ] {
+                    if value == Some(0) {
+                        problems.add(
+                            field(name),
+                            "out_of_range",
+                            format!(
+                                "service '{}' outlier_detection.{name} must be > 0",
+                                service.name
+                            ),
+                        );
+                    }
+                }
             }
-        }
 
-        // Validate routes
-        let mut defaults = 0usize;
-        for route in &self.routes {
-            if route.is_default {
-                defaults += 1;
+            if service.slow_start_s == Some(0) {
+                problems.add(
+                    field("slow_start_s"),
+                    "out_of_range",
+                    format!("service '{}' slow_start_s must be > 0", service.name),
+                );
+            }
+            if let Some(discover) = &service.discover {
+                if discover.name.trim().is_empty() {
+                    problems.add(
+                        field("discover.name"),
+                        "required",
+                        format!("service '{}' discover.name must not be empty", service.name),
+                    );
+                }
+                if discover.interval_s == 0 {
+                    problems.add(
+                        field("discover.interval_s"),
+                        "out_of_range",
+                        format!("service '{}' discover.interval_s must be > 0", service.name),
+                    );
+                }
+                if let Some(nameserver) = &discover.nameserver
+                    && crate::discovery::parse_nameserver(nameserver).is_none()
+                {
+                    problems.add(
+                        field("discover.nameserver"),
+                        "invalid",
+                        format!(
+                            "service '{}' discover.nameserver {nameserver:?} must be ip or ip:port",
+                            service.name
+                        ),
+                    );
+                }
             }
 
-            if route.path_prefix.is_empty() {
-                bail!("route '{}' has empty path_prefix", route.name);
+            let breaker = &service.circuit_breaker;
+            let field = |name: &str| field(&format!("circuit_breaker.{name}"));
+            if breaker.enabled {
+                if breaker.consecutive_failures == 0 {
+                    problems.add(
+                        field("consecutive_failures"),
+                        "out_of_range",
+                        format!(
+                            "service '{}' circuit_breaker.consecutive_failures must be > 0",
+                            service.name
+                        ),
+                    );
+                }
+                if breaker.open_ms == 0 {
+                    problems.add(
+                        field("open_ms"),
+                        "out_of_range",
+                        format!(
+                            "service '{}' circuit_breaker.open_ms must be > 0",
+                            service.name
+                        ),
+                    );
+                }
+                if breaker.half_open_probes == 0 {
+                    problems.add(
+                        field("half_open_probes"),
+                        "out_of_range",
+                        format!(
+                            "service '{}' circuit_breaker.half_open_probes must be > 0",
+                            service.name
+                        ),
+                    );
+                }
+            }
+            for (name, statuses) in [
+                ("failure_statuses", &breaker.failure_statuses),
+                ("overload_statuses", &breaker.overload_statuses),
+            ] {
+                if let Some(status) = statuses
+                    .iter()
+                    .find(|status| !(100..=599).contains(*status))
+                {
+                    problems.add(
+                        field(name),
+                        "out_of_range",
+                        format!(
+                            "service '{}' circuit_breaker.{name} has invalid status {status}",
+                            service.name
+                        ),
+                    );
+                }
             }
-            if !route.path_prefix.starts_with('/') {
-                bail!("route '{}' path_prefix must start with '/'", route.name);
+            if let Some(header) = &breaker.overload_header
+                && http::HeaderName::from_bytes(header.as_bytes()).is_err()
+            {
+                problems.add(
+                    field("overload_header"),
+                    "invalid",
+                    format!(
+                        "service '{}' circuit_breaker.overload_header {header:?} is not a valid header name",
+                        service.name
+                    ),
+                );
             }
-
-            if !service_names.contains(&route.service) {
-                bail!(
-                    "route '{}' references unknown service '{}'",
-                    route.name,
-                    route.service
+            if (breaker.overload_header.is_some() || !breaker.overload_statuses.is_empty())
+                && breaker.overload_ms == 0
+            {
+                problems.add(
+                    field("overload_ms"),
+                    "out_of_range",
+                    format!(
+                        "service '{}' circuit_breaker.overload_ms must be > 0",
+                        service.name
+                    ),
+                );
+            }
+            if breaker.overload_weight_percent > 100 {
+                problems.add(
+                    field("overload_weight_percent"),
+                    "out_of_range",
+                    format!(
+                        "service '{}' circuit_breaker.overload_weight_percent must be <= 100",
+                        service.name
+                    ),
                 );
             }
         }
 
-        if defaults > 1 {
-            bail!("only one route can be marked is_default = true");
+        // Validate apps
+        let mut listeners = std::collections::HashMap::new();
+        for addr in &self.server.listen {
+            listeners.insert(addr.as_str(), "server.listen".to_string());
         }
-
-        Ok(())
-    }
-}
-
-#[derive(Debug, Clone, Deserialize, Serialize)]
-pub struct ServerConfig {
-    #[serde(default = "default_listen")]
-    pub listen: Vec<String>,
-    #[serde(default = "default_health_path")]
-    pub health_path: String,
-    #[serde(default = "default_ready_path")]
-    pub ready_path: String,
-    #[serde(default)]
-    pub threads: Option<usize>,
-    #[serde(default)]
-    pub grace_period_seconds: Option<u64>,
-    #[serde(default)]
-    pub graceful_shutdown_timeout_seconds: Option<u64>,
-    #[serde(default = "default_reload_debounce_ms")]
-    pub config_reload_debounce_ms: u64,
-    #[serde(default)]
-    pub tls: Option<TlsConfig>,
-}
-
-impl Default for ServerConfig {
-    fn default() -> Self {
-        Self {
-            listen: default_listen(),
-            health_path: default_health_path(),
-            ready_path: default_ready_path(),
-            threads: None,
-            grace_period_seconds: None,
-            graceful_shutdown_timeout_seconds: None,
-            config_reload_debounce_ms: default_reload_debounce_ms(),
-            tls: None,
+        if let Some(tls) = &self.server.tls {
+            problems.check(
+                "server.tls",
+                tls.validate().context("server.tls is invalid"),
+            );
+            // Pingora finishes the TLS handshake before prx sees the stream,
+            // so a PROXY header in front of it would break the handshake.
+            if self.server.proxy_protocol.is_some() {
+                problems.add(
+                    "server.proxy_protocol",
+                    "conflict",
+                    "server.proxy_protocol cannot be combined with server.tls",
+                );
+            }
+            listeners.insert(tls.listen.as_str(), "server.tls".to_string());
+        }
+        let mut app_names = std::collections::HashSet::new();
+        for (index, app) in self.apps.iter().enumerate() {
+            let field = |name: &str| format!("app[{index}].{name}");
+            if app.name.trim().is_empty() {
+                problems.add(field("name"), "required", "app name cannot be empty");
+            } else if !app_names.insert(app.name.as_str()) {
+                problems.add(
+                    field("name"),
+                    "duplicate",
+                    format!("duplicate app name '{}'", app.name),
+                );
+            }
+            if app.listen.is_empty() && app.tls.is_none() {
+                problems.add(
+                    field("listen"),
+                    "required",
+                    format!("app '{}' must set listen or tls", app.name),
+                );
+            }
+            if let Some(tls) = &app.tls {
+                problems.check(
+                    field("tls"),
+                    tls.validate()
+                        .with_context(|| format!("app '{}' tls is invalid", app.name)),
+                );
+                if app.proxy_protocol.is_some() {
+                    problems.add(
+                        field("proxy_protocol"),
+                        "conflict",
+                        format!("app '{}' cannot combine proxy_protocol and tls", app.name),
+                    );
+                }
+            }
+            if let Some(rate) = app.observability.access_log_sample_rate
+                && !(0.0..=1.0).contains(&rate)
+            {
+                problems.add(
+                    field("observability.access_log_sample_rate"),
+                    "out_of_range",
+                    format!(
+                        "app '{}' observability.access_log_sample_rate must be between 0.0 and 1.0",
+                        app.name
+                    ),
+                );
+            }
+            problems.check(
+                field("acl"),
+                crate::acl::AccessList::from_config(&app.acl)
+                    .with_context(|| format!("app '{}' has an invalid acl", app.name)),
+            );
+            let owner = format!("app '{}'", app.name);
+            for addr in app
+                .listen
+                .iter()
+                .chain(app.tls.iter().map(|tls| &tls.listen))
+            {
+                if let Some(other) = listeners.insert(addr.as_str(), owner.clone()) {
+                    problems.add(
+                        field("listen"),
+                        "conflict",
+                        format!("listener {addr} is used by both {other} and {owner}"),
+                    );
+                }
+            }
+            if !self
+                .routes
+                .iter()
+                .any(|route| route.app.as_deref() == Some(&app.name))
+            {
+                problems.add(
+                    field("name"),
+                    "required",
+                    format!("app '{}' has no routes", app.name),
+                );
+            }
         }
-    }
-}
 
-fn default_listen() -> Vec<String> {
-    vec!["0.0.0.0:8080".to_string()]
-}
+        let mut tcp_route_names = std::collections::HashSet::new();
+        for (index, tcp_route) in self.tcp_routes.iter().enumerate() {
+            let field = |name: &str| format!("tcp_route[{index}].{name}");
+            if tcp_route.name.trim().is_empty() {
+                problems.add(field("name"), "required", "tcp_route name cannot be empty");
+            }
+            // Both kinds of route share the `route` metric label.
+            if !tcp_route_names.insert(tcp_route.name.as_str())
+                || self.routes.iter().any(|route| route.name == tcp_route.name)
+            {
+                problems.add(
+                    field("name"),
+                    "duplicate",
+                    format!("duplicate route name '{}'", tcp_route.name),
+                );
+            }
+            if tcp_route.listen.is_empty() {
+                problems.add(
+                    field("listen"),
+                    "required",
+                    format!("tcp_route '{}' must set listen", tcp_route.name),
+                );
+            }
+            let owner = format!("tcp_route '{}'", tcp_route.name);
+            for addr in &tcp_route.listen {
+                if let Some(other) = listeners.insert(addr.as_str(), owner.clone()) {
+                    problems.add(
+                        field("listen"),
+                        "conflict",
+                        format!("listener {addr} is used by both {other} and {owner}"),
+                    );
+                }
+            }
+            match self
+                .services
+                .iter()
+                .find(|service| service.name == tcp_route.service)
+            {
+                None => problems.add(
+                    field("service"),
+                    "unknown_reference",
+                    format!(
+                        "tcp_route '{}' references unknown service '{}'",
+                        tcp_route.name, tcp_route.service
+                    ),
+                ),
+                Some(service) => {
+                    if let Some(upstream) = service.upstreams.iter().find(|upstream| upstream.tls) {
+                        problems.add(
+                            field("service"),
+                            "unsupported",
+                            format!(
+                                "tcp_route '{}' service '{}' upstream '{}' sets tls, which tcp routes do not originate",
+                                tcp_route.name, service.name, upstream.addr
+                            ),
+                        );
+                    }
+                }
+            }
+            if tcp_route.idle_timeout_ms == Some(0) {
+                problems.add(
+                    field("idle_timeout_ms"),
+                    "out_of_range",
+                    format!("tcp_route '{}' idle_timeout_ms must be > 0", tcp_route.name),
+                );
+            }
+            problems.check(
+                field("acl"),
+                crate::acl::AccessList::from_config(&tcp_route.acl)
+                    .with_context(|| format!("tcp_route '{}' has an invalid acl", tcp_route.name)),
+            );
+        }
 
-fn default_reload_debounce_ms() -> u64 {
-    250
-}
+        if let Some(waf) = &self.waf {
+            if waf.rule_files.is_empty() {
+                problems.add(
+                    "waf.rule_files",
+                    "required",
+                    "waf.rule_files must list at least one rule file",
+                );
+            } else {
+                problems.check("waf.rule_files", crate::waf::RuleSet::load(waf));
+            }
+        }
 
-fn default_health_path() -> String {
-    "/healthz".to_string()
-}
+        if let Some(reputation) = &self.reputation {
+            if reputation.http.is_some() && reputation.redis.is_some() {
+                problems.add(
+                    "reputation.redis",
+                    "conflict",
+                    "reputation.http and reputation.redis cannot both be set",
+                );
+            }
+            if let Some(url) = &reputation.http {
+                if url.starts_with("http://") || url.starts_with("https://") {
+                    problems.check(
+                        "reputation.http",
+                        crate::source::RemoteUrl::parse(url, crate::source::DEFAULT_S3_ENDPOINT)
+                            .context("invalid reputation.http"),
+                    );
+                } else {
+                    problems.add(
+                        "reputation.http",
+                        "invalid",
+                        "reputation.http must be an http(s) url",
+                    );
+                }
+            }
+            if let Some(addr) = &reputation.redis
+                && addr
+                    .rsplit_once(':')
+                    .is_none_or(|(host, port)| host.is_empty() || port.parse::<u16>().is_err())
+            {
+                problems.add(
+                    "reputation.redis",
+                    "invalid",
+                    "reputation.redis must be host:port",
+                );
+            }
+            if reputation.timeout_ms == 0 {
+                problems.add(
+                    "reputation.timeout_ms",
+                    "out_of_range",
+                    "reputation.timeout_ms must be > 0",
+                );
+            }
+            if http::HeaderName::from_bytes(reputation.tag_header.as_bytes()).is_err() {
+                problems.add(
+                    "reputation.tag_header",
+                    "invalid",
+                    format!(
+                        "reputation.tag_header {:?} is not a valid header name",
+                        reputation.tag_header
+                    ),
+                );
+            }
+        }
 
-fn default_ready_path() -> String {
-    "/readyz".to_string()
-}
+        // Validate tenants
+        let mut tenant_names = std::collections::HashSet::new();
+        for (index, tenant) in self.tenants.iter().enumerate() {
+            let field = |name: &str| format!("tenant[{index}].{name}");
+            if tenant.name.trim().is_empty() {
+                problems.add(field("name"), "required", "tenant name cannot be empty");
+            } else if !tenant_names.insert(tenant.name.as_str()) {
+                problems.add(
+                    field("name"),
+                    "duplicate",
+                    format!("duplicate tenant name '{}'", tenant.name),
+                );
+            }
+            if tenant.admin_token.trim().is_empty() {
+                problems.add(
+                    field("admin_token"),
+                    "required",
+                    format!("tenant '{}' admin_token cannot be empty", tenant.name),
+                );
+            }
+            for host in &tenant.hosts {
+                let domain = host.strip_prefix("*.").unwrap_or(host);
+                if domain.trim().is_empty() || domain.contains('*') {
+                    problems.add(
+                        field("hosts"),
+                        "invalid",
+                        format!("tenant '{}' has an invalid host {host:?}", tenant.name),
+                    );
+                } else if let Some(other) = self.tenants[..index]
+                    .iter()
+                    .find(|other| other.hosts.contains(host))
+                {
+                    problems.add(
+                        field("hosts"),
+                        "duplicate",
+                        format!(
+                            "tenants '{}' and '{}' both own host {host}",
+                            other.name, tenant.name
+                        ),
+                    );
+                }
+            }
+            for service in &tenant.services {
+                if !self.services.iter().any(|svc| &svc.name == service) {
+                    problems.add(
+                        field("services"),
+                        "unknown_reference",
+                        format!(
+                            "tenant '{}' references unknown service '{service}'",
+                            tenant.name
+                        ),
+                    );
+                } else if let Some(other) = self.tenants[..index]
+                    .iter()
+                    .find(|other| other.services.contains(service))
+                {
+                    problems.add(
+                        field("services"),
+                        "duplicate",
+                        format!(
+                            "tenants '{}' and '{}' both own service {service}",
+                            other.name, tenant.name
+                        ),
+                    );
+                }
+            }
+        }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+        if self
+            .admin
+            .as_ref()
+            .and_then(|admin| admin.audit_log.as_deref())
+            .is_some_and(|path| path.trim().is_empty())
+        {
+            problems.add(
+                "admin.audit_log",
+                "invalid",
+                "admin.audit_log cannot be empty",
+            );
+        }
+        if let Some(auth) = self.admin.as_ref().and_then(|admin| admin.auth.as_ref()) {
+            if auth.tokens.is_empty() && auth.basic.is_empty() {
+                problems.add(
+                    "admin.auth",
+                    "required",
+                    "admin.auth needs at least one [[admin.auth.token]] or [[admin.auth.basic]]",
+                );
+            }
+            let mut names = std::collections::HashSet::new();
+            for (index, token) in auth.tokens.iter().enumerate() {
+                let field = |name: &str| format!("admin.auth.token[{index}].{name}");
+                if token.name.trim().is_empty() {
+                    problems.add(
+                        field("name"),
+                        "required",
+                        "admin token name cannot be empty",
+                    );
+                } else if !names.insert(token.name.as_str()) {
+                    problems.add(
+                        field("name"),
+                        "duplicate",
+                        format!("duplicate admin token name '{}'", token.name),
+                    );
+                }
+                if token.token.trim().is_empty() {
+                    problems.add(
+                        field("token"),
+                        "required",
+                        format!("admin token '{}' cannot be empty", token.name),
+                    );
+                }
+            }
+            let mut usernames = std::collections::HashSet::new();
+            for (index, basic) in auth.basic.iter().enumerate() {
+                let field = |name: &str| format!("admin.auth.basic[{index}].{name}");
+                if basic.username.trim().is_empty() || basic.username.contains(':') {
+                    problems.add(
+                        field("username"),
+                        "invalid",
+                        "admin username must be non-empty and cannot contain ':'",
+                    );
+                } else if !usernames.insert(basic.username.as_str()) {
+                    problems.add(
+                        field("username"),
+                        "duplicate",
+                        format!("duplicate admin username '{}'", basic.username),
+                    );
+                }
+                if basic.password.is_empty() {
+                    problems.add(
+                        field("password"),
+                        "required",
+                        format!("admin user '{}' password cannot be empty", basic.username),
+                    );
+                }
+            }
+        }
+
+        // Validate routes
+        let mut defaults = BTreeMap::new();
+        let mut matchers = BTreeMap::new();
+        for (index, route) in self.routes.iter().enumerate() {
+            let field = |name: &str| format!("route[{index}].{name}");
+            let route_problem = |problem: &str| format!("route '{}' {problem}", route.name);
+            if let Some(tenant) = &route.tenant
+                && !tenant_names.contains(tenant.as_str())
+            {
+                problems.add(
+                    field("tenant"),
+                    "unknown_reference",
+                    route_problem(&format!("references unknown tenant '{tenant}'")),
+                );
+            }
+            if let Some(app) = &route.app
+                && !app_names.contains(app.as_str())
+            {
+                problems.add(
+                    field("app"),
+                    "unknown_reference",
+                    route_problem(&format!("references unknown app '{app}'")),
+                );
+            }
+            if route.is_default {
+                defaults
+                    .entry(route.app.as_deref())
+                    .or_insert_with(Vec::new)
+                    .push(index);
+            }
+            if let Some(first) = matchers.insert(route.matcher(), route) {
+                // Put back the first, so a third copy is compared with it.
+                matchers.insert(first.matcher(), first);
+                problems.add(
+                    field("path_prefix"),
+                    "duplicate",
+                    route_problem(&format!(
+                        "matches the same requests as route '{}' (same host, path_prefix and \
+                         headers)",
+                        first.name
+                    )),
+                );
+            }
+
+            if route.path_prefix.is_empty() {
+                problems.add(
+                    field("path_prefix"),
+                    "required",
+                    route_problem("has empty path_prefix"),
+                );
+            } else if !route.path_prefix.starts_with('/') {
+                problems.add(
+                    field("path_prefix"),
+                    "invalid",
+                    route_problem("path_prefix must start with '/'"),
+                );
+            }
+            for (name, value) in &route.headers {
+                if http::HeaderName::from_bytes(name.as_bytes()).is_err()
+                    || http::HeaderValue::from_str(value).is_err()
+                {
+                    problems.add(
+                        field(&format!("headers.{name}")),
+                        "invalid",
+                        route_problem(&format!("has invalid header match {name} = {value:?}")),
+                    );
+                }
+            }
+            if let Some(rate) = route.observability.access_log_sample_rate
+                && !(0.0..=1.0).contains(&rate)
+            {
+                problems.add(
+                    field("observability.access_log_sample_rate"),
+                    "out_of_range",
+                    route_problem(
+                        "observability.access_log_sample_rate must be between 0.0 and 1.0",
+                    ),
+                );
+            }
+
+            if let Some(bandwidth) = &route.bandwidth {
+                for (name, value) in [
+                    ("upload_bytes_per_sec", bandwidth.upload_bytes_per_sec),
+                    ("download_bytes_per_sec", bandwidth.download_bytes_per_sec),
+                    (
+                        "request_upload_bytes_per_sec",
+                        bandwidth.request_upload_bytes_per_sec,
+                    ),
+                    (
+                        "request_download_bytes_per_sec",
+                        bandwidth.request_download_bytes_per_sec,
+                    ),
+                    ("burst_bytes", bandwidth.burst_bytes),
+                ] {
+                    if value == Some(0) {
+                        problems.add(
+                            field(&format!("bandwidth.{name}")),
+                            "out_of_range",
+                            route_problem(&format!("bandwidth.{name} must be > 0")),
+                        );
+                    }
+                }
+            }
+            for (name, value) in [
+                ("max_response_bytes", route.max_response_bytes),
+                ("max_request_body_bytes", route.max_request_body_bytes),
+                ("max_concurrent_requests", route.max_concurrent_requests),
+                ("timeout_ms", route.timeout_ms),
+            ] {
+                if value == Some(0) {
+                    problems.add(
+                        field(name),
+                        "out_of_range",
+                        route_problem(&format!("{name} must be > 0")),
+                    );
+                }
+            }
+            if let Some(bytes) = route.retry_body_bytes
+                && !(1..=MAX_RETRY_BODY_BYTES).contains(&bytes)
+            {
+                problems.add(
+                    field("retry_body_bytes"),
+                    "out_of_range",
+                    route_problem(&format!(
+                        "retry_body_bytes must be > 0 and <= {MAX_RETRY_BODY_BYTES}"
+                    )),
+                );
+            }
+            if let Some(cache) = &route.preflight_cache
+                && (cache.ttl_ms == 0 || cache.max_entries == 0)
+            {
+                problems.add(
+                    field("preflight_cache"),
+                    "out_of_range",
+                    route_problem("preflight_cache.ttl_ms and max_entries must be > 0"),
+                );
+            }
+            if let Some(idempotency) = &route.idempotency {
+                if idempotency.max_entries == 0 || idempotency.max_body_bytes == 0 {
+                    problems.add(
+                        field("idempotency"),
+                        "out_of_range",
+                        route_problem("idempotency.max_entries and max_body_bytes must be > 0"),
+                    );
+                }
+                if http::HeaderName::from_bytes(idempotency.header.as_bytes()).is_err() {
+                    problems.add(
+                        field("idempotency.header"),
+                        "invalid",
+                        route_problem(&format!(
+                            "idempotency.header {:?} is not a valid header name",
+                            idempotency.header
+                        )),
+                    );
+                }
+                if let Some(identity) = &idempotency.identity {
+                    problems.check(
+                        field("idempotency.identity"),
+                        identity
+                            .parse::<crate::ratelimit::RateLimitKey>()
+                            .with_context(|| route_problem("has an invalid idempotency.identity")),
+                    );
+                }
+            }
+            if let Some(limit) = &route.rate_limit {
+                if limit.rps == 0 || limit.burst == Some(0) {
+                    problems.add(
+                        field("rate_limit"),
+                        "out_of_range",
+                        route_problem("rate_limit.rps and burst must be > 0"),
+                    );
+                }
+                problems.check(
+                    field("rate_limit.key"),
+                    limit
+                        .key
+                        .parse::<crate::ratelimit::RateLimitKey>()
+                        .with_context(|| route_problem("has an invalid rate_limit")),
+                );
+            }
+            problems.check(
+                field("egress"),
+                route
+                    .egress
+                    .validate()
+                    .with_context(|| route_problem("has an invalid egress")),
+            );
+            if let Some(proxy) = &route.outbound_proxy {
+                problems.check(
+                    field("outbound_proxy"),
+                    proxy
+                        .parse::<crate::outbound::OutboundProxy>()
+                        .with_context(|| route_problem("has an invalid outbound_proxy")),
+                );
+            }
+            if let Some(template) = &route.rewrite_path {
+                if route.strip_prefix {
+                    problems.add(
+                        field("rewrite_path"),
+                        "conflict",
+                        route_problem("sets both strip_prefix and rewrite_path"),
+                    );
+                }
+                if !template.starts_with('/')
+                    || template.parse::<http::uri::PathAndQuery>().is_err()
+                {
+                    problems.add(
+                        field("rewrite_path"),
+                        "invalid",
+                        route_problem("rewrite_path must be a path starting with '/'"),
+                    );
+                }
+            }
+
+            if !service_names.contains(&route.service) {
+                problems.add(
+                    field("service"),
+                    "unknown_reference",
+                    route_problem(&format!("references unknown service '{}'", route.service)),
+                );
+            } else if route.hash_affinity == HashAffinity::PreferReuse
+                && self
+                    .services
+                    .iter()
+                    .any(|service| service.name == route.service && service.lb != LbStrategy::Hash)
+            {
+                problems.add(
+                    field("hash_affinity"),
+                    "conflict",
+                    route_problem("hash_affinity needs a service with lb = \"hash\""),
+                );
+            }
+            let mut group_names = std::collections::HashSet::new();
+            for (group_index, group) in route.groups.iter().enumerate() {
+                let field = |name: &str| field(&format!("group[{group_index}].{name}"));
+                if group.name.trim().is_empty() {
+                    problems.add(
+                        field("name"),
+                        "required",
+                        route_problem("has a group with an empty name"),
+                    );
+                } else if !group_names.insert(group.name.as_str()) {
+                    problems.add(
+                        field("name"),
+                        "duplicate",
+                        route_problem(&format!("has duplicate group '{}'", group.name)),
+                    );
+                }
+                if !service_names.contains(&group.service) {
+                    problems.add(
+                        field("service"),
+                        "unknown_reference",
+                        route_problem(&format!(
+                            "group '{}' references unknown service '{}'",
+                            group.name, group.service
+                        )),
+                    );
+                }
+            }
+            if let Some(ms) = route.websocket_idle_timeout_ms {
+                if !route.websocket {
+                    problems.add(
+                        field("websocket_idle_timeout_ms"),
+                        "conflict",
+                        route_problem("sets websocket_idle_timeout_ms without websocket = true"),
+                    );
+                }
+                if ms == 0 {
+                    problems.add(
+                        field("websocket_idle_timeout_ms"),
+                        "out_of_range",
+                        route_problem("websocket_idle_timeout_ms must be > 0"),
+                    );
+                }
+            }
+            if let Some(validation) = &route.response_validation {
+                let field = |name: &str| field(&format!("response_validation.{name}"));
+                if validation.required_headers.is_empty()
+                    && validation.max_latency_ms.is_none()
+                    && validation.content_types.is_empty()
+                {
+                    problems.add(
+                        field("required_headers"),
+                        "required",
+                        route_problem(
+                            "response_validation needs required_headers, max_latency_ms or content_types",
+                        ),
+                    );
+                }
+                if let Some(name) = validation
+                    .required_headers
+                    .iter()
+                    .find(|name| http::HeaderName::from_bytes(name.as_bytes()).is_err())
+                {
+                    problems.add(
+                        field("required_headers"),
+                        "invalid",
+                        route_problem(&format!(
+                            "response_validation.required_headers includes invalid header name {name:?}"
+                        )),
+                    );
+                }
+                if validation.max_latency_ms == Some(0) {
+                    problems.add(
+                        field("max_latency_ms"),
+                        "out_of_range",
+                        route_problem("response_validation.max_latency_ms must be > 0"),
+                    );
+                }
+                if let Some(content_type) = validation.content_types.iter().find(|content_type| {
+                    !content_type.split_once('/').is_some_and(|(kind, subtype)| {
+                        !kind.trim().is_empty() && !subtype.trim().is_empty()
+                    })
+                }) {
+                    problems.add(
+                        field("content_types"),
+                        "invalid",
+                        route_problem(&format!(
+                            "response_validation.content_types includes {content_type:?}, not a type/subtype"
+                        )),
+                    );
+                }
+            }
+            let mut mapped = std::collections::HashSet::new();
+            for (mapping_index, mapping) in route.status_map.iter().enumerate() {
+                let field = |name: &str| field(&format!("status_map[{mapping_index}].{name}"));
+                if !(200..=599).contains(&mapping.from) || !(200..=599).contains(&mapping.to) {
+                    problems.add(
+                        field("to"),
+                        "out_of_range",
+                        route_problem(&format!(
+                            "status_map {} -> {} must map statuses within 200..=599",
+                            mapping.from, mapping.to
+                        )),
+                    );
+                }
+                if !mapped.insert(mapping.from) {
+                    problems.add(
+                        field("from"),
+                        "duplicate",
+                        route_problem(&format!("maps status {} twice", mapping.from)),
+                    );
+                }
+                if mapping.body.is_some() && matches!(mapping.to, 204 | 304) {
+                    problems.add(
+                        field("body"),
+                        "conflict",
+                        route_problem(&format!(
+                            "status_map cannot send a body with status {}",
+                            mapping.to
+                        )),
+                    );
+                }
+                for (name, value) in &mapping.headers {
+                    if http::HeaderName::from_bytes(name.as_bytes()).is_err()
+                        || http::HeaderValue::from_str(value).is_err()
+                    {
+                        problems.add(
+                            field(&format!("headers.{name}")),
+                            "invalid",
+                            route_problem(&format!(
+                                "status_map {} has invalid header {name} = {value:?}",
+                                mapping.from
+                            )),
+                        );
+                    }
+                }
+            }
+            if route.protocol == RouteProtocol::Grpc {
+                let services = std::iter::once(&route.service)
+                    .chain(route.groups.iter().map(|group| &group.service));
+                for name in services {
+                    let forced_h1 = self
+                        .services
+                        .iter()
+                        .filter(|service| &service.name == name)
+                        .flat_map(|service| &service.upstreams)
+                        .find(|upstream| upstream.http_version == Some(UpstreamHttpVersion::H1));
+                    if let Some(upstream) = forced_h1 {
+                        problems.add(
+                            field("protocol"),
+                            "conflict",
+                            route_problem(&format!(
+                                "is a grpc route, but service '{name}' upstream {} sets http_version = \"h1\"",
+                                upstream.addr
+                            )),
+                        );
+                    }
+                }
+            }
+            if route
+                .groups
+                .iter()
+                .map(|group| u64::from(group.percent))
+                .sum::<u64>()
+                > 100
+            {
+                problems.add(
+                    field("group"),
+                    "out_of_range",
+                    route_problem("group percents add up to more than 100"),
+                );
+            }
+            if let Some(key) = &route.group_key {
+                problems.check(
+                    field("group_key"),
+                    key.parse::<crate::ratelimit::RateLimitKey>()
+                        .with_context(|| route_problem("has an invalid group_key")),
+                );
+            }
+            if let Some(header) = &route.group_header
+                && http::HeaderName::from_bytes(header.as_bytes()).is_err()
+            {
+                problems.add(
+                    field("group_header"),
+                    "invalid",
+                    route_problem(&format!(
+                        "group_header {header:?} is not a valid header name"
+                    )),
+                );
+            }
+        }
+
+        for (app, routes) in defaults {
+            if let [_, second, ..] = routes[..] {
+                problems.add(
+                    format!("route[{second}].is_default"),
+                    "duplicate",
+                    match app {
+                        Some(app) => {
+                            format!("only one route in app '{app}' can be marked is_default = true")
+                        }
+                        None => "only one route can be marked is_default = true".to_string(),
+                    },
+                );
+            }
+        }
+
+        match crate::secret::reveal(self).context("config contains undecryptable values") {
+            Ok(revealed) => {
+                let mut tokens = std::collections::HashMap::new();
+                for (index, tenant) in revealed.tenants.iter().enumerate() {
+                    let token = tenant.admin_token.as_str();
+                    if let Some(other) = tokens.insert(token, tenant.name.as_str()) {
+                        problems.add(
+                            format!("tenant[{index}].admin_token"),
+                            "duplicate",
+                            format!(
+                                "tenants '{other}' and '{}' share an admin_token",
+                                tenant.name
+                            ),
+                        );
+                    }
+                }
+                let auth = revealed
+                    .admin
+                    .as_ref()
+                    .and_then(|admin| admin.auth.as_ref());
+                for (index, admin) in auth.into_iter().flat_map(|auth| &auth.tokens).enumerate() {
+                    if let Some(tenant) = tokens.get(admin.token.as_str()) {
+                        problems.add(
+                            format!("admin.auth.token[{index}].token"),
+                            "duplicate",
+                            format!(
+                                "admin token '{}' is also the admin_token of tenant '{tenant}'",
+                                admin.name
+                            ),
+                        );
+                    }
+                }
+            }
+            Err(err) => problems.add("", "invalid", format!("{err:#}")),
+        }
+
+        problems.0
+    }
+
+    /// Every problem of the config at once: the errors [`Self::validate`]
+    /// rejects it for, and warnings that do not stop it from loading.
+    pub fn report(&self) -> ValidationReport {
+        ValidationReport {
+            errors: self.problems(),
+            warnings: self.warnings(),
+        }
+    }
+
+    /// Settings that load but likely do not do what was meant: routes no
+    /// request can reach and services nothing sends traffic to.
+    pub fn warnings(&self) -> Vec<ConfigProblem> {
+        let mut warnings = Problems::default();
+        // The order routes are tried in, as the runtime sorts them.
+        let mut order: Vec<usize> = (0..self.routes.len()).collect();
+        order.sort_by(|&a, &b| {
+            let (a, b) = (&self.routes[a], &self.routes[b]);
+            b.path_prefix
+                .len()
+                .cmp(&a.path_prefix.len())
+                .then_with(|| b.headers.len().cmp(&a.headers.len()))
+                .then_with(|| a.name.cmp(&b.name))
+        });
+        for (position, &index) in order.iter().enumerate() {
+            let route = &self.routes[index];
+            if route.is_default {
+                continue;
+            }
+            let shadowing = order[..position]
+                .iter()
+                .map(|&earlier| &self.routes[earlier])
+                .find(|earlier| {
+                    // Exact copies are rejected by `problems` already.
+                    earlier.matcher() != route.matcher()
+                        && earlier.app == route.app
+                        && route.path_prefix.starts_with(&earlier.path_prefix)
+                        && host_covers(earlier.host.as_deref(), route.host.as_deref())
+                        && earlier
+                            .headers
+                            .iter()
+                            .all(|(name, value)| route.headers.get(name) == Some(value))
+                });
+            if let Some(earlier) = shadowing {
+                warnings.add(
+                    format!("route[{index}].path_prefix"),
+                    "shadowed",
+                    format!(
+                        "route '{}' is never matched: route '{}' is tried first and takes \
+                         every request it would",
+                        route.name, earlier.name
+                    ),
+                );
+            }
+        }
+        for (index, service) in self.services.iter().enumerate() {
+            let used = self.routes.iter().any(|route| {
+                route.service == service.name
+                    || route
+                        .groups
+                        .iter()
+                        .any(|group| group.service == service.name)
+            }) || self
+                .tcp_routes
+                .iter()
+                .any(|route| route.service == service.name);
+            if !used {
+                warnings.add(
+                    format!("service[{index}]"),
+                    "unused",
+                    format!("service '{}' is not used by any route", service.name),
+                );
+            }
+        }
+        warnings.0
+    }
+}
+
+/// Whether every host `covered` matches is also matched by `pattern`, `None`
+/// being any host.
+fn host_covers(pattern: Option<&str>, covered: Option<&str>) -> bool {
+    let Some(pattern) = pattern.map(str::to_ascii_lowercase) else {
+        return true;
+    };
+    let Some(covered) = covered.map(str::to_ascii_lowercase) else {
+        return false;
+    };
+    match pattern.strip_prefix("*.") {
+        Some(suffix) => covered
+            .strip_prefix("*.")
+            .unwrap_or(&covered)
+            .strip_suffix(suffix)
+            .is_some_and(|rest| rest.is_empty() || rest.ends_with('.')),
+        None => pattern == covered,
+    }
+}
+
+/// What [`PrxConfig::report`] found.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ValidationReport {
+    pub errors: Vec<ConfigProblem>,
+    pub warnings: Vec<ConfigProblem>,
+}
+
+/// One problem found by [`PrxConfig::validate`] or [`PrxConfig::warnings`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ConfigProblem {
+    /// Path of the offending setting in TOML terms, e.g.
+    /// `service[0].upstream[1].drain_hook`; empty for the config as a whole.
+    pub field: String,
+    /// `required`, `invalid`, `out_of_range`, `conflict`, `duplicate`,
+    /// `unknown_reference` or `unsupported`; `syntax` for TOML that does not
+    /// parse. Warnings are `shadowed` or `unused`.
+    pub code: &'static str,
+    pub message: String,
+    /// The included file the setting is in; `None` for the config file.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+}
+
+/// The error [`PrxConfig::validate`] returns: every problem of the config,
+/// in the order of the config's sections.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigErrors(pub Vec<ConfigProblem>);
+
+impl std::fmt::Display for ConfigErrors {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.0[..] {
+            [problem] => write!(f, "{}", problem.message),
+            problems => {
+                write!(f, "{} config problems", problems.len())?;
+                for problem in problems {
+                    write!(f, "; {}", problem.message)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigErrors {}
+
+#[derive(Debug, Default)]
+struct Problems(Vec<ConfigProblem>);
+
+impl Problems {
+    fn add(&mut self, field: impl Into<String>, code: &'static str, message: impl Into<String>) {
+        self.0.push(ConfigProblem {
+            field: field.into(),
+            code,
+            message: message.into(),
+            file: None,
+        });
+    }
+
+    /// Records a failed check of a nested setting, causes included.
+    fn check<T>(&mut self, field: impl Into<String>, result: anyhow::Result<T>) {
+        if let Err(err) = result {
+            self.add(field, "invalid", format!("{err:#}"));
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct ServerConfig {
+    #[serde(default = "default_listen")]
+    pub listen: Vec<String>,
+    #[serde(default = "default_health_path")]
+    pub health_path: String,
+    #[serde(default = "default_ready_path")]
+    pub ready_path: String,
+    /// Path answering how busy this instance is, as JSON; off without one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub load_path: Option<String>,
+    /// Paths left out of request metrics, access logs and rate limits like
+    /// the health, ready and load paths; a trailing `*` matches a prefix.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub noise_paths: Vec<String>,
+    /// Count, log and rate limit those paths like any other.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub observe_noise: bool,
+    /// Where the health, ready and load paths are answered.
+    #[serde(default, skip_serializing_if = "ProbesConfig::is_default")]
+    pub probes: ProbesConfig,
+    #[serde(default)]
+    pub threads: Option<usize>,
+    #[serde(default)]
+    pub grace_period_seconds: Option<u64>,
+    #[serde(default)]
+    pub graceful_shutdown_timeout_seconds: Option<u64>,
+    /// Size of the shared upstream keepalive pool (idle connections across all upstreams).
+    #[serde(default)]
+    pub upstream_keepalive_pool_size: Option<usize>,
+    /// Userspace read buffer of each downstream connection (pingora default: 64 KiB).
+    #[serde(default)]
+    pub downstream_read_buffer_bytes: Option<usize>,
+    /// Userspace write buffer of each new upstream connection (unbuffered by default).
+    #[serde(default)]
+    pub upstream_write_buffer_bytes: Option<usize>,
+    /// How long a kept-alive HTTP/1 client connection may sit idle between requests.
+    #[serde(default)]
+    pub downstream_idle_timeout_seconds: Option<u64>,
+    /// Close an HTTP/1 client connection after it has served this many requests.
+    #[serde(default)]
+    pub downstream_max_requests_per_connection: Option<u64>,
+    /// Close an HTTP/1 client connection after its first response past this age.
+    #[serde(default)]
+    pub downstream_max_connection_lifetime_ms: Option<u64>,
+    /// Accept prior-knowledge HTTP/2 on plaintext listeners.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub h2c: bool,
+    /// PROXY protocol header (`v1` or `v2`) every connection on `listen`
+    /// starts with, so prx behind an L4 load balancer sees real client IPs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proxy_protocol: Option<ProxyProtocolVersion>,
+    /// Requests in flight across all listeners past which new ones get a 503.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_concurrent_requests: Option<u64>,
+    #[serde(default = "default_reload_debounce_ms")]
+    pub config_reload_debounce_ms: u64,
+    /// How long requests to an upstream a reload removed may take to finish
+    /// before they are cut.
+    #[serde(default = "default_drain_timeout_s")]
+    pub drain_timeout_s: u64,
+    /// Stage config updates: changed routes and services get a growing share
+    /// of traffic instead of all of it at once.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rollout: Option<RolloutConfig>,
+    /// Hold config updates back while their routing is compared against the
+    /// current config on live requests.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shadow: Option<ShadowConfig>,
+    /// Reject ambiguous or malformed requests before they reach a backend.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub strict_http: Option<StrictHttpConfig>,
+    /// Client networks checked on every listener right after accept.
+    #[serde(default, skip_serializing_if = "AccessControlConfig::is_empty")]
+    pub acl: AccessControlConfig,
+    /// Peers whose `X-Forwarded-*` and `Forwarded` headers are kept and
+    /// appended to; everyone else's are replaced.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub trusted_proxies: Vec<String>,
+    /// Denied client networks and paths, reloaded whenever the file changes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blocklist_path: Option<String>,
+    /// Unix socket over which the prx processes of one host share rate limit
+    /// buckets and open circuit breakers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shared_state_socket: Option<String>,
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+    #[serde(default)]
+    pub socket: ListenerSocketConfig,
+    #[serde(default, skip_serializing_if = "WorkersConfig::is_empty")]
+    pub workers: WorkersConfig,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            listen: default_listen(),
+            health_path: default_health_path(),
+            ready_path: default_ready_path(),
+            load_path: None,
+            noise_paths: Vec::new(),
+            observe_noise: false,
+            probes: ProbesConfig::default(),
+            threads: None,
+            grace_period_seconds: None,
+            graceful_shutdown_timeout_seconds: None,
+            upstream_keepalive_pool_size: None,
+            downstream_read_buffer_bytes: None,
+            upstream_write_buffer_bytes: None,
+            downstream_idle_timeout_seconds: None,
+            downstream_max_requests_per_connection: None,
+            downstream_max_connection_lifetime_ms: None,
+            h2c: false,
+            proxy_protocol: None,
+            max_concurrent_requests: None,
+            config_reload_debounce_ms: default_reload_debounce_ms(),
+            drain_timeout_s: default_drain_timeout_s(),
+            rollout: None,
+            shadow: None,
+            strict_http: None,
+            acl: AccessControlConfig::default(),
+            trusted_proxies: Vec::new(),
+            blocklist_path: None,
+            shared_state_socket: None,
+            tls: None,
+            socket: ListenerSocketConfig::default(),
+            workers: WorkersConfig::default(),
+        }
+    }
+}
+
+fn default_listen() -> Vec<String> {
+    vec!["0.0.0.0:8080".to_string()]
+}
+
+fn default_reload_debounce_ms() -> u64 {
+    250
+}
+
+fn default_drain_timeout_s() -> u64 {
+    30
+}
+
+fn default_health_path() -> String {
+    "/healthz".to_string()
+}
+
+fn default_ready_path() -> String {
+    "/readyz".to_string()
+}
+
+/// TCP options applied to every proxy listener (plain and TLS).
+/// Unset fields keep the Pingora/kernel defaults.
+#[derive(Debug, Clone, PartialEq, Default, Deserialize, Serialize)]
+pub struct ListenerSocketConfig {
+    #[serde(default)]
+    pub so_reuseport: Option<bool>,
+    #[serde(default)]
+    pub backlog: Option<u32>,
+    #[serde(default)]
+    pub tcp_nodelay: Option<bool>,
+    /// TCP Fast Open queue length; unset disables TFO.
+    #[serde(default)]
+    pub tcp_fastopen: Option<usize>,
+    #[serde(default)]
+    pub ipv6_only: Option<bool>,
+    #[serde(default)]
+    pub tcp_keepalive: Option<TcpKeepaliveConfig>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct TcpKeepaliveConfig {
+    pub idle_secs: u64,
+    pub interval_secs: u64,
+    pub count: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ProbesConfig {
+    /// Plain listeners answering only the probe paths, e.g. for the kubelet.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub listen: Vec<String>,
+    /// Answer them on the admin listener too.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub admin: bool,
+    /// Answer them on `listen` and app listeners; without, those paths go to
+    /// the routes like any other.
+    #[serde(default = "default_true")]
+    pub public: bool,
+}
+
+impl Default for ProbesConfig {
+    fn default() -> Self {
+        Self {
+            listen: Vec::new(),
+            admin: false,
+            public: true,
+        }
+    }
+}
+
+impl ProbesConfig {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct RolloutConfig {
+    /// Time for the new config to go from 0% to 100% of requests.
+    #[serde(default = "default_rollout_ramp_ms")]
+    pub ramp_ms: u64,
+    /// Roll back when the new config's 5xx rate exceeds the previous one's by
+    /// more than this fraction (0.05 = five percentage points).
+    #[serde(default = "default_rollout_max_error_rate_increase")]
+    pub max_error_rate_increase: f64,
+    /// Requests the new config must serve before its error rate is judged.
+    #[serde(default = "default_rollout_min_requests")]
+    pub min_requests: u64,
+}
+
+impl Default for RolloutConfig {
+    fn default() -> Self {
+        Self {
+            ramp_ms: default_rollout_ramp_ms(),
+            max_error_rate_increase: default_rollout_max_error_rate_increase(),
+            min_requests: default_rollout_min_requests(),
+        }
+    }
+}
+
+fn default_rollout_ramp_ms() -> u64 {
+    60_000
+}
+
+fn default_rollout_max_error_rate_increase() -> f64 {
+    0.05
+}
+
+fn default_rollout_min_requests() -> u64 {
+    100
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct ShadowConfig {
+    /// Time the new config is evaluated for before it serves traffic.
+    #[serde(default = "default_shadow_duration_ms")]
+    pub duration_ms: u64,
+}
+
+impl Default for ShadowConfig {
+    fn default() -> Self {
+        Self {
+            duration_ms: default_shadow_duration_ms(),
+        }
+    }
+}
+
+fn default_shadow_duration_ms() -> u64 {
+    30_000
+}
+
+/// Per-service thread counts and CPU pinning, so the admin API and background
+/// work cannot steal cycles from the data plane.
+#[derive(Debug, Clone, PartialEq, Default, Deserialize, Serialize)]
+pub struct WorkersConfig {
+    #[serde(default)]
+    pub proxy: ServiceWorkersConfig,
+    #[serde(default)]
+    pub admin: ServiceWorkersConfig,
+    #[serde(default)]
+    pub metrics: ServiceWorkersConfig,
+    /// CPUs for background threads (config watcher, remote poller, clock ticker).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub background_cpu_affinity: Vec<usize>,
+}
+
+impl WorkersConfig {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Deserialize, Serialize)]
+pub struct ServiceWorkersConfig {
+    /// Runtime threads for this service; defaults to `server.threads` (admin: 1).
+    #[serde(default)]
+    pub threads: Option<usize>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cpu_affinity: Vec<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct TlsConfig {
     pub listen: String,
     pub cert_path: String,
     pub key_path: String,
     #[serde(default = "default_true")]
     pub enable_h2: bool,
+    /// CA bundle client certificates are verified against.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_ca_path: Option<String>,
+    /// Rejects handshakes without a client certificate; needs `client_ca_path`.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub require_client_cert: bool,
+    /// Picked by SNI before falling back to `cert_path`/`key_path`.
+    #[serde(rename = "certificate", default, skip_serializing_if = "Vec::is_empty")]
+    pub certificates: Vec<TlsCertificateConfig>,
+}
+
+/// A certificate served to clients whose SNI matches one of `hosts`.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct TlsCertificateConfig {
+    /// Exact hostnames or `*.example.com` wildcards covering one label.
+    pub hosts: Vec<String>,
+    pub cert_path: String,
+    pub key_path: String,
+}
+
+impl TlsConfig {
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.require_client_cert && self.client_ca_path.is_none() {
+            bail!("require_client_cert needs client_ca_path");
+        }
+        let mut hosts = std::collections::HashSet::new();
+        for certificate in &self.certificates {
+            if certificate.hosts.is_empty() {
+                bail!(
+                    "certificate {} must list at least one host",
+                    certificate.cert_path
+                );
+            }
+            for host in &certificate.hosts {
+                let name = host.strip_prefix("*.").unwrap_or(host);
+                if name.is_empty() || name.contains(['*', ':', '/', ' ']) {
+                    bail!("certificate host {host:?} must be a hostname or *.<domain>");
+                }
+                if !hosts.insert(host.to_ascii_lowercase()) {
+                    bail!("certificate host {host:?} is listed twice");
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// An independent proxy with its own listeners; routes join it via `app = "<name>"`.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct AppConfig {
+    pub name: String,
+    #[serde(default)]
+    pub listen: Vec<String>,
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+    /// Like `server.proxy_protocol`, for this app's `listen`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proxy_protocol: Option<ProxyProtocolVersion>,
+    /// Layered between the global `[observability]` block and route overrides.
+    #[serde(default, skip_serializing_if = "RouteObservabilityConfig::is_empty")]
+    pub observability: RouteObservabilityConfig,
+    /// Checked after `server.acl` on this app's listeners.
+    #[serde(default, skip_serializing_if = "AccessControlConfig::is_empty")]
+    pub acl: AccessControlConfig,
+}
+
+/// Forwards every connection accepted on `listen` to an upstream of
+/// `service`, picked and guarded like for HTTP routes.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct TcpRouteConfig {
+    pub name: String,
+    pub listen: Vec<String>,
+    pub service: String,
+    /// Close a connection once neither side sent anything for this long.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idle_timeout_ms: Option<u64>,
+    /// Like `server.proxy_protocol`, for this route's `listen`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proxy_protocol: Option<ProxyProtocolVersion>,
+    /// Checked after `server.acl` on this route's listeners.
+    #[serde(default, skip_serializing_if = "AccessControlConfig::is_empty")]
+    pub acl: AccessControlConfig,
+}
+
+/// Request smuggling defenses; each check can be turned off on its own.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct StrictHttpConfig {
+    #[serde(default = "default_true")]
+    pub reject_content_length_with_transfer_encoding: bool,
+    /// Header values continued on the next line (obsolete line folding).
+    #[serde(default = "default_true")]
+    pub reject_obs_fold: bool,
+    /// Control characters and non-ASCII bytes in header values or the path.
+    #[serde(default = "default_true")]
+    pub reject_invalid_chars: bool,
+    /// Headers that may appear at most once.
+    #[serde(default = "default_unique_headers")]
+    pub unique_headers: Vec<String>,
+}
+
+impl Default for StrictHttpConfig {
+    fn default() -> Self {
+        Self {
+            reject_content_length_with_transfer_encoding: true,
+            reject_obs_fold: true,
+            reject_invalid_chars: true,
+            unique_headers: default_unique_headers(),
+        }
+    }
+}
+
+fn default_unique_headers() -> Vec<String> {
+    [
+        "host",
+        "content-length",
+        "transfer-encoding",
+        "authorization",
+    ]
+    .map(String::from)
+    .to_vec()
+}
+
+/// CIDR lists applied to client addresses at TCP accept time, before any TLS
+/// or HTTP work. Denied networks win; a non-empty `allow` admits nothing else.
+#[derive(Debug, Clone, PartialEq, Default, Deserialize, Serialize)]
+pub struct AccessControlConfig {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allow: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub deny: Vec<String>,
+}
+
+impl AccessControlConfig {
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+}
+
+/// Optional request inspection with ModSecurity/CRS-style rule files.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct WafConfig {
+    pub rule_files: Vec<String>,
+    #[serde(default)]
+    pub mode: WafMode,
+    /// Skip rules using unsupported syntax with a warning instead of failing.
+    #[serde(default)]
+    pub skip_unsupported: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum WafMode {
+    /// Reject requests matched by a disruptive rule.
+    #[default]
+    Block,
+    /// Only log and count matches.
+    Detect,
+}
+
+/// Client IP reputation service consulted before a request is proxied.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct ReputationConfig {
+    /// `http(s)://` endpoint asked with `?ip=<client ip>`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub http: Option<String>,
+    /// `host:port` of a Redis server holding a verdict per client IP.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub redis: Option<String>,
+    #[serde(default = "default_reputation_redis_key_prefix")]
+    pub redis_key_prefix: String,
+    #[serde(default = "default_reputation_timeout_ms")]
+    pub timeout_ms: u64,
+    /// How long a verdict is reused for the same IP; `0` asks every time.
+    #[serde(default = "default_reputation_cache_ms")]
+    pub cache_ms: u64,
+    /// Verdict for requests whose lookup failed or timed out.
+    #[serde(default)]
+    pub on_error: ReputationOnError,
+    /// Request header carrying a `tag` verdict to upstreams.
+    #[serde(default = "default_reputation_tag_header")]
+    pub tag_header: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ReputationOnError {
+    #[default]
+    Allow,
+    Deny,
+}
+
+fn default_reputation_redis_key_prefix() -> String {
+    "reputation:".to_string()
+}
+
+fn default_reputation_timeout_ms() -> u64 {
+    50
+}
+
+fn default_reputation_cache_ms() -> u64 {
+    60_000
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+fn default_reputation_tag_header() -> String {
+    "x-client-reputation".to_string()
+}
+
+/// A named owner of routes (`tenant = "<name>"` on a route).
+///
+/// Requests to the admin API carrying `Authorization: Bearer <admin_token>`
+/// only see and change that tenant's routes. The token may be `enc:` encrypted.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct TenantConfig {
+    pub name: String,
+    pub admin_token: String,
+    /// Route hosts the tenant's token may write, exact or `*.<domain>`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub hosts: Vec<String>,
+    /// Services the tenant's token may send routes to.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub services: Vec<String>,
+}
+
+/// The admin API listener (`PRX_ADMIN_LISTEN`).
+#[derive(Debug, Clone, PartialEq, Default, Deserialize, Serialize)]
+pub struct AdminConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auth: Option<AdminAuthConfig>,
+    /// JSON-lines file every admin request that may change something is
+    /// appended to, relative to the config file.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audit_log: Option<String>,
+    /// Configs the admin API replaced to keep as `Prx.toml.v{n}`; `0` keeps
+    /// none. Defaults to 10.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub config_history: Option<usize>,
+}
+
+/// Credentials the admin API requires once set; requests without any get
+/// `401`. Tenant tokens keep working on top of these.
+#[derive(Debug, Clone, PartialEq, Default, Deserialize, Serialize)]
+pub struct AdminAuthConfig {
+    /// Accepted as `Authorization: Bearer <token>`.
+    #[serde(rename = "token", default, skip_serializing_if = "Vec::is_empty")]
+    pub tokens: Vec<AdminTokenConfig>,
+    /// Accepted as `Authorization: Basic`, which browsers ask for on the WebUI.
+    #[serde(rename = "basic", default, skip_serializing_if = "Vec::is_empty")]
+    pub basic: Vec<AdminBasicConfig>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct AdminTokenConfig {
+    pub name: String,
+    /// May be `enc:` encrypted.
+    pub token: String,
+    #[serde(default)]
+    pub scope: AdminAccess,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct AdminBasicConfig {
+    pub username: String,
+    /// May be `enc:` encrypted.
+    pub password: String,
+    #[serde(default)]
+    pub scope: AdminAccess,
+}
+
+/// What an admin credential may do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AdminAccess {
+    /// Reads, plus validating and health-checking drafts.
+    #[default]
+    ReadOnly,
+    ReadWrite,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct ObservabilityConfig {
     #[serde(default = "default_log_level")]
     pub log_level: String,
@@ -187,6 +2458,39 @@ pub struct ObservabilityConfig {
     pub access_log: bool,
     #[serde(default)]
     pub prometheus_listen: Option<String>,
+    #[serde(default = "default_sample_rate")]
+    pub access_log_sample_rate: f64,
+    /// Distinct route / upstream label values kept in metrics before the rest
+    /// are folded into an overflow label.
+    #[serde(default = "default_max_metric_label_values")]
+    pub max_metric_label_values: usize,
+    #[serde(default, skip_serializing_if = "AccessLogFieldsConfig::is_empty")]
+    pub access_log_fields: AccessLogFieldsConfig,
+}
+
+/// Optional connection details added to access log lines.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Deserialize, Serialize)]
+pub struct AccessLogFieldsConfig {
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub tls_version: bool,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub tls_cipher: bool,
+    /// The server name the TLS client sent, if any.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub sni: bool,
+    /// Organization and serial number of the client certificate, if any.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub client_cert: bool,
+    /// Request header carrying the client's country, as set by a CDN or
+    /// GeoIP-aware load balancer in front of prx.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub geo_country_header: Option<String>,
+}
+
+impl AccessLogFieldsConfig {
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
 }
 
 impl Default for ObservabilityConfig {
@@ -195,71 +2499,568 @@ impl Default for ObservabilityConfig {
             log_level: default_log_level(),
             access_log: true,
             prometheus_listen: None,
+            access_log_sample_rate: default_sample_rate(),
+            max_metric_label_values: default_max_metric_label_values(),
+            access_log_fields: AccessLogFieldsConfig::default(),
+        }
+    }
+}
+
+/// Per-route overrides layered over the global `[observability]` block.
+/// Unset fields inherit the global value.
+#[derive(Debug, Clone, PartialEq, Default, Deserialize, Serialize)]
+pub struct RouteObservabilityConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub access_log: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub access_log_sample_rate: Option<f64>,
+}
+
+impl RouteObservabilityConfig {
+    pub fn is_empty(&self) -> bool {
+        self.access_log.is_none() && self.access_log_sample_rate.is_none()
+    }
+}
+
+fn default_sample_rate() -> f64 {
+    1.0
+}
+
+fn default_max_metric_label_values() -> usize {
+    1000
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_log_level() -> String {
+    "info".to_string()
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct ServiceConfig {
+    #[serde(default = "default_service_name")]
+    pub name: String,
+    #[serde(default)]
+    pub lb: LbStrategy,
+    #[serde(default)]
+    pub max_retries: usize,
+    #[serde(default)]
+    pub retry_backoff_ms: u64,
+    /// Exponential backoff between retries, in place of `retry_backoff_ms`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_backoff: Option<RetryBackoffConfig>,
+    /// What a request is retried on: `connect`, `timeout`, `proxy_error`,
+    /// `5xx` or an upstream status such as `429`. Empty means the first three.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub retry_on: Vec<String>,
+    /// Methods retried once the request may have reached an upstream; empty
+    /// means all of them. Failed connects are retried for any method.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub retry_methods: Vec<String>,
+    #[serde(default)]
+    pub circuit_breaker: CircuitBreakerConfig,
+    /// Eject upstreams whose recent error rate or p99 latency stands out.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub outlier_detection: Option<OutlierDetectionConfig>,
+    /// Seconds over which an upstream that comes back, or is added by a
+    /// reload, ramps from a tenth of its share of traffic to all of it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub slow_start_s: Option<u64>,
+    /// Upstreams looked up at runtime and served after the listed ones.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub discover: Option<DiscoverConfig>,
+    #[serde(rename = "upstream", default)]
+    pub upstreams: Vec<UpstreamConfig>,
+}
+
+fn default_service_name() -> String {
+    "default".to_string()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct DiscoverConfig {
+    #[serde(rename = "type")]
+    pub kind: DiscoverKind,
+    /// Record name, e.g. `_http._tcp.app.service.consul`.
+    pub name: String,
+    #[serde(default = "default_discover_interval_s")]
+    pub interval_s: u64,
+    /// `ip` or `ip:port` of the name server asked; defaults to the first
+    /// `nameserver` of `/etc/resolv.conf`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nameserver: Option<String>,
+    /// Connect to the discovered targets over TLS, with the target name as SNI.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub tls: bool,
+    /// Take each upstream's `weight` from its record; without, all get 1.
+    #[serde(default = "default_true")]
+    pub use_srv_weights: bool,
+    /// Serve from the records of the lowest priority and fail over to the
+    /// next only when none of those can take a request; without, all records
+    /// share one tier.
+    #[serde(default = "default_true")]
+    pub use_srv_priorities: bool,
+}
+
+fn default_discover_interval_s() -> u64 {
+    30
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DiscoverKind {
+    /// SRV records: target, port and weight of every upstream.
+    DnsSrv,
+}
+
+/// Doubles the wait before each further retry, from `base_ms` up to `max_ms`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RetryBackoffConfig {
+    pub base_ms: u64,
+    pub max_ms: u64,
+    /// Wait a random share of the delay instead, so clients retrying at once
+    /// spread out.
+    #[serde(default = "default_true")]
+    pub jitter: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct RouteConfig {
+    #[serde(default = "default_route_name")]
+    pub name: String,
+    /// `[[app]]` serving this route; unset routes belong to the main proxy.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub app: Option<String>,
+    /// `[[tenant]]` owning this route.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    pub service: String,
+    #[serde(default)]
+    pub host: Option<String>,
+    #[serde(default = "default_path_prefix")]
+    pub path_prefix: String,
+    /// Request headers that must all be present with exactly these values.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+    #[serde(default)]
+    pub methods: Vec<String>,
+    #[serde(default)]
+    pub is_default: bool,
+    #[serde(default, skip_serializing_if = "RouteObservabilityConfig::is_empty")]
+    pub observability: RouteObservabilityConfig,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bandwidth: Option<BandwidthConfig>,
+    /// Upstream response bodies larger than this are cut off: a 502 when the
+    /// declared length is known up front, a closed connection otherwise.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_response_bytes: Option<u64>,
+    /// Request bodies larger than this get a 413 instead of reaching the
+    /// upstream.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_request_body_bytes: Option<u64>,
+    /// Retry requests whose body had already started going upstream, as long
+    /// as it is no larger than this; pingora keeps it for the replay.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_body_bytes: Option<u64>,
+    /// Deadline for the whole request, retries and backoff included, past
+    /// which the client gets a 504.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_ms: Option<u64>,
+    /// Forward the path with `path_prefix` removed, so `/api/users` on an
+    /// `/api/` route reaches the upstream as `/users`.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub strip_prefix: bool,
+    /// Upstream path template; `$1` is the path with `path_prefix` removed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rewrite_path: Option<String>,
+    #[serde(default, skip_serializing_if = "ForwardedHeadersPolicy::is_default")]
+    pub forwarded_headers: ForwardedHeadersPolicy,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rate_limit: Option<RateLimitConfig>,
+    /// Requests in flight on this route past which new ones get a 503.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_concurrent_requests: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preflight_cache: Option<PreflightCacheConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idempotency: Option<IdempotencyConfig>,
+    /// Traffic split across services; the share the groups leave goes to
+    /// `service`.
+    #[serde(rename = "group", default, skip_serializing_if = "Vec::is_empty")]
+    pub groups: Vec<RouteGroupConfig>,
+    /// What keeps a client in one group, in the `rate_limit.key` syntax;
+    /// the client IP when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group_key: Option<String>,
+    /// Response header naming the group each request was assigned to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group_header: Option<String>,
+    /// Overrides the egress settings of the service's upstreams field by field.
+    #[serde(default, skip_serializing_if = "EgressConfig::is_empty")]
+    pub egress: EgressConfig,
+    /// `http://` (CONNECT) or `socks5://` proxy upstream connections are
+    /// tunneled through.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub outbound_proxy: Option<String>,
+    #[serde(default, skip_serializing_if = "RouteProtocol::is_default")]
+    pub protocol: RouteProtocol,
+    /// Pass `Connection: Upgrade` requests such as WebSocket handshakes
+    /// through; other routes refuse them.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub websocket: bool,
+    /// How long an upgraded connection may go without data from one side
+    /// before it is closed (default 300000).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub websocket_idle_timeout_ms: Option<u64>,
+    /// Upstream statuses sent to clients as a different status, e.g. to keep
+    /// old clients working during a migration.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub status_map: Vec<StatusMapConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response_validation: Option<ResponseValidationConfig>,
+    /// Casing of request header names sent upstream over HTTP/1.1.
+    #[serde(default, skip_serializing_if = "HeaderCase::is_default")]
+    pub header_case: HeaderCase,
+    /// Send repeated request headers upstream as one comma-separated field.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub merge_duplicate_headers: bool,
+    /// With `lb = "hash"`, whether a key may move to a second upstream that
+    /// has a pooled connection ready.
+    #[serde(default, skip_serializing_if = "HashAffinity::is_default")]
+    pub hash_affinity: HashAffinity,
+}
+
+/// Rules every upstream response of a route is checked against, to catch
+/// backends answering what clients do not expect.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ResponseValidationConfig {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub required_headers: Vec<String>,
+    /// Longest wait for the response headers, from the attempt's start.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_latency_ms: Option<u64>,
+    /// Allowed media types, e.g. `application/json` or `image/*`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub content_types: Vec<String>,
+    #[serde(default)]
+    pub action: ResponseViolationAction,
+}
+
+/// What prx does with a response that broke a `response_validation` rule;
+/// every violation is counted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResponseViolationAction {
+    /// Also log it.
+    #[default]
+    Log,
+    Count,
+    /// Also log it and answer 502 instead.
+    Reject,
+}
+
+/// Answers an upstream `from` status as `to`, with extra response headers
+/// and optionally a fixed body in place of the upstream's.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct StatusMapConfig {
+    pub from: u16,
+    pub to: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+}
+
+/// What a route carries; `grpc` keeps HTTP/2 on both sides and reports
+/// `grpc-status` codes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum RouteProtocol {
+    #[default]
+    Http,
+    Grpc,
+}
+
+impl RouteProtocol {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// A named share of a route's traffic, e.g. a canary.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RouteGroupConfig {
+    pub name: String,
+    pub service: String,
+    pub percent: u32,
+}
+
+/// Answers repeated CORS preflights (`OPTIONS` with `Origin` and
+/// `Access-Control-Request-Method`) from memory instead of the upstream.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct PreflightCacheConfig {
+    #[serde(default = "default_preflight_ttl_ms")]
+    pub ttl_ms: u64,
+    #[serde(default = "default_preflight_max_entries")]
+    pub max_entries: usize,
+}
+
+impl Default for PreflightCacheConfig {
+    fn default() -> Self {
+        Self {
+            ttl_ms: default_preflight_ttl_ms(),
+            max_entries: default_preflight_max_entries(),
+        }
+    }
+}
+
+fn default_preflight_ttl_ms() -> u64 {
+    60_000
+}
+
+fn default_preflight_max_entries() -> usize {
+    1024
+}
+
+/// Deduplicates requests carrying an idempotency key, so a client retrying a
+/// submit does not reach the upstream twice.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct IdempotencyConfig {
+    #[serde(default = "default_idempotency_header")]
+    pub header: String,
+    /// Who a key belongs to, in the `rate_limit.key` syntax; the client IP
+    /// when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub identity: Option<String>,
+    #[serde(default)]
+    pub in_flight: IdempotencyInFlight,
+    /// How long a finished answer is replayed to duplicates; `0` replays none.
+    #[serde(default)]
+    pub replay_ttl_ms: u64,
+    #[serde(default = "default_idempotency_max_entries")]
+    pub max_entries: usize,
+    /// Larger answers are not stored for replay.
+    #[serde(default = "default_idempotency_max_body_bytes")]
+    pub max_body_bytes: usize,
+}
+
+impl Default for IdempotencyConfig {
+    fn default() -> Self {
+        Self {
+            header: default_idempotency_header(),
+            identity: None,
+            in_flight: IdempotencyInFlight::default(),
+            replay_ttl_ms: 0,
+            max_entries: default_idempotency_max_entries(),
+            max_body_bytes: default_idempotency_max_body_bytes(),
         }
     }
 }
 
-fn default_true() -> bool {
-    true
+/// What a duplicate gets while the first request with its key is in flight.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IdempotencyInFlight {
+    /// Answered with 409 Conflict.
+    #[default]
+    Reject,
+    /// Held until the first finishes, then replayed or sent on.
+    Wait,
+}
+
+fn default_idempotency_header() -> String {
+    "idempotency-key".to_string()
+}
+
+fn default_idempotency_max_entries() -> usize {
+    10_000
+}
+
+fn default_idempotency_max_body_bytes() -> usize {
+    64 * 1024
+}
+
+/// Token bucket request limit of a route, counted separately for each value
+/// of `key`: `client_ip`, `header:<name>` or `cookie:<name>`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RateLimitConfig {
+    pub rps: u64,
+    /// Requests that may arrive at once; defaults to `rps`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub burst: Option<u64>,
+    #[serde(default = "default_rate_limit_key")]
+    pub key: String,
+}
+
+impl RateLimitConfig {
+    pub fn burst(&self) -> u64 {
+        self.burst.unwrap_or(self.rps)
+    }
+}
+
+fn default_rate_limit_key() -> String {
+    "client_ip".to_string()
+}
+
+/// How request header names are cased toward HTTP/1.1 upstreams, for
+/// backends that are picky about it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum HeaderCase {
+    /// As the client sent them; headers from HTTP/2 clients or added by prx
+    /// are lowercase, except well-known ones.
+    #[default]
+    Preserve,
+    /// `Content-Type`, `X-Request-Id`.
+    Title,
+    Lower,
+}
+
+impl HeaderCase {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum HashAffinity {
+    /// Every key goes to the one upstream it hashes to.
+    #[default]
+    Strict,
+    /// A key goes to one of two upstreams, preferring one with an idle
+    /// pooled connection, so fewer connections and TLS handshakes are made.
+    PreferReuse,
 }
 
-fn default_log_level() -> String {
-    "info".to_string()
+impl HashAffinity {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
-pub struct ServiceConfig {
-    #[serde(default = "default_service_name")]
-    pub name: String,
-    #[serde(default)]
-    pub lb: LbStrategy,
-    #[serde(default)]
-    pub max_retries: usize,
-    #[serde(default)]
-    pub retry_backoff_ms: u64,
-    #[serde(default)]
-    pub circuit_breaker: CircuitBreakerConfig,
-    #[serde(rename = "upstream", default)]
-    pub upstreams: Vec<UpstreamConfig>,
+/// How a route reports the client to its upstream in `X-Forwarded-For`,
+/// `X-Forwarded-Proto`, `X-Forwarded-Host` and `Forwarded`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ForwardedHeadersPolicy {
+    /// Add this hop to the values sent by a trusted proxy; values from any
+    /// other peer are replaced.
+    #[default]
+    Append,
+    /// Always drop incoming values and send only this hop.
+    Replace,
+    /// Forward the headers untouched.
+    Off,
 }
 
-fn default_service_name() -> String {
-    "default".to_string()
+impl ForwardedHeadersPolicy {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
-pub struct RouteConfig {
-    #[serde(default = "default_route_name")]
-    pub name: String,
-    pub service: String,
-    #[serde(default)]
-    pub host: Option<String>,
-    #[serde(default = "default_path_prefix")]
-    pub path_prefix: String,
-    #[serde(default)]
-    pub methods: Vec<String>,
-    #[serde(default)]
-    pub is_default: bool,
+/// Body byte rate limits for a route. Route-wide limits are shared by all of
+/// its requests; `request_*` limits apply to each request on its own.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize, Default)]
+pub struct BandwidthConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upload_bytes_per_sec: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub download_bytes_per_sec: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_upload_bytes_per_sec: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_download_bytes_per_sec: Option<u64>,
+    /// Bytes that may pass at full speed; defaults to one second of each rate.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub burst_bytes: Option<u64>,
+}
+
+impl BandwidthConfig {
+    pub fn burst(&self, bytes_per_sec: u64) -> u64 {
+        self.burst_bytes.unwrap_or(bytes_per_sec)
+    }
 }
 
 fn default_route_name() -> String {
     "default".to_string()
 }
 
+impl RouteConfig {
+    /// What decides which requests the route takes.
+    fn matcher(
+        &self,
+    ) -> (
+        Option<&str>,
+        Option<String>,
+        &str,
+        &BTreeMap<String, String>,
+    ) {
+        (
+            self.app.as_deref(),
+            self.host.as_deref().map(str::to_ascii_lowercase),
+            &self.path_prefix,
+            &self.headers,
+        )
+    }
+}
+
 fn default_path_prefix() -> String {
     "/".to_string()
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize, Default)]
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize, Default)]
 #[serde(rename_all = "snake_case")]
 pub enum LbStrategy {
     #[default]
     RoundRobin,
     Random,
     Hash,
+    /// Favors the upstream with the lower moving average of latencies.
+    Ewma,
+}
+
+/// Passive ejection of upstreams by their answers over the last `window_ms`.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct OutlierDetectionConfig {
+    #[serde(default = "default_outlier_window_ms")]
+    pub window_ms: u64,
+    /// Answers an upstream needs within the window before it can be ejected.
+    #[serde(default = "default_outlier_min_requests")]
+    pub min_requests: usize,
+    /// Share of failed answers, from 0 to 1, past which the upstream is ejected.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_error_rate: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_p99_latency_ms: Option<u64>,
+    #[serde(default = "default_outlier_eject_ms")]
+    pub eject_ms: u64,
+    /// How long an upstream back from ejection takes to get its full share
+    /// again, starting from a tenth of it.
+    #[serde(default = "default_outlier_reintroduce_ms")]
+    pub reintroduce_ms: u64,
+}
+
+fn default_outlier_window_ms() -> u64 {
+    10_000
+}
+
+fn default_outlier_min_requests() -> usize {
+    20
+}
+
+fn default_outlier_eject_ms() -> u64 {
+    30_000
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+fn default_outlier_reintroduce_ms() -> u64 {
+    10_000
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct CircuitBreakerConfig {
     #[serde(default)]
     pub enabled: bool,
@@ -267,6 +3068,33 @@ pub struct CircuitBreakerConfig {
     pub consecutive_failures: usize,
     #[serde(default = "default_cb_open_ms")]
     pub open_ms: u64,
+    /// Requests let through once `open_ms` is over; the breaker closes when
+    /// all of them succeed and reopens when one fails.
+    #[serde(default = "default_cb_half_open_probes")]
+    pub half_open_probes: usize,
+    /// Cap on the open time, which doubles with every failed probe; never
+    /// below `open_ms`.
+    #[serde(default = "default_cb_max_open_ms")]
+    pub max_open_ms: u64,
+    /// Failures that count toward `consecutive_failures`.
+    #[serde(default = "default_cb_failure_on")]
+    pub failure_on: Vec<BreakerFailure>,
+    /// Upstream response statuses that also count, e.g. `[429]`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub failure_statuses: Vec<u16>,
+    /// Upstream response header whose presence marks the upstream overloaded,
+    /// unless its value is `0` or `false`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub overload_header: Option<String>,
+    /// Upstream response statuses that mark the upstream overloaded, e.g. `[429]`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub overload_statuses: Vec<u16>,
+    /// How long an overloaded upstream keeps its reduced weight.
+    #[serde(default = "default_cb_overload_ms")]
+    pub overload_ms: u64,
+    /// Share of its weight, in percent, an overloaded upstream still gets.
+    #[serde(default = "default_cb_overload_weight_percent")]
+    pub overload_weight_percent: u8,
 }
 
 impl Default for CircuitBreakerConfig {
@@ -275,10 +3103,41 @@ impl Default for CircuitBreakerConfig {
             enabled: false,
             consecutive_failures: default_cb_failures(),
             open_ms: default_cb_open_ms(),
+            half_open_probes: default_cb_half_open_probes(),
+            max_open_ms: default_cb_max_open_ms(),
+            failure_on: default_cb_failure_on(),
+            failure_statuses: Vec::new(),
+            overload_header: None,
+            overload_statuses: Vec::new(),
+            overload_ms: default_cb_overload_ms(),
+            overload_weight_percent: default_cb_overload_weight_percent(),
         }
     }
 }
 
+/// A kind of upstream failure the circuit breaker can count.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BreakerFailure {
+    /// Connecting (TCP, TLS or HTTP/2 handshake) failed or timed out.
+    Connect,
+    /// Reading from or writing to a connected upstream timed out.
+    Timeout,
+    /// Any other error after the connection was established.
+    ProxyError,
+    /// The upstream answered with a 5xx status.
+    #[serde(rename = "5xx")]
+    ServerError,
+}
+
+fn default_cb_failure_on() -> Vec<BreakerFailure> {
+    vec![
+        BreakerFailure::Connect,
+        BreakerFailure::Timeout,
+        BreakerFailure::ProxyError,
+    ]
+}
+
 impl std::str::FromStr for LbStrategy {
     type Err = String;
 
@@ -287,6 +3146,7 @@ impl std::str::FromStr for LbStrategy {
             "round_robin" => Ok(LbStrategy::RoundRobin),
             "random" => Ok(LbStrategy::Random),
             "hash" => Ok(LbStrategy::Hash),
+            "ewma" => Ok(LbStrategy::Ewma),
             _ => Err(format!("invalid load balancing strategy: {}", s)),
         }
     }
@@ -300,19 +3160,61 @@ fn default_cb_open_ms() -> u64 {
     30_000
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+fn default_cb_half_open_probes() -> usize {
+    1
+}
+
+fn default_cb_max_open_ms() -> u64 {
+    300_000
+}
+
+fn default_cb_overload_ms() -> u64 {
+    10_000
+}
+
+fn default_cb_overload_weight_percent() -> u8 {
+    25
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct UpstreamConfig {
     pub addr: String,
     #[serde(default)]
     pub tls: bool,
     #[serde(default)]
     pub sni: Option<String>,
+    /// Extra name the upstream certificate may match instead of the SNI.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub verify_hostname_as: Option<String>,
+    /// Host header sent upstream; defaults to the SNI.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    /// Forward the client's Host header unchanged.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub preserve_host: bool,
+    /// Prefer HTTP/2 (ALPN over TLS, prior knowledge in plaintext) and fall back
+    /// to HTTP/1.1 once the upstream fails to speak it.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub http2: bool,
+    /// `h1`, `h2` (HTTP/2 only) or `auto` (like `http2 = true`); unset
+    /// follows `http2`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub http_version: Option<UpstreamHttpVersion>,
     #[serde(default = "default_weight")]
     pub weight: u16,
     #[serde(default)]
     pub verify_cert: Option<bool>,
     #[serde(default)]
     pub verify_hostname: Option<bool>,
+    /// Certificate prx presents to upstreams that require mutual TLS.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_cert_path: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_key_path: Option<String>,
+    /// SHA-256 hashes of the SubjectPublicKeyInfo the upstream certificate
+    /// must match one of, in hex or base64.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pinned_cert_sha256: Vec<String>,
     #[serde(default)]
     pub connect_timeout_ms: Option<u64>,
     #[serde(default)]
@@ -323,6 +3225,166 @@ pub struct UpstreamConfig {
     pub write_timeout_ms: Option<u64>,
     #[serde(default)]
     pub idle_timeout_ms: Option<u64>,
+    /// Close a pooled connection after it has served this many requests.
+    #[serde(default)]
+    pub max_requests_per_connection: Option<u64>,
+    /// Close a pooled connection once it is older than this.
+    #[serde(default)]
+    pub max_connection_lifetime_ms: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub health_check: Option<HealthCheckConfig>,
+    #[serde(default, skip_serializing_if = "EgressConfig::is_empty")]
+    pub egress: EgressConfig,
+    /// Send a PROXY protocol header with the client's address at the start
+    /// of every connection, for upstreams that need the original client.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proxy_protocol: Option<ProxyProtocolVersion>,
+    /// POSTed when a reload takes the upstream out of its service: a path on
+    /// the upstream itself, or an `http://` URL elsewhere.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub drain_hook: Option<String>,
+    /// Resolve the host of `addr` every this many seconds and spread
+    /// requests over all its A/AAAA records.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resolve_interval_s: Option<u64>,
+    /// Fallback tier of a discovered upstream, from its SRV priority: those
+    /// of a tier only get requests while none of a lower one can take them.
+    /// Listed upstreams are all in tier 0.
+    #[serde(skip)]
+    pub tier: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProxyProtocolVersion {
+    /// The human-readable `PROXY TCP4 ...` line.
+    V1,
+    /// The binary header.
+    V2,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UpstreamHttpVersion {
+    #[default]
+    H1,
+    /// HTTP/2 without falling back, e.g. for gRPC.
+    H2,
+    /// HTTP/2 until the upstream fails to speak it, then HTTP/1.1.
+    Auto,
+}
+
+/// How upstream connections leave the host, for multi-homed deployments with
+/// policy routing.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct EgressConfig {
+    /// Local IP address upstream connections are made from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub local_addr: Option<String>,
+    /// Network interface upstream connections are bound to (Linux only).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interface: Option<String>,
+    /// DSCP codepoint (0-63) marked on upstream packets.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dscp: Option<u8>,
+}
+
+impl EgressConfig {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if let Some(addr) = &self.local_addr
+            && addr.parse::<std::net::IpAddr>().is_err()
+        {
+            bail!("local_addr {addr:?} is not an IP address");
+        }
+        // IFNAMSIZ includes the trailing NUL.
+        if let Some(interface) = &self.interface
+            && (interface.is_empty() || interface.len() > 15 || interface.contains(['/', ' ']))
+        {
+            bail!("interface {interface:?} is not a valid interface name");
+        }
+        if let Some(dscp) = self.dscp
+            && dscp > 63
+        {
+            bail!("dscp must be between 0 and 63");
+        }
+        Ok(())
+    }
+}
+
+/// A `pinned_cert_sha256` entry: 64 hex digits (colons allowed), or base64
+/// with an optional `sha256/` prefix as printed for HPKP pins.
+pub(crate) fn parse_sha256_pin(pin: &str) -> anyhow::Result<[u8; 32]> {
+    use base64::Engine;
+
+    let hex: String = pin.chars().filter(|c| *c != ':').collect();
+    let bytes = if hex.len() == 64 && hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        (0..hex.len())
+            .step_by(2)
+            .map(|at| u8::from_str_radix(&hex[at..at + 2], 16))
+            .collect::<Result<Vec<_>, _>>()?
+    } else {
+        base64::engine::general_purpose::STANDARD
+            .decode(pin.strip_prefix("sha256/").unwrap_or(pin))
+            .with_context(|| format!("{pin:?} is neither hex nor base64"))?
+    };
+    let Ok(hash) = <[u8; 32]>::try_from(bytes) else {
+        bail!("{pin:?} is not a SHA-256 hash");
+    };
+    Ok(hash)
+}
+
+/// Active probe of one upstream; failing upstreams are skipped by the load
+/// balancer until they pass again.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct HealthCheckConfig {
+    #[serde(rename = "type", default)]
+    pub kind: HealthCheckKind,
+    #[serde(default = "default_health_check_interval_ms")]
+    pub interval_ms: u64,
+    #[serde(default = "default_health_check_timeout_ms")]
+    pub timeout_ms: u64,
+    /// Request path of `http` checks.
+    #[serde(default = "default_path_prefix")]
+    pub path: String,
+    /// Status `http` checks expect; any other status counts as a failure.
+    #[serde(default = "default_health_check_status")]
+    pub expected_status: u16,
+    /// Consecutive failures before the upstream is marked unhealthy.
+    #[serde(default = "default_health_check_threshold")]
+    pub unhealthy_threshold: u32,
+    /// Consecutive passes before an unhealthy upstream is used again.
+    #[serde(default = "default_health_check_threshold")]
+    pub healthy_threshold: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthCheckKind {
+    /// Passes when a TCP connection can be opened.
+    #[default]
+    Tcp,
+    /// Passes when `GET path` answers `expected_status`.
+    Http,
+}
+
+fn default_health_check_interval_ms() -> u64 {
+    5_000
+}
+
+fn default_health_check_timeout_ms() -> u64 {
+    1_000
+}
+
+fn default_health_check_status() -> u16 {
+    200
+}
+
+fn default_health_check_threshold() -> u32 {
+    2
 }
 
 fn default_weight() -> u16 {
@@ -338,14 +3400,30 @@ mod tests {
             addr: addr.to_string(),
             tls: false,
             sni: None,
+            verify_hostname_as: None,
+            host: None,
+            preserve_host: false,
+            http2: false,
+            http_version: None,
             weight: 1,
             verify_cert: None,
             verify_hostname: None,
+            client_cert_path: None,
+            client_key_path: None,
+            pinned_cert_sha256: Vec::new(),
             connect_timeout_ms: None,
             total_connect_timeout_ms: None,
             read_timeout_ms: None,
             write_timeout_ms: None,
             idle_timeout_ms: None,
+            max_requests_per_connection: None,
+            max_connection_lifetime_ms: None,
+            health_check: None,
+            egress: Default::default(),
+            proxy_protocol: None,
+            drain_hook: None,
+            resolve_interval_s: None,
+            tier: 0,
         }
     }
 
@@ -355,6 +3433,12 @@ mod tests {
             lb: LbStrategy::RoundRobin,
             max_retries: 0,
             retry_backoff_ms: 0,
+            retry_backoff: None,
+            retry_on: Vec::new(),
+            retry_methods: Vec::new(),
+            outlier_detection: None,
+            slow_start_s: None,
+            discover: None,
             circuit_breaker: CircuitBreakerConfig::default(),
             upstreams: vec![valid_upstream("127.0.0.1:8081")],
         }
@@ -363,18 +3447,54 @@ mod tests {
     fn valid_route(name: &str, service: &str) -> RouteConfig {
         RouteConfig {
             name: name.to_string(),
+            app: None,
+            tenant: None,
             service: service.to_string(),
             host: None,
             path_prefix: "/".to_string(),
             methods: Vec::new(),
             is_default: true,
+            observability: RouteObservabilityConfig::default(),
+            bandwidth: None,
+            max_response_bytes: None,
+            max_request_body_bytes: None,
+            retry_body_bytes: None,
+            timeout_ms: None,
+            strip_prefix: false,
+            rewrite_path: None,
+            forwarded_headers: ForwardedHeadersPolicy::default(),
+            rate_limit: None,
+            max_concurrent_requests: None,
+            preflight_cache: None,
+            idempotency: None,
+            groups: Vec::new(),
+            group_key: None,
+            group_header: None,
+            egress: Default::default(),
+            outbound_proxy: None,
+            protocol: Default::default(),
+            websocket: false,
+            websocket_idle_timeout_ms: None,
+            status_map: Vec::new(),
+            response_validation: None,
+            header_case: Default::default(),
+            merge_duplicate_headers: false,
+            hash_affinity: Default::default(),
+            headers: Default::default(),
         }
     }
 
     fn valid_config() -> PrxConfig {
         PrxConfig {
+            include: Vec::new(),
             server: ServerConfig::default(),
             observability: ObservabilityConfig::default(),
+            apps: Vec::new(),
+            tenants: Vec::new(),
+            admin: None,
+            tcp_routes: Vec::new(),
+            waf: None,
+            reputation: None,
             services: vec![valid_service("default")],
             routes: vec![valid_route("default", "default")],
         }
@@ -401,6 +3521,82 @@ mod tests {
         assert!(err.to_string().contains("consecutive_failures"));
     }
 
+    #[test]
+    fn validate_rejects_invalid_retry_policy() {
+        let mut cfg = valid_config();
+        cfg.services[0].retry_on = vec!["5xx".to_string(), "teapot".to_string()];
+        let err = cfg
+            .validate()
+            .expect_err("unknown retry condition should fail");
+        assert!(format!("{err:#}").contains("\"teapot\""));
+
+        let mut cfg = valid_config();
+        cfg.services[0].retry_backoff_ms = 10;
+        cfg.services[0].retry_backoff = Some(RetryBackoffConfig {
+            base_ms: 10,
+            max_ms: 100,
+            jitter: true,
+        });
+        let err = cfg.validate().expect_err("two backoffs should fail");
+        assert!(
+            err.to_string()
+                .contains("both retry_backoff_ms and retry_backoff")
+        );
+    }
+
+    #[test]
+    fn validate_rejects_outlier_detection_without_valid_thresholds() {
+        let mut cfg = valid_config();
+        let outlier = |text: &str| toml::from_str::<OutlierDetectionConfig>(text).expect("outlier");
+        cfg.services[0].outlier_detection = Some(outlier("max_p99_latency_ms = 250"));
+        assert!(cfg.problems().is_empty());
+
+        for (text, code) in [
+            ("window_ms = 1000", "required"),
+            ("max_error_rate = 1.5", "out_of_range"),
+        ] {
+            cfg.services[0].outlier_detection = Some(outlier(text));
+            let problems = cfg.problems();
+            assert_eq!(problems.len(), 1, "{text}");
+            assert_eq!(
+                problems[0].field,
+                "service[0].outlier_detection.max_error_rate"
+            );
+            assert_eq!(problems[0].code, code);
+        }
+    }
+
+    #[test]
+    fn validate_reports_every_problem_at_once() {
+        let mut cfg = valid_config();
+        cfg.server.health_path = "healthz".to_string();
+        cfg.services[0].upstreams[0].max_requests_per_connection = Some(0);
+        cfg.routes[0].service = "nonexistent".to_string();
+
+        let fields: Vec<_> = cfg
+            .problems()
+            .into_iter()
+            .map(|problem| problem.field)
+            .collect();
+        assert_eq!(
+            fields,
+            [
+                "server.health_path",
+                "service[0].upstream[0].max_requests_per_connection",
+                "route[0].service",
+            ]
+        );
+        let err = cfg.validate().expect_err("invalid config should fail");
+        let errors = err
+            .downcast_ref::<ConfigErrors>()
+            .expect("structured errors");
+        assert_eq!(errors.0.len(), 3);
+        assert!(
+            err.to_string()
+                .starts_with("3 config problems; server.health_path must start")
+        );
+    }
+
     #[test]
     fn validate_rejects_route_with_unknown_service() {
         let mut cfg = valid_config();
@@ -434,9 +3630,511 @@ mod tests {
         assert!(err.to_string().contains("duplicate service name"));
     }
 
+    #[test]
+    fn validate_rejects_invalid_geo_country_header() {
+        let mut cfg = valid_config();
+        cfg.observability.access_log_fields.geo_country_header = Some("cf ipcountry".to_string());
+
+        let err = cfg
+            .validate()
+            .expect_err("header names cannot contain spaces");
+        assert!(err.to_string().contains("geo_country_header"));
+    }
+
+    #[test]
+    fn validate_rejects_proxy_protocol_on_tls_listeners() {
+        let mut cfg = valid_config();
+        cfg.server.proxy_protocol = Some(ProxyProtocolVersion::V2);
+        cfg.validate()
+            .expect("plain listeners accept PROXY headers");
+
+        cfg.server.tls = Some(TlsConfig {
+            listen: "127.0.0.1:8443".to_string(),
+            cert_path: "/etc/prx/default.crt".to_string(),
+            key_path: "/etc/prx/default.key".to_string(),
+            enable_h2: true,
+            client_ca_path: None,
+            require_client_cert: false,
+            certificates: Vec::new(),
+        });
+        let err = cfg.validate().expect_err("the handshake comes first");
+        assert!(err.to_string().contains("server.proxy_protocol"));
+    }
+
+    #[test]
+    fn validate_rejects_nested_sni_wildcards() {
+        let mut cfg = valid_config();
+        cfg.server.tls = Some(TlsConfig {
+            listen: "127.0.0.1:8443".to_string(),
+            cert_path: "/etc/prx/default.crt".to_string(),
+            key_path: "/etc/prx/default.key".to_string(),
+            enable_h2: true,
+            client_ca_path: None,
+            require_client_cert: false,
+            certificates: vec![TlsCertificateConfig {
+                hosts: vec!["*.example.com".to_string(), "*.*.example.com".to_string()],
+                cert_path: "/etc/prx/example.crt".to_string(),
+                key_path: "/etc/prx/example.key".to_string(),
+            }],
+        });
+
+        let err = cfg.validate().expect_err("wildcards cover one label");
+        assert!(format!("{err:#}").contains("*.*.example.com"));
+    }
+
+    #[test]
+    fn validate_accepts_upstream_client_certs_only_with_openssl() {
+        let mut cfg = valid_config();
+        let upstream = &mut cfg.services[0].upstreams[0];
+        upstream.tls = true;
+        upstream.client_cert_path = Some("/etc/prx/client.crt".to_string());
+        upstream.client_key_path = Some("/etc/prx/client.key".to_string());
+
+        #[cfg(feature = "openssl")]
+        cfg.validate().expect("client certs are presented");
+        #[cfg(not(feature = "openssl"))]
+        {
+            let err = cfg.validate().expect_err("stub TLS backend");
+            assert!(
+                err.to_string()
+                    .contains("client certificates need prx built with the openssl feature")
+            );
+        }
+    }
+
+    #[test]
+    fn validate_rejects_upstream_client_cert_without_key() {
+        let mut cfg = valid_config();
+        cfg.services[0].upstreams[0].tls = true;
+        cfg.services[0].upstreams[0].client_cert_path = Some("/etc/prx/client.crt".to_string());
+
+        let err = cfg.validate().expect_err("a certificate needs its key");
+        assert!(
+            err.to_string()
+                .contains("both client_cert_path and client_key_path")
+        );
+    }
+
+    #[test]
+    fn validate_parses_certificate_pins() {
+        let hex = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+        assert_eq!(
+            parse_sha256_pin(hex).expect("hex pin"),
+            parse_sha256_pin("n4bQgYhMfWWaL+qgxVrQFaO/TxsrC4Is0V1sFbDwCgg=").expect("base64 pin")
+        );
+        assert!(parse_sha256_pin("sha256/n4bQgYhMfWWaL+qgxVrQFaO/TxsrC4Is0V1sFbDwCgg=").is_ok());
+        assert!(parse_sha256_pin(&hex[..62]).is_err());
+
+        let mut cfg = valid_config();
+        cfg.services[0].upstreams[0].pinned_cert_sha256 = vec![hex.to_string()];
+        let err = cfg.validate().expect_err("pins need tls");
+        assert!(err.to_string().contains("needs tls = true"));
+
+        cfg.services[0].upstreams[0].tls = true;
+        cfg.services[0].upstreams[0]
+            .pinned_cert_sha256
+            .push("not-a-hash".to_string());
+        let err = cfg.validate().expect_err("invalid pin");
+        assert!(err.to_string().contains("invalid pinned_cert_sha256"));
+
+        cfg.services[0].upstreams[0].pinned_cert_sha256.pop();
+        #[cfg(feature = "openssl")]
+        cfg.validate().expect("pins are checked");
+        #[cfg(not(feature = "openssl"))]
+        {
+            let err = cfg.validate().expect_err("stub TLS backend");
+            assert!(
+                err.to_string()
+                    .contains("certificate pinning needs prx built with the openssl feature")
+            );
+        }
+    }
+
+    #[test]
+    fn validate_rejects_route_groups_over_100_percent() {
+        let mut cfg = valid_config();
+        let group = |name: &str, percent| RouteGroupConfig {
+            name: name.to_string(),
+            service: cfg.services[0].name.clone(),
+            percent,
+        };
+        cfg.routes[0].groups = vec![group("canary", 60), group("beta", 50)];
+
+        let err = cfg
+            .validate()
+            .expect_err("groups cannot exceed 100 percent");
+        assert!(err.to_string().contains("more than 100"));
+    }
+
+    #[test]
+    fn validate_rejects_duplicate_and_bodiless_status_maps() {
+        let mapping = |from, to| StatusMapConfig {
+            from,
+            to,
+            body: None,
+            headers: BTreeMap::new(),
+        };
+        let mut cfg = valid_config();
+        cfg.routes[0].status_map = vec![mapping(404, 200), mapping(404, 410)];
+        let err = cfg.validate().expect_err("404 is mapped twice");
+        assert!(err.to_string().contains("maps status 404 twice"));
+
+        let mut cfg = valid_config();
+        cfg.routes[0].status_map = vec![StatusMapConfig {
+            body: Some("gone".to_string()),
+            ..mapping(410, 204)
+        }];
+        let err = cfg.validate().expect_err("204 has no body");
+        assert!(err.to_string().contains("cannot send a body"));
+    }
+
+    #[test]
+    fn validate_rejects_tcp_routes_clashing_with_http_routes() {
+        let tcp_route = |name: &str, listen: &str| TcpRouteConfig {
+            name: name.to_string(),
+            listen: vec![listen.to_string()],
+            service: "default".to_string(),
+            idle_timeout_ms: None,
+            proxy_protocol: None,
+            acl: AccessControlConfig::default(),
+        };
+        let mut cfg = valid_config();
+        cfg.tcp_routes = vec![tcp_route("default", "127.0.0.1:5432")];
+        let err = cfg.validate().expect_err("names share the route label");
+        assert!(err.to_string().contains("duplicate route name"));
+
+        let mut cfg = valid_config();
+        cfg.tcp_routes = vec![tcp_route("postgres", &cfg.server.listen[0])];
+        let err = cfg.validate().expect_err("listener is taken");
+        assert!(err.to_string().contains("used by both"));
+
+        let mut cfg = valid_config();
+        cfg.services[0].upstreams[0].tls = true;
+        cfg.tcp_routes = vec![tcp_route("postgres", "127.0.0.1:5432")];
+        let err = cfg.validate().expect_err("no tls origination");
+        assert!(err.to_string().contains("sets tls"));
+    }
+
+    #[test]
+    fn validate_rejects_invalid_egress() {
+        let mut cfg = valid_config();
+        cfg.routes[0].egress.dscp = Some(64);
+        let err = cfg.validate().expect_err("dscp is six bits");
+        assert!(format!("{err:#}").contains("dscp"));
+
+        let mut cfg = valid_config();
+        cfg.services[0].upstreams[0].egress.local_addr = Some("eth0".to_string());
+        let err = cfg.validate().expect_err("local_addr must be an IP");
+        assert!(format!("{err:#}").contains("local_addr"));
+    }
+
+    #[test]
+    fn validate_rejects_out_of_range_route_sample_rate() {
+        let mut cfg = valid_config();
+        cfg.routes[0].observability.access_log_sample_rate = Some(1.5);
+
+        let err = cfg
+            .validate()
+            .expect_err("sample rate above 1.0 should fail");
+        assert!(err.to_string().contains("access_log_sample_rate"));
+    }
+
+    #[test]
+    fn validate_rejects_zero_listener_backlog() {
+        let mut cfg = valid_config();
+        cfg.server.socket.backlog = Some(0);
+
+        let err = cfg.validate().expect_err("zero backlog should fail");
+        assert!(err.to_string().contains("server.socket.backlog"));
+    }
+
+    #[test]
+    fn validate_rejects_zero_buffer_sizes() {
+        let mut cfg = valid_config();
+        cfg.server.upstream_write_buffer_bytes = Some(0);
+
+        let err = cfg.validate().expect_err("zero buffer should fail");
+        assert!(
+            err.to_string()
+                .contains("server.upstream_write_buffer_bytes")
+        );
+    }
+
+    #[test]
+    fn validate_rejects_app_listener_shared_with_server() {
+        let mut cfg = valid_config();
+        cfg.apps.push(AppConfig {
+            name: "internal".to_string(),
+            listen: cfg.server.listen.clone(),
+            tls: None,
+            proxy_protocol: None,
+            observability: RouteObservabilityConfig::default(),
+            acl: AccessControlConfig::default(),
+        });
+        cfg.routes[0].app = Some("internal".to_string());
+
+        let err = cfg.validate().expect_err("shared listener should fail");
+        assert!(err.to_string().contains("used by both"));
+    }
+
+    #[test]
+    fn validate_rejects_route_in_unknown_app() {
+        let mut cfg = valid_config();
+        cfg.routes[0].app = Some("missing".to_string());
+
+        let err = cfg.validate().expect_err("unknown app should fail");
+        assert!(err.to_string().contains("unknown app 'missing'"));
+    }
+
+    #[test]
+    fn validate_rejects_unknown_tenant_and_shared_tokens() {
+        let mut cfg = valid_config();
+        cfg.routes[0].tenant = Some("payments".to_string());
+        let err = cfg.validate().expect_err("unknown tenant should fail");
+        assert!(err.to_string().contains("unknown tenant 'payments'"));
+
+        cfg.tenants = ["payments", "search"]
+            .map(|name| TenantConfig {
+                name: name.to_string(),
+                admin_token: "shared".to_string(),
+                hosts: Vec::new(),
+                services: Vec::new(),
+            })
+            .to_vec();
+        let err = cfg.validate().expect_err("shared token should fail");
+        assert!(err.to_string().contains("share an admin_token"));
+    }
+
+    #[test]
+    fn validate_rejects_tenants_owning_unknown_or_shared_hosts_and_services() {
+        let mut cfg = valid_config();
+        let service = cfg.services[0].name.clone();
+        cfg.tenants = [("payments", "pay-token"), ("search", "search-token")]
+            .map(|(name, token)| TenantConfig {
+                name: name.to_string(),
+                admin_token: token.to_string(),
+                hosts: vec!["*.example.com".to_string()],
+                services: vec![service.clone()],
+            })
+            .to_vec();
+        cfg.tenants[1].hosts.push("*.*".to_string());
+        cfg.tenants[1].services.push("missing".to_string());
+
+        let fields: Vec<_> = cfg
+            .problems()
+            .into_iter()
+            .map(|problem| (problem.field, problem.code))
+            .collect();
+        assert_eq!(
+            fields,
+            [
+                ("tenant[1].hosts".to_string(), "duplicate"),
+                ("tenant[1].hosts".to_string(), "invalid"),
+                ("tenant[1].services".to_string(), "duplicate"),
+                ("tenant[1].services".to_string(), "unknown_reference"),
+            ]
+        );
+    }
+
+    #[test]
+    fn validate_rejects_incomplete_admin_auth() {
+        let mut cfg = valid_config();
+        cfg.admin = Some(AdminConfig {
+            auth: Some(AdminAuthConfig::default()),
+            audit_log: None,
+            config_history: None,
+        });
+        let fields = |cfg: &PrxConfig| -> Vec<_> {
+            cfg.problems()
+                .into_iter()
+                .map(|problem| (problem.field, problem.code))
+                .collect()
+        };
+        assert_eq!(fields(&cfg), [("admin.auth".to_string(), "required")]);
+
+        cfg.tenants = vec![TenantConfig {
+            name: "payments".to_string(),
+            admin_token: "pay-token".to_string(),
+            hosts: Vec::new(),
+            services: Vec::new(),
+        }];
+        cfg.routes[0].tenant = Some("payments".to_string());
+        let auth = cfg
+            .admin
+            .as_mut()
+            .and_then(|admin| admin.auth.as_mut())
+            .expect("auth");
+        auth.tokens.push(AdminTokenConfig {
+            name: "ci".to_string(),
+            token: "pay-token".to_string(),
+            scope: AdminAccess::ReadWrite,
+        });
+        auth.basic.push(AdminBasicConfig {
+            username: "ops:1".to_string(),
+            password: String::new(),
+            scope: AdminAccess::ReadOnly,
+        });
+        assert_eq!(
+            fields(&cfg),
+            [
+                ("admin.auth.basic[0].username".to_string(), "invalid"),
+                ("admin.auth.basic[0].password".to_string(), "required"),
+                ("admin.auth.token[0].token".to_string(), "duplicate"),
+            ]
+        );
+    }
+
+    #[test]
+    fn merges_included_routes_and_names_their_file_in_problems() {
+        let dir = tempfile::tempdir().expect("tempdir");
+        fs::create_dir(dir.path().join("conf.d")).expect("conf.d");
+        let path = dir.path().join("Prx.toml");
+        let main = "include = [\"conf.d/*.toml\"]\n\n[[service]]\nname = \"app\"\n\n\
+                    [[service.upstream]]\naddr = \"127.0.0.1:9000\"\n";
+        fs::write(&path, main).expect("write");
+        fs::write(
+            dir.path().join("conf.d/api.toml"),
+            "[[route]]\nname = \"api\"\nservice = \"app\"\npath_prefix = \"/api\"\n",
+        )
+        .expect("write");
+        fs::write(
+            dir.path().join("conf.d/web.toml"),
+            "[[route]]\nname = \"web\"\nservice = \"missing\"\n",
+        )
+        .expect("write");
+
+        let err = PrxConfig::from_file(&path).expect_err("unknown service");
+        let problems = &err.downcast_ref::<ConfigErrors>().expect("config errors").0;
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].field, "route[1].service");
+        assert!(
+            problems[0]
+                .file
+                .as_deref()
+                .is_some_and(|file| file.ends_with("web.toml"))
+        );
+        assert!(
+            problems[0].message.contains("web.toml: "),
+            "{}",
+            problems[0].message
+        );
+
+        fs::write(
+            dir.path().join("conf.d/web.toml"),
+            "[[route]]\nname = \"web\"\nservice = \"app\"\n",
+        )
+        .expect("write");
+        let text = fs::read_to_string(&path).expect("read");
+        let (config, included) = PrxConfig::parse_at(&text, &path).expect("parse");
+        let names: Vec<_> = config
+            .routes
+            .iter()
+            .map(|route| route.name.as_str())
+            .collect();
+        assert_eq!(names, ["api", "web"]);
+        config.validate_with(&included).expect("valid");
+
+        let mut edited = config.clone();
+        edited.routes.push(RouteConfig {
+            name: "added".to_string(),
+            ..config.routes[0].clone()
+        });
+        let main = edited.without_included(&included).expect("main file");
+        assert_eq!(main.routes.len(), 1);
+        assert_eq!(main.include, config.include);
+        edited.routes[0].timeout_ms = Some(1_000);
+        let err = edited
+            .without_included(&included)
+            .expect_err("included route changed");
+        assert!(err.to_string().contains("defined in"), "{err}");
+        assert!(PrxConfig::from_toml_str(&text).is_err());
+    }
+
+    #[test]
+    fn warns_about_shadowed_routes_and_unused_services() {
+        let mut cfg = valid_config();
+        assert_eq!(cfg.report(), ValidationReport::default());
+
+        let mut wildcard = cfg.routes[0].clone();
+        wildcard.name = "any-sub".to_string();
+        wildcard.host = Some("*.Example.com".to_string());
+        wildcard.path_prefix = "/api".to_string();
+        wildcard.is_default = false;
+        let shadowed = RouteConfig {
+            name: "api".to_string(),
+            host: Some("api.example.com".to_string()),
+            ..wildcard.clone()
+        };
+        let with_header = RouteConfig {
+            name: "beta".to_string(),
+            headers: BTreeMap::from([("x-beta".to_string(), "1".to_string())]),
+            ..shadowed.clone()
+        };
+        cfg.routes.extend([shadowed, wildcard, with_header]);
+        let mut unused = cfg.services[0].clone();
+        unused.name = "legacy".to_string();
+        cfg.services.push(unused);
+
+        let report = cfg.report();
+        assert!(report.errors.is_empty(), "{:?}", report.errors);
+        let warnings: Vec<_> = report
+            .warnings
+            .iter()
+            .map(|warning| (warning.field.as_str(), warning.code))
+            .collect();
+        assert_eq!(
+            warnings,
+            [
+                ("route[1].path_prefix", "shadowed"),
+                ("service[1]", "unused")
+            ]
+        );
+        assert!(
+            report.warnings[0]
+                .message
+                .contains("route 'any-sub' is tried first")
+        );
+        cfg.validate().expect("warnings do not fail validation");
+    }
+
+    #[test]
+    fn rejects_routes_with_the_same_matchers() {
+        let mut cfg = valid_config();
+        let mut api = cfg.routes[0].clone();
+        api.name = "api".to_string();
+        api.host = Some("api.example.com".to_string());
+        api.path_prefix = "/api".to_string();
+        api.is_default = false;
+        let copy = RouteConfig {
+            name: "api-v2".to_string(),
+            host: Some("API.example.com".to_string()),
+            ..api.clone()
+        };
+        let other_path = RouteConfig {
+            name: "admin".to_string(),
+            path_prefix: "/admin".to_string(),
+            ..api.clone()
+        };
+        cfg.routes.extend([api, copy, other_path]);
+
+        let report = cfg.report();
+        let errors: Vec<_> = report
+            .errors
+            .iter()
+            .map(|problem| (problem.field.as_str(), problem.code))
+            .collect();
+        assert_eq!(errors, [("route[2].path_prefix", "duplicate")]);
+        assert!(
+            report.errors[0]
+                .message
+                .contains("same requests as route 'api'")
+        );
+        assert!(report.warnings.is_empty(), "{:?}", report.warnings);
+    }
+
     #[test]
     fn validate_accepts_valid_config() {
         let cfg = valid_config();
         cfg.validate().expect("valid config should pass");
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,140 @@
+use serde::Serialize;
+
+use crate::config::PrxConfig;
+
+/// What differs between two configs: whole sections, and named blocks.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ConfigDiff {
+    /// `include`, or the tables such as `server` and `waf` that changed.
+    pub sections: Vec<&'static str>,
+    pub apps: BlockDiff,
+    pub tenants: BlockDiff,
+    pub services: BlockDiff,
+    pub routes: BlockDiff,
+    pub tcp_routes: BlockDiff,
+}
+
+/// Names of the blocks of one kind that were added, removed or changed.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct BlockDiff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub changed: Vec<String>,
+}
+
+impl ConfigDiff {
+    pub fn between(before: &PrxConfig, after: &PrxConfig) -> Self {
+        let sections = [
+            ("include", before.include != after.include),
+            ("server", before.server != after.server),
+            ("observability", before.observability != after.observability),
+            ("admin", before.admin != after.admin),
+            ("waf", before.waf != after.waf),
+            ("reputation", before.reputation != after.reputation),
+        ];
+        Self {
+            sections: sections
+                .into_iter()
+                .filter_map(|(section, changed)| changed.then_some(section))
+                .collect(),
+            apps: BlockDiff::between(&before.apps, &after.apps, |app| &app.name),
+            tenants: BlockDiff::between(&before.tenants, &after.tenants, |tenant| &tenant.name),
+            services: BlockDiff::between(&before.services, &after.services, |service| {
+                &service.name
+            }),
+            routes: BlockDiff::between(&before.routes, &after.routes, |route| &route.name),
+            tcp_routes: BlockDiff::between(&before.tcp_routes, &after.tcp_routes, |route| {
+                &route.name
+            }),
+        }
+    }
+
+    /// One line per change, e.g. `[server] changed` or `route 'api' added`.
+    pub fn summary(&self) -> Vec<String> {
+        let mut lines: Vec<String> = self
+            .sections
+            .iter()
+            .map(|section| match *section {
+                "include" => "include changed".to_string(),
+                table => format!("[{table}] changed"),
+            })
+            .collect();
+        for (kind, blocks) in [
+            ("app", &self.apps),
+            ("tenant", &self.tenants),
+            ("service", &self.services),
+            ("route", &self.routes),
+            ("tcp_route", &self.tcp_routes),
+        ] {
+            for (names, change) in [
+                (&blocks.removed, "removed"),
+                (&blocks.changed, "changed"),
+                (&blocks.added, "added"),
+            ] {
+                lines.extend(names.iter().map(|name| format!("{kind} '{name}' {change}")));
+            }
+        }
+        lines
+    }
+}
+
+impl BlockDiff {
+    fn between<T: PartialEq>(before: &[T], after: &[T], name: impl Fn(&T) -> &String) -> Self {
+        let mut diff = Self::default();
+        for old in before {
+            match after.iter().find(|new| name(new) == name(old)) {
+                None => diff.removed.push(name(old).clone()),
+                Some(new) if new != old => diff.changed.push(name(old).clone()),
+                Some(_) => {}
+            }
+        }
+        diff.added = after
+            .iter()
+            .filter(|new| !before.iter().any(|old| name(old) == name(new)))
+            .map(|new| name(new).clone())
+            .collect();
+        diff
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_changed_sections_and_blocks() {
+        let before: PrxConfig = toml::from_str(
+            "[[service]]\nname = \"app\"\n\n[[route]]\nname = \"old\"\nservice = \"app\"\n\n\
+             [[route]]\nname = \"api\"\nservice = \"app\"\n",
+        )
+        .expect("before");
+        let mut after = before.clone();
+        after.routes.remove(0);
+        after.routes[0].path_prefix = "/api".to_string();
+        after.routes.push(before.routes[0].clone());
+        after.routes[1].name = "new".to_string();
+        after.server.health_path = "/health".to_string();
+
+        let diff = ConfigDiff::between(&before, &after);
+        assert_eq!(diff.sections, ["server"]);
+        assert_eq!(
+            diff.routes,
+            BlockDiff {
+                added: vec!["new".to_string()],
+                removed: vec!["old".to_string()],
+                changed: vec!["api".to_string()],
+            }
+        );
+        assert_eq!(diff.services, BlockDiff::default());
+        assert_eq!(
+            diff.summary(),
+            [
+                "[server] changed",
+                "route 'old' removed",
+                "route 'api' changed",
+                "route 'new' added"
+            ]
+        );
+        assert!(ConfigDiff::between(&before, &before).summary().is_empty());
+    }
+}
@@ -0,0 +1,661 @@
+use std::{
+    collections::HashMap,
+    fs, io,
+    io::{Read, Write},
+    net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket},
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+use anyhow::{Context, bail};
+use arc_swap::ArcSwap;
+use rand::Rng;
+use tracing::{info, warn};
+
+use crate::{
+    config::{DiscoverConfig, UpstreamConfig},
+    runtime::{RuntimeConfig, ServiceDiscovery, UpstreamRuntime, now_epoch_ms},
+};
+
+const TICK: Duration = Duration::from_millis(100);
+const DNS_TIMEOUT: Duration = Duration::from_secs(3);
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const TYPE_SRV: u16 = 33;
+
+/// Resolves every upstream with a `resolve_interval_s`, and looks up every
+/// service's `discover`, on their schedules, following the active config
+/// across reloads. The system resolver does not report record TTLs, so the
+/// intervals stand in for them.
+pub fn spawn_resolver(active_config: Arc<ArcSwap<RuntimeConfig>>) -> io::Result<()> {
+    thread::Builder::new()
+        .name("prx-resolver".to_string())
+        .spawn(move || {
+            loop {
+                start_due_lookups(&active_config);
+                thread::sleep(TICK);
+            }
+        })
+        .map(|_| ())
+}
+
+fn start_due_lookups(active_config: &Arc<ArcSwap<RuntimeConfig>>) {
+    let now_ms = now_epoch_ms();
+    for service in active_config.load().services() {
+        if let Some(discovery) = service.claim_discovery(now_ms) {
+            let service = service.name.clone();
+            let active_config = active_config.clone();
+            let spawned = thread::Builder::new()
+                .name("prx-discover".to_string())
+                .spawn(move || discover(&active_config, &service, &discovery));
+            if let Err(err) = spawned {
+                warn!(error = %err, "failed to start upstream discovery");
+            }
+        }
+        for upstream in &service.upstreams {
+            if !upstream.claim_resolve(now_ms) {
+                continue;
+            }
+            let service = service.name.clone();
+            let upstream = upstream.clone();
+            // getaddrinfo blocks, so a slow name server must not hold back
+            // the other upstreams.
+            let spawned = thread::Builder::new()
+                .name("prx-resolve".to_string())
+                .spawn(move || resolve(&service, &upstream));
+            if let Err(err) = spawned {
+                warn!(error = %err, "failed to start upstream lookup");
+            }
+        }
+    }
+}
+
+fn resolve(service: &str, upstream: &UpstreamRuntime) {
+    let addrs = match upstream.addr.to_socket_addrs() {
+        Ok(addrs) => Some(addrs.collect::<Vec<SocketAddr>>()),
+        Err(err) => {
+            warn!(
+                service,
+                upstream = &*upstream.addr,
+                error = %err,
+                "failed to resolve upstream; keeping its previous addresses"
+            );
+            None
+        }
+    };
+    if upstream.finish_resolve(addrs) {
+        info!(
+            service,
+            upstream = &*upstream.addr,
+            addrs = ?upstream.resolved_addrs(),
+            "upstream resolved to new addresses"
+        );
+    }
+}
+
+/// Looks up `service`'s `discover` records and swaps in a snapshot serving
+/// them when they changed. A failed lookup keeps the upstreams found before.
+fn discover(
+    active_config: &ArcSwap<RuntimeConfig>,
+    service: &str,
+    discovery: &Arc<ServiceDiscovery>,
+) {
+    let found = lookup_srv(&discovery.config);
+    discovery.finish();
+    let found = match found {
+        Ok(found) => found,
+        Err(err) => {
+            warn!(
+                service,
+                name = %discovery.config.name,
+                error = %format!("{err:#}"),
+                "failed to discover upstreams; keeping the previous ones"
+            );
+            return;
+        }
+    };
+    // A reload may land in between; retry against whatever is active then.
+    loop {
+        let current = active_config.load_full();
+        let Some(next) = current.with_discovered(service, discovery, found.clone()) else {
+            return;
+        };
+        let next = Arc::new(next);
+        let swapped = active_config.compare_and_swap(&current, next.clone());
+        if !Arc::ptr_eq(&swapped, &current) {
+            continue;
+        }
+        info!(
+            service,
+            name = %discovery.config.name,
+            upstreams = ?found.iter().map(|upstream| &upstream.addr).collect::<Vec<_>>(),
+            "discovered upstreams changed"
+        );
+        // The stable side of a rollout still serves the old upstreams.
+        if next.rollout().is_none() && next.shadow().is_none() {
+            current.retire_stale(&next);
+        }
+        return;
+    }
+}
+
+/// `ip:port`, or an `ip` on port 53.
+pub fn parse_nameserver(value: &str) -> Option<SocketAddr> {
+    value.parse().ok().or_else(|| {
+        value
+            .parse::<IpAddr>()
+            .ok()
+            .map(|ip| SocketAddr::new(ip, 53))
+    })
+}
+
+fn system_nameserver() -> anyhow::Result<SocketAddr> {
+    let resolv =
+        fs::read_to_string("/etc/resolv.conf").context("failed to read /etc/resolv.conf")?;
+    resolv
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("nameserver"))
+        .find_map(|rest| parse_nameserver(rest.trim()))
+        .context("/etc/resolv.conf lists no usable nameserver")
+}
+
+fn lookup_srv(config: &DiscoverConfig) -> anyhow::Result<Vec<UpstreamConfig>> {
+    let nameserver = match &config.nameserver {
+        Some(nameserver) => parse_nameserver(nameserver).context("invalid nameserver")?,
+        None => system_nameserver()?,
+    };
+    let id = rand::rng().random();
+    let response = query(nameserver, &encode_query(id, &config.name)?)
+        .with_context(|| format!("failed to query {nameserver}"))?;
+    let (records, addrs) = parse_response(&response, id)?;
+    srv_upstreams(records, &addrs, config)
+}
+
+fn encode_query(id: u16, name: &str) -> anyhow::Result<Vec<u8>> {
+    let mut query = Vec::with_capacity(18 + name.len());
+    query.extend_from_slice(&id.to_be_bytes());
+    // Recursion desired, one question.
+    query.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            bail!("invalid record name {name:?}");
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&TYPE_SRV.to_be_bytes());
+    query.extend_from_slice(&1u16.to_be_bytes());
+    Ok(query)
+}
+
+/// Asks over UDP, and again over TCP when the answer did not fit.
+fn query(nameserver: SocketAddr, query: &[u8]) -> io::Result<Vec<u8>> {
+    let local: SocketAddr = if nameserver.is_ipv4() {
+        ([0, 0, 0, 0], 0).into()
+    } else {
+        ([0u16; 8], 0).into()
+    };
+    let socket = UdpSocket::bind(local)?;
+    socket.set_read_timeout(Some(DNS_TIMEOUT))?;
+    socket.connect(nameserver)?;
+    socket.send(query)?;
+    let mut response = vec![0; 4096];
+    loop {
+        let len = socket.recv(&mut response)?;
+        // Stray datagrams for an earlier query are skipped.
+        if len >= 12 && response[..2] == query[..2] {
+            response.truncate(len);
+            break;
+        }
+    }
+    if response[2] & 0x02 == 0 {
+        return Ok(response);
+    }
+
+    let mut stream = TcpStream::connect_timeout(&nameserver, DNS_TIMEOUT)?;
+    stream.set_read_timeout(Some(DNS_TIMEOUT))?;
+    stream.set_write_timeout(Some(DNS_TIMEOUT))?;
+    stream.write_all(&(query.len() as u16).to_be_bytes())?;
+    stream.write_all(query)?;
+    let mut len = [0; 2];
+    stream.read_exact(&mut len)?;
+    let mut response = vec![0; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut response)?;
+    Ok(response)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct SrvRecord {
+    priority: u16,
+    weight: u16,
+    port: u16,
+    target: String,
+}
+
+type TargetAddrs = HashMap<String, Vec<IpAddr>>;
+
+/// The SRV records of a response, and addresses it carries for their targets.
+fn parse_response(message: &[u8], id: u16) -> anyhow::Result<(Vec<SrvRecord>, TargetAddrs)> {
+    let field = |pos: usize| -> anyhow::Result<u16> {
+        let bytes = message
+            .get(pos..pos + 2)
+            .context("truncated dns response")?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    };
+    if message.len() < 12 || field(0)? != id || message[2] & 0x80 == 0 {
+        bail!("unexpected dns response");
+    }
+    match message[3] & 0x0f {
+        0 => {}
+        3 => bail!("no such name"),
+        rcode => bail!("name server answered rcode {rcode}"),
+    }
+    let questions = field(4)?;
+    let records = field(6)? as usize + field(8)? as usize + field(10)? as usize;
+
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = read_name(message, pos)?.1 + 4;
+    }
+    let mut srv = Vec::new();
+    let mut addrs = TargetAddrs::new();
+    for _ in 0..records {
+        let (owner, at) = read_name(message, pos)?;
+        let kind = field(at)?;
+        let start = at + 10;
+        let end = start + field(at + 8)? as usize;
+        let data = message.get(start..end).context("truncated dns record")?;
+        match (kind, data.len()) {
+            (TYPE_SRV, 7..) => srv.push(SrvRecord {
+                priority: field(start)?,
+                weight: field(start + 2)?,
+                port: field(start + 4)?,
+                target: read_name(message, start + 6)?.0,
+            }),
+            (TYPE_A, 4) => addrs
+                .entry(owner)
+                .or_default()
+                .push(IpAddr::from(<[u8; 4]>::try_from(data)?)),
+            (TYPE_AAAA, 16) => addrs
+                .entry(owner)
+                .or_default()
+                .push(IpAddr::from(<[u8; 16]>::try_from(data)?)),
+            _ => {}
+        }
+        pos = end;
+    }
+    Ok((srv, addrs))
+}
+
+/// Reads the possibly compressed name at `pos`, lowercased and without the
+/// trailing dot; also returns where the data after it starts.
+fn read_name(message: &[u8], mut pos: usize) -> anyhow::Result<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut after = None;
+    let mut jumps = 0;
+    loop {
+        let len = *message.get(pos).context("truncated dns name")? as usize;
+        match len & 0xc0 {
+            0x00 if len == 0 => {
+                pos += 1;
+                break;
+            }
+            0x00 => {
+                let label = message
+                    .get(pos + 1..pos + 1 + len)
+                    .context("truncated dns name")?;
+                labels.push(String::from_utf8_lossy(label).to_ascii_lowercase());
+                pos += 1 + len;
+            }
+            0xc0 => {
+                let low = *message.get(pos + 1).context("truncated dns name")? as usize;
+                after.get_or_insert(pos + 2);
+                jumps += 1;
+                if jumps > 32 {
+                    bail!("dns name compression loops");
+                }
+                pos = ((len & 0x3f) << 8) | low;
+            }
+            _ => bail!("unsupported dns label type"),
+        }
+    }
+    Ok((labels.join("."), after.unwrap_or(pos)))
+}
+
+/// One upstream per address of every target, tiered by priority with the
+/// lowest in tier 0; weights above what a service takes are scaled down,
+/// keeping their ratio.
+fn srv_upstreams(
+    mut records: Vec<SrvRecord>,
+    addrs: &TargetAddrs,
+    config: &DiscoverConfig,
+) -> anyhow::Result<Vec<UpstreamConfig>> {
+    // A "." target says the service is not offered there.
+    records.retain(|record| !record.target.is_empty());
+    let Some(lowest) = records.iter().map(|record| record.priority).min() else {
+        bail!("no SRV records");
+    };
+    let max_weight = records
+        .iter()
+        .map(|record| u32::from(record.weight))
+        .max()
+        .unwrap_or(1);
+
+    let mut upstreams = Vec::new();
+    for record in records {
+        let weight = match max_weight {
+            _ if !config.use_srv_weights => 1,
+            0..=256 => u32::from(record.weight),
+            _ => u32::from(record.weight) * 256 / max_weight,
+        }
+        .max(1) as u16;
+        let tier = if config.use_srv_priorities {
+            record.priority - lowest
+        } else {
+            0
+        };
+        let targets: Vec<String> = match addrs.get(&record.target) {
+            Some(ips) => ips
+                .iter()
+                .map(|ip| SocketAddr::new(*ip, record.port).to_string())
+                .collect(),
+            None => (record.target.as_str(), record.port)
+                .to_socket_addrs()
+                .map(|addrs| addrs.map(|addr| addr.to_string()).collect())
+                .unwrap_or_default(),
+        };
+        let targets = if targets.is_empty() {
+            // Left to the connector, as for a listed upstream.
+            vec![format!("{}:{}", record.target, record.port)]
+        } else {
+            targets
+        };
+        for addr in targets {
+            upstreams.push(UpstreamConfig {
+                tier,
+                ..discovered_upstream(addr, &record.target, weight, config.tls)
+            });
+        }
+    }
+    Ok(upstreams)
+}
+
+fn discovered_upstream(addr: String, target: &str, weight: u16, tls: bool) -> UpstreamConfig {
+    UpstreamConfig {
+        addr,
+        tls,
+        sni: Some(target.to_string()),
+        verify_hostname_as: None,
+        host: None,
+        preserve_host: false,
+        http2: false,
+        http_version: None,
+        weight,
+        verify_cert: None,
+        verify_hostname: None,
+        client_cert_path: None,
+        client_key_path: None,
+        pinned_cert_sha256: Vec::new(),
+        connect_timeout_ms: None,
+        total_connect_timeout_ms: None,
+        read_timeout_ms: None,
+        write_timeout_ms: None,
+        idle_timeout_ms: None,
+        max_requests_per_connection: None,
+        max_connection_lifetime_ms: None,
+        health_check: None,
+        egress: Default::default(),
+        proxy_protocol: None,
+        drain_hook: None,
+        resolve_interval_s: None,
+        tier: 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PrxConfig;
+
+    #[test]
+    fn keeps_the_last_records_and_spreads_requests_over_them() {
+        let config = PrxConfig::from_toml_str(
+            r#"
+[[service]]
+name = "backend"
+
+[[service.upstream]]
+addr = "127.0.0.1:9000"
+resolve_interval_s = 30
+
+[[route]]
+name = "web"
+path_prefix = "/"
+is_default = true
+service = "backend"
+"#,
+        )
+        .expect("config");
+        let snapshot = RuntimeConfig::from_config(config).expect("runtime");
+        let upstream = &snapshot.services()[0].upstreams[0];
+        assert_eq!(upstream.pick_addr(None), None);
+
+        assert!(upstream.claim_resolve(1_000));
+        assert!(!upstream.claim_resolve(1_000));
+        resolve("backend", upstream);
+        let local: SocketAddr = "127.0.0.1:9000".parse().expect("addr");
+        assert_eq!(*upstream.resolved_addrs(), [local]);
+        assert!(!upstream.claim_resolve(30_999));
+        assert!(upstream.claim_resolve(31_000));
+
+        let other: SocketAddr = "127.0.0.2:9000".parse().expect("addr");
+        assert!(upstream.finish_resolve(Some(vec![other, local, other])));
+        assert!(!upstream.finish_resolve(None));
+        assert!(!upstream.finish_resolve(Some(Vec::new())));
+        assert_eq!(*upstream.resolved_addrs(), [local, other]);
+        let picks: Vec<_> = (0..4).filter_map(|_| upstream.pick_addr(None)).collect();
+        assert_eq!(picks.iter().filter(|addr| **addr == other).count(), 2);
+        assert_eq!(upstream.pick_addr(Some(7)), upstream.pick_addr(Some(7)));
+    }
+
+    fn name(name: &str) -> Vec<u8> {
+        let mut encoded = Vec::new();
+        for label in name.split('.') {
+            encoded.push(label.len() as u8);
+            encoded.extend_from_slice(label.as_bytes());
+        }
+        encoded.push(0);
+        encoded
+    }
+
+    fn record(owner: &[u8], kind: u16, data: &[u8]) -> Vec<u8> {
+        let mut record = owner.to_vec();
+        record.extend_from_slice(&kind.to_be_bytes());
+        record.extend_from_slice(&[0, 1, 0, 0, 0, 30]);
+        record.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        record.extend_from_slice(data);
+        record
+    }
+
+    fn srv(priority: u16, weight: u16, port: u16, target: &[u8]) -> Vec<u8> {
+        [priority, weight, port]
+            .iter()
+            .flat_map(|field| field.to_be_bytes())
+            .chain(target.iter().copied())
+            .collect()
+    }
+
+    /// Answers every query with two SRV targets at priority 10, one with its
+    /// address attached, and a backup at priority 20.
+    fn spawn_nameserver(weights: Vec<(u16, u16)>) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").expect("bind");
+        let addr = socket.local_addr().expect("addr");
+        thread::spawn(move || {
+            for (first, second) in weights {
+                let mut query = [0; 512];
+                let (len, client) = socket.recv_from(&mut query).expect("recv");
+                let mut response = query[..2].to_vec();
+                response.extend_from_slice(&[0x81, 0x80, 0, 1, 0, 3, 0, 0, 0, 1]);
+                response.extend_from_slice(&query[12..len]);
+                // Owners point back at the question name.
+                let question = [0xc0, 12];
+                response.extend(record(
+                    &question,
+                    TYPE_SRV,
+                    &srv(10, first, 8080, &name("a.node.consul")),
+                ));
+                response.extend(record(
+                    &question,
+                    TYPE_SRV,
+                    &srv(10, second, 8081, &name("localhost")),
+                ));
+                response.extend(record(
+                    &question,
+                    TYPE_SRV,
+                    &srv(20, 1, 8082, &name("backup.node.consul")),
+                ));
+                response.extend(record(&name("a.node.consul"), TYPE_A, &[127, 0, 0, 3]));
+                socket.send_to(&response, client).expect("send");
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn merges_srv_records_into_the_service_upstreams() {
+        let nameserver = spawn_nameserver(vec![(1000, 10), (1000, 10), (1, 0)]);
+        let config = PrxConfig::from_toml_str(&format!(
+            r#"
+[[service]]
+name = "backend"
+discover = {{ type = "dns_srv", name = "_http._tcp.app.service.consul", nameserver = "{nameserver}" }}
+
+[service.circuit_breaker]
+enabled = true
+
+[[service.upstream]]
+addr = "127.0.0.1:9000"
+
+[[route]]
+name = "web"
+path_prefix = "/"
+is_default = true
+service = "backend"
+"#
+        ))
+        .expect("config");
+        let active = ArcSwap::from_pointee(RuntimeConfig::from_config(config).expect("runtime"));
+        let discovery = active.load().services()[0]
+            .claim_discovery(1_000)
+            .expect("due");
+        assert!(active.load().services()[0].claim_discovery(1_000).is_none());
+        assert!(
+            active.load().services()[0]
+                .claim_discovery(30_999)
+                .is_none()
+        );
+
+        discover(&active, "backend", &discovery);
+        let snapshot = active.load_full();
+        let upstreams = &snapshot.services()[0].upstreams;
+        assert_eq!(&*upstreams[0].addr, "127.0.0.1:9000");
+        let first = upstreams
+            .iter()
+            .find(|upstream| &*upstream.addr == "127.0.0.3:8080");
+        assert_eq!(
+            first.map(|upstream| (upstream.weight, upstream.sni.as_str())),
+            Some((256, "a.node.consul"))
+        );
+        let second = upstreams
+            .iter()
+            .find(|upstream| &*upstream.addr == "127.0.0.1:8081");
+        assert_eq!(second.map(|upstream| upstream.weight), Some(2));
+        let backup = upstreams
+            .iter()
+            .position(|upstream| upstream.addr.ends_with(":8082"))
+            .expect("backup");
+        assert_eq!(upstreams[backup].tier, 10);
+        // The backup tier only serves once the first has no upstream left.
+        let service = &snapshot.services()[0];
+        assert!(
+            (0..32)
+                .filter_map(|seed| service.next_upstream(seed, &[]))
+                .all(|(idx, _)| idx != backup)
+        );
+        let first_tier: Vec<usize> = (0..upstreams.len()).filter(|idx| *idx != backup).collect();
+        assert_eq!(
+            service.next_upstream(0, &first_tier).map(|(idx, _)| idx),
+            Some(backup)
+        );
+
+        // The same records leave the snapshot and its upstream state alone.
+        snapshot.services()[0].mark_upstream_failure(1);
+        discover(&active, "backend", &discovery);
+        assert!(Arc::ptr_eq(&snapshot, &active.load_full()));
+
+        discover(&active, "backend", &discovery);
+        let reweighted = active.load_full();
+        assert_eq!(reweighted.services()[0].upstreams[1].weight, 1);
+        assert_eq!(
+            reweighted.services()[0].upstreams[1]
+                .operational_state()
+                .consecutive_failures,
+            1
+        );
+    }
+
+    #[test]
+    fn srv_weights_and_priorities_can_be_ignored() {
+        let records = vec![
+            SrvRecord {
+                priority: 10,
+                weight: 50,
+                port: 80,
+                target: "a.example".to_string(),
+            },
+            SrvRecord {
+                priority: 20,
+                weight: 5,
+                port: 80,
+                target: "b.example".to_string(),
+            },
+        ];
+        let addrs = TargetAddrs::from([
+            ("a.example".to_string(), vec![IpAddr::from([127, 0, 0, 3])]),
+            ("b.example".to_string(), vec![IpAddr::from([127, 0, 0, 4])]),
+        ]);
+        let upstreams = |use_srv_weights, use_srv_priorities| {
+            let config = DiscoverConfig {
+                kind: crate::config::DiscoverKind::DnsSrv,
+                name: "_http._tcp.app.example".to_string(),
+                interval_s: 30,
+                nameserver: None,
+                tls: false,
+                use_srv_weights,
+                use_srv_priorities,
+            };
+            srv_upstreams(records.clone(), &addrs, &config)
+                .expect("upstreams")
+                .into_iter()
+                .map(|upstream| (upstream.addr, upstream.weight, upstream.tier))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            upstreams(true, true),
+            [
+                ("127.0.0.3:80".to_string(), 50, 0),
+                ("127.0.0.4:80".to_string(), 5, 10)
+            ]
+        );
+        assert_eq!(
+            upstreams(false, false),
+            [
+                ("127.0.0.3:80".to_string(), 1, 0),
+                ("127.0.0.4:80".to_string(), 1, 0)
+            ]
+        );
+    }
+}
@@ -0,0 +1,240 @@
+use std::{
+    io,
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+use pingora::server::ExecutionPhase;
+use tokio::sync::broadcast::{Receiver, error::RecvError};
+use tracing::{info, warn};
+
+use crate::{healthcheck::request_status, metrics, proxy_protocol, runtime::UpstreamRuntime};
+
+static DRAIN: Drain = Drain::new();
+
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+const HOOK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Process-wide shutdown drain state.
+///
+/// Pingora stops the accept loops and sends GOAWAY on HTTP/2 connections as
+/// soon as shutdown starts; prx additionally answers `Connection: close` on
+/// HTTP/1 responses, reports not-ready and logs the in-flight count until
+/// every request has finished.
+struct Drain {
+    draining: AtomicBool,
+}
+
+impl Drain {
+    const fn new() -> Self {
+        Self {
+            draining: AtomicBool::new(false),
+        }
+    }
+
+    /// Returns true only for the call that started draining.
+    fn begin(&self) -> bool {
+        !self.draining.swap(true, Ordering::AcqRel)
+    }
+
+    fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Acquire)
+    }
+}
+
+pub fn is_draining() -> bool {
+    DRAIN.is_draining()
+}
+
+/// Counts a request as in flight until dropped.
+#[derive(Debug)]
+pub struct InFlight(());
+
+impl InFlight {
+    pub fn start() -> Self {
+        metrics::inc_in_flight();
+        Self(())
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        metrics::dec_in_flight();
+    }
+}
+
+/// Starts draining when pingora begins a graceful terminate or upgrade.
+pub fn spawn_drain_watcher(mut phases: Receiver<ExecutionPhase>) -> io::Result<()> {
+    thread::Builder::new()
+        .name("prx-drain".to_string())
+        .spawn(move || {
+            loop {
+                match phases.blocking_recv() {
+                    Ok(
+                        ExecutionPhase::GracefulTerminate
+                        | ExecutionPhase::GracefulUpgradeTransferringFds,
+                    ) => {
+                        if DRAIN.begin() {
+                            report_until_drained();
+                            return;
+                        }
+                    }
+                    Ok(ExecutionPhase::Terminated) | Err(RecvError::Closed) => return,
+                    Ok(_) | Err(RecvError::Lagged(_)) => {}
+                }
+            }
+        })
+        .map(|_| ())
+}
+
+fn report_until_drained() {
+    loop {
+        let in_flight = metrics::in_flight();
+        if in_flight <= 0 {
+            info!("all in-flight requests drained");
+            return;
+        }
+        info!(in_flight, "draining in-flight requests");
+        thread::sleep(REPORT_INTERVAL);
+    }
+}
+
+/// Stops keeping connections to an upstream a reload took out of `service`
+/// alive and logs its requests in flight until they finish or `timeout`
+/// ends, after which the proxy cuts them.
+pub fn drain_upstream(service: &str, upstream: &UpstreamRuntime, timeout: Duration) {
+    if !upstream.begin_drain(timeout) || upstream.requests_in_flight() == 0 {
+        return;
+    }
+    let service = service.to_string();
+    let upstream = upstream.clone();
+    let deadline = Instant::now() + timeout;
+    let spawned = thread::Builder::new()
+        .name("prx-upstream-drain".to_string())
+        .spawn(move || {
+            loop {
+                let in_flight = upstream.requests_in_flight();
+                if in_flight == 0 {
+                    info!(
+                        service,
+                        upstream = &*upstream.addr,
+                        "removed upstream drained"
+                    );
+                    return;
+                }
+                let left = deadline.saturating_duration_since(Instant::now());
+                if left.is_zero() {
+                    warn!(
+                        service,
+                        upstream = &*upstream.addr,
+                        in_flight,
+                        "drain_timeout_s passed, cutting requests to removed upstream"
+                    );
+                    return;
+                }
+                info!(
+                    service,
+                    upstream = &*upstream.addr,
+                    in_flight,
+                    "draining removed upstream"
+                );
+                thread::sleep(left.min(REPORT_INTERVAL));
+            }
+        });
+    if let Err(err) = spawned {
+        warn!(error = %err, "failed to start upstream drain");
+    }
+}
+
+/// POSTs the `drain_hook` of an upstream a reload took out of `service`, on
+/// its own thread so the reload does not wait for it.
+pub fn signal_upstream(service: &str, upstream: &UpstreamRuntime) {
+    let Some(hook) = upstream.drain_hook.clone() else {
+        return;
+    };
+    let service = service.to_string();
+    let addr = upstream.addr.clone();
+    // Like health probes, the hook speaks for prx itself.
+    let preamble = match upstream.proxy_protocol {
+        Some(version) if hook.starts_with('/') => proxy_protocol::encode(version, None),
+        _ => Vec::new(),
+    };
+    let spawned = thread::Builder::new()
+        .name("prx-drain-hook".to_string())
+        .spawn(move || {
+            let (authority, path) = hook_target(&hook, &addr);
+            match request_status("POST", "prx", &preamble, authority, path, HOOK_TIMEOUT) {
+                Ok(status) if (200..300).contains(&status) => {
+                    info!(
+                        service,
+                        upstream = &*addr,
+                        hook,
+                        status,
+                        "signalled upstream drain"
+                    );
+                }
+                Ok(status) => {
+                    warn!(
+                        service,
+                        upstream = &*addr,
+                        hook,
+                        status,
+                        "upstream drain hook failed"
+                    );
+                }
+                Err(err) => warn!(
+                    service,
+                    upstream = &*addr,
+                    hook,
+                    error = %format!("{err:#}"),
+                    "upstream drain hook failed"
+                ),
+            }
+        });
+    if let Err(err) = spawned {
+        warn!(error = %err, "failed to start upstream drain hook");
+    }
+}
+
+/// Authority and path a hook is sent to: a path goes to the upstream
+/// itself, an `http://` URL to its own authority.
+fn hook_target<'a>(hook: &'a str, addr: &'a str) -> (&'a str, &'a str) {
+    match hook.strip_prefix("http://") {
+        Some(rest) => match rest.find('/') {
+            Some(idx) => rest.split_at(idx),
+            None => (rest, "/"),
+        },
+        None => (addr, hook),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn begin_reports_only_the_first_transition() {
+        let drain = Drain::new();
+        assert!(!drain.is_draining());
+        assert!(drain.begin());
+        assert!(!drain.begin());
+        assert!(drain.is_draining());
+    }
+
+    #[test]
+    fn hooks_go_to_the_upstream_or_their_own_authority() {
+        assert_eq!(
+            hook_target("/drain", "10.0.0.1:80"),
+            ("10.0.0.1:80", "/drain")
+        );
+        assert_eq!(
+            hook_target("http://ops:9000/hooks/drain", "10.0.0.1:80"),
+            ("ops:9000", "/hooks/drain")
+        );
+        assert_eq!(
+            hook_target("http://ops:9000", "10.0.0.1:80"),
+            ("ops:9000", "/")
+        );
+    }
+}
@@ -0,0 +1,114 @@
+use std::{fmt, io, iter, ptr};
+
+use tracing::{
+    Event, Level, Subscriber,
+    field::{Field, Visit},
+};
+use tracing_subscriber::{
+    EnvFilter, Layer, layer::Context, layer::SubscriberExt, util::SubscriberInitExt,
+};
+use windows_sys::Win32::{
+    Foundation::HANDLE,
+    System::EventLog::{
+        DeregisterEventSource, EVENTLOG_ERROR_TYPE, EVENTLOG_INFORMATION_TYPE,
+        EVENTLOG_WARNING_TYPE, RegisterEventSourceW, ReportEventW,
+    },
+};
+
+/// Event source prx writes under, in the Application log.
+pub const SOURCE: &str = "prx";
+
+/// Writes tracing events to the Windows Event Log, one entry per event, as
+/// `<target>: <message> <field>=<value>...`.
+pub struct EventLog {
+    source: HANDLE,
+}
+
+// The handle is only ever passed to ReportEventW, which any thread may call.
+unsafe impl Send for EventLog {}
+unsafe impl Sync for EventLog {}
+
+impl EventLog {
+    pub fn open() -> io::Result<Self> {
+        let name = wide(SOURCE);
+        // SAFETY: `name` is a NUL-terminated UTF-16 string that outlives the call.
+        let source = unsafe { RegisterEventSourceW(ptr::null(), name.as_ptr()) };
+        if source.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self { source })
+    }
+
+    /// Writes one entry; errors and warnings keep their level, everything
+    /// else is informational.
+    pub fn report(&self, level: Level, message: &str) {
+        let kind = match level {
+            Level::ERROR => EVENTLOG_ERROR_TYPE,
+            Level::WARN => EVENTLOG_WARNING_TYPE,
+            _ => EVENTLOG_INFORMATION_TYPE,
+        };
+        let message = wide(message);
+        let strings = [message.as_ptr()];
+        // SAFETY: `strings` holds one NUL-terminated UTF-16 string, and both
+        // outlive the call. A failed write has nowhere else to be reported.
+        unsafe {
+            ReportEventW(
+                self.source,
+                kind,
+                0,
+                0,
+                ptr::null_mut(),
+                1,
+                0,
+                strings.as_ptr(),
+                ptr::null(),
+            );
+        }
+    }
+}
+
+impl Drop for EventLog {
+    fn drop(&mut self) {
+        // SAFETY: the handle came from RegisterEventSourceW and is not used again.
+        unsafe {
+            DeregisterEventSource(self.source);
+        }
+    }
+}
+
+impl<S: Subscriber> Layer<S> for EventLog {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let mut line = Line(format!("{}:", event.metadata().target()));
+        event.record(&mut line);
+        self.report(*event.metadata().level(), &line.0);
+    }
+}
+
+/// Sends prx's logs to the Event Log, unless an embedding binary already
+/// installed its own subscriber.
+pub fn init(filter: EnvFilter) -> io::Result<()> {
+    let _ = tracing_subscriber::registry()
+        .with(EventLog::open()?.with_filter(filter))
+        .try_init();
+    Ok(())
+}
+
+struct Line(String);
+
+impl Visit for Line {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.record_debug(field, &format_args!("{value}"));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        use fmt::Write;
+        let _ = match field.name() {
+            "message" => write!(self.0, " {value:?}"),
+            name => write!(self.0, " {name}={value:?}"),
+        };
+    }
+}
+
+fn wide(value: &str) -> Vec<u16> {
+    value.encode_utf16().chain(iter::once(0)).collect()
+}
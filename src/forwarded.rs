@@ -0,0 +1,240 @@
+use std::net::IpAddr;
+
+use pingora::{http::RequestHeader, prelude::*};
+
+use crate::config::ForwardedHeadersPolicy;
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+const X_FORWARDED_PROTO: &str = "x-forwarded-proto";
+const X_FORWARDED_HOST: &str = "x-forwarded-host";
+const FORWARDED: &str = "forwarded";
+
+/// Carry the verified certificate of a TLS client to the upstream; only
+/// prx or a trusted proxy in front of it may set them.
+pub const CLIENT_CERT_SUBJECT_HEADER: &str = "x-client-cert-subject";
+pub const CLIENT_CERT_SAN_HEADER: &str = "x-client-cert-san";
+
+/// The hop prx reports to the upstream.
+#[derive(Debug, Clone, Copy)]
+pub struct ClientHop<'a> {
+    pub ip: Option<IpAddr>,
+    pub proto: &'a str,
+    pub host: Option<&'a str>,
+}
+
+impl ClientHop<'_> {
+    /// One RFC 7239 forwarded-element.
+    fn forwarded_element(&self) -> String {
+        let mut pairs = Vec::with_capacity(3);
+        match self.ip {
+            Some(IpAddr::V6(ip)) => pairs.push(format!("for=\"[{ip}]\"")),
+            Some(IpAddr::V4(ip)) => pairs.push(format!("for={ip}")),
+            None => pairs.push("for=unknown".to_string()),
+        }
+        pairs.push(format!("proto={}", self.proto));
+        if let Some(host) = self.host {
+            // A port makes the host a quoted-string rather than a token.
+            pairs.push(if host.contains(':') {
+                format!("host=\"{host}\"")
+            } else {
+                format!("host={host}")
+            });
+        }
+        pairs.join(";")
+    }
+}
+
+/// Rewrites the forwarding headers of `header` by `policy`. `trusted` says
+/// whether the immediate peer is one of `server.trusted_proxies`.
+pub fn apply(
+    policy: ForwardedHeadersPolicy,
+    trusted: bool,
+    hop: &ClientHop<'_>,
+    header: &mut RequestHeader,
+) -> Result<()> {
+    let keep = match policy {
+        ForwardedHeadersPolicy::Off => return Ok(()),
+        ForwardedHeadersPolicy::Append => trusted,
+        ForwardedHeadersPolicy::Replace => false,
+    };
+    let incoming = |header: &RequestHeader, name: &str| {
+        let values: Vec<&str> = header
+            .headers
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .collect();
+        (keep && !values.is_empty()).then(|| values.join(", "))
+    };
+
+    let forwarded_for = incoming(header, X_FORWARDED_FOR);
+    let forwarded = incoming(header, FORWARDED);
+    let proto = incoming(header, X_FORWARDED_PROTO);
+    let host = incoming(header, X_FORWARDED_HOST);
+    for name in [
+        X_FORWARDED_FOR,
+        X_FORWARDED_PROTO,
+        X_FORWARDED_HOST,
+        FORWARDED,
+    ] {
+        header.remove_header(name);
+    }
+
+    let ip = hop.ip.map(|ip| ip.to_string());
+    let forwarded_for = match (forwarded_for, ip) {
+        (Some(prior), Some(ip)) => Some(format!("{prior}, {ip}")),
+        (prior, ip) => prior.or(ip),
+    };
+    if let Some(value) = forwarded_for {
+        header.insert_header(X_FORWARDED_FOR, value)?;
+    }
+    header.insert_header(
+        X_FORWARDED_PROTO,
+        proto.unwrap_or_else(|| hop.proto.to_string()),
+    )?;
+    if let Some(value) = host.or_else(|| hop.host.map(str::to_string)) {
+        header.insert_header(X_FORWARDED_HOST, value)?;
+    }
+    let element = hop.forwarded_element();
+    header.insert_header(
+        FORWARDED,
+        match forwarded {
+            Some(prior) => format!("{prior}, {element}"),
+            None => element,
+        },
+    )?;
+    Ok(())
+}
+
+/// Drops the client certificate headers of `header` unless the immediate
+/// peer is one of `server.trusted_proxies`, whatever the listener.
+pub fn strip_client_cert(trusted: bool, header: &mut RequestHeader) {
+    if !trusted {
+        header.remove_header(CLIENT_CERT_SUBJECT_HEADER);
+        header.remove_header(CLIENT_CERT_SAN_HEADER);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(headers: &[(&str, &str)]) -> RequestHeader {
+        let mut header = RequestHeader::build("GET", b"/", None).expect("request");
+        for (name, value) in headers {
+            header
+                .append_header(name.to_string(), value.to_string())
+                .expect("header");
+        }
+        header
+    }
+
+    fn value(header: &RequestHeader, name: &str) -> Option<String> {
+        header
+            .headers
+            .get(name)
+            .map(|value| value.to_str().expect("ascii").to_string())
+    }
+
+    const HOP: ClientHop<'static> = ClientHop {
+        ip: Some(IpAddr::V4(std::net::Ipv4Addr::new(198, 51, 100, 7))),
+        proto: "https",
+        host: Some("shop.example:8443"),
+    };
+
+    #[test]
+    fn appends_to_trusted_values_and_replaces_untrusted_ones() {
+        let incoming = [
+            ("x-forwarded-for", "203.0.113.1"),
+            ("x-forwarded-for", "10.0.0.2"),
+            ("x-forwarded-proto", "http"),
+            ("forwarded", "for=203.0.113.1"),
+        ];
+
+        let mut trusted = request(&incoming);
+        apply(ForwardedHeadersPolicy::Append, true, &HOP, &mut trusted).expect("apply");
+        assert_eq!(
+            value(&trusted, "x-forwarded-for").as_deref(),
+            Some("203.0.113.1, 10.0.0.2, 198.51.100.7")
+        );
+        assert_eq!(
+            value(&trusted, "x-forwarded-proto").as_deref(),
+            Some("http")
+        );
+        assert_eq!(
+            value(&trusted, "x-forwarded-host").as_deref(),
+            Some("shop.example:8443")
+        );
+        assert_eq!(
+            value(&trusted, "forwarded").as_deref(),
+            Some("for=203.0.113.1, for=198.51.100.7;proto=https;host=\"shop.example:8443\"")
+        );
+
+        for (policy, trusted) in [
+            (ForwardedHeadersPolicy::Append, false),
+            (ForwardedHeadersPolicy::Replace, true),
+        ] {
+            let mut header = request(&incoming);
+            apply(policy, trusted, &HOP, &mut header).expect("apply");
+            assert_eq!(
+                value(&header, "x-forwarded-for").as_deref(),
+                Some("198.51.100.7")
+            );
+            assert_eq!(
+                value(&header, "x-forwarded-proto").as_deref(),
+                Some("https")
+            );
+        }
+
+        let mut untouched = request(&incoming);
+        apply(ForwardedHeadersPolicy::Off, false, &HOP, &mut untouched).expect("apply");
+        assert_eq!(
+            untouched.headers.get_all("x-forwarded-for").iter().count(),
+            2
+        );
+    }
+
+    #[test]
+    fn quotes_ipv6_clients_in_forwarded() {
+        let hop = ClientHop {
+            ip: Some("2001:db8::1".parse().expect("ip")),
+            proto: "http",
+            host: None,
+        };
+        let mut header = request(&[]);
+        apply(ForwardedHeadersPolicy::Replace, false, &hop, &mut header).expect("apply");
+        assert_eq!(
+            value(&header, "forwarded").as_deref(),
+            Some("for=\"[2001:db8::1]\";proto=http")
+        );
+        assert_eq!(
+            value(&header, "x-forwarded-for").as_deref(),
+            Some("2001:db8::1")
+        );
+        assert_eq!(value(&header, "x-forwarded-host"), None);
+    }
+
+    #[test]
+    fn keeps_client_cert_headers_only_from_trusted_proxies() {
+        let incoming = [
+            (CLIENT_CERT_SUBJECT_HEADER, "CN=admin"),
+            (CLIENT_CERT_SAN_HEADER, "DNS:admin"),
+        ];
+
+        let mut untrusted = request(&incoming);
+        strip_client_cert(false, &mut untrusted);
+        assert_eq!(value(&untrusted, CLIENT_CERT_SUBJECT_HEADER), None);
+        assert_eq!(value(&untrusted, CLIENT_CERT_SAN_HEADER), None);
+
+        let mut trusted = request(&incoming);
+        strip_client_cert(true, &mut trusted);
+        assert_eq!(
+            value(&trusted, CLIENT_CERT_SUBJECT_HEADER).as_deref(),
+            Some("CN=admin")
+        );
+        assert_eq!(
+            value(&trusted, CLIENT_CERT_SAN_HEADER).as_deref(),
+            Some("DNS:admin")
+        );
+    }
+}
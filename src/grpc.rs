@@ -0,0 +1,53 @@
+use http::HeaderMap;
+
+/// `UNKNOWN`, also used for `grpc-status` values outside the defined codes.
+const UNKNOWN: u8 = 2;
+
+/// Highest status code defined by gRPC (`UNAUTHENTICATED`).
+pub const MAX_STATUS: u8 = 16;
+
+/// The `grpc-status` in response trailers, or in the headers of a
+/// trailers-only response.
+pub fn status(headers: &HeaderMap) -> Option<u8> {
+    let value = headers.get("grpc-status")?;
+    Some(
+        value
+            .to_str()
+            .ok()
+            .and_then(|value| value.trim().parse::<u8>().ok())
+            .filter(|code| *code <= MAX_STATUS)
+            .unwrap_or(UNKNOWN),
+    )
+}
+
+/// The status a gRPC client reports for a response without `grpc-status`,
+/// following gRPC's HTTP to status code mapping.
+pub fn status_from_http(status: u16) -> u8 {
+    match status {
+        400 => 13,
+        401 => 16,
+        403 => 7,
+        404 => 12,
+        429 | 502 | 503 | 504 => 14,
+        _ => UNKNOWN,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_grpc_status_and_maps_plain_http_errors() {
+        let mut trailers = HeaderMap::new();
+        assert_eq!(status(&trailers), None);
+        trailers.insert("grpc-status", "5".parse().expect("value"));
+        assert_eq!(status(&trailers), Some(5));
+        trailers.insert("grpc-status", "42".parse().expect("value"));
+        assert_eq!(status(&trailers), Some(UNKNOWN));
+
+        assert_eq!(status_from_http(503), 14);
+        assert_eq!(status_from_http(404), 12);
+        assert_eq!(status_from_http(200), UNKNOWN);
+    }
+}
@@ -0,0 +1,263 @@
+use std::{
+    io::{self, Write},
+    net::{TcpStream, ToSocketAddrs},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+    },
+    thread,
+    time::Duration,
+};
+
+use anyhow::{Context, bail};
+use arc_swap::ArcSwap;
+use tracing::{info, warn};
+
+use crate::{
+    config::{HealthCheckConfig, HealthCheckKind},
+    healthcheck::http_status_after,
+    proxy_protocol,
+    runtime::{RuntimeConfig, UpstreamRuntime, now_epoch_ms},
+};
+
+const TICK: Duration = Duration::from_millis(100);
+
+/// Outcome of the active checks of one upstream. Upstreams start healthy so a
+/// fresh config serves traffic before the first probe has finished.
+#[derive(Debug, Default)]
+pub struct HealthState {
+    unhealthy: AtomicBool,
+    // Consecutive results that disagree with the current state.
+    streak: AtomicU32,
+    next_probe_ms: AtomicU64,
+    probing: AtomicBool,
+}
+
+impl HealthState {
+    pub fn is_healthy(&self) -> bool {
+        !self.unhealthy.load(Ordering::Relaxed)
+    }
+
+    /// Takes over a health state exported by another process; the next probe
+    /// is still due on the usual schedule.
+    pub fn restore(&self, healthy: bool) {
+        self.unhealthy.store(!healthy, Ordering::Relaxed);
+        self.streak.store(0, Ordering::Relaxed);
+    }
+
+    /// Marks the upstream healthy and due for an immediate probe.
+    pub fn reset(&self) {
+        self.unhealthy.store(false, Ordering::Relaxed);
+        self.streak.store(0, Ordering::Relaxed);
+        self.next_probe_ms.store(0, Ordering::Relaxed);
+    }
+
+    /// Claims the next probe if it is due at `now_ms` and none is running.
+    fn claim_probe(&self, now_ms: u64, interval_ms: u64) -> bool {
+        if self.next_probe_ms.load(Ordering::Relaxed) > now_ms
+            || self.probing.swap(true, Ordering::AcqRel)
+        {
+            return false;
+        }
+        self.next_probe_ms
+            .store(now_ms.saturating_add(interval_ms), Ordering::Relaxed);
+        true
+    }
+
+    /// Records a probe result and returns the new health when it flipped.
+    fn record(&self, passed: bool, config: &HealthCheckConfig) -> Option<bool> {
+        self.probing.store(false, Ordering::Release);
+        if passed == self.is_healthy() {
+            self.streak.store(0, Ordering::Relaxed);
+            return None;
+        }
+        let threshold = if passed {
+            config.healthy_threshold
+        } else {
+            config.unhealthy_threshold
+        };
+        if self.streak.fetch_add(1, Ordering::Relaxed) + 1 < threshold {
+            return None;
+        }
+        self.streak.store(0, Ordering::Relaxed);
+        self.unhealthy.store(!passed, Ordering::Relaxed);
+        Some(passed)
+    }
+}
+
+/// Probes every upstream that has a `health_check`, following the active
+/// config across reloads.
+pub fn spawn_health_checker(active_config: Arc<ArcSwap<RuntimeConfig>>) -> io::Result<()> {
+    thread::Builder::new()
+        .name("prx-health".to_string())
+        .spawn(move || {
+            loop {
+                start_due_probes(&active_config.load());
+                thread::sleep(TICK);
+            }
+        })
+        .map(|_| ())
+}
+
+fn start_due_probes(snapshot: &RuntimeConfig) {
+    let now_ms = now_epoch_ms();
+    for service in snapshot.services() {
+        for upstream in &service.upstreams {
+            let Some(check) = &upstream.health_check else {
+                continue;
+            };
+            if !upstream.health().claim_probe(now_ms, check.interval_ms) {
+                continue;
+            }
+            let service = service.name.clone();
+            let upstream = upstream.clone();
+            // Probes block for up to their timeout, so a slow upstream must
+            // not hold back the others.
+            let spawned = thread::Builder::new()
+                .name("prx-health-probe".to_string())
+                .spawn(move || run_probe(&service, &upstream));
+            if let Err(err) = spawned {
+                warn!(error = %err, "failed to start health probe");
+            }
+        }
+    }
+}
+
+fn run_probe(service: &str, upstream: &UpstreamRuntime) {
+    let Some(check) = &upstream.health_check else {
+        return;
+    };
+    // Probes speak for prx itself, so they announce no client.
+    let preamble = upstream
+        .proxy_protocol
+        .map(|version| proxy_protocol::encode(version, None))
+        .unwrap_or_default();
+    let result = probe(&upstream.addr, check, &preamble);
+    match (upstream.health().record(result.is_ok(), check), result) {
+        (Some(false), Err(err)) => warn!(
+            service,
+            upstream = &*upstream.addr,
+            error = %format!("{err:#}"),
+            "upstream failed health check; marking unhealthy"
+        ),
+        (Some(true), _) => {
+            upstream.begin_slow_start();
+            info!(
+                service,
+                upstream = &*upstream.addr,
+                "upstream passed health check; marking healthy"
+            )
+        }
+        _ => {}
+    }
+}
+
+fn probe(addr: &str, check: &HealthCheckConfig, preamble: &[u8]) -> anyhow::Result<()> {
+    let timeout = Duration::from_millis(check.timeout_ms);
+    match check.kind {
+        HealthCheckKind::Tcp => {
+            let target = addr
+                .to_socket_addrs()
+                .with_context(|| format!("failed to resolve {addr}"))?
+                .next()
+                .with_context(|| format!("{addr} resolved to no addresses"))?;
+            let mut stream = TcpStream::connect_timeout(&target, timeout)
+                .with_context(|| format!("failed to connect to {addr}"))?;
+            if !preamble.is_empty() {
+                stream.set_write_timeout(Some(timeout))?;
+                stream
+                    .write_all(preamble)
+                    .with_context(|| format!("failed to send PROXY header to {addr}"))?;
+            }
+        }
+        HealthCheckKind::Http => {
+            let status = http_status_after(preamble, addr, &check.path, timeout)?;
+            if status != check.expected_status {
+                bail!(
+                    "GET {} returned {status}, expected {}",
+                    check.path,
+                    check.expected_status
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    fn check(kind: HealthCheckKind) -> HealthCheckConfig {
+        HealthCheckConfig {
+            kind,
+            interval_ms: 1_000,
+            timeout_ms: 500,
+            path: "/status".to_string(),
+            expected_status: 204,
+            unhealthy_threshold: 2,
+            healthy_threshold: 1,
+        }
+    }
+
+    #[test]
+    fn flips_only_after_threshold_consecutive_results() {
+        let config = check(HealthCheckKind::Tcp);
+        let state = HealthState::default();
+        assert!(state.is_healthy());
+
+        assert_eq!(state.record(false, &config), None);
+        assert_eq!(state.record(true, &config), None);
+        assert_eq!(state.record(false, &config), None);
+        assert_eq!(state.record(false, &config), Some(false));
+        assert!(!state.is_healthy());
+        assert_eq!(state.record(true, &config), Some(true));
+        assert!(state.is_healthy());
+    }
+
+    #[test]
+    fn claims_one_probe_per_interval() {
+        let state = HealthState::default();
+        assert!(state.claim_probe(10_000, 1_000));
+        // Still running.
+        assert!(!state.claim_probe(20_000, 1_000));
+        state.record(true, &check(HealthCheckKind::Tcp));
+        assert!(!state.claim_probe(10_500, 1_000));
+        assert!(state.claim_probe(11_000, 1_000));
+    }
+
+    #[test]
+    fn tcp_and_http_probes() {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+        let addr = listener.local_addr().expect("addr").to_string();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.expect("accept");
+                let mut buf = [0; 1024];
+                let _ = stream.read(&mut buf);
+                let _ = write!(stream, "HTTP/1.1 204 No Content\r\n\r\n");
+            }
+        });
+
+        assert!(probe(&addr, &check(HealthCheckKind::Tcp), &[]).is_ok());
+        assert!(probe(&addr, &check(HealthCheckKind::Http), &[]).is_ok());
+        let wrong_status = HealthCheckConfig {
+            expected_status: 200,
+            ..check(HealthCheckKind::Http)
+        };
+        let err = probe(&addr, &wrong_status, &[]).expect_err("204 is not 200");
+        assert!(err.to_string().contains("returned 204"), "{err}");
+
+        let closed = TcpListener::bind("127.0.0.1:0")
+            .expect("bind")
+            .local_addr()
+            .expect("addr")
+            .to_string();
+        assert!(probe(&closed, &check(HealthCheckKind::Tcp), &[]).is_err());
+    }
+}
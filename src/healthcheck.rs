@@ -0,0 +1,341 @@
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::{SocketAddr, TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+
+use anyhow::{Context, bail};
+
+use crate::{
+    config::{ProxyProtocolVersion, PrxConfig},
+    proxy_protocol,
+};
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// `prx healthcheck [--ready] [--url URL]`: probes a running prx over plain
+/// HTTP so container images need no curl.
+///
+/// Without `--url` the probe targets the health path, or the ready path with
+/// `--ready`, on the first listener of the config that answers it: the first
+/// `server.probes.listen` address, the admin listener with
+/// `server.probes.admin`, then the first `server.listen` address unless
+/// `server.probes.public` is off. Wildcard addresses are probed on loopback.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Healthcheck {
+    url: Option<String>,
+    ready: bool,
+}
+
+impl Healthcheck {
+    /// Returns `None` when the first argument is not `healthcheck`.
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> anyhow::Result<Option<Self>> {
+        let mut args = args.into_iter();
+        if args.next().as_deref() != Some("healthcheck") {
+            return Ok(None);
+        }
+
+        let mut check = Self::default();
+        while let Some(flag) = args.next() {
+            match flag.as_str() {
+                "--ready" => check.ready = true,
+                "--url" => check.url = Some(args.next().context("--url requires a value")?),
+                _ => bail!("unknown healthcheck argument {flag}"),
+            }
+        }
+        Ok(Some(check))
+    }
+
+    /// `admin_listen` is the admin listener's address (`PRX_ADMIN_LISTEN`).
+    pub fn target(
+        &self,
+        admin_listen: &str,
+        config: impl FnOnce() -> anyhow::Result<PrxConfig>,
+    ) -> anyhow::Result<Target> {
+        if let Some(url) = &self.url {
+            return Ok(Target {
+                url: url.clone(),
+                proxy_protocol: None,
+            });
+        }
+        let config = config().context("healthcheck needs a loadable config or --url")?;
+        let probes = &config.server.probes;
+        let (field, listen, proxy_protocol) = if let Some(listen) = probes.listen.first() {
+            ("server.probes.listen", listen.as_str(), None)
+        } else if probes.admin {
+            ("PRX_ADMIN_LISTEN", admin_listen, None)
+        } else if probes.public {
+            let listen = config
+                .server
+                .listen
+                .first()
+                .context("server.listen is empty; pass --url")?;
+            // The listener drops connections that open without a PROXY header.
+            (
+                "server.listen",
+                listen.as_str(),
+                config.server.proxy_protocol,
+            )
+        } else {
+            bail!("server.probes serves probes on no listener; pass --url");
+        };
+        let mut addr = listen
+            .parse::<SocketAddr>()
+            .with_context(|| format!("invalid {field} address {listen}"))?;
+        if addr.ip().is_unspecified() {
+            addr.set_ip(match addr {
+                SocketAddr::V4(_) => [127, 0, 0, 1].into(),
+                SocketAddr::V6(_) => std::net::Ipv6Addr::LOCALHOST.into(),
+            });
+        }
+        let path = if self.ready {
+            &config.server.ready_path
+        } else {
+            &config.server.health_path
+        };
+        Ok(Target {
+            url: format!("http://{addr}{path}"),
+            proxy_protocol,
+        })
+    }
+}
+
+/// Where [`probe`] sends its request.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Target {
+    pub url: String,
+    /// Sends a `LOCAL` (v2) or `UNKNOWN` (v1) PROXY header first, as a
+    /// balancer's own probes do.
+    pub proxy_protocol: Option<ProxyProtocolVersion>,
+}
+
+/// GETs the URL of `target` and returns its status line detail when the
+/// status is 2xx.
+pub fn probe(target: &Target) -> anyhow::Result<String> {
+    let url = &target.url;
+    let Some(rest) = url.strip_prefix("http://") else {
+        bail!("healthcheck only supports http:// URLs, got {url}");
+    };
+    let (authority, path) = match rest.find('/') {
+        Some(idx) => rest.split_at(idx),
+        None => (rest, "/"),
+    };
+    let preamble = target
+        .proxy_protocol
+        .map(|version| proxy_protocol::encode(version, None))
+        .unwrap_or_default();
+    let status = http_status_after(&preamble, authority, path, PROBE_TIMEOUT)?;
+    if !(200..300).contains(&status) {
+        bail!("{url} returned {status}");
+    }
+    Ok(format!("{url} returned {status}"))
+}
+
+/// Sends `GET path` to `authority` over plain HTTP/1.1 and returns the
+/// response status; connect, read and write each get `timeout`.
+pub(crate) fn http_status(authority: &str, path: &str, timeout: Duration) -> anyhow::Result<u16> {
+    http_status_after(&[], authority, path, timeout)
+}
+
+/// [`http_status`] with `preamble` (a PROXY protocol header) sent first.
+pub(crate) fn http_status_after(
+    preamble: &[u8],
+    authority: &str,
+    path: &str,
+    timeout: Duration,
+) -> anyhow::Result<u16> {
+    request_status("GET", "prx-healthcheck", preamble, authority, path, timeout)
+}
+
+/// Sends a bodiless `method path` request after `preamble` and returns the
+/// response status.
+pub(crate) fn request_status(
+    method: &str,
+    user_agent: &str,
+    preamble: &[u8],
+    authority: &str,
+    path: &str,
+    timeout: Duration,
+) -> anyhow::Result<u16> {
+    let addr = authority
+        .to_socket_addrs()
+        .with_context(|| format!("failed to resolve {authority}"))?
+        .next()
+        .with_context(|| format!("{authority} resolved to no addresses"))?;
+
+    let mut stream = TcpStream::connect_timeout(&addr, timeout)
+        .with_context(|| format!("failed to connect to {authority}"))?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    // One write, so a server that answers after its first read sees the
+    // whole request.
+    let mut request = preamble.to_vec();
+    request.extend_from_slice(
+        format!("{method} {path} HTTP/1.1\r\nHost: {authority}\r\nUser-Agent: {user_agent}\r\n")
+            .as_bytes(),
+    );
+    if method != "GET" {
+        request.extend_from_slice(b"Content-Length: 0\r\n");
+    }
+    request.extend_from_slice(b"Connection: close\r\n\r\n");
+    stream
+        .write_all(&request)
+        .with_context(|| format!("failed to send request to {authority}"))?;
+
+    let mut status_line = String::new();
+    BufReader::new(stream)
+        .read_line(&mut status_line)
+        .with_context(|| format!("failed to read response from {authority}"))?;
+    status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse::<u16>().ok())
+        .with_context(|| format!("malformed response from {authority}: {status_line:?}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Read, net::TcpListener, sync::mpsc, thread};
+
+    use super::*;
+
+    /// Answers one request with `status` and hands over what it read.
+    fn serve_once(status: &'static str) -> (String, mpsc::Receiver<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+        let addr = listener.local_addr().expect("addr");
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().expect("accept");
+            let mut buf = [0; 1024];
+            let read = stream.read(&mut buf).unwrap_or_default();
+            let _ = tx.send(buf[..read].to_vec());
+            let _ = write!(stream, "HTTP/1.1 {status}\r\nContent-Length: 0\r\n\r\n");
+        });
+        (format!("http://{addr}/healthz"), rx)
+    }
+
+    fn plain(url: &str) -> Target {
+        Target {
+            url: url.to_string(),
+            proxy_protocol: None,
+        }
+    }
+
+    fn config(toml: &'static str) -> impl Fn() -> anyhow::Result<PrxConfig> {
+        move || {
+            PrxConfig::from_toml_str(&format!(
+                r#"{toml}
+
+[[service]]
+name = "app"
+
+[[service.upstream]]
+addr = "127.0.0.1:9000"
+
+[[route]]
+name = "default"
+service = "app"
+path_prefix = "/"
+is_default = true
+"#
+            ))
+        }
+    }
+
+    fn healthcheck(args: &[&str]) -> Healthcheck {
+        Healthcheck::from_args(
+            std::iter::once("healthcheck")
+                .chain(args.iter().copied())
+                .map(str::to_string),
+        )
+        .expect("parse")
+        .expect("healthcheck")
+    }
+
+    #[test]
+    fn probe_passes_on_2xx_and_fails_otherwise() {
+        assert!(probe(&plain(&serve_once("200 OK").0)).is_ok());
+        let err = probe(&plain(&serve_once("503 Service Unavailable").0)).expect_err("503");
+        assert!(err.to_string().ends_with("returned 503"), "{err}");
+        assert!(probe(&plain("https://127.0.0.1/healthz")).is_err());
+    }
+
+    #[test]
+    fn default_url_probes_first_listener_on_loopback() {
+        let config = config(
+            r#"[server]
+listen = ["0.0.0.0:8080"]
+ready_path = "/ready""#,
+        );
+        assert_eq!(
+            healthcheck(&[])
+                .target("127.0.0.1:9090", &config)
+                .expect("url"),
+            plain("http://127.0.0.1:8080/healthz")
+        );
+        assert_eq!(
+            healthcheck(&["--ready"])
+                .target("127.0.0.1:9090", &config)
+                .expect("url"),
+            plain("http://127.0.0.1:8080/ready")
+        );
+        assert_eq!(
+            Healthcheck::from_args(["--check".to_string()]).expect("parse"),
+            None
+        );
+    }
+
+    #[test]
+    fn default_url_follows_the_listeners_serving_probes() {
+        let dedicated = config(
+            r#"[server]
+listen = ["0.0.0.0:8080"]
+
+[server.probes]
+listen = ["0.0.0.0:8081"]
+admin = true
+public = false"#,
+        );
+        assert_eq!(
+            healthcheck(&[])
+                .target("127.0.0.1:9090", dedicated)
+                .expect("url"),
+            plain("http://127.0.0.1:8081/healthz")
+        );
+
+        let admin = config(
+            r#"[server]
+listen = ["0.0.0.0:8080"]
+
+[server.probes]
+admin = true
+public = false"#,
+        );
+        assert_eq!(
+            healthcheck(&[])
+                .target("127.0.0.1:9090", admin)
+                .expect("url"),
+            plain("http://127.0.0.1:9090/healthz")
+        );
+    }
+
+    #[test]
+    fn default_url_sends_a_local_proxy_header_to_proxy_protocol_listeners() {
+        let config = config(
+            r#"[server]
+listen = ["0.0.0.0:8080"]
+proxy_protocol = "v2""#,
+        );
+        let target = healthcheck(&[])
+            .target("127.0.0.1:9090", config)
+            .expect("url");
+        assert_eq!(target.proxy_protocol, Some(ProxyProtocolVersion::V2));
+
+        let (url, request) = serve_once("200 OK");
+        probe(&Target { url, ..target }).expect("probe");
+        let request = request.recv().expect("request");
+        let local = proxy_protocol::encode(ProxyProtocolVersion::V2, None);
+        assert!(request.starts_with(&local), "{request:?}");
+        assert!(request[local.len()..].starts_with(b"GET /healthz HTTP/1.1\r\n"));
+    }
+}
@@ -0,0 +1,145 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use anyhow::Context;
+use serde::Serialize;
+
+use crate::{admin::ConfigAdmin, config::PrxConfig};
+
+/// Versions kept when `admin.config_history` is not set.
+pub const DEFAULT_KEPT: usize = 10;
+
+/// A config the admin API replaced, saved next to it as `Prx.toml.v{n}`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ConfigVersion {
+    pub version: u64,
+    pub saved_at_epoch_ms: u64,
+    pub bytes: u64,
+}
+
+/// How many versions `config` asks to keep.
+pub fn kept(config: &PrxConfig) -> usize {
+    config
+        .admin
+        .as_ref()
+        .and_then(|admin| admin.config_history)
+        .unwrap_or(DEFAULT_KEPT)
+}
+
+/// Saves `content`, the config at `config_path` before a change, as the next
+/// version and drops all but the newest `keep`. Answers the new version, or
+/// `None` when none are kept.
+pub fn save(config_path: &Path, content: &[u8], keep: usize) -> anyhow::Result<Option<u64>> {
+    if keep == 0 {
+        return Ok(None);
+    }
+    let mut versions = numbers(config_path)?;
+    let version = versions.last().map_or(1, |last| last + 1);
+    ConfigAdmin::atomic_replace(&path_of(config_path, version), content)?;
+    versions.push(version);
+    for old in &versions[..versions.len().saturating_sub(keep)] {
+        let path = path_of(config_path, *old);
+        fs::remove_file(&path)
+            .with_context(|| format!("failed to remove config version {}", path.display()))?;
+    }
+    Ok(Some(version))
+}
+
+/// Saved versions, newest first.
+pub fn list(config_path: &Path) -> anyhow::Result<Vec<ConfigVersion>> {
+    let mut versions = Vec::new();
+    for version in numbers(config_path)?.into_iter().rev() {
+        let path = path_of(config_path, version);
+        let metadata = fs::metadata(&path)
+            .with_context(|| format!("failed to read config version {}", path.display()))?;
+        let saved_at_epoch_ms = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |since| since.as_millis() as u64);
+        versions.push(ConfigVersion {
+            version,
+            saved_at_epoch_ms,
+            bytes: metadata.len(),
+        });
+    }
+    Ok(versions)
+}
+
+/// The text of a saved version, `None` when it is not kept.
+pub fn read(config_path: &Path, version: u64) -> anyhow::Result<Option<String>> {
+    let path = path_of(config_path, version);
+    match fs::read_to_string(&path) {
+        Ok(content) => Ok(Some(content)),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => {
+            Err(err).with_context(|| format!("failed to read config version {}", path.display()))
+        }
+    }
+}
+
+fn file_name(config_path: &Path) -> String {
+    config_path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or("Prx.toml")
+        .to_string()
+}
+
+fn path_of(config_path: &Path, version: u64) -> PathBuf {
+    config_path.with_file_name(format!("{}.v{version}", file_name(config_path)))
+}
+
+/// Version numbers on disk, in ascending order.
+fn numbers(config_path: &Path) -> anyhow::Result<Vec<u64>> {
+    let dir = config_path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let prefix = format!("{}.v", file_name(config_path));
+    let entries = fs::read_dir(dir)
+        .with_context(|| format!("failed to list config versions in {}", dir.display()))?;
+    let mut versions: Vec<u64> = entries
+        .filter_map(Result::ok)
+        .filter_map(|entry| {
+            let name = entry.file_name();
+            let number = name.to_str()?.strip_prefix(&prefix)?;
+            // `v01` would name the same version as `v1`.
+            if number.starts_with('0') {
+                return None;
+            }
+            number.parse().ok()
+        })
+        .collect();
+    versions.sort_unstable();
+    Ok(versions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_newest_versions() {
+        let dir = tempfile::tempdir().expect("tempdir");
+        let config = dir.path().join("Prx.toml");
+        assert!(list(&config).expect("list").is_empty());
+        for content in ["one", "two", "three"] {
+            save(&config, content.as_bytes(), 2).expect("save");
+        }
+        fs::write(dir.path().join("Prx.toml.v02"), "junk").expect("write");
+
+        let versions: Vec<_> = list(&config)
+            .expect("list")
+            .iter()
+            .map(|version| (version.version, version.bytes))
+            .collect();
+        assert_eq!(versions, [(3, 5), (2, 3)]);
+        assert_eq!(read(&config, 2).expect("read").as_deref(), Some("two"));
+        assert_eq!(read(&config, 1).expect("read"), None);
+        assert_eq!(save(&config, b"four", 0).expect("save"), None);
+    }
+}
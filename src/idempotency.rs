@@ -0,0 +1,442 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use blake2::{Blake2s256, Digest};
+use bytes::{Bytes, BytesMut};
+use http::{HeaderName, header};
+use pingora::http::{RequestHeader, ResponseHeader};
+use tokio::sync::watch;
+
+use crate::{
+    config::{IdempotencyConfig, IdempotencyInFlight},
+    purge::Purge,
+    ratelimit::RateLimitKey,
+};
+
+/// Largest request body held to compare it with the first request of its
+/// key: what Pingora keeps to send upstream after it was read.
+pub const MAX_REQUEST_BODY_BYTES: usize = 64 * 1024;
+
+/// Digest of a request body; a key reused with another body is refused.
+pub type BodyDigest = [u8; 32];
+
+pub fn body_digest(body: &[u8]) -> BodyDigest {
+    Blake2s256::digest(body).into()
+}
+
+/// Requests of one route that carry an idempotency key, in flight or recently
+/// answered. Kept across reloads that leave the route unchanged.
+#[derive(Debug)]
+pub struct IdempotencyCache {
+    header: HeaderName,
+    identity: RateLimitKey,
+    in_flight: IdempotencyInFlight,
+    replay_ttl: Duration,
+    max_entries: usize,
+    max_body_bytes: usize,
+    entries: Mutex<HashMap<String, Entry>>,
+}
+
+#[derive(Debug)]
+enum Entry {
+    // Closed when the owning request finishes, one way or another.
+    InFlight(watch::Receiver<()>, BodyDigest),
+    Done(Arc<Replay>),
+}
+
+/// A stored answer, replayed to duplicates until it expires.
+#[derive(Debug)]
+pub struct Replay {
+    pub response: ResponseHeader,
+    pub body: Bytes,
+    digest: BodyDigest,
+    expires_at: Instant,
+}
+
+pub enum Begin {
+    /// The caller owns the key until the guard is dropped.
+    Owner(IdempotencyGuard),
+    /// Another request with the key is in flight; resolves once it finishes.
+    InFlight(watch::Receiver<()>),
+    Replay(Arc<Replay>),
+    /// The key was first sent with another body.
+    Mismatch,
+    /// The cache is full; the request goes through undeduplicated.
+    Untracked,
+}
+
+impl IdempotencyCache {
+    /// The header name and identity are checked by `PrxConfig::validate`.
+    pub fn from_config(config: &IdempotencyConfig) -> Self {
+        Self {
+            header: HeaderName::from_bytes(config.header.as_bytes())
+                .unwrap_or(HeaderName::from_static("idempotency-key")),
+            identity: config
+                .identity
+                .as_deref()
+                .and_then(|identity| identity.parse().ok())
+                .unwrap_or(RateLimitKey::ClientIp),
+            in_flight: config.in_flight,
+            replay_ttl: Duration::from_millis(config.replay_ttl_ms),
+            max_entries: config.max_entries,
+            max_body_bytes: config.max_body_bytes,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Cache key of a request; `None` without an idempotency key or client
+    /// identity. The same key on another method or path, or from another
+    /// client, is a different request.
+    pub fn key(&self, request: &RequestHeader, client_ip: Option<IpAddr>) -> Option<String> {
+        let key = request
+            .headers
+            .get(&self.header)
+            .and_then(|value| value.to_str().ok())
+            .filter(|value| !value.is_empty())?;
+        let identity = self.identity.extract(client_ip, &request.headers);
+        if identity.is_empty() {
+            return None;
+        }
+        Some(format!(
+            "{} {}\n{identity}\n{key}",
+            request.method,
+            request.uri.path()
+        ))
+    }
+
+    pub fn in_flight(&self) -> IdempotencyInFlight {
+        self.in_flight
+    }
+
+    pub fn begin(self: &Arc<Self>, key: String, digest: BodyDigest) -> Begin {
+        self.begin_at(key, digest, Instant::now())
+    }
+
+    pub fn reset(&self) {
+        self.entries().clear();
+    }
+
+    /// Drops the stored answers `purge` matches and returns how many there
+    /// were. Requests still in flight keep their key.
+    pub fn purge(&self, purge: &Purge) -> usize {
+        let mut entries = self.entries();
+        let before = entries.len();
+        entries.retain(|key, entry| match entry {
+            Entry::InFlight(..) => true,
+            Entry::Done(replay) => !purge.matches(key_path(key), &replay.response),
+        });
+        before - entries.len()
+    }
+
+    fn begin_at(self: &Arc<Self>, key: String, digest: BodyDigest, now: Instant) -> Begin {
+        let mut entries = self.entries();
+        match entries.get(&key) {
+            Some(Entry::InFlight(done, first)) if done.has_changed().is_ok() => {
+                if *first != digest {
+                    return Begin::Mismatch;
+                }
+                return Begin::InFlight(done.clone());
+            }
+            Some(Entry::Done(replay)) if replay.expires_at > now => {
+                if replay.digest != digest {
+                    return Begin::Mismatch;
+                }
+                return Begin::Replay(replay.clone());
+            }
+            _ => {}
+        }
+        if entries.len() >= self.max_entries && !entries.contains_key(&key) {
+            entries.retain(|_, entry| match entry {
+                Entry::InFlight(done, _) => done.has_changed().is_ok(),
+                Entry::Done(replay) => replay.expires_at > now,
+            });
+            if entries.len() >= self.max_entries {
+                return Begin::Untracked;
+            }
+        }
+        let (sender, receiver) = watch::channel(());
+        entries.insert(key.clone(), Entry::InFlight(receiver, digest));
+        Begin::Owner(IdempotencyGuard {
+            cache: self.clone(),
+            key,
+            digest,
+            done: sender,
+            response: None,
+            body: BytesMut::new(),
+        })
+    }
+
+    fn finish(
+        &self,
+        key: &str,
+        digest: BodyDigest,
+        replay: Option<(ResponseHeader, Bytes)>,
+        now: Instant,
+    ) {
+        let mut entries = self.entries();
+        match replay.filter(|_| !self.replay_ttl.is_zero()) {
+            Some((response, body)) => {
+                entries.insert(
+                    key.to_string(),
+                    Entry::Done(Arc::new(Replay {
+                        response,
+                        body,
+                        digest,
+                        expires_at: now + self.replay_ttl,
+                    })),
+                );
+            }
+            None => {
+                entries.remove(key);
+            }
+        }
+    }
+
+    fn entries(&self) -> MutexGuard<'_, HashMap<String, Entry>> {
+        self.entries
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// The path of a key built by [`IdempotencyCache::key`]; the path cannot
+/// hold the newline that ends it.
+fn key_path(key: &str) -> &str {
+    key.split_once('\n')
+        .and_then(|(request, _)| request.split_once(' '))
+        .map_or("", |(_, path)| path)
+}
+
+/// Ownership of an idempotency key for the request holding it. Dropping it
+/// without [`IdempotencyGuard::complete`] releases the key so a retry of the
+/// request can go through.
+pub struct IdempotencyGuard {
+    cache: Arc<IdempotencyCache>,
+    key: String,
+    digest: BodyDigest,
+    done: watch::Sender<()>,
+    // Unset until the upstream answered, and again once the answer turned
+    // out not to be replayable.
+    response: Option<Box<ResponseHeader>>,
+    body: BytesMut,
+}
+
+impl IdempotencyGuard {
+    /// Server errors are not stored, so a duplicate retries the upstream,
+    /// and neither are answers setting cookies, which belong to one session.
+    pub fn set_response(&mut self, response: &ResponseHeader) {
+        if self.cache.replay_ttl.is_zero()
+            || response.status.is_server_error()
+            || response.headers.contains_key(header::SET_COOKIE)
+        {
+            return;
+        }
+        let mut response = response.clone();
+        // Replays carry the whole body with a known length.
+        response.remove_header(&header::TRANSFER_ENCODING);
+        self.response = Some(Box::new(response));
+    }
+
+    pub fn push_body(&mut self, chunk: &[u8]) {
+        if self.response.is_none() {
+            return;
+        }
+        if self.body.len() + chunk.len() > self.cache.max_body_bytes {
+            self.response = None;
+            self.body = BytesMut::new();
+            return;
+        }
+        self.body.extend_from_slice(chunk);
+    }
+
+    /// The response was fully sent; stores it for replay when it qualifies.
+    pub fn complete(mut self) {
+        let body = std::mem::take(&mut self.body).freeze();
+        let replay = self.response.take().map(|mut response| {
+            let _ = response.insert_header(header::CONTENT_LENGTH, body.len());
+            (*response, body)
+        });
+        self.cache
+            .finish(&self.key, self.digest, replay, Instant::now());
+    }
+}
+
+impl Drop for IdempotencyGuard {
+    fn drop(&mut self) {
+        // After `complete` the entry already holds the replay, if any; after
+        // a reset it may belong to another request.
+        let mut entries = self.cache.entries();
+        if let Some(Entry::InFlight(done, _)) = entries.get(&self.key)
+            && done.same_channel(&self.done.subscribe())
+        {
+            entries.remove(&self.key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache(replay_ttl_ms: u64) -> Arc<IdempotencyCache> {
+        Arc::new(IdempotencyCache::from_config(&IdempotencyConfig {
+            replay_ttl_ms,
+            max_body_bytes: 8,
+            ..IdempotencyConfig::default()
+        }))
+    }
+
+    fn owner(begin: Begin) -> IdempotencyGuard {
+        match begin {
+            Begin::Owner(guard) => guard,
+            _ => panic!("expected to own the key"),
+        }
+    }
+
+    fn answer(status: u16) -> ResponseHeader {
+        ResponseHeader::build(status, None).expect("response")
+    }
+
+    const BODY: BodyDigest = [0; 32];
+
+    #[test]
+    fn holds_duplicates_while_in_flight_and_replays_the_answer() {
+        let cache = cache(60_000);
+        let now = Instant::now();
+        let mut guard = owner(cache.begin_at("k".to_string(), BODY, now));
+        let Begin::InFlight(done) = cache.begin_at("k".to_string(), BODY, now) else {
+            panic!("expected the duplicate to wait");
+        };
+
+        guard.set_response(&answer(201));
+        guard.push_body(b"created");
+        guard.complete();
+        assert!(done.has_changed().is_err(), "waiters see the owner finish");
+
+        let Begin::Replay(replay) = cache.begin_at("k".to_string(), BODY, now) else {
+            panic!("expected a replay");
+        };
+        assert_eq!(replay.response.status.as_u16(), 201);
+        assert_eq!(&replay.body[..], b"created");
+        assert_eq!(
+            replay
+                .response
+                .headers
+                .get("content-length")
+                .map(|v| v.as_bytes()),
+            Some(&b"7"[..])
+        );
+        assert!(matches!(
+            cache.begin_at("k".to_string(), BODY, now + Duration::from_secs(61)),
+            Begin::Owner(_)
+        ));
+    }
+
+    #[test]
+    fn releases_keys_of_failed_or_unreplayable_requests() {
+        let cache = cache(60_000);
+        let now = Instant::now();
+        // Dropped before the upstream answered, e.g. a connect failure.
+        drop(owner(cache.begin_at("k".to_string(), BODY, now)));
+        let mut guard = owner(cache.begin_at("k".to_string(), BODY, now));
+
+        guard.set_response(&answer(503));
+        guard.complete();
+        let mut guard = owner(cache.begin_at("k".to_string(), BODY, now));
+
+        guard.set_response(&answer(200));
+        guard.push_body(b"far too long");
+        guard.complete();
+        assert!(matches!(
+            cache.begin_at("k".to_string(), BODY, now),
+            Begin::Owner(_)
+        ));
+    }
+
+    #[test]
+    fn keys_are_scoped_to_the_client_identity() {
+        let cache = cache(60_000);
+        let mut request = RequestHeader::build("POST", b"/orders", None).expect("request");
+        request
+            .insert_header("idempotency-key", "k")
+            .expect("header");
+        let alice = cache.key(&request, Some("192.0.2.1".parse().unwrap()));
+        let bob = cache.key(&request, Some("192.0.2.2".parse().unwrap()));
+        assert_ne!(alice, bob);
+        assert_eq!(cache.key(&request, None), None);
+
+        let now = Instant::now();
+        let mut guard = owner(cache.begin_at(alice.clone().unwrap(), BODY, now));
+        guard.set_response(&answer(201));
+        guard.complete();
+        assert!(matches!(
+            cache.begin_at(alice.unwrap(), BODY, now),
+            Begin::Replay(_)
+        ));
+        assert!(matches!(
+            cache.begin_at(bob.unwrap(), BODY, now),
+            Begin::Owner(_)
+        ));
+
+        let cache = Arc::new(IdempotencyCache::from_config(&IdempotencyConfig {
+            identity: Some("header:x-user".to_string()),
+            ..IdempotencyConfig::default()
+        }));
+        assert_eq!(
+            cache.key(&request, Some("192.0.2.1".parse().unwrap())),
+            None
+        );
+        request.insert_header("x-user", "alice").expect("header");
+        assert_eq!(
+            cache.key(&request, None).as_deref(),
+            Some("POST /orders\nalice\nk")
+        );
+        assert_eq!(key_path("POST /orders\nalice\nk"), "/orders");
+    }
+
+    #[test]
+    fn refuses_keys_reused_with_another_body() {
+        let cache = cache(60_000);
+        let now = Instant::now();
+        let first = body_digest(b"{\"amount\":10}");
+        let other = body_digest(b"{\"amount\":99}");
+        let mut guard = owner(cache.begin_at("k".to_string(), first, now));
+        assert!(matches!(
+            cache.begin_at("k".to_string(), other, now),
+            Begin::Mismatch
+        ));
+
+        guard.set_response(&answer(201));
+        guard.complete();
+        assert!(matches!(
+            cache.begin_at("k".to_string(), other, now),
+            Begin::Mismatch
+        ));
+        assert!(matches!(
+            cache.begin_at("k".to_string(), first, now),
+            Begin::Replay(_)
+        ));
+    }
+
+    #[test]
+    fn does_not_store_answers_setting_cookies() {
+        let cache = cache(60_000);
+        let now = Instant::now();
+        let mut guard = owner(cache.begin_at("k".to_string(), BODY, now));
+        let mut response = answer(200);
+        response
+            .insert_header("set-cookie", "session=abc")
+            .expect("header");
+        guard.set_response(&response);
+        guard.push_body(b"hello");
+        guard.complete();
+        assert!(matches!(
+            cache.begin_at("k".to_string(), BODY, now),
+            Begin::Owner(_)
+        ));
+    }
+}
@@ -0,0 +1,232 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, bail};
+use serde::Deserialize;
+
+use crate::config::{RouteConfig, ServiceConfig};
+
+/// Services and routes merged in from one file an `include` pattern matched.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IncludedFile {
+    pub path: PathBuf,
+    pub services: Vec<ServiceConfig>,
+    pub routes: Vec<RouteConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct IncludedConfig {
+    #[serde(rename = "service", default)]
+    services: Vec<ServiceConfig>,
+    #[serde(rename = "route", default)]
+    routes: Vec<RouteConfig>,
+}
+
+/// Context of the error for an included file that does not parse.
+#[derive(Debug)]
+pub struct InvalidIncludedFile(pub PathBuf);
+
+impl std::fmt::Display for InvalidIncludedFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid TOML in included file {}", self.0.display())
+    }
+}
+
+/// A directory and the file names in it that make up the config.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WatchTarget {
+    pub dir: PathBuf,
+    pub pattern: String,
+}
+
+/// Reads the files `patterns` match, relative to the directory of the config
+/// at `config_path`. Each pattern's matches are sorted by name, and a file
+/// matched twice is read once.
+pub fn load(config_path: &Path, patterns: &[String]) -> anyhow::Result<Vec<IncludedFile>> {
+    let mut paths: Vec<PathBuf> = Vec::new();
+    for (index, pattern) in patterns.iter().enumerate() {
+        let target = target(config_path, pattern).with_context(|| format!("include[{index}]"))?;
+        let mut matched = if has_glob(&target.pattern) {
+            let entries = match fs::read_dir(&target.dir) {
+                Ok(entries) => entries,
+                // A pattern over a directory that is not there yet matches nothing.
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(err) => {
+                    return Err(err).with_context(|| {
+                        format!("include[{index}]: failed to list {}", target.dir.display())
+                    });
+                }
+            };
+            entries
+                .filter_map(Result::ok)
+                .filter(|entry| entry.file_type().is_ok_and(|kind| kind.is_file()))
+                .filter(|entry| {
+                    entry
+                        .file_name()
+                        .to_str()
+                        .is_some_and(|name| matches(&target.pattern, name))
+                })
+                .map(|entry| entry.path())
+                .collect::<Vec<_>>()
+        } else {
+            vec![target.dir.join(&target.pattern)]
+        };
+        matched.sort();
+        for path in matched {
+            if !paths.contains(&path) && !same_file(&path, config_path) {
+                paths.push(path);
+            }
+        }
+    }
+    paths
+        .into_iter()
+        .map(|path| {
+            let content = fs::read_to_string(&path)
+                .with_context(|| format!("failed to read included file {}", path.display()))?;
+            let included: IncludedConfig =
+                toml::from_str(&content).with_context(|| InvalidIncludedFile(path.clone()))?;
+            Ok(IncludedFile {
+                path,
+                services: included.services,
+                routes: included.routes,
+            })
+        })
+        .collect()
+}
+
+/// What to watch for the config at `config_path` and the files it includes.
+/// Patterns that are not valid are left out; loading reports them.
+pub fn watch_targets(config_path: &Path, patterns: &[String]) -> Vec<WatchTarget> {
+    let config = WatchTarget {
+        dir: parent_dir(config_path),
+        pattern: config_path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or("Prx.toml")
+            .to_string(),
+    };
+    std::iter::once(config)
+        .chain(
+            patterns
+                .iter()
+                .filter_map(|pattern| target(config_path, pattern).ok()),
+        )
+        .collect()
+}
+
+fn target(config_path: &Path, pattern: &str) -> anyhow::Result<WatchTarget> {
+    let pattern = Path::new(pattern);
+    let Some(name) = pattern.file_name().and_then(|name| name.to_str()) else {
+        bail!(
+            "include pattern {:?} must name a file",
+            pattern.display().to_string()
+        );
+    };
+    let dir = pattern.parent().unwrap_or(Path::new(""));
+    if dir.to_str().is_none_or(has_glob) {
+        bail!(
+            "include pattern {:?} may only use `*` and `?` in the file name",
+            pattern.display().to_string()
+        );
+    }
+    Ok(WatchTarget {
+        dir: parent_dir(config_path).join(dir),
+        pattern: name.to_string(),
+    })
+}
+
+fn parent_dir(path: &Path) -> PathBuf {
+    path.parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .map(Path::to_path_buf)
+        .unwrap_or_else(|| PathBuf::from("."))
+}
+
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+fn has_glob(pattern: &str) -> bool {
+    pattern.contains(['*', '?'])
+}
+
+/// Shell-style file name match: `*` is any run of characters and `?` one
+/// character, but neither matches a leading `.`, so editor backups and
+/// hidden files stay out.
+pub fn matches(pattern: &str, name: &str) -> bool {
+    if name.starts_with('.') && !pattern.starts_with('.') {
+        return false;
+    }
+    let (pattern, name): (Vec<char>, Vec<char>) =
+        (pattern.chars().collect(), name.chars().collect());
+    // Where the last `*` was seen, and the part of the name it covered so far.
+    let (mut p, mut n, mut star) = (0, 0, None);
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    p = star_p + 1;
+                    n = star_n + 1;
+                    star = Some((star_p, star_n + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loads_matching_files_in_name_order() {
+        let dir = tempfile::tempdir().expect("tempdir");
+        let conf_d = dir.path().join("conf.d");
+        fs::create_dir(&conf_d).expect("conf.d");
+        let route = |name: &str| format!("[[route]]\nname = \"{name}\"\nservice = \"app\"\n");
+        fs::write(conf_d.join("20-b.toml"), route("b")).expect("write");
+        fs::write(conf_d.join("10-a.toml"), route("a")).expect("write");
+        fs::write(conf_d.join(".10-a.toml.swp"), "junk").expect("write");
+        fs::write(conf_d.join("notes.txt"), "junk").expect("write");
+        let config = dir.path().join("Prx.toml");
+
+        let patterns = vec!["conf.d/*.toml".to_string(), "conf.d/20-b.toml".to_string()];
+        let included = load(&config, &patterns).expect("load");
+        let names: Vec<_> = included
+            .iter()
+            .map(|file| file.routes[0].name.as_str())
+            .collect();
+        assert_eq!(names, ["a", "b"]);
+        assert_eq!(included[0].path, conf_d.join("10-a.toml"));
+
+        assert!(
+            load(&config, &["missing/*.toml".to_string()])
+                .expect("load")
+                .is_empty()
+        );
+        assert!(load(&config, &["conf.d/none.toml".to_string()]).is_err());
+        assert!(load(&config, &["*/routes.toml".to_string()]).is_err());
+        fs::write(conf_d.join("30-c.toml"), "[server]\nlisten = []\n").expect("write");
+        let err = load(&config, &patterns).expect_err("server block");
+        assert!(format!("{err:#}").contains("30-c.toml"), "{err:#}");
+
+        assert!(matches("*.toml", "a.b.toml") && matches("r?.toml", "r1.toml"));
+        assert!(!matches("*.toml", "a.toml.bak") && !matches("r?.toml", "r12.toml"));
+    }
+}
@@ -0,0 +1,68 @@
+//! prx as a library: build the proxy from a [`PrxConfig`](config::PrxConfig)
+//! with [`PrxBuilder`] and serve it from any Rust binary.
+
+mod acl;
+mod admin;
+mod admin_auth;
+mod affinity;
+mod audit;
+mod blocklist;
+mod builder;
+mod check;
+pub mod cli;
+pub mod config;
+mod diff;
+mod discovery;
+mod drain;
+#[cfg(windows)]
+mod event_log;
+mod forwarded;
+mod grpc;
+mod health;
+mod healthcheck;
+mod history;
+mod idempotency;
+mod include;
+mod load;
+mod lookup;
+mod memory;
+mod metrics;
+mod outbound;
+mod preflight;
+mod proxy;
+mod proxy_protocol;
+mod purge;
+mod ratelimit;
+mod reload;
+mod reputation;
+mod request_headers;
+mod response_validation;
+mod rollout;
+mod runtime;
+mod secret;
+mod selftest;
+#[cfg(unix)]
+mod shared_state;
+mod source;
+mod strict;
+mod tcp;
+mod throttle;
+#[cfg(feature = "openssl")]
+mod tls;
+mod unmatched;
+mod waf;
+#[cfg(windows)]
+mod win_service;
+
+use std::env;
+
+pub use builder::{Prx, PrxBuilder, PrxHandle};
+pub use reputation::{ReputationLookup, ReputationVerdict};
+pub use runtime::RebuildStats;
+
+pub(crate) fn env_value(name: &str) -> Option<String> {
+    env::var(name)
+        .ok()
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+}
@@ -0,0 +1,181 @@
+use std::sync::Arc;
+
+use arc_swap::ArcSwap;
+use prometheus::{
+    IntGauge,
+    core::{Collector, Desc},
+    proto::MetricFamily,
+};
+use serde::Serialize;
+
+use crate::{drain, metrics, runtime::RuntimeConfig};
+
+/// How busy this instance is, for autoscalers and load balancers weighting
+/// prx instances.
+#[derive(Debug, Serialize)]
+pub struct Load {
+    /// Highest utilization of any configured concurrency limit, `0..=100`;
+    /// `100` while draining or not ready.
+    pub load: u8,
+    pub ready: bool,
+    pub draining: bool,
+    pub in_flight: u64,
+    pub max_concurrent_requests: Option<u64>,
+    pub websocket_connections: u64,
+    pub tcp_connections: u64,
+    pub routes: Vec<RouteLoad>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RouteLoad {
+    pub route: String,
+    pub in_flight: u64,
+    pub max_concurrent_requests: Option<u64>,
+    /// `None` without a `max_concurrent_requests`.
+    pub load: Option<u8>,
+}
+
+impl Load {
+    /// `in_flight` is the server-wide count, which the caller may have to
+    /// correct for its own request.
+    pub fn measure(snapshot: &RuntimeConfig, in_flight: u64) -> Self {
+        let routes: Vec<RouteLoad> = snapshot
+            .routes()
+            .iter()
+            .map(|route| {
+                let in_flight = route.in_flight() as u64;
+                RouteLoad {
+                    route: route.name.to_string(),
+                    in_flight,
+                    max_concurrent_requests: route.max_concurrent_requests,
+                    load: route
+                        .max_concurrent_requests
+                        .map(|limit| percent(in_flight, limit)),
+                }
+            })
+            .collect();
+        let ready = snapshot.is_ready();
+        let draining = drain::is_draining();
+        let max_concurrent_requests = snapshot.max_concurrent_requests();
+        let load = if draining || !ready {
+            100
+        } else {
+            max_concurrent_requests
+                .map(|limit| percent(in_flight, limit))
+                .into_iter()
+                .chain(routes.iter().filter_map(|route| route.load))
+                .max()
+                .unwrap_or(0)
+        };
+        Self {
+            load,
+            ready,
+            draining,
+            in_flight,
+            max_concurrent_requests,
+            websocket_connections: snapshot
+                .routes()
+                .iter()
+                .map(|route| route.websockets() as u64)
+                .sum(),
+            tcp_connections: metrics::tcp_connections(),
+            routes,
+        }
+    }
+}
+
+fn percent(used: u64, limit: u64) -> u8 {
+    if limit == 0 {
+        return 100;
+    }
+    (used.saturating_mul(100) / limit).min(100) as u8
+}
+
+/// Reports `prx_load_percent` on every scrape.
+struct LoadCollector {
+    active_config: Arc<ArcSwap<RuntimeConfig>>,
+    load: IntGauge,
+}
+
+impl Collector for LoadCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        self.load.desc()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let in_flight = metrics::in_flight().max(0) as u64;
+        let load = Load::measure(&self.active_config.load(), in_flight);
+        self.load.set(i64::from(load.load));
+        self.load.collect()
+    }
+}
+
+/// Registers `prx_load_percent` with the default registry served on `/metrics`.
+pub fn register_collector(active_config: Arc<ArcSwap<RuntimeConfig>>) -> prometheus::Result<()> {
+    // The gauges read while collecting register themselves on first use,
+    // which would deadlock inside the registry's own gather.
+    metrics::in_flight();
+    metrics::tcp_connections();
+    prometheus::register(Box::new(LoadCollector {
+        active_config,
+        load: IntGauge::new(
+            "prx_load_percent",
+            "Highest utilization of a max_concurrent_requests limit, 100 while draining or not ready",
+        )?,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PrxConfig;
+
+    #[test]
+    fn reports_the_busiest_limit() {
+        let config = PrxConfig::from_toml_str(
+            r#"
+[server]
+max_concurrent_requests = 40
+
+[[service]]
+name = "backend"
+
+[[service.upstream]]
+addr = "127.0.0.1:9000"
+
+[[route]]
+name = "api"
+path_prefix = "/api"
+service = "backend"
+max_concurrent_requests = 8
+
+[[route]]
+name = "web"
+path_prefix = "/"
+is_default = true
+service = "backend"
+"#,
+        )
+        .expect("config");
+        let snapshot = RuntimeConfig::from_config(config).expect("runtime");
+        let api = snapshot
+            .routes()
+            .iter()
+            .position(|route| &*route.name == "api");
+        let api = &snapshot.routes()[api.expect("api route")];
+        let _held = [api.enter(), api.enter(), api.enter()];
+
+        let load = Load::measure(&snapshot, 4);
+        assert_eq!(load.load, 37);
+        assert!(load.ready && !load.draining);
+        let routes: Vec<_> = load
+            .routes
+            .iter()
+            .map(|route| (route.route.as_str(), route.in_flight, route.load))
+            .collect();
+        assert_eq!(routes, [("api", 3, Some(37)), ("web", 0, None)]);
+        assert_eq!(Load::measure(&snapshot, 30).load, 75);
+        assert_eq!(Load::measure(&snapshot, 90).load, 100);
+        assert_eq!(percent(1, 0), 100);
+    }
+}
@@ -0,0 +1,323 @@
+use std::fmt;
+
+use anyhow::{Context, bail};
+use http::{HeaderMap, HeaderName, HeaderValue};
+
+use crate::{
+    config::PrxConfig,
+    runtime::{RuntimeConfig, hash_key, normalize_host},
+};
+
+/// A request described on the command line by `prx test-route`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RouteQuery {
+    pub host: String,
+    pub path: String,
+    pub method: String,
+    pub app: Option<String>,
+    pub headers: Vec<(String, String)>,
+}
+
+impl RouteQuery {
+    /// Parses `test-route --host H --path P [--method M] [--app A]
+    /// [--header NAME:VALUE]...`; returns `None` when the first argument is
+    /// not `test-route`.
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> anyhow::Result<Option<Self>> {
+        let mut args = args.into_iter();
+        if args.next().as_deref() != Some("test-route") {
+            return Ok(None);
+        }
+
+        let mut host = None;
+        let mut path = None;
+        let mut method = None;
+        let mut app = None;
+        let mut headers = Vec::new();
+        while let Some(flag) = args.next() {
+            if flag == "--header" {
+                let header = args.next().context("--header requires a value")?;
+                let Some((name, value)) = header.split_once(':') else {
+                    bail!("--header must look like NAME:VALUE, got {header}");
+                };
+                headers.push((name.trim().to_string(), value.trim().to_string()));
+                continue;
+            }
+            let slot = match flag.as_str() {
+                "--host" => &mut host,
+                "--path" => &mut path,
+                "--method" => &mut method,
+                "--app" => &mut app,
+                _ => bail!("unknown test-route argument {flag}"),
+            };
+            let value = args
+                .next()
+                .with_context(|| format!("{flag} requires a value"))?;
+            *slot = Some(value);
+        }
+
+        let path = path.unwrap_or_else(|| "/".to_string());
+        if !path.starts_with('/') {
+            bail!("--path must start with '/'");
+        }
+        Ok(Some(Self {
+            host: host.context("test-route requires --host")?,
+            path,
+            method: method
+                .unwrap_or_else(|| "GET".to_string())
+                .to_ascii_uppercase(),
+            app,
+            headers,
+        }))
+    }
+
+    /// Resolves the query the way the proxy would route a fresh request.
+    pub fn resolve(&self, config: &PrxConfig) -> anyhow::Result<RouteLookup> {
+        if let Some(app) = &self.app
+            && !config.apps.iter().any(|candidate| &candidate.name == app)
+        {
+            bail!("unknown app {app}");
+        }
+        if self.path == config.server.health_path || self.path == config.server.ready_path {
+            return Ok(RouteLookup::Builtin(self.path.clone()));
+        }
+
+        let mut headers = HeaderMap::new();
+        for (name, value) in &self.headers {
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .with_context(|| format!("invalid header name {name}"))?;
+            let value = HeaderValue::from_str(value)
+                .with_context(|| format!("invalid value for header {name}"))?;
+            headers.append(name, value);
+        }
+
+        let runtime = RuntimeConfig::from_config(config.clone())?;
+        let host = normalize_host(&self.host);
+        let Some(route) = runtime
+            .select_route(self.app.as_deref(), &host, &self.path, &headers)
+            .and_then(|idx| runtime.route(idx))
+        else {
+            return Ok(RouteLookup::NoRoute);
+        };
+        let service = runtime
+            .service(route.service_idx)
+            .context("route points to a missing service")?;
+        let upstream = service
+            .next_upstream(hash_key(&[host.as_str(), self.path.as_str()]), &[])
+            .map(|(_, upstream)| upstream.addr.to_string());
+        let unlisted_method = config
+            .routes
+            .iter()
+            .find(|candidate| candidate.name == *route.name)
+            .is_some_and(|candidate| {
+                !candidate.methods.is_empty()
+                    && !candidate
+                        .methods
+                        .iter()
+                        .any(|method| method.eq_ignore_ascii_case(&self.method))
+            });
+
+        Ok(RouteLookup::Route {
+            route: route.name.to_string(),
+            is_default: route.is_default,
+            service: service.name.clone(),
+            lb: format!("{:?}", service.lb).to_ascii_lowercase(),
+            upstream,
+            unlisted_method: unlisted_method.then(|| self.method.clone()),
+        })
+    }
+}
+
+/// Where `prx test-route` found a request would be sent.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RouteLookup {
+    /// Answered by the proxy itself on the health or ready path.
+    Builtin(String),
+    Route {
+        route: String,
+        is_default: bool,
+        service: String,
+        lb: String,
+        /// First pick of a freshly started proxy; `None` if the service has
+        /// no routable upstream.
+        upstream: Option<String>,
+        /// Set when the route lists `methods` that do not include the query's.
+        unlisted_method: Option<String>,
+    },
+    NoRoute,
+}
+
+impl RouteLookup {
+    pub fn is_match(&self) -> bool {
+        !matches!(self, Self::NoRoute)
+    }
+}
+
+impl fmt::Display for RouteLookup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Builtin(path) => write!(f, "builtin {path} (answered by prx)"),
+            Self::NoRoute => write!(f, "no route matched (404)"),
+            Self::Route {
+                route,
+                is_default,
+                service,
+                lb,
+                upstream,
+                unlisted_method,
+            } => {
+                let fallback = if *is_default { " (default)" } else { "" };
+                writeln!(f, "route    {route}{fallback}")?;
+                writeln!(f, "service  {service} (lb {lb})")?;
+                match upstream {
+                    Some(addr) => write!(f, "upstream {addr}")?,
+                    None => write!(f, "upstream none (502)")?,
+                }
+                if let Some(method) = unlisted_method {
+                    write!(
+                        f,
+                        "\nnote     {method} is not in the route's methods; prx routes by host, path and headers only"
+                    )?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> PrxConfig {
+        PrxConfig::from_toml_str(
+            r#"
+[server]
+listen = ["127.0.0.1:0"]
+
+[[service]]
+name = "api"
+lb = "hash"
+
+[[service.upstream]]
+addr = "127.0.0.1:9001"
+
+[[service.upstream]]
+addr = "127.0.0.1:9002"
+
+[[service]]
+name = "web"
+
+[[service.upstream]]
+addr = "127.0.0.1:9100"
+
+[[route]]
+name = "api"
+host = "api.example.com"
+path_prefix = "/v1"
+service = "api"
+methods = ["GET"]
+
+[[route]]
+name = "web-canary"
+path_prefix = "/"
+service = "web"
+headers = { "x-version" = "v2" }
+
+[[route]]
+name = "web"
+path_prefix = "/"
+service = "web"
+is_default = true
+"#,
+        )
+        .expect("config")
+    }
+
+    fn query(args: &[&str]) -> RouteQuery {
+        RouteQuery::from_args(args.iter().map(|arg| arg.to_string()))
+            .expect("parse")
+            .expect("test-route")
+    }
+
+    #[test]
+    fn parses_only_the_test_route_subcommand() {
+        assert_eq!(
+            RouteQuery::from_args(["--check".to_string()]).expect("parse"),
+            None
+        );
+        let parsed = query(&["test-route", "--host", "a", "--method", "post"]);
+        assert_eq!(parsed.path, "/");
+        assert_eq!(parsed.method, "POST");
+        assert!(RouteQuery::from_args(["test-route".to_string()]).is_err());
+        assert!(RouteQuery::from_args(["test-route".to_string(), "--host".to_string()]).is_err());
+        assert_eq!(
+            query(&["test-route", "--host", "a", "--header", "x: 1"]).headers,
+            [("x".to_string(), "1".to_string())]
+        );
+    }
+
+    #[test]
+    fn resolves_route_and_stable_hash_upstream() {
+        let config = config();
+        let api = query(&[
+            "test-route",
+            "--host",
+            "API.example.com:443",
+            "--path",
+            "/v1/users",
+        ]);
+        let first = api.resolve(&config).expect("resolve");
+        let RouteLookup::Route {
+            route, upstream, ..
+        } = &first
+        else {
+            panic!("expected route, got {first:?}");
+        };
+        assert_eq!(route, "api");
+        assert!(upstream.is_some());
+        assert_eq!(api.resolve(&config).expect("resolve"), first);
+
+        let fallback = query(&["test-route", "--host", "other", "--path", "/v1"])
+            .resolve(&config)
+            .expect("resolve");
+        assert!(fallback.to_string().starts_with("route    web (default)"));
+
+        let canary = query(&["test-route", "--host", "other", "--header", "X-Version: v2"])
+            .resolve(&config)
+            .expect("resolve");
+        assert!(
+            canary.to_string().starts_with("route    web-canary\n"),
+            "{canary}"
+        );
+    }
+
+    #[test]
+    fn flags_unlisted_method_and_builtin_paths() {
+        let config = config();
+        let post = query(&[
+            "test-route",
+            "--host",
+            "api.example.com",
+            "--path",
+            "/v1",
+            "--method",
+            "POST",
+        ])
+        .resolve(&config)
+        .expect("resolve");
+        assert!(
+            post.to_string()
+                .contains("POST is not in the route's methods")
+        );
+
+        let health = query(&["test-route", "--host", "x", "--path", "/healthz"])
+            .resolve(&config)
+            .expect("resolve");
+        assert!(matches!(health, RouteLookup::Builtin(_)));
+        assert!(
+            query(&["test-route", "--host", "x", "--app", "missing"])
+                .resolve(&config)
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,161 @@
+use prometheus::{
+    Gauge, IntGaugeVec, Opts,
+    core::{Collector, Desc},
+    proto::MetricFamily,
+};
+
+#[cfg(feature = "jemalloc")]
+#[global_allocator]
+static GLOBAL: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
+
+// jemalloc wins when both features are enabled, e.g. under `--all-features`.
+#[cfg(all(feature = "mimalloc", not(feature = "jemalloc")))]
+#[global_allocator]
+static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
+
+pub const ALLOCATOR: &str = if cfg!(feature = "jemalloc") {
+    "jemalloc"
+} else if cfg!(feature = "mimalloc") {
+    "mimalloc"
+} else {
+    "system"
+};
+
+/// Reports process and allocator memory on every scrape, so memory growth
+/// from large discovery or cache configs is visible without external tooling.
+struct MemoryCollector {
+    resident: Gauge,
+    virtual_size: Gauge,
+    allocated: Gauge,
+    allocator_resident: Gauge,
+    allocator: IntGaugeVec,
+}
+
+impl MemoryCollector {
+    fn new() -> prometheus::Result<Self> {
+        let allocator = IntGaugeVec::new(
+            Opts::new("prx_allocator_info", "Global allocator prx was built with"),
+            &["allocator"],
+        )?;
+        allocator.with_label_values(&[ALLOCATOR]).set(1);
+        Ok(Self {
+            resident: Gauge::new(
+                "prx_process_resident_memory_bytes",
+                "Resident set size of the prx process",
+            )?,
+            virtual_size: Gauge::new(
+                "prx_process_virtual_memory_bytes",
+                "Virtual memory size of the prx process",
+            )?,
+            allocated: Gauge::new(
+                "prx_allocator_allocated_bytes",
+                "Bytes currently allocated by the application (jemalloc only)",
+            )?,
+            allocator_resident: Gauge::new(
+                "prx_allocator_resident_bytes",
+                "Bytes in physically resident pages mapped by the allocator (jemalloc only)",
+            )?,
+            allocator,
+        })
+    }
+
+    fn refresh(&self) {
+        if let Some((virtual_size, resident)) = process_memory() {
+            self.virtual_size.set(virtual_size as f64);
+            self.resident.set(resident as f64);
+        }
+        if let Some((allocated, resident)) = allocator_memory() {
+            self.allocated.set(allocated as f64);
+            self.allocator_resident.set(resident as f64);
+        }
+    }
+
+    fn collectors(&self) -> Vec<&dyn Collector> {
+        let mut collectors: Vec<&dyn Collector> =
+            vec![&self.resident, &self.virtual_size, &self.allocator];
+        if cfg!(feature = "jemalloc") {
+            collectors.push(&self.allocated);
+            collectors.push(&self.allocator_resident);
+        }
+        collectors
+    }
+}
+
+impl Collector for MemoryCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        self.collectors()
+            .into_iter()
+            .flat_map(Collector::desc)
+            .collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        self.refresh();
+        self.collectors()
+            .into_iter()
+            .flat_map(|collector| collector.collect())
+            .collect()
+    }
+}
+
+/// Registers the memory gauges with the default registry served on `/metrics`.
+pub fn register_collector() -> prometheus::Result<()> {
+    prometheus::register(Box::new(MemoryCollector::new()?))
+}
+
+/// `(virtual, resident)` bytes of this process.
+#[cfg(target_os = "linux")]
+fn process_memory() -> Option<(u64, u64)> {
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let mut pages = statm.split_whitespace().map(str::parse::<u64>);
+    let virtual_pages = pages.next()?.ok()?;
+    let resident_pages = pages.next()?.ok()?;
+    // SAFETY: sysconf has no preconditions.
+    let page_size = u64::try_from(unsafe { libc::sysconf(libc::_SC_PAGESIZE) }).ok()?;
+    Some((virtual_pages * page_size, resident_pages * page_size))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn process_memory() -> Option<(u64, u64)> {
+    None
+}
+
+/// `(allocated, resident)` bytes as reported by jemalloc.
+#[cfg(feature = "jemalloc")]
+fn allocator_memory() -> Option<(usize, usize)> {
+    use tikv_jemalloc_ctl::{epoch, stats};
+
+    // jemalloc caches its statistics until the epoch is advanced.
+    epoch::advance().ok()?;
+    Some((
+        stats::allocated::read().ok()?,
+        stats::resident::read().ok()?,
+    ))
+}
+
+#[cfg(not(feature = "jemalloc"))]
+fn allocator_memory() -> Option<(usize, usize)> {
+    None
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn collect_reports_resident_memory() {
+        let collector = MemoryCollector::new().expect("collector");
+        let families = collector.collect();
+
+        let resident = families
+            .iter()
+            .find(|family| family.name() == "prx_process_resident_memory_bytes")
+            .expect("resident family");
+        assert!(resident.get_metric()[0].get_gauge().value() > 0.0);
+        assert!(
+            families
+                .iter()
+                .any(|family| family.name() == "prx_allocator_info")
+        );
+    }
+}
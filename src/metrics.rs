@@ -1,25 +1,70 @@
 use once_cell::sync::Lazy;
 use prometheus::{
-    HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, register_histogram_vec,
-    register_int_counter_vec, register_int_gauge_vec,
+    HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, core::Collector,
+    register_histogram_vec, register_int_counter_vec, register_int_gauge, register_int_gauge_vec,
 };
 
+/// Label value shared by routes/upstreams past `observability.max_metric_label_values`.
+pub const OVERFLOW_LABEL: &str = "__overflow__";
+
+/// Every `stage` value passed to `inc_upstream_error`.
+const UPSTREAM_ERROR_STAGES: [&str; 3] = ["connect", "proxy", "status"];
+
+/// Every `outcome` value passed to `inc_idempotent_duplicate`.
+const IDEMPOTENT_OUTCOMES: [&str; 4] = ["rejected", "waited", "replayed", "mismatched"];
+
 static REQUESTS_TOTAL: Lazy<IntCounterVec> = Lazy::new(|| {
     register_int_counter_vec!(
         "prx_requests_total",
         "Total requests processed by prx",
-        &["route", "status"]
+        &["route", "tenant", "status"]
     )
     .expect("failed to register prx_requests_total")
 });
 
+static RESPONSES_TOTAL: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "prx_responses_total",
+        "Responses by route and status class",
+        &["route", "class"]
+    )
+    .expect("failed to register prx_responses_total")
+});
+
+static GROUP_RESPONSES_TOTAL: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "prx_route_group_responses_total",
+        "Responses of routes with groups, by assigned group and status class",
+        &["route", "group", "class"]
+    )
+    .expect("failed to register prx_route_group_responses_total")
+});
+
+static GRPC_RESPONSES_TOTAL: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "prx_grpc_responses_total",
+        "Responses of grpc routes, by route and grpc-status code",
+        &["route", "grpc_status"]
+    )
+    .expect("failed to register prx_grpc_responses_total")
+});
+
+static SHADOW_ROUTE_DIVERGENCES_TOTAL: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "prx_shadow_route_divergences_total",
+        "Requests a config under shadow evaluation would have routed elsewhere",
+        &["route", "service", "shadow_route", "shadow_service"]
+    )
+    .expect("failed to register prx_shadow_route_divergences_total")
+});
+
 static REQUEST_LATENCY_MS: Lazy<HistogramVec> = Lazy::new(|| {
     register_histogram_vec!(
         HistogramOpts::new(
             "prx_request_latency_ms",
             "Request latency in milliseconds for prx"
         ),
-        &["route"]
+        &["route", "tenant"]
     )
     .expect("failed to register prx_request_latency_ms")
 });
@@ -51,16 +96,244 @@ static CIRCUIT_OPEN_STATE: Lazy<IntGaugeVec> = Lazy::new(|| {
     .expect("failed to register prx_upstream_circuit_open")
 });
 
-pub fn observe_request(route: &str, status: u16, latency_ms: f64) {
-    let status_label = status.to_string();
+static UPSTREAM_OVERLOADS_TOTAL: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "prx_upstream_overloads_total",
+        "Upstream responses that signaled overload and cut the upstream's weight",
+        &["route", "upstream"]
+    )
+    .expect("failed to register prx_upstream_overloads_total")
+});
+
+static UPSTREAM_EJECTIONS_TOTAL: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "prx_upstream_ejections_total",
+        "Upstreams ejected by outlier detection for their error rate or p99 latency",
+        &["route", "upstream"]
+    )
+    .expect("failed to register prx_upstream_ejections_total")
+});
+
+static HTTP2_FALLBACKS_TOTAL: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "prx_upstream_http2_fallbacks_total",
+        "Upstreams switched from HTTP/2 to HTTP/1.1 after an HTTP/2 failure",
+        &["route", "upstream"]
+    )
+    .expect("failed to register prx_upstream_http2_fallbacks_total")
+});
+
+static WAF_MATCHES_TOTAL: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "prx_waf_matches_total",
+        "Requests matched by a waf rule, by rule id and whether they were denied",
+        &["rule", "action"]
+    )
+    .expect("failed to register prx_waf_matches_total")
+});
+
+static REPUTATION_VERDICTS_TOTAL: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "prx_reputation_verdicts_total",
+        "Client IP reputation verdicts, including cached ones and failed lookups",
+        &["verdict"]
+    )
+    .expect("failed to register prx_reputation_verdicts_total")
+});
+
+static UNMATCHED_HOST_REQUESTS_TOTAL: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "prx_unmatched_host_requests_total",
+        "Requests for a host no route covers, answered 404 or by the default route, and TLS handshakes for one no certificate covers",
+        &["reason", "tls"]
+    )
+    .expect("failed to register prx_unmatched_host_requests_total")
+});
+
+static CONNECTIONS_DENIED_TOTAL: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "prx_connections_denied_total",
+        "Client connections dropped by an acl at accept time, by app",
+        &["app"]
+    )
+    .expect("failed to register prx_connections_denied_total")
+});
+
+static REQUESTS_REJECTED_TOTAL: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "prx_requests_rejected_total",
+        "Requests rejected by server.strict_http, server.blocklist_path or reputation, by reason",
+        &["reason"]
+    )
+    .expect("failed to register prx_requests_rejected_total")
+});
+
+static REQUEST_BODY_TOO_LARGE_TOTAL: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "prx_request_body_too_large_total",
+        "Requests answered with 413 by a route max_request_body_bytes",
+        &["route"]
+    )
+    .expect("failed to register prx_request_body_too_large_total")
+});
+
+static RATE_LIMITED_TOTAL: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "prx_rate_limited_total",
+        "Requests answered with 429 by a route rate limit",
+        &["route"]
+    )
+    .expect("failed to register prx_rate_limited_total")
+});
+
+static REQUEST_TIMEOUTS_TOTAL: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "prx_request_timeouts_total",
+        "Requests answered with 504 because their route timeout_ms ran out",
+        &["route"]
+    )
+    .expect("failed to register prx_request_timeouts_total")
+});
+
+static RESPONSE_VIOLATIONS_TOTAL: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "prx_response_violations_total",
+        "Upstream responses that broke a route response_validation rule",
+        &["route", "rule"]
+    )
+    .expect("failed to register prx_response_violations_total")
+});
+
+static IDEMPOTENT_DUPLICATES_TOTAL: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "prx_idempotent_duplicates_total",
+        "Requests repeating an idempotency key, by how prx answered them",
+        &["route", "outcome"]
+    )
+    .expect("failed to register prx_idempotent_duplicates_total")
+});
+
+static IN_FLIGHT_REQUESTS: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "prx_in_flight_requests",
+        "Requests currently being processed by prx"
+    )
+    .expect("failed to register prx_in_flight_requests")
+});
+
+static WEBSOCKET_CONNECTIONS: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "prx_websocket_connections",
+        "Upgraded (WebSocket) connections currently open per route",
+        &["route"]
+    )
+    .expect("failed to register prx_websocket_connections")
+});
+
+static ROUTE_IN_FLIGHT_REQUESTS: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "prx_route_in_flight_requests",
+        "Requests currently being processed per route",
+        &["route"]
+    )
+    .expect("failed to register prx_route_in_flight_requests")
+});
+
+static TCP_CONNECTIONS_TOTAL: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "prx_tcp_connections_total",
+        "Connections accepted by tcp routes",
+        &["route"]
+    )
+    .expect("failed to register prx_tcp_connections_total")
+});
+
+static TCP_ACTIVE_CONNECTIONS: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "prx_tcp_active_connections",
+        "Open connections of tcp routes",
+        &["route"]
+    )
+    .expect("failed to register prx_tcp_active_connections")
+});
+
+static TCP_BYTES_TOTAL: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "prx_tcp_bytes_total",
+        "Bytes relayed by tcp routes, towards the upstream or the client",
+        &["route", "direction"]
+    )
+    .expect("failed to register prx_tcp_bytes_total")
+});
+
+static RESPONSE_STALL_MS: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        HistogramOpts::new(
+            "prx_response_stall_ms",
+            "Time a response body spent waiting on the client (writes) or the upstream (reads)"
+        ),
+        &["route", "side"]
+    )
+    .expect("failed to register prx_response_stall_ms")
+});
+
+// Status labels are interned once so recording a request does not format the code.
+static STATUS_LABELS: Lazy<Vec<String>> =
+    Lazy::new(|| (0..1000u16).map(|status| status.to_string()).collect());
+
+/// Every `class` value of `prx_responses_total`.
+const STATUS_CLASSES: [&str; 6] = ["1xx", "2xx", "3xx", "4xx", "5xx", "other"];
+
+fn status_class(status: u16) -> &'static str {
+    match status {
+        100..=599 => STATUS_CLASSES[usize::from(status / 100 - 1)],
+        _ => "other",
+    }
+}
+
+fn status_label(status: u16) -> &'static str {
+    STATUS_LABELS
+        .get(usize::from(status))
+        .map(String::as_str)
+        .unwrap_or("other")
+}
+
+pub fn observe_request(route: &str, tenant: &str, status: u16, latency_ms: f64) {
     REQUESTS_TOTAL
-        .with_label_values(&[route, status_label.as_str()])
+        .with_label_values(&[route, tenant, status_label(status)])
+        .inc();
+    RESPONSES_TOTAL
+        .with_label_values(&[route, status_class(status)])
         .inc();
     REQUEST_LATENCY_MS
-        .with_label_values(&[route])
+        .with_label_values(&[route, tenant])
         .observe(latency_ms);
 }
 
+pub fn inc_group_response(route: &str, group: &str, status: u16) {
+    GROUP_RESPONSES_TOTAL
+        .with_label_values(&[route, group, status_class(status)])
+        .inc();
+}
+
+/// `code` is at most `grpc::MAX_STATUS`.
+pub fn inc_grpc_response(route: &str, code: u8) {
+    GRPC_RESPONSES_TOTAL
+        .with_label_values(&[route, &code.to_string()])
+        .inc();
+}
+
+pub fn inc_shadow_route_divergence(
+    route: &str,
+    service: &str,
+    shadow_route: &str,
+    shadow_service: &str,
+) {
+    SHADOW_ROUTE_DIVERGENCES_TOTAL
+        .with_label_values(&[route, service, shadow_route, shadow_service])
+        .inc();
+}
+
 pub fn inc_upstream_error(route: &str, upstream: &str, stage: &str) {
     UPSTREAM_ERRORS_TOTAL
         .with_label_values(&[route, upstream, stage])
@@ -81,3 +354,225 @@ pub fn set_circuit_state(route: &str, upstream: &str, is_open: bool) {
         .with_label_values(&[route, upstream])
         .set(if is_open { 1 } else { 0 });
 }
+
+pub fn inc_upstream_overload(route: &str, upstream: &str) {
+    UPSTREAM_OVERLOADS_TOTAL
+        .with_label_values(&[route, upstream])
+        .inc();
+}
+
+pub fn inc_upstream_ejection(route: &str, upstream: &str) {
+    UPSTREAM_EJECTIONS_TOTAL
+        .with_label_values(&[route, upstream])
+        .inc();
+}
+
+pub fn inc_http2_fallback(route: &str, upstream: &str) {
+    HTTP2_FALLBACKS_TOTAL
+        .with_label_values(&[route, upstream])
+        .inc();
+}
+
+pub fn inc_waf_match(rule_id: u64, denied: bool) {
+    let action = if denied { "deny" } else { "pass" };
+    WAF_MATCHES_TOTAL
+        .with_label_values(&[rule_id.to_string().as_str(), action])
+        .inc();
+}
+
+pub fn inc_reputation_verdict(verdict: &str) {
+    REPUTATION_VERDICTS_TOTAL
+        .with_label_values(&[verdict])
+        .inc();
+}
+
+pub fn inc_unmatched_host(reason: &str, tls: bool) {
+    UNMATCHED_HOST_REQUESTS_TOTAL
+        .with_label_values(&[reason, if tls { "true" } else { "false" }])
+        .inc();
+}
+
+pub fn inc_connection_denied(app: &str) {
+    CONNECTIONS_DENIED_TOTAL.with_label_values(&[app]).inc();
+}
+
+pub fn inc_request_rejected(reason: &str) {
+    REQUESTS_REJECTED_TOTAL.with_label_values(&[reason]).inc();
+}
+
+pub fn inc_request_body_too_large(route: &str) {
+    REQUEST_BODY_TOO_LARGE_TOTAL
+        .with_label_values(&[route])
+        .inc();
+}
+
+pub fn inc_rate_limited(route: &str) {
+    RATE_LIMITED_TOTAL.with_label_values(&[route]).inc();
+}
+
+pub fn inc_request_timeout(route: &str) {
+    REQUEST_TIMEOUTS_TOTAL.with_label_values(&[route]).inc();
+}
+
+pub fn inc_response_violation(route: &str, rule: &str) {
+    RESPONSE_VIOLATIONS_TOTAL
+        .with_label_values(&[route, rule])
+        .inc();
+}
+
+pub fn inc_idempotent_duplicate(route: &str, outcome: &str) {
+    IDEMPOTENT_DUPLICATES_TOTAL
+        .with_label_values(&[route, outcome])
+        .inc();
+}
+
+pub fn observe_response_stall(route: &str, client_ms: f64, upstream_ms: f64) {
+    RESPONSE_STALL_MS
+        .with_label_values(&[route, "client"])
+        .observe(client_ms);
+    RESPONSE_STALL_MS
+        .with_label_values(&[route, "upstream"])
+        .observe(upstream_ms);
+}
+
+pub fn inc_in_flight() {
+    IN_FLIGHT_REQUESTS.inc();
+}
+
+pub fn dec_in_flight() {
+    IN_FLIGHT_REQUESTS.dec();
+}
+
+pub fn set_route_in_flight(route: &str, count: usize) {
+    ROUTE_IN_FLIGHT_REQUESTS
+        .with_label_values(&[route])
+        .set(count as i64);
+}
+
+pub fn set_websocket_connections(route: &str, count: usize) {
+    WEBSOCKET_CONNECTIONS
+        .with_label_values(&[route])
+        .set(count as i64);
+}
+
+pub fn open_tcp_connection(route: &str) {
+    TCP_CONNECTIONS_TOTAL.with_label_values(&[route]).inc();
+    TCP_ACTIVE_CONNECTIONS.with_label_values(&[route]).inc();
+}
+
+pub fn close_tcp_connection(route: &str, to_upstream: u64, to_client: u64) {
+    TCP_ACTIVE_CONNECTIONS.with_label_values(&[route]).dec();
+    TCP_BYTES_TOTAL
+        .with_label_values(&[route, "upstream"])
+        .inc_by(to_upstream);
+    TCP_BYTES_TOTAL
+        .with_label_values(&[route, "client"])
+        .inc_by(to_client);
+}
+
+pub fn in_flight() -> i64 {
+    IN_FLIGHT_REQUESTS.get()
+}
+
+/// Open connections across all tcp routes.
+pub fn tcp_connections() -> u64 {
+    TCP_ACTIVE_CONNECTIONS
+        .collect()
+        .iter()
+        .flat_map(|family| family.get_metric())
+        .map(|metric| metric.get_gauge().value().max(0.0) as u64)
+        .sum()
+}
+
+pub fn remove_route_series(route: &str, tenant: &str) {
+    for status in STATUS_LABELS.iter().map(String::as_str).chain(["other"]) {
+        let _ = REQUESTS_TOTAL.remove_label_values(&[route, tenant, status]);
+    }
+    for class in STATUS_CLASSES {
+        let _ = RESPONSES_TOTAL.remove_label_values(&[route, class]);
+    }
+    let _ = REQUEST_LATENCY_MS.remove_label_values(&[route, tenant]);
+    let _ = RATE_LIMITED_TOTAL.remove_label_values(&[route]);
+    let _ = REQUEST_TIMEOUTS_TOTAL.remove_label_values(&[route]);
+    for rule in ["required_header", "max_latency", "content_type"] {
+        let _ = RESPONSE_VIOLATIONS_TOTAL.remove_label_values(&[route, rule]);
+    }
+    let _ = REQUEST_BODY_TOO_LARGE_TOTAL.remove_label_values(&[route]);
+    let _ = ROUTE_IN_FLIGHT_REQUESTS.remove_label_values(&[route]);
+    let _ = WEBSOCKET_CONNECTIONS.remove_label_values(&[route]);
+    for code in 0..=crate::grpc::MAX_STATUS {
+        let _ = GRPC_RESPONSES_TOTAL.remove_label_values(&[route, &code.to_string()]);
+    }
+    for outcome in IDEMPOTENT_OUTCOMES {
+        let _ = IDEMPOTENT_DUPLICATES_TOTAL.remove_label_values(&[route, outcome]);
+    }
+    for side in ["client", "upstream"] {
+        let _ = RESPONSE_STALL_MS.remove_label_values(&[route, side]);
+    }
+}
+
+pub fn remove_group_series(route: &str, group: &str) {
+    for class in STATUS_CLASSES {
+        let _ = GROUP_RESPONSES_TOTAL.remove_label_values(&[route, group, class]);
+    }
+}
+
+pub fn remove_upstream_series(route: &str, upstream: &str) {
+    for stage in UPSTREAM_ERROR_STAGES {
+        let _ = UPSTREAM_ERRORS_TOTAL.remove_label_values(&[route, upstream, stage]);
+    }
+    let _ = CIRCUIT_OPEN_TOTAL.remove_label_values(&[route, upstream]);
+    let _ = CIRCUIT_OPEN_STATE.remove_label_values(&[route, upstream]);
+    let _ = UPSTREAM_OVERLOADS_TOTAL.remove_label_values(&[route, upstream]);
+    let _ = UPSTREAM_EJECTIONS_TOTAL.remove_label_values(&[route, upstream]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn has_series(name: &str, route: &str) -> bool {
+        prometheus::gather()
+            .iter()
+            .filter(|family| family.name() == name)
+            .flat_map(|family| family.get_metric())
+            .any(|metric| {
+                metric
+                    .get_label()
+                    .iter()
+                    .any(|label| label.name() == "route" && label.value() == route)
+            })
+    }
+
+    #[test]
+    fn groups_statuses_into_classes() {
+        assert_eq!(status_class(101), "1xx");
+        assert_eq!(status_class(204), "2xx");
+        assert_eq!(status_class(308), "3xx");
+        assert_eq!(status_class(499), "4xx");
+        assert_eq!(status_class(503), "5xx");
+        assert_eq!(status_class(0), "other");
+        assert_eq!(status_class(600), "other");
+    }
+
+    #[test]
+    fn removed_series_disappear_from_gather() {
+        observe_request("metrics-test-route", "", 200, 1.0);
+        set_circuit_state("metrics-test-route", "127.0.0.1:1", true);
+        observe_response_stall("metrics-test-route", 5.0, 0.0);
+        assert!(has_series("prx_requests_total", "metrics-test-route"));
+        assert!(has_series("prx_responses_total", "metrics-test-route"));
+        assert!(has_series("prx_response_stall_ms", "metrics-test-route"));
+
+        remove_route_series("metrics-test-route", "");
+        remove_upstream_series("metrics-test-route", "127.0.0.1:1");
+        assert!(!has_series("prx_requests_total", "metrics-test-route"));
+        assert!(!has_series("prx_responses_total", "metrics-test-route"));
+        assert!(!has_series("prx_request_latency_ms", "metrics-test-route"));
+        assert!(!has_series("prx_response_stall_ms", "metrics-test-route"));
+        assert!(!has_series(
+            "prx_upstream_circuit_open",
+            "metrics-test-route"
+        ));
+    }
+}
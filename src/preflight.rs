@@ -0,0 +1,229 @@
+use std::{
+    collections::HashMap,
+    sync::{Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use http::{Method, header};
+use pingora::http::{RequestHeader, ResponseHeader};
+
+use crate::{config::PreflightCacheConfig, purge::Purge};
+
+/// Upstream answers to CORS preflights of one route, keyed by everything the
+/// answer may depend on. Kept across reloads that leave the route unchanged.
+#[derive(Debug)]
+pub struct PreflightCache {
+    ttl: Duration,
+    max_entries: usize,
+    entries: Mutex<HashMap<String, Entry>>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    response: ResponseHeader,
+    expires_at: Instant,
+}
+
+impl PreflightCache {
+    pub fn from_config(config: &PreflightCacheConfig) -> Self {
+        Self {
+            ttl: Duration::from_millis(config.ttl_ms),
+            max_entries: config.max_entries,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Cache key of a CORS preflight; `None` for any other request.
+    pub fn key(request: &RequestHeader) -> Option<String> {
+        if request.method != Method::OPTIONS {
+            return None;
+        }
+        let value = |name| {
+            request
+                .headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+        };
+        let origin = value(header::ORIGIN)?;
+        let method = value(header::ACCESS_CONTROL_REQUEST_METHOD)?;
+        let headers = value(header::ACCESS_CONTROL_REQUEST_HEADERS).unwrap_or_default();
+        let host = value(header::HOST).unwrap_or_default();
+        // Fields are joined by a byte that cannot appear in header values.
+        // The path comes first and keeps its case, see `key_path`.
+        Some(format!(
+            "{}\n{}",
+            request.uri.path(),
+            [host, origin, method, headers]
+                .join("\n")
+                .to_ascii_lowercase()
+        ))
+    }
+
+    pub fn get(&self, key: &str) -> Option<ResponseHeader> {
+        self.get_at(key, Instant::now())
+    }
+
+    /// Stores a successful preflight answer for the route's TTL, or for its
+    /// `Access-Control-Max-Age` when that is shorter.
+    pub fn insert(&self, key: String, response: &ResponseHeader) {
+        self.insert_at(key, response, Instant::now());
+    }
+
+    pub fn reset(&self) {
+        self.entries().clear();
+    }
+
+    /// Drops the answers `purge` matches and returns how many there were.
+    pub fn purge(&self, purge: &Purge) -> usize {
+        let mut entries = self.entries();
+        let before = entries.len();
+        entries.retain(|key, entry| !purge.matches(key_path(key), &entry.response));
+        before - entries.len()
+    }
+
+    fn get_at(&self, key: &str, now: Instant) -> Option<ResponseHeader> {
+        let entries = self.entries();
+        let entry = entries.get(key).filter(|entry| entry.expires_at > now)?;
+        Some(entry.response.clone())
+    }
+
+    fn insert_at(&self, key: String, response: &ResponseHeader, now: Instant) {
+        if !response.status.is_success() {
+            return;
+        }
+        let max_age = response
+            .headers
+            .get(header::ACCESS_CONTROL_MAX_AGE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.trim().parse::<u64>().ok())
+            .map(Duration::from_secs);
+        let ttl = max_age.map_or(self.ttl, |max_age| max_age.min(self.ttl));
+        if ttl.is_zero() {
+            return;
+        }
+
+        let mut response = response.clone();
+        // Cached answers are replayed without a body.
+        response.remove_header(&header::TRANSFER_ENCODING);
+        let _ = response.insert_header(header::CONTENT_LENGTH, 0);
+
+        let mut entries = self.entries();
+        if entries.len() >= self.max_entries && !entries.contains_key(&key) {
+            entries.retain(|_, entry| entry.expires_at > now);
+            if entries.len() >= self.max_entries {
+                return;
+            }
+        }
+        entries.insert(
+            key,
+            Entry {
+                response,
+                expires_at: now + ttl,
+            },
+        );
+    }
+
+    fn entries(&self) -> MutexGuard<'_, HashMap<String, Entry>> {
+        self.entries
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+fn key_path(key: &str) -> &str {
+    key.split('\n').next().unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn preflight(origin: &str) -> RequestHeader {
+        let mut request = RequestHeader::build("OPTIONS", b"/api/items", None).expect("request");
+        request.insert_header("host", "app.local").expect("host");
+        request.insert_header("origin", origin).expect("origin");
+        request
+            .insert_header("access-control-request-method", "PUT")
+            .expect("method");
+        request
+    }
+
+    fn answer(status: u16, max_age: Option<&str>) -> ResponseHeader {
+        let mut response = ResponseHeader::build(status, None).expect("response");
+        response
+            .insert_header("access-control-allow-origin", "https://a.example")
+            .expect("allow origin");
+        if let Some(max_age) = max_age {
+            response
+                .insert_header("access-control-max-age", max_age)
+                .expect("max age");
+        }
+        response
+    }
+
+    #[test]
+    fn keys_only_cors_preflights_by_origin_path_and_headers() {
+        let key = PreflightCache::key(&preflight("https://a.example")).expect("preflight");
+        assert_ne!(
+            PreflightCache::key(&preflight("https://b.example")),
+            Some(key.clone())
+        );
+        let mut with_headers = preflight("https://a.example");
+        with_headers
+            .insert_header("access-control-request-headers", "x-token")
+            .expect("headers");
+        assert_ne!(PreflightCache::key(&with_headers), Some(key));
+
+        let plain = RequestHeader::build("OPTIONS", b"/api/items", None).expect("request");
+        assert_eq!(PreflightCache::key(&plain), None);
+    }
+
+    #[test]
+    fn caches_successful_answers_for_the_shorter_of_ttl_and_max_age() {
+        let cache = PreflightCache::from_config(&PreflightCacheConfig {
+            ttl_ms: 60_000,
+            max_entries: 1,
+        });
+        let now = Instant::now();
+        cache.insert_at("a".to_string(), &answer(204, Some("10")), now);
+        let cached = cache.get_at("a", now).expect("cached");
+        assert_eq!(cached.status.as_u16(), 204);
+        assert_eq!(
+            cached.headers.get("content-length").map(|v| v.as_bytes()),
+            Some(&b"0"[..])
+        );
+        assert!(cache.get_at("a", now + Duration::from_secs(11)).is_none());
+
+        // Full: only an expired entry makes room.
+        cache.insert_at("b".to_string(), &answer(204, None), now);
+        assert!(cache.get_at("b", now).is_none());
+        cache.insert_at(
+            "b".to_string(),
+            &answer(204, None),
+            now + Duration::from_secs(11),
+        );
+        assert!(cache.get_at("b", now + Duration::from_secs(11)).is_some());
+
+        cache.insert_at("c".to_string(), &answer(403, None), now);
+        assert!(cache.get_at("c", now).is_none());
+    }
+
+    #[test]
+    fn purges_answers_by_path_and_tag() {
+        let cache = PreflightCache::from_config(&PreflightCacheConfig::default());
+        let mut assets = preflight("https://a.example");
+        assets.set_uri("/Assets/app.js".parse().expect("uri"));
+        let mut tagged = answer(204, None);
+        tagged
+            .insert_header("x-cache-tags", "release-42")
+            .expect("tags");
+        cache.insert(PreflightCache::key(&assets).expect("preflight"), &tagged);
+        let api_key = PreflightCache::key(&preflight("https://a.example")).expect("preflight");
+        cache.insert(api_key.clone(), &answer(204, None));
+
+        assert_eq!(cache.purge(&Purge::new(Some("/assets/*"), "")), 0);
+        assert_eq!(cache.purge(&Purge::new(None, "release-42")), 1);
+        assert_eq!(cache.purge(&Purge::new(Some("/api/*"), "")), 1);
+        assert!(cache.get(&api_key).is_none());
+    }
+}
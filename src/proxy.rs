@@ -1,53 +1,233 @@
 use std::{
+    net::SocketAddr,
     sync::Arc,
     time::{Duration, Instant},
 };
 
 use arc_swap::ArcSwap;
 use async_trait::async_trait;
-use bytes::Bytes;
-use pingora::prelude::*;
+use bytes::{Bytes, BytesMut};
+use once_cell::sync::Lazy;
+use pingora::{
+    connectors::l4::BindTo, prelude::*, protocols::Digest, proxy::FailToProxy,
+    upstreams::peer::PeerOptions,
+};
+use tokio::net::TcpSocket;
 use tracing::{debug, error, info, warn};
 
+use crate::blocklist::Blocklist;
+use crate::config::{
+    AccessLogFieldsConfig, ForwardedHeadersPolicy, HashAffinity, IdempotencyInFlight, LbStrategy,
+    ResponseViolationAction, ServerConfig,
+};
+use crate::drain::{self, InFlight};
+use crate::forwarded::{self, ClientHop};
+use crate::grpc;
+use crate::idempotency::{self, Begin, IdempotencyGuard};
+use crate::load::Load;
 use crate::metrics;
-use crate::runtime::{RuntimeConfig, hash_key, normalize_host};
+use crate::preflight::PreflightCache;
+use crate::proxy_protocol::{self, HeaderConnect};
+use crate::reputation::{Reputation, ReputationVerdict};
+use crate::request_headers;
+use crate::response_validation::REJECTED_RESPONSE_BODY;
+use crate::runtime::{
+    Egress, HostHeader, RouteInFlight, RuntimeConfig, UpstreamFailure, UpstreamRequest,
+    UpstreamRuntime, WebSocketTunnel, hash_key, normalize_host,
+};
+#[cfg(unix)]
+use crate::shared_state::SharedState;
+use crate::strict::StrictHttp;
+use crate::throttle::RequestThrottle;
+use crate::unmatched::{self, Unmatched};
 
 pub struct PrxProxy {
     active_config: Arc<ArcSwap<RuntimeConfig>>,
-    access_log: bool,
     health_path: String,
     ready_path: String,
+    load_path: Option<String>,
+    upstream_write_buffer_bytes: Option<usize>,
+    downstream: DownstreamLimits,
+    strict_http: Option<StrictHttp>,
+    blocklist: Option<Arc<ArcSwap<Blocklist>>>,
+    #[cfg(unix)]
+    shared_state: Option<Arc<SharedState>>,
+    reputation: Option<Arc<Reputation>>,
+    /// `[[app]]` this proxy serves; `None` for the main proxy.
+    app: Option<String>,
+    probes: Probes,
+}
+
+/// Whether a proxy answers the health, ready and load paths.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Probes {
+    Answer,
+    /// Leaves them to the routes.
+    Skip,
+    /// Answers nothing else, for a listener of its own.
+    Only,
+}
+
+/// Keepalive limits of HTTP/1 client connections; HTTP/2 connections are
+/// recycled by their own GOAWAY handling.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DownstreamLimits {
+    pub idle_timeout_seconds: Option<u64>,
+    pub max_requests_per_connection: Option<u64>,
+    pub max_connection_lifetime: Option<Duration>,
+}
+
+impl DownstreamLimits {
+    pub fn from_config(server: &ServerConfig) -> Self {
+        Self {
+            idle_timeout_seconds: server.downstream_idle_timeout_seconds,
+            max_requests_per_connection: server.downstream_max_requests_per_connection,
+            max_connection_lifetime: server
+                .downstream_max_connection_lifetime_ms
+                .map(Duration::from_millis),
+        }
+    }
+
+    fn apply(&self, session: &mut Session) {
+        // Clients that asked for `Connection: close` already have keepalive off.
+        if session.get_keepalive().is_none() {
+            return;
+        }
+        let served = session.requests_served() + 1;
+        let over_requests = self
+            .max_requests_per_connection
+            .is_some_and(|limit| served >= limit);
+        let over_lifetime = self.max_connection_lifetime.is_some_and(|limit| {
+            session
+                .digest()
+                .and_then(|digest| digest.timing_digest.first())
+                .and_then(|timing| timing.as_ref())
+                .and_then(|timing| timing.established_ts.elapsed().ok())
+                .is_some_and(|age| age >= limit)
+        });
+        if over_requests || over_lifetime {
+            session.set_keepalive(None);
+        } else if let Some(seconds) = self.idle_timeout_seconds {
+            session.set_keepalive(Some(seconds));
+        }
+    }
 }
 
 impl PrxProxy {
     pub fn new(
         active_config: Arc<ArcSwap<RuntimeConfig>>,
-        access_log: bool,
         health_path: String,
         ready_path: String,
     ) -> Self {
         Self {
             active_config,
-            access_log,
             health_path,
             ready_path,
+            load_path: None,
+            upstream_write_buffer_bytes: None,
+            downstream: DownstreamLimits::default(),
+            strict_http: None,
+            blocklist: None,
+            #[cfg(unix)]
+            shared_state: None,
+            reputation: None,
+            app: None,
+            probes: Probes::Answer,
+        }
+    }
+
+    pub fn for_app(mut self, app: &str) -> Self {
+        self.app = Some(app.to_string());
+        self
+    }
+
+    pub fn with_load_path(mut self, path: Option<String>) -> Self {
+        self.load_path = path;
+        self
+    }
+
+    /// Leaves the health, ready and load paths to the routes, for public
+    /// listeners when probes are served elsewhere.
+    pub fn without_probes(mut self) -> Self {
+        self.probes = Probes::Skip;
+        self
+    }
+
+    /// Answers only the health, ready and load paths and `404` for the rest.
+    pub fn probes_only(mut self) -> Self {
+        self.probes = Probes::Only;
+        self
+    }
+
+    pub fn with_upstream_write_buffer(mut self, bytes: Option<usize>) -> Self {
+        self.upstream_write_buffer_bytes = bytes;
+        self
+    }
+
+    pub fn with_downstream_limits(mut self, limits: DownstreamLimits) -> Self {
+        self.downstream = limits;
+        self
+    }
+
+    pub fn with_strict_http(mut self, strict_http: Option<StrictHttp>) -> Self {
+        self.strict_http = strict_http;
+        self
+    }
+
+    pub fn with_blocklist(mut self, blocklist: Option<Arc<ArcSwap<Blocklist>>>) -> Self {
+        self.blocklist = blocklist;
+        self
+    }
+
+    #[cfg(unix)]
+    pub fn with_shared_state(mut self, shared_state: Option<Arc<SharedState>>) -> Self {
+        self.shared_state = shared_state;
+        self
+    }
+
+    pub fn with_reputation(mut self, reputation: Option<Arc<Reputation>>) -> Self {
+        self.reputation = reputation;
+        self
+    }
+
+    /// Puts the stable snapshot back when the rollout of `candidate` sees its
+    /// error rate rise; later reloads stage a fresh rollout as usual.
+    fn record_rollout(&self, candidate: Arc<RuntimeConfig>, served: bool, error: bool) {
+        let Some(rollout) = candidate.rollout() else {
+            return;
+        };
+        if !rollout.record(served, error) {
+            return;
+        }
+        let stable = rollout.stable().clone();
+        let previous = self
+            .active_config
+            .compare_and_swap(&candidate, stable.clone());
+        if Arc::ptr_eq(&previous, &candidate) {
+            error!("config rollout raised the error rate; rolled back to the previous config");
+            candidate.retire_stale(&stable);
         }
     }
 
-    fn should_retry(&self, ctx: &mut RequestCtx) -> bool {
+    fn should_retry(
+        &self,
+        ctx: &mut RequestCtx,
+        failure: UpstreamFailure,
+        method: &http::Method,
+    ) -> bool {
         let Some(snapshot) = &ctx.snapshot else {
             return false;
         };
-        let Some(route_idx) = ctx.route_idx else {
+        let Some(service) = ctx.service_idx.and_then(|idx| snapshot.service(idx)) else {
             return false;
         };
-        let Some(route) = snapshot.route(route_idx) else {
+
+        if !service.retry.retries(failure, Some(method)) {
             return false;
-        };
-        let Some(service) = snapshot.service(route.service_idx) else {
+        }
+        if ctx.deadline_passed() {
             return false;
-        };
-
+        }
         if ctx.retries >= service.max_retries {
             return false;
         }
@@ -59,6 +239,33 @@ impl PrxProxy {
         true
     }
 
+    /// Whether a retry can send the upstream the whole request body again:
+    /// none of it went out yet, or the route keeps bodies of its size.
+    fn body_replayable(session: &Session, ctx: &RequestCtx) -> bool {
+        if ctx.request_body_bytes == 0 {
+            return true;
+        }
+        let cap = ctx
+            .route_idx
+            .and_then(|idx| ctx.snapshot.as_ref()?.route(idx))
+            .and_then(|route| route.retry_body_bytes);
+        cap.is_some_and(|cap| ctx.request_body_bytes <= cap) && !session.retry_buffer_truncated()
+    }
+
+    /// A peer for `upstream` with the connection options every request to
+    /// it shares.
+    fn new_peer(&self, upstream: &UpstreamRuntime, sticky: Option<u64>) -> HttpPeer {
+        let mut peer = match upstream.pick_addr(sticky) {
+            Some(addr) => HttpPeer::new(addr, upstream.tls, upstream.sni.clone()),
+            None => HttpPeer::new(&*upstream.addr, upstream.tls, upstream.sni.clone()),
+        };
+        peer.options.verify_cert = upstream.verify_cert;
+        peer.options.verify_hostname = upstream.verify_hostname;
+        peer.options.alternative_cn = upstream.verify_hostname_as.clone();
+        peer.options.write_buffer_size = self.upstream_write_buffer_bytes;
+        peer
+    }
+
     async fn respond_text(session: &mut Session, status: u16, body: &'static str) -> Result<bool> {
         session
             .respond_error_with_body(status, Bytes::from_static(body.as_bytes()))
@@ -66,7 +273,7 @@ impl PrxProxy {
         Ok(true)
     }
 
-    fn record_upstream_failure(&self, ctx: &mut RequestCtx, stage: &'static str) {
+    fn record_upstream_failure(&self, ctx: &mut RequestCtx, failure: UpstreamFailure) {
         let Some(snapshot) = &ctx.snapshot else {
             return;
         };
@@ -76,7 +283,7 @@ impl PrxProxy {
         let Some(route) = snapshot.route(route_idx) else {
             return;
         };
-        let Some(service) = snapshot.service(route.service_idx) else {
+        let Some(service) = ctx.service_idx.and_then(|idx| snapshot.service(idx)) else {
             return;
         };
         let Some(upstream_idx) = ctx.attempted_upstreams.last().copied() else {
@@ -86,21 +293,137 @@ impl PrxProxy {
             return;
         };
 
-        metrics::inc_upstream_error(route.name.as_str(), upstream.addr.as_str(), stage);
+        metrics::inc_upstream_error(&route.metric_label, &upstream.metric_label, failure.stage());
+        if !service.circuit_breaker.counts(failure) {
+            return;
+        }
         let opened = service.mark_upstream_failure(upstream_idx);
         let is_open = upstream.is_circuit_open();
-        metrics::set_circuit_state(route.name.as_str(), upstream.addr.as_str(), is_open);
+        metrics::set_circuit_state(&route.metric_label, &upstream.metric_label, is_open);
         if opened {
-            metrics::mark_circuit_open(route.name.as_str(), upstream.addr.as_str());
+            metrics::mark_circuit_open(&route.metric_label, &upstream.metric_label);
             warn!(
-                route = route.name.as_str(),
+                route = &*route.name,
                 service = service.name.as_str(),
-                upstream = upstream.addr.as_str(),
+                upstream = &*upstream.addr,
                 "opened circuit breaker for upstream"
             );
         }
     }
 
+    /// Moves the upstream to HTTP/1.1 when an HTTP/2 attempt failed at the
+    /// protocol level. Returns true for such failures: the request is then
+    /// retried on the same upstream without using up a retry, and the upstream
+    /// is not blamed for it.
+    fn record_http2_failure(&self, ctx: &mut RequestCtx, e: &Error) -> bool {
+        if !ctx.http2_fallback
+            || !matches!(
+                e.etype(),
+                ErrorType::H2Error
+                    | ErrorType::InvalidH2
+                    | ErrorType::H2Downgrade
+                    | ErrorType::HandshakeError
+            )
+        {
+            return false;
+        }
+        let Some(snapshot) = &ctx.snapshot else {
+            return false;
+        };
+        let Some(route) = ctx.route_idx.and_then(|idx| snapshot.route(idx)) else {
+            return false;
+        };
+        let Some(upstream) = ctx
+            .service_idx
+            .and_then(|idx| snapshot.service(idx))
+            .and_then(|service| {
+                ctx.attempted_upstreams
+                    .last()
+                    .and_then(|idx| service.upstreams.get(*idx))
+            })
+        else {
+            return false;
+        };
+        if upstream.fall_back_to_http1() {
+            metrics::inc_http2_fallback(&route.metric_label, &upstream.metric_label);
+            warn!(
+                route = &*route.name,
+                upstream = &*upstream.addr,
+                error = %e,
+                "upstream failed to speak HTTP/2; falling back to HTTP/1.1"
+            );
+        }
+        ctx.attempted_upstreams.pop();
+        true
+    }
+
+    fn record_upstream_overload(&self, ctx: &RequestCtx, response: &ResponseHeader) {
+        let Some(snapshot) = &ctx.snapshot else {
+            return;
+        };
+        let Some(route) = ctx.route_idx.and_then(|idx| snapshot.route(idx)) else {
+            return;
+        };
+        let Some(service) = ctx.service_idx.and_then(|idx| snapshot.service(idx)) else {
+            return;
+        };
+        let Some(upstream) = ctx
+            .attempted_upstreams
+            .last()
+            .and_then(|idx| service.upstreams.get(*idx))
+        else {
+            return;
+        };
+        let breaker = &service.circuit_breaker;
+        if !breaker.signals_overload(response.status.as_u16(), &response.headers) {
+            return;
+        }
+        metrics::inc_upstream_overload(&route.metric_label, &upstream.metric_label);
+        if upstream.mark_overloaded(breaker.overload_ms) {
+            info!(
+                route = &*route.name,
+                service = service.name.as_str(),
+                upstream = &*upstream.addr,
+                "upstream signaled overload; reducing its weight"
+            );
+        }
+    }
+
+    fn record_outlier_sample(&self, ctx: &mut RequestCtx, failed: bool) {
+        let Some(started_at) = ctx.attempt_started_at.take() else {
+            return;
+        };
+        let Some(snapshot) = &ctx.snapshot else {
+            return;
+        };
+        let Some(route) = ctx.route_idx.and_then(|idx| snapshot.route(idx)) else {
+            return;
+        };
+        let Some(service) = ctx.service_idx.and_then(|idx| snapshot.service(idx)) else {
+            return;
+        };
+        let Some((upstream_idx, upstream)) = ctx
+            .attempted_upstreams
+            .last()
+            .and_then(|idx| Some((*idx, service.upstreams.get(*idx)?)))
+        else {
+            return;
+        };
+        let latency = started_at.elapsed();
+        if service.lb == LbStrategy::Ewma {
+            ctx.attempt_latencies.push((upstream_idx, latency, failed));
+        }
+        if service.record_outlier_sample(upstream_idx, latency, failed) {
+            metrics::inc_upstream_ejection(&route.metric_label, &upstream.metric_label);
+            warn!(
+                route = &*route.name,
+                service = service.name.as_str(),
+                upstream = &*upstream.addr,
+                "ejected outlier upstream"
+            );
+        }
+    }
+
     fn record_upstream_success(&self, ctx: &mut RequestCtx) {
         let Some(snapshot) = &ctx.snapshot else {
             return;
@@ -111,7 +434,7 @@ impl PrxProxy {
         let Some(route) = snapshot.route(route_idx) else {
             return;
         };
-        let Some(service) = snapshot.service(route.service_idx) else {
+        let Some(service) = ctx.service_idx.and_then(|idx| snapshot.service(idx)) else {
             return;
         };
         let Some(upstream_idx) = ctx.attempted_upstreams.last().copied() else {
@@ -122,22 +445,78 @@ impl PrxProxy {
         };
 
         service.mark_upstream_success(upstream_idx);
-        metrics::set_circuit_state(route.name.as_str(), upstream.addr.as_str(), false);
+        metrics::set_circuit_state(&route.metric_label, &upstream.metric_label, false);
     }
 }
 
+static HEALTH_ROUTE: Lazy<Arc<str>> = Lazy::new(|| Arc::from("health"));
+static READY_ROUTE: Lazy<Arc<str>> = Lazy::new(|| Arc::from("ready"));
+static LOAD_ROUTE: Lazy<Arc<str>> = Lazy::new(|| Arc::from("load"));
+static NO_ROUTE: Lazy<Arc<str>> = Lazy::new(|| Arc::from("no_route"));
+static UNKNOWN_ROUTE: Lazy<Arc<str>> = Lazy::new(|| Arc::from("unknown"));
+
+/// Body of the 504 sent when a route's `timeout_ms` runs out, so clients can
+/// tell it from an upstream's own 504.
+const ROUTE_TIMEOUT_BODY: &str = "prx: route timeout_ms exceeded\n";
+
 pub struct RequestCtx {
     started_at: Instant,
     snapshot: Option<Arc<RuntimeConfig>>,
+    // The snapshot being rolled out and whether it served this request.
+    rollout: Option<(Arc<RuntimeConfig>, bool)>,
     route_idx: Option<usize>,
     service_idx: Option<usize>,
     attempted_upstreams: Vec<usize>,
     retries: usize,
+    // Set while a retried upstream status unwinds through `error_while_proxy`.
+    retrying_status: bool,
+    // When the current attempt went to its upstream, until its outcome is
+    // recorded for outlier detection.
+    attempt_started_at: Option<Instant>,
+    // Upstream, latency and failure of each finished attempt, fed into
+    // `lb = "ewma"` averages once the request is logged.
+    attempt_latencies: Vec<(usize, Duration, bool)>,
+    // When the route's `timeout_ms` runs out, and whether it did.
+    deadline: Option<Instant>,
+    timed_out: bool,
     hash_seed: Option<u64>,
     host: String,
-    path: String,
-    route_name: Option<String>,
-    upstream_addr: Option<String>,
+    // Shared with the runtime snapshot so per-request bookkeeping does not copy names.
+    route_name: Option<Arc<str>>,
+    upstream_addr: Option<Arc<str>>,
+    retire_upstream_connection: bool,
+    // Whether the current attempt got a connection, pooled or new.
+    upstream_connected: bool,
+    upstream_request: Option<UpstreamRequest>,
+    /// Set when HTTP/2 was offered to an upstream that may fall back to HTTP/1.1.
+    http2_fallback: bool,
+    throttle: Option<RequestThrottle>,
+    max_response_bytes: Option<u64>,
+    response_body_bytes: u64,
+    max_request_body_bytes: Option<u64>,
+    request_body_bytes: u64,
+    /// Group of a route with groups; `None` for the route's own service.
+    group: Option<Arc<str>>,
+    // When the last response chunk was handed on, and the downstream body
+    // write time at that point; unset until the upstream answered.
+    response_mark: Option<(Instant, Duration)>,
+    upstream_stall: Duration,
+    route_in_flight: Option<RouteInFlight>,
+    /// Set for CORS preflights the route caches but had no answer for.
+    preflight_key: Option<String>,
+    /// Held while this request owns its idempotency key.
+    idempotency: Option<IdempotencyGuard>,
+    /// `grpc-status` of the upstream answer, from its headers or trailers.
+    grpc_status: Option<u8>,
+    /// Held once the upstream accepted an upgrade.
+    websocket: Option<WebSocketTunnel>,
+    /// Sent instead of the upstream body when a `status_map` entry has one.
+    replacement_body: Option<Bytes>,
+    /// `tag` verdict of the client IP's reputation lookup.
+    reputation_tag: Option<String>,
+    /// Kept out of request metrics, access logs and rate limits.
+    noise: bool,
+    _in_flight: InFlight,
 }
 
 impl Default for RequestCtx {
@@ -145,19 +524,57 @@ impl Default for RequestCtx {
         Self {
             started_at: Instant::now(),
             snapshot: None,
+            rollout: None,
             route_idx: None,
             service_idx: None,
             attempted_upstreams: Vec::new(),
             retries: 0,
+            retrying_status: false,
+            attempt_started_at: None,
+            attempt_latencies: Vec::new(),
+            deadline: None,
+            timed_out: false,
             hash_seed: None,
             host: String::new(),
-            path: String::new(),
             route_name: None,
             upstream_addr: None,
+            retire_upstream_connection: false,
+            upstream_connected: false,
+            upstream_request: None,
+            http2_fallback: false,
+            throttle: None,
+            max_response_bytes: None,
+            response_body_bytes: 0,
+            max_request_body_bytes: None,
+            request_body_bytes: 0,
+            group: None,
+            response_mark: None,
+            upstream_stall: Duration::ZERO,
+            route_in_flight: None,
+            preflight_key: None,
+            idempotency: None,
+            grpc_status: None,
+            websocket: None,
+            replacement_body: None,
+            reputation_tag: None,
+            noise: false,
+            _in_flight: InFlight::start(),
         }
     }
 }
 
+impl RequestCtx {
+    /// Time left before the route's `timeout_ms` runs out; `None` without one.
+    fn time_left(&self) -> Option<Duration> {
+        self.deadline
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+
+    fn deadline_passed(&self) -> bool {
+        self.time_left().is_some_and(|left| left.is_zero())
+    }
+}
+
 #[async_trait]
 impl ProxyHttp for PrxProxy {
     type CTX = RequestCtx;
@@ -167,61 +584,401 @@ impl ProxyHttp for PrxProxy {
     }
 
     async fn request_filter(&self, session: &mut Session, ctx: &mut Self::CTX) -> Result<bool> {
-        let snapshot = self.active_config.load_full();
+        let active = self.active_config.load_full();
+        let snapshot = active.for_request();
+        if active.rollout().is_some() {
+            ctx.rollout = Some((active.clone(), Arc::ptr_eq(&snapshot, &active)));
+        }
         ctx.snapshot = Some(snapshot.clone());
+        self.downstream.apply(session);
+        if drain::is_draining() {
+            // HTTP/1 answers `Connection: close`; HTTP/2 already got GOAWAY.
+            session.set_keepalive(None);
+        }
+
+        if let Some(violation) = self.strict_http.as_ref().and_then(|strict| {
+            strict.check(
+                session.req_header(),
+                session.content_length_with_transfer_encoding(),
+            )
+        }) {
+            metrics::inc_request_rejected(violation.reason());
+            warn!(
+                reason = violation.reason(),
+                path = %session.req_header().uri.path(),
+                "rejected malformed request"
+            );
+            // The rest of the connection cannot be trusted to be framed the
+            // way the client meant it.
+            session.set_keepalive(None);
+            session.respond_error(400).await?;
+            return Ok(true);
+        }
+
+        if let Some(blocked) = self.blocklist.as_ref().and_then(|blocklist| {
+            let client_ip = session
+                .client_addr()
+                .and_then(|addr| addr.as_inet())
+                .map(|addr| addr.ip());
+            blocklist
+                .load()
+                .check(client_ip, session.req_header().uri.path())
+        }) {
+            metrics::inc_request_rejected(blocked.reason());
+            debug!(
+                reason = blocked.reason(),
+                path = %session.req_header().uri.path(),
+                "rejected blocklisted request"
+            );
+            session.respond_error(403).await?;
+            return Ok(true);
+        }
 
         let req_header = session.req_header();
-        let host = req_header
+        ctx.host = req_header
             .headers
             .get("host")
             .and_then(|val| val.to_str().ok())
             .map(normalize_host)
             .unwrap_or_else(|| "localhost".to_string());
-        let path = req_header.uri.path().to_string();
+        let path = req_header.uri.path();
+        ctx.hash_seed = Some(hash_key(&[ctx.host.as_str(), path]));
+        let builtin = self.probes != Probes::Skip
+            && (path == self.health_path
+                || path == self.ready_path
+                || self.load_path.as_deref() == Some(path));
+        ctx.noise = snapshot.is_noise(path, builtin);
 
-        ctx.host = host;
-        ctx.path = path;
-        ctx.hash_seed = Some(hash_key(&[ctx.host.as_str(), ctx.path.as_str()]));
-
-        if ctx.path == self.health_path {
-            ctx.route_name = Some("health".to_string());
+        if !builtin && self.probes == Probes::Only {
+            session.respond_error(404).await?;
+            return Ok(true);
+        }
+        if builtin && path == self.health_path {
+            ctx.route_name = Some(HEALTH_ROUTE.clone());
             return Self::respond_text(session, 200, "ok\n").await;
         }
-        if ctx.path == self.ready_path {
-            let ready = snapshot.is_ready();
-            ctx.route_name = Some("ready".to_string());
-            if ready {
+        if builtin && path == self.ready_path {
+            ctx.route_name = Some(READY_ROUTE.clone());
+            if drain::is_draining() {
+                return Self::respond_text(session, 503, "draining\n").await;
+            }
+            if snapshot.is_ready() {
                 return Self::respond_text(session, 200, "ready\n").await;
             }
             return Self::respond_text(session, 503, "not_ready\n").await;
         }
+        if builtin && self.load_path.as_deref() == Some(path) {
+            ctx.route_name = Some(LOAD_ROUTE.clone());
+            // Not counting this request itself.
+            let in_flight = (metrics::in_flight() - 1).max(0) as u64;
+            let body = serde_json::to_vec(&Load::measure(&snapshot, in_flight))
+                .map(Bytes::from)
+                .unwrap_or_default();
+            let mut response = ResponseHeader::build(200, Some(3))?;
+            response.insert_header(http::header::CONTENT_TYPE, "application/json")?;
+            response.insert_header(http::header::CONTENT_LENGTH, body.len())?;
+            response.insert_header(http::header::CACHE_CONTROL, "no-store")?;
+            session
+                .write_response_header(Box::new(response), false)
+                .await?;
+            session.write_response_body(Some(body), true).await?;
+            return Ok(true);
+        }
+
+        // The count includes this request.
+        if let Some(limit) = snapshot.max_concurrent_requests()
+            && metrics::in_flight() > limit as i64
+        {
+            debug!(limit, "server at max_concurrent_requests; shedding request");
+            session.respond_error(503).await?;
+            return Ok(true);
+        }
+
+        ctx.route_idx =
+            snapshot.select_route(self.app.as_deref(), &ctx.host, path, &req_header.headers);
+        if !Arc::ptr_eq(&snapshot, &active)
+            && let Some(shadow) = active.shadow()
+        {
+            let shadow_idx =
+                active.select_route(self.app.as_deref(), &ctx.host, path, &req_header.headers);
+            let stable_target = route_target(&snapshot, ctx.route_idx);
+            let shadow_target = route_target(&active, shadow_idx);
+            let diverged = stable_target != shadow_target;
+            shadow.record(diverged);
+            if diverged {
+                metrics::inc_shadow_route_divergence(
+                    stable_target.0,
+                    stable_target.1,
+                    shadow_target.0,
+                    shadow_target.1,
+                );
+                debug!(
+                    route = stable_target.0,
+                    shadow_route = shadow_target.0,
+                    host = %ctx.host,
+                    path = %path,
+                    "config under shadow evaluation routes request differently"
+                );
+            }
+        }
 
-        ctx.route_idx = snapshot.select_route(&ctx.host, &ctx.path);
+        let unmatched = match ctx.route_idx.and_then(|idx| snapshot.route(idx)) {
+            None => Some(Unmatched::NoRoute),
+            Some(route) if route.is_default && !route.matches_host(&ctx.host) => {
+                Some(Unmatched::DefaultRoute)
+            }
+            Some(_) => None,
+        };
+        // A host another route names is known, whatever its path.
+        if let Some(reason) = unmatched
+            && (reason == Unmatched::NoRoute
+                || !snapshot.covers_host(self.app.as_deref(), &ctx.host))
+        {
+            let tls = session
+                .digest()
+                .is_some_and(|digest| digest.ssl_digest.is_some());
+            unmatched::record(&ctx.host, tls, reason);
+        }
 
         if let Some(route_idx) = ctx.route_idx {
             if let Some(route) = snapshot.route(route_idx) {
-                ctx.service_idx = Some(route.service_idx);
+                let client_ip = session
+                    .client_addr()
+                    .and_then(|addr| addr.as_inet())
+                    .map(|addr| addr.ip());
+                let group = route.select_group(client_ip, &req_header.headers);
+                ctx.service_idx = Some(group.map_or(route.service_idx, |group| group.service_idx));
+                ctx.group = group.map(|group| group.name.clone());
                 ctx.route_name = Some(route.name.clone());
+                ctx.throttle = route.bandwidth.clone().map(RequestThrottle::new);
+                ctx.max_response_bytes = route.max_response_bytes;
+                // A websocket tunnel lasts as long as the connection does.
+                ctx.deadline = route
+                    .timeout
+                    .filter(|_| !session.is_upgrade_req())
+                    .map(|timeout| ctx.started_at + timeout);
                 debug!(
                     route = %route.name,
+                    group = group.map_or("-", |group| &*group.name),
                     host = %ctx.host,
-                    path = %ctx.path,
+                    path = %path,
                     "matched route"
                 );
             }
         } else {
-            ctx.route_name = Some("no_route".to_string());
-            warn!(host = %ctx.host, path = %ctx.path, "no route matched");
+            ctx.route_name = Some(NO_ROUTE.clone());
+            warn!(host = %ctx.host, path = %path, "no route matched");
             session.respond_error(404).await?;
             return Ok(true);
         }
 
+        if let Some(route) = ctx.route_idx.and_then(|idx| snapshot.route(idx))
+            && route.grpc
+            && !session.is_http2()
+        {
+            debug!(route = %route.name, "rejected grpc request that is not HTTP/2");
+            session.respond_error(505).await?;
+            return Ok(true);
+        }
+
+        if let Some(route) = ctx.route_idx.and_then(|idx| snapshot.route(idx))
+            && let Some(limit) = route.max_request_body_bytes
+        {
+            ctx.max_request_body_bytes = Some(limit);
+            if let Some(length) = session
+                .req_header()
+                .headers
+                .get(http::header::CONTENT_LENGTH)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse::<u64>().ok())
+                .filter(|length| *length > limit)
+            {
+                metrics::inc_request_body_too_large(&route.metric_label);
+                debug!(route = %route.name, length, limit, "rejected request body over max_request_body_bytes");
+                // The unread body would otherwise be parsed as the next request.
+                session.set_keepalive(None);
+                session.respond_error(413).await?;
+                return Ok(true);
+            }
+        }
+
+        if let Some(route) = ctx.route_idx.and_then(|idx| snapshot.route(idx))
+            && session.is_upgrade_req()
+        {
+            let Some(idle_timeout) = route.websocket_idle_timeout else {
+                debug!(route = %route.name, "rejected upgrade request on a route without websocket");
+                session.set_keepalive(None);
+                session.respond_error(400).await?;
+                return Ok(true);
+            };
+            // Covers the client side of the tunnel; `upstream_peer` sets the
+            // upstream side.
+            session.set_read_timeout(Some(idle_timeout));
+        }
+
+        if let Some(route) = ctx.route_idx.and_then(|idx| snapshot.route(idx))
+            && let Some(limit) = &route.rate_limit
+            && !ctx.noise
+        {
+            let client_ip = session
+                .client_addr()
+                .and_then(|addr| addr.as_inet())
+                .map(|addr| addr.ip());
+            let key = limit
+                .key()
+                .extract(client_ip, &session.req_header().headers);
+            #[cfg(unix)]
+            let taken = match &self.shared_state {
+                Some(shared) => shared.check(&route.name, limit, &key).await,
+                None => limit.check(&key),
+            };
+            #[cfg(not(unix))]
+            let taken = limit.check(&key);
+            if let Err(wait) = taken {
+                metrics::inc_rate_limited(&route.metric_label);
+                debug!(route = %route.name, key = %key, "rate limited request");
+                let mut response = ResponseHeader::build(429, Some(2))?;
+                // Retry-After is in whole seconds; rounding up means a retry
+                // at that time is admitted.
+                response.insert_header("retry-after", wait.as_secs_f64().ceil().max(1.0) as u64)?;
+                response.insert_header("content-length", 0)?;
+                session
+                    .write_response_header(Box::new(response), true)
+                    .await?;
+                return Ok(true);
+            }
+        }
+
+        if let Some(route) = ctx.route_idx.and_then(|idx| snapshot.route(idx)) {
+            let Some(in_flight) = route.enter() else {
+                debug!(route = %route.name, "route at max_concurrent_requests; shedding request");
+                session.respond_error(503).await?;
+                return Ok(true);
+            };
+            ctx.route_in_flight = Some(in_flight);
+        }
+
+        if let Some(reputation) = &self.reputation
+            && let Some(ip) = session
+                .client_addr()
+                .and_then(|addr| addr.as_inet())
+                .map(|addr| addr.ip())
+        {
+            match reputation.check(ip).await {
+                ReputationVerdict::Allow => {}
+                ReputationVerdict::Deny => {
+                    metrics::inc_request_rejected("reputation");
+                    debug!(%ip, path = %session.req_header().uri.path(), "denied by reputation");
+                    session.respond_error(403).await?;
+                    return Ok(true);
+                }
+                ReputationVerdict::Tag(tag) => ctx.reputation_tag = Some(tag),
+            }
+        }
+
+        if let Some(verdict) = snapshot
+            .waf()
+            .and_then(|waf| waf.inspect(session.req_header()))
+        {
+            metrics::inc_waf_match(verdict.rule_id, verdict.deny_status.is_some());
+            warn!(
+                rule = verdict.rule_id,
+                msg = %verdict.msg,
+                host = %ctx.host,
+                path = %session.req_header().uri.path(),
+                denied = verdict.deny_status.is_some(),
+                "waf rule matched"
+            );
+            if let Some(status) = verdict.deny_status {
+                session.respond_error(status).await?;
+                return Ok(true);
+            }
+        }
+
+        if let Some(cache) = ctx
+            .route_idx
+            .and_then(|idx| snapshot.route(idx))
+            .and_then(|route| route.preflight_cache.as_ref())
+            && let Some(key) = PreflightCache::key(session.req_header())
+        {
+            if let Some(response) = cache.get(&key) {
+                debug!(host = %ctx.host, "answered preflight from cache");
+                session
+                    .write_response_header(Box::new(response), true)
+                    .await?;
+                return Ok(true);
+            }
+            ctx.preflight_key = Some(key);
+        }
+
+        if let Some(route) = ctx.route_idx.and_then(|idx| snapshot.route(idx))
+            && let Some(cache) = &route.idempotency
+            && let Some(key) = cache.key(
+                session.req_header(),
+                session
+                    .client_addr()
+                    .and_then(|addr| addr.as_inet())
+                    .map(|addr| addr.ip()),
+            )
+        {
+            let Some(body) = read_idempotent_body(session).await? else {
+                metrics::inc_request_body_too_large(&route.metric_label);
+                session.respond_error(413).await?;
+                return Ok(true);
+            };
+            let digest = idempotency::body_digest(&body);
+            let mut waited = false;
+            loop {
+                match cache.begin(key.clone(), digest) {
+                    Begin::Owner(guard) => {
+                        ctx.idempotency = Some(guard);
+                        break;
+                    }
+                    Begin::Untracked => break,
+                    Begin::Mismatch => {
+                        metrics::inc_idempotent_duplicate(&route.metric_label, "mismatched");
+                        debug!(route = %route.name, "rejected an idempotency key reused with another body");
+                        session.respond_error(422).await?;
+                        return Ok(true);
+                    }
+                    Begin::Replay(replay) => {
+                        metrics::inc_idempotent_duplicate(&route.metric_label, "replayed");
+                        debug!(route = %route.name, "replayed idempotent response");
+                        let empty = replay.body.is_empty();
+                        session
+                            .write_response_header(Box::new(replay.response.clone()), empty)
+                            .await?;
+                        if !empty {
+                            session
+                                .write_response_body(Some(replay.body.clone()), true)
+                                .await?;
+                        }
+                        return Ok(true);
+                    }
+                    Begin::InFlight(mut done) => {
+                        if cache.in_flight() == IdempotencyInFlight::Reject {
+                            metrics::inc_idempotent_duplicate(&route.metric_label, "rejected");
+                            debug!(route = %route.name, "rejected request with an idempotency key in flight");
+                            session.respond_error(409).await?;
+                            return Ok(true);
+                        }
+                        if !waited {
+                            metrics::inc_idempotent_duplicate(&route.metric_label, "waited");
+                            waited = true;
+                        }
+                        // Errors once the owner finished, which is all we wait for.
+                        let _ = done.changed().await;
+                    }
+                }
+            }
+        }
+
         Ok(false)
     }
 
     async fn upstream_peer(
         &self,
-        _session: &mut Session,
+        session: &mut Session,
         ctx: &mut Self::CTX,
     ) -> Result<Box<HttpPeer>> {
         let snapshot = if let Some(snapshot) = &ctx.snapshot {
@@ -237,11 +994,17 @@ impl ProxyHttp for PrxProxy {
             None => {
                 return Error::e_explain(
                     HTTPStatus(404),
-                    format!("no route matched host={} path={}", ctx.host, ctx.path),
+                    format!(
+                        "no route matched host={} path={}",
+                        ctx.host,
+                        session.req_header().uri.path()
+                    ),
                 );
             }
         };
         ctx.route_idx = Some(route_idx);
+        // Each attempt sends the body through `request_body_filter` from the start.
+        ctx.request_body_bytes = 0;
 
         let Some(route) = snapshot.route(route_idx) else {
             return Error::e_explain(
@@ -250,46 +1013,67 @@ impl ProxyHttp for PrxProxy {
             );
         };
 
-        let Some(service) = snapshot.service(route.service_idx) else {
+        let service_idx = *ctx.service_idx.get_or_insert(route.service_idx);
+        let Some(service) = snapshot.service(service_idx) else {
             return Error::e_explain(
                 InternalError,
                 format!(
-                    "route '{}' references service index {} which is out of bounds",
-                    route.name, route.service_idx
+                    "route '{}' references service index {service_idx} which is out of bounds",
+                    route.name
                 ),
             );
         };
 
-        if ctx.retries > 0 && service.retry_backoff_ms > 0 {
-            tokio::time::sleep(Duration::from_millis(service.retry_backoff_ms)).await;
+        if ctx.retries > 0
+            && let Some(backoff) = service.retry.backoff(ctx.retries)
+        {
+            tokio::time::sleep(ctx.time_left().map_or(backoff, |left| backoff.min(left))).await;
+        }
+        let time_left = ctx.time_left();
+        if time_left.is_some_and(|left| left.is_zero()) {
+            ctx.timed_out = true;
+            return Error::e_explain(HTTPStatus(504), "route timeout_ms exceeded");
         }
 
         let hash_seed = ctx
             .hash_seed
-            .unwrap_or_else(|| hash_key(&[ctx.host.as_str(), ctx.path.as_str()]));
-        let (upstream_idx, upstream) =
-            if let Some(selected) = service.next_upstream(hash_seed, &ctx.attempted_upstreams) {
+            .unwrap_or_else(|| hash_key(&[ctx.host.as_str(), session.req_header().uri.path()]));
+        let select = |attempted: &[usize]| match route.hash_affinity {
+            HashAffinity::Strict => service.next_upstream(hash_seed, attempted),
+            HashAffinity::PreferReuse => service.next_upstream_reusing(hash_seed, attempted),
+        };
+        let (upstream_idx, upstream) = if let Some(selected) = select(&ctx.attempted_upstreams) {
+            selected
+        } else {
+            ctx.attempted_upstreams.clear();
+            if let Some(selected) = select(&ctx.attempted_upstreams) {
                 selected
             } else {
-                ctx.attempted_upstreams.clear();
-                if let Some(selected) = service.next_upstream(hash_seed, &ctx.attempted_upstreams) {
-                    selected
-                } else {
-                    return Error::e_explain(
-                        InternalError,
-                        format!(
-                            "service '{}' (via route '{}') has no selectable upstreams",
-                            service.name, route.name
-                        ),
-                    );
-                }
-            };
+                return Error::e_explain(
+                    InternalError,
+                    format!(
+                        "service '{}' (via route '{}') has no selectable upstreams",
+                        service.name, route.name
+                    ),
+                );
+            }
+        };
         ctx.attempted_upstreams.push(upstream_idx);
         ctx.upstream_addr = Some(upstream.addr.clone());
+        ctx.upstream_connected = false;
+        ctx.upstream_request = Some(upstream.start_request());
 
-        let mut peer = HttpPeer::new(upstream.addr.clone(), upstream.tls, upstream.sni.clone());
-        peer.options.verify_cert = upstream.verify_cert;
-        peer.options.verify_hostname = upstream.verify_hostname;
+        let sticky = (service.lb == LbStrategy::Hash).then_some(hash_seed);
+        let mut peer = self.new_peer(upstream, sticky);
+        ctx.http2_fallback = false;
+        if route.grpc || upstream.uses_http2() {
+            // Plaintext has no ALPN, so HTTP/2 there means prior knowledge.
+            // `h2` upstreams and grpc routes are not offered HTTP/1.1 at all.
+            let fallback = !route.grpc && upstream.falls_back_to_http1();
+            let min = if upstream.tls && fallback { 1 } else { 2 };
+            peer.options.set_http_version(2, min);
+            ctx.http2_fallback = fallback;
+        }
         if let Some(ms) = upstream.connect_timeout_ms {
             peer.options.connection_timeout = Some(Duration::from_millis(ms));
         }
@@ -305,13 +1089,50 @@ impl ProxyHttp for PrxProxy {
         if let Some(ms) = upstream.idle_timeout_ms {
             peer.options.idle_timeout = Some(Duration::from_millis(ms));
         }
+        if let Some(idle_timeout) = route.websocket_idle_timeout
+            && session.is_upgrade_req()
+        {
+            peer.options.read_timeout = Some(idle_timeout);
+        }
+        if let Some(left) = time_left {
+            let cap =
+                |timeout: Option<Duration>| Some(timeout.map_or(left, |timeout| timeout.min(left)));
+            peer.options.connection_timeout = cap(peer.options.connection_timeout);
+            peer.options.total_connection_timeout = cap(peer.options.total_connection_timeout);
+            peer.options.read_timeout = cap(peer.options.read_timeout);
+            peer.options.write_timeout = cap(peer.options.write_timeout);
+        }
+        let egress = route.egress.or(&upstream.egress);
+        if !egress.is_empty() {
+            apply_egress(&mut peer, egress);
+        }
+        if let Some(proxy) = &route.outbound_proxy {
+            peer.options.custom_l4 =
+                Some(proxy.connector(upstream.addr.clone(), peer.options.connection_timeout));
+            // Tunnels through another proxy go to the same address but must
+            // stay in their own pool.
+            peer.group_key ^= proxy.pool_key();
+        }
+        if let Some(version) = upstream.proxy_protocol {
+            let inet = |addr: Option<&pingora::protocols::l4::socket::SocketAddr>| {
+                addr.and_then(|addr| addr.as_inet().copied())
+            };
+            let addrs = inet(session.client_addr()).zip(inet(session.server_addr()));
+            let header = proxy_protocol::encode(version, addrs);
+            let connect = HeaderConnect::new(header, &mut peer.options);
+            peer.group_key ^= connect.pool_key();
+            peer.options.custom_l4 = Some(Arc::new(connect));
+        }
+        #[cfg(feature = "openssl")]
+        upstream.tls_auth.configure(&mut peer);
 
+        ctx.attempt_started_at = Some(Instant::now());
         Ok(Box::new(peer))
     }
 
     async fn upstream_request_filter(
         &self,
-        _session: &mut Session,
+        session: &mut Session,
         upstream_request: &mut RequestHeader,
         ctx: &mut Self::CTX,
     ) -> Result<()> {
@@ -324,7 +1145,7 @@ impl ProxyHttp for PrxProxy {
         let Some(route) = snapshot.route(route_idx) else {
             return Ok(());
         };
-        let Some(service) = snapshot.service(route.service_idx) else {
+        let Some(service) = ctx.service_idx.and_then(|idx| snapshot.service(idx)) else {
             return Ok(());
         };
         let Some(upstream_idx) = ctx.attempted_upstreams.last().copied() else {
@@ -334,68 +1155,583 @@ impl ProxyHttp for PrxProxy {
             return Ok(());
         };
 
-        // Keep Host aligned with SNI when proxying to strict virtual hosts.
-        upstream_request.insert_header("host", upstream.sni.as_str())?;
-        self.record_upstream_success(ctx);
+        let ip = session
+            .client_addr()
+            .and_then(|addr| addr.as_inet())
+            .map(|addr| addr.ip());
+        let trusted = ip.is_some_and(|ip| snapshot.is_trusted_proxy(ip));
+        if route.forwarded_headers != ForwardedHeadersPolicy::Off {
+            let tls = session
+                .digest()
+                .is_some_and(|digest| digest.ssl_digest.is_some());
+            // HTTP/2 clients send the authority instead of a Host header.
+            let host = upstream_request
+                .headers
+                .get("host")
+                .and_then(|value| value.to_str().ok())
+                .or_else(|| {
+                    upstream_request
+                        .uri
+                        .authority()
+                        .map(|authority| authority.as_str())
+                })
+                .map(str::to_string);
+            let hop = ClientHop {
+                ip,
+                proto: if tls { "https" } else { "http" },
+                host: host.as_deref(),
+            };
+            forwarded::apply(route.forwarded_headers, trusted, &hop, upstream_request)?;
+        }
+        if let Some(path) = route.upstream_path(upstream_request.uri.path()) {
+            let path_and_query = match upstream_request.uri.query() {
+                Some(query) => format!("{path}?{query}"),
+                None => path,
+            };
+            let uri = http::Uri::builder()
+                .path_and_query(path_and_query)
+                .build()
+                .or_err(InternalError, "rewritten upstream path is not a valid URI")?;
+            upstream_request.set_uri(uri);
+        }
+        match &upstream.host_header {
+            // Keep Host aligned with SNI when proxying to strict virtual hosts.
+            HostHeader::Sni => upstream_request.insert_header("host", upstream.sni.as_str())?,
+            HostHeader::Fixed(host) => upstream_request.insert_header("host", host.as_str())?,
+            HostHeader::Preserve => {}
+        }
+        forwarded::strip_client_cert(trusted, upstream_request);
+        #[cfg(feature = "openssl")]
+        if let Some(ssl) = session
+            .digest()
+            .and_then(|digest| digest.ssl_digest.as_deref())
+        {
+            crate::tls::forward_client_cert(ssl, upstream_request)?;
+        }
+        if let Some(reputation) = &self.reputation {
+            // Only prx gets to say what the client's reputation is.
+            upstream_request.remove_header(&reputation.tag_header);
+            if let Some(tag) = &ctx.reputation_tag {
+                upstream_request.insert_header(reputation.tag_header.clone(), tag.as_str())?;
+            }
+        }
+        if ctx.retire_upstream_connection {
+            // Asking for close keeps pingora from returning the connection to the pool.
+            upstream_request.insert_header("connection", "close")?;
+        }
+        // Last, so headers added above are covered too.
+        request_headers::normalize(
+            upstream_request,
+            route.header_case,
+            route.merge_duplicate_headers,
+        )?;
         Ok(())
     }
 
-    fn fail_to_connect(
+    async fn upstream_response_filter(
+        &self,
+        session: &mut Session,
+        upstream_response: &mut ResponseHeader,
+        ctx: &mut Self::CTX,
+    ) -> Result<()> {
+        let latency = ctx
+            .attempt_started_at
+            .map(|started_at| started_at.elapsed());
+        ctx.response_mark = Some((Instant::now(), session.body_write_time()));
+        // Set here for trailers-only responses.
+        ctx.grpc_status = grpc::status(&upstream_response.headers);
+        if upstream_response.status == 101
+            && let Some(route) = ctx
+                .snapshot
+                .as_ref()
+                .zip(ctx.route_idx)
+                .and_then(|(snapshot, idx)| snapshot.route(idx))
+                .filter(|route| route.websocket_idle_timeout.is_some())
+        {
+            ctx.websocket = Some(route.open_websocket());
+        }
+        let failure = UpstreamFailure::Status(upstream_response.status.as_u16());
+        let counts = ctx
+            .snapshot
+            .as_ref()
+            .zip(ctx.service_idx)
+            .and_then(|(snapshot, idx)| snapshot.service(idx))
+            .is_some_and(|service| service.circuit_breaker.counts(failure));
+        if counts {
+            self.record_upstream_failure(ctx, failure);
+        } else {
+            self.record_upstream_success(ctx);
+        }
+        self.record_outlier_sample(ctx, upstream_response.status.is_server_error());
+        self.record_upstream_overload(ctx, upstream_response);
+        let retries_statuses = ctx
+            .snapshot
+            .as_ref()
+            .zip(ctx.service_idx)
+            .and_then(|(snapshot, idx)| snapshot.service(idx))
+            .is_some_and(|service| service.retry.retries_statuses());
+        // The response is dropped unseen, so only when another upstream
+        // gets a go; the last attempt's answer goes to the client.
+        if retries_statuses
+            && Self::body_replayable(session, ctx)
+            && self.should_retry(ctx, failure, &session.req_header().method)
+        {
+            ctx.retrying_status = true;
+            return Error::e_explain(
+                ErrorType::HTTPStatus(upstream_response.status.as_u16()),
+                "retrying upstream status",
+            );
+        }
+        if let Some(route) = ctx
+            .snapshot
+            .as_ref()
+            .zip(ctx.route_idx)
+            .and_then(|(snapshot, idx)| snapshot.route(idx))
+            && let Some(validation) = &route.response_validation
+            && let Some(violation) =
+                validation.check(upstream_response, latency.unwrap_or_default())
+        {
+            metrics::inc_response_violation(&route.metric_label, violation.rule());
+            if validation.action != ResponseViolationAction::Count {
+                warn!(
+                    route = &*route.name,
+                    upstream = ctx.upstream_addr.as_deref().unwrap_or("-"),
+                    status = upstream_response.status.as_u16(),
+                    %violation,
+                    "upstream response failed validation"
+                );
+            }
+            if validation.action == ResponseViolationAction::Reject {
+                let body = Bytes::from_static(REJECTED_RESPONSE_BODY.as_bytes());
+                let mut rejected = ResponseHeader::build(502, Some(2))?;
+                rejected.insert_header(http::header::CONTENT_TYPE, "text/plain")?;
+                rejected.insert_header(http::header::CONTENT_LENGTH, body.len())?;
+                *upstream_response = rejected;
+                ctx.replacement_body = Some(body);
+                return Ok(());
+            }
+        }
+        // Mapped after the breaker and overload accounting, which judge the
+        // upstream's own status.
+        if let Some(mapping) = ctx
+            .snapshot
+            .as_ref()
+            .zip(ctx.route_idx)
+            .and_then(|(snapshot, idx)| snapshot.route(idx))
+            .and_then(|route| route.status_mapping(upstream_response.status.as_u16()))
+            .cloned()
+        {
+            upstream_response.set_status(mapping.to)?;
+            for (name, value) in mapping.headers {
+                upstream_response.insert_header(name, value)?;
+            }
+            if let Some(body) = mapping.body {
+                upstream_response.remove_header(&http::header::TRANSFER_ENCODING);
+                upstream_response.remove_header(&http::header::CONTENT_ENCODING);
+                upstream_response.insert_header(http::header::CONTENT_LENGTH, body.len())?;
+                ctx.replacement_body = Some(body);
+            }
+        }
+        Ok(())
+    }
+
+    fn upstream_response_trailer_filter(
+        &self,
+        _session: &mut Session,
+        upstream_trailers: &mut http::HeaderMap,
+        ctx: &mut Self::CTX,
+    ) -> Result<()> {
+        if let Some(code) = grpc::status(upstream_trailers) {
+            ctx.grpc_status = Some(code);
+        }
+        Ok(())
+    }
+
+    async fn connected_to_upstream(
         &self,
         _session: &mut Session,
+        reused: bool,
+        _peer: &HttpPeer,
+        #[cfg(unix)] _fd: std::os::unix::io::RawFd,
+        #[cfg(windows)] _sock: std::os::windows::io::RawSocket,
+        digest: Option<&Digest>,
+        ctx: &mut Self::CTX,
+    ) -> Result<()> {
+        let Some(snapshot) = &ctx.snapshot else {
+            return Ok(());
+        };
+        let Some(upstream) = ctx
+            .service_idx
+            .and_then(|idx| snapshot.service(idx))
+            .zip(ctx.attempted_upstreams.last())
+            .and_then(|(service, idx)| service.upstreams.get(*idx))
+        else {
+            return Ok(());
+        };
+        #[cfg(feature = "openssl")]
+        upstream.tls_auth.check(digest)?;
+
+        let local_addr = digest
+            .and_then(|digest| digest.socket_digest.as_ref())
+            .and_then(|socket| socket.local_addr())
+            .and_then(|addr| addr.as_inet())
+            .copied();
+        let established_at = digest
+            .and_then(|digest| digest.timing_digest.first())
+            .and_then(|timing| timing.as_ref())
+            .map(|timing| timing.established_ts);
+        ctx.retire_upstream_connection =
+            upstream.should_retire_connection(reused, local_addr, established_at);
+        if reused {
+            upstream.take_pooled_connection();
+        }
+        ctx.upstream_connected = true;
+        Ok(())
+    }
+
+    fn fail_to_connect(
+        &self,
+        session: &mut Session,
         _peer: &HttpPeer,
         ctx: &mut Self::CTX,
         mut e: Box<Error>,
     ) -> Box<Error> {
-        self.record_upstream_failure(ctx, "connect");
-        e.set_retry(self.should_retry(ctx));
+        if ctx.deadline_passed() {
+            ctx.timed_out = true;
+            e.set_retry(false);
+            return e;
+        }
+        if self.record_http2_failure(ctx, &e) {
+            e.set_retry(true);
+            return e;
+        }
+        self.record_upstream_failure(ctx, UpstreamFailure::Connect);
+        self.record_outlier_sample(ctx, true);
+        let method = &session.req_header().method;
+        e.set_retry(self.should_retry(ctx, UpstreamFailure::Connect, method));
         e
     }
 
     fn error_while_proxy(
         &self,
         _peer: &HttpPeer,
-        _session: &mut Session,
+        session: &mut Session,
         mut e: Box<Error>,
         ctx: &mut Self::CTX,
         _client_reused: bool,
     ) -> Box<Error> {
+        // Raised by `upstream_response_filter`, which already decided.
+        if std::mem::take(&mut ctx.retrying_status) {
+            e.set_retry(true);
+            return e;
+        }
+        if ctx.deadline_passed() {
+            ctx.timed_out = true;
+            e.set_retry(false);
+            return e;
+        }
         warn!(
             upstream = ctx.upstream_addr.as_deref().unwrap_or("-"),
             error = %e,
             "proxying error"
         );
-        self.record_upstream_failure(ctx, "proxy");
-        e.set_retry(self.should_retry(ctx));
+        if self.record_http2_failure(ctx, &e) {
+            e.set_retry(true);
+            return e;
+        }
+        let failure = match e.etype() {
+            ErrorType::ReadTimedout | ErrorType::WriteTimedout => UpstreamFailure::Timeout,
+            _ => UpstreamFailure::Proxy,
+        };
+        self.record_upstream_failure(ctx, failure);
+        self.record_outlier_sample(ctx, true);
+        let retry = Self::body_replayable(session, ctx)
+            && self.should_retry(ctx, failure, &session.req_header().method);
+        e.set_retry(retry);
         e
     }
 
-    async fn logging(&self, session: &mut Session, e: Option<&Error>, ctx: &mut Self::CTX) {
-        if !self.access_log {
-            return;
+    async fn request_body_filter(
+        &self,
+        _session: &mut Session,
+        body: &mut Option<Bytes>,
+        _end_of_stream: bool,
+        ctx: &mut Self::CTX,
+    ) -> Result<()> {
+        if let Some(chunk) = body.as_ref() {
+            ctx.request_body_bytes += chunk.len() as u64;
+            if let Some(limit) = ctx.max_request_body_bytes
+                && ctx.request_body_bytes > limit
+            {
+                let route = ctx
+                    .route_idx
+                    .and_then(|idx| ctx.snapshot.as_ref()?.route(idx));
+                if let Some(route) = route {
+                    metrics::inc_request_body_too_large(&route.metric_label);
+                }
+                return Error::e_explain(
+                    HTTPStatus(413),
+                    format!("request body exceeded max_request_body_bytes={limit}"),
+                );
+            }
+        }
+        if let (Some(throttle), Some(chunk)) = (&ctx.throttle, body.as_ref()) {
+            let delay = throttle.upload_delay(chunk.len());
+            if !delay.is_zero() {
+                tokio::time::sleep(delay).await;
+            }
+        }
+        Ok(())
+    }
+
+    fn response_body_filter(
+        &self,
+        session: &mut Session,
+        body: &mut Option<Bytes>,
+        end_of_stream: bool,
+        ctx: &mut Self::CTX,
+    ) -> Result<Option<Duration>> {
+        let now = Instant::now();
+        let written = session.body_write_time();
+        if let Some((marked_at, written_then)) = ctx.response_mark {
+            // Since the previous chunk, prx either wrote to the client or
+            // waited for the upstream.
+            let waited = now.saturating_duration_since(marked_at);
+            ctx.upstream_stall += waited.saturating_sub(written.saturating_sub(written_then));
+        }
+        if let Some(replacement) = &ctx.replacement_body {
+            *body = end_of_stream.then(|| replacement.clone());
         }
+        if ctx
+            .upstream_request
+            .as_ref()
+            .is_some_and(UpstreamRequest::drain_expired)
+        {
+            return Error::e_explain(
+                HTTPStatus(502),
+                "upstream removed by a reload did not finish within drain_timeout_s",
+            );
+        }
+        if let Some(chunk) = body.as_ref() {
+            ctx.response_body_bytes += chunk.len() as u64;
+            if let Some(limit) = ctx.max_response_bytes
+                && ctx.response_body_bytes > limit
+            {
+                // Headers are already out; failing here drops the chunk and
+                // closes the downstream connection.
+                return Error::e_explain(
+                    HTTPStatus(502),
+                    format!("upstream response body exceeded max_response_bytes={limit}"),
+                );
+            }
+        }
+        if let Some(guard) = &mut ctx.idempotency {
+            if let Some(chunk) = body.as_ref() {
+                guard.push_body(chunk);
+            }
+            if end_of_stream && let Some(guard) = ctx.idempotency.take() {
+                guard.complete();
+            }
+        }
+        let delay = match (&ctx.throttle, body.as_ref()) {
+            (Some(throttle), Some(chunk)) => {
+                Some(throttle.download_delay(chunk.len())).filter(|delay| !delay.is_zero())
+            }
+            _ => None,
+        };
+        // Throttling is prx holding the chunk back, not the upstream.
+        ctx.response_mark = Some((now + delay.unwrap_or_default(), written));
+        Ok(delay)
+    }
 
+    async fn response_filter(
+        &self,
+        session: &mut Session,
+        upstream_response: &mut ResponseHeader,
+        ctx: &mut Self::CTX,
+    ) -> Result<()> {
+        if let Some(limit) = ctx.max_response_bytes
+            && let Some(length) = upstream_response
+                .headers
+                .get(http::header::CONTENT_LENGTH)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse::<u64>().ok())
+            && length > limit
+        {
+            return Error::e_explain(
+                HTTPStatus(502),
+                format!("upstream response of {length} bytes exceeds max_response_bytes={limit}"),
+            );
+        }
+        if let Some(header) = ctx
+            .snapshot
+            .as_ref()
+            .and_then(|snapshot| ctx.route_idx.and_then(|idx| snapshot.route(idx)))
+            .and_then(|route| route.group_header.as_ref())
+        {
+            upstream_response.insert_header(header.clone(), ctx.group.as_deref().unwrap_or("-"))?;
+        }
+        if let Some(key) = ctx.preflight_key.take()
+            && let Some(cache) = ctx
+                .snapshot
+                .as_ref()
+                .and_then(|snapshot| ctx.route_idx.and_then(|idx| snapshot.route(idx)))
+                .and_then(|route| route.preflight_cache.as_ref())
+        {
+            cache.insert(key, upstream_response);
+        }
+        if let Some(guard) = &mut ctx.idempotency {
+            guard.set_response(upstream_response);
+        }
+        // Covers requests that were already in flight when draining started.
+        if drain::is_draining() {
+            session.set_keepalive(None);
+        }
+        Ok(())
+    }
+
+    async fn fail_to_proxy(
+        &self,
+        session: &mut Session,
+        e: &Error,
+        ctx: &mut Self::CTX,
+    ) -> FailToProxy {
+        if !ctx.timed_out {
+            return default_fail_to_proxy(session, e).await;
+        }
+        if let Some(route) = ctx
+            .route_idx
+            .and_then(|idx| ctx.snapshot.as_ref()?.route(idx))
+        {
+            metrics::inc_request_timeout(&route.metric_label);
+            debug!(route = %route.name, error = %e, "route timeout_ms exceeded");
+        }
+        // Past the response headers all that is left is closing the connection.
+        if session.response_written().is_none() {
+            let body = Bytes::from_static(ROUTE_TIMEOUT_BODY.as_bytes());
+            if let Err(err) = session.respond_error_with_body(504, body).await {
+                error!("failed to send error response to downstream: {err}");
+            }
+        }
+        FailToProxy {
+            error_code: 504,
+            can_reuse_downstream: false,
+        }
+    }
+
+    async fn logging(&self, session: &mut Session, e: Option<&Error>, ctx: &mut Self::CTX) {
         let latency_ms = ctx.started_at.elapsed().as_millis();
-        let summary = session.request_summary();
         let route_name = ctx.route_name.clone().unwrap_or_else(|| {
             ctx.snapshot
                 .as_ref()
                 .and_then(|cfg| ctx.route_idx.and_then(|idx| cfg.route(idx)))
                 .map(|route| route.name.clone())
-                .unwrap_or_else(|| "unknown".to_string())
+                .unwrap_or_else(|| UNKNOWN_ROUTE.clone())
         });
         let status = session
             .response_written()
             .map(|resp| resp.status.as_u16())
             .unwrap_or_else(|| if e.is_some() { 500 } else { 0 });
-        metrics::observe_request(route_name.as_str(), status, latency_ms as f64);
+        if let Some((candidate, served)) = ctx.rollout.take() {
+            self.record_rollout(candidate, served, status >= 500 || e.is_some());
+        }
+        if let Some(service) = ctx
+            .snapshot
+            .as_ref()
+            .and_then(|cfg| ctx.service_idx.and_then(|idx| cfg.service(idx)))
+        {
+            for (upstream_idx, latency, failed) in ctx.attempt_latencies.drain(..) {
+                service.record_upstream_latency(upstream_idx, latency, failed);
+            }
+            // As far as prx can tell, pingora keeps the connection for reuse.
+            if ctx.upstream_connected
+                && e.is_none()
+                && !ctx.retire_upstream_connection
+                && !session.is_upgrade_req()
+                && let Some(upstream) = ctx
+                    .attempted_upstreams
+                    .last()
+                    .and_then(|idx| service.upstreams.get(*idx))
+            {
+                upstream.release_connection();
+            }
+        }
+        if ctx.noise {
+            return;
+        }
+        let route = ctx
+            .snapshot
+            .as_ref()
+            .and_then(|cfg| ctx.route_idx.and_then(|idx| cfg.route(idx)));
+        metrics::observe_request(
+            route.map_or(&route_name, |route| &route.metric_label),
+            route.map_or("", |route| &route.tenant),
+            status,
+            latency_ms as f64,
+        );
+        if let Some(route) = route
+            && !route.groups.is_empty()
+        {
+            metrics::inc_group_response(
+                &route.metric_label,
+                ctx.group.as_deref().unwrap_or("-"),
+                status,
+            );
+        }
+        if let Some(route) = route
+            && route.grpc
+        {
+            metrics::inc_grpc_response(
+                &route.metric_label,
+                ctx.grpc_status
+                    .unwrap_or_else(|| grpc::status_from_http(status)),
+            );
+        }
+        let client_stall_ms = session.body_write_time().as_millis();
+        let upstream_stall_ms = ctx.upstream_stall.as_millis();
+        if let Some(route) = route
+            && ctx.response_mark.is_some()
+        {
+            metrics::observe_response_stall(
+                &route.metric_label,
+                client_stall_ms as f64,
+                upstream_stall_ms as f64,
+            );
+        }
+
+        let observability = ctx
+            .snapshot
+            .as_ref()
+            .map(|cfg| cfg.observability(self.app.as_deref(), ctx.route_idx));
+        if !observability.is_some_and(|obs| obs.should_log_access()) {
+            return;
+        }
 
+        let summary = session.request_summary();
+        let request_bytes = session.body_bytes_read();
+        let response_bytes = session.body_bytes_sent();
+        let extras = ctx
+            .snapshot
+            .as_ref()
+            .map(|cfg| AccessLogExtras::collect(session, cfg.access_log_fields()))
+            .unwrap_or_default();
         if let Some(err) = e {
             error!(
-                route = route_name,
+                route = &*route_name,
                 upstream = ctx.upstream_addr.as_deref().unwrap_or("-"),
+                group = ctx.group.as_deref().unwrap_or("-"),
                 retries = ctx.retries,
                 latency_ms,
+                request_bytes,
+                response_bytes,
+                client_stall_ms,
+                upstream_stall_ms,
+                tls_version = extras.tls_version,
+                tls_cipher = extras.tls_cipher,
+                sni = extras.sni,
+                client_cert_org = extras.client_cert_org,
+                client_cert_serial = extras.client_cert_serial,
+                geo_country = extras.geo_country,
                 error = %err,
                 "{}",
                 summary
@@ -404,22 +1740,177 @@ impl ProxyHttp for PrxProxy {
         }
 
         info!(
-            route = route_name,
+            route = &*route_name,
             upstream = ctx.upstream_addr.as_deref().unwrap_or("-"),
+            group = ctx.group.as_deref().unwrap_or("-"),
             retries = ctx.retries,
             latency_ms,
+            request_bytes,
+            response_bytes,
+            client_stall_ms,
+            upstream_stall_ms,
+            tls_version = extras.tls_version,
+            tls_cipher = extras.tls_cipher,
+            sni = extras.sni,
+            client_cert_org = extras.client_cert_org,
+            client_cert_serial = extras.client_cert_serial,
+            geo_country = extras.geo_country,
             "{}",
             summary
         );
     }
 }
 
+/// Route and service names a request is sent to, compared during a shadow
+/// evaluation.
+fn route_target(snapshot: &RuntimeConfig, route_idx: Option<usize>) -> (&str, &str) {
+    route_idx
+        .and_then(|idx| snapshot.route(idx))
+        .map(|route| {
+            let service = snapshot
+                .service(route.service_idx)
+                .map_or("", |service| service.name.as_str());
+            (&*route.name, service)
+        })
+        .unwrap_or((&NO_ROUTE, ""))
+}
+
+fn apply_egress(peer: &mut HttpPeer, egress: Egress) {
+    // Pooled connections made with other egress settings must not be reused.
+    peer.group_key = egress.pool_key();
+    apply_egress_options(&mut peer.options, egress);
+}
+
+/// The socket side of [`apply_egress`], shared with tcp routes.
+pub(crate) fn apply_egress_options(options: &mut PeerOptions, egress: Egress) {
+    if let Some(ip) = egress.local_addr {
+        let mut bind_to = BindTo::default();
+        bind_to.addr = Some(SocketAddr::new(ip, 0));
+        options.bind_to = Some(bind_to);
+    }
+    // pingora writes the whole ToS / traffic class byte; DSCP is its upper six bits.
+    options.dscp = egress.dscp.map(|dscp| dscp << 2);
+    if let Some(interface) = egress.interface {
+        options.upstream_tcp_sock_tweak_hook = Some(Arc::new(move |socket: &TcpSocket| {
+            bind_device(socket, &interface)
+        }));
+    }
+}
+
+#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+fn bind_device(socket: &TcpSocket, interface: &str) -> Result<()> {
+    socket
+        .bind_device(Some(interface.as_bytes()))
+        .or_err_with(SocketError, || {
+            format!("failed to bind upstream socket to {interface}")
+        })
+}
+
+#[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+fn bind_device(_socket: &TcpSocket, interface: &str) -> Result<()> {
+    Error::e_explain(
+        SocketError,
+        format!("cannot bind upstream socket to {interface}: interfaces are Linux only"),
+    )
+}
+
+/// Fields picked by `[observability.access_log_fields]`; unset ones are left
+/// out of the log line.
+#[derive(Debug, Default)]
+struct AccessLogExtras<'a> {
+    tls_version: Option<&'a str>,
+    tls_cipher: Option<&'a str>,
+    sni: Option<&'a str>,
+    client_cert_org: Option<&'a str>,
+    client_cert_serial: Option<&'a str>,
+    geo_country: Option<&'a str>,
+}
+
+impl<'a> AccessLogExtras<'a> {
+    fn collect(session: &'a Session, fields: &AccessLogFieldsConfig) -> Self {
+        let ssl = session
+            .digest()
+            .and_then(|digest| digest.ssl_digest.as_deref());
+        let client_cert = ssl.filter(|_| fields.client_cert);
+        Self {
+            tls_version: ssl
+                .filter(|_| fields.tls_version)
+                .map(|ssl| ssl.version.as_ref()),
+            tls_cipher: ssl
+                .filter(|_| fields.tls_cipher)
+                .map(|ssl| ssl.cipher.as_ref()),
+            // Only the openssl backend keeps the server name.
+            #[cfg(feature = "openssl")]
+            sni: ssl.filter(|_| fields.sni).and_then(crate::tls::server_name),
+            #[cfg(not(feature = "openssl"))]
+            sni: None,
+            client_cert_org: client_cert.and_then(|ssl| ssl.organization.as_deref()),
+            client_cert_serial: client_cert.and_then(|ssl| ssl.serial_number.as_deref()),
+            geo_country: fields
+                .geo_country_header
+                .as_deref()
+                .and_then(|name| session.req_header().headers.get(name))
+                .and_then(|value| value.to_str().ok()),
+        }
+    }
+}
+
+/// Pingora's own `fail_to_proxy`.
+/// The whole body of a request with an idempotency key, read up front to
+/// compare it with the first request of the key; Pingora sends it upstream
+/// from its retry buffer. `None` when it does not fit there.
+async fn read_idempotent_body(session: &mut Session) -> Result<Option<BytesMut>> {
+    let limit = idempotency::MAX_REQUEST_BODY_BYTES;
+    if session
+        .req_header()
+        .headers
+        .get(http::header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok())
+        .is_some_and(|length| length > limit as u64)
+    {
+        return Ok(None);
+    }
+    session.enable_retry_buffering();
+    let mut body = BytesMut::new();
+    while let Some(chunk) = session.read_request_body().await? {
+        body.extend_from_slice(&chunk);
+        if body.len() > limit {
+            return Ok(None);
+        }
+    }
+    Ok(Some(body))
+}
+
+async fn default_fail_to_proxy(session: &mut Session, e: &Error) -> FailToProxy {
+    let code = match e.etype() {
+        HTTPStatus(code) => *code,
+        _ => match e.esource() {
+            ErrorSource::Upstream => 502,
+            ErrorSource::Downstream => match e.etype() {
+                ErrorType::WriteError | ErrorType::ReadError | ErrorType::ConnectionClosed => 0,
+                _ => 400,
+            },
+            ErrorSource::Internal | ErrorSource::Unset => 500,
+        },
+    };
+    if code > 0 {
+        session.respond_error(code).await.unwrap_or_else(|err| {
+            error!("failed to send error response to downstream: {err}");
+        });
+    }
+    FailToProxy {
+        error_code: code,
+        can_reuse_downstream: false,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::config::{
         CircuitBreakerConfig, LbStrategy, ObservabilityConfig, PrxConfig, RouteConfig,
-        ServerConfig, ServiceConfig, UpstreamConfig,
+        RouteObservabilityConfig, ServerConfig, ServiceConfig, UpstreamConfig,
     };
 
     fn upstream(addr: &str) -> UpstreamConfig {
@@ -427,14 +1918,30 @@ mod tests {
             addr: addr.to_string(),
             tls: false,
             sni: None,
+            verify_hostname_as: None,
+            host: None,
+            preserve_host: false,
+            http2: false,
+            http_version: None,
             weight: 1,
             verify_cert: None,
             verify_hostname: None,
+            client_cert_path: None,
+            client_key_path: None,
+            pinned_cert_sha256: Vec::new(),
             connect_timeout_ms: None,
             total_connect_timeout_ms: None,
             read_timeout_ms: None,
             write_timeout_ms: None,
             idle_timeout_ms: None,
+            max_requests_per_connection: None,
+            max_connection_lifetime_ms: None,
+            health_check: None,
+            egress: Default::default(),
+            proxy_protocol: None,
+            drain_hook: None,
+            resolve_interval_s: None,
+            tier: 0,
         }
     }
 
@@ -448,6 +1955,12 @@ mod tests {
             lb: LbStrategy::RoundRobin,
             max_retries,
             retry_backoff_ms: 0,
+            retry_backoff: None,
+            retry_on: Vec::new(),
+            retry_methods: Vec::new(),
+            outlier_detection: None,
+            slow_start_s: None,
+            discover: None,
             circuit_breaker: CircuitBreakerConfig::default(),
             upstreams,
         }
@@ -456,27 +1969,65 @@ mod tests {
     fn route(name: &str, service: &str) -> RouteConfig {
         RouteConfig {
             name: name.to_string(),
+            app: None,
+            tenant: None,
             service: service.to_string(),
             host: None,
             path_prefix: "/".to_string(),
             methods: Vec::new(),
             is_default: true,
+            observability: RouteObservabilityConfig::default(),
+            bandwidth: None,
+            max_response_bytes: None,
+            max_request_body_bytes: None,
+            retry_body_bytes: None,
+            timeout_ms: None,
+            strip_prefix: false,
+            rewrite_path: None,
+            forwarded_headers: Default::default(),
+            rate_limit: None,
+            max_concurrent_requests: None,
+            preflight_cache: None,
+            idempotency: None,
+            groups: Vec::new(),
+            group_key: None,
+            group_header: None,
+            egress: Default::default(),
+            outbound_proxy: None,
+            protocol: Default::default(),
+            websocket: false,
+            websocket_idle_timeout_ms: None,
+            status_map: Vec::new(),
+            response_validation: None,
+            header_case: Default::default(),
+            merge_duplicate_headers: false,
+            hash_affinity: Default::default(),
+            headers: Default::default(),
         }
     }
 
     fn build_runtime(max_retries: usize, upstream_count: usize) -> Arc<RuntimeConfig> {
-        Arc::new(RuntimeConfig::from_config(PrxConfig {
-            server: ServerConfig::default(),
-            observability: ObservabilityConfig::default(),
-            services: vec![service("default", max_retries, upstream_count)],
-            routes: vec![route("default", "default")],
-        }))
+        Arc::new(
+            RuntimeConfig::from_config(PrxConfig {
+                include: Vec::new(),
+                server: ServerConfig::default(),
+                observability: ObservabilityConfig::default(),
+                apps: Vec::new(),
+                tenants: Vec::new(),
+                admin: None,
+                tcp_routes: Vec::new(),
+                waf: None,
+                reputation: None,
+                services: vec![service("default", max_retries, upstream_count)],
+                routes: vec![route("default", "default")],
+            })
+            .expect("runtime"),
+        )
     }
 
     fn build_proxy(runtime: Arc<RuntimeConfig>) -> PrxProxy {
         PrxProxy::new(
             Arc::new(ArcSwap::new(runtime)),
-            false,
             "/healthz".to_string(),
             "/readyz".to_string(),
         )
@@ -494,9 +2045,9 @@ mod tests {
             ..RequestCtx::default()
         };
 
-        assert!(proxy.should_retry(&mut ctx));
+        assert!(proxy.should_retry(&mut ctx, UpstreamFailure::Connect, &http::Method::GET));
         assert_eq!(ctx.retries, 1);
-        assert!(!proxy.should_retry(&mut ctx));
+        assert!(!proxy.should_retry(&mut ctx, UpstreamFailure::Connect, &http::Method::GET));
     }
 
     #[test]
@@ -512,7 +2063,20 @@ mod tests {
             ..RequestCtx::default()
         };
 
-        assert!(!proxy.should_retry(&mut ctx));
+        assert!(!proxy.should_retry(&mut ctx, UpstreamFailure::Connect, &http::Method::GET));
         assert_eq!(ctx.retries, 0);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn upstream_peers_carry_the_configured_write_buffer() {
+        let runtime = build_runtime(0, 1);
+        let upstream = &runtime.service(0).expect("service").upstreams[0];
+
+        let peer = build_proxy(runtime.clone()).new_peer(upstream, None);
+        assert_eq!(peer.options.write_buffer_size, None);
+
+        let proxy = build_proxy(runtime.clone()).with_upstream_write_buffer(Some(256 * 1024));
+        let peer = proxy.new_peer(upstream, None);
+        assert_eq!(peer.options.write_buffer_size, Some(256 * 1024));
+    }
+}
@@ -0,0 +1,419 @@
+use std::{
+    hash::{DefaultHasher, Hash, Hasher},
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{Arc, LazyLock},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use pingora::{
+    apps::ServerApp,
+    connectors::{L4Connect, TransportConnector},
+    listeners::ConnectionFilter,
+    prelude::*,
+    protocols::{
+        SocketDigest, Stream,
+        l4::{socket::SocketAddr as L4SocketAddr, stream::Stream as L4Stream},
+    },
+    server::ShutdownWatch,
+    upstreams::peer::{BasicPeer, PeerOptions},
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tracing::debug;
+
+use crate::{acl::ConnectionAcl, config::ProxyProtocolVersion};
+
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Longest v1 header, `\r\n` included.
+const MAX_V1_HEADER_BYTES: usize = 107;
+
+/// Largest v2 address block prx reads; addresses plus TLVs it skips.
+const MAX_V2_ADDRESS_BYTES: usize = 2048;
+
+/// How long a new connection may take to send its PROXY header.
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+static CONNECTOR: LazyLock<TransportConnector> = LazyLock::new(|| TransportConnector::new(None));
+
+/// Client and local address a PROXY header carries. `None` stands for the
+/// v1 `UNKNOWN` and v2 `LOCAL` headers, sent by a balancer's own probes.
+pub type ProxiedAddrs = Option<(SocketAddr, SocketAddr)>;
+
+/// The header sent ahead of a connection made on behalf of `addrs`.
+pub fn encode(version: ProxyProtocolVersion, addrs: ProxiedAddrs) -> Vec<u8> {
+    // Both addresses must be of one family; a dual-stack listener can mix them.
+    let addrs = addrs.map(|(source, destination)| match (source, destination) {
+        (SocketAddr::V4(_), SocketAddr::V4(_)) | (SocketAddr::V6(_), SocketAddr::V6(_)) => {
+            (source, destination)
+        }
+        _ => (to_v6(source), to_v6(destination)),
+    });
+    match version {
+        ProxyProtocolVersion::V1 => match addrs {
+            Some((source, destination)) => format!(
+                "PROXY {} {} {} {} {}\r\n",
+                if source.is_ipv4() { "TCP4" } else { "TCP6" },
+                source.ip(),
+                destination.ip(),
+                source.port(),
+                destination.port()
+            )
+            .into_bytes(),
+            None => b"PROXY UNKNOWN\r\n".to_vec(),
+        },
+        ProxyProtocolVersion::V2 => {
+            let mut header = V2_SIGNATURE.to_vec();
+            let mut block = Vec::new();
+            let family = match addrs {
+                Some((SocketAddr::V4(source), SocketAddr::V4(destination))) => {
+                    block.extend_from_slice(&source.ip().octets());
+                    block.extend_from_slice(&destination.ip().octets());
+                    0x11
+                }
+                Some((SocketAddr::V6(source), SocketAddr::V6(destination))) => {
+                    block.extend_from_slice(&source.ip().octets());
+                    block.extend_from_slice(&destination.ip().octets());
+                    0x21
+                }
+                _ => 0x00,
+            };
+            if let Some((source, destination)) = addrs {
+                block.extend_from_slice(&source.port().to_be_bytes());
+                block.extend_from_slice(&destination.port().to_be_bytes());
+            }
+            header.push(if addrs.is_some() { 0x21 } else { 0x20 });
+            header.push(family);
+            header.extend_from_slice(&(block.len() as u16).to_be_bytes());
+            header.extend_from_slice(&block);
+            header
+        }
+    }
+}
+
+fn to_v6(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V4(ip) => SocketAddr::new(ip.to_ipv6_mapped().into(), addr.port()),
+        IpAddr::V6(_) => addr,
+    }
+}
+
+/// Reads the header `version` at the start of `stream`, and nothing past it.
+pub async fn read_header<R: AsyncRead + Unpin>(
+    stream: &mut R,
+    version: ProxyProtocolVersion,
+) -> io::Result<ProxiedAddrs> {
+    match version {
+        ProxyProtocolVersion::V1 => {
+            let mut line = Vec::new();
+            while !line.ends_with(b"\r\n") {
+                if line.len() == MAX_V1_HEADER_BYTES {
+                    return Err(invalid("v1 header is too long"));
+                }
+                line.push(stream.read_u8().await?);
+            }
+            parse_v1(&line[..line.len() - 2])
+        }
+        ProxyProtocolVersion::V2 => {
+            let mut head = [0u8; 16];
+            stream.read_exact(&mut head).await?;
+            if head[..12] != V2_SIGNATURE {
+                return Err(invalid("missing v2 signature"));
+            }
+            let len = u16::from_be_bytes([head[14], head[15]]) as usize;
+            if len > MAX_V2_ADDRESS_BYTES {
+                return Err(invalid("v2 address block is too long"));
+            }
+            let mut block = vec![0u8; len];
+            stream.read_exact(&mut block).await?;
+            parse_v2(head[12], head[13], &block)
+        }
+    }
+}
+
+fn parse_v1(line: &[u8]) -> io::Result<ProxiedAddrs> {
+    let line = std::str::from_utf8(line).map_err(|_| invalid("v1 header is not ASCII"))?;
+    let mut fields = line.split(' ');
+    if fields.next() != Some("PROXY") {
+        return Err(invalid("v1 header must start with PROXY"));
+    }
+    match fields.next() {
+        Some("TCP4" | "TCP6") => {}
+        Some("UNKNOWN") => return Ok(None),
+        _ => return Err(invalid("v1 header has an unknown protocol")),
+    }
+    let fields: Vec<&str> = fields.collect();
+    let [source, destination, source_port, destination_port] = fields[..] else {
+        return Err(invalid("v1 header needs two addresses and two ports"));
+    };
+    let addr = |ip: &str, port: &str| -> io::Result<SocketAddr> {
+        let ip = ip
+            .parse::<IpAddr>()
+            .map_err(|_| invalid("v1 header has an invalid address"))?;
+        let port = port
+            .parse::<u16>()
+            .map_err(|_| invalid("v1 header has an invalid port"))?;
+        Ok(SocketAddr::new(ip, port))
+    };
+    Ok(Some((
+        addr(source, source_port)?,
+        addr(destination, destination_port)?,
+    )))
+}
+
+fn parse_v2(version_command: u8, family: u8, block: &[u8]) -> io::Result<ProxiedAddrs> {
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported v2 version"));
+    }
+    match version_command & 0x0f {
+        0x0 => return Ok(None),
+        0x1 => {}
+        _ => return Err(invalid("unknown v2 command")),
+    }
+    let port = |at: usize| u16::from_be_bytes([block[at], block[at + 1]]);
+    // Anything but TCP over IPv4 or IPv6 keeps the connection's own
+    // addresses, as the spec asks of receivers.
+    match family {
+        0x11 if block.len() >= 12 => {
+            let ip =
+                |at: usize| Ipv4Addr::new(block[at], block[at + 1], block[at + 2], block[at + 3]);
+            Ok(Some((
+                SocketAddr::new(ip(0).into(), port(8)),
+                SocketAddr::new(ip(4).into(), port(10)),
+            )))
+        }
+        0x21 if block.len() >= 36 => {
+            let ip = |at: usize| {
+                Ipv6Addr::from(<[u8; 16]>::try_from(&block[at..at + 16]).unwrap_or_default())
+            };
+            Ok(Some((
+                SocketAddr::new(ip(0).into(), port(32)),
+                SocketAddr::new(ip(16).into(), port(34)),
+            )))
+        }
+        0x11 | 0x21 => Err(invalid("v2 address block is too short")),
+        _ => Ok(None),
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Reads the PROXY header of every accepted connection before handing it to
+/// `inner`, which then sees the client the header names as the peer.
+pub struct ProxyProtocolApp<A> {
+    inner: Arc<A>,
+    version: ProxyProtocolVersion,
+    /// Checked against the proxied client instead of the balancer.
+    acl: Option<ConnectionAcl>,
+}
+
+impl<A> ProxyProtocolApp<A> {
+    pub fn new(inner: A, version: ProxyProtocolVersion, acl: Option<ConnectionAcl>) -> Self {
+        Self {
+            inner: Arc::new(inner),
+            version,
+            acl,
+        }
+    }
+}
+
+#[async_trait]
+impl<A: ServerApp + Send + Sync + 'static> ServerApp for ProxyProtocolApp<A> {
+    async fn process_new(
+        self: &Arc<Self>,
+        mut stream: Stream,
+        shutdown: &ShutdownWatch,
+    ) -> Option<Stream> {
+        let addrs = match tokio::time::timeout(
+            HEADER_TIMEOUT,
+            read_header(&mut stream, self.version),
+        )
+        .await
+        {
+            Ok(Ok(addrs)) => addrs,
+            Ok(Err(err)) => {
+                debug!(error = %err, "dropping connection with an invalid PROXY protocol header");
+                return None;
+            }
+            Err(_) => {
+                debug!("dropping connection that sent no PROXY protocol header in time");
+                return None;
+            }
+        };
+        if let Some((source, destination)) = addrs {
+            set_proxied_addrs(&mut stream, source, destination);
+        }
+        if let Some(acl) = &self.acl {
+            let peer = stream
+                .get_socket_digest()
+                .and_then(|digest| digest.peer_addr().and_then(|addr| addr.as_inet().copied()));
+            if !acl.should_accept(peer.as_ref()).await {
+                return None;
+            }
+        }
+        // Kept-alive connections come back here, but their header was
+        // already read.
+        let mut reused = self.inner.process_new(stream, shutdown).await;
+        while let Some(stream) = reused {
+            reused = self.inner.process_new(stream, shutdown).await;
+        }
+        None
+    }
+
+    async fn cleanup(&self) {
+        self.inner.cleanup().await;
+    }
+}
+
+/// Replaces the socket digest, where every later stage (logs, ACLs,
+/// `X-Forwarded-For`) reads the peer, with the proxied addresses.
+fn set_proxied_addrs(stream: &mut Stream, source: SocketAddr, destination: SocketAddr) {
+    let Some(l4) = stream.as_any().downcast_ref::<L4Stream>() else {
+        return;
+    };
+    #[cfg(unix)]
+    let digest = {
+        use std::os::unix::io::AsRawFd;
+        SocketDigest::from_raw_fd(l4.as_raw_fd())
+    };
+    #[cfg(windows)]
+    let digest = {
+        use std::os::windows::io::AsRawSocket;
+        SocketDigest::from_raw_socket(l4.as_raw_socket())
+    };
+    let _ = digest.peer_addr.set(Some(L4SocketAddr::Inet(source)));
+    let _ = digest.local_addr.set(Some(L4SocketAddr::Inet(destination)));
+    stream.set_socket_digest(digest);
+}
+
+/// Connects like pingora would, or through `inner` (an outbound proxy
+/// tunnel), and sends `header` before anything else.
+#[derive(Debug)]
+pub struct HeaderConnect {
+    header: Vec<u8>,
+    inner: Option<Arc<dyn L4Connect + Send + Sync>>,
+    options: PeerOptions,
+}
+
+impl HeaderConnect {
+    /// Takes over `options.custom_l4`, if any, as the inner connector.
+    pub fn new(header: Vec<u8>, options: &mut PeerOptions) -> Self {
+        let inner = options.custom_l4.take();
+        Self {
+            header,
+            inner,
+            options: options.clone(),
+        }
+    }
+
+    /// Part of the connection pool key: a connection announced one client,
+    /// so it must not be reused for another.
+    pub fn pool_key(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.header.hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+#[async_trait]
+impl L4Connect for HeaderConnect {
+    async fn connect(&self, addr: &L4SocketAddr) -> Result<L4Stream> {
+        let mut stream = match &self.inner {
+            Some(inner) => inner.connect(addr).await?,
+            None => {
+                let peer = BasicPeer {
+                    _address: addr.clone(),
+                    sni: String::new(),
+                    options: self.options.clone(),
+                };
+                let stream = CONNECTOR.new_stream(&peer).await?;
+                // Plaintext peers come back as the plain L4 stream.
+                *stream
+                    .into_any()
+                    .downcast::<L4Stream>()
+                    .map_err(|_| Error::explain(InternalError, "upstream connection is not TCP"))?
+            }
+        };
+        write_header(&mut stream, &self.header).await?;
+        Ok(stream)
+    }
+}
+
+pub async fn write_header<S: AsyncWriteExt + Unpin>(stream: &mut S, header: &[u8]) -> Result<()> {
+    stream
+        .write_all(header)
+        .await
+        .or_err(WriteError, "failed to send PROXY protocol header")?;
+    stream
+        .flush()
+        .await
+        .or_err(WriteError, "failed to send PROXY protocol header")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(bytes: &[u8], version: ProxyProtocolVersion) -> io::Result<ProxiedAddrs> {
+        let mut reader = bytes;
+        let addrs = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .expect("runtime")
+            .block_on(read_header(&mut reader, version))?;
+        assert_eq!(reader, b"GET", "nothing past the header is read");
+        Ok(addrs)
+    }
+
+    #[test]
+    fn encodes_and_reads_both_versions() {
+        let v4 = Some((
+            "203.0.113.7:51000".parse().expect("addr"),
+            "10.0.0.1:443".parse().expect("addr"),
+        ));
+        let mixed = Some((
+            "203.0.113.7:51000".parse().expect("addr"),
+            "[2001:db8::1]:443".parse().expect("addr"),
+        ));
+        assert_eq!(
+            encode(ProxyProtocolVersion::V1, v4),
+            b"PROXY TCP4 203.0.113.7 10.0.0.1 51000 443\r\n"
+        );
+        for version in [ProxyProtocolVersion::V1, ProxyProtocolVersion::V2] {
+            for addrs in [v4, None] {
+                let mut bytes = encode(version, addrs);
+                bytes.extend_from_slice(b"GET");
+                assert_eq!(read(&bytes, version).expect("header"), addrs);
+            }
+            let mut bytes = encode(version, mixed);
+            bytes.extend_from_slice(b"GET");
+            let (source, _) = read(&bytes, version).expect("header").expect("addrs");
+            assert_eq!(source.ip().to_canonical().to_string(), "203.0.113.7");
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_headers() {
+        for (bytes, version) in [
+            (&b"GET / HTTP/1.1\r\n"[..], ProxyProtocolVersion::V1),
+            (
+                b"PROXY TCP4 1.2.3.4 5.6.7.8 80\r\n",
+                ProxyProtocolVersion::V1,
+            ),
+            (
+                b"PROXY TCP4 1.2.3.4 5.6.7.8 80 99999\r\n",
+                ProxyProtocolVersion::V1,
+            ),
+            (
+                b"PROXY TCP4 1.2.3.4 5.6.7.8 80 443\r\nGET",
+                ProxyProtocolVersion::V2,
+            ),
+        ] {
+            assert!(read(bytes, version).is_err(), "{bytes:?}");
+        }
+        let long = [b'P'; 200];
+        assert!(read(&long, ProxyProtocolVersion::V1).is_err());
+    }
+}
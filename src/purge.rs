@@ -0,0 +1,81 @@
+use pingora::http::ResponseHeader;
+
+/// Response header upstreams use to tag what they answer with, so cached
+/// answers can later be purged by tag.
+pub const CACHE_TAGS_HEADER: &str = "x-cache-tags";
+
+/// Which cached answers of a route to drop. Without a path or tags, all of
+/// them.
+#[derive(Debug, Default)]
+pub struct Purge {
+    path: Option<String>,
+    tags: Vec<String>,
+}
+
+impl Purge {
+    /// `path` is an exact path, or a prefix when it ends in `*`; `tags` is a
+    /// comma-separated list, of which one is enough to match.
+    pub fn new(path: Option<&str>, tags: &str) -> Self {
+        Self {
+            path: path.map(str::to_string),
+            tags: split_tags(tags).map(str::to_string).collect(),
+        }
+    }
+
+    pub fn matches(&self, path: &str, response: &ResponseHeader) -> bool {
+        let path_matches = match self.path.as_deref() {
+            None => true,
+            Some(pattern) => match pattern.strip_suffix('*') {
+                Some(prefix) => path.starts_with(prefix),
+                None => path == pattern,
+            },
+        };
+        path_matches
+            && (self.tags.is_empty()
+                || response
+                    .headers
+                    .get_all(CACHE_TAGS_HEADER)
+                    .iter()
+                    .filter_map(|value| value.to_str().ok())
+                    .flat_map(split_tags)
+                    .any(|tag| self.tags.iter().any(|wanted| wanted == tag)))
+    }
+}
+
+fn split_tags(tags: &str) -> impl Iterator<Item = &str> {
+    tags.split(',').map(str::trim).filter(|tag| !tag.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tagged(tags: &str) -> ResponseHeader {
+        let mut response = ResponseHeader::build(200, None).expect("response");
+        if !tags.is_empty() {
+            response
+                .insert_header(CACHE_TAGS_HEADER, tags)
+                .expect("tags");
+        }
+        response
+    }
+
+    #[test]
+    fn matches_path_patterns_and_any_tag() {
+        let untagged = tagged("");
+        assert!(Purge::default().matches("/anything", &untagged));
+
+        let assets = Purge::new(Some("/assets/*"), "");
+        assert!(assets.matches("/assets/app.js", &untagged));
+        assert!(!assets.matches("/api/items", &untagged));
+        let exact = Purge::new(Some("/index.html"), "");
+        assert!(exact.matches("/index.html", &untagged));
+        assert!(!exact.matches("/index.html.bak", &untagged));
+
+        let release = Purge::new(None, "release-42, ");
+        assert!(release.matches("/a", &tagged("catalog, release-42")));
+        assert!(!release.matches("/a", &tagged("release-41")));
+        assert!(!release.matches("/a", &untagged));
+        assert!(!Purge::new(Some("/b"), "release-42").matches("/a", &tagged("release-42")));
+    }
+}
@@ -0,0 +1,210 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    str::FromStr,
+    sync::{Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use anyhow::bail;
+use http::{HeaderMap, HeaderName};
+
+use crate::config::RateLimitConfig;
+
+/// Distinct keys tracked per route before idle buckets are dropped.
+const MAX_TRACKED_KEYS: usize = 100_000;
+
+/// What a route's rate limit counts requests by.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RateLimitKey {
+    ClientIp,
+    Header(HeaderName),
+    Cookie(String),
+}
+
+impl FromStr for RateLimitKey {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> anyhow::Result<Self> {
+        if value == "client_ip" {
+            return Ok(Self::ClientIp);
+        }
+        if let Some(name) = value.strip_prefix("header:") {
+            let Ok(name) = HeaderName::from_bytes(name.as_bytes()) else {
+                bail!("rate limit key {value:?} names an invalid header");
+            };
+            return Ok(Self::Header(name));
+        }
+        if let Some(name) = value.strip_prefix("cookie:")
+            && !name.is_empty()
+        {
+            return Ok(Self::Cookie(name.to_string()));
+        }
+        bail!("rate limit key {value:?} must be client_ip, header:<name> or cookie:<name>")
+    }
+}
+
+impl RateLimitKey {
+    /// The bucket a request is counted in. Requests without the header or
+    /// cookie share one bucket, so leaving it out does not lift the limit.
+    pub fn extract(&self, client_ip: Option<IpAddr>, headers: &HeaderMap) -> String {
+        match self {
+            Self::ClientIp => client_ip.map(|ip| ip.to_string()).unwrap_or_default(),
+            Self::Header(name) => headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or_default()
+                .to_string(),
+            Self::Cookie(name) => headers
+                .get_all(http::header::COOKIE)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(';'))
+                .filter_map(|pair| pair.trim().split_once('='))
+                .find(|(cookie, _)| cookie == name)
+                .map(|(_, value)| value.to_string())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// Request token buckets of one route, one per key. Kept across reloads that
+/// leave the route unchanged.
+#[derive(Debug)]
+pub struct RouteRateLimit {
+    rps: f64,
+    burst: f64,
+    key: RateLimitKey,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl RouteRateLimit {
+    /// The key is checked by `PrxConfig::validate`; an invalid one falls back
+    /// to the client IP.
+    pub fn from_config(config: &RateLimitConfig) -> Self {
+        Self {
+            key: config.key.parse().unwrap_or(RateLimitKey::ClientIp),
+            ..Self::with_rate(config.rps as f64, config.burst() as f64)
+        }
+    }
+
+    /// Buckets of `rps` and `burst` under keys the caller extracts itself.
+    pub fn with_rate(rps: f64, burst: f64) -> Self {
+        Self {
+            rps,
+            burst,
+            key: RateLimitKey::ClientIp,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn key(&self) -> &RateLimitKey {
+        &self.key
+    }
+
+    /// `(rps, burst)`.
+    #[cfg(unix)]
+    pub fn rate(&self) -> (f64, f64) {
+        (self.rps, self.burst)
+    }
+
+    /// Takes one request from the bucket of `key`, or returns how long until
+    /// the next one would be admitted.
+    pub fn check(&self, key: &str) -> Result<(), Duration> {
+        self.check_at(key, Instant::now())
+    }
+
+    pub fn reset(&self) {
+        self.buckets().clear();
+    }
+
+    fn check_at(&self, key: &str, now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.buckets();
+        if buckets.len() >= MAX_TRACKED_KEYS && !buckets.contains_key(key) {
+            // A full bucket behaves exactly like a missing one.
+            buckets.retain(|_, bucket| self.refilled(*bucket, now).tokens < self.burst);
+        }
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: self.burst,
+            refilled_at: now,
+        });
+        *bucket = self.refilled(*bucket, now);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rps))
+        }
+    }
+
+    fn refilled(&self, bucket: Bucket, now: Instant) -> Bucket {
+        let elapsed = now.saturating_duration_since(bucket.refilled_at);
+        Bucket {
+            tokens: (bucket.tokens + elapsed.as_secs_f64() * self.rps).min(self.burst),
+            refilled_at: now,
+        }
+    }
+
+    fn buckets(&self) -> MutexGuard<'_, HashMap<String, Bucket>> {
+        self.buckets
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::HeaderValue;
+
+    use super::*;
+
+    fn limit(rps: u64, burst: u64) -> RouteRateLimit {
+        RouteRateLimit::from_config(&RateLimitConfig {
+            rps,
+            burst: Some(burst),
+            key: "client_ip".to_string(),
+        })
+    }
+
+    #[test]
+    fn admits_the_burst_then_refills_at_the_rate() {
+        let limit = limit(10, 2);
+        let start = Instant::now();
+        assert_eq!(limit.check_at("a", start), Ok(()));
+        assert_eq!(limit.check_at("a", start), Ok(()));
+        assert_eq!(limit.check_at("a", start), Err(Duration::from_millis(100)));
+        // Other keys have their own bucket.
+        assert_eq!(limit.check_at("b", start), Ok(()));
+        assert_eq!(
+            limit.check_at("a", start + Duration::from_millis(100)),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn extracts_keys_from_ip_header_and_cookie() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-api-key", HeaderValue::from_static("k1"));
+        headers.insert(
+            http::header::COOKIE,
+            HeaderValue::from_static("theme=dark; session=s-42"),
+        );
+        let ip = "192.0.2.1".parse().ok();
+
+        let key = |spec: &str| spec.parse::<RateLimitKey>().expect("key");
+        assert_eq!(key("client_ip").extract(ip, &headers), "192.0.2.1");
+        assert_eq!(key("header:X-Api-Key").extract(ip, &headers), "k1");
+        assert_eq!(key("cookie:session").extract(ip, &headers), "s-42");
+        assert_eq!(key("cookie:missing").extract(ip, &headers), "");
+
+        assert!("cookie:".parse::<RateLimitKey>().is_err());
+        assert!("header:bad name".parse::<RateLimitKey>().is_err());
+        assert!("path".parse::<RateLimitKey>().is_err());
+    }
+}
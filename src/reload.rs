@@ -1,6 +1,6 @@
 use std::{
-    ffi::OsStr,
-    path::{Path, PathBuf},
+    collections::BTreeSet,
+    path::PathBuf,
     sync::Arc,
     thread,
     time::{Duration, Instant},
@@ -10,21 +10,123 @@ use arc_swap::ArcSwap;
 use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
 use tracing::{error, info, warn};
 
-use crate::{config::PrxConfig, runtime::RuntimeConfig};
+use crate::{
+    config::PrxConfig,
+    include::{self, WatchTarget},
+    runtime::RuntimeConfig,
+};
 
+/// Reloads the config whenever the file at `config_path` or one it includes
+/// is written; `include` is the config's list at start, and the files
+/// watched follow it on every reload.
 pub fn spawn_config_watcher(
     config_path: PathBuf,
+    include: &[String],
     debounce: Duration,
     active_config: Arc<ArcSwap<RuntimeConfig>>,
 ) -> anyhow::Result<()> {
-    let watched_file = config_path
-        .file_name()
-        .map(|name| name.to_owned())
-        .unwrap_or_else(|| OsStr::new("Prx.toml").to_owned());
-    let watched_dir = resolve_watch_dir(&config_path);
+    let path = config_path.clone();
+    let targets = include::watch_targets(&config_path, include);
+    spawn_watcher(
+        "prx-config-watcher",
+        targets,
+        debounce,
+        move || match PrxConfig::from_file(&path).and_then(|config| {
+            let targets = include::watch_targets(&path, &config.include);
+            Ok((targets, active_config.load().rebuild(config)?))
+        }) {
+            Ok((targets, (next_config, stats))) => {
+                active_config.store(Arc::new(next_config));
+                info!(
+                    config = %path.to_string_lossy(),
+                    reused_services = stats.reused_services,
+                    rebuilt_services = stats.rebuilt_services,
+                    reused_routes = stats.reused_routes,
+                    rebuilt_routes = stats.rebuilt_routes,
+                    "reloaded config from disk"
+                );
+                Some(targets)
+            }
+            Err(err) => {
+                error!(
+                    error = %err,
+                    config = %path.to_string_lossy(),
+                    "failed to reload config, keeping previous version"
+                );
+                None
+            }
+        },
+    )
+}
+
+/// Calls `on_change` on a background thread whenever `path` is written,
+/// at most once per `debounce`. The parent directory is watched so editors
+/// and tools that replace the file are seen too.
+pub fn spawn_file_watcher(
+    thread_name: &str,
+    path: PathBuf,
+    debounce: Duration,
+    mut on_change: impl FnMut() + Send + 'static,
+) -> anyhow::Result<()> {
+    let targets = include::watch_targets(&path, &[]);
+    spawn_watcher(thread_name, targets, debounce, move || {
+        on_change();
+        None
+    })
+}
+
+/// Like [`spawn_file_watcher`] over several files.
+#[cfg(feature = "openssl")]
+pub fn spawn_files_watcher(
+    thread_name: &str,
+    paths: &[PathBuf],
+    debounce: Duration,
+    mut on_change: impl FnMut() + Send + 'static,
+) -> anyhow::Result<()> {
+    spawn_watcher(thread_name, files_targets(paths), debounce, move || {
+        on_change();
+        None
+    })
+}
+
+/// The files at `paths` and the `..data` entry next to each: Kubernetes
+/// updates a mounted secret by swapping that symlink, leaving the files
+/// named in the config untouched.
+#[cfg(feature = "openssl")]
+fn files_targets(paths: &[PathBuf]) -> Vec<WatchTarget> {
+    let mut targets: Vec<WatchTarget> = Vec::new();
+    for target in paths
+        .iter()
+        .flat_map(|path| include::watch_targets(path, &[]))
+    {
+        let data = WatchTarget {
+            dir: target.dir.clone(),
+            pattern: "..data".to_string(),
+        };
+        for target in [target, data] {
+            if !targets.contains(&target) {
+                targets.push(target);
+            }
+        }
+    }
+    targets
+}
+
+/// Like [`spawn_file_watcher`] over every file `targets` match; `on_change`
+/// may return new targets to watch from then on.
+fn spawn_watcher(
+    thread_name: &str,
+    mut targets: Vec<WatchTarget>,
+    debounce: Duration,
+    mut on_change: impl FnMut() -> Option<Vec<WatchTarget>> + Send + 'static,
+) -> anyhow::Result<()> {
+    let path = targets
+        .first()
+        .map(|target| target.dir.join(&target.pattern))
+        .unwrap_or_default();
 
     thread::Builder::new()
-        .name("prx-config-watcher".to_string())
+        .name(thread_name.to_string())
         .spawn(move || {
             let (tx, rx) = std::sync::mpsc::channel();
             let mut watcher: RecommendedWatcher = match notify::recommended_watcher(move |res| {
@@ -32,24 +134,21 @@ pub fn spawn_config_watcher(
             }) {
                 Ok(watcher) => watcher,
                 Err(err) => {
-                    error!(error = %err, "failed to start config watcher");
+                    error!(error = %err, file = %path.to_string_lossy(), "failed to start file watcher");
                     return;
                 }
             };
 
-            if let Err(err) = watcher.watch(&watched_dir, RecursiveMode::NonRecursive) {
-                error!(
-                    error = %err,
-                    directory = %watched_dir.to_string_lossy(),
-                    "failed to watch config directory"
-                );
+            let mut watched_dirs = BTreeSet::new();
+            watch_dirs(&mut watcher, &mut watched_dirs, &targets);
+            if watched_dirs.is_empty() {
                 return;
             }
 
             info!(
-                config = %config_path.to_string_lossy(),
+                file = %path.to_string_lossy(),
                 debounce_ms = debounce.as_millis(),
-                "auto reload for Prx.toml is active"
+                "auto reload is active"
             );
 
             let mut last_reload = Instant::now()
@@ -64,12 +163,12 @@ pub fn spawn_config_watcher(
                         continue;
                     }
                     Err(err) => {
-                        warn!(error = %err, "config watcher channel closed");
+                        warn!(error = %err, "file watcher channel closed");
                         return;
                     }
                 };
 
-                if !event_touches_file(&event, &watched_file) {
+                if !event_touches_targets(&event, &targets) {
                     continue;
                 }
 
@@ -78,22 +177,11 @@ pub fn spawn_config_watcher(
                     continue;
                 }
                 last_reload = now;
-
-                match PrxConfig::from_file(&config_path).map(RuntimeConfig::from_config) {
-                    Ok(next_config) => {
-                        active_config.store(Arc::new(next_config));
-                        info!(
-                            config = %config_path.to_string_lossy(),
-                            "reloaded config from disk"
-                        );
-                    }
-                    Err(err) => {
-                        error!(
-                            error = %err,
-                            config = %config_path.to_string_lossy(),
-                            "failed to reload config, keeping previous version"
-                        );
-                    }
+                if let Some(next) = on_change()
+                    && next != targets
+                {
+                    targets = next;
+                    watch_dirs(&mut watcher, &mut watched_dirs, &targets);
                 }
             }
         })?;
@@ -101,34 +189,93 @@ pub fn spawn_config_watcher(
     Ok(())
 }
 
-fn resolve_watch_dir(config_path: &Path) -> PathBuf {
-    config_path
-        .parent()
-        .filter(|parent| !parent.as_os_str().is_empty())
-        .map(Path::to_path_buf)
-        .unwrap_or_else(|| PathBuf::from("."))
+/// Watches the directories of `targets` that are not watched yet and stops
+/// watching those no target is in anymore.
+fn watch_dirs(
+    watcher: &mut RecommendedWatcher,
+    watched: &mut BTreeSet<PathBuf>,
+    targets: &[WatchTarget],
+) {
+    let wanted: BTreeSet<PathBuf> = targets.iter().map(|target| target.dir.clone()).collect();
+    for dir in watched.difference(&wanted) {
+        let _ = watcher.unwatch(dir);
+    }
+    watched.retain(|dir| wanted.contains(dir));
+    for dir in wanted {
+        if watched.contains(&dir) {
+            continue;
+        }
+        match watcher.watch(&dir, RecursiveMode::NonRecursive) {
+            Ok(()) => {
+                watched.insert(dir);
+            }
+            Err(err) => error!(
+                error = %err,
+                directory = %dir.to_string_lossy(),
+                "failed to watch directory"
+            ),
+        }
+    }
 }
 
-fn event_touches_file(event: &Event, file_name: &OsStr) -> bool {
-    event
-        .paths
-        .iter()
-        .any(|path| path.file_name().is_some_and(|name| name == file_name))
+/// Matches by file name only, since notify reports absolute paths; a
+/// same-named file in another watched directory just reloads once more.
+fn event_touches_targets(event: &Event, targets: &[WatchTarget]) -> bool {
+    event.paths.iter().any(|path| {
+        path.file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| {
+                targets
+                    .iter()
+                    .any(|target| include::matches(&target.pattern, name))
+            })
+    })
 }
 
 #[cfg(test)]
 mod tests {
+    use std::path::Path;
+
     use super::*;
 
     #[test]
-    fn resolve_watch_dir_uses_current_dir_for_relative_file() {
-        let dir = resolve_watch_dir(Path::new("Prx.toml"));
-        assert_eq!(dir, PathBuf::from("."));
+    fn watch_targets_use_current_dir_for_relative_file() {
+        let targets = include::watch_targets(Path::new("Prx.toml"), &[]);
+        assert_eq!(targets[0].dir, PathBuf::from("."));
+    }
+
+    #[test]
+    fn watch_targets_use_parent_for_absolute_file() {
+        let include = ["conf.d/*.toml".to_string()];
+        let targets = include::watch_targets(Path::new("/tmp/prx/Prx.toml"), &include);
+        assert_eq!(targets[0].dir, PathBuf::from("/tmp/prx"));
+        assert_eq!(
+            targets[1],
+            WatchTarget {
+                dir: PathBuf::from("/tmp/prx/conf.d"),
+                pattern: "*.toml".to_string(),
+            }
+        );
+
+        let event = Event::default().add_path(PathBuf::from("/tmp/prx/conf.d/10-api.toml"));
+        assert!(event_touches_targets(&event, &targets));
+        let event = Event::default().add_path(PathBuf::from("/tmp/prx/conf.d/.10-api.toml.swp"));
+        assert!(!event_touches_targets(&event, &targets));
     }
 
+    #[cfg(feature = "openssl")]
     #[test]
-    fn resolve_watch_dir_uses_parent_for_absolute_file() {
-        let dir = resolve_watch_dir(Path::new("/tmp/prx/Prx.toml"));
-        assert_eq!(dir, PathBuf::from("/tmp/prx"));
+    fn files_targets_follow_kubernetes_secret_updates() {
+        let targets = files_targets(&[
+            PathBuf::from("/etc/prx/tls/tls.crt"),
+            PathBuf::from("/etc/prx/tls/tls.key"),
+        ]);
+        assert_eq!(targets.len(), 3);
+        for name in ["tls.crt", "tls.key", "..data"] {
+            let event = Event::default().add_path(PathBuf::from(format!("/etc/prx/tls/{name}")));
+            assert!(event_touches_targets(&event, &targets), "{name}");
+        }
+        let event = Event::default().add_path(PathBuf::from("/etc/prx/tls/..data_tmp"));
+        assert!(!event_touches_targets(&event, &targets));
     }
 }
@@ -0,0 +1,445 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::IpAddr,
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, bail};
+use async_trait::async_trait;
+use http::HeaderName;
+use pingora::{connectors::http::Connector, prelude::*};
+use serde::Deserialize;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufStream},
+    net::TcpStream,
+};
+use tracing::debug;
+
+use crate::{
+    config::{ReputationConfig, ReputationOnError},
+    metrics,
+    source::{DEFAULT_S3_ENDPOINT, RemoteUrl},
+};
+
+// Past this many cached verdicts, expired ones are dropped, then all of them.
+const MAX_CACHED_VERDICTS: usize = 100_000;
+const MAX_IDLE_CONNECTIONS: usize = 16;
+const MAX_VERDICT_BYTES: usize = 4096;
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// What a reputation lookup decided about a client IP.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReputationVerdict {
+    Allow,
+    /// The request is answered `403`.
+    Deny,
+    /// The request is proxied with the tag in the `tag_header`.
+    Tag(String),
+}
+
+impl ReputationVerdict {
+    fn label(&self) -> &'static str {
+        match self {
+            Self::Allow => "allow",
+            Self::Deny => "deny",
+            Self::Tag(_) => "tag",
+        }
+    }
+}
+
+/// A source of client IP verdicts, e.g. a threat-intel service.
+///
+/// prx bounds every lookup by `timeout_ms` and caches verdicts for
+/// `cache_ms`, so implementations only need to answer.
+#[async_trait]
+pub trait ReputationLookup: Send + Sync {
+    async fn lookup(&self, ip: IpAddr) -> anyhow::Result<ReputationVerdict>;
+}
+
+/// The lookup stage consulted by the proxy for every request.
+pub struct Reputation {
+    lookup: Arc<dyn ReputationLookup>,
+    timeout: Duration,
+    cache_ttl: Duration,
+    on_error: ReputationOnError,
+    pub tag_header: HeaderName,
+    cache: Mutex<HashMap<IpAddr, (ReputationVerdict, Instant)>>,
+}
+
+impl Reputation {
+    /// `custom` replaces the `http` or `redis` lookup of the config.
+    pub fn from_config(
+        config: &ReputationConfig,
+        custom: Option<Arc<dyn ReputationLookup>>,
+    ) -> anyhow::Result<Self> {
+        let lookup: Arc<dyn ReputationLookup> = match (custom, &config.http, &config.redis) {
+            (Some(custom), _, _) => custom,
+            (None, Some(url), _) => Arc::new(HttpLookup {
+                url: RemoteUrl::parse(url, DEFAULT_S3_ENDPOINT)
+                    .context("invalid reputation.http")?,
+                connector: Connector::new(None),
+                timeout: Duration::from_millis(config.timeout_ms),
+            }),
+            (None, None, Some(addr)) => Arc::new(RedisLookup {
+                addr: addr.clone(),
+                key_prefix: config.redis_key_prefix.clone(),
+                idle: Mutex::new(Vec::new()),
+            }),
+            (None, None, None) => bail!("[reputation] needs an http or redis lookup"),
+        };
+        Ok(Self {
+            lookup,
+            timeout: Duration::from_millis(config.timeout_ms.max(1)),
+            cache_ttl: Duration::from_millis(config.cache_ms),
+            on_error: config.on_error,
+            tag_header: HeaderName::from_bytes(config.tag_header.as_bytes())
+                .context("invalid reputation.tag_header")?,
+            cache: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub async fn check(&self, ip: IpAddr) -> ReputationVerdict {
+        let now = Instant::now();
+        if let Some((verdict, _)) = self
+            .cache()
+            .get(&ip)
+            .filter(|(_, expires_at)| *expires_at > now)
+        {
+            metrics::inc_reputation_verdict(verdict.label());
+            return verdict.clone();
+        }
+        let verdict = match tokio::time::timeout(self.timeout, self.lookup.lookup(ip)).await {
+            Ok(Ok(verdict)) => verdict,
+            Ok(Err(err)) => return self.failed(ip, &format!("{err:#}")),
+            Err(_) => return self.failed(ip, "timed out"),
+        };
+        metrics::inc_reputation_verdict(verdict.label());
+        if !self.cache_ttl.is_zero() {
+            let mut cache = self.cache();
+            if cache.len() >= MAX_CACHED_VERDICTS {
+                cache.retain(|_, (_, expires_at)| *expires_at > now);
+                if cache.len() >= MAX_CACHED_VERDICTS {
+                    cache.clear();
+                }
+            }
+            cache.insert(ip, (verdict.clone(), now + self.cache_ttl));
+        }
+        verdict
+    }
+
+    fn failed(&self, ip: IpAddr, error: &str) -> ReputationVerdict {
+        metrics::inc_reputation_verdict("error");
+        debug!(%ip, error, "reputation lookup failed");
+        match self.on_error {
+            ReputationOnError::Allow => ReputationVerdict::Allow,
+            ReputationOnError::Deny => ReputationVerdict::Deny,
+        }
+    }
+
+    fn cache(&self) -> MutexGuard<'_, HashMap<IpAddr, (ReputationVerdict, Instant)>> {
+        self.cache
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Asks `GET <url>?ip=<ip>`. `403` denies; a `2xx` answers with an empty
+/// body (allow) or `{"verdict": "allow" | "deny" | "tag", "tag": "..."}`.
+struct HttpLookup {
+    url: RemoteUrl,
+    connector: Connector,
+    timeout: Duration,
+}
+
+#[derive(Deserialize)]
+struct HttpVerdict {
+    verdict: String,
+    #[serde(default)]
+    tag: Option<String>,
+}
+
+#[async_trait]
+impl ReputationLookup for HttpLookup {
+    async fn lookup(&self, ip: IpAddr) -> anyhow::Result<ReputationVerdict> {
+        let addr = tokio::net::lookup_host((self.url.host.as_str(), self.url.port))
+            .await
+            .with_context(|| format!("failed to resolve {}", self.url.host))?
+            .next()
+            .with_context(|| format!("{} resolved to no addresses", self.url.host))?;
+        let mut peer = HttpPeer::new(addr, self.url.tls, self.url.host.clone());
+        peer.options.connection_timeout = Some(self.timeout);
+        peer.options.read_timeout = Some(self.timeout);
+        peer.options.write_timeout = Some(self.timeout);
+
+        let (mut session, _reused) = self
+            .connector
+            .get_http_session(&peer)
+            .await
+            .map_err(|err| anyhow::anyhow!("failed to connect to {}: {err}", self.url.host))?;
+        let separator = if self.url.path.contains('?') {
+            '&'
+        } else {
+            '?'
+        };
+        let path = format!("{}{separator}ip={ip}", self.url.path);
+        let mut request = RequestHeader::build("GET", path.as_bytes(), None)
+            .map_err(|err| anyhow::anyhow!("failed to build request: {err}"))?;
+        request
+            .insert_header("host", self.url.host_header())
+            .map_err(|err| anyhow::anyhow!("failed to set host header: {err}"))?;
+        session
+            .write_request_header(Box::new(request))
+            .await
+            .map_err(|err| anyhow::anyhow!("failed to send request: {err}"))?;
+        session
+            .finish_request_body()
+            .await
+            .map_err(|err| anyhow::anyhow!("failed to finish request: {err}"))?;
+        session
+            .read_response_header()
+            .await
+            .map_err(|err| anyhow::anyhow!("failed to read response header: {err}"))?;
+        let status = session
+            .response_header()
+            .map(|header| header.status.as_u16())
+            .context("reputation service sent no response header")?;
+        let mut body = Vec::new();
+        while let Some(chunk) = session
+            .read_response_body()
+            .await
+            .map_err(|err| anyhow::anyhow!("failed to read response body: {err}"))?
+        {
+            body.extend_from_slice(&chunk);
+            if body.len() > MAX_VERDICT_BYTES {
+                bail!("reputation verdict exceeds {MAX_VERDICT_BYTES} bytes");
+            }
+        }
+        self.connector
+            .release_http_session(session, &peer, Some(IDLE_TIMEOUT))
+            .await;
+
+        match status {
+            403 => Ok(ReputationVerdict::Deny),
+            200..=299 if body.iter().all(u8::is_ascii_whitespace) => Ok(ReputationVerdict::Allow),
+            200..=299 => {
+                let answer: HttpVerdict =
+                    serde_json::from_slice(&body).context("invalid reputation verdict")?;
+                parse_verdict(&answer.verdict, answer.tag)
+            }
+            status => bail!("reputation service responded with status {status}"),
+        }
+    }
+}
+
+/// Reads `GET <key_prefix><ip>`: nothing or `allow` allows, `deny` denies and
+/// any other value tags the request with it.
+struct RedisLookup {
+    addr: String,
+    key_prefix: String,
+    idle: Mutex<Vec<BufStream<TcpStream>>>,
+}
+
+#[async_trait]
+impl ReputationLookup for RedisLookup {
+    async fn lookup(&self, ip: IpAddr) -> anyhow::Result<ReputationVerdict> {
+        let pooled = self.idle().pop();
+        let mut stream = match pooled {
+            Some(stream) => stream,
+            None => BufStream::new(
+                TcpStream::connect(&self.addr)
+                    .await
+                    .with_context(|| format!("failed to connect to {}", self.addr))?,
+            ),
+        };
+        let key = format!("{}{ip}", self.key_prefix);
+        let command = format!("*2\r\n$3\r\nGET\r\n${}\r\n{key}\r\n", key.len());
+        stream.write_all(command.as_bytes()).await?;
+        stream.flush().await?;
+        let value = read_bulk_string(&mut stream).await?;
+        let mut idle = self.idle();
+        if idle.len() < MAX_IDLE_CONNECTIONS {
+            idle.push(stream);
+        }
+        match value {
+            None => Ok(ReputationVerdict::Allow),
+            Some(value) => parse_verdict(value.trim(), Some(value.trim().to_string())),
+        }
+    }
+}
+
+impl RedisLookup {
+    fn idle(&self) -> MutexGuard<'_, Vec<BufStream<TcpStream>>> {
+        self.idle
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Reads a RESP bulk string reply; `None` for a missing key.
+async fn read_bulk_string(stream: &mut BufStream<TcpStream>) -> anyhow::Result<Option<String>> {
+    let mut line = String::new();
+    if stream.read_line(&mut line).await? == 0 {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    let line = line.trim_end();
+    if let Some(error) = line.strip_prefix('-') {
+        bail!("redis answered {error}");
+    }
+    let Some(length) = line.strip_prefix('$') else {
+        bail!("unexpected redis reply {line:?}");
+    };
+    let length: i64 = length.parse().context("invalid redis bulk length")?;
+    if length < 0 {
+        return Ok(None);
+    }
+    let length = usize::try_from(length).unwrap_or(usize::MAX);
+    if length > MAX_VERDICT_BYTES {
+        bail!("reputation verdict exceeds {MAX_VERDICT_BYTES} bytes");
+    }
+    let mut value = vec![0; length + 2];
+    stream.read_exact(&mut value).await?;
+    value.truncate(length);
+    String::from_utf8(value)
+        .map(Some)
+        .context("reputation verdict is not valid utf-8")
+}
+
+fn parse_verdict(verdict: &str, tag: Option<String>) -> anyhow::Result<ReputationVerdict> {
+    match verdict {
+        "allow" | "" => Ok(ReputationVerdict::Allow),
+        "deny" => Ok(ReputationVerdict::Deny),
+        _ => match tag.filter(|tag| !tag.is_empty()) {
+            Some(tag) if http::HeaderValue::from_str(&tag).is_ok() => {
+                Ok(ReputationVerdict::Tag(tag))
+            }
+            Some(tag) => bail!("reputation tag {tag:?} is not a valid header value"),
+            None => bail!("reputation verdict {verdict:?} has no tag"),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    struct Counting {
+        calls: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl ReputationLookup for Counting {
+        async fn lookup(&self, ip: IpAddr) -> anyhow::Result<ReputationVerdict> {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+            match ip.to_string().as_str() {
+                "192.0.2.1" => Ok(ReputationVerdict::Deny),
+                "192.0.2.2" => Ok(ReputationVerdict::Tag("tor-exit".to_string())),
+                "192.0.2.3" => bail!("threat intel unavailable"),
+                _ => {
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    Ok(ReputationVerdict::Deny)
+                }
+            }
+        }
+    }
+
+    fn config(on_error: ReputationOnError) -> ReputationConfig {
+        ReputationConfig {
+            http: None,
+            redis: None,
+            redis_key_prefix: "reputation:".to_string(),
+            timeout_ms: 20,
+            cache_ms: 60_000,
+            on_error,
+            tag_header: "x-client-reputation".to_string(),
+        }
+    }
+
+    #[test]
+    fn caches_verdicts_and_falls_back_on_errors() {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("runtime")
+            .block_on(async {
+                let lookup = Arc::new(Counting {
+                    calls: AtomicUsize::new(0),
+                });
+                let reputation = Reputation::from_config(
+                    &config(ReputationOnError::Allow),
+                    Some(lookup.clone()),
+                )
+                .expect("reputation");
+                let ip = |last: u8| IpAddr::from([192, 0, 2, last]);
+
+                assert_eq!(reputation.check(ip(1)).await, ReputationVerdict::Deny);
+                assert_eq!(reputation.check(ip(1)).await, ReputationVerdict::Deny);
+                assert_eq!(
+                    reputation.check(ip(2)).await,
+                    ReputationVerdict::Tag("tor-exit".to_string())
+                );
+                assert_eq!(lookup.calls.load(Ordering::Relaxed), 2);
+
+                // Failures are not cached.
+                assert_eq!(reputation.check(ip(3)).await, ReputationVerdict::Allow);
+                assert_eq!(reputation.check(ip(4)).await, ReputationVerdict::Allow);
+                assert_eq!(reputation.check(ip(4)).await, ReputationVerdict::Allow);
+                assert_eq!(lookup.calls.load(Ordering::Relaxed), 5);
+
+                let strict =
+                    Reputation::from_config(&config(ReputationOnError::Deny), Some(lookup))
+                        .expect("reputation");
+                assert_eq!(strict.check(ip(3)).await, ReputationVerdict::Deny);
+            });
+    }
+
+    #[test]
+    fn reads_verdicts_from_redis() {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("runtime")
+            .block_on(async {
+                let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+                let addr = listener.local_addr().expect("addr");
+                tokio::spawn(async move {
+                    let (stream, _) = listener.accept().await.expect("accept");
+                    let mut stream = BufStream::new(stream);
+                    for reply in ["$4\r\ndeny\r\n", "$-1\r\n", "$8\r\ntor-exit\r\n"] {
+                        let mut command = Vec::new();
+                        // `*2`, `$3`, `GET`, `$<len>` and the key.
+                        for _ in 0..5 {
+                            let mut line = String::new();
+                            stream.read_line(&mut line).await.expect("command");
+                            command.push(line);
+                        }
+                        assert!(command[4].starts_with("reputation:198.51.100."));
+                        stream.write_all(reply.as_bytes()).await.expect("reply");
+                        stream.flush().await.expect("flush");
+                    }
+                });
+
+                let reputation = Reputation::from_config(
+                    &ReputationConfig {
+                        redis: Some(addr.to_string()),
+                        cache_ms: 0,
+                        ..config(ReputationOnError::Deny)
+                    },
+                    None,
+                )
+                .expect("reputation");
+                let ip = |last: u8| IpAddr::from([198, 51, 100, last]);
+                assert_eq!(reputation.check(ip(1)).await, ReputationVerdict::Deny);
+                assert_eq!(reputation.check(ip(2)).await, ReputationVerdict::Allow);
+                assert_eq!(
+                    reputation.check(ip(3)).await,
+                    ReputationVerdict::Tag("tor-exit".to_string())
+                );
+            });
+    }
+}
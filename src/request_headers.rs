@@ -0,0 +1,143 @@
+use http::{HeaderValue, header};
+use pingora::{http::RequestHeader, prelude::*};
+
+use crate::config::HeaderCase;
+
+/// Applies a route's `header_case` and `merge_duplicate_headers` to a request
+/// about to go upstream.
+pub fn normalize(request: &mut RequestHeader, case: HeaderCase, merge: bool) -> Result<()> {
+    if case == HeaderCase::Preserve && !merge {
+        return Ok(());
+    }
+    // Names as they will be sent, in order.
+    let mut fields: Vec<(String, HeaderValue)> = if request.has_case() {
+        request
+            .case_header_iter()
+            .map(|(name, value)| {
+                (
+                    String::from_utf8_lossy(name.as_slice()).into_owned(),
+                    value.clone(),
+                )
+            })
+            .collect()
+    } else {
+        request
+            .headers
+            .iter()
+            .map(|(name, value)| (name.as_str().to_string(), value.clone()))
+            .collect()
+    };
+    if merge {
+        fields = merged(fields)?;
+    }
+
+    // Only a header built with case keeps the names as given; one from an
+    // HTTP/2 client has none, so the request is built anew.
+    let mut rebuilt = RequestHeader::build(
+        request.method.clone(),
+        request.raw_path(),
+        Some(fields.len()),
+    )?;
+    rebuilt.uri = request.uri.clone();
+    rebuilt.version = request.version;
+    rebuilt.extensions = std::mem::take(&mut request.extensions);
+    if let Some(end_stream) = request.send_end_stream() {
+        rebuilt.set_send_end_stream(end_stream);
+    }
+    for (name, value) in fields {
+        rebuilt.append_header(cased(&name, case), value)?;
+    }
+    *request = rebuilt;
+    Ok(())
+}
+
+/// Folds repeated fields into the first one, comma-separated as RFC 9110
+/// allows for lists; cookies are joined with `; `.
+fn merged(fields: Vec<(String, HeaderValue)>) -> Result<Vec<(String, HeaderValue)>> {
+    let mut merged: Vec<(String, Vec<u8>)> = Vec::with_capacity(fields.len());
+    for (name, value) in fields {
+        match merged
+            .iter_mut()
+            .find(|(seen, _)| seen.eq_ignore_ascii_case(&name))
+        {
+            Some((_, joined)) => {
+                let separator: &[u8] = if name.eq_ignore_ascii_case(header::COOKIE.as_str()) {
+                    b"; "
+                } else {
+                    b", "
+                };
+                joined.extend_from_slice(separator);
+                joined.extend_from_slice(value.as_bytes());
+            }
+            None => merged.push((name, value.as_bytes().to_vec())),
+        }
+    }
+    merged
+        .into_iter()
+        .map(|(name, value)| {
+            let value = HeaderValue::from_bytes(&value)
+                .or_err(InvalidHTTPHeader, "merged header value is invalid")?;
+            Ok((name, value))
+        })
+        .collect()
+}
+
+fn cased(name: &str, case: HeaderCase) -> String {
+    match case {
+        HeaderCase::Preserve => name.to_string(),
+        HeaderCase::Lower => name.to_ascii_lowercase(),
+        HeaderCase::Title => name
+            .split('-')
+            .map(|word| {
+                let mut chars = word.chars();
+                chars.next().map_or_else(String::new, |first| {
+                    first.to_ascii_uppercase().to_string() + &chars.as_str().to_ascii_lowercase()
+                })
+            })
+            .collect::<Vec<_>>()
+            .join("-"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wire(request: &RequestHeader) -> String {
+        let mut buf = Vec::new();
+        request.header_to_h1_wire(&mut buf);
+        String::from_utf8(buf).expect("utf-8")
+    }
+
+    #[test]
+    fn recases_and_merges_request_headers() {
+        let mut request = RequestHeader::build("GET", b"/a?b=1", None).expect("request");
+        request.append_header("X-LEGACY-id", "7").expect("header");
+        request
+            .append_header("Accept", "text/html")
+            .expect("header");
+        request.append_header("cookie", "a=1").expect("header");
+        request.append_header("accept", "*/*").expect("header");
+        request.append_header("Cookie", "b=2").expect("header");
+
+        let mut preserved = request.clone();
+        normalize(&mut preserved, HeaderCase::Preserve, false).expect("normalize");
+        assert_eq!(wire(&preserved), wire(&request));
+
+        let mut title = request.clone();
+        normalize(&mut title, HeaderCase::Title, true).expect("normalize");
+        assert_eq!(
+            wire(&title),
+            "X-Legacy-Id: 7\r\nAccept: text/html, */*\r\nCookie: a=1; b=2\r\n"
+        );
+        assert_eq!(title.raw_path(), b"/a?b=1");
+
+        // HTTP/2 clients' headers come without case.
+        let mut h2 = RequestHeader::build_no_case("GET", b"/", None).expect("request");
+        h2.append_header("x-request-id", "r1").expect("header");
+        normalize(&mut h2, HeaderCase::Title, false).expect("normalize");
+        assert_eq!(wire(&h2), "X-Request-Id: r1\r\n");
+        normalize(&mut h2, HeaderCase::Lower, false).expect("normalize");
+        assert_eq!(wire(&h2), "x-request-id: r1\r\n");
+    }
+}
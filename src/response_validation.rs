@@ -0,0 +1,204 @@
+use std::{fmt, time::Duration};
+
+use http::{HeaderName, header};
+use pingora::http::ResponseHeader;
+
+use crate::config::{ResponseValidationConfig, ResponseViolationAction};
+
+/// Body of the 502 sent in place of a response that failed validation.
+pub const REJECTED_RESPONSE_BODY: &str = "prx: upstream response failed validation\n";
+
+/// Assertions a route makes about its upstream responses.
+#[derive(Debug, Clone)]
+pub struct ResponseValidation {
+    required_headers: Vec<HeaderName>,
+    max_latency: Option<Duration>,
+    // Lowercase media types; `type/*` allows any subtype.
+    content_types: Vec<String>,
+    pub action: ResponseViolationAction,
+}
+
+/// The first rule a response broke.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Violation {
+    MissingHeader(HeaderName),
+    Latency(Duration),
+    ContentType(Option<String>),
+}
+
+impl Violation {
+    /// The `rule` metric label.
+    pub fn rule(&self) -> &'static str {
+        match self {
+            Self::MissingHeader(_) => "required_header",
+            Self::Latency(_) => "max_latency",
+            Self::ContentType(_) => "content_type",
+        }
+    }
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingHeader(name) => write!(f, "missing required header {name}"),
+            Self::Latency(latency) => write!(f, "answered after {}ms", latency.as_millis()),
+            Self::ContentType(Some(content_type)) => {
+                write!(f, "content type {content_type} is not allowed")
+            }
+            Self::ContentType(None) => write!(f, "body without a content type"),
+        }
+    }
+}
+
+impl ResponseValidation {
+    /// Header names are checked by `PrxConfig::validate`.
+    pub fn from_config(config: &ResponseValidationConfig) -> Self {
+        Self {
+            required_headers: config
+                .required_headers
+                .iter()
+                .filter_map(|name| HeaderName::from_bytes(name.as_bytes()).ok())
+                .collect(),
+            max_latency: config.max_latency_ms.map(Duration::from_millis),
+            content_types: config
+                .content_types
+                .iter()
+                .map(|content_type| content_type.trim().to_ascii_lowercase())
+                .collect(),
+            action: config.action,
+        }
+    }
+
+    /// `latency` runs from the attempt's start to the response headers.
+    pub fn check(&self, response: &ResponseHeader, latency: Duration) -> Option<Violation> {
+        if let Some(name) = self
+            .required_headers
+            .iter()
+            .find(|name| !response.headers.contains_key(*name))
+        {
+            return Some(Violation::MissingHeader(name.clone()));
+        }
+        if let Some(max) = self.max_latency
+            && latency > max
+        {
+            return Some(Violation::Latency(latency));
+        }
+        if self.content_types.is_empty() {
+            return None;
+        }
+        let content_type = response
+            .headers
+            .get(header::CONTENT_TYPE)
+            .map(|value| String::from_utf8_lossy(value.as_bytes()))
+            .map(|value| {
+                value
+                    .split(';')
+                    .next()
+                    .unwrap_or_default()
+                    .trim()
+                    .to_ascii_lowercase()
+            });
+        match content_type {
+            Some(content_type) if self.allows(&content_type) => None,
+            Some(content_type) => Some(Violation::ContentType(Some(content_type))),
+            None if has_body(response) => Some(Violation::ContentType(None)),
+            None => None,
+        }
+    }
+
+    fn allows(&self, content_type: &str) -> bool {
+        self.content_types
+            .iter()
+            .any(|allowed| match allowed.strip_suffix("/*") {
+                Some(kind) => content_type
+                    .strip_prefix(kind)
+                    .is_some_and(|rest| rest.starts_with('/')),
+                None => allowed == content_type,
+            })
+    }
+}
+
+fn has_body(response: &ResponseHeader) -> bool {
+    let status = response.status.as_u16();
+    !(response.status.is_informational() || status == 204 || status == 304)
+        && response
+            .headers
+            .get(header::CONTENT_LENGTH)
+            .is_none_or(|length| length.as_bytes() != b"0")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(status: u16, headers: &[(&'static str, &'static str)]) -> ResponseHeader {
+        let mut response = ResponseHeader::build(status, None).expect("response");
+        for (name, value) in headers {
+            response.insert_header(*name, *value).expect("header");
+        }
+        response
+    }
+
+    #[test]
+    fn reports_the_first_broken_rule() {
+        let validation = ResponseValidation::from_config(&ResponseValidationConfig {
+            required_headers: vec!["X-Request-Id".to_string()],
+            max_latency_ms: Some(500),
+            content_types: vec!["application/json".to_string(), "image/*".to_string()],
+            action: ResponseViolationAction::Reject,
+        });
+        let fast = Duration::from_millis(20);
+        let json = response(
+            200,
+            &[
+                ("x-request-id", "r1"),
+                ("content-type", "Application/JSON; charset=utf-8"),
+            ],
+        );
+        assert_eq!(validation.check(&json, fast), None);
+        assert_eq!(
+            validation.check(&json, Duration::from_millis(800)),
+            Some(Violation::Latency(Duration::from_millis(800)))
+        );
+        assert_eq!(
+            validation.check(
+                &response(200, &[("content-type", "application/json")]),
+                fast
+            ),
+            Some(Violation::MissingHeader(HeaderName::from_static(
+                "x-request-id"
+            )))
+        );
+
+        let with_id = |headers: &[(&'static str, &'static str)]| {
+            let mut response = response(200, headers);
+            response
+                .insert_header("x-request-id", "r1")
+                .expect("header");
+            response
+        };
+        assert_eq!(
+            validation.check(&with_id(&[("content-type", "image/png")]), fast),
+            None
+        );
+        assert_eq!(
+            validation
+                .check(&with_id(&[("content-type", "text/html")]), fast)
+                .map(|violation| violation.rule()),
+            Some("content_type")
+        );
+        assert_eq!(
+            validation.check(&with_id(&[]), fast),
+            Some(Violation::ContentType(None))
+        );
+        assert_eq!(
+            validation.check(&with_id(&[("content-length", "0")]), fast),
+            None
+        );
+        let mut no_content = response(204, &[]);
+        no_content
+            .insert_header("x-request-id", "r1")
+            .expect("header");
+        assert_eq!(validation.check(&no_content, fast), None);
+    }
+}
@@ -0,0 +1,219 @@
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use rand::Rng;
+
+use crate::{
+    config::{RolloutConfig, ShadowConfig},
+    runtime::RuntimeConfig,
+};
+
+/// A config update being phased in. The snapshot that owns it is the
+/// candidate; `stable` keeps serving the rest of the traffic until the ramp
+/// ends or the candidate is rolled back.
+#[derive(Debug)]
+pub struct Rollout {
+    stable: Arc<RuntimeConfig>,
+    config: RolloutConfig,
+    started: Instant,
+    stable_outcomes: Outcomes,
+    candidate_outcomes: Outcomes,
+    rolled_back: AtomicBool,
+}
+
+#[derive(Debug, Default)]
+struct Outcomes {
+    requests: AtomicU64,
+    errors: AtomicU64,
+}
+
+impl Outcomes {
+    fn record(&self, error: bool) -> (u64, u64) {
+        let requests = self.requests.fetch_add(1, Ordering::Relaxed) + 1;
+        let errors = if error {
+            self.errors.fetch_add(1, Ordering::Relaxed) + 1
+        } else {
+            self.errors.load(Ordering::Relaxed)
+        };
+        (requests, errors)
+    }
+
+    fn error_rate(&self) -> f64 {
+        let requests = self.requests.load(Ordering::Relaxed);
+        if requests == 0 {
+            return 0.0;
+        }
+        self.errors.load(Ordering::Relaxed) as f64 / requests as f64
+    }
+}
+
+impl Rollout {
+    pub fn new(stable: Arc<RuntimeConfig>, config: RolloutConfig) -> Self {
+        Self {
+            stable,
+            config,
+            started: Instant::now(),
+            stable_outcomes: Outcomes::default(),
+            candidate_outcomes: Outcomes::default(),
+            rolled_back: AtomicBool::new(false),
+        }
+    }
+
+    pub fn stable(&self) -> &Arc<RuntimeConfig> {
+        &self.stable
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.progress_at(Instant::now()) >= 1.0
+    }
+
+    /// Share of requests the candidate takes at `now`.
+    fn progress_at(&self, now: Instant) -> f64 {
+        let ramp = Duration::from_millis(self.config.ramp_ms);
+        (now.saturating_duration_since(self.started).as_secs_f64() / ramp.as_secs_f64()).min(1.0)
+    }
+
+    /// Picks the snapshot for one request; `true` means the candidate.
+    pub fn serve_candidate(&self) -> bool {
+        rand::rng().random_bool(self.progress_at(Instant::now()))
+    }
+
+    /// Records how a request ended and returns `true` exactly once, when the
+    /// candidate's error rate has risen past the allowed margin.
+    pub fn record(&self, candidate: bool, error: bool) -> bool {
+        if !candidate {
+            self.stable_outcomes.record(error);
+            return false;
+        }
+        let (requests, errors) = self.candidate_outcomes.record(error);
+        if requests < self.config.min_requests {
+            return false;
+        }
+        let increase = errors as f64 / requests as f64 - self.stable_outcomes.error_rate();
+        increase > self.config.max_error_rate_increase
+            && !self.rolled_back.swap(true, Ordering::AcqRel)
+    }
+}
+
+/// A config update being evaluated before it serves anything. The snapshot
+/// that owns it is the candidate; `stable` serves every request until the
+/// window ends, while the proxy also routes each request on the candidate and
+/// counts where the two disagree.
+#[derive(Debug)]
+pub struct Shadow {
+    stable: Arc<RuntimeConfig>,
+    config: ShadowConfig,
+    started: Instant,
+    compared: AtomicU64,
+    diverged: AtomicU64,
+}
+
+impl Shadow {
+    pub fn new(stable: Arc<RuntimeConfig>, config: ShadowConfig) -> Self {
+        Self {
+            stable,
+            config,
+            started: Instant::now(),
+            compared: AtomicU64::new(0),
+            diverged: AtomicU64::new(0),
+        }
+    }
+
+    pub fn stable(&self) -> &Arc<RuntimeConfig> {
+        &self.stable
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.started.elapsed() >= Duration::from_millis(self.config.duration_ms)
+    }
+
+    pub fn record(&self, diverged: bool) {
+        self.compared.fetch_add(1, Ordering::Relaxed);
+        if diverged {
+            self.diverged.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Requests compared so far, and how many of them were routed differently.
+    pub fn totals(&self) -> (u64, u64) {
+        (
+            self.compared.load(Ordering::Relaxed),
+            self.diverged.load(Ordering::Relaxed),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PrxConfig;
+
+    fn rollout(ramp_ms: u64) -> Rollout {
+        let config = PrxConfig::from_toml_str(
+            r#"
+[[service]]
+name = "app"
+
+[[service.upstream]]
+addr = "127.0.0.1:9000"
+
+[[route]]
+name = "default"
+service = "app"
+path_prefix = "/"
+"#,
+        )
+        .expect("config");
+        Rollout::new(
+            Arc::new(RuntimeConfig::from_config(config).expect("runtime")),
+            RolloutConfig {
+                ramp_ms,
+                max_error_rate_increase: 0.1,
+                min_requests: 10,
+            },
+        )
+    }
+
+    #[test]
+    fn ramps_linearly_over_the_window() {
+        let rollout = rollout(1_000);
+        let start = rollout.started;
+        assert_eq!(rollout.progress_at(start), 0.0);
+        assert_eq!(
+            rollout.progress_at(start + Duration::from_millis(250)),
+            0.25
+        );
+        assert_eq!(rollout.progress_at(start + Duration::from_secs(5)), 1.0);
+        assert!(!rollout.is_complete());
+    }
+
+    #[test]
+    fn rolls_back_once_when_candidate_errors_exceed_the_margin() {
+        let rollout = rollout(1_000);
+        for _ in 0..20 {
+            assert!(!rollout.record(false, false));
+        }
+        // Below min_requests nothing is judged, however bad it looks.
+        for _ in 0..9 {
+            assert!(!rollout.record(true, true));
+        }
+        assert!(rollout.record(true, true));
+        assert!(!rollout.record(true, true));
+    }
+
+    #[test]
+    fn tolerates_errors_the_stable_config_also_has() {
+        let rollout = rollout(1_000);
+        for idx in 0..20 {
+            rollout.record(false, idx % 2 == 0);
+        }
+        for idx in 0..20 {
+            assert!(!rollout.record(true, idx % 2 == 0));
+        }
+    }
+}
@@ -1,68 +1,544 @@
 use std::{
-    collections::hash_map::DefaultHasher,
+    collections::{HashMap, HashSet, VecDeque, hash_map::DefaultHasher},
     hash::{Hash, Hasher},
-    net::SocketAddr,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
     sync::{
-        Arc,
-        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard,
+        atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering},
     },
-    time::{SystemTime, UNIX_EPOCH},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
+use anyhow::{Context, bail};
+use arc_swap::{ArcSwap, ArcSwapOption};
+use bytes::Bytes;
+use http::{HeaderMap, HeaderName, HeaderValue};
 use rand::Rng;
+use tracing::{error, info};
+
+use crate::{
+    acl::Cidr,
+    admin_auth::AdminAuth,
+    config::{
+        AccessLogFieldsConfig, BreakerFailure, DiscoverConfig, EgressConfig,
+        ForwardedHeadersPolicy, HashAffinity, HeaderCase, HealthCheckConfig, LbStrategy,
+        ObservabilityConfig, OutlierDetectionConfig, ProxyProtocolVersion, PrxConfig,
+        RetryBackoffConfig, RouteObservabilityConfig, RouteProtocol, StatusMapConfig,
+        UpstreamHttpVersion,
+    },
+    drain,
+    health::HealthState,
+    idempotency::IdempotencyCache,
+    metrics,
+    outbound::OutboundProxy,
+    preflight::PreflightCache,
+    purge::Purge,
+    ratelimit::{RateLimitKey, RouteRateLimit},
+    response_validation::ResponseValidation,
+    rollout::{Rollout, Shadow},
+    throttle::RouteBandwidth,
+    waf::RuleSet,
+};
 
-use crate::config::{LbStrategy, PrxConfig};
+type LabelPairs<'a> = HashSet<(&'a str, &'a str)>;
 
 #[derive(Debug)]
 pub struct RuntimeConfig {
     routes: Vec<RouteRuntime>,
     services: Vec<ServiceRuntime>,
+    tcp_routes: Vec<TcpRouteRuntime>,
+    observability: ObservabilityRuntime,
+    /// Base observability of each `[[app]]`, layered over the global block.
+    app_observability: HashMap<String, ObservabilityRuntime>,
+    waf: Option<Arc<RuleSet>>,
+    access_log_fields: AccessLogFieldsConfig,
+    trusted_proxies: Vec<Cidr>,
+    max_concurrent_requests: Option<u64>,
+    noise_paths: Vec<String>,
+    observe_noise: bool,
+    max_metric_label_values: usize,
+    drain_timeout: Duration,
+    admin_auth: Option<Arc<AdminAuth>>,
+    admin_audit_log: Option<String>,
+    /// Set while this snapshot is being phased in over a previous one.
+    rollout: ArcSwapOption<Rollout>,
+    /// Set while this snapshot is evaluated against a previous one.
+    shadow: ArcSwapOption<Shadow>,
 }
 
 impl RuntimeConfig {
-    pub fn from_config(config: PrxConfig) -> Self {
+    pub fn from_config(config: PrxConfig) -> anyhow::Result<Self> {
+        Ok(Self::build(config, None)?.0)
+    }
+
+    /// Builds the next snapshot from `config`, reusing services and routes whose
+    /// definitions did not change since `self`.
+    ///
+    /// Reused services share their upstream state (circuit breaker counters,
+    /// round-robin cursor) with the previous snapshot, so an unrelated edit does
+    /// not reset them. A changed service takes over the state of every upstream
+    /// it kept by `addr`, so a reload during an outage does not resend traffic
+    /// to upstreams whose breakers are open.
+    ///
+    /// With `server.rollout` set, a snapshot that changed anything starts out
+    /// serving no traffic and ramps up over the stable one; see
+    /// [`RuntimeConfig::for_request`]. `server.shadow` instead holds it back
+    /// entirely until its evaluation window ends.
+    ///
+    /// Fails, leaving `self` untouched, when an encrypted value no longer
+    /// decrypts, e.g. because the config key changed since validation.
+    pub fn rebuild(self: &Arc<Self>, config: PrxConfig) -> anyhow::Result<(Self, RebuildStats)> {
+        let rollout = config.server.rollout.clone();
+        let shadow = config.server.shadow.clone();
+        let (next, stats) = Self::build(config, Some(self))?;
+        let changed = stats.rebuilt_services + stats.rebuilt_routes > 0
+            || next.services.len() != self.services.len()
+            || next.routes.len() != self.routes.len();
+        match (rollout, shadow) {
+            (Some(rollout), _) if changed => {
+                // A rollout that is superseded keeps its stable side, so
+                // snapshots never chain more than one level deep.
+                let stable = match self.rollout() {
+                    Some(current) if !current.is_complete() => current.stable().clone(),
+                    _ => self.clone(),
+                };
+                info!(ramp_ms = rollout.ramp_ms, "staging config rollout");
+                next.rollout
+                    .store(Some(Arc::new(Rollout::new(stable, rollout))));
+            }
+            (None, Some(shadow)) if changed => {
+                let stable = match self.shadow() {
+                    Some(current) if !current.is_complete() => current.stable().clone(),
+                    _ => self.clone(),
+                };
+                info!(
+                    duration_ms = shadow.duration_ms,
+                    "staging config shadow evaluation"
+                );
+                next.shadow
+                    .store(Some(Arc::new(Shadow::new(stable, shadow))));
+            }
+            _ => self.retire_stale(&next),
+        }
+        Ok((next, stats))
+    }
+
+    /// The snapshot to serve one request from: `self`, or the stable
+    /// snapshot for the share of traffic a rollout has not reached yet, or
+    /// for all of it during a shadow evaluation.
+    pub fn for_request(self: &Arc<Self>) -> Arc<Self> {
+        if let Some(shadow) = self.shadow() {
+            if !shadow.is_complete() {
+                return shadow.stable().clone();
+            }
+            if self.shadow.swap(None).is_some() {
+                let (requests, divergences) = shadow.totals();
+                info!(requests, divergences, "config shadow evaluation complete");
+                shadow.stable().retire_stale(self);
+            }
+        }
+        let Some(rollout) = self.rollout() else {
+            return self.clone();
+        };
+        if rollout.is_complete() {
+            if self.rollout.swap(None).is_some() {
+                info!("config rollout complete");
+                rollout.stable().retire_stale(self);
+            }
+            return self.clone();
+        }
+        if rollout.serve_candidate() {
+            self.clone()
+        } else {
+            rollout.stable().clone()
+        }
+    }
+
+    pub fn rollout(&self) -> Option<Arc<Rollout>> {
+        self.rollout.load_full()
+    }
+
+    pub fn shadow(&self) -> Option<Arc<Shadow>> {
+        self.shadow.load_full()
+    }
+
+    /// `[admin.auth]`, with plaintext secrets; `None` leaves the admin API open.
+    pub fn admin_auth(&self) -> Option<&Arc<AdminAuth>> {
+        self.admin_auth.as_ref()
+    }
+
+    /// `admin.audit_log` as configured, relative to the config file.
+    pub fn admin_audit_log(&self) -> Option<&str> {
+        self.admin_audit_log.as_deref()
+    }
+
+    fn build(
+        config: PrxConfig,
+        previous: Option<&RuntimeConfig>,
+    ) -> anyhow::Result<(Self, RebuildStats)> {
+        let config =
+            crate::secret::reveal(&config).context("failed to decrypt encrypted config values")?;
+        let mut stats = RebuildStats::default();
+        // Rule files are read again on every build; if one became unreadable
+        // since validation, keep inspecting with the previous rules.
+        let waf = config.waf.as_ref().and_then(|waf| match RuleSet::load(waf) {
+            Ok(rules) => Some(Arc::new(rules)),
+            Err(err) => {
+                error!(error = %format!("{err:#}"), "failed to load waf rules; keeping previous rules");
+                previous.and_then(|prev| prev.waf.clone())
+            }
+        });
+        let previous_services: HashMap<&str, &ServiceRuntime> = previous
+            .map(|prev| {
+                prev.services
+                    .iter()
+                    .map(|svc| (svc.name.as_str(), svc))
+                    .collect()
+            })
+            .unwrap_or_default();
+
         // Build services first with their upstreams
         let services = config
             .services
             .into_iter()
-            .map(ServiceRuntime::from_config)
-            .collect::<Vec<_>>();
+            .map(|svc| match previous_services.get(svc.name.as_str()) {
+                Some(prev) if prev.source == svc => {
+                    stats.reused_services += 1;
+                    Ok((*prev).clone())
+                }
+                prev => {
+                    stats.rebuilt_services += 1;
+                    let mut service = ServiceRuntime::from_config(svc);
+                    #[cfg(feature = "openssl")]
+                    service.load_tls()?;
+                    if let Some(prev) = prev {
+                        service.carry_over(prev);
+                    }
+                    // Upstreams a reload adds warm up; those at startup don't.
+                    if previous.is_some() {
+                        service
+                            .upstreams
+                            .iter()
+                            .filter(|upstream| {
+                                prev.is_none_or(|prev| {
+                                    !prev.upstreams.iter().any(|old| old.addr == upstream.addr)
+                                })
+                            })
+                            .for_each(UpstreamRuntime::begin_slow_start);
+                    }
+                    Ok(service)
+                }
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
 
         // Build a name-to-index map for service resolution
-        let service_index: std::collections::HashMap<String, usize> = services
+        let service_index: HashMap<String, usize> = services
             .iter()
             .enumerate()
             .map(|(idx, svc)| (svc.name.clone(), idx))
             .collect();
 
+        let observability = ObservabilityRuntime::from_config(&config.observability);
+        let app_observability: HashMap<String, ObservabilityRuntime> = config
+            .apps
+            .iter()
+            .map(|app| (app.name.clone(), observability.layered(&app.observability)))
+            .collect();
+        let previous_routes: HashMap<&str, &RouteRuntime> = previous
+            .filter(|prev| {
+                prev.observability == observability && prev.app_observability == app_observability
+            })
+            .map(|prev| {
+                prev.routes
+                    .iter()
+                    .map(|route| (&*route.name, route))
+                    .collect()
+            })
+            .unwrap_or_default();
+
         // Build routes, resolving service names to indices
         let mut routes = config
             .routes
             .into_iter()
-            .map(|route| RouteRuntime::from_config(route, &service_index))
+            .map(|route| match previous_routes.get(route.name.as_str()) {
+                Some(prev) if prev.source == route => {
+                    stats.reused_routes += 1;
+                    RouteRuntime {
+                        service_idx: resolve_service_idx(&service_index, &route.service),
+                        groups: route_groups(&route, &service_index),
+                        ..(*prev).clone()
+                    }
+                }
+                _ => {
+                    stats.rebuilt_routes += 1;
+                    let base = route
+                        .app
+                        .as_ref()
+                        .and_then(|app| app_observability.get(app))
+                        .unwrap_or(&observability);
+                    RouteRuntime::from_config(route, &service_index, base)
+                }
+            })
             .collect::<Vec<_>>();
 
-        // Sort routes by path_prefix length (longest first) for matching
+        // Sort routes by path_prefix length (longest first) for matching; on
+        // equal prefixes, routes with more header conditions go first.
         routes.sort_by(|a, b| {
             b.path_prefix
                 .len()
                 .cmp(&a.path_prefix.len())
+                .then_with(|| b.headers.len().cmp(&a.headers.len()))
                 .then_with(|| a.name.cmp(&b.name))
         });
 
-        Self { routes, services }
+        let tcp_routes = config
+            .tcp_routes
+            .iter()
+            .map(|route| TcpRouteRuntime {
+                name: Arc::from(route.name.as_str()),
+                service_idx: resolve_service_idx(&service_index, &route.service),
+                idle_timeout: route.idle_timeout_ms.map(Duration::from_millis),
+            })
+            .collect();
+
+        let mut runtime = Self {
+            routes,
+            services,
+            tcp_routes,
+            observability,
+            app_observability,
+            waf,
+            access_log_fields: config.observability.access_log_fields.clone(),
+            // Entries are checked by PrxConfig::validate.
+            trusted_proxies: config
+                .server
+                .trusted_proxies
+                .iter()
+                .filter_map(|entry| entry.parse().ok())
+                .collect(),
+            max_concurrent_requests: config.server.max_concurrent_requests,
+            noise_paths: config.server.noise_paths.clone(),
+            observe_noise: config.server.observe_noise,
+            max_metric_label_values: config.observability.max_metric_label_values,
+            drain_timeout: Duration::from_secs(config.server.drain_timeout_s),
+            admin_auth: config
+                .admin
+                .as_ref()
+                .and_then(|admin| admin.auth.as_ref())
+                .map(|auth| Arc::new(AdminAuth::from_config(auth))),
+            admin_audit_log: config
+                .admin
+                .as_ref()
+                .and_then(|admin| admin.audit_log.clone()),
+            rollout: ArcSwapOption::empty(),
+            shadow: ArcSwapOption::empty(),
+        };
+        runtime.assign_metric_labels(config.observability.max_metric_label_values);
+        Ok((runtime, stats))
+    }
+
+    /// This snapshot with `found` as the upstreams `discovery` turned up for
+    /// `service`, after its listed ones. `None` when they are the same already,
+    /// or a reload replaced the service since the lookup started.
+    pub fn with_discovered(
+        &self,
+        service: &str,
+        discovery: &Arc<ServiceDiscovery>,
+        mut found: Vec<crate::config::UpstreamConfig>,
+    ) -> Option<Self> {
+        let idx = self.services.iter().position(|svc| svc.name == service)?;
+        let current = &self.services[idx];
+        if !current
+            .discovery
+            .as_ref()
+            .is_some_and(|own| Arc::ptr_eq(own, discovery))
+        {
+            return None;
+        }
+        found.sort_by(|a, b| a.addr.cmp(&b.addr));
+        found.dedup_by(|a, b| a.addr == b.addr);
+        let listed = current.source.upstreams.len();
+        let discovered = &current.upstreams[listed..];
+        if found.len() == discovered.len()
+            && found.iter().zip(discovered).all(|(found, upstream)| {
+                *upstream.addr == *found.addr
+                    && upstream.weight == found.weight.max(1)
+                    && upstream.tier == found.tier
+            })
+        {
+            return None;
+        }
+
+        let mut upstreams = current.upstreams[..listed].to_vec();
+        for config in found {
+            // Records that stay keep their breaker, health and counters.
+            match discovered.iter().find(|old| *old.addr == config.addr) {
+                Some(old) => upstreams.push(UpstreamRuntime {
+                    weight: config.weight.max(1),
+                    tier: config.tier,
+                    ..old.clone()
+                }),
+                None => {
+                    let upstream = UpstreamRuntime::from_config(config);
+                    if !discovered.is_empty() {
+                        upstream.begin_slow_start();
+                    }
+                    upstreams.push(upstream);
+                }
+            }
+        }
+        let mut services = self.services.clone();
+        services[idx] = ServiceRuntime {
+            ring: build_selection_ring(&upstreams),
+            tiers: upstream_tiers(&upstreams),
+            upstreams,
+            ..current.clone()
+        };
+        let mut next = Self {
+            routes: self.routes.clone(),
+            services,
+            tcp_routes: self.tcp_routes.clone(),
+            observability: self.observability,
+            app_observability: self.app_observability.clone(),
+            waf: self.waf.clone(),
+            access_log_fields: self.access_log_fields.clone(),
+            trusted_proxies: self.trusted_proxies.clone(),
+            max_concurrent_requests: self.max_concurrent_requests,
+            noise_paths: self.noise_paths.clone(),
+            observe_noise: self.observe_noise,
+            max_metric_label_values: self.max_metric_label_values,
+            drain_timeout: self.drain_timeout,
+            admin_auth: self.admin_auth.clone(),
+            admin_audit_log: self.admin_audit_log.clone(),
+            rollout: ArcSwapOption::new(self.rollout.load_full()),
+            shadow: ArcSwapOption::new(self.shadow.load_full()),
+        };
+        next.assign_metric_labels(self.max_metric_label_values);
+        Some(next)
+    }
+
+    /// Caps distinct route and upstream label values; anything past `limit`
+    /// is reported under the shared overflow label.
+    fn assign_metric_labels(&mut self, limit: usize) {
+        let overflow: Arc<str> = Arc::from(metrics::OVERFLOW_LABEL);
+        let mut upstream_labels: HashMap<Arc<str>, Arc<str>> = HashMap::new();
+        for service in &mut self.services {
+            for upstream in &mut service.upstreams {
+                let admitted = upstream_labels.len() < limit;
+                let label = upstream_labels
+                    .entry(upstream.addr.clone())
+                    .or_insert_with(|| {
+                        if admitted {
+                            upstream.addr.clone()
+                        } else {
+                            overflow.clone()
+                        }
+                    })
+                    .clone();
+                upstream.metric_label = label;
+            }
+        }
+        for (idx, route) in self.routes.iter_mut().enumerate() {
+            route.metric_label = if idx < limit {
+                route.name.clone()
+            } else {
+                overflow.clone()
+            };
+        }
+    }
+
+    /// Drops metric series for routes and route/upstream pairs that `next` no
+    /// longer has, so reloads and discovery churn do not leave stale series,
+    /// and drains upstreams `next` took out of their service.
+    pub(crate) fn retire_stale(&self, next: &RuntimeConfig) {
+        for service in &self.services {
+            let kept = next.services.iter().find(|kept| kept.name == service.name);
+            for upstream in &service.upstreams {
+                let removed = !kept.is_some_and(|kept| {
+                    kept.upstreams.iter().any(|kept| kept.addr == upstream.addr)
+                });
+                if removed {
+                    drain::signal_upstream(&service.name, upstream);
+                    drain::drain_upstream(&service.name, upstream, next.drain_timeout);
+                }
+            }
+        }
+        let (previous_routes, previous_pairs) = self.metric_series();
+        let (next_routes, next_pairs) = next.metric_series();
+        for (route, tenant) in previous_routes.difference(&next_routes) {
+            metrics::remove_route_series(route, tenant);
+        }
+        for (route, upstream) in previous_pairs.difference(&next_pairs) {
+            metrics::remove_upstream_series(route, upstream);
+        }
+        for (route, group) in self.group_series().difference(&next.group_series()) {
+            metrics::remove_group_series(route, group);
+        }
+    }
+
+    /// `(route, group)` label pairs, `-` standing for the route's own service.
+    fn group_series(&self) -> LabelPairs<'_> {
+        self.routes
+            .iter()
+            .filter(|route| !route.groups.is_empty())
+            .flat_map(|route| {
+                std::iter::once("-")
+                    .chain(route.groups.iter().map(|group| &*group.name))
+                    .map(|group| (&*route.metric_label, group))
+            })
+            .collect()
+    }
+
+    /// `(route, tenant)` label pairs and `(route, upstream)` label pairs.
+    fn metric_series(&self) -> (LabelPairs<'_>, LabelPairs<'_>) {
+        let mut routes = HashSet::new();
+        let mut pairs = HashSet::new();
+        for route in &self.routes {
+            routes.insert((&*route.metric_label, &*route.tenant));
+            for service in route
+                .service_indices()
+                .filter_map(|idx| self.services.get(idx))
+            {
+                for upstream in &service.upstreams {
+                    pairs.insert((&*route.metric_label, &*upstream.metric_label));
+                }
+            }
+        }
+        for route in &self.tcp_routes {
+            for upstream in self
+                .services
+                .get(route.service_idx)
+                .into_iter()
+                .flat_map(|service| &service.upstreams)
+            {
+                pairs.insert((&*route.name, &*upstream.metric_label));
+            }
+        }
+        (routes, pairs)
     }
 
-    pub fn select_route(&self, host: &str, path: &str) -> Option<usize> {
+    /// Picks the route for a request among the routes of `app` (`None` being
+    /// the main proxy).
+    pub fn select_route(
+        &self,
+        app: Option<&str>,
+        host: &str,
+        path: &str,
+        headers: &HeaderMap,
+    ) -> Option<usize> {
         let normalized = normalize_host(host);
         let mut fallback_idx = None;
 
         for (idx, route) in self.routes.iter().enumerate() {
+            if route.app.as_deref() != app {
+                continue;
+            }
             if route.is_default && fallback_idx.is_none() {
                 fallback_idx = Some(idx);
             }
 
-            if !route.matches_host(&normalized) {
+            if !route.matches_host(&normalized) || !route.matches_headers(headers) {
                 continue;
             }
 
@@ -74,26 +550,147 @@ impl RuntimeConfig {
         fallback_idx
     }
 
+    /// Whether a route of `app` names the host or takes any host; `host`
+    /// is normalized.
+    pub fn covers_host(&self, app: Option<&str>, host: &str) -> bool {
+        self.routes
+            .iter()
+            .any(|route| route.app.as_deref() == app && route.matches_host(host))
+    }
+
+    pub fn waf(&self) -> Option<&RuleSet> {
+        self.waf.as_deref()
+    }
+
     pub fn route(&self, idx: usize) -> Option<&RouteRuntime> {
         self.routes.get(idx)
     }
 
+    pub fn routes(&self) -> &[RouteRuntime] {
+        &self.routes
+    }
+
+    pub fn tcp_route(&self, name: &str) -> Option<&TcpRouteRuntime> {
+        self.tcp_routes.iter().find(|route| &*route.name == name)
+    }
+
+    pub fn services(&self) -> &[ServiceRuntime] {
+        &self.services
+    }
+
     pub fn service(&self, idx: usize) -> Option<&ServiceRuntime> {
         self.services.get(idx)
     }
 
+    /// Effective observability settings for a request, falling back to the
+    /// app (or global) block for requests that never matched a route.
+    pub fn observability(
+        &self,
+        app: Option<&str>,
+        route_idx: Option<usize>,
+    ) -> ObservabilityRuntime {
+        route_idx
+            .and_then(|idx| self.route(idx))
+            .map(|route| route.observability)
+            .or_else(|| app.and_then(|app| self.app_observability.get(app).copied()))
+            .unwrap_or(self.observability)
+    }
+
+    pub fn access_log_fields(&self) -> &AccessLogFieldsConfig {
+        &self.access_log_fields
+    }
+
+    /// Whether requests for `path` stay out of request metrics, access logs
+    /// and rate limits; `builtin` is set for the health, ready and load paths.
+    pub fn is_noise(&self, path: &str, builtin: bool) -> bool {
+        !self.observe_noise
+            && (builtin
+                || self
+                    .noise_paths
+                    .iter()
+                    .any(|pattern| match pattern.strip_suffix('*') {
+                        Some(prefix) => path.starts_with(prefix),
+                        None => path == pattern,
+                    }))
+    }
+
+    pub fn max_concurrent_requests(&self) -> Option<u64> {
+        self.max_concurrent_requests
+    }
+
+    pub fn is_trusted_proxy(&self, ip: IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|cidr| cidr.contains(ip))
+    }
+
     pub fn is_ready(&self) -> bool {
         self.services
             .iter()
             .all(ServiceRuntime::has_available_upstream)
     }
+
+    /// Clears circuit breaker, connection, health, bandwidth, rate limit,
+    /// preflight cache and idempotency state as if the snapshot had just been built.
+    /// Prometheus counters are left alone.
+    pub fn reset_stats(&self) {
+        for service in &self.services {
+            service.rr_cursor.store(0, Ordering::Relaxed);
+            for upstream in &service.upstreams {
+                upstream.reset_stats();
+            }
+        }
+        for route in &self.routes {
+            if let Some(bandwidth) = &route.bandwidth {
+                bandwidth.reset();
+            }
+            if let Some(limit) = &route.rate_limit {
+                limit.reset();
+            }
+            if let Some(cache) = &route.preflight_cache {
+                cache.reset();
+            }
+            if let Some(idempotency) = &route.idempotency {
+                idempotency.reset();
+            }
+            for service in route
+                .service_indices()
+                .filter_map(|idx| self.services.get(idx))
+            {
+                for upstream in &service.upstreams {
+                    metrics::set_circuit_state(&route.metric_label, &upstream.metric_label, false);
+                }
+            }
+        }
+    }
+
+    /// Drops the cached preflight answers and idempotency replays `purge`
+    /// matches on route `name`; `None` when there is no such route.
+    pub fn purge_route_caches(&self, name: &str, purge: &Purge) -> Option<usize> {
+        let route = self.routes.iter().find(|route| &*route.name == name)?;
+        let preflight = route
+            .preflight_cache
+            .as_ref()
+            .map_or(0, |cache| cache.purge(purge));
+        let idempotency = route
+            .idempotency
+            .as_ref()
+            .map_or(0, |cache| cache.purge(purge));
+        Some(preflight + idempotency)
+    }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 pub struct CircuitBreakerRuntime {
     enabled: bool,
     consecutive_failures: usize,
     open_ms: u64,
+    half_open_probes: usize,
+    max_open_ms: u64,
+    failure_on: Vec<BreakerFailure>,
+    failure_statuses: Vec<u16>,
+    overload_header: Option<HeaderName>,
+    overload_statuses: Vec<u16>,
+    pub overload_ms: u64,
+    overload_weight_percent: u64,
 }
 
 impl CircuitBreakerRuntime {
@@ -102,40 +699,414 @@ impl CircuitBreakerRuntime {
             enabled: config.enabled,
             consecutive_failures: config.consecutive_failures.max(1),
             open_ms: config.open_ms.max(1),
+            half_open_probes: config.half_open_probes.max(1),
+            max_open_ms: config.max_open_ms.max(config.open_ms).max(1),
+            failure_on: config.failure_on.clone(),
+            failure_statuses: config.failure_statuses.clone(),
+            // The header name is checked by `PrxConfig::validate`.
+            overload_header: config
+                .overload_header
+                .as_deref()
+                .and_then(|name| HeaderName::from_bytes(name.as_bytes()).ok()),
+            overload_statuses: config.overload_statuses.clone(),
+            overload_ms: config.overload_ms,
+            overload_weight_percent: config.overload_weight_percent.min(100).into(),
+        }
+    }
+
+    /// Whether an upstream response asks for less traffic: an overload
+    /// status, or the overload header with a value other than `0`/`false`.
+    pub fn signals_overload(&self, status: u16, headers: &HeaderMap) -> bool {
+        if self.overload_statuses.contains(&status) {
+            return true;
+        }
+        self.overload_header
+            .as_ref()
+            .and_then(|name| headers.get(name))
+            .is_some_and(|value| {
+                let value = value.to_str().unwrap_or_default().trim();
+                !(value == "0" || value.eq_ignore_ascii_case("false"))
+            })
+    }
+
+    /// Whether `failure` counts toward opening the breaker.
+    pub fn counts(&self, failure: UpstreamFailure) -> bool {
+        match failure {
+            UpstreamFailure::Connect => self.failure_on.contains(&BreakerFailure::Connect),
+            UpstreamFailure::Timeout => self.failure_on.contains(&BreakerFailure::Timeout),
+            UpstreamFailure::Proxy => self.failure_on.contains(&BreakerFailure::ProxyError),
+            UpstreamFailure::Status(status) => {
+                (status >= 500 && self.failure_on.contains(&BreakerFailure::ServerError))
+                    || self.failure_statuses.contains(&status)
+            }
         }
     }
 }
 
-#[derive(Debug)]
+/// Answers kept per upstream for outlier detection, newest last.
+pub const MAX_OUTLIER_SAMPLES: usize = 1024;
+
+/// Parsed `OutlierDetectionConfig`.
+#[derive(Debug, Clone)]
+pub struct OutlierDetection {
+    window_ms: u64,
+    min_requests: usize,
+    max_error_rate: Option<f64>,
+    max_p99_latency_ms: Option<u64>,
+    eject_ms: u64,
+    reintroduce_ms: u64,
+}
+
+impl OutlierDetection {
+    fn from_config(config: &OutlierDetectionConfig) -> Self {
+        Self {
+            window_ms: config.window_ms.max(1),
+            min_requests: config.min_requests.max(1),
+            max_error_rate: config.max_error_rate,
+            max_p99_latency_ms: config.max_p99_latency_ms,
+            eject_ms: config.eject_ms,
+            reintroduce_ms: config.reintroduce_ms,
+        }
+    }
+
+    fn is_outlier(&self, samples: &VecDeque<OutlierSample>) -> bool {
+        let failed = samples.iter().filter(|sample| sample.failed).count();
+        if self
+            .max_error_rate
+            .is_some_and(|max| failed as f64 / samples.len() as f64 > max)
+        {
+            return true;
+        }
+        self.max_p99_latency_ms.is_some_and(|max| {
+            let mut latencies: Vec<u64> = samples.iter().map(|sample| sample.latency_ms).collect();
+            // Nearest rank.
+            let rank = (latencies.len() * 99).div_ceil(100) - 1;
+            *latencies.select_nth_unstable(rank).1 > max
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct OutlierSample {
+    at_ms: u64,
+    latency_ms: u64,
+    failed: bool,
+}
+
+#[derive(Debug, Default)]
+struct OutlierWindow {
+    samples: VecDeque<OutlierSample>,
+    evaluated_at_ms: u64,
+}
+
+/// A `retry_on` entry of a service.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetryOn {
+    Connect,
+    Timeout,
+    ProxyError,
+    ServerError,
+    Status(u16),
+}
+
+impl FromStr for RetryOn {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> anyhow::Result<Self> {
+        Ok(match value {
+            "connect" => Self::Connect,
+            "timeout" => Self::Timeout,
+            "proxy_error" => Self::ProxyError,
+            "5xx" => Self::ServerError,
+            _ => match value.parse::<u16>() {
+                Ok(status) if (100..600).contains(&status) => Self::Status(status),
+                _ => bail!(
+                    "retry condition {value:?} must be connect, timeout, proxy_error, 5xx or a status"
+                ),
+            },
+        })
+    }
+}
+
+/// When and how soon a service retries a request on another upstream.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    on: Vec<RetryOn>,
+    methods: Vec<http::Method>,
+    backoff: Option<RetryBackoffConfig>,
+    backoff_ms: u64,
+}
+
+impl RetryPolicy {
+    fn from_config(config: &crate::config::ServiceConfig) -> Self {
+        // Entries are checked by `PrxConfig::validate`.
+        let mut on: Vec<RetryOn> = config
+            .retry_on
+            .iter()
+            .filter_map(|condition| condition.parse().ok())
+            .collect();
+        if on.is_empty() {
+            on = vec![RetryOn::Connect, RetryOn::Timeout, RetryOn::ProxyError];
+        }
+        Self {
+            on,
+            methods: config
+                .retry_methods
+                .iter()
+                .filter_map(|method| http::Method::from_bytes(method.as_bytes()).ok())
+                .collect(),
+            backoff: config.retry_backoff.clone(),
+            backoff_ms: config.retry_backoff_ms,
+        }
+    }
+
+    /// Whether `failure` of a `method` request is worth another upstream.
+    /// Methods only matter once the request may have reached the upstream;
+    /// tcp routes pass none.
+    pub fn retries(&self, failure: UpstreamFailure, method: Option<&http::Method>) -> bool {
+        let listed = match failure {
+            UpstreamFailure::Connect => return self.on.contains(&RetryOn::Connect),
+            UpstreamFailure::Timeout => self.on.contains(&RetryOn::Timeout),
+            UpstreamFailure::Proxy => self.on.contains(&RetryOn::ProxyError),
+            UpstreamFailure::Status(status) => {
+                (status >= 500 && self.on.contains(&RetryOn::ServerError))
+                    || self.on.contains(&RetryOn::Status(status))
+            }
+        };
+        listed
+            && (self.methods.is_empty()
+                || method.is_some_and(|method| self.methods.contains(method)))
+    }
+
+    /// Whether any upstream status is retried.
+    pub fn retries_statuses(&self) -> bool {
+        self.on
+            .iter()
+            .any(|condition| matches!(condition, RetryOn::ServerError | RetryOn::Status(_)))
+    }
+
+    /// The wait before the `retry`-th retry (counting from 1).
+    pub fn backoff(&self, retry: usize) -> Option<Duration> {
+        let Some(backoff) = &self.backoff else {
+            return (self.backoff_ms > 0).then(|| Duration::from_millis(self.backoff_ms));
+        };
+        let doublings = u32::try_from(retry.saturating_sub(1))
+            .unwrap_or(u32::MAX)
+            .min(63);
+        let delay = backoff
+            .base_ms
+            .saturating_mul(1 << doublings)
+            .min(backoff.max_ms);
+        let delay = if backoff.jitter {
+            rand::rng().random_range(0..=delay)
+        } else {
+            delay
+        };
+        Some(Duration::from_millis(delay))
+    }
+}
+
+/// How an attempt at an upstream went wrong.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpstreamFailure {
+    Connect,
+    Timeout,
+    Proxy,
+    Status(u16),
+}
+
+impl UpstreamFailure {
+    /// The `stage` label of `prx_upstream_errors_total`.
+    pub fn stage(self) -> &'static str {
+        match self {
+            Self::Connect => "connect",
+            Self::Timeout | Self::Proxy => "proxy",
+            Self::Status(_) => "status",
+        }
+    }
+}
+
+/// Counts of services and routes carried over or rebuilt by [`RuntimeConfig::rebuild`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RebuildStats {
+    pub reused_services: usize,
+    pub rebuilt_services: usize,
+    pub reused_routes: usize,
+    pub rebuilt_routes: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ObservabilityRuntime {
+    pub access_log: bool,
+    pub access_log_sample_rate: f64,
+}
+
+impl ObservabilityRuntime {
+    fn from_config(config: &ObservabilityConfig) -> Self {
+        Self {
+            access_log: config.access_log,
+            access_log_sample_rate: config.access_log_sample_rate.clamp(0.0, 1.0),
+        }
+    }
+
+    fn layered(self, overrides: &RouteObservabilityConfig) -> Self {
+        Self {
+            access_log: overrides.access_log.unwrap_or(self.access_log),
+            access_log_sample_rate: overrides
+                .access_log_sample_rate
+                .map(|rate| rate.clamp(0.0, 1.0))
+                .unwrap_or(self.access_log_sample_rate),
+        }
+    }
+
+    pub fn should_log_access(&self) -> bool {
+        if !self.access_log || self.access_log_sample_rate <= 0.0 {
+            return false;
+        }
+        self.access_log_sample_rate >= 1.0 || rand::rng().random_bool(self.access_log_sample_rate)
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct RouteRuntime {
-    pub name: String,
+    pub name: Arc<str>,
+    pub app: Option<Arc<str>>,
+    /// Value used for the `route` metric label (the name, or the overflow label).
+    pub metric_label: Arc<str>,
+    /// Value used for the `tenant` metric label; empty for routes without one.
+    pub tenant: Arc<str>,
     pub host: Option<String>,
     pub path_prefix: String,
+    pub headers: Vec<(HeaderName, HeaderValue)>,
     pub is_default: bool,
     pub service_idx: usize,
+    pub groups: Vec<RouteGroup>,
+    group_key: RateLimitKey,
+    pub group_header: Option<HeaderName>,
+    pub observability: ObservabilityRuntime,
+    pub bandwidth: Option<Arc<RouteBandwidth>>,
+    pub rate_limit: Option<Arc<RouteRateLimit>>,
+    pub max_concurrent_requests: Option<u64>,
+    pub preflight_cache: Option<Arc<PreflightCache>>,
+    pub idempotency: Option<Arc<IdempotencyCache>>,
+    // Shared with the previous snapshot when the route is reused.
+    in_flight: Arc<AtomicUsize>,
+    websockets: Arc<AtomicUsize>,
+    /// Set for `websocket` routes.
+    pub websocket_idle_timeout: Option<Duration>,
+    pub max_response_bytes: Option<u64>,
+    pub max_request_body_bytes: Option<u64>,
+    pub retry_body_bytes: Option<u64>,
+    pub timeout: Option<Duration>,
+    strip_prefix: bool,
+    rewrite_path: Option<String>,
+    pub forwarded_headers: ForwardedHeadersPolicy,
+    pub egress: Egress,
+    pub outbound_proxy: Option<Arc<OutboundProxy>>,
+    pub grpc: bool,
+    status_map: Vec<StatusMapping>,
+    pub response_validation: Option<ResponseValidation>,
+    pub header_case: HeaderCase,
+    pub merge_duplicate_headers: bool,
+    pub hash_affinity: HashAffinity,
+    source: crate::config::RouteConfig,
 }
 
 impl RouteRuntime {
     fn from_config(
         config: crate::config::RouteConfig,
-        service_index: &std::collections::HashMap<String, usize>,
+        service_index: &HashMap<String, usize>,
+        observability: &ObservabilityRuntime,
     ) -> Self {
         let host = config.host.as_deref().map(normalize_host);
-        let service_idx = service_index
-            .get(&config.service)
-            .copied()
-            .expect("route references a service that was not found in service_index");
+        let service_idx = resolve_service_idx(service_index, &config.service);
 
         Self {
-            name: config.name,
+            name: Arc::from(config.name.as_str()),
+            app: config.app.as_deref().map(Arc::from),
+            metric_label: Arc::from(config.name.as_str()),
+            tenant: Arc::from(config.tenant.as_deref().unwrap_or_default()),
             host,
-            path_prefix: config.path_prefix,
+            path_prefix: config.path_prefix.clone(),
+            headers: config
+                .headers
+                .iter()
+                .filter_map(|(name, value)| {
+                    Some((
+                        HeaderName::from_bytes(name.as_bytes()).ok()?,
+                        HeaderValue::from_str(value).ok()?,
+                    ))
+                })
+                .collect(),
             is_default: config.is_default,
             service_idx,
+            groups: route_groups(&config, service_index),
+            // The key is checked by PrxConfig::validate.
+            group_key: config
+                .group_key
+                .as_deref()
+                .and_then(|key| key.parse().ok())
+                .unwrap_or(RateLimitKey::ClientIp),
+            // The header name is checked by `PrxConfig::validate`.
+            group_header: config
+                .group_header
+                .as_deref()
+                .and_then(|header| HeaderName::from_bytes(header.as_bytes()).ok()),
+            observability: observability.layered(&config.observability),
+            bandwidth: config
+                .bandwidth
+                .as_ref()
+                .map(|bandwidth| Arc::new(RouteBandwidth::from_config(bandwidth))),
+            rate_limit: config
+                .rate_limit
+                .as_ref()
+                .map(|limit| Arc::new(RouteRateLimit::from_config(limit))),
+            max_concurrent_requests: config.max_concurrent_requests,
+            preflight_cache: config
+                .preflight_cache
+                .as_ref()
+                .map(|cache| Arc::new(PreflightCache::from_config(cache))),
+            idempotency: config
+                .idempotency
+                .as_ref()
+                .map(|idempotency| Arc::new(IdempotencyCache::from_config(idempotency))),
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            websockets: Arc::new(AtomicUsize::new(0)),
+            websocket_idle_timeout: config.websocket.then(|| {
+                Duration::from_millis(config.websocket_idle_timeout_ms.unwrap_or(300_000))
+            }),
+            max_response_bytes: config.max_response_bytes,
+            max_request_body_bytes: config.max_request_body_bytes,
+            retry_body_bytes: config.retry_body_bytes,
+            timeout: config.timeout_ms.map(Duration::from_millis),
+            strip_prefix: config.strip_prefix,
+            rewrite_path: config.rewrite_path.clone(),
+            forwarded_headers: config.forwarded_headers,
+            egress: Egress::from_config(&config.egress),
+            // Checked by PrxConfig::validate.
+            outbound_proxy: config
+                .outbound_proxy
+                .as_ref()
+                .and_then(|proxy| proxy.parse().ok())
+                .map(Arc::new),
+            grpc: config.protocol == RouteProtocol::Grpc,
+            status_map: config
+                .status_map
+                .iter()
+                .map(StatusMapping::from_config)
+                .collect(),
+            response_validation: config
+                .response_validation
+                .as_ref()
+                .map(ResponseValidation::from_config),
+            header_case: config.header_case,
+            merge_duplicate_headers: config.merge_duplicate_headers,
+            hash_affinity: config.hash_affinity,
+            source: config,
         }
     }
 
-    fn matches_host(&self, request_host: &str) -> bool {
+    pub fn matches_host(&self, request_host: &str) -> bool {
         let Some(pattern) = &self.host else {
             return true;
         };
@@ -146,18 +1117,248 @@ impl RouteRuntime {
             pattern == request_host
         }
     }
+
+    /// Counts a request as in flight on this route, or returns `None` when
+    /// `max_concurrent_requests` are already in flight.
+    pub fn enter(&self) -> Option<RouteInFlight> {
+        let count = self.in_flight.fetch_add(1, Ordering::AcqRel) + 1;
+        let guard = RouteInFlight {
+            count: self.in_flight.clone(),
+            metric_label: self.metric_label.clone(),
+        };
+        metrics::set_route_in_flight(&self.metric_label, count);
+        if self
+            .max_concurrent_requests
+            .is_some_and(|limit| count as u64 > limit)
+        {
+            return None;
+        }
+        Some(guard)
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Acquire)
+    }
+
+    pub fn websockets(&self) -> usize {
+        self.websockets.load(Ordering::Acquire)
+    }
+
+    /// Counts an upgraded connection as open until the returned guard drops.
+    pub fn open_websocket(&self) -> WebSocketTunnel {
+        let count = self.websockets.fetch_add(1, Ordering::AcqRel) + 1;
+        metrics::set_websocket_connections(&self.metric_label, count);
+        WebSocketTunnel {
+            count: self.websockets.clone(),
+            metric_label: self.metric_label.clone(),
+        }
+    }
+
+    /// The group a request is assigned to by its `group_key`, or `None` for
+    /// the share served by the route's own service. Requests without the
+    /// header or cookie are assigned by client IP, and at random without one.
+    pub fn select_group(
+        &self,
+        client_ip: Option<IpAddr>,
+        headers: &HeaderMap,
+    ) -> Option<&RouteGroup> {
+        if self.groups.is_empty() {
+            return None;
+        }
+        let mut key = self.group_key.extract(client_ip, headers);
+        if key.is_empty() {
+            key = RateLimitKey::ClientIp.extract(client_ip, headers);
+        }
+        let bucket = if key.is_empty() {
+            rand::rng().random_range(0..100)
+        } else {
+            (hash_key(&[&key]) % 100) as u32
+        };
+        let mut upper = 0;
+        self.groups.iter().find(|group| {
+            upper += group.percent;
+            bucket < upper
+        })
+    }
+
+    /// The route's own service and those of its groups.
+    fn service_indices(&self) -> impl Iterator<Item = usize> + '_ {
+        std::iter::once(self.service_idx).chain(self.groups.iter().map(|group| group.service_idx))
+    }
+
+    /// Path to forward upstream, or `None` when the route forwards it as is.
+    pub fn upstream_path(&self, path: &str) -> Option<String> {
+        if !self.strip_prefix && self.rewrite_path.is_none() {
+            return None;
+        }
+        // Default routes can match paths outside their prefix.
+        let rest = path.strip_prefix(self.path_prefix.as_str()).unwrap_or(path);
+        let rest = if rest.starts_with('/') {
+            rest.to_string()
+        } else {
+            format!("/{rest}")
+        };
+        Some(match &self.rewrite_path {
+            Some(template) => template.replace("$1", &rest),
+            None => rest,
+        })
+    }
+
+    /// How an upstream answer with `status` reaches the client, if remapped.
+    pub fn status_mapping(&self, status: u16) -> Option<&StatusMapping> {
+        self.status_map
+            .iter()
+            .find(|mapping| mapping.from == status)
+    }
+
+    fn matches_headers(&self, request_headers: &HeaderMap) -> bool {
+        self.headers.iter().all(|(name, value)| {
+            request_headers
+                .get_all(name)
+                .iter()
+                .any(|candidate| candidate == value)
+        })
+    }
+}
+
+/// A `[[tcp_route]]`; its listeners are fixed at startup, everything else
+/// follows reloads.
+#[derive(Debug, Clone)]
+pub struct TcpRouteRuntime {
+    /// Also the `route` metric label.
+    pub name: Arc<str>,
+    pub service_idx: usize,
+    pub idle_timeout: Option<Duration>,
+}
+
+/// A `status_map` entry of a route.
+#[derive(Debug, Clone)]
+pub struct StatusMapping {
+    from: u16,
+    pub to: u16,
+    pub headers: Vec<(HeaderName, HeaderValue)>,
+    /// Replaces the upstream body when set.
+    pub body: Option<Bytes>,
+}
+
+impl StatusMapping {
+    // Statuses and headers are checked by PrxConfig::validate.
+    fn from_config(config: &StatusMapConfig) -> Self {
+        Self {
+            from: config.from,
+            to: config.to,
+            headers: config
+                .headers
+                .iter()
+                .filter_map(|(name, value)| {
+                    Some((
+                        HeaderName::from_bytes(name.as_bytes()).ok()?,
+                        HeaderValue::from_str(value).ok()?,
+                    ))
+                })
+                .collect(),
+            body: config.body.clone().map(Bytes::from),
+        }
+    }
+}
+
+/// A share of a route's traffic served by another service.
+#[derive(Debug, Clone)]
+pub struct RouteGroup {
+    pub name: Arc<str>,
+    pub service_idx: usize,
+    percent: u32,
+}
+
+fn route_groups(
+    config: &crate::config::RouteConfig,
+    service_index: &HashMap<String, usize>,
+) -> Vec<RouteGroup> {
+    config
+        .groups
+        .iter()
+        .map(|group| RouteGroup {
+            name: Arc::from(group.name.as_str()),
+            service_idx: resolve_service_idx(service_index, &group.service),
+            percent: group.percent,
+        })
+        .collect()
+}
+
+/// A request in flight on a route, counted until dropped.
+#[derive(Debug)]
+pub struct RouteInFlight {
+    count: Arc<AtomicUsize>,
+    metric_label: Arc<str>,
+}
+
+impl Drop for RouteInFlight {
+    fn drop(&mut self) {
+        self.count.fetch_sub(1, Ordering::AcqRel);
+        // Reading the count back rather than using the decremented value keeps
+        // racing drops from leaving a stale gauge behind.
+        metrics::set_route_in_flight(&self.metric_label, self.count.load(Ordering::Acquire));
+    }
+}
+
+/// A request sent to an upstream, counted until dropped.
+#[derive(Debug)]
+pub struct UpstreamRequest(Arc<UpstreamState>);
+
+impl UpstreamRequest {
+    /// Whether the upstream was removed by a reload and `drain_timeout_s`
+    /// has passed since.
+    pub fn drain_expired(&self) -> bool {
+        let deadline = self.0.drain_deadline_epoch_ms.load(Ordering::Acquire);
+        deadline != 0 && now_epoch_ms() >= deadline
+    }
 }
 
+impl Drop for UpstreamRequest {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// An open upgraded connection on a route.
 #[derive(Debug)]
+pub struct WebSocketTunnel {
+    count: Arc<AtomicUsize>,
+    metric_label: Arc<str>,
+}
+
+impl Drop for WebSocketTunnel {
+    fn drop(&mut self) {
+        self.count.fetch_sub(1, Ordering::AcqRel);
+        metrics::set_websocket_connections(&self.metric_label, self.count.load(Ordering::Acquire));
+    }
+}
+
+/// Latency a failed attempt counts as for `lb = "ewma"` at the least, so an
+/// upstream failing fast does not attract traffic.
+pub const EWMA_FAILURE_PENALTY: Duration = Duration::from_secs(1);
+
+/// How long after a connection went back to the pool it still counts as
+/// warm for `hash_affinity = "prefer_reuse"`.
+const POOL_WARM_MS: u64 = 4_000;
+
+#[derive(Debug, Clone)]
 pub struct ServiceRuntime {
     pub name: String,
     pub lb: LbStrategy,
     pub max_retries: usize,
-    pub retry_backoff_ms: u64,
+    pub retry: RetryPolicy,
     pub circuit_breaker: CircuitBreakerRuntime,
+    outlier_detection: Option<OutlierDetection>,
+    slow_start_ms: Option<u64>,
+    discovery: Option<Arc<ServiceDiscovery>>,
+    /// The listed upstreams, then any `discover` found.
     pub upstreams: Vec<UpstreamRuntime>,
     ring: Vec<usize>,
+    /// Distinct `tier`s of `upstreams`, lowest first.
+    tiers: Vec<u16>,
     rr_cursor: Arc<AtomicUsize>,
+    source: crate::config::ServiceConfig,
 }
 
 impl ServiceRuntime {
@@ -165,21 +1366,47 @@ impl ServiceRuntime {
         let circuit_breaker = CircuitBreakerRuntime::from_config(&config.circuit_breaker);
         let upstreams = config
             .upstreams
-            .into_iter()
+            .iter()
+            .cloned()
             .map(UpstreamRuntime::from_config)
             .collect::<Vec<_>>();
         let ring = build_selection_ring(&upstreams);
+        let tiers = upstream_tiers(&upstreams);
 
         Self {
-            name: config.name,
-            lb: config.lb,
+            name: config.name.clone(),
+            lb: config.lb.clone(),
             max_retries: config.max_retries,
-            retry_backoff_ms: config.retry_backoff_ms,
+            retry: RetryPolicy::from_config(&config),
             circuit_breaker,
+            outlier_detection: config
+                .outlier_detection
+                .as_ref()
+                .map(OutlierDetection::from_config),
+            slow_start_ms: config.slow_start_s.map(|secs| secs.saturating_mul(1_000)),
+            discovery: config.discover.clone().map(|config| {
+                Arc::new(ServiceDiscovery {
+                    config,
+                    next_lookup_ms: AtomicU64::new(0),
+                    looking_up: AtomicBool::new(false),
+                })
+            }),
             upstreams,
             ring,
+            tiers,
             rr_cursor: Arc::new(AtomicUsize::new(0)),
+            source: config,
+        }
+    }
+
+    /// Reads the client certificates and pins of the listed upstreams.
+    #[cfg(feature = "openssl")]
+    fn load_tls(&mut self) -> anyhow::Result<()> {
+        for (upstream, config) in self.upstreams.iter_mut().zip(&self.source.upstreams) {
+            upstream.tls_auth = crate::tls::UpstreamTls::load(config)
+                .with_context(|| format!("service {} upstream {}", self.name, config.addr))?;
         }
+        Ok(())
     }
 
     pub fn next_upstream(
@@ -195,11 +1422,62 @@ impl ServiceRuntime {
             LbStrategy::RoundRobin => self.select_round_robin(attempted),
             LbStrategy::Random => self.select_random(attempted),
             LbStrategy::Hash => self.select_hash(hash_seed, attempted),
+            LbStrategy::Ewma => self.select_ewma(attempted),
         }?;
 
         self.upstreams
-            .get(chosen_idx)
-            .map(|upstream| (chosen_idx, upstream))
+            .get(chosen_idx)
+            .map(|upstream| (chosen_idx, upstream))
+    }
+
+    /// Takes over the round-robin cursor of the service this one replaces,
+    /// and the breaker, health and balancing state of the upstreams it kept.
+    fn carry_over(&mut self, prev: &ServiceRuntime) {
+        self.rr_cursor = prev.rr_cursor.clone();
+        for upstream in &mut self.upstreams {
+            let Some(old) = prev.upstreams.iter().find(|old| old.addr == upstream.addr) else {
+                continue;
+            };
+            upstream.state = old.state.clone();
+            // Nothing would bring back an upstream whose check was removed,
+            // or update records no longer resolved.
+            if upstream.health_check.is_none() {
+                upstream.state.health.reset();
+            }
+            if upstream.resolve_interval_ms.is_none() {
+                upstream.state.resolved.store(Arc::new(Vec::new()));
+            }
+        }
+    }
+
+    /// Like [`ServiceRuntime::next_upstream`], but with `lb = "hash"` a key
+    /// whose upstream has no idle pooled connection goes to a second one
+    /// that has.
+    pub fn next_upstream_reusing(
+        &self,
+        hash_seed: u64,
+        attempted: &[usize],
+    ) -> Option<(usize, &UpstreamRuntime)> {
+        if self.lb != LbStrategy::Hash || self.ring.is_empty() {
+            return self.next_upstream(hash_seed, attempted);
+        }
+        let primary = self.select_hash(hash_seed, attempted)?;
+        let mut now = LazyNow::default();
+        let mut warm = |idx: usize| self.upstreams[idx].has_warm_connection(&mut now);
+        let chosen = if warm(primary) {
+            primary
+        } else {
+            // A second spot on the ring, fixed per key like the first.
+            let alternate = hash_seed
+                .wrapping_mul(0x9e37_79b9_7f4a_7c15)
+                .rotate_left(31);
+            self.select_hash(alternate, attempted)
+                .filter(|idx| *idx != primary && warm(*idx))
+                .unwrap_or(primary)
+        };
+        self.upstreams
+            .get(chosen)
+            .map(|upstream| (chosen, upstream))
     }
 
     fn select_round_robin(&self, attempted: &[usize]) -> Option<usize> {
@@ -218,27 +1496,85 @@ impl ServiceRuntime {
         self.select_from_ring(base, attempted)
     }
 
+    /// Power of two choices: the faster of two weighted random picks, so a
+    /// slow upstream still gets the odd request to show it recovered.
+    fn select_ewma(&self, attempted: &[usize]) -> Option<usize> {
+        let first = self.select_random(attempted)?;
+        let Some(second) = self
+            .select_random(attempted)
+            .filter(|second| *second != first)
+        else {
+            return Some(first);
+        };
+        let latency = |idx: usize| self.upstreams[idx].ewma_latency_us();
+        Some(if latency(second) < latency(first) {
+            second
+        } else {
+            first
+        })
+    }
+
+    /// Picks from the lowest tier that has an upstream to take the request.
     fn select_from_ring(&self, start: usize, attempted: &[usize]) -> Option<usize> {
-        let now_ms = now_epoch_ms();
+        let mut now = LazyNow::default();
+        // An ejected or overloaded upstream passed over for its reduced share
+        // is still better than none, though not better than a later tier.
+        let mut passed_over = None;
+        for &tier in &self.tiers {
+            if let Some(candidate) =
+                self.select_from_tier(tier, start, attempted, &mut now, &mut passed_over)
+            {
+                return Some(candidate);
+            }
+        }
+        passed_over
+    }
+
+    fn select_from_tier(
+        &self,
+        tier: u16,
+        start: usize,
+        attempted: &[usize],
+        now: &mut LazyNow,
+        passed_over: &mut Option<usize>,
+    ) -> Option<usize> {
         for offset in 0..self.ring.len() {
             let candidate = self.ring[(start + offset) % self.ring.len()];
-            if !attempted.contains(&candidate)
-                && self
-                    .upstreams
-                    .get(candidate)
-                    .is_some_and(|upstream| upstream.is_available_at(now_ms))
+            if attempted.contains(&candidate) {
+                continue;
+            }
+            let Some(upstream) = self.upstreams.get(candidate) else {
+                continue;
+            };
+            if upstream.tier != tier {
+                continue;
+            }
+            if !upstream.is_available(now) || !upstream.takes_probe_turn(&self.circuit_breaker, now)
             {
-                return Some(candidate);
+                continue;
             }
+            if !upstream.takes_outlier_turn(self.outlier_detection.as_ref(), now)
+                || !upstream.takes_slow_start_turn(self.slow_start_ms, now)
+            {
+                passed_over.get_or_insert(candidate);
+                continue;
+            }
+            if upstream.is_overloaded(now)
+                && !upstream.takes_overloaded_turn(self.circuit_breaker.overload_weight_percent)
+            {
+                passed_over.get_or_insert(candidate);
+                continue;
+            }
+            return Some(candidate);
         }
         None
     }
 
     pub fn has_available_upstream(&self) -> bool {
-        let now_ms = now_epoch_ms();
+        let mut now = LazyNow::default();
         self.upstreams
             .iter()
-            .any(|upstream| upstream.is_available_at(now_ms))
+            .any(|upstream| upstream.is_available(&mut now))
     }
 
     pub fn mark_upstream_failure(&self, upstream_idx: usize) -> bool {
@@ -248,18 +1584,91 @@ impl ServiceRuntime {
         upstream.mark_failure(&self.circuit_breaker)
     }
 
+    /// Records how an upstream answered for outlier detection; returns true
+    /// when this got it ejected.
+    pub fn record_outlier_sample(
+        &self,
+        upstream_idx: usize,
+        latency: Duration,
+        failed: bool,
+    ) -> bool {
+        let (Some(outlier), Some(upstream)) =
+            (&self.outlier_detection, self.upstreams.get(upstream_idx))
+        else {
+            return false;
+        };
+        let latency_ms = latency.as_millis() as u64;
+        upstream.record_outlier_sample(outlier, coarse_now_ms(), latency_ms, failed)
+    }
+
+    pub fn round_robin_cursor(&self) -> usize {
+        self.rr_cursor.load(Ordering::Relaxed)
+    }
+
+    /// Feeds an attempt's latency into the upstream's moving average; a failed
+    /// attempt counts as at least [`EWMA_FAILURE_PENALTY`].
+    pub fn record_upstream_latency(&self, upstream_idx: usize, latency: Duration, failed: bool) {
+        if self.lb != LbStrategy::Ewma {
+            return;
+        }
+        if let Some(upstream) = self.upstreams.get(upstream_idx) {
+            let latency = if failed {
+                latency.max(EWMA_FAILURE_PENALTY)
+            } else {
+                latency
+            };
+            upstream.record_latency(latency);
+        }
+    }
+
     pub fn mark_upstream_success(&self, upstream_idx: usize) {
         if let Some(upstream) = self.upstreams.get(upstream_idx) {
-            upstream.mark_success();
+            upstream.mark_success(&self.circuit_breaker);
+        }
+    }
+
+    /// Claims the service's `discover` lookup when it is due, so only one
+    /// runs at a time.
+    pub fn claim_discovery(&self, now_ms: u64) -> Option<Arc<ServiceDiscovery>> {
+        let discovery = self.discovery.as_ref()?;
+        if discovery.next_lookup_ms.load(Ordering::Relaxed) > now_ms
+            || discovery.looking_up.swap(true, Ordering::AcqRel)
+        {
+            return None;
         }
+        let interval_ms = discovery.config.interval_s.saturating_mul(1_000);
+        discovery
+            .next_lookup_ms
+            .store(now_ms.saturating_add(interval_ms), Ordering::Relaxed);
+        Some(discovery.clone())
     }
 }
 
+/// Lookup schedule of a service's `discover`, shared by the snapshots that
+/// reuse the service.
 #[derive(Debug)]
+pub struct ServiceDiscovery {
+    pub config: DiscoverConfig,
+    next_lookup_ms: AtomicU64,
+    looking_up: AtomicBool,
+}
+
+impl ServiceDiscovery {
+    /// Ends a lookup claimed by [`ServiceRuntime::claim_discovery`].
+    pub fn finish(&self) {
+        self.looking_up.store(false, Ordering::Release);
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct UpstreamRuntime {
-    pub addr: String,
+    pub addr: Arc<str>,
+    /// Value used for the `upstream` metric label (the addr, or the overflow label).
+    pub metric_label: Arc<str>,
     pub tls: bool,
     pub sni: String,
+    pub verify_hostname_as: Option<String>,
+    pub host_header: HostHeader,
     pub weight: u16,
     pub verify_cert: bool,
     pub verify_hostname: bool,
@@ -268,13 +1677,131 @@ pub struct UpstreamRuntime {
     pub read_timeout_ms: Option<u64>,
     pub write_timeout_ms: Option<u64>,
     pub idle_timeout_ms: Option<u64>,
+    pub max_requests_per_connection: Option<u64>,
+    pub max_connection_lifetime_ms: Option<u64>,
+    pub health_check: Option<HealthCheckConfig>,
+    pub egress: Egress,
+    pub proxy_protocol: Option<ProxyProtocolVersion>,
+    pub drain_hook: Option<String>,
+    pub resolve_interval_ms: Option<u64>,
+    pub tier: u16,
+    /// Read by [`ServiceRuntime::load_tls`]; discovered upstreams have none.
+    #[cfg(feature = "openssl")]
+    pub tls_auth: crate::tls::UpstreamTls,
+    http_version: UpstreamHttpVersion,
     state: Arc<UpstreamState>,
 }
 
+/// Breaker, health and overload state of an upstream. Deadlines are kept as
+/// time left, so the state can move to another process or host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UpstreamOperationalState {
+    pub healthy: bool,
+    pub consecutive_failures: usize,
+    pub circuit_open_ms: u64,
+    pub overloaded_ms: u64,
+    pub http1_fallback: bool,
+    pub disabled: bool,
+}
+
+/// Parsed `EgressConfig`: how connections to an upstream leave the host.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Egress {
+    pub local_addr: Option<IpAddr>,
+    pub interface: Option<Arc<str>>,
+    pub dscp: Option<u8>,
+}
+
+impl Egress {
+    /// The address is checked by `PrxConfig::validate`.
+    fn from_config(config: &EgressConfig) -> Self {
+        Self {
+            local_addr: config
+                .local_addr
+                .as_deref()
+                .and_then(|addr| addr.parse().ok()),
+            interface: config.interface.as_deref().map(Arc::from),
+            dscp: config.dscp,
+        }
+    }
+
+    /// These settings, with unset fields taken from `fallback`.
+    pub fn or(&self, fallback: &Self) -> Self {
+        Self {
+            local_addr: self.local_addr.or(fallback.local_addr),
+            interface: self
+                .interface
+                .clone()
+                .or_else(|| fallback.interface.clone()),
+            dscp: self.dscp.or(fallback.dscp),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Pool key for connections made with these settings.
+    pub fn pool_key(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+/// Host header prx sends to an upstream.
+#[derive(Debug, Clone, PartialEq)]
+pub enum HostHeader {
+    Sni,
+    Preserve,
+    Fixed(String),
+}
+
 #[derive(Debug, Default)]
 struct UpstreamState {
     consecutive_failures: AtomicUsize,
     open_until_epoch_ms: AtomicU64,
+    // Requests served per pooled connection, keyed by the connection's local address.
+    connection_uses: Mutex<HashMap<SocketAddr, u64>>,
+    health: HealthState,
+    // Set once an `http2` upstream failed to speak HTTP/2.
+    http1_fallback: AtomicBool,
+    overloaded_until_epoch_ms: AtomicU64,
+    // Selections that reached the upstream while it was overloaded.
+    overloaded_visits: AtomicU64,
+    outliers: Mutex<OutlierWindow>,
+    // Set by outlier detection; cleared once the upstream is fully back.
+    ejected_until_epoch_ms: AtomicU64,
+    // Probes let through, and how many of them succeeded, since the breaker
+    // went half-open.
+    probes: AtomicUsize,
+    probe_successes: AtomicUsize,
+    // Failed probes in a row, doubling the open time.
+    reopens: AtomicU32,
+    // When the upstream came back; 0 once it takes its full share.
+    warming_since_epoch_ms: AtomicU64,
+    // Moving average of attempt latencies for `lb = "ewma"`; 0 before the
+    // first one.
+    ewma_latency_us: AtomicU64,
+    // Records `addr` resolved to with `resolve_interval_s`, sorted; empty
+    // until the first lookup succeeded.
+    resolved: ArcSwap<Vec<SocketAddr>>,
+    resolved_cursor: AtomicUsize,
+    next_resolve_ms: AtomicU64,
+    resolving: AtomicBool,
+    // Estimated idle connections in pingora's pool, and when one was last
+    // returned to it.
+    idle_connections: AtomicUsize,
+    last_release_epoch_ms: AtomicU64,
+    // Requests sent to the upstream and not finished yet, and when the last
+    // one was; 0 before the first.
+    in_flight: AtomicUsize,
+    last_selected_epoch_ms: AtomicU64,
+    // Set once a reload removed the upstream: when requests still in flight
+    // get cut.
+    drain_deadline_epoch_ms: AtomicU64,
+    // Taken out of rotation through the admin API, e.g. for maintenance.
+    disabled: AtomicBool,
 }
 
 impl UpstreamRuntime {
@@ -283,10 +1810,18 @@ impl UpstreamRuntime {
             .sni
             .or_else(|| sni_from_addr(&config.addr))
             .unwrap_or_else(|| "localhost".to_string());
+        let addr: Arc<str> = Arc::from(config.addr);
         Self {
-            addr: config.addr,
+            metric_label: addr.clone(),
+            addr,
             tls: config.tls,
+            host_header: match (config.preserve_host, config.host) {
+                (true, _) => HostHeader::Preserve,
+                (false, Some(host)) => HostHeader::Fixed(host),
+                (false, None) => HostHeader::Sni,
+            },
             sni,
+            verify_hostname_as: config.verify_hostname_as,
             weight: config.weight.max(1),
             verify_cert: config.verify_cert.unwrap_or(true),
             verify_hostname: config.verify_hostname.unwrap_or(true),
@@ -295,16 +1830,514 @@ impl UpstreamRuntime {
             read_timeout_ms: config.read_timeout_ms,
             write_timeout_ms: config.write_timeout_ms,
             idle_timeout_ms: config.idle_timeout_ms,
+            max_requests_per_connection: config.max_requests_per_connection,
+            max_connection_lifetime_ms: config.max_connection_lifetime_ms,
+            health_check: config.health_check,
+            egress: Egress::from_config(&config.egress),
+            proxy_protocol: config.proxy_protocol,
+            drain_hook: config.drain_hook,
+            resolve_interval_ms: config
+                .resolve_interval_s
+                .map(|secs| secs.saturating_mul(1_000)),
+            tier: config.tier,
+            #[cfg(feature = "openssl")]
+            tls_auth: crate::tls::UpstreamTls::default(),
+            http_version: config.http_version.unwrap_or(if config.http2 {
+                UpstreamHttpVersion::Auto
+            } else {
+                UpstreamHttpVersion::H1
+            }),
             state: Arc::new(UpstreamState::default()),
         }
     }
 
+    /// Records a request on the connection identified by `local_addr` and returns
+    /// true when the connection has reached its request or lifetime budget and
+    /// must not be returned to the keepalive pool.
+    pub fn should_retire_connection(
+        &self,
+        reused: bool,
+        local_addr: Option<SocketAddr>,
+        established_at: Option<SystemTime>,
+    ) -> bool {
+        if self.is_draining() {
+            if let Some(addr) = local_addr {
+                self.connection_uses().remove(&addr);
+            }
+            return true;
+        }
+        if let (Some(limit_ms), Some(established_at)) =
+            (self.max_connection_lifetime_ms, established_at)
+        {
+            let age = SystemTime::now()
+                .duration_since(established_at)
+                .unwrap_or_default();
+            if age >= Duration::from_millis(limit_ms) {
+                if let Some(addr) = local_addr {
+                    self.connection_uses().remove(&addr);
+                }
+                return true;
+            }
+        }
+
+        let (Some(limit), Some(addr)) = (self.max_requests_per_connection, local_addr) else {
+            return false;
+        };
+        let mut uses = self.connection_uses();
+        let count = if reused {
+            uses.get(&addr).copied().unwrap_or(0) + 1
+        } else {
+            1
+        };
+        if count >= limit {
+            uses.remove(&addr);
+            true
+        } else {
+            uses.insert(addr, count);
+            false
+        }
+    }
+
+    fn record_outlier_sample(
+        &self,
+        outlier: &OutlierDetection,
+        now: u64,
+        latency_ms: u64,
+        failed: bool,
+    ) -> bool {
+        let mut window = self
+            .state
+            .outliers
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        window.samples.push_back(OutlierSample {
+            at_ms: now,
+            latency_ms,
+            failed,
+        });
+        let oldest = now.saturating_sub(outlier.window_ms);
+        while window.samples.len() > MAX_OUTLIER_SAMPLES
+            || window
+                .samples
+                .front()
+                .is_some_and(|sample| sample.at_ms < oldest)
+        {
+            window.samples.pop_front();
+        }
+        if window.samples.len() < outlier.min_requests
+            || now.saturating_sub(window.evaluated_at_ms) < outlier.window_ms / 10
+        {
+            return false;
+        }
+        window.evaluated_at_ms = now;
+        if !outlier.is_outlier(&window.samples) {
+            return false;
+        }
+        // Reintroduction judges the upstream on its new answers only.
+        window.samples.clear();
+        self.state.ejected_until_epoch_ms.store(
+            now.saturating_add(outlier.eject_ms).max(1),
+            Ordering::Relaxed,
+        );
+        true
+    }
+
+    /// Whether this selection may go to the upstream: never while it is
+    /// ejected, then a share growing from a tenth to all of it over
+    /// `reintroduce_ms`.
+    fn takes_outlier_turn(&self, outlier: Option<&OutlierDetection>, now: &mut LazyNow) -> bool {
+        // Never ejected stores 0, so the common path never reads the clock.
+        let until = self.state.ejected_until_epoch_ms.load(Ordering::Relaxed);
+        let Some(outlier) = outlier.filter(|_| until != 0) else {
+            return true;
+        };
+        let now = now.get();
+        if now < until {
+            return false;
+        }
+        let back_ms = now - until;
+        if back_ms >= outlier.reintroduce_ms {
+            // Keeps an ejection that happened meanwhile.
+            let _ = self.state.ejected_until_epoch_ms.compare_exchange(
+                until,
+                0,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            );
+            return true;
+        }
+        let percent = 10 + 90 * back_ms / outlier.reintroduce_ms;
+        rand::rng().random_range(0..100) < percent
+    }
+
+    /// One of the records `addr` resolved to, sticky by `hash_seed` when
+    /// given; `None` leaves resolving `addr` to the connector.
+    pub fn pick_addr(&self, hash_seed: Option<u64>) -> Option<SocketAddr> {
+        let resolved = self.state.resolved.load();
+        if resolved.is_empty() {
+            return None;
+        }
+        let turn = match hash_seed {
+            Some(seed) => seed as usize,
+            None => self.state.resolved_cursor.fetch_add(1, Ordering::Relaxed),
+        };
+        Some(resolved[turn % resolved.len()])
+    }
+
+    pub fn resolved_addrs(&self) -> Arc<Vec<SocketAddr>> {
+        self.state.resolved.load_full()
+    }
+
+    /// Claims the next lookup if one is due at `now_ms` and none is running.
+    pub fn claim_resolve(&self, now_ms: u64) -> bool {
+        let Some(interval_ms) = self.resolve_interval_ms else {
+            return false;
+        };
+        if self.state.next_resolve_ms.load(Ordering::Relaxed) > now_ms
+            || self.state.resolving.swap(true, Ordering::AcqRel)
+        {
+            return false;
+        }
+        self.state
+            .next_resolve_ms
+            .store(now_ms.saturating_add(interval_ms), Ordering::Relaxed);
+        true
+    }
+
+    /// Ends a claimed lookup; a failed or empty one keeps the records found
+    /// before. Returns true when the records changed.
+    pub fn finish_resolve(&self, addrs: Option<Vec<SocketAddr>>) -> bool {
+        self.state.resolving.store(false, Ordering::Release);
+        let Some(mut addrs) = addrs.filter(|addrs| !addrs.is_empty()) else {
+            return false;
+        };
+        addrs.sort();
+        addrs.dedup();
+        if **self.state.resolved.load() == addrs {
+            return false;
+        }
+        self.state.resolved.store(Arc::new(addrs));
+        true
+    }
+
+    fn record_latency(&self, latency: Duration) {
+        let sample = (latency.as_micros() as u64).max(1);
+        let _ = self.state.ewma_latency_us.fetch_update(
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+            |average| {
+                Some(match average {
+                    0 => sample,
+                    // Each sample moves the average a fifth of the way.
+                    average => (average - average / 5 + sample / 5).max(1),
+                })
+            },
+        );
+    }
+
+    pub fn ewma_latency_us(&self) -> u64 {
+        self.state.ewma_latency_us.load(Ordering::Relaxed)
+    }
+
+    /// Counts a request as in flight at this upstream until the guard drops.
+    pub fn start_request(&self) -> UpstreamRequest {
+        self.state.in_flight.fetch_add(1, Ordering::AcqRel);
+        self.state
+            .last_selected_epoch_ms
+            .store(coarse_now_ms(), Ordering::Relaxed);
+        UpstreamRequest(self.state.clone())
+    }
+
+    pub fn requests_in_flight(&self) -> usize {
+        self.state.in_flight.load(Ordering::Acquire)
+    }
+
+    /// When a request was last sent to the upstream; 0 if none was yet.
+    pub fn last_selected_epoch_ms(&self) -> u64 {
+        self.state.last_selected_epoch_ms.load(Ordering::Relaxed)
+    }
+
+    /// Gives requests in flight until `timeout` from now to finish; returns
+    /// false if the upstream was draining already.
+    pub(crate) fn begin_drain(&self, timeout: Duration) -> bool {
+        let deadline = now_epoch_ms()
+            .saturating_add(timeout.as_millis() as u64)
+            .max(1);
+        self.state
+            .drain_deadline_epoch_ms
+            .compare_exchange(0, deadline, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+    }
+
+    /// Whether a reload removed the upstream; its connections are no longer
+    /// kept alive.
+    pub fn is_draining(&self) -> bool {
+        self.state.drain_deadline_epoch_ms.load(Ordering::Acquire) != 0
+    }
+
+    /// Takes the upstream out of rotation, or puts it back, apart from its
+    /// health and breaker; returns false if it already was. Requests in flight
+    /// finish as usual.
+    pub fn set_disabled(&self, disabled: bool) -> bool {
+        self.state.disabled.swap(disabled, Ordering::Relaxed) != disabled
+    }
+
+    pub fn is_disabled(&self) -> bool {
+        self.state.disabled.load(Ordering::Relaxed)
+    }
+
+    /// Counts a connection going back to the pool after a request.
+    pub fn release_connection(&self) {
+        self.state.idle_connections.fetch_add(1, Ordering::Relaxed);
+        self.state
+            .last_release_epoch_ms
+            .store(coarse_now_ms(), Ordering::Relaxed);
+    }
+
+    /// Counts a request taking a connection out of the pool.
+    pub fn take_pooled_connection(&self) {
+        let _ = self.state.idle_connections.fetch_update(
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+            |idle| idle.checked_sub(1),
+        );
+    }
+
+    /// Estimate of connections waiting in the pool; ones the upstream closed
+    /// are not noticed until a request finds them gone.
+    pub fn idle_connections(&self) -> usize {
+        self.state.idle_connections.load(Ordering::Relaxed)
+    }
+
+    /// Whether a pooled connection is likely ready. Upstreams commonly close
+    /// idle connections after a few seconds, so an old release does not count.
+    fn has_warm_connection(&self, now: &mut LazyNow) -> bool {
+        self.idle_connections() > 0
+            && self.state.last_release_epoch_ms.load(Ordering::Relaxed) + POOL_WARM_MS > now.get()
+    }
+
+    /// Starts ramping the upstream in over the service's `slow_start_s`.
+    pub fn begin_slow_start(&self) {
+        self.state
+            .warming_since_epoch_ms
+            .store(coarse_now_ms().max(1), Ordering::Relaxed);
+    }
+
+    fn takes_slow_start_turn(&self, window_ms: Option<u64>, now: &mut LazyNow) -> bool {
+        let Some(window_ms) = window_ms else {
+            return true;
+        };
+        let since = self.state.warming_since_epoch_ms.load(Ordering::Relaxed);
+        if since == 0 {
+            return true;
+        }
+        let warm_ms = now.get().saturating_sub(since);
+        if warm_ms >= window_ms {
+            let _ = self.state.warming_since_epoch_ms.compare_exchange(
+                since,
+                0,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            );
+            return true;
+        }
+        let percent = 10 + 90 * warm_ms / window_ms;
+        rand::rng().random_range(0..100) < percent
+    }
+
+    fn connection_uses(&self) -> MutexGuard<'_, HashMap<SocketAddr, u64>> {
+        self.state
+            .connection_uses
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
     pub fn is_circuit_open(&self) -> bool {
-        !self.is_available_at(now_epoch_ms())
+        !self.is_circuit_closed(&mut LazyNow::default())
+    }
+
+    pub fn health(&self) -> &HealthState {
+        &self.state.health
+    }
+
+    pub fn consecutive_failures(&self) -> usize {
+        self.state.consecutive_failures.load(Ordering::Relaxed)
+    }
+
+    /// Pooled connections whose request budget is being tracked.
+    pub fn tracked_connections(&self) -> usize {
+        self.connection_uses().len()
+    }
+
+    /// Whether new connections should offer HTTP/2.
+    pub fn uses_http2(&self) -> bool {
+        match self.http_version {
+            UpstreamHttpVersion::H1 => false,
+            UpstreamHttpVersion::H2 => true,
+            UpstreamHttpVersion::Auto => !self.state.http1_fallback.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Whether HTTP/2 failures may move the upstream to HTTP/1.1.
+    pub fn falls_back_to_http1(&self) -> bool {
+        self.http_version == UpstreamHttpVersion::Auto
+    }
+
+    /// Switches the upstream to HTTP/1.1 until its service is rebuilt or the
+    /// stats are reset; returns true for the call that made the switch.
+    pub fn fall_back_to_http1(&self) -> bool {
+        self.falls_back_to_http1() && !self.state.http1_fallback.swap(true, Ordering::Relaxed)
+    }
+
+    /// Cuts the upstream's share of traffic for `duration_ms` without opening
+    /// its breaker; returns true when it was not overloaded yet.
+    pub fn mark_overloaded(&self, duration_ms: u64) -> bool {
+        let now = coarse_now_ms();
+        let previous = self
+            .state
+            .overloaded_until_epoch_ms
+            .swap(now.saturating_add(duration_ms), Ordering::Relaxed);
+        previous <= now
     }
 
-    fn is_available_at(&self, now_ms: u64) -> bool {
-        self.state.open_until_epoch_ms.load(Ordering::Relaxed) <= now_ms
+    fn is_overloaded(&self, now: &mut LazyNow) -> bool {
+        let until = self.state.overloaded_until_epoch_ms.load(Ordering::Relaxed);
+        until != 0 && until > now.get()
+    }
+
+    /// Spreads `percent` of the selections that reach an overloaded upstream
+    /// evenly over time.
+    fn takes_overloaded_turn(&self, percent: u64) -> bool {
+        let visit = self.state.overloaded_visits.fetch_add(1, Ordering::Relaxed);
+        (visit + 1) * percent / 100 > visit * percent / 100
+    }
+
+    pub fn operational_state(&self) -> UpstreamOperationalState {
+        let now = coarse_now_ms();
+        let left = |until: &AtomicU64| until.load(Ordering::Relaxed).saturating_sub(now);
+        UpstreamOperationalState {
+            healthy: self.state.health.is_healthy(),
+            consecutive_failures: self.consecutive_failures(),
+            circuit_open_ms: left(&self.state.open_until_epoch_ms),
+            overloaded_ms: left(&self.state.overloaded_until_epoch_ms),
+            http1_fallback: self.state.http1_fallback.load(Ordering::Relaxed),
+            disabled: self.is_disabled(),
+        }
+    }
+
+    /// Epoch ms the breaker stays open until; 0 or past when it is closed.
+    pub fn circuit_open_until_ms(&self) -> u64 {
+        self.state.open_until_epoch_ms.load(Ordering::Relaxed)
+    }
+
+    /// `closed`, `open`, or `half_open` once its open time is up and probes
+    /// decide whether it closes.
+    pub fn circuit_state(&self) -> &'static str {
+        match self.circuit_open_until_ms() {
+            0 => "closed",
+            until if until > coarse_now_ms() => "open",
+            _ => "half_open",
+        }
+    }
+
+    /// Keeps the breaker open until at least `until_epoch_ms`, as opened by
+    /// another process.
+    #[cfg(unix)]
+    pub fn hold_circuit_open(&self, until_epoch_ms: u64) {
+        let previous = self
+            .state
+            .open_until_epoch_ms
+            .fetch_max(until_epoch_ms, Ordering::Relaxed);
+        if previous < until_epoch_ms {
+            self.state.probes.store(0, Ordering::Relaxed);
+            self.state.probe_successes.store(0, Ordering::Relaxed);
+        }
+    }
+
+    pub fn restore_operational_state(&self, restored: UpstreamOperationalState) {
+        let now = coarse_now_ms();
+        let until = |left: u64| {
+            if left == 0 {
+                0
+            } else {
+                now.saturating_add(left)
+            }
+        };
+        self.state.health.restore(restored.healthy);
+        self.state
+            .consecutive_failures
+            .store(restored.consecutive_failures, Ordering::Relaxed);
+        self.state
+            .open_until_epoch_ms
+            .store(until(restored.circuit_open_ms), Ordering::Relaxed);
+        self.state
+            .overloaded_until_epoch_ms
+            .store(until(restored.overloaded_ms), Ordering::Relaxed);
+        self.state.http1_fallback.store(
+            restored.http1_fallback && self.falls_back_to_http1(),
+            Ordering::Relaxed,
+        );
+        self.set_disabled(restored.disabled);
+    }
+
+    fn reset_stats(&self) {
+        self.close_circuit();
+        self.connection_uses().clear();
+        self.state.health.reset();
+        self.state.http1_fallback.store(false, Ordering::Relaxed);
+        self.state
+            .overloaded_until_epoch_ms
+            .store(0, Ordering::Relaxed);
+        self.state
+            .ejected_until_epoch_ms
+            .store(0, Ordering::Relaxed);
+        self.state
+            .warming_since_epoch_ms
+            .store(0, Ordering::Relaxed);
+        self.state.ewma_latency_us.store(0, Ordering::Relaxed);
+        *self
+            .state
+            .outliers
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = OutlierWindow::default();
+    }
+
+    fn is_available(&self, now: &mut LazyNow) -> bool {
+        !self.is_disabled() && self.state.health.is_healthy() && self.is_circuit_closed(now)
+    }
+
+    /// Also true while half-open, when only probes get through.
+    fn is_circuit_closed(&self, now: &mut LazyNow) -> bool {
+        // A closed breaker stores 0, so the common path never reads the clock.
+        let open_until = self.state.open_until_epoch_ms.load(Ordering::Relaxed);
+        open_until == 0 || open_until <= now.get()
+    }
+
+    /// Past its `open_until` the breaker is half-open and lets only
+    /// `half_open_probes` requests through until they decide its state.
+    fn takes_probe_turn(&self, circuit_breaker: &CircuitBreakerRuntime, now: &mut LazyNow) -> bool {
+        let open_until = self.state.open_until_epoch_ms.load(Ordering::Relaxed);
+        if open_until == 0 {
+            return true;
+        }
+        if self.state.probes.fetch_add(1, Ordering::Relaxed) < circuit_breaker.half_open_probes {
+            return true;
+        }
+        // Probes that never report back, e.g. with failures the breaker does
+        // not count, are written off after another `open_ms`.
+        let now = now.get();
+        if now.saturating_sub(open_until) >= circuit_breaker.open_ms
+            && self
+                .state
+                .open_until_epoch_ms
+                .compare_exchange(open_until, now, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        {
+            self.state.probes.store(1, Ordering::Relaxed);
+            self.state.probe_successes.store(0, Ordering::Relaxed);
+            return true;
+        }
+        false
     }
 
     fn mark_failure(&self, circuit_breaker: &CircuitBreakerRuntime) -> bool {
@@ -312,6 +2345,19 @@ impl UpstreamRuntime {
             return false;
         }
 
+        let now = coarse_now_ms();
+        let open_until = self.state.open_until_epoch_ms.load(Ordering::Relaxed);
+        if open_until != 0 && open_until <= now {
+            // A failed probe reopens the breaker for twice as long as before.
+            let reopens = self.state.reopens.fetch_add(1, Ordering::Relaxed) + 1;
+            let open_ms = circuit_breaker
+                .open_ms
+                .saturating_mul(1 << reopens.min(32))
+                .min(circuit_breaker.max_open_ms);
+            self.open_circuit(now.saturating_add(open_ms));
+            return true;
+        }
+
         let failures = self
             .state
             .consecutive_failures
@@ -321,22 +2367,55 @@ impl UpstreamRuntime {
             return false;
         }
 
-        let now = now_epoch_ms();
-        let was_open = self.state.open_until_epoch_ms.load(Ordering::Relaxed) > now;
-        self.state.open_until_epoch_ms.store(
-            now.saturating_add(circuit_breaker.open_ms),
-            Ordering::Relaxed,
-        );
-        self.state.consecutive_failures.store(0, Ordering::Relaxed);
+        let was_open = open_until > now;
+        if !was_open {
+            self.state.reopens.store(0, Ordering::Relaxed);
+        }
+        self.open_circuit(now.saturating_add(circuit_breaker.open_ms));
         !was_open
     }
 
-    fn mark_success(&self) {
+    fn open_circuit(&self, until_epoch_ms: u64) {
+        // Never shortens an open time grown by failed probes.
+        self.state
+            .open_until_epoch_ms
+            .fetch_max(until_epoch_ms, Ordering::Relaxed);
+        self.state.consecutive_failures.store(0, Ordering::Relaxed);
+        self.state.probes.store(0, Ordering::Relaxed);
+        self.state.probe_successes.store(0, Ordering::Relaxed);
+    }
+
+    /// Closes a half-open breaker once all its probes succeeded; successes
+    /// of requests sent before it opened leave an open one alone.
+    fn mark_success(&self, circuit_breaker: &CircuitBreakerRuntime) {
+        self.state.consecutive_failures.store(0, Ordering::Relaxed);
+        let open_until = self.state.open_until_epoch_ms.load(Ordering::Relaxed);
+        if open_until == 0 || open_until > coarse_now_ms() {
+            return;
+        }
+        let successes = self.state.probe_successes.fetch_add(1, Ordering::Relaxed) + 1;
+        if successes >= circuit_breaker.half_open_probes {
+            self.close_circuit();
+            self.begin_slow_start();
+        }
+    }
+
+    fn close_circuit(&self) {
         self.state.consecutive_failures.store(0, Ordering::Relaxed);
         self.state.open_until_epoch_ms.store(0, Ordering::Relaxed);
+        self.state.reopens.store(0, Ordering::Relaxed);
+        self.state.probes.store(0, Ordering::Relaxed);
+        self.state.probe_successes.store(0, Ordering::Relaxed);
     }
 }
 
+fn resolve_service_idx(service_index: &HashMap<String, usize>, service: &str) -> usize {
+    service_index
+        .get(service)
+        .copied()
+        .expect("route references a service that was not found in service_index")
+}
+
 fn sni_from_addr(addr: &str) -> Option<String> {
     if addr.parse::<SocketAddr>().is_ok() {
         return None;
@@ -359,6 +2438,13 @@ fn build_selection_ring(upstreams: &[UpstreamRuntime]) -> Vec<usize> {
     ring
 }
 
+fn upstream_tiers(upstreams: &[UpstreamRuntime]) -> Vec<u16> {
+    let mut tiers: Vec<u16> = upstreams.iter().map(|upstream| upstream.tier).collect();
+    tiers.sort_unstable();
+    tiers.dedup();
+    tiers
+}
+
 fn upstream_weight(upstream: &UpstreamRuntime, _idx: usize) -> usize {
     upstream.weight.clamp(1, 256) as usize
 }
@@ -383,19 +2469,56 @@ pub fn hash_key(parts: &[&str]) -> u64 {
     hasher.finish()
 }
 
-fn now_epoch_ms() -> u64 {
+pub(crate) fn now_epoch_ms() -> u64 {
     SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .map(|d| d.as_millis() as u64)
         .unwrap_or(0)
 }
 
+const COARSE_CLOCK_TICK: Duration = Duration::from_millis(5);
+
+// Epoch milliseconds refreshed by `spawn_coarse_clock`; 0 until the ticker runs.
+static COARSE_NOW_MS: AtomicU64 = AtomicU64::new(0);
+
+/// Starts the ticker behind the cached clock used for circuit breaker checks,
+/// so upstream selection does not read the system clock per candidate.
+pub fn spawn_coarse_clock() -> std::io::Result<()> {
+    COARSE_NOW_MS.store(now_epoch_ms(), Ordering::Relaxed);
+    std::thread::Builder::new()
+        .name("prx-clock".to_string())
+        .spawn(|| {
+            loop {
+                std::thread::sleep(COARSE_CLOCK_TICK);
+                COARSE_NOW_MS.store(now_epoch_ms(), Ordering::Relaxed);
+            }
+        })
+        .map(|_| ())
+}
+
+fn coarse_now_ms() -> u64 {
+    match COARSE_NOW_MS.load(Ordering::Relaxed) {
+        0 => now_epoch_ms(),
+        now => now,
+    }
+}
+
+/// Reads the clock at most once, and only if a candidate actually needs it.
+#[derive(Default)]
+struct LazyNow(Option<u64>);
+
+impl LazyNow {
+    fn get(&mut self) -> u64 {
+        *self.0.get_or_insert_with(coarse_now_ms)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::config::{
-        CircuitBreakerConfig, ObservabilityConfig, RouteConfig, ServerConfig, ServiceConfig,
-        UpstreamConfig,
+        CircuitBreakerConfig, ObservabilityConfig, RouteConfig, RouteGroupConfig,
+        RouteObservabilityConfig, ServerConfig, ServiceConfig, UpstreamConfig,
     };
 
     fn upstream(addr: &str) -> UpstreamConfig {
@@ -403,14 +2526,30 @@ mod tests {
             addr: addr.to_string(),
             tls: false,
             sni: None,
+            verify_hostname_as: None,
+            host: None,
+            preserve_host: false,
+            http2: false,
+            http_version: None,
             weight: 1,
             verify_cert: None,
             verify_hostname: None,
+            client_cert_path: None,
+            client_key_path: None,
+            pinned_cert_sha256: Vec::new(),
             connect_timeout_ms: None,
             total_connect_timeout_ms: None,
             read_timeout_ms: None,
             write_timeout_ms: None,
             idle_timeout_ms: None,
+            max_requests_per_connection: None,
+            max_connection_lifetime_ms: None,
+            health_check: None,
+            egress: Default::default(),
+            proxy_protocol: None,
+            drain_hook: None,
+            resolve_interval_s: None,
+            tier: 0,
         }
     }
 
@@ -425,19 +2564,60 @@ mod tests {
             lb,
             max_retries,
             retry_backoff_ms: 0,
+            retry_backoff: None,
+            retry_on: Vec::new(),
+            retry_methods: Vec::new(),
+            outlier_detection: None,
+            slow_start_s: None,
+            discover: None,
             circuit_breaker: no_breaker(),
             upstreams,
         }
     }
 
-    fn route(name: &str, service: &str, host: Option<&str>, path_prefix: &str, is_default: bool) -> RouteConfig {
+    fn route(
+        name: &str,
+        service: &str,
+        host: Option<&str>,
+        path_prefix: &str,
+        is_default: bool,
+    ) -> RouteConfig {
         RouteConfig {
             name: name.to_string(),
+            app: None,
+            tenant: None,
             service: service.to_string(),
             host: host.map(ToString::to_string),
             path_prefix: path_prefix.to_string(),
             methods: Vec::new(),
             is_default,
+            observability: RouteObservabilityConfig::default(),
+            bandwidth: None,
+            max_response_bytes: None,
+            max_request_body_bytes: None,
+            retry_body_bytes: None,
+            timeout_ms: None,
+            strip_prefix: false,
+            rewrite_path: None,
+            forwarded_headers: Default::default(),
+            rate_limit: None,
+            max_concurrent_requests: None,
+            preflight_cache: None,
+            idempotency: None,
+            groups: Vec::new(),
+            group_key: None,
+            group_header: None,
+            egress: Default::default(),
+            outbound_proxy: None,
+            protocol: Default::default(),
+            websocket: false,
+            websocket_idle_timeout_ms: None,
+            status_map: Vec::new(),
+            response_validation: None,
+            header_case: Default::default(),
+            merge_duplicate_headers: false,
+            hash_affinity: Default::default(),
+            headers: Default::default(),
         }
     }
 
@@ -447,29 +2627,93 @@ mod tests {
 
     fn runtime_from_parts(services: Vec<ServiceConfig>, routes: Vec<RouteConfig>) -> RuntimeConfig {
         RuntimeConfig::from_config(PrxConfig {
+            include: Vec::new(),
             server: ServerConfig::default(),
             observability: ObservabilityConfig::default(),
+            apps: Vec::new(),
+            tenants: Vec::new(),
+            admin: None,
+            tcp_routes: Vec::new(),
+            waf: None,
+            reputation: None,
             services,
             routes,
         })
+        .expect("runtime")
+    }
+
+    #[test]
+    fn noise_covers_probes_and_listed_paths_unless_observed() {
+        let services = vec![service(
+            "api",
+            LbStrategy::RoundRobin,
+            0,
+            vec![upstream("127.0.0.1:9000")],
+        )];
+        let routes = vec![route("api", "api", None, "/", true)];
+        let mut config = PrxConfig {
+            include: Vec::new(),
+            server: ServerConfig {
+                noise_paths: vec!["/ping*".to_string(), "/favicon.ico".to_string()],
+                ..ServerConfig::default()
+            },
+            observability: ObservabilityConfig::default(),
+            apps: Vec::new(),
+            tenants: Vec::new(),
+            admin: None,
+            tcp_routes: Vec::new(),
+            waf: None,
+            reputation: None,
+            services,
+            routes,
+        };
+        let runtime = RuntimeConfig::from_config(config.clone()).expect("runtime");
+        assert!(runtime.is_noise("/healthz", true));
+        assert!(runtime.is_noise("/ping/lb", false));
+        assert!(runtime.is_noise("/favicon.ico", false));
+        assert!(!runtime.is_noise("/favicon.ico.bak", false));
+        assert!(!runtime.is_noise("/api", false));
+
+        config.server.observe_noise = true;
+        let runtime = RuntimeConfig::from_config(config).expect("runtime");
+        assert!(!runtime.is_noise("/healthz", true));
+        assert!(!runtime.is_noise("/ping/lb", false));
     }
 
     #[test]
     fn select_route_returns_none_when_no_route_matches_and_no_default() {
         let runtime = runtime_from_parts(
-            vec![service("api", LbStrategy::RoundRobin, 0, vec![upstream("127.0.0.1:9000")])],
+            vec![service(
+                "api",
+                LbStrategy::RoundRobin,
+                0,
+                vec![upstream("127.0.0.1:9000")],
+            )],
             vec![route("api", "api", Some("api.local"), "/api", false)],
         );
 
-        assert_eq!(runtime.select_route("www.local", "/"), None);
+        assert_eq!(
+            runtime.select_route(None, "www.local", "/", &HeaderMap::new()),
+            None
+        );
     }
 
     #[test]
     fn select_route_uses_default_route_when_present() {
         let runtime = runtime_from_parts(
             vec![
-                service("api", LbStrategy::RoundRobin, 0, vec![upstream("127.0.0.1:9000")]),
-                service("default", LbStrategy::RoundRobin, 0, vec![upstream("127.0.0.1:9001")]),
+                service(
+                    "api",
+                    LbStrategy::RoundRobin,
+                    0,
+                    vec![upstream("127.0.0.1:9000")],
+                ),
+                service(
+                    "default",
+                    LbStrategy::RoundRobin,
+                    0,
+                    vec![upstream("127.0.0.1:9001")],
+                ),
             ],
             vec![
                 route("api", "api", Some("api.local"), "/api", false),
@@ -478,23 +2722,305 @@ mod tests {
         );
 
         let idx = runtime
-            .select_route("no-match.local", "/anything")
+            .select_route(None, "no-match.local", "/anything", &HeaderMap::new())
             .expect("default route should match");
-        assert_eq!(runtime.route(idx).map(|r| r.name.as_str()), Some("default"));
+        assert_eq!(runtime.route(idx).map(|r| &*r.name), Some("default"));
+    }
+
+    #[test]
+    fn select_route_only_considers_routes_of_the_requested_app() {
+        let mut internal = route("internal", "default", None, "/", true);
+        internal.app = Some("internal".to_string());
+        let runtime = RuntimeConfig::from_config(PrxConfig {
+            include: Vec::new(),
+            server: ServerConfig::default(),
+            observability: ObservabilityConfig::default(),
+            apps: vec![crate::config::AppConfig {
+                name: "internal".to_string(),
+                listen: vec!["127.0.0.1:0".to_string()],
+                tls: None,
+                proxy_protocol: None,
+                observability: RouteObservabilityConfig {
+                    access_log: Some(false),
+                    ..RouteObservabilityConfig::default()
+                },
+                acl: Default::default(),
+            }],
+            tenants: Vec::new(),
+            admin: None,
+            tcp_routes: Vec::new(),
+            waf: None,
+            reputation: None,
+            services: vec![service(
+                "default",
+                LbStrategy::RoundRobin,
+                0,
+                vec![upstream("127.0.0.1:9000")],
+            )],
+            routes: vec![
+                route("main", "default", Some("main.local"), "/", false),
+                internal,
+            ],
+        })
+        .expect("runtime");
+
+        let idx = runtime
+            .select_route(Some("internal"), "main.local", "/", &HeaderMap::new())
+            .expect("app default route");
+        assert_eq!(runtime.route(idx).map(|r| &*r.name), Some("internal"));
+        assert!(
+            !runtime
+                .observability(Some("internal"), Some(idx))
+                .access_log
+        );
+        assert!(!runtime.observability(Some("internal"), None).access_log);
+
+        let idx = runtime
+            .select_route(None, "main.local", "/", &HeaderMap::new())
+            .expect("main route");
+        assert_eq!(runtime.route(idx).map(|r| &*r.name), Some("main"));
+        assert_eq!(
+            runtime.select_route(None, "other.local", "/", &HeaderMap::new()),
+            None
+        );
+    }
+
+    #[test]
+    fn select_route_prefers_routes_whose_headers_all_match() {
+        let mut canary = route("canary", "default", None, "/", false);
+        canary
+            .headers
+            .insert("x-tenant".to_string(), "acme".to_string());
+        canary
+            .headers
+            .insert("X-Version".to_string(), "v2".to_string());
+        let runtime = runtime_from_parts(
+            vec![service(
+                "default",
+                LbStrategy::RoundRobin,
+                0,
+                vec![upstream("127.0.0.1:9102")],
+            )],
+            vec![route("main", "default", None, "/", true), canary],
+        );
+        let selected = |headers: &[(&'static str, &'static str)]| {
+            let headers: HeaderMap = headers
+                .iter()
+                .map(|(name, value)| {
+                    (
+                        HeaderName::from_static(name),
+                        HeaderValue::from_static(value),
+                    )
+                })
+                .collect();
+            let idx = runtime
+                .select_route(None, "any.local", "/", &headers)
+                .expect("route");
+            runtime.route(idx).expect("route exists").name.to_string()
+        };
+
+        assert_eq!(
+            selected(&[("x-tenant", "acme"), ("x-version", "v2")]),
+            "canary"
+        );
+        assert_eq!(selected(&[("x-tenant", "acme")]), "main");
+        assert_eq!(
+            selected(&[("x-tenant", "acme"), ("x-version", "V2")]),
+            "main"
+        );
+        assert_eq!(selected(&[]), "main");
+    }
+
+    #[test]
+    fn upstream_path_strips_or_rewrites_the_route_prefix() {
+        let mut stripped = route("stripped", "default", None, "/api/", false);
+        stripped.strip_prefix = true;
+        let mut rewritten = route("rewritten", "default", None, "/v1", false);
+        rewritten.rewrite_path = Some("/internal$1".to_string());
+        let runtime = runtime_from_parts(
+            vec![service(
+                "default",
+                LbStrategy::RoundRobin,
+                0,
+                vec![upstream("127.0.0.1:9102")],
+            )],
+            vec![
+                route("main", "default", None, "/", true),
+                stripped,
+                rewritten,
+            ],
+        );
+        let path = |name: &str, path: &str| {
+            let idx = runtime
+                .routes()
+                .iter()
+                .position(|route| &*route.name == name)
+                .expect("route");
+            runtime
+                .route(idx)
+                .expect("route exists")
+                .upstream_path(path)
+        };
+
+        assert_eq!(path("stripped", "/api/users").as_deref(), Some("/users"));
+        assert_eq!(path("stripped", "/api/").as_deref(), Some("/"));
+        assert_eq!(
+            path("rewritten", "/v1/users").as_deref(),
+            Some("/internal/users")
+        );
+        assert_eq!(path("rewritten", "/v1").as_deref(), Some("/internal/"));
+        assert_eq!(path("main", "/api/users"), None);
+    }
+
+    #[test]
+    fn select_group_splits_traffic_by_sticky_key() {
+        let mut split = route("api", "stable", None, "/", true);
+        split.groups = vec![RouteGroupConfig {
+            name: "canary".to_string(),
+            service: "canary".to_string(),
+            percent: 20,
+        }];
+        split.group_key = Some("header:x-user".to_string());
+        let runtime = runtime_from_parts(
+            vec![
+                service(
+                    "stable",
+                    LbStrategy::RoundRobin,
+                    0,
+                    vec![upstream("127.0.0.1:9501")],
+                ),
+                service(
+                    "canary",
+                    LbStrategy::RoundRobin,
+                    0,
+                    vec![upstream("127.0.0.1:9502")],
+                ),
+            ],
+            vec![split],
+        );
+        let route = runtime.route(0).expect("route exists");
+
+        let user = |id: usize| {
+            let mut headers = HeaderMap::new();
+            headers.insert(
+                "x-user",
+                HeaderValue::from_str(&format!("user-{id}")).expect("value"),
+            );
+            headers
+        };
+        let canary = (0..1000)
+            .filter(|id| route.select_group(None, &user(*id)).is_some())
+            .count();
+        assert!((120..=280).contains(&canary), "canary got {canary} of 1000");
+
+        // The same key always lands in the same group.
+        let first = route
+            .select_group(None, &user(7))
+            .map(|group| group.service_idx);
+        for _ in 0..10 {
+            assert_eq!(
+                route
+                    .select_group(None, &user(7))
+                    .map(|group| group.service_idx),
+                first
+            );
+        }
+        let canary_idx = runtime
+            .services()
+            .iter()
+            .position(|svc| svc.name == "canary");
+        assert!(first.is_none() || first == canary_idx);
+    }
+
+    #[test]
+    fn select_group_falls_back_to_client_ip_without_the_cookie() {
+        let mut split = route("api", "stable", None, "/", true);
+        split.groups = vec![RouteGroupConfig {
+            name: "canary".to_string(),
+            service: "canary".to_string(),
+            percent: 20,
+        }];
+        split.group_key = Some("cookie:session".to_string());
+        let runtime = runtime_from_parts(
+            vec![
+                service(
+                    "stable",
+                    LbStrategy::RoundRobin,
+                    0,
+                    vec![upstream("127.0.0.1:9501")],
+                ),
+                service(
+                    "canary",
+                    LbStrategy::RoundRobin,
+                    0,
+                    vec![upstream("127.0.0.1:9502")],
+                ),
+            ],
+            vec![split],
+        );
+        let route = runtime.route(0).expect("route exists");
+        let canary_idx = runtime
+            .services()
+            .iter()
+            .position(|svc| svc.name == "canary");
+
+        // A client keeps to the group of its IP until it has the cookie.
+        let ips: Vec<IpAddr> = (0..=255)
+            .map(|last| IpAddr::from([10, 0, 0, last]))
+            .collect();
+        let groups = |ip: IpAddr| {
+            (0..10)
+                .map(|_| {
+                    route
+                        .select_group(Some(ip), &HeaderMap::new())
+                        .map(|group| group.service_idx)
+                })
+                .collect::<Vec<_>>()
+        };
+        for ip in &ips {
+            let groups = groups(*ip);
+            assert!(groups.windows(2).all(|pair| pair[0] == pair[1]), "{ip}");
+        }
+        assert!(ips.iter().any(|ip| groups(*ip)[0] == canary_idx));
+        assert!(ips.iter().any(|ip| groups(*ip)[0].is_none()));
+    }
+
+    #[test]
+    fn route_enter_sheds_past_max_concurrent_requests() {
+        let mut limited = route("limited", "default", None, "/", true);
+        limited.max_concurrent_requests = Some(2);
+        let runtime = runtime_from_parts(
+            vec![service(
+                "default",
+                LbStrategy::RoundRobin,
+                0,
+                vec![upstream("127.0.0.1:9102")],
+            )],
+            vec![limited],
+        );
+        let route = runtime.route(0).expect("route exists");
+
+        let first = route.enter().expect("first");
+        let _second = route.enter().expect("second");
+        assert!(route.enter().is_none());
+        drop(first);
+        assert!(route.enter().is_some());
     }
 
     #[test]
     fn next_upstream_skips_attempted_candidate_for_failover() {
         let runtime = runtime_from_parts(
-            vec![service("default", LbStrategy::Hash, 1, vec![
-                upstream("127.0.0.1:9100"),
-                upstream("127.0.0.1:9101"),
-            ])],
+            vec![service(
+                "default",
+                LbStrategy::Hash,
+                1,
+                vec![upstream("127.0.0.1:9100"), upstream("127.0.0.1:9101")],
+            )],
             vec![route("default", "default", None, "/", true)],
         );
 
         let route_idx = runtime
-            .select_route("example.local", "/")
+            .select_route(None, "example.local", "/", &HeaderMap::new())
             .expect("route selected");
         let route = runtime.route(route_idx).expect("route exists");
         let svc = runtime.service(route.service_idx).expect("service exists");
@@ -507,27 +3033,189 @@ mod tests {
         assert_ne!(first_idx, second_idx);
     }
 
+    #[test]
+    fn ewma_favors_faster_upstreams_and_penalizes_failures() {
+        let runtime = runtime_from_parts(
+            vec![service(
+                "default",
+                LbStrategy::Ewma,
+                1,
+                vec![upstream("127.0.0.1:9110"), upstream("127.0.0.1:9111")],
+            )],
+            vec![route("default", "default", None, "/", true)],
+        );
+        let svc = runtime.service(0).expect("service exists");
+        let ms = Duration::from_millis;
+
+        svc.record_upstream_latency(0, ms(10), false);
+        svc.record_upstream_latency(0, ms(60), false);
+        assert_eq!(svc.upstreams[0].ewma_latency_us(), 20_000);
+        svc.record_upstream_latency(1, ms(200), false);
+        let fast = (0..1_000)
+            .filter(|_| svc.next_upstream(0, &[]).expect("next upstream").0 == 0)
+            .count();
+        assert!((650..850).contains(&fast), "fast picked {fast} of 1000");
+        assert_eq!(svc.next_upstream(0, &[0]).expect("next upstream").0, 1);
+
+        for _ in 0..5 {
+            svc.record_upstream_latency(0, ms(1), true);
+        }
+        assert!(svc.upstreams[0].ewma_latency_us() > 200_000);
+        runtime.reset_stats();
+        assert_eq!(svc.upstreams[0].ewma_latency_us(), 0);
+    }
+
+    #[test]
+    fn prefer_reuse_moves_hash_keys_only_to_warm_upstreams() {
+        let runtime = runtime_from_parts(
+            vec![service(
+                "default",
+                LbStrategy::Hash,
+                1,
+                vec![
+                    upstream("127.0.0.1:9120"),
+                    upstream("127.0.0.1:9121"),
+                    upstream("127.0.0.1:9122"),
+                    upstream("127.0.0.1:9123"),
+                ],
+            )],
+            vec![route("default", "default", None, "/", true)],
+        );
+        let svc = runtime.service(0).expect("service exists");
+        let picks = |svc: &ServiceRuntime| {
+            (0..64)
+                .map(|seed| {
+                    let strict = svc.next_upstream(seed, &[]).expect("next upstream").0;
+                    let reusing = svc
+                        .next_upstream_reusing(seed, &[])
+                        .expect("next upstream")
+                        .0;
+                    (strict, reusing)
+                })
+                .collect::<Vec<_>>()
+        };
+        assert!(picks(svc).iter().all(|(strict, reusing)| strict == reusing));
+
+        svc.upstreams[2].release_connection();
+        let moved = picks(svc)
+            .iter()
+            .filter(|(strict, reusing)| {
+                assert!(reusing == strict || *reusing == 2);
+                reusing != strict
+            })
+            .count();
+        assert!((1..48).contains(&moved), "moved {moved} of 64 keys");
+        assert_eq!(picks(svc), picks(svc));
+
+        svc.upstreams[2].take_pooled_connection();
+        svc.upstreams[2].take_pooled_connection();
+        assert_eq!(svc.upstreams[2].idle_connections(), 0);
+        assert!(picks(svc).iter().all(|(strict, reusing)| strict == reusing));
+    }
+
     #[test]
     fn normalize_host_lowercases_and_strips_port() {
         assert_eq!(normalize_host("Example.COM:8443"), "example.com");
     }
 
+    #[test]
+    fn outlier_detection_ejects_then_reintroduces_upstreams() {
+        let mut svc = service(
+            "default",
+            LbStrategy::RoundRobin,
+            1,
+            vec![upstream("127.0.0.1:9200"), upstream("127.0.0.1:9201")],
+        );
+        svc.outlier_detection = Some(OutlierDetectionConfig {
+            window_ms: 10_000,
+            min_requests: 4,
+            max_error_rate: Some(0.5),
+            max_p99_latency_ms: None,
+            eject_ms: 1_000,
+            reintroduce_ms: 1_000,
+        });
+        let runtime = runtime_from_parts(
+            vec![svc],
+            vec![route("default", "default", None, "/", true)],
+        );
+        let service = runtime.service(0).expect("service exists");
+        let outlier = service
+            .outlier_detection
+            .as_ref()
+            .expect("outlier detection");
+        let upstream = &service.upstreams[0];
+
+        let start = now_epoch_ms();
+        assert!(!upstream.record_outlier_sample(outlier, start, 5, false));
+        for offset in 1..3 {
+            assert!(!upstream.record_outlier_sample(outlier, start + offset, 5, true));
+        }
+        // The fourth answer reaches min_requests with 3 of 4 failed.
+        assert!(upstream.record_outlier_sample(outlier, start + 3, 5, true));
+        for _ in 0..4 {
+            assert_eq!(service.next_upstream(0, &[]).expect("next upstream").0, 1);
+        }
+        // Ejected upstreams are still picked over none.
+        assert_eq!(service.next_upstream(0, &[1]).expect("next upstream").0, 0);
+
+        let back = start + 3 + 1_000;
+        let taken = (0..1_000)
+            .filter(|_| upstream.takes_outlier_turn(Some(outlier), &mut LazyNow(Some(back))))
+            .count();
+        assert!((30..250).contains(&taken), "taken {taken} of 1000");
+        assert!(upstream.takes_outlier_turn(Some(outlier), &mut LazyNow(Some(back + 1_000))));
+        assert_eq!(
+            upstream
+                .state
+                .ejected_until_epoch_ms
+                .load(Ordering::Relaxed),
+            0
+        );
+
+        let p99 = OutlierDetection {
+            max_error_rate: None,
+            max_p99_latency_ms: Some(50),
+            ..outlier.clone()
+        };
+        let samples = |slow: usize| {
+            (0..100)
+                .map(|index| OutlierSample {
+                    at_ms: 0,
+                    latency_ms: if index < slow { 1_000 } else { 10 },
+                    failed: false,
+                })
+                .collect::<VecDeque<_>>()
+        };
+        assert!(!p99.is_outlier(&samples(1)));
+        assert!(p99.is_outlier(&samples(2)));
+    }
+
     #[test]
     fn circuit_breaker_opens_after_failure_threshold() {
         let breaker = CircuitBreakerConfig {
             enabled: true,
             consecutive_failures: 1,
             open_ms: 60_000,
+            ..CircuitBreakerConfig::default()
         };
         let svc = ServiceConfig {
             name: "default".to_string(),
             lb: LbStrategy::RoundRobin,
             max_retries: 1,
             retry_backoff_ms: 0,
+            retry_backoff: None,
+            retry_on: Vec::new(),
+            retry_methods: Vec::new(),
+            outlier_detection: None,
+            slow_start_s: None,
+            discover: None,
             circuit_breaker: breaker,
             upstreams: vec![upstream("127.0.0.1:9200"), upstream("127.0.0.1:9201")],
         };
-        let runtime = runtime_from_parts(vec![svc], vec![route("default", "default", None, "/", true)]);
+        let runtime = runtime_from_parts(
+            vec![svc],
+            vec![route("default", "default", None, "/", true)],
+        );
 
         let route = runtime.route(0).expect("route exists");
         let service = runtime.service(route.service_idx).expect("service exists");
@@ -540,22 +3228,105 @@ mod tests {
         assert_eq!(next_idx, 1);
     }
 
+    #[test]
+    fn half_open_breaker_probes_then_closes_or_backs_off() {
+        let mut svc = service(
+            "default",
+            LbStrategy::RoundRobin,
+            1,
+            vec![upstream("127.0.0.1:9200"), upstream("127.0.0.1:9201")],
+        );
+        svc.circuit_breaker = CircuitBreakerConfig {
+            enabled: true,
+            consecutive_failures: 1,
+            open_ms: 10_000,
+            half_open_probes: 2,
+            max_open_ms: 30_000,
+            ..CircuitBreakerConfig::default()
+        };
+        let runtime = runtime_from_parts(
+            vec![svc],
+            vec![route("default", "default", None, "/", true)],
+        );
+        let service = runtime.service(0).expect("service exists");
+        let upstream = &service.upstreams[0];
+        let open_until = || upstream.state.open_until_epoch_ms.load(Ordering::Relaxed);
+        let half_open = || {
+            upstream
+                .state
+                .open_until_epoch_ms
+                .store(coarse_now_ms() - 1, Ordering::Relaxed)
+        };
+        let picks = || {
+            (0..6)
+                .filter(|_| {
+                    service
+                        .next_upstream(0, &[1])
+                        .is_some_and(|(idx, _)| idx == 0)
+                })
+                .count()
+        };
+
+        assert!(service.mark_upstream_failure(0));
+        assert_eq!(picks(), 0);
+        // A success of a request sent before the breaker opened changes nothing.
+        service.mark_upstream_success(0);
+        assert!(upstream.is_circuit_open());
+
+        half_open();
+        assert_eq!(picks(), 2);
+        service.mark_upstream_success(0);
+        assert!(service.mark_upstream_failure(0));
+        let reopened_for = open_until() - coarse_now_ms();
+        assert!(
+            (15_000..=20_000).contains(&reopened_for),
+            "{reopened_for}ms"
+        );
+
+        half_open();
+        assert!(service.mark_upstream_failure(0));
+        let reopened_for = open_until() - coarse_now_ms();
+        assert!(
+            (25_000..=30_000).contains(&reopened_for),
+            "{reopened_for}ms"
+        );
+
+        half_open();
+        assert_eq!(picks(), 2);
+        service.mark_upstream_success(0);
+        assert_ne!(open_until(), 0);
+        service.mark_upstream_success(0);
+        assert_eq!(open_until(), 0);
+        assert_eq!(picks(), 6);
+        assert_eq!(upstream.state.reopens.load(Ordering::Relaxed), 0);
+    }
+
     #[test]
     fn readiness_fails_when_all_upstreams_are_open_circuit() {
         let breaker = CircuitBreakerConfig {
             enabled: true,
             consecutive_failures: 1,
             open_ms: 60_000,
+            ..CircuitBreakerConfig::default()
         };
         let svc = ServiceConfig {
             name: "default".to_string(),
             lb: LbStrategy::RoundRobin,
             max_retries: 1,
             retry_backoff_ms: 0,
+            retry_backoff: None,
+            retry_on: Vec::new(),
+            retry_methods: Vec::new(),
+            outlier_detection: None,
+            slow_start_s: None,
+            discover: None,
             circuit_breaker: breaker,
             upstreams: vec![upstream("127.0.0.1:9300")],
         };
-        let runtime = runtime_from_parts(vec![svc], vec![route("default", "default", None, "/", true)]);
+        let runtime = runtime_from_parts(
+            vec![svc],
+            vec![route("default", "default", None, "/", true)],
+        );
 
         let route = runtime.route(0).expect("route exists");
         let service = runtime.service(route.service_idx).expect("service exists");
@@ -564,12 +3335,130 @@ mod tests {
         assert!(!runtime.is_ready());
     }
 
+    #[test]
+    fn circuit_breaker_counts_only_configured_failures() {
+        let defaults = CircuitBreakerRuntime::from_config(&CircuitBreakerConfig::default());
+        assert!(defaults.counts(UpstreamFailure::Connect));
+        assert!(defaults.counts(UpstreamFailure::Timeout));
+        assert!(!defaults.counts(UpstreamFailure::Status(503)));
+
+        let breaker = CircuitBreakerRuntime::from_config(&CircuitBreakerConfig {
+            failure_on: vec![BreakerFailure::Connect, BreakerFailure::ServerError],
+            failure_statuses: vec![429],
+            ..CircuitBreakerConfig::default()
+        });
+        assert!(breaker.counts(UpstreamFailure::Connect));
+        assert!(!breaker.counts(UpstreamFailure::Timeout));
+        assert!(!breaker.counts(UpstreamFailure::Proxy));
+        assert!(breaker.counts(UpstreamFailure::Status(502)));
+        assert!(breaker.counts(UpstreamFailure::Status(429)));
+        assert!(!breaker.counts(UpstreamFailure::Status(404)));
+    }
+
+    #[test]
+    fn retry_policy_matches_failures_methods_and_backs_off() {
+        let mut svc = service(
+            "app",
+            LbStrategy::RoundRobin,
+            0,
+            vec![upstream("127.0.0.1:9504")],
+        );
+        let defaults = RetryPolicy::from_config(&svc);
+        assert!(defaults.retries(UpstreamFailure::Proxy, Some(&http::Method::POST)));
+        assert!(!defaults.retries(UpstreamFailure::Status(503), Some(&http::Method::GET)));
+        assert!(!defaults.retries_statuses());
+        assert_eq!(defaults.backoff(1), None);
+
+        svc.retry_on = vec!["5xx".to_string(), "429".to_string(), "connect".to_string()];
+        svc.retry_methods = vec!["GET".to_string(), "HEAD".to_string()];
+        svc.retry_backoff = Some(RetryBackoffConfig {
+            base_ms: 100,
+            max_ms: 250,
+            jitter: false,
+        });
+        let policy = RetryPolicy::from_config(&svc);
+        let get = Some(&http::Method::GET);
+        assert!(policy.retries(UpstreamFailure::Status(502), get));
+        assert!(policy.retries(UpstreamFailure::Status(429), get));
+        assert!(!policy.retries(UpstreamFailure::Status(404), get));
+        assert!(!policy.retries(UpstreamFailure::Timeout, get));
+        assert!(!policy.retries(UpstreamFailure::Status(503), Some(&http::Method::POST)));
+        assert!(policy.retries(UpstreamFailure::Connect, Some(&http::Method::POST)));
+        assert!(policy.retries(UpstreamFailure::Connect, None));
+        let waits: Vec<_> = (1..=4).filter_map(|retry| policy.backoff(retry)).collect();
+        assert_eq!(waits, [100, 200, 250, 250].map(Duration::from_millis));
+
+        svc.retry_backoff = Some(RetryBackoffConfig {
+            base_ms: 100,
+            max_ms: 250,
+            jitter: true,
+        });
+        let jittered = RetryPolicy::from_config(&svc);
+        assert!((0..20).all(|_| jittered.backoff(2) <= Some(Duration::from_millis(200))));
+    }
+
+    #[test]
+    fn overloaded_upstream_keeps_a_reduced_share() {
+        let breaker = CircuitBreakerConfig {
+            overload_header: Some("x-backend-overloaded".to_string()),
+            overload_statuses: vec![429],
+            overload_weight_percent: 25,
+            ..CircuitBreakerConfig::default()
+        };
+        let svc = ServiceConfig {
+            name: "default".to_string(),
+            lb: LbStrategy::RoundRobin,
+            max_retries: 0,
+            retry_backoff_ms: 0,
+            retry_backoff: None,
+            retry_on: Vec::new(),
+            retry_methods: Vec::new(),
+            outlier_detection: None,
+            slow_start_s: None,
+            discover: None,
+            circuit_breaker: breaker,
+            upstreams: vec![upstream("127.0.0.1:9210"), upstream("127.0.0.1:9211")],
+        };
+        let runtime = runtime_from_parts(
+            vec![svc],
+            vec![route("default", "default", None, "/", true)],
+        );
+        let service = runtime.service(0).expect("service exists");
+
+        let mut headers = HeaderMap::new();
+        assert!(service.circuit_breaker.signals_overload(429, &headers));
+        assert!(!service.circuit_breaker.signals_overload(200, &headers));
+        headers.insert("x-backend-overloaded", HeaderValue::from_static("0"));
+        assert!(!service.circuit_breaker.signals_overload(200, &headers));
+        headers.insert("x-backend-overloaded", HeaderValue::from_static("1"));
+        assert!(service.circuit_breaker.signals_overload(200, &headers));
+
+        assert!(service.upstreams[0].mark_overloaded(60_000));
+        assert!(!service.upstreams[0].mark_overloaded(60_000));
+        let picks = (0..200)
+            .filter(|_| service.next_upstream(0, &[]).expect("next upstream").0 == 0)
+            .count();
+        assert_eq!(picks, 25);
+        // Still used when nothing else is left.
+        assert_eq!(service.next_upstream(0, &[1]).map(|(idx, _)| idx), Some(0));
+    }
+
     #[test]
     fn route_resolves_correct_service_index() {
         let runtime = runtime_from_parts(
             vec![
-                service("first", LbStrategy::RoundRobin, 0, vec![upstream("127.0.0.1:8001")]),
-                service("second", LbStrategy::RoundRobin, 0, vec![upstream("127.0.0.1:8002")]),
+                service(
+                    "first",
+                    LbStrategy::RoundRobin,
+                    0,
+                    vec![upstream("127.0.0.1:8001")],
+                ),
+                service(
+                    "second",
+                    LbStrategy::RoundRobin,
+                    0,
+                    vec![upstream("127.0.0.1:8002")],
+                ),
             ],
             vec![
                 route("r1", "second", None, "/a", false),
@@ -579,16 +3468,540 @@ mod tests {
 
         let r1 = runtime.route(0).expect("r1 exists");
         assert_eq!(r1.service_idx, 1); // "second" is at index 1
-        assert_eq!(
-            runtime.service(r1.service_idx).unwrap().name,
-            "second"
-        );
+        assert_eq!(runtime.service(r1.service_idx).unwrap().name, "second");
 
         let r2 = runtime.route(1).expect("r2 exists");
         assert_eq!(r2.service_idx, 0); // "first" is at index 0
+        assert_eq!(runtime.service(r2.service_idx).unwrap().name, "first");
+    }
+
+    #[test]
+    fn route_observability_overrides_global_settings() {
+        let mut internal = route("internal", "default", None, "/internal", false);
+        internal.observability.access_log = Some(false);
+        let runtime = runtime_from_parts(
+            vec![service(
+                "default",
+                LbStrategy::RoundRobin,
+                0,
+                vec![upstream("127.0.0.1:9400")],
+            )],
+            vec![internal, route("default", "default", None, "/", true)],
+        );
+
+        let internal_idx = runtime
+            .select_route(None, "any.local", "/internal/x", &HeaderMap::new())
+            .expect("internal route");
+        let default_idx = runtime
+            .select_route(None, "any.local", "/", &HeaderMap::new())
+            .expect("default route");
+
+        assert!(!runtime.observability(None, Some(internal_idx)).access_log);
+        assert!(runtime.observability(None, Some(default_idx)).access_log);
+        assert!(runtime.observability(None, None).access_log);
+    }
+
+    #[test]
+    fn rebuild_reuses_unchanged_services_and_keeps_their_state() {
+        let breaker = CircuitBreakerConfig {
+            enabled: true,
+            consecutive_failures: 1,
+            open_ms: 60_000,
+            ..CircuitBreakerConfig::default()
+        };
+        let rr = LbStrategy::RoundRobin;
+        let mut stable = service("stable", rr.clone(), 0, vec![upstream("127.0.0.1:9500")]);
+        stable.circuit_breaker = breaker;
+        let changing = service("changing", rr, 0, vec![upstream("127.0.0.1:9501")]);
+        let routes = vec![
+            route("stable", "stable", None, "/stable", false),
+            route("changing", "changing", None, "/", true),
+        ];
+        let runtime = Arc::new(runtime_from_parts(
+            vec![stable.clone(), changing.clone()],
+            routes.clone(),
+        ));
+        let stable_runtime = runtime.service(0).expect("stable service");
+        stable_runtime.mark_upstream_failure(0);
+
+        let mut changed = changing;
+        changed.max_retries = 2;
+        let (next, stats) = runtime
+            .rebuild(PrxConfig {
+                include: Vec::new(),
+                server: ServerConfig::default(),
+                observability: ObservabilityConfig::default(),
+                apps: Vec::new(),
+                tenants: Vec::new(),
+                admin: None,
+                tcp_routes: Vec::new(),
+                waf: None,
+                reputation: None,
+                services: vec![stable, changed],
+                routes,
+            })
+            .expect("rebuild");
+
+        assert_eq!(
+            stats,
+            RebuildStats {
+                reused_services: 1,
+                rebuilt_services: 1,
+                reused_routes: 2,
+                rebuilt_routes: 0,
+            }
+        );
+        assert!(next.service(0).expect("stable service").upstreams[0].is_circuit_open());
+        assert_eq!(next.service(1).expect("changed service").max_retries, 2);
+    }
+
+    #[test]
+    fn rebuild_fails_when_an_encrypted_value_does_not_decrypt() {
+        let svc = service(
+            "app",
+            LbStrategy::RoundRobin,
+            0,
+            vec![upstream("127.0.0.1:9510")],
+        );
+        let routes = vec![route("app", "app", None, "/", true)];
+        let runtime = Arc::new(runtime_from_parts(vec![svc.clone()], routes.clone()));
+
+        let mut encrypted = svc;
+        encrypted.upstreams[0].addr = format!(
+            "{}{}",
+            crate::secret::ENCRYPTED_PREFIX,
+            "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA="
+        );
+        let Err(err) = runtime.rebuild(PrxConfig {
+            include: Vec::new(),
+            server: ServerConfig::default(),
+            observability: ObservabilityConfig::default(),
+            apps: Vec::new(),
+            tenants: Vec::new(),
+            admin: None,
+            tcp_routes: Vec::new(),
+            waf: None,
+            reputation: None,
+            services: vec![encrypted],
+            routes,
+        }) else {
+            panic!("undecryptable value must fail the rebuild");
+        };
+
+        assert!(format!("{err:#}").contains("failed to decrypt encrypted config values"));
+        assert!(!runtime.service(0).expect("service").upstreams[0].is_draining());
+    }
+
+    #[test]
+    fn rebuild_drains_removed_upstreams() {
+        let svc = service(
+            "app",
+            LbStrategy::RoundRobin,
+            0,
+            vec![upstream("127.0.0.1:9520"), upstream("127.0.0.1:9521")],
+        );
+        let routes = vec![route("app", "app", None, "/", true)];
+        let runtime = Arc::new(runtime_from_parts(vec![svc.clone()], routes.clone()));
+        let kept = runtime.service(0).expect("service").upstreams[0].clone();
+        let removed = runtime.service(0).expect("service").upstreams[1].clone();
+        let to_kept = kept.start_request();
+        let to_removed = removed.start_request();
+        assert_eq!(removed.requests_in_flight(), 1);
+        assert!(!removed.should_retire_connection(true, None, None));
+
+        let (next, _) = runtime
+            .rebuild(PrxConfig {
+                include: Vec::new(),
+                server: ServerConfig {
+                    drain_timeout_s: 0,
+                    ..ServerConfig::default()
+                },
+                observability: ObservabilityConfig::default(),
+                apps: Vec::new(),
+                tenants: Vec::new(),
+                admin: None,
+                tcp_routes: Vec::new(),
+                waf: None,
+                reputation: None,
+                services: vec![ServiceConfig {
+                    upstreams: vec![upstream("127.0.0.1:9520")],
+                    ..svc
+                }],
+                routes,
+            })
+            .expect("rebuild");
+        assert!(removed.is_draining());
+        assert!(removed.should_retire_connection(true, None, None));
+        assert!(to_removed.drain_expired());
+        assert!(!next.service(0).expect("service").upstreams[0].is_draining());
+        assert!(!to_kept.drain_expired());
+        drop(to_removed);
+        assert_eq!(removed.requests_in_flight(), 0);
+    }
+
+    #[test]
+    fn rebuild_carries_state_over_to_changed_services() {
+        let breaker = CircuitBreakerConfig {
+            enabled: true,
+            consecutive_failures: 1,
+            open_ms: 60_000,
+            ..CircuitBreakerConfig::default()
+        };
+        let mut svc = service(
+            "app",
+            LbStrategy::RoundRobin,
+            0,
+            vec![upstream("127.0.0.1:9510"), upstream("127.0.0.1:9511")],
+        );
+        svc.circuit_breaker = breaker.clone();
+        let routes = vec![route("app", "app", None, "/", true)];
+        let runtime = Arc::new(runtime_from_parts(vec![svc.clone()], routes.clone()));
+        let before = runtime.service(0).expect("service");
+        before.mark_upstream_failure(0);
+        before.upstreams[1].state.health.restore(false);
+        for _ in 0..3 {
+            before.next_upstream(0, &[]);
+        }
+
+        svc.max_retries = 1;
+        svc.upstreams.push(upstream("127.0.0.1:9512"));
+        let (next, stats) = runtime
+            .rebuild(PrxConfig {
+                include: Vec::new(),
+                server: ServerConfig::default(),
+                observability: ObservabilityConfig::default(),
+                apps: Vec::new(),
+                tenants: Vec::new(),
+                admin: None,
+                tcp_routes: Vec::new(),
+                waf: None,
+                reputation: None,
+                services: vec![svc],
+                routes,
+            })
+            .expect("rebuild");
+        assert_eq!(stats.rebuilt_services, 1);
+        let after = next.service(0).expect("service");
+        assert_eq!(after.max_retries, 1);
+        assert!(after.upstreams[0].is_circuit_open());
+        assert!(!after.upstreams[2].is_circuit_open());
+        assert_eq!(after.round_robin_cursor(), before.round_robin_cursor());
+        // Without a health check the kept upstream could never turn healthy.
+        assert!(after.upstreams[1].health().is_healthy());
+        assert_eq!(after.next_upstream(0, &[]).map(|(idx, _)| idx), Some(1));
+    }
+
+    #[test]
+    fn slow_start_ramps_in_upstreams_added_by_a_reload() {
+        let routes = vec![route("app", "app", None, "/", true)];
+        let svc = |upstreams| {
+            let mut svc = service("app", LbStrategy::RoundRobin, 0, upstreams);
+            svc.slow_start_s = Some(10);
+            svc
+        };
+        let runtime = Arc::new(runtime_from_parts(
+            vec![svc(vec![upstream("127.0.0.1:9504")])],
+            routes.clone(),
+        ));
+        let first = &runtime.service(0).expect("service").upstreams[0];
+        assert_eq!(
+            first.state.warming_since_epoch_ms.load(Ordering::Relaxed),
+            0
+        );
+
+        let (next, _) = runtime
+            .rebuild(PrxConfig {
+                include: Vec::new(),
+                server: ServerConfig::default(),
+                observability: ObservabilityConfig::default(),
+                apps: Vec::new(),
+                tenants: Vec::new(),
+                admin: None,
+                tcp_routes: Vec::new(),
+                waf: None,
+                reputation: None,
+                services: vec![svc(vec![
+                    upstream("127.0.0.1:9504"),
+                    upstream("127.0.0.1:9505"),
+                ])],
+                routes,
+            })
+            .expect("rebuild");
+        let service = next.service(0).expect("service");
+        let (kept, added) = (&service.upstreams[0], &service.upstreams[1]);
+        assert_eq!(kept.state.warming_since_epoch_ms.load(Ordering::Relaxed), 0);
+        let since = added.state.warming_since_epoch_ms.load(Ordering::Relaxed);
+        assert_ne!(since, 0);
+
+        let window = service.slow_start_ms;
+        let taken = |at: u64| {
+            (0..1_000)
+                .filter(|_| added.takes_slow_start_turn(window, &mut LazyNow(Some(at))))
+                .count()
+        };
+        assert!(
+            (30..250).contains(&taken(since)),
+            "taken {} of 1000",
+            taken(since)
+        );
+        let halfway = taken(since + 5_000);
+        assert!((450..650).contains(&halfway), "taken {halfway} of 1000");
+        assert!(added.takes_slow_start_turn(window, &mut LazyNow(Some(since + 10_000))));
+        assert_eq!(
+            added.state.warming_since_epoch_ms.load(Ordering::Relaxed),
+            0
+        );
+        // Warming upstreams are still picked over none.
+        added.begin_slow_start();
+        assert_eq!(service.next_upstream(0, &[0]).expect("next upstream").0, 1);
+    }
+
+    #[test]
+    fn rebuild_signals_upstreams_taken_out_of_their_service() {
+        use std::io::Read;
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("bind");
+        let addr = listener.local_addr().expect("addr").to_string();
+        let mut leaving = upstream(&addr);
+        leaving.drain_hook = Some("/drain".to_string());
+        let remaining = upstream("127.0.0.1:9503");
+        let routes = vec![route("app", "app", None, "/", true)];
+        let svc = |upstreams| service("app", LbStrategy::RoundRobin, 0, upstreams);
+        let runtime = Arc::new(runtime_from_parts(
+            vec![svc(vec![leaving, remaining.clone()])],
+            routes.clone(),
+        ));
+
+        runtime
+            .rebuild(PrxConfig {
+                include: Vec::new(),
+                server: ServerConfig::default(),
+                observability: ObservabilityConfig::default(),
+                apps: Vec::new(),
+                tenants: Vec::new(),
+                admin: None,
+                tcp_routes: Vec::new(),
+                waf: None,
+                reputation: None,
+                services: vec![svc(vec![remaining])],
+                routes,
+            })
+            .expect("rebuild");
+
+        let (mut stream, _) = listener.accept().expect("drain hook");
+        let mut request = [0; 256];
+        let read = stream.read(&mut request).expect("read");
+        assert!(request[..read].starts_with(b"POST /drain HTTP/1.1\r\n"));
+    }
+
+    #[test]
+    fn rollout_stages_changed_configs_over_the_first_stable_snapshot() {
+        let services = vec![service(
+            "app",
+            LbStrategy::RoundRobin,
+            0,
+            vec![upstream("127.0.0.1:9502")],
+        )];
+        let config = |max_retries: usize| {
+            let mut services = services.clone();
+            services[0].max_retries = max_retries;
+            PrxConfig {
+                include: Vec::new(),
+                server: ServerConfig {
+                    rollout: Some(crate::config::RolloutConfig {
+                        ramp_ms: 3_600_000,
+                        ..Default::default()
+                    }),
+                    ..ServerConfig::default()
+                },
+                observability: ObservabilityConfig::default(),
+                apps: Vec::new(),
+                tenants: Vec::new(),
+                admin: None,
+                tcp_routes: Vec::new(),
+                waf: None,
+                reputation: None,
+                services,
+                routes: vec![route("default", "app", None, "/", true)],
+            }
+        };
+        let stable = Arc::new(RuntimeConfig::from_config(config(0)).expect("runtime"));
+
+        let (unchanged, _) = stable.rebuild(config(0)).expect("rebuild");
+        assert!(unchanged.rollout().is_none());
+
+        let first = Arc::new(stable.rebuild(config(1)).expect("rebuild").0);
+        let rollout = first.rollout().expect("staged");
+        assert!(Arc::ptr_eq(rollout.stable(), &stable));
+        // At the start of the ramp every request stays on the stable snapshot.
+        assert!(Arc::ptr_eq(&first.for_request(), &stable));
+
+        let second = first.rebuild(config(2)).expect("rebuild").0;
+        assert!(Arc::ptr_eq(
+            second.rollout().expect("staged").stable(),
+            &stable
+        ));
+    }
+
+    #[test]
+    fn shadow_keeps_serving_the_stable_snapshot_until_the_window_ends() {
+        let services = vec![service(
+            "app",
+            LbStrategy::RoundRobin,
+            0,
+            vec![upstream("127.0.0.1:9503")],
+        )];
+        let config = |max_retries: usize, duration_ms: u64| {
+            let mut services = services.clone();
+            services[0].max_retries = max_retries;
+            PrxConfig {
+                include: Vec::new(),
+                server: ServerConfig {
+                    shadow: Some(crate::config::ShadowConfig { duration_ms }),
+                    ..ServerConfig::default()
+                },
+                observability: ObservabilityConfig::default(),
+                apps: Vec::new(),
+                tenants: Vec::new(),
+                admin: None,
+                tcp_routes: Vec::new(),
+                waf: None,
+                reputation: None,
+                services,
+                routes: vec![route("default", "app", None, "/", true)],
+            }
+        };
+        let stable = Arc::new(RuntimeConfig::from_config(config(0, 3_600_000)).expect("runtime"));
+
+        let first = Arc::new(stable.rebuild(config(1, 3_600_000)).expect("rebuild").0);
+        assert!(Arc::ptr_eq(
+            first.shadow().expect("staged").stable(),
+            &stable
+        ));
+        assert!(Arc::ptr_eq(&first.for_request(), &stable));
+        let second = Arc::new(first.rebuild(config(2, 3_600_000)).expect("rebuild").0);
+        assert!(Arc::ptr_eq(
+            second.shadow().expect("staged").stable(),
+            &stable
+        ));
+
+        let done = Arc::new(second.rebuild(config(3, 1)).expect("rebuild").0);
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(Arc::ptr_eq(&done.for_request(), &done));
+        assert!(done.shadow().is_none());
+    }
+
+    #[test]
+    fn retires_connections_after_request_or_lifetime_budget() {
+        let mut config = upstream("127.0.0.1:9600");
+        config.max_requests_per_connection = Some(2);
+        config.max_connection_lifetime_ms = Some(60_000);
+        let upstream = UpstreamRuntime::from_config(config);
+        let conn: SocketAddr = "127.0.0.1:40000".parse().expect("addr");
+        let fresh = Some(SystemTime::now());
+
+        assert!(!upstream.should_retire_connection(false, Some(conn), fresh));
+        assert!(upstream.should_retire_connection(true, Some(conn), fresh));
+        // A new connection on the same local port starts a fresh budget.
+        assert!(!upstream.should_retire_connection(false, Some(conn), fresh));
+
+        let expired = SystemTime::now().checked_sub(Duration::from_secs(120));
+        assert!(upstream.should_retire_connection(true, Some(conn), expired));
+    }
+
+    #[test]
+    fn host_header_defaults_to_sni_and_is_decoupled_from_verification() {
+        let mut config = upstream("10.0.0.5:443");
+        config.sni = Some("edge.example.com".to_string());
+        config.verify_hostname_as = Some("origin.internal".to_string());
+        let default = UpstreamRuntime::from_config(config.clone());
+        assert_eq!(default.host_header, HostHeader::Sni);
+        assert_eq!(default.sni, "edge.example.com");
+        assert_eq!(
+            default.verify_hostname_as.as_deref(),
+            Some("origin.internal")
+        );
+
+        config.host = Some("app.example.com".to_string());
+        assert_eq!(
+            UpstreamRuntime::from_config(config.clone()).host_header,
+            HostHeader::Fixed("app.example.com".to_string())
+        );
+        config.host = None;
+        config.preserve_host = true;
+        assert_eq!(
+            UpstreamRuntime::from_config(config).host_header,
+            HostHeader::Preserve
+        );
+    }
+
+    #[test]
+    fn http2_upstream_falls_back_to_http1_once_until_reset() {
+        let plain = UpstreamRuntime::from_config(upstream("127.0.0.1:9703"));
+        assert!(!plain.uses_http2());
+        assert!(!plain.fall_back_to_http1());
+
+        let mut config = upstream("127.0.0.1:9704");
+        config.http2 = true;
+        let upstream = UpstreamRuntime::from_config(config);
+        assert!(upstream.uses_http2());
+        assert!(upstream.fall_back_to_http1());
+        assert!(!upstream.fall_back_to_http1());
+        assert!(!upstream.clone().uses_http2());
+        upstream.reset_stats();
+        assert!(upstream.uses_http2());
+
+        let mut only = self::upstream("127.0.0.1:9705");
+        only.http_version = Some(UpstreamHttpVersion::H2);
+        let only = UpstreamRuntime::from_config(only);
+        assert!(!only.fall_back_to_http1());
+        assert!(only.uses_http2());
+    }
+
+    #[test]
+    fn caps_metric_labels_and_shares_overflow() {
+        let services = vec![service(
+            "app",
+            LbStrategy::RoundRobin,
+            0,
+            vec![
+                upstream("127.0.0.1:9700"),
+                upstream("127.0.0.1:9701"),
+                upstream("127.0.0.1:9700"),
+            ],
+        )];
+        let routes = vec![
+            route("first", "app", None, "/first", false),
+            route("second", "app", None, "/", true),
+        ];
+        let runtime = RuntimeConfig::from_config(PrxConfig {
+            include: Vec::new(),
+            server: ServerConfig::default(),
+            observability: ObservabilityConfig {
+                max_metric_label_values: 1,
+                ..ObservabilityConfig::default()
+            },
+            apps: Vec::new(),
+            tenants: Vec::new(),
+            admin: None,
+            tcp_routes: Vec::new(),
+            waf: None,
+            reputation: None,
+            services,
+            routes,
+        })
+        .expect("runtime");
+
+        let labels = runtime.service(0).expect("service").upstreams.iter();
+        let labels = labels.map(|u| &*u.metric_label).collect::<Vec<_>>();
         assert_eq!(
-            runtime.service(r2.service_idx).unwrap().name,
-            "first"
+            labels,
+            ["127.0.0.1:9700", metrics::OVERFLOW_LABEL, "127.0.0.1:9700"]
         );
+        let route_labels = (0..2)
+            .map(|idx| runtime.route(idx).expect("route").metric_label.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(&*route_labels[0], "first");
+        assert_eq!(&*route_labels[1], metrics::OVERFLOW_LABEL);
     }
-}
\ No newline at end of file
+}
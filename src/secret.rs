@@ -0,0 +1,195 @@
+use std::fs;
+
+use aes_gcm::{
+    Aes256Gcm, Key, KeyInit, Nonce,
+    aead::{Aead, AeadCore, OsRng},
+};
+use anyhow::{Context, anyhow, bail};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use once_cell::sync::Lazy;
+
+use crate::{config::PrxConfig, env_value};
+
+/// Prefix marking an encrypted config string: `enc:<base64(nonce || ciphertext)>`.
+pub const ENCRYPTED_PREFIX: &str = "enc:";
+const NONCE_LEN: usize = 12;
+
+/// Key used to decrypt `enc:` values, loaded once from `PRX_CONFIG_KEY`
+/// (base64, 32 bytes) or the file named by `PRX_CONFIG_KEY_FILE`.
+static CONFIG_KEY: Lazy<Result<Option<ConfigKey>, String>> =
+    Lazy::new(|| ConfigKey::from_env().map_err(|err| format!("{err:#}")));
+
+#[derive(Clone)]
+pub struct ConfigKey(Key<Aes256Gcm>);
+
+impl ConfigKey {
+    pub fn from_base64(raw: &str) -> anyhow::Result<Self> {
+        let bytes = STANDARD
+            .decode(raw.trim())
+            .context("config key must be base64")?;
+        if bytes.len() != 32 {
+            bail!("config key must be 32 bytes, got {}", bytes.len());
+        }
+        Ok(Self(*Key::<Aes256Gcm>::from_slice(&bytes)))
+    }
+
+    fn from_env() -> anyhow::Result<Option<Self>> {
+        if let Some(raw) = env_value("PRX_CONFIG_KEY") {
+            return Self::from_base64(&raw)
+                .context("invalid PRX_CONFIG_KEY")
+                .map(Some);
+        }
+        let Some(path) = env_value("PRX_CONFIG_KEY_FILE") else {
+            return Ok(None);
+        };
+        let raw = fs::read_to_string(&path)
+            .with_context(|| format!("failed to read PRX_CONFIG_KEY_FILE at {path}"))?;
+        Self::from_base64(&raw)
+            .with_context(|| format!("invalid config key in {path}"))
+            .map(Some)
+    }
+
+    pub fn encrypt(&self, plaintext: &str) -> String {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = Aes256Gcm::new(&self.0)
+            .encrypt(&nonce, plaintext.as_bytes())
+            .expect("AES-GCM encryption of an in-memory buffer cannot fail");
+        let mut payload = nonce.to_vec();
+        payload.extend_from_slice(&ciphertext);
+        format!("{ENCRYPTED_PREFIX}{}", STANDARD.encode(payload))
+    }
+
+    pub fn decrypt(&self, value: &str) -> anyhow::Result<String> {
+        let encoded = value
+            .strip_prefix(ENCRYPTED_PREFIX)
+            .with_context(|| format!("value is not prefixed with {ENCRYPTED_PREFIX}"))?;
+        let payload = STANDARD
+            .decode(encoded)
+            .context("encrypted value is not valid base64")?;
+        if payload.len() <= NONCE_LEN {
+            bail!("encrypted value is too short");
+        }
+        let (nonce, ciphertext) = payload.split_at(NONCE_LEN);
+        let plaintext = Aes256Gcm::new(&self.0)
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| anyhow!("failed to decrypt value (wrong key or corrupted data)"))?;
+        String::from_utf8(plaintext).context("decrypted value is not valid UTF-8")
+    }
+}
+
+/// Returns `config` with every `enc:` string replaced by its plaintext, using
+/// the key from the environment.
+///
+/// Only the runtime snapshot sees plaintext: `PrxConfig` itself keeps the
+/// ciphertext, so the admin API and config rewrites never expose it.
+pub fn reveal(config: &PrxConfig) -> anyhow::Result<PrxConfig> {
+    let key = CONFIG_KEY.as_ref().map_err(|err| anyhow!("{err}"))?;
+    reveal_with(config, key.as_ref())
+}
+
+pub fn reveal_with(config: &PrxConfig, key: Option<&ConfigKey>) -> anyhow::Result<PrxConfig> {
+    let mut value = toml::Value::try_from(config).context("failed to inspect config values")?;
+    let mut found = false;
+    decrypt_strings(&mut value, "", key, &mut found)?;
+    if !found {
+        return Ok(config.clone());
+    }
+    value
+        .try_into()
+        .context("decrypted config no longer matches the schema")
+}
+
+fn decrypt_strings(
+    value: &mut toml::Value,
+    path: &str,
+    key: Option<&ConfigKey>,
+    found: &mut bool,
+) -> anyhow::Result<()> {
+    match value {
+        toml::Value::String(text) if text.starts_with(ENCRYPTED_PREFIX) => {
+            *found = true;
+            let key = key.with_context(|| {
+                format!(
+                    "{path} is encrypted but neither PRX_CONFIG_KEY nor PRX_CONFIG_KEY_FILE is set"
+                )
+            })?;
+            *text = key
+                .decrypt(text)
+                .with_context(|| format!("failed to decrypt {path}"))?;
+        }
+        toml::Value::Array(items) => {
+            for (idx, item) in items.iter_mut().enumerate() {
+                decrypt_strings(item, &format!("{path}[{idx}]"), key, found)?;
+            }
+        }
+        toml::Value::Table(table) => {
+            for (name, item) in table.iter_mut() {
+                let child = if path.is_empty() {
+                    name.clone()
+                } else {
+                    format!("{path}.{name}")
+                };
+                decrypt_strings(item, &child, key, found)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=";
+
+    fn config_with_addr(addr: &str) -> PrxConfig {
+        toml::from_str(&format!(
+            r#"
+[[service]]
+name = "app"
+
+[[service.upstream]]
+addr = "{addr}"
+
+[[route]]
+name = "default"
+service = "app"
+path_prefix = "/"
+"#
+        ))
+        .expect("config")
+    }
+
+    #[test]
+    fn reveal_decrypts_encrypted_strings() {
+        let key = ConfigKey::from_base64(KEY).expect("key");
+        let config = config_with_addr(&key.encrypt("10.0.0.5:443"));
+
+        let revealed = reveal_with(&config, Some(&key)).expect("reveal");
+        assert_eq!(revealed.services[0].upstreams[0].addr, "10.0.0.5:443");
+        assert!(
+            config.services[0].upstreams[0]
+                .addr
+                .starts_with(ENCRYPTED_PREFIX)
+        );
+    }
+
+    #[test]
+    fn reveal_requires_key_for_encrypted_values() {
+        let key = ConfigKey::from_base64(KEY).expect("key");
+        let config = config_with_addr(&key.encrypt("10.0.0.5:443"));
+
+        let err = reveal_with(&config, None).expect_err("missing key must fail");
+        assert!(format!("{err:#}").contains("service[0].upstream[0].addr"));
+    }
+
+    #[test]
+    fn decrypt_rejects_wrong_key() {
+        let key = ConfigKey::from_base64(KEY).expect("key");
+        let other = ConfigKey::from_base64(&STANDARD.encode([7u8; 32])).expect("key");
+        let value = key.encrypt("secret");
+        assert!(other.decrypt(&value).is_err());
+        assert_eq!(key.decrypt(&value).expect("decrypt"), "secret");
+    }
+}
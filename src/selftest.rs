@@ -0,0 +1,589 @@
+use std::{
+    collections::BTreeSet,
+    env, fmt, fs,
+    io::{BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    path::PathBuf,
+    process::{Child, Command, Stdio},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, bail};
+
+use crate::{
+    config::{PrxConfig, RouteConfig, RouteProtocol},
+    healthcheck::http_status,
+};
+
+/// How long the spawned proxy gets to answer on its health path.
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(15);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Response header a stub upstream names its service in.
+const STUB_SERVICE_HEADER: &str = "x-prx-selftest-service";
+
+/// `prx selftest [--config PATH] [--real-upstreams]`: boots a copy of the
+/// config on ephemeral loopback ports, sends one synthetic request through
+/// every route and reports which ones answered as expected.
+///
+/// By default every service is pointed at a stub upstream that names the
+/// service it stands in for, so a route only passes when the request reached
+/// one of its own services. `--real-upstreams` keeps the configured upstreams
+/// and passes any response below 500.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Selftest {
+    pub config: Option<PathBuf>,
+    pub real_upstreams: bool,
+}
+
+impl Selftest {
+    /// Returns `None` when the first argument is not `selftest`.
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> anyhow::Result<Option<Self>> {
+        let mut args = args.into_iter();
+        if args.next().as_deref() != Some("selftest") {
+            return Ok(None);
+        }
+
+        let mut selftest = Self::default();
+        while let Some(flag) = args.next() {
+            match flag.as_str() {
+                "--config" => {
+                    selftest.config = Some(args.next().context("--config requires a value")?.into())
+                }
+                "--real-upstreams" => selftest.real_upstreams = true,
+                _ => bail!("unknown selftest argument {flag}"),
+            }
+        }
+        Ok(Some(selftest))
+    }
+
+    /// Runs the routes of `config` through `prx_binary`, which is started
+    /// with a rewritten copy of the config and stopped afterwards.
+    pub fn run(&self, config: &PrxConfig, prx_binary: PathBuf) -> anyhow::Result<SelftestReport> {
+        let mut config = config.clone();
+        let stubs = if self.real_upstreams {
+            Vec::new()
+        } else {
+            stub_upstreams(&mut config)?
+        };
+        let listeners = isolate_listeners(&mut config)?;
+        config.validate().context("selftest config is invalid")?;
+
+        let dir = env::temp_dir();
+        let config_path = dir.join(format!("prx-selftest-{}.toml", std::process::id()));
+        let log_path = config_path.with_extension("log");
+        fs::write(
+            &config_path,
+            toml::to_string(&config).context("failed to serialize selftest config")?,
+        )
+        .with_context(|| format!("failed to write {}", config_path.to_string_lossy()))?;
+
+        let proxy = SpawnedProxy::start(prx_binary, &config_path, &log_path, &listeners);
+        let report = proxy.and_then(|mut proxy| {
+            proxy.wait_until_healthy(&listeners.main, &config.server.health_path)?;
+            Ok(self.probe_routes(&config, &listeners))
+        });
+        let _ = fs::remove_file(&config_path);
+        let _ = fs::remove_file(&log_path);
+        drop(stubs);
+        report
+    }
+
+    fn probe_routes(&self, config: &PrxConfig, listeners: &Listeners) -> SelftestReport {
+        let mut report = SelftestReport::default();
+        for route in &config.routes {
+            let request = SyntheticRequest::for_route(route);
+            let label = format!("{} {}{}", request.method, request.host, request.path);
+            if route.protocol == RouteProtocol::Grpc {
+                report.push(
+                    route,
+                    label,
+                    Outcome::Skip("grpc routes need an HTTP/2 client".to_string()),
+                );
+                continue;
+            }
+            let addr = route
+                .app
+                .as_ref()
+                .and_then(|app| listeners.apps.iter().find(|(name, _)| name == app))
+                .map_or(listeners.main, |(_, addr)| *addr);
+            let outcome = match request.send(addr) {
+                Ok(response) => self.judge(route, &response),
+                Err(err) => Outcome::Fail(format!("{err:#}")),
+            };
+            report.push(route, label, outcome);
+        }
+        report
+    }
+
+    fn judge(&self, route: &RouteConfig, response: &SyntheticResponse) -> Outcome {
+        let status = response.status;
+        if self.real_upstreams {
+            return if status < 500 {
+                Outcome::Pass(format!("{status}"))
+            } else {
+                Outcome::Fail(format!("{status}"))
+            };
+        }
+        let expected = std::iter::once(&route.service)
+            .chain(route.groups.iter().map(|group| &group.service))
+            .collect::<BTreeSet<_>>();
+        match &response.stub_service {
+            Some(service) if expected.contains(service) && (200..300).contains(&status) => {
+                Outcome::Pass(format!("{status} from service {service}"))
+            }
+            Some(service) if expected.contains(service) => {
+                Outcome::Fail(format!("{status} from service {service}"))
+            }
+            Some(service) => Outcome::Fail(format!(
+                "{status} from service {service}, expected {}",
+                expected
+                    .into_iter()
+                    .cloned()
+                    .collect::<Vec<_>>()
+                    .join(" or ")
+            )),
+            None => Outcome::Fail(format!("{status} answered by prx")),
+        }
+    }
+}
+
+/// Per-route result matrix of a [`Selftest`].
+#[derive(Debug, Default)]
+pub struct SelftestReport {
+    rows: Vec<Row>,
+}
+
+#[derive(Debug)]
+struct Row {
+    route: String,
+    request: String,
+    outcome: Outcome,
+}
+
+#[derive(Debug)]
+enum Outcome {
+    Pass(String),
+    Fail(String),
+    Skip(String),
+}
+
+impl SelftestReport {
+    pub fn is_ok(&self) -> bool {
+        !self
+            .rows
+            .iter()
+            .any(|row| matches!(row.outcome, Outcome::Fail(_)))
+    }
+
+    fn push(&mut self, route: &RouteConfig, request: String, outcome: Outcome) {
+        self.rows.push(Row {
+            route: route.name.clone(),
+            request,
+            outcome,
+        });
+    }
+}
+
+impl fmt::Display for SelftestReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let route_width = self
+            .rows
+            .iter()
+            .map(|row| row.route.len())
+            .max()
+            .unwrap_or(0);
+        let request_width = self
+            .rows
+            .iter()
+            .map(|row| row.request.len())
+            .max()
+            .unwrap_or(0);
+        let mut failed = 0;
+        let mut skipped = 0;
+        for row in &self.rows {
+            let (result, detail) = match &row.outcome {
+                Outcome::Pass(detail) => ("pass", detail),
+                Outcome::Fail(detail) => {
+                    failed += 1;
+                    ("FAIL", detail)
+                }
+                Outcome::Skip(detail) => {
+                    skipped += 1;
+                    ("skip", detail)
+                }
+            };
+            writeln!(
+                f,
+                "{result} {:route_width$}  {:request_width$}  {detail}",
+                row.route, row.request
+            )?;
+        }
+        let total = self.rows.len();
+        if failed == 0 {
+            write!(f, "selftest passed ({total} routes, {skipped} skipped)")
+        } else {
+            write!(f, "selftest failed ({failed} of {total} routes)")
+        }
+    }
+}
+
+/// Request that the route matches: its host (a wildcard gets a `selftest`
+/// label), path prefix, first listed method and required headers.
+#[derive(Debug, Clone, PartialEq)]
+struct SyntheticRequest {
+    method: String,
+    host: String,
+    path: String,
+    headers: Vec<(String, String)>,
+}
+
+#[derive(Debug)]
+struct SyntheticResponse {
+    status: u16,
+    stub_service: Option<String>,
+}
+
+impl SyntheticRequest {
+    fn for_route(route: &RouteConfig) -> Self {
+        let host = match route.host.as_deref() {
+            Some(host) => match host.strip_prefix("*.") {
+                Some(suffix) => format!("selftest.{suffix}"),
+                None => host.to_string(),
+            },
+            None => "localhost".to_string(),
+        };
+        Self {
+            method: route
+                .methods
+                .first()
+                .map_or_else(|| "GET".to_string(), |method| method.to_ascii_uppercase()),
+            host,
+            path: route.path_prefix.clone(),
+            headers: route
+                .headers
+                .iter()
+                .map(|(name, value)| (name.clone(), value.clone()))
+                .collect(),
+        }
+    }
+
+    fn send(&self, addr: SocketAddr) -> anyhow::Result<SyntheticResponse> {
+        let mut stream = TcpStream::connect_timeout(&addr, REQUEST_TIMEOUT)
+            .with_context(|| format!("failed to connect to {addr}"))?;
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+        let mut request = format!(
+            "{} {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: prx-selftest\r\nContent-Length: 0\r\nConnection: close\r\n",
+            self.method, self.path, self.host
+        );
+        for (name, value) in &self.headers {
+            request.push_str(&format!("{name}: {value}\r\n"));
+        }
+        request.push_str("\r\n");
+        stream
+            .write_all(request.as_bytes())
+            .context("failed to send request")?;
+
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        reader
+            .read_line(&mut line)
+            .context("failed to read response")?;
+        let status = line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|code| code.parse::<u16>().ok())
+            .with_context(|| format!("malformed response: {line:?}"))?;
+        let mut stub_service = None;
+        loop {
+            line.clear();
+            if reader
+                .read_line(&mut line)
+                .context("failed to read response")?
+                == 0
+            {
+                break;
+            }
+            let header = line.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':')
+                && name.eq_ignore_ascii_case(STUB_SERVICE_HEADER)
+            {
+                stub_service = Some(value.trim().to_string());
+            }
+        }
+        Ok(SyntheticResponse {
+            status,
+            stub_service,
+        })
+    }
+}
+
+/// Loopback addresses the selftest copy of the config listens on.
+#[derive(Debug)]
+struct Listeners {
+    main: SocketAddr,
+    apps: Vec<(String, SocketAddr)>,
+    admin: SocketAddr,
+}
+
+/// Moves every listener to a free loopback port and drops TLS listeners,
+/// PROXY protocol and the metrics listener, so the selftest runs next to a
+/// live prx. Tcp routes
+/// are not probed, only kept off their real ports.
+fn isolate_listeners(config: &mut PrxConfig) -> anyhow::Result<Listeners> {
+    let main = free_port()?;
+    config.server.listen = vec![main.to_string()];
+    config.server.tls = None;
+    config.server.proxy_protocol = None;
+    config.observability.prometheus_listen = None;
+    let mut apps = Vec::new();
+    for app in &mut config.apps {
+        let addr = free_port()?;
+        app.listen = vec![addr.to_string()];
+        app.tls = None;
+        app.proxy_protocol = None;
+        apps.push((app.name.clone(), addr));
+    }
+    for tcp_route in &mut config.tcp_routes {
+        tcp_route.listen = vec![free_port()?.to_string()];
+    }
+    Ok(Listeners {
+        main,
+        apps,
+        admin: free_port()?,
+    })
+}
+
+/// Points every service at its own stub and strips the upstream settings a
+/// plain HTTP/1.1 stub cannot honour.
+fn stub_upstreams(config: &mut PrxConfig) -> anyhow::Result<Vec<StubUpstream>> {
+    let mut stubs = Vec::new();
+    for service in &mut config.services {
+        let stub = StubUpstream::spawn(&service.name)?;
+        for upstream in &mut service.upstreams {
+            upstream.addr = stub.addr.to_string();
+            upstream.tls = false;
+            upstream.sni = None;
+            upstream.verify_hostname_as = None;
+            upstream.client_cert_path = None;
+            upstream.client_key_path = None;
+            upstream.pinned_cert_sha256.clear();
+            upstream.http2 = false;
+            upstream.http_version = None;
+            upstream.health_check = None;
+            upstream.egress = Default::default();
+            upstream.proxy_protocol = None;
+        }
+        stubs.push(stub);
+    }
+    for route in &mut config.routes {
+        route.outbound_proxy = None;
+        route.egress = Default::default();
+    }
+    Ok(stubs)
+}
+
+fn free_port() -> anyhow::Result<SocketAddr> {
+    TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .context("failed to reserve a loopback port")
+}
+
+/// Answers every request with `200` and the name of the service it stands in for.
+struct StubUpstream {
+    addr: SocketAddr,
+    shutdown: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl StubUpstream {
+    fn spawn(service: &str) -> anyhow::Result<Self> {
+        let listener =
+            TcpListener::bind("127.0.0.1:0").context("failed to bind a stub upstream")?;
+        let addr = listener.local_addr()?;
+        listener.set_nonblocking(true)?;
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let stop = shutdown.clone();
+        let service = service.to_string();
+        let handle = thread::spawn(move || {
+            while !stop.load(Ordering::Relaxed) {
+                match listener.accept() {
+                    Ok((stream, _)) => {
+                        let service = service.clone();
+                        thread::spawn(move || stub_answer(stream, &service));
+                    }
+                    Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {
+                        thread::sleep(Duration::from_millis(10));
+                    }
+                    Err(_) => break,
+                }
+            }
+        });
+        Ok(Self {
+            addr,
+            shutdown,
+            handle: Some(handle),
+        })
+    }
+}
+
+impl Drop for StubUpstream {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn stub_answer(mut stream: TcpStream, service: &str) {
+    let _ = stream.set_nonblocking(false);
+    let _ = stream.set_read_timeout(Some(REQUEST_TIMEOUT));
+    // The synthetic requests carry no body, so the head is all there is.
+    let mut head = Vec::new();
+    let mut buf = [0u8; 4096];
+    while !head.windows(4).any(|window| window == b"\r\n\r\n") {
+        match stream.read(&mut buf) {
+            Ok(0) | Err(_) => return,
+            Ok(read) => head.extend_from_slice(&buf[..read]),
+        }
+    }
+    let _ = write!(
+        stream,
+        "HTTP/1.1 200 OK\r\n{STUB_SERVICE_HEADER}: {service}\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok"
+    );
+}
+
+struct SpawnedProxy {
+    child: Child,
+    log_path: PathBuf,
+}
+
+impl SpawnedProxy {
+    fn start(
+        binary: PathBuf,
+        config_path: &std::path::Path,
+        log_path: &std::path::Path,
+        listeners: &Listeners,
+    ) -> anyhow::Result<Self> {
+        let log = fs::File::create(log_path)
+            .with_context(|| format!("failed to create {}", log_path.to_string_lossy()))?;
+        let child = Command::new(&binary)
+            .env("PRX_CONFIG", config_path)
+            .env("PRX_ADMIN_LISTEN", listeners.admin.to_string())
+            .env_remove("PRX_CONFIG_URL")
+            .env_remove("PRX_CHECK")
+            .stdout(Stdio::null())
+            .stderr(log)
+            .spawn()
+            .with_context(|| format!("failed to start {}", binary.to_string_lossy()))?;
+        Ok(Self {
+            child,
+            log_path: log_path.to_path_buf(),
+        })
+    }
+
+    fn wait_until_healthy(&mut self, addr: &SocketAddr, health_path: &str) -> anyhow::Result<()> {
+        let deadline = Instant::now() + STARTUP_TIMEOUT;
+        while Instant::now() < deadline {
+            if let Some(status) = self.child.try_wait()? {
+                let log = fs::read_to_string(&self.log_path).unwrap_or_default();
+                let last = log.lines().last().unwrap_or_default();
+                bail!("prx exited during startup ({status}): {last}");
+            }
+            if http_status(&addr.to_string(), health_path, REQUEST_TIMEOUT)
+                .is_ok_and(|status| (200..300).contains(&status))
+            {
+                return Ok(());
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        bail!("prx did not answer on {addr}{health_path} within {STARTUP_TIMEOUT:?}")
+    }
+}
+
+impl Drop for SpawnedProxy {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> PrxConfig {
+        PrxConfig::from_toml_str(
+            r#"
+[server]
+listen = ["0.0.0.0:8080"]
+
+[[service]]
+name = "api"
+
+[[service.upstream]]
+addr = "api.internal:443"
+tls = true
+sni = "api.internal"
+
+[[route]]
+name = "tenant-api"
+service = "api"
+host = "*.example.com"
+path_prefix = "/v1"
+methods = ["post"]
+headers = { x-tenant = "blue" }
+"#,
+        )
+        .expect("config")
+    }
+
+    #[test]
+    fn synthetic_request_matches_its_route() {
+        let config = config();
+        let request = SyntheticRequest::for_route(&config.routes[0]);
+        assert_eq!(
+            request,
+            SyntheticRequest {
+                method: "POST".to_string(),
+                host: "selftest.example.com".to_string(),
+                path: "/v1".to_string(),
+                headers: vec![("x-tenant".to_string(), "blue".to_string())],
+            }
+        );
+    }
+
+    #[test]
+    fn stubbed_config_stays_valid_on_loopback() {
+        let mut config = config();
+        let stubs = stub_upstreams(&mut config).expect("stubs");
+        let listeners = isolate_listeners(&mut config).expect("listeners");
+        config.validate().expect("valid selftest config");
+
+        let upstream = &config.services[0].upstreams[0];
+        assert_eq!(upstream.addr, stubs[0].addr.to_string());
+        assert!(!upstream.tls && upstream.sni.is_none());
+        assert_eq!(config.server.listen, vec![listeners.main.to_string()]);
+
+        assert_eq!(
+            Selftest::from_args(
+                ["selftest", "--config", "Prx.toml", "--real-upstreams"].map(String::from)
+            )
+            .expect("parse"),
+            Some(Selftest {
+                config: Some("Prx.toml".into()),
+                real_upstreams: true,
+            })
+        );
+    }
+}
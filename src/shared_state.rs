@@ -0,0 +1,506 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    os::{
+        fd::AsRawFd,
+        unix::net::{UnixListener, UnixStream as StdUnixStream},
+    },
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard, OnceLock},
+    thread,
+    time::Duration,
+};
+
+use anyhow::{Context, bail};
+use arc_swap::ArcSwap;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufStream},
+    net::UnixStream,
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+    ratelimit::RouteRateLimit,
+    runtime::{RuntimeConfig, now_epoch_ms},
+};
+
+/// How often open circuit breakers are exchanged with the other processes.
+const SYNC_INTERVAL: Duration = Duration::from_millis(250);
+/// Longest a rate limit check waits on the socket before deciding locally.
+const TAKE_TIMEOUT: Duration = Duration::from_millis(50);
+/// Longest a breaker exchange or a new connection waits on the socket.
+const SYNC_TIMEOUT: Duration = Duration::from_secs(1);
+/// Connections kept open for rate limit checks.
+const MAX_IDLE_CONNECTIONS: usize = 16;
+
+/// Rate limit buckets and open circuit breakers shared by the prx processes
+/// of one host over `server.shared_state_socket`.
+///
+/// The process holding the socket's lock file keeps the state and answers the
+/// others, one line per request. When it exits the kernel drops the lock and
+/// the next process to find the socket dead takes over; until then every
+/// process decides on its own.
+pub struct SharedState {
+    socket: PathBuf,
+    table: Arc<Table>,
+    // The lock file while this process serves the socket.
+    owner: OnceLock<File>,
+    idle: Mutex<Vec<BufStream<UnixStream>>>,
+}
+
+impl SharedState {
+    /// Serves the socket when no other process does yet.
+    pub fn start(socket: &Path) -> anyhow::Result<Arc<Self>> {
+        let shared = Arc::new(Self {
+            socket: socket.to_path_buf(),
+            table: Arc::new(Table::default()),
+            owner: OnceLock::new(),
+            idle: Mutex::new(Vec::new()),
+        });
+        shared.claim()?;
+        if shared.is_owner() {
+            info!(socket = %socket.display(), "serving shared state to other prx processes");
+        } else {
+            info!(socket = %socket.display(), "using shared state of another prx process");
+        }
+        Ok(shared)
+    }
+
+    pub fn is_owner(&self) -> bool {
+        self.owner.get().is_some()
+    }
+
+    /// Takes one request of `route` from the shared bucket of `key`, or from
+    /// `limit` itself while the socket cannot answer.
+    pub async fn check(
+        &self,
+        route: &str,
+        limit: &RouteRateLimit,
+        key: &str,
+    ) -> Result<(), Duration> {
+        let (rps, burst) = limit.rate();
+        if self.is_owner() {
+            return self.table.take(route, rps, burst, key);
+        }
+        if !route.contains(['\t', '\n']) && !key.contains('\n') {
+            let request = format!("take\t{rps}\t{burst}\t{route}\t{key}\n");
+            match tokio::time::timeout(TAKE_TIMEOUT, self.ask(&request)).await {
+                Ok(Ok(reply)) => {
+                    if let Some(taken) = parse_take(&reply) {
+                        return taken;
+                    }
+                    debug!(reply = %reply.trim_end(), "unexpected shared state reply");
+                }
+                Ok(Err(err)) => debug!(error = %err, "shared state socket unavailable"),
+                Err(_) => debug!("shared state socket timed out"),
+            }
+        }
+        limit.check(key)
+    }
+
+    async fn ask(&self, request: &str) -> io::Result<String> {
+        let pooled = self.idle().pop();
+        let mut stream = match pooled {
+            Some(stream) => stream,
+            None => BufStream::new(UnixStream::connect(&self.socket).await?),
+        };
+        stream.write_all(request.as_bytes()).await?;
+        stream.flush().await?;
+        let mut reply = String::new();
+        if stream.read_line(&mut reply).await? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let mut idle = self.idle();
+        if idle.len() < MAX_IDLE_CONNECTIONS {
+            idle.push(stream);
+        }
+        Ok(reply)
+    }
+
+    /// Binds the socket if this process can take the lock file.
+    fn claim(&self) -> anyhow::Result<()> {
+        let lock_path = lock_path(&self.socket);
+        let lock = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&lock_path)
+            .with_context(|| format!("failed to open {}", lock_path.display()))?;
+        // SAFETY: flock only reads the descriptor, which `lock` keeps open.
+        if unsafe { libc::flock(lock.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::WouldBlock {
+                return Ok(());
+            }
+            bail!("failed to lock {}: {err}", lock_path.display());
+        }
+        // Left behind by a previous owner; only the lock holder replaces it.
+        match fs::remove_file(&self.socket) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => bail!("failed to remove {}: {err}", self.socket.display()),
+        }
+        let listener = UnixListener::bind(&self.socket)
+            .with_context(|| format!("failed to bind {}", self.socket.display()))?;
+        let table = self.table.clone();
+        thread::Builder::new()
+            .name("prx-shared-state".to_string())
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    match stream {
+                        Ok(stream) => {
+                            let table = table.clone();
+                            let spawned = thread::Builder::new()
+                                .name("prx-shared-state-peer".to_string())
+                                .spawn(move || serve_peer(&table, stream));
+                            if let Err(err) = spawned {
+                                warn!(error = %err, "failed to serve shared state peer");
+                            }
+                        }
+                        Err(err) => warn!(error = %err, "failed to accept shared state peer"),
+                    }
+                }
+            })?;
+        let _ = self.owner.set(lock);
+        Ok(())
+    }
+
+    fn idle(&self) -> MutexGuard<'_, Vec<BufStream<UnixStream>>> {
+        self.idle
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Exchanges open circuit breakers of the active config with the other
+/// processes, taking over the socket once its owner is gone.
+pub fn spawn_breaker_sync(
+    shared: Arc<SharedState>,
+    runtime_config: Arc<ArcSwap<RuntimeConfig>>,
+) -> io::Result<()> {
+    thread::Builder::new()
+        .name("prx-shared-breakers".to_string())
+        .spawn(move || {
+            let mut peer = None;
+            loop {
+                thread::sleep(SYNC_INTERVAL);
+                let active = runtime_config.load_full();
+                let open = open_breakers(&active);
+                let merged = if shared.is_owner() {
+                    Some(shared.table.merge_breakers(open, now_epoch_ms()))
+                } else {
+                    match exchange_breakers(&shared.socket, &mut peer, &open) {
+                        Ok(merged) => Some(merged),
+                        Err(err) => {
+                            peer = None;
+                            debug!(error = %err, "shared state socket unavailable");
+                            match shared.claim() {
+                                Ok(()) if shared.is_owner() => {
+                                    info!(
+                                        socket = %shared.socket.display(),
+                                        "took over shared state from a stopped prx process"
+                                    );
+                                    Some(shared.table.merge_breakers(open, now_epoch_ms()))
+                                }
+                                Ok(()) => None,
+                                Err(err) => {
+                                    warn!(error = %err, "failed to take over shared state");
+                                    None
+                                }
+                            }
+                        }
+                    }
+                };
+                for breaker in merged.unwrap_or_default() {
+                    hold_open(&active, &breaker);
+                }
+            }
+        })
+        .map(|_| ())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct OpenBreaker {
+    service: String,
+    addr: String,
+    until_epoch_ms: u64,
+}
+
+fn open_breakers(config: &RuntimeConfig) -> Vec<OpenBreaker> {
+    let now = now_epoch_ms();
+    config
+        .services()
+        .iter()
+        .flat_map(|service| {
+            service.upstreams.iter().filter_map(move |upstream| {
+                let until_epoch_ms = upstream.circuit_open_until_ms();
+                (until_epoch_ms > now).then(|| OpenBreaker {
+                    service: service.name.clone(),
+                    addr: upstream.addr.to_string(),
+                    until_epoch_ms,
+                })
+            })
+        })
+        .collect()
+}
+
+fn hold_open(config: &RuntimeConfig, breaker: &OpenBreaker) {
+    let upstream = config
+        .services()
+        .iter()
+        .filter(|service| service.name == breaker.service)
+        .flat_map(|service| &service.upstreams)
+        .find(|upstream| *upstream.addr == *breaker.addr);
+    if let Some(upstream) = upstream {
+        upstream.hold_circuit_open(breaker.until_epoch_ms);
+    }
+}
+
+fn exchange_breakers(
+    socket: &Path,
+    peer: &mut Option<BufReader<StdUnixStream>>,
+    open: &[OpenBreaker],
+) -> io::Result<Vec<OpenBreaker>> {
+    let peer = match peer {
+        Some(peer) => peer,
+        None => {
+            let stream = StdUnixStream::connect(socket)?;
+            stream.set_read_timeout(Some(SYNC_TIMEOUT))?;
+            stream.set_write_timeout(Some(SYNC_TIMEOUT))?;
+            peer.insert(BufReader::new(stream))
+        }
+    };
+    peer.get_mut().write_all(format_breakers(open).as_bytes())?;
+    let mut reply = String::new();
+    if peer.read_line(&mut reply)? == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    parse_breakers(&reply).ok_or_else(|| io::ErrorKind::InvalidData.into())
+}
+
+fn serve_peer(table: &Table, stream: StdUnixStream) {
+    let mut writer = match stream.try_clone() {
+        Ok(writer) => writer,
+        Err(err) => {
+            warn!(error = %err, "failed to serve shared state peer");
+            return;
+        }
+    };
+    for line in BufReader::new(stream).lines() {
+        let Ok(line) = line else {
+            return;
+        };
+        if writer
+            .write_all(table.answer(&line, now_epoch_ms()).as_bytes())
+            .is_err()
+        {
+            return;
+        }
+    }
+}
+
+/// State kept by the process serving the socket.
+#[derive(Debug, Default)]
+struct Table {
+    // By route and the bits of its rps and burst, so processes still on the
+    // config before a reload keep their own buckets instead of resetting
+    // the new ones on every take.
+    limits: Mutex<HashMap<(String, u64, u64), RouteRateLimit>>,
+    // Open until epoch ms, by service and upstream addr.
+    breakers: Mutex<HashMap<(String, String), u64>>,
+}
+
+impl Table {
+    /// Answers one request line:
+    ///
+    /// - `take <rps> <burst> <route> <key>` gets `ok`, or `wait <micros>`.
+    /// - `breakers [<service> <addr> <until epoch ms>]...` reports the
+    ///   sender's open breakers and gets back everyone's, in the same form.
+    ///
+    /// Fields are tab-separated; the key is the rest of the line.
+    fn answer(&self, line: &str, now: u64) -> String {
+        if let Some(take) = line.strip_prefix("take\t") {
+            let mut fields = take.splitn(4, '\t');
+            let rps = fields.next().and_then(|rps| rps.parse::<f64>().ok());
+            let burst = fields.next().and_then(|burst| burst.parse::<f64>().ok());
+            if let (Some(rps), Some(burst), Some(route), Some(key)) =
+                (rps, burst, fields.next(), fields.next())
+                && rps > 0.0
+                && burst >= 1.0
+            {
+                return match self.take(route, rps, burst, key) {
+                    Ok(()) => "ok\n".to_string(),
+                    Err(wait) => format!("wait\t{}\n", wait.as_micros()),
+                };
+            }
+        } else if let Some(open) = parse_breakers(line) {
+            return format_breakers(&self.merge_breakers(open, now));
+        }
+        "error\n".to_string()
+    }
+
+    fn take(&self, route: &str, rps: f64, burst: f64, key: &str) -> Result<(), Duration> {
+        let mut limits = self
+            .limits
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        limits
+            .entry((route.to_string(), rps.to_bits(), burst.to_bits()))
+            .or_insert_with(|| RouteRateLimit::with_rate(rps, burst))
+            .check(key)
+    }
+
+    fn merge_breakers(&self, open: Vec<OpenBreaker>, now: u64) -> Vec<OpenBreaker> {
+        let mut breakers = self
+            .breakers
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        for breaker in open {
+            let until = breakers.entry((breaker.service, breaker.addr)).or_default();
+            *until = (*until).max(breaker.until_epoch_ms);
+        }
+        breakers.retain(|_, until| *until > now);
+        breakers
+            .iter()
+            .map(|((service, addr), until)| OpenBreaker {
+                service: service.clone(),
+                addr: addr.clone(),
+                until_epoch_ms: *until,
+            })
+            .collect()
+    }
+}
+
+fn lock_path(socket: &Path) -> PathBuf {
+    let mut path = socket.as_os_str().to_owned();
+    path.push(".lock");
+    PathBuf::from(path)
+}
+
+fn parse_take(reply: &str) -> Option<Result<(), Duration>> {
+    match reply.trim_end() {
+        "ok" => Some(Ok(())),
+        reply => reply
+            .strip_prefix("wait\t")
+            .and_then(|micros| micros.parse().ok())
+            .map(|micros| Err(Duration::from_micros(micros))),
+    }
+}
+
+fn format_breakers(breakers: &[OpenBreaker]) -> String {
+    let mut line = "breakers".to_string();
+    for breaker in breakers {
+        line.push_str(&format!(
+            "\t{}\t{}\t{}",
+            breaker.service, breaker.addr, breaker.until_epoch_ms
+        ));
+    }
+    line.push('\n');
+    line
+}
+
+fn parse_breakers(line: &str) -> Option<Vec<OpenBreaker>> {
+    let fields = line.trim_end().strip_prefix("breakers")?;
+    if fields.is_empty() {
+        return Some(Vec::new());
+    }
+    let fields: Vec<&str> = fields.strip_prefix('\t')?.split('\t').collect();
+    if !fields.len().is_multiple_of(3) {
+        return None;
+    }
+    fields
+        .chunks(3)
+        .map(|breaker| {
+            Some(OpenBreaker {
+                service: breaker[0].to_string(),
+                addr: breaker[1].to_string(),
+                until_epoch_ms: breaker[2].parse().ok()?,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn table_answers_takes_and_merges_breakers() {
+        let table = Table::default();
+        assert_eq!(table.answer("take\t10\t2\tapi\tclient a", 0), "ok\n");
+        assert_eq!(table.answer("take\t10\t2\tapi\tclient a", 0), "ok\n");
+        assert!(
+            table
+                .answer("take\t10\t2\tapi\tclient a", 0)
+                .starts_with("wait\t")
+        );
+        assert_eq!(table.answer("take\t10\t2\tapi\tclient b", 0), "ok\n");
+        // A changed limit has its own bucket.
+        assert_eq!(table.answer("take\t10\t3\tapi\tclient a", 0), "ok\n");
+        assert_eq!(table.answer("take\t0\t2\tapi\tclient a", 0), "error\n");
+        assert_eq!(table.answer("peek", 0), "error\n");
+
+        assert_eq!(
+            table.answer("breakers\tpayments\t10.0.0.1:80\t5000", 1000),
+            "breakers\tpayments\t10.0.0.1:80\t5000\n"
+        );
+        assert_eq!(
+            table.answer("breakers", 2000),
+            "breakers\tpayments\t10.0.0.1:80\t5000\n"
+        );
+        assert_eq!(table.answer("breakers", 5000), "breakers\n");
+        assert_eq!(
+            table.answer("breakers\tpayments\t10.0.0.1:80", 0),
+            "error\n"
+        );
+    }
+
+    #[test]
+    fn table_keeps_a_bucket_per_rate_of_a_route() {
+        // Two processes on either side of a reload that changed the rate.
+        let table = Table::default();
+        assert_eq!(table.answer("take\t1\t1\tapi\tclient", 0), "ok\n");
+        assert_eq!(table.answer("take\t1\t2\tapi\tclient", 0), "ok\n");
+        assert!(
+            table
+                .answer("take\t1\t1\tapi\tclient", 0)
+                .starts_with("wait\t")
+        );
+        assert_eq!(table.answer("take\t1\t2\tapi\tclient", 0), "ok\n");
+        assert!(
+            table
+                .answer("take\t1\t2\tapi\tclient", 0)
+                .starts_with("wait\t")
+        );
+        assert!(
+            table
+                .answer("take\t1\t1\tapi\tclient", 0)
+                .starts_with("wait\t")
+        );
+    }
+
+    #[test]
+    fn second_process_shares_the_first_ones_buckets() {
+        let dir = tempfile::tempdir().expect("tempdir");
+        let socket = dir.path().join("prx.sock");
+        let first = SharedState::start(&socket).expect("first");
+        assert!(first.is_owner());
+        let second = SharedState::start(&socket).expect("second");
+        assert!(!second.is_owner());
+
+        let limit = RouteRateLimit::with_rate(1.0, 2.0);
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("runtime");
+        runtime.block_on(async {
+            assert_eq!(first.check("api", &limit, "k").await, Ok(()));
+            assert_eq!(second.check("api", &limit, "k").await, Ok(()));
+            assert!(second.check("api", &limit, "k").await.is_err());
+            assert!(first.check("api", &limit, "k").await.is_err());
+        });
+        // The local buckets were never used.
+        assert_eq!(limit.check("k"), Ok(()));
+    }
+}
@@ -0,0 +1,487 @@
+use std::{fs, path::PathBuf, thread, time::Duration};
+
+use anyhow::{Context, bail};
+use pingora::{connectors::http::Connector, prelude::*};
+use tracing::{error, info, warn};
+
+use crate::{admin::ConfigAdmin, config::PrxConfig};
+
+pub const DEFAULT_POLL_INTERVAL_MS: u64 = 30_000;
+pub const DEFAULT_S3_ENDPOINT: &str = "https://s3.amazonaws.com";
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_REMOTE_CONFIG_BYTES: usize = 10 * 1024 * 1024;
+
+/// Remote location of `Prx.toml`, mirrored into a local cache file.
+///
+/// The cache file doubles as the regular config path, so the file watcher in
+/// `reload.rs` picks up every remote change without a second reload path.
+#[derive(Debug, Clone)]
+pub struct RemoteSource {
+    pub url: RemoteUrl,
+    pub cache_path: PathBuf,
+    pub poll_interval: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemoteUrl {
+    pub tls: bool,
+    pub host: String,
+    pub port: u16,
+    pub path: String,
+}
+
+impl RemoteUrl {
+    /// Parses `http://`, `https://` and `s3://bucket/key` locations.
+    ///
+    /// `s3://` URLs are mapped onto a path-style request against `s3_endpoint`,
+    /// which covers public objects and S3-compatible stores (MinIO, R2, ...).
+    pub fn parse(raw: &str, s3_endpoint: &str) -> anyhow::Result<Self> {
+        let raw = raw.trim();
+        if let Some(rest) = raw.strip_prefix("s3://") {
+            let (bucket, key) = rest
+                .split_once('/')
+                .filter(|(bucket, key)| !bucket.is_empty() && !key.is_empty())
+                .with_context(|| format!("s3 url must look like s3://bucket/key, got {raw}"))?;
+            let endpoint = Self::parse(s3_endpoint, DEFAULT_S3_ENDPOINT)
+                .with_context(|| format!("invalid s3 endpoint {s3_endpoint}"))?;
+            let base = endpoint.path.trim_end_matches('/');
+            return Ok(Self {
+                path: format!("{base}/{bucket}/{key}"),
+                ..endpoint
+            });
+        }
+
+        let (tls, rest) = if let Some(rest) = raw.strip_prefix("https://") {
+            (true, rest)
+        } else if let Some(rest) = raw.strip_prefix("http://") {
+            (false, rest)
+        } else {
+            bail!("unsupported config url scheme in {raw} (expected http, https or s3)");
+        };
+        // Pingora's stub TLS backend fails every handshake, so an https
+        // location would only ever time out.
+        #[cfg(not(feature = "openssl"))]
+        if tls {
+            bail!("{raw} uses https, which needs prx built with the openssl feature");
+        }
+
+        let (authority, path) = match rest.find('/') {
+            Some(idx) => (&rest[..idx], &rest[idx..]),
+            None => (rest, "/"),
+        };
+        if authority.is_empty() {
+            bail!("config url {raw} is missing a host");
+        }
+
+        let default_port = if tls { 443 } else { 80 };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) if !host.contains(':') || host.ends_with(']') => {
+                let port = port
+                    .parse::<u16>()
+                    .with_context(|| format!("invalid port in config url {raw}"))?;
+                (host, port)
+            }
+            _ => (authority, default_port),
+        };
+
+        Ok(Self {
+            tls,
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+
+    pub(crate) fn host_header(&self) -> String {
+        let default_port = if self.tls { 443 } else { 80 };
+        if self.port == default_port {
+            self.host.clone()
+        } else {
+            format!("{}:{}", self.host, self.port)
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum FetchOutcome {
+    NotModified,
+    Updated { body: String, etag: Option<String> },
+}
+
+impl RemoteSource {
+    pub fn new(
+        url: &str,
+        s3_endpoint: &str,
+        cache_path: PathBuf,
+        poll_interval: Duration,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            url: RemoteUrl::parse(url, s3_endpoint)?,
+            cache_path,
+            poll_interval,
+        })
+    }
+
+    fn etag_path(&self) -> PathBuf {
+        let mut name = self
+            .cache_path
+            .file_name()
+            .map(|name| name.to_os_string())
+            .unwrap_or_else(|| "Prx.toml".into());
+        name.push(".etag");
+        self.cache_path.with_file_name(name)
+    }
+
+    fn read_cached_etag(&self) -> Option<String> {
+        fs::read_to_string(self.etag_path())
+            .ok()
+            .map(|etag| etag.trim().to_string())
+            .filter(|etag| !etag.is_empty())
+    }
+
+    /// Fetches the remote config once, honoring `If-None-Match` when an ETag is known.
+    pub fn fetch(&self, etag: Option<&str>) -> anyhow::Result<FetchOutcome> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .context("failed to build runtime for remote config fetch")?;
+        runtime.block_on(fetch_remote(&self.url, etag))
+    }
+
+    /// Refreshes the local cache from the remote source.
+    ///
+    /// Returns `true` when the cache file was rewritten. Invalid remote content
+    /// is rejected before it reaches the cache, so a bad push can never replace
+    /// the last known good config.
+    pub fn refresh_cache(&self) -> anyhow::Result<bool> {
+        let etag = self
+            .cache_path
+            .exists()
+            .then(|| self.read_cached_etag())
+            .flatten();
+        let (body, etag) = match self.fetch(etag.as_deref())? {
+            FetchOutcome::NotModified => return Ok(false),
+            FetchOutcome::Updated { body, etag } => (body, etag),
+        };
+
+        PrxConfig::from_toml_str(&body).context("remote config failed validation")?;
+
+        let unchanged = fs::read_to_string(&self.cache_path).is_ok_and(|cached| cached == body);
+        if !unchanged {
+            ConfigAdmin::atomic_replace(&self.cache_path, body.as_bytes()).with_context(|| {
+                format!(
+                    "failed to write config cache at {}",
+                    self.cache_path.to_string_lossy()
+                )
+            })?;
+        }
+        match etag {
+            Some(etag) => {
+                let _ = fs::write(self.etag_path(), etag);
+            }
+            None => {
+                let _ = fs::remove_file(self.etag_path());
+            }
+        }
+        Ok(!unchanged)
+    }
+
+    /// Populates the cache before boot, falling back to the existing cache file
+    /// when the remote store is unreachable.
+    ///
+    /// Runs before tracing is initialized, so the outcome is returned for the
+    /// caller to log instead of being logged here.
+    pub fn bootstrap(&self) -> anyhow::Result<BootstrapOutcome> {
+        match self.refresh_cache() {
+            Ok(_) => Ok(BootstrapOutcome::Remote),
+            Err(err) if self.cache_path.exists() => Ok(BootstrapOutcome::Cached(err)),
+            Err(err) => Err(err).with_context(|| {
+                format!(
+                    "remote config fetch failed and no cache exists at {}",
+                    self.cache_path.to_string_lossy()
+                )
+            }),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum BootstrapOutcome {
+    Remote,
+    Cached(anyhow::Error),
+}
+
+impl BootstrapOutcome {
+    pub fn log(&self, source: &RemoteSource) {
+        match self {
+            Self::Remote => info!(
+                host = %source.url.host,
+                path = %source.url.path,
+                cache = %source.cache_path.to_string_lossy(),
+                "loaded config from remote source"
+            ),
+            Self::Cached(err) => warn!(
+                error = %format!("{err:#}"),
+                cache = %source.cache_path.to_string_lossy(),
+                "remote config fetch failed, using cached copy"
+            ),
+        }
+    }
+}
+
+async fn fetch_remote(url: &RemoteUrl, etag: Option<&str>) -> anyhow::Result<FetchOutcome> {
+    let addr = tokio::net::lookup_host((url.host.as_str(), url.port))
+        .await
+        .with_context(|| format!("failed to resolve {}", url.host))?
+        .next()
+        .with_context(|| format!("{} resolved to no addresses", url.host))?;
+
+    let connector = Connector::new(None);
+    let mut peer = HttpPeer::new(addr, url.tls, url.host.clone());
+    peer.options.connection_timeout = Some(FETCH_TIMEOUT);
+    peer.options.read_timeout = Some(FETCH_TIMEOUT);
+    peer.options.write_timeout = Some(FETCH_TIMEOUT);
+
+    let (mut session, _reused) = connector
+        .get_http_session(&peer)
+        .await
+        .map_err(|err| anyhow::anyhow!("failed to connect to {}: {err}", url.host))?;
+
+    let mut request = RequestHeader::build("GET", url.path.as_bytes(), None)
+        .map_err(|err| anyhow::anyhow!("failed to build request: {err}"))?;
+    request
+        .insert_header("host", url.host_header())
+        .map_err(|err| anyhow::anyhow!("failed to set host header: {err}"))?;
+    if let Some(etag) = etag {
+        request
+            .insert_header("if-none-match", etag)
+            .map_err(|err| anyhow::anyhow!("failed to set if-none-match header: {err}"))?;
+    }
+
+    session
+        .write_request_header(Box::new(request))
+        .await
+        .map_err(|err| anyhow::anyhow!("failed to send request: {err}"))?;
+    session
+        .finish_request_body()
+        .await
+        .map_err(|err| anyhow::anyhow!("failed to finish request: {err}"))?;
+    session
+        .read_response_header()
+        .await
+        .map_err(|err| anyhow::anyhow!("failed to read response header: {err}"))?;
+
+    let (status, etag) = match session.response_header() {
+        Some(header) => (
+            header.status.as_u16(),
+            header
+                .headers
+                .get("etag")
+                .and_then(|value| value.to_str().ok())
+                .map(ToString::to_string),
+        ),
+        None => bail!("remote config server sent no response header"),
+    };
+
+    if status == 304 {
+        return Ok(FetchOutcome::NotModified);
+    }
+    if status != 200 {
+        bail!("remote config server responded with status {status}");
+    }
+
+    let mut body = Vec::new();
+    while let Some(chunk) = session
+        .read_response_body()
+        .await
+        .map_err(|err| anyhow::anyhow!("failed to read response body: {err}"))?
+    {
+        body.extend_from_slice(&chunk);
+        if body.len() > MAX_REMOTE_CONFIG_BYTES {
+            bail!("remote config exceeds {MAX_REMOTE_CONFIG_BYTES} bytes");
+        }
+    }
+
+    let body = String::from_utf8(body).context("remote config is not valid utf-8")?;
+    Ok(FetchOutcome::Updated { body, etag })
+}
+
+pub fn spawn_remote_poller(source: RemoteSource) -> anyhow::Result<()> {
+    thread::Builder::new()
+        .name("prx-config-poller".to_string())
+        .spawn(move || {
+            info!(
+                host = %source.url.host,
+                path = %source.url.path,
+                interval_ms = source.poll_interval.as_millis(),
+                "remote config polling is active"
+            );
+            loop {
+                thread::sleep(source.poll_interval);
+                match source.refresh_cache() {
+                    Ok(true) => info!(
+                        cache = %source.cache_path.to_string_lossy(),
+                        "remote config changed, cache updated"
+                    ),
+                    Ok(false) => {}
+                    Err(err) => error!(
+                        error = %format!("{err:#}"),
+                        "failed to refresh remote config, keeping previous version"
+                    ),
+                }
+            }
+        })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+    };
+
+    const VALID_CONFIG: &str = r#"[[service]]
+name = "default"
+
+[[service.upstream]]
+addr = "127.0.0.1:9000"
+
+[[route]]
+name = "default"
+service = "default"
+path_prefix = "/"
+is_default = true
+"#;
+
+    fn serve_once(response: String) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind test server");
+        let port = listener.local_addr().expect("local addr").port();
+        thread::spawn(move || {
+            if let Ok((mut stream, _)) = listener.accept() {
+                let mut buf = [0u8; 4096];
+                let _ = stream.read(&mut buf);
+                let _ = stream.write_all(response.as_bytes());
+            }
+        });
+        port
+    }
+
+    #[test]
+    fn parse_http_url_uses_default_port() {
+        let url = RemoteUrl::parse("http://config.local/prx/Prx.toml", DEFAULT_S3_ENDPOINT)
+            .expect("url should parse");
+        assert_eq!(
+            url,
+            RemoteUrl {
+                tls: false,
+                host: "config.local".to_string(),
+                port: 80,
+                path: "/prx/Prx.toml".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn parse_s3_url_maps_to_path_style_endpoint() {
+        let url = RemoteUrl::parse("s3://fleet/edge/Prx.toml", "http://minio.local:9000")
+            .expect("url should parse");
+        assert!(!url.tls);
+        assert_eq!(url.host, "minio.local");
+        assert_eq!(url.port, 9000);
+        assert_eq!(url.path, "/fleet/edge/Prx.toml");
+    }
+
+    #[test]
+    fn parse_rejects_unknown_scheme() {
+        let err = RemoteUrl::parse("ftp://config.local/Prx.toml", DEFAULT_S3_ENDPOINT)
+            .expect_err("ftp should be rejected");
+        assert!(err.to_string().contains("unsupported config url scheme"));
+    }
+
+    #[test]
+    fn parse_https_url_needs_the_openssl_feature() {
+        let parsed = RemoteUrl::parse("https://config.local/Prx.toml", DEFAULT_S3_ENDPOINT);
+        // The default endpoint is https too.
+        let s3 = RemoteUrl::parse("s3://fleet/Prx.toml", DEFAULT_S3_ENDPOINT);
+        #[cfg(feature = "openssl")]
+        {
+            let url = parsed.expect("https url");
+            assert!(url.tls);
+            assert_eq!(url.port, 443);
+            assert!(s3.expect("s3 url").tls);
+        }
+        #[cfg(not(feature = "openssl"))]
+        for result in [parsed, s3] {
+            let err = result.expect_err("no TLS backend");
+            assert!(
+                format!("{err:#}").contains("needs prx built with the openssl feature"),
+                "{err:#}"
+            );
+        }
+    }
+
+    #[test]
+    fn refresh_cache_writes_body_and_etag() {
+        let port = serve_once(format!(
+            "HTTP/1.1 200 OK\r\netag: \"v1\"\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+            VALID_CONFIG.len(),
+            VALID_CONFIG
+        ));
+        let dir = tempfile::tempdir().expect("tempdir");
+        let cache = dir.path().join("Prx.toml");
+        let source = RemoteSource::new(
+            &format!("http://127.0.0.1:{port}/Prx.toml"),
+            DEFAULT_S3_ENDPOINT,
+            cache.clone(),
+            Duration::from_secs(1),
+        )
+        .expect("source");
+
+        assert!(source.refresh_cache().expect("refresh should succeed"));
+        assert_eq!(fs::read_to_string(&cache).expect("cache"), VALID_CONFIG);
+        assert_eq!(source.read_cached_etag().as_deref(), Some("\"v1\""));
+    }
+
+    #[test]
+    fn refresh_cache_keeps_previous_copy_on_invalid_remote() {
+        let body = "not = [valid";
+        let port = serve_once(format!(
+            "HTTP/1.1 200 OK\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+            body.len()
+        ));
+        let dir = tempfile::tempdir().expect("tempdir");
+        let cache = dir.path().join("Prx.toml");
+        fs::write(&cache, VALID_CONFIG).expect("seed cache");
+        let source = RemoteSource::new(
+            &format!("http://127.0.0.1:{port}/Prx.toml"),
+            DEFAULT_S3_ENDPOINT,
+            cache.clone(),
+            Duration::from_secs(1),
+        )
+        .expect("source");
+
+        source
+            .refresh_cache()
+            .expect_err("invalid remote config should fail");
+        assert_eq!(fs::read_to_string(&cache).expect("cache"), VALID_CONFIG);
+        let outcome = source.bootstrap().expect("bootstrap falls back to cache");
+        assert!(matches!(outcome, BootstrapOutcome::Cached(_)));
+    }
+
+    #[test]
+    fn fetch_reports_not_modified() {
+        let port = serve_once("HTTP/1.1 304 Not Modified\r\nconnection: close\r\n\r\n".to_string());
+        let source = RemoteSource::new(
+            &format!("http://127.0.0.1:{port}/Prx.toml"),
+            DEFAULT_S3_ENDPOINT,
+            PathBuf::from("Prx.toml"),
+            Duration::from_secs(1),
+        )
+        .expect("source");
+
+        let outcome = source.fetch(Some("\"v1\"")).expect("fetch should succeed");
+        assert_eq!(outcome, FetchOutcome::NotModified);
+    }
+}
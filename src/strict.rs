@@ -0,0 +1,142 @@
+use http::HeaderName;
+use pingora::http::RequestHeader;
+
+use crate::config::StrictHttpConfig;
+
+/// Why a request was rejected by [`StrictHttp`]; also the `reason` metric label.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Violation {
+    ContentLengthWithTransferEncoding,
+    ObsFold,
+    InvalidChars,
+    DuplicateHeader,
+}
+
+impl Violation {
+    pub fn reason(self) -> &'static str {
+        match self {
+            Self::ContentLengthWithTransferEncoding => "content_length_with_transfer_encoding",
+            Self::ObsFold => "obs_fold",
+            Self::InvalidChars => "invalid_chars",
+            Self::DuplicateHeader => "duplicate_header",
+        }
+    }
+}
+
+/// Request checks that close smuggling vectors between prx and backends that
+/// parse HTTP/1 more leniently than pingora does.
+#[derive(Debug, Clone)]
+pub struct StrictHttp {
+    reject_content_length_with_transfer_encoding: bool,
+    reject_obs_fold: bool,
+    reject_invalid_chars: bool,
+    unique_headers: Vec<HeaderName>,
+}
+
+impl StrictHttp {
+    pub fn from_config(config: &StrictHttpConfig) -> Self {
+        Self {
+            reject_content_length_with_transfer_encoding: config
+                .reject_content_length_with_transfer_encoding,
+            reject_obs_fold: config.reject_obs_fold,
+            reject_invalid_chars: config.reject_invalid_chars,
+            // Names are checked by PrxConfig::validate.
+            unique_headers: config
+                .unique_headers
+                .iter()
+                .filter_map(|name| HeaderName::from_bytes(name.as_bytes()).ok())
+                .collect(),
+        }
+    }
+
+    /// `content_length_with_transfer_encoding` comes from the session: pingora
+    /// drops Content-Length from the parsed header when both were sent.
+    pub fn check(
+        &self,
+        header: &RequestHeader,
+        content_length_with_transfer_encoding: bool,
+    ) -> Option<Violation> {
+        if self.reject_content_length_with_transfer_encoding
+            && content_length_with_transfer_encoding
+        {
+            return Some(Violation::ContentLengthWithTransferEncoding);
+        }
+        for value in header.headers.values() {
+            let bytes = value.as_bytes();
+            if self.reject_obs_fold && bytes.iter().any(|byte| matches!(byte, b'\r' | b'\n')) {
+                return Some(Violation::ObsFold);
+            }
+            if self.reject_invalid_chars && !bytes.iter().all(|byte| is_field_char(*byte)) {
+                return Some(Violation::InvalidChars);
+            }
+        }
+        if self.reject_invalid_chars
+            && !header.raw_path().iter().all(|byte| byte.is_ascii_graphic())
+        {
+            return Some(Violation::InvalidChars);
+        }
+        self.unique_headers
+            .iter()
+            .any(|name| header.headers.get_all(name).iter().nth(1).is_some())
+            .then_some(Violation::DuplicateHeader)
+    }
+}
+
+/// Visible ASCII, space and tab: RFC 9110 field content without obs-text.
+fn is_field_char(byte: u8) -> bool {
+    byte == b'\t' || (b' '..=b'~').contains(&byte)
+}
+
+#[cfg(test)]
+mod tests {
+    use http::HeaderValue;
+
+    use super::*;
+
+    fn request(headers: &[(&str, &[u8])]) -> RequestHeader {
+        let mut header = RequestHeader::build("POST", b"/upload", None).expect("request");
+        for (name, value) in headers {
+            let value = HeaderValue::from_bytes(value).expect("value");
+            header
+                .append_header(name.to_string(), value)
+                .expect("header");
+        }
+        header
+    }
+
+    #[test]
+    fn rejects_each_smuggling_vector() {
+        let strict = StrictHttp::from_config(&StrictHttpConfig::default());
+        let clean = request(&[("host", b"a.example"), ("x-note", b"tab\tok")]);
+        assert_eq!(strict.check(&clean, false), None);
+        assert_eq!(
+            strict.check(&clean, true),
+            Some(Violation::ContentLengthWithTransferEncoding)
+        );
+        assert_eq!(
+            strict.check(&request(&[("x-note", b"caf\xc3\xa9")]), false),
+            Some(Violation::InvalidChars)
+        );
+        assert_eq!(
+            strict.check(&request(&[("host", b"a"), ("host", b"b")]), false),
+            Some(Violation::DuplicateHeader)
+        );
+        // Repeated headers outside the list are fine.
+        assert_eq!(
+            strict.check(&request(&[("accept", b"a"), ("accept", b"b")]), false),
+            None
+        );
+    }
+
+    #[test]
+    fn checks_can_be_turned_off() {
+        let lenient = StrictHttp::from_config(&StrictHttpConfig {
+            reject_content_length_with_transfer_encoding: false,
+            reject_obs_fold: false,
+            reject_invalid_chars: false,
+            unique_headers: Vec::new(),
+        });
+        let messy = request(&[("x-note", b"caf\xc3\xa9"), ("host", b"a"), ("host", b"b")]);
+        assert_eq!(lenient.check(&messy, true), None);
+    }
+}
@@ -0,0 +1,234 @@
+use std::{io, net::SocketAddr, sync::Arc, time::Duration};
+
+use arc_swap::ArcSwap;
+use async_trait::async_trait;
+use pingora::{
+    apps::ServerApp, connectors::TransportConnector, prelude::*, protocols::Stream,
+    server::ShutdownWatch, upstreams::peer::BasicPeer,
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tracing::{debug, warn};
+
+use crate::{
+    metrics,
+    proxy::apply_egress_options,
+    proxy_protocol::{self, ProxiedAddrs, write_header},
+    runtime::{
+        RuntimeConfig, ServiceRuntime, TcpRouteRuntime, UpstreamFailure, UpstreamRuntime, hash_key,
+    },
+};
+
+const RELAY_BUFFER_BYTES: usize = 16 * 1024;
+
+/// Serves one `[[tcp_route]]`: every accepted connection is relayed to an
+/// upstream of the route's service in the snapshot active at accept time.
+pub struct TcpProxy {
+    route: Arc<str>,
+    active_config: Arc<ArcSwap<RuntimeConfig>>,
+    connector: TransportConnector,
+}
+
+impl TcpProxy {
+    pub fn new(route: &str, active_config: Arc<ArcSwap<RuntimeConfig>>) -> Self {
+        Self {
+            route: Arc::from(route),
+            active_config,
+            connector: TransportConnector::new(None),
+        }
+    }
+
+    /// Connects to the first upstream that accepts, trying up to
+    /// `max_retries` others after a failed connect.
+    async fn connect(
+        &self,
+        route: &TcpRouteRuntime,
+        service: &ServiceRuntime,
+        hash_seed: u64,
+        client_addrs: ProxiedAddrs,
+    ) -> Option<Stream> {
+        let mut attempted = Vec::new();
+        while attempted.len() <= service.max_retries {
+            if !attempted.is_empty() && !service.retry.retries(UpstreamFailure::Connect, None) {
+                break;
+            }
+            let Some((upstream_idx, upstream)) = service.next_upstream(hash_seed, &attempted)
+            else {
+                break;
+            };
+            attempted.push(upstream_idx);
+            if attempted.len() > 1
+                && let Some(backoff) = service.retry.backoff(attempted.len() - 1)
+            {
+                tokio::time::sleep(backoff).await;
+            }
+            match self.connect_upstream(upstream, client_addrs).await {
+                Ok(stream) => {
+                    service.mark_upstream_success(upstream_idx);
+                    metrics::set_circuit_state(&route.name, &upstream.metric_label, false);
+                    return Some(stream);
+                }
+                Err(err) => {
+                    warn!(
+                        route = &*route.name,
+                        upstream = &*upstream.addr,
+                        error = %err,
+                        "tcp route failed to connect to upstream"
+                    );
+                    record_connect_failure(route, service, upstream_idx, upstream);
+                }
+            }
+        }
+        None
+    }
+
+    async fn connect_upstream(
+        &self,
+        upstream: &UpstreamRuntime,
+        client_addrs: ProxiedAddrs,
+    ) -> Result<Stream> {
+        let resolved = match upstream.pick_addr(None) {
+            Some(addr) => Some(addr),
+            None => tokio::net::lookup_host(&*upstream.addr)
+                .await
+                .ok()
+                .and_then(|mut addrs| addrs.next()),
+        };
+        let Some(addr) = resolved else {
+            return Error::e_explain(ConnectError, format!("failed to resolve {}", upstream.addr));
+        };
+        let mut peer = BasicPeer::new(&addr.to_string());
+        peer.options.connection_timeout = upstream.connect_timeout_ms.map(Duration::from_millis);
+        peer.options.total_connection_timeout =
+            upstream.total_connect_timeout_ms.map(Duration::from_millis);
+        apply_egress_options(&mut peer.options, upstream.egress.clone());
+        let mut stream = self.connector.new_stream(&peer).await?;
+        if let Some(version) = upstream.proxy_protocol {
+            write_header(&mut stream, &proxy_protocol::encode(version, client_addrs)).await?;
+        }
+        Ok(stream)
+    }
+}
+
+#[async_trait]
+impl ServerApp for TcpProxy {
+    async fn process_new(
+        self: &Arc<Self>,
+        mut client: Stream,
+        _shutdown: &ShutdownWatch,
+    ) -> Option<Stream> {
+        let snapshot = self.active_config.load_full();
+        let route = snapshot.tcp_route(&self.route)?;
+        let service = snapshot.service(route.service_idx)?;
+        let digest = client.get_socket_digest();
+        let inet = |addr: Option<&pingora::protocols::l4::socket::SocketAddr>| {
+            addr.and_then(|addr| addr.as_inet().copied())
+        };
+        let client_addr = digest.as_ref().and_then(|digest| inet(digest.peer_addr()));
+        let local_addr = digest.as_ref().and_then(|digest| inet(digest.local_addr()));
+        let client_ip = client_addr
+            .map(|addr: SocketAddr| addr.ip().to_string())
+            .unwrap_or_default();
+
+        let mut upstream = self
+            .connect(
+                route,
+                service,
+                hash_key(&[client_ip.as_str()]),
+                client_addr.zip(local_addr),
+            )
+            .await?;
+        metrics::open_tcp_connection(&route.name);
+        let mut relayed = Relayed::default();
+        if let Err(err) = relay(&mut client, &mut upstream, route.idle_timeout, &mut relayed).await
+        {
+            debug!(route = &*route.name, error = %err, "tcp relay ended with an error");
+        }
+        metrics::close_tcp_connection(&route.name, relayed.to_upstream, relayed.to_client);
+        None
+    }
+}
+
+fn record_connect_failure(
+    route: &TcpRouteRuntime,
+    service: &ServiceRuntime,
+    upstream_idx: usize,
+    upstream: &UpstreamRuntime,
+) {
+    let failure = UpstreamFailure::Connect;
+    metrics::inc_upstream_error(&route.name, &upstream.metric_label, failure.stage());
+    if !service.circuit_breaker.counts(failure) {
+        return;
+    }
+    let opened = service.mark_upstream_failure(upstream_idx);
+    metrics::set_circuit_state(
+        &route.name,
+        &upstream.metric_label,
+        upstream.is_circuit_open(),
+    );
+    if opened {
+        metrics::mark_circuit_open(&route.name, &upstream.metric_label);
+        warn!(
+            route = &*route.name,
+            service = service.name.as_str(),
+            upstream = &*upstream.addr,
+            "opened circuit breaker for upstream"
+        );
+    }
+}
+
+#[derive(Debug, Default)]
+struct Relayed {
+    to_upstream: u64,
+    to_client: u64,
+}
+
+/// Copies bytes both ways until both sides have closed, either side fails,
+/// or `idle` passes without data in either direction. A side that closes
+/// has its close passed on, and the other direction keeps flowing.
+async fn relay(
+    client: &mut Stream,
+    upstream: &mut Stream,
+    idle: Option<Duration>,
+    relayed: &mut Relayed,
+) -> io::Result<()> {
+    let mut from_client = vec![0u8; RELAY_BUFFER_BYTES];
+    let mut from_upstream = vec![0u8; RELAY_BUFFER_BYTES];
+    let mut client_open = true;
+    let mut upstream_open = true;
+    while client_open || upstream_open {
+        let idle_timer = async {
+            match idle {
+                Some(idle) => tokio::time::sleep(idle).await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            read = client.read(&mut from_client), if client_open => match read? {
+                0 => {
+                    client_open = false;
+                    upstream.shutdown().await?;
+                }
+                read => {
+                    upstream.write_all(&from_client[..read]).await?;
+                    upstream.flush().await?;
+                    relayed.to_upstream += read as u64;
+                }
+            },
+            read = upstream.read(&mut from_upstream), if upstream_open => match read? {
+                0 => {
+                    upstream_open = false;
+                    client.shutdown().await?;
+                }
+                read => {
+                    client.write_all(&from_upstream[..read]).await?;
+                    client.flush().await?;
+                    relayed.to_client += read as u64;
+                }
+            },
+            _ = idle_timer => {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "tcp route idle timeout"));
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,190 @@
+use std::{
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use crate::config::BandwidthConfig;
+
+/// Token bucket that always admits the bytes and reports how long the caller
+/// has to wait to stay within the rate.
+///
+/// Admitting first keeps body chunks intact: pingora only lets a body filter
+/// delay a chunk, not split it, so the debt is paid back by waiting.
+#[derive(Debug)]
+pub struct TokenBucket {
+    bytes_per_sec: f64,
+    burst: f64,
+    state: Mutex<BucketState>,
+}
+
+#[derive(Debug)]
+struct BucketState {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(bytes_per_sec: u64, burst_bytes: u64) -> Self {
+        Self {
+            bytes_per_sec: bytes_per_sec as f64,
+            burst: burst_bytes as f64,
+            state: Mutex::new(BucketState {
+                tokens: burst_bytes as f64,
+                refilled_at: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn take(&self, bytes: usize) -> Duration {
+        self.take_at(bytes, Instant::now())
+    }
+
+    /// Bytes that can pass right now; negative while the bucket is in debt.
+    pub fn available(&self) -> i64 {
+        let mut state = self.state();
+        self.refill(&mut state, Instant::now());
+        state.tokens as i64
+    }
+
+    pub fn reset(&self) {
+        let mut state = self.state();
+        state.tokens = self.burst;
+        state.refilled_at = Instant::now();
+    }
+
+    fn take_at(&self, bytes: usize, now: Instant) -> Duration {
+        let mut state = self.state();
+        self.refill(&mut state, now);
+        state.tokens -= bytes as f64;
+        if state.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-state.tokens / self.bytes_per_sec)
+        }
+    }
+
+    fn refill(&self, state: &mut BucketState, now: Instant) {
+        let elapsed = now.saturating_duration_since(state.refilled_at);
+        state.tokens = (state.tokens + elapsed.as_secs_f64() * self.bytes_per_sec).min(self.burst);
+        state.refilled_at = now;
+    }
+
+    fn state(&self) -> MutexGuard<'_, BucketState> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Route-wide buckets, shared by every request on the route and kept across
+/// reloads that leave the route unchanged.
+#[derive(Debug)]
+pub struct RouteBandwidth {
+    config: BandwidthConfig,
+    upload: Option<TokenBucket>,
+    download: Option<TokenBucket>,
+}
+
+impl RouteBandwidth {
+    pub fn from_config(config: &BandwidthConfig) -> Self {
+        let bucket =
+            |rate: Option<u64>| rate.map(|rate| TokenBucket::new(rate, config.burst(rate)));
+        Self {
+            config: config.clone(),
+            upload: bucket(config.upload_bytes_per_sec),
+            download: bucket(config.download_bytes_per_sec),
+        }
+    }
+
+    pub fn upload(&self) -> Option<&TokenBucket> {
+        self.upload.as_ref()
+    }
+
+    pub fn download(&self) -> Option<&TokenBucket> {
+        self.download.as_ref()
+    }
+
+    /// Refills the route-wide buckets; requests in flight keep their own.
+    pub fn reset(&self) {
+        for bucket in [&self.upload, &self.download].into_iter().flatten() {
+            bucket.reset();
+        }
+    }
+}
+
+/// Throttle state of one request: the route's shared buckets plus its own.
+#[derive(Debug)]
+pub struct RequestThrottle {
+    route: Arc<RouteBandwidth>,
+    upload: Option<TokenBucket>,
+    download: Option<TokenBucket>,
+}
+
+impl RequestThrottle {
+    pub fn new(route: Arc<RouteBandwidth>) -> Self {
+        let config = &route.config;
+        let bucket =
+            |rate: Option<u64>| rate.map(|rate| TokenBucket::new(rate, config.burst(rate)));
+        let upload = bucket(config.request_upload_bytes_per_sec);
+        let download = bucket(config.request_download_bytes_per_sec);
+        Self {
+            route,
+            upload,
+            download,
+        }
+    }
+
+    pub fn upload_delay(&self, bytes: usize) -> Duration {
+        delay(&[self.route.upload.as_ref(), self.upload.as_ref()], bytes)
+    }
+
+    pub fn download_delay(&self, bytes: usize) -> Duration {
+        delay(
+            &[self.route.download.as_ref(), self.download.as_ref()],
+            bytes,
+        )
+    }
+}
+
+fn delay(buckets: &[Option<&TokenBucket>], bytes: usize) -> Duration {
+    buckets
+        .iter()
+        .flatten()
+        .map(|bucket| bucket.take(bytes))
+        .max()
+        .unwrap_or(Duration::ZERO)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_admits_burst_then_asks_to_wait_for_the_debt() {
+        let bucket = TokenBucket::new(1000, 1000);
+        let start = Instant::now();
+        assert_eq!(bucket.take_at(1000, start), Duration::ZERO);
+        assert_eq!(bucket.take_at(500, start), Duration::from_millis(500));
+        // Half a second later the debt is paid and nothing has accrued.
+        let later = start + Duration::from_millis(500);
+        assert_eq!(bucket.take_at(0, later), Duration::ZERO);
+        assert_eq!(bucket.take_at(250, later), Duration::from_millis(250));
+    }
+
+    #[test]
+    fn request_waits_for_the_slower_of_route_and_request_buckets() {
+        let route = Arc::new(RouteBandwidth::from_config(&BandwidthConfig {
+            download_bytes_per_sec: Some(10_000),
+            request_download_bytes_per_sec: Some(1_000),
+            ..BandwidthConfig::default()
+        }));
+        let first = RequestThrottle::new(route.clone());
+        let second = RequestThrottle::new(route);
+
+        assert_eq!(first.download_delay(1_000), Duration::ZERO);
+        assert!(first.download_delay(1_000) >= Duration::from_millis(990));
+        // The second request has its own allowance but shares the route's.
+        assert_eq!(second.download_delay(1_000), Duration::ZERO);
+        assert_eq!(second.upload_delay(1_000_000), Duration::ZERO);
+    }
+}
@@ -0,0 +1,698 @@
+//! TLS listeners and upstream client certificates on the OpenSSL backend
+//! of the `openssl` feature. Builds
+//! without it keep pingora's stub backend and reject the settings below
+//! when the config is loaded.
+
+use std::{
+    any::Any,
+    collections::{HashMap, HashSet, hash_map::DefaultHasher},
+    fs,
+    hash::{Hash, Hasher},
+    path::PathBuf,
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+use anyhow::{Context, bail};
+use arc_swap::ArcSwap;
+use async_trait::async_trait;
+use pingora::{
+    Error, ErrorType,
+    http::RequestHeader,
+    listeners::{TlsAccept, tls::TlsSettings},
+    protocols::{
+        Digest,
+        tls::{SslDigest, TlsRef},
+    },
+    tls::{
+        ext,
+        hash::{MessageDigest, hash},
+        nid::Nid,
+        pkey::{PKey, Private},
+        ssl::{NameType, SslVerifyMode},
+        x509::{GeneralNameRef, X509, X509Name, X509NameRef, X509Ref},
+    },
+    upstreams::peer::HttpPeer,
+    utils::tls::CertKey,
+};
+use tracing::{error, info};
+
+use crate::{
+    config::{TlsConfig, UpstreamConfig, parse_sha256_pin},
+    forwarded::{CLIENT_CERT_SAN_HEADER, CLIENT_CERT_SUBJECT_HEADER},
+    reload::spawn_files_watcher,
+    unmatched::{self, Unmatched},
+};
+
+/// A certificate chain, leaf first, and its private key.
+struct Certified {
+    chain: Vec<X509>,
+    key: PKey<Private>,
+}
+
+impl Certified {
+    fn load(cert_path: &str, key_path: &str) -> anyhow::Result<Self> {
+        let pem =
+            fs::read(cert_path).with_context(|| format!("failed to read cert file {cert_path}"))?;
+        let chain =
+            X509::stack_from_pem(&pem).with_context(|| format!("invalid cert file {cert_path}"))?;
+        let Some(leaf) = chain.first() else {
+            bail!("cert file {cert_path} holds no certificate");
+        };
+        let pem =
+            fs::read(key_path).with_context(|| format!("failed to read key file {key_path}"))?;
+        let key = PKey::private_key_from_pem(&pem)
+            .with_context(|| format!("invalid key file {key_path}"))?;
+        if !leaf.public_key()?.public_eq(&key) {
+            bail!("key file {key_path} does not belong to cert file {cert_path}");
+        }
+        Ok(Self { chain, key })
+    }
+
+    fn serve(&self, ssl: &mut TlsRef) -> anyhow::Result<()> {
+        ext::ssl_use_certificate(ssl, &self.chain[0])?;
+        for cert in &self.chain[1..] {
+            ext::ssl_add_chain_cert(ssl, cert)?;
+        }
+        ext::ssl_use_private_key(ssl, &self.key)?;
+        Ok(())
+    }
+
+    /// Lowercase DNS names and wildcards the leaf is issued for: its DNS
+    /// SANs, or its common name when it has none.
+    fn hosts(&self) -> HashSet<String> {
+        let leaf = &self.chain[0];
+        let sans: HashSet<String> = leaf
+            .subject_alt_names()
+            .map(|names| {
+                names
+                    .iter()
+                    .filter_map(|name| name.dnsname().map(str::to_ascii_lowercase))
+                    .collect()
+            })
+            .unwrap_or_default();
+        if !sans.is_empty() {
+            return sans;
+        }
+        leaf.subject_name()
+            .entries_by_nid(Nid::COMMONNAME)
+            .filter_map(|entry| entry.data().to_string().ok())
+            .map(|cn| cn.to_ascii_lowercase())
+            .collect()
+    }
+}
+
+/// The certificates of one listener, picked by SNI.
+struct CertStore {
+    default: Certified,
+    /// What `default` is issued for, to tell the names it does not cover.
+    default_hosts: HashSet<String>,
+    certificates: Vec<Certified>,
+    /// Lowercase exact names and `*.<domain>` wildcards to `certificates`.
+    by_host: HashMap<String, usize>,
+}
+
+impl CertStore {
+    fn load(config: &TlsConfig) -> anyhow::Result<Self> {
+        let mut certificates = Vec::with_capacity(config.certificates.len());
+        let mut by_host = HashMap::new();
+        for (idx, certificate) in config.certificates.iter().enumerate() {
+            certificates.push(Certified::load(
+                &certificate.cert_path,
+                &certificate.key_path,
+            )?);
+            for host in &certificate.hosts {
+                by_host.insert(host.to_ascii_lowercase(), idx);
+            }
+        }
+        let default = Certified::load(&config.cert_path, &config.key_path)?;
+        Ok(Self {
+            default_hosts: default.hosts(),
+            default,
+            certificates,
+            by_host,
+        })
+    }
+
+    /// The certificate for `server_name`: an exact match, then a wildcard
+    /// for its first label, then the default. `false` with the default when
+    /// it is not issued for the name either.
+    fn select(&self, server_name: Option<&str>) -> (&Certified, bool) {
+        let Some(name) = server_name.map(|name| name.trim_end_matches('.').to_ascii_lowercase())
+        else {
+            return (&self.default, true);
+        };
+        let wildcard = name
+            .split_once('.')
+            .map(|(_, domain)| format!("*.{domain}"));
+        match self.by_host.get(&name).or_else(|| {
+            wildcard
+                .as_ref()
+                .and_then(|wildcard| self.by_host.get(wildcard))
+        }) {
+            Some(&idx) => (&self.certificates[idx], true),
+            None => {
+                let covered = self.default_hosts.contains(&name)
+                    || wildcard.is_some_and(|wildcard| self.default_hosts.contains(&wildcard));
+                (&self.default, covered)
+            }
+        }
+    }
+}
+
+/// TLS of one listener: its certificates, and client certificates checked
+/// against `client_ca_path`.
+pub struct ListenerTls {
+    config: TlsConfig,
+    store: ArcSwap<CertStore>,
+}
+
+impl ListenerTls {
+    pub fn load(config: &TlsConfig) -> anyhow::Result<Arc<Self>> {
+        Ok(Arc::new(Self {
+            store: ArcSwap::from_pointee(CertStore::load(config)?),
+            config: config.clone(),
+        }))
+    }
+
+    /// Re-reads every certificate and key. On error the ones served so far
+    /// stay in place.
+    pub fn reload(&self) -> anyhow::Result<()> {
+        self.store.store(Arc::new(CertStore::load(&self.config)?));
+        Ok(())
+    }
+
+    /// Reloads the certificates whenever one of their files changes.
+    pub fn watch(self: &Arc<Self>, debounce: Duration) -> anyhow::Result<()> {
+        let paths: Vec<PathBuf> = std::iter::once((&self.config.cert_path, &self.config.key_path))
+            .chain(
+                self.config
+                    .certificates
+                    .iter()
+                    .map(|certificate| (&certificate.cert_path, &certificate.key_path)),
+            )
+            .flat_map(|(cert, key)| [PathBuf::from(cert), PathBuf::from(key)])
+            .collect();
+        let listener = self.clone();
+        spawn_files_watcher("prx-tls-watcher", &paths, debounce, move || {
+            // A key written just after its certificate is then read with it.
+            thread::sleep(debounce);
+            match listener.reload() {
+                Ok(()) => info!(
+                    listen = listener.config.listen.as_str(),
+                    "reloaded TLS certificates"
+                ),
+                Err(err) => error!(
+                    error = %format!("{err:#}"),
+                    listen = listener.config.listen.as_str(),
+                    "failed to reload TLS certificates, keeping previous ones"
+                ),
+            }
+        })
+    }
+
+    /// Acceptor settings that serve the certificates of `self`.
+    pub fn settings(self: &Arc<Self>) -> anyhow::Result<TlsSettings> {
+        let mut settings = TlsSettings::with_callbacks(Box::new(Callbacks(self.clone())))?;
+        if let Some(ca) = &self.config.client_ca_path {
+            settings
+                .set_ca_file(ca)
+                .with_context(|| format!("failed to read client CA file {ca}"))?;
+            // Sent in the certificate request, so clients pick the right one.
+            let names = X509Name::load_client_ca_file(ca)
+                .with_context(|| format!("invalid client CA file {ca}"))?;
+            settings.set_client_ca_list(names);
+            let mut mode = SslVerifyMode::PEER;
+            if self.config.require_client_cert {
+                mode |= SslVerifyMode::FAIL_IF_NO_PEER_CERT;
+            }
+            settings.set_verify(mode);
+            // Session resumption fails without one once peers are verified.
+            settings.set_session_id_context(b"prx")?;
+        }
+        if self.config.enable_h2 {
+            settings.enable_h2();
+        }
+        Ok(settings)
+    }
+}
+
+struct Callbacks(Arc<ListenerTls>);
+
+#[async_trait]
+impl TlsAccept for Callbacks {
+    async fn certificate_callback(&self, ssl: &mut TlsRef) {
+        let store = self.0.store.load();
+        let server_name = ssl.servername(NameType::HOST_NAME).map(str::to_string);
+        let (certified, covered) = store.select(server_name.as_deref());
+        if !covered && let Some(name) = &server_name {
+            unmatched::record(name, true, Unmatched::NoCertificate);
+        }
+        if let Err(err) = certified.serve(ssl) {
+            error!(
+                error = %format!("{err:#}"),
+                listen = self.0.config.listen.as_str(),
+                "failed to use TLS certificate"
+            );
+        }
+    }
+
+    async fn handshake_complete_callback(
+        &self,
+        ssl: &TlsRef,
+    ) -> Option<Arc<dyn Any + Send + Sync>> {
+        Some(Arc::new(Handshake {
+            server_name: ssl.servername(NameType::HOST_NAME).map(str::to_string),
+            // Only requested, and so only present once verified, with a client CA.
+            client_cert: ssl
+                .peer_certificate()
+                .map(|cert| ClientCert::from_x509(&cert)),
+        }))
+    }
+}
+
+/// What prx keeps of a client's handshake, in the `SslDigest` extension.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Handshake {
+    server_name: Option<String>,
+    client_cert: Option<ClientCert>,
+}
+
+/// The SNI the client sent on `ssl`, if any.
+pub fn server_name(ssl: &SslDigest) -> Option<&str> {
+    ssl.extension.get::<Handshake>()?.server_name.as_deref()
+}
+
+/// The verified certificate a client presented.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientCert {
+    /// Like `CN=client,O=Example`, in certificate order.
+    pub subject: String,
+    /// Like `DNS:client.example.com`, `IP:10.0.0.1`, `email:` or `URI:`.
+    pub sans: Vec<String>,
+}
+
+impl ClientCert {
+    fn from_x509(cert: &X509Ref) -> Self {
+        Self {
+            subject: name_string(cert.subject_name()),
+            sans: cert
+                .subject_alt_names()
+                .map(|names| names.iter().filter_map(san_string).collect())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+fn name_string(name: &X509NameRef) -> String {
+    name.entries()
+        .map(|entry| {
+            let key = entry.object().nid().short_name().unwrap_or("?");
+            let value = entry.data().to_string().unwrap_or_default();
+            format!("{key}={value}")
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn san_string(name: &GeneralNameRef) -> Option<String> {
+    if let Some(dns) = name.dnsname() {
+        return Some(format!("DNS:{dns}"));
+    }
+    if let Some(ip) = name.ipaddress() {
+        let ip = match ip.len() {
+            4 => std::net::IpAddr::from(<[u8; 4]>::try_from(ip).ok()?),
+            16 => std::net::IpAddr::from(<[u8; 16]>::try_from(ip).ok()?),
+            _ => return None,
+        };
+        return Some(format!("IP:{ip}"));
+    }
+    if let Some(email) = name.email() {
+        return Some(format!("email:{email}"));
+    }
+    name.uri().map(|uri| format!("URI:{uri}"))
+}
+
+/// Replaces the client certificate headers on `request` with the
+/// certificate the client presented on `ssl`, if any.
+pub fn forward_client_cert(ssl: &SslDigest, request: &mut RequestHeader) -> pingora::Result<()> {
+    let Some(cert) = ssl
+        .extension
+        .get::<Handshake>()
+        .and_then(|handshake| handshake.client_cert.as_ref())
+    else {
+        return Ok(());
+    };
+    request.remove_header(CLIENT_CERT_SUBJECT_HEADER);
+    request.remove_header(CLIENT_CERT_SAN_HEADER);
+    request.insert_header(CLIENT_CERT_SUBJECT_HEADER, header_safe(&cert.subject))?;
+    if !cert.sans.is_empty() {
+        request.insert_header(CLIENT_CERT_SAN_HEADER, header_safe(&cert.sans.join(", ")))?;
+    }
+    Ok(())
+}
+
+/// `value` with bytes a header value cannot hold written as `\xNN`.
+fn header_safe(value: &str) -> String {
+    let mut safe = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        if byte == b' ' || byte.is_ascii_graphic() {
+            safe.push(byte as char);
+        } else {
+            safe.push_str(&format!("\\x{byte:02X}"));
+        }
+    }
+    safe
+}
+
+/// What prx proves to one TLS upstream, and the keys it must prove to hold.
+#[derive(Debug, Clone, Default)]
+pub struct UpstreamTls {
+    client_cert: Option<Arc<CertKey>>,
+    /// SHA-256 of the SubjectPublicKeyInfo of accepted certificates.
+    pins: Arc<[[u8; 32]]>,
+}
+
+/// SHA-256 of the SubjectPublicKeyInfo of the certificate an upstream sent.
+struct UpstreamSpki([u8; 32]);
+
+impl UpstreamTls {
+    pub fn load(config: &UpstreamConfig) -> anyhow::Result<Self> {
+        let client_cert = match (&config.client_cert_path, &config.client_key_path) {
+            (Some(cert_path), Some(key_path)) => {
+                let Certified { chain, key } = Certified::load(cert_path, key_path)?;
+                Some(Arc::new(CertKey::new(chain, key)))
+            }
+            _ => None,
+        };
+        let pins = config
+            .pinned_cert_sha256
+            .iter()
+            .map(|pin| parse_sha256_pin(pin))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(Self {
+            client_cert,
+            pins: pins.into(),
+        })
+    }
+
+    /// Presents the client certificate, if any, on connections of `peer`,
+    /// and records the key of upstream certificates for [`Self::check`].
+    /// The pool keys connections by both, so they are not shared with peers
+    /// presenting another certificate or checking other pins.
+    pub fn configure(&self, peer: &mut HttpPeer) {
+        peer.client_cert_key = self.client_cert.clone();
+        if !self.pins.is_empty() {
+            peer.options.upstream_tls_handshake_complete_hook = Some(Arc::new(upstream_spki));
+            let mut hasher = DefaultHasher::new();
+            self.pins.hash(&mut hasher);
+            peer.group_key ^= hasher.finish();
+        }
+    }
+
+    /// Fails unless the upstream certificate of `digest` matches one of the
+    /// pins, when there are any.
+    pub fn check(&self, digest: Option<&Digest>) -> pingora::Result<()> {
+        if self.pins.is_empty() {
+            return Ok(());
+        }
+        let spki = digest
+            .and_then(|digest| digest.ssl_digest.as_ref())
+            .and_then(|ssl| ssl.extension.get::<UpstreamSpki>());
+        match spki {
+            Some(spki) if self.pins.contains(&spki.0) => Ok(()),
+            // Reported like any other upstream failure: a 502.
+            _ => Err(Error::explain(
+                ErrorType::InvalidCert,
+                "upstream certificate matches none of pinned_cert_sha256",
+            )
+            .into_up()),
+        }
+    }
+}
+
+fn upstream_spki(ssl: &TlsRef) -> Option<Arc<dyn Any + Send + Sync>> {
+    let der = ssl
+        .peer_certificate()?
+        .public_key()
+        .ok()?
+        .public_key_to_der()
+        .ok()?;
+    let digest = hash(MessageDigest::sha256(), &der).ok()?;
+    Some(Arc::new(UpstreamSpki((*digest).try_into().ok()?)))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use openssl::{
+        asn1::Asn1Time,
+        bn::BigNum,
+        ec::{EcGroup, EcKey},
+        hash::MessageDigest,
+        nid::Nid,
+        x509::{
+            X509Builder, X509NameBuilder,
+            extension::{BasicConstraints, SubjectAlternativeName},
+        },
+    };
+
+    use super::*;
+
+    /// A certificate for `cn` and `sans`, signed by `issuer` or by itself.
+    pub(crate) fn issue(
+        cn: &str,
+        sans: &[&str],
+        issuer: Option<&(X509, PKey<Private>)>,
+    ) -> (X509, PKey<Private>) {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).expect("curve");
+        let key = PKey::from_ec_key(EcKey::generate(&group).expect("ec key")).expect("key");
+        let mut name = X509NameBuilder::new().expect("name");
+        name.append_entry_by_nid(Nid::COMMONNAME, cn).expect("cn");
+        name.append_entry_by_nid(Nid::ORGANIZATIONNAME, "Example")
+            .expect("o");
+        let name = name.build();
+
+        let mut builder = X509Builder::new().expect("builder");
+        builder.set_version(2).expect("version");
+        let serial = BigNum::from_u32(rand::random::<u32>() >> 1).expect("serial");
+        builder
+            .set_serial_number(&serial.to_asn1_integer().expect("serial"))
+            .expect("serial");
+        builder.set_subject_name(&name).expect("subject");
+        builder
+            .set_issuer_name(issuer.map_or(&*name, |(cert, _)| cert.subject_name()))
+            .expect("issuer");
+        builder.set_pubkey(&key).expect("pubkey");
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).expect("time"))
+            .expect("not before");
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).expect("time"))
+            .expect("not after");
+        if issuer.is_none() {
+            builder
+                .append_extension(BasicConstraints::new().critical().ca().build().expect("ca"))
+                .expect("ca");
+        }
+        if !sans.is_empty() {
+            let mut names = SubjectAlternativeName::new();
+            for san in sans {
+                match san.parse::<std::net::IpAddr>() {
+                    Ok(_) => names.ip(san),
+                    Err(_) => names.dns(san),
+                };
+            }
+            let context = builder.x509v3_context(issuer.map(|(cert, _)| &**cert), None);
+            let names = names.build(&context).expect("sans");
+            builder.append_extension(names).expect("sans");
+        }
+        let signer = issuer.map_or(&key, |(_, key)| key);
+        builder.sign(signer, MessageDigest::sha256()).expect("sign");
+        (builder.build(), key)
+    }
+
+    /// Writes `cert` and `key` as `<name>.crt` and `<name>.key` into `dir`.
+    pub(crate) fn write_pem(
+        dir: &std::path::Path,
+        name: &str,
+        (cert, key): &(X509, PKey<Private>),
+    ) -> (String, String) {
+        let cert_path = dir.join(format!("{name}.crt"));
+        let key_path = dir.join(format!("{name}.key"));
+        fs::write(&cert_path, cert.to_pem().expect("cert pem")).expect("write cert");
+        fs::write(&key_path, key.private_key_to_pem_pkcs8().expect("key pem")).expect("write key");
+        (
+            cert_path.to_string_lossy().into_owned(),
+            key_path.to_string_lossy().into_owned(),
+        )
+    }
+
+    #[test]
+    fn client_cert_reports_subject_and_alt_names() {
+        let ca = issue("prx test ca", &[], None);
+        let (cert, _) = issue("client", &["client.example.com", "10.0.0.1"], Some(&ca));
+
+        assert_eq!(
+            ClientCert::from_x509(&cert),
+            ClientCert {
+                subject: "CN=client,O=Example".to_string(),
+                sans: vec![
+                    "DNS:client.example.com".to_string(),
+                    "IP:10.0.0.1".to_string()
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn forward_client_cert_keeps_headers_without_a_certificate() {
+        let mut request = RequestHeader::build("GET", b"/", None).expect("request");
+        request
+            .insert_header(CLIENT_CERT_SUBJECT_HEADER, "CN=admin")
+            .expect("header");
+        request
+            .insert_header(CLIENT_CERT_SAN_HEADER, "DNS:admin")
+            .expect("header");
+        let ssl = SslDigest::new("TLS_AES_128_GCM_SHA256", "TLSv1.3", None, None, Vec::new());
+
+        // Left to strip_client_cert, which keeps those of trusted proxies.
+        forward_client_cert(&ssl, &mut request).expect("forward");
+        assert_eq!(request.headers[CLIENT_CERT_SUBJECT_HEADER], "CN=admin");
+        assert_eq!(request.headers[CLIENT_CERT_SAN_HEADER], "DNS:admin");
+        assert_eq!(header_safe("CN=caf\u{e9}"), "CN=caf\\xC3\\xA9");
+    }
+
+    #[test]
+    fn cert_store_picks_certificates_by_server_name() {
+        let dir = tempfile::tempdir().expect("tempdir");
+        let (cert_path, key_path) = write_pem(dir.path(), "default", &issue("default", &[], None));
+        let (api_cert, api_key) = write_pem(dir.path(), "api", &issue("api", &[], None));
+        let (wild_cert, wild_key) = write_pem(dir.path(), "wild", &issue("wild", &[], None));
+        let config: TlsConfig = toml::from_str(&format!(
+            r#"
+listen = "127.0.0.1:8443"
+cert_path = "{cert_path}"
+key_path = "{key_path}"
+
+[[certificate]]
+hosts = ["api.example.com"]
+cert_path = "{api_cert}"
+key_path = "{api_key}"
+
+[[certificate]]
+hosts = ["*.example.com", "example.org"]
+cert_path = "{wild_cert}"
+key_path = "{wild_key}"
+"#
+        ))
+        .expect("tls config");
+        let store = CertStore::load(&config).expect("store");
+        let served = |name| name_string(store.select(name).0.chain[0].subject_name());
+
+        assert_eq!(served(Some("api.example.com")), "CN=api,O=Example");
+        assert_eq!(served(Some("API.Example.com.")), "CN=api,O=Example");
+        assert_eq!(served(Some("www.example.com")), "CN=wild,O=Example");
+        assert_eq!(served(Some("example.org")), "CN=wild,O=Example");
+        // Wildcards cover one label only.
+        assert_eq!(served(Some("a.b.example.com")), "CN=default,O=Example");
+        assert_eq!(served(Some("example.com")), "CN=default,O=Example");
+        assert_eq!(served(None), "CN=default,O=Example");
+
+        let covered = |name| store.select(name).1;
+        assert!(covered(Some("www.example.com")));
+        assert!(covered(Some("Default")));
+        assert!(covered(None));
+        assert!(!covered(Some("a.b.example.com")));
+        assert!(!covered(Some("example.com")));
+    }
+
+    #[test]
+    fn listener_tls_rejects_a_key_of_another_certificate() {
+        let dir = tempfile::tempdir().expect("tempdir");
+        let (cert_path, _) = write_pem(dir.path(), "server", &issue("localhost", &[], None));
+        let (_, key_path) = write_pem(dir.path(), "other", &issue("other", &[], None));
+
+        let Err(err) = Certified::load(&cert_path, &key_path) else {
+            panic!("a key of another certificate must be rejected");
+        };
+        assert!(format!("{err:#}").contains("does not belong to cert file"));
+        assert!(Certified::load(&cert_path, &cert_path.replace(".crt", ".key")).is_ok());
+    }
+
+    #[test]
+    fn reload_keeps_the_previous_certificates_on_error() {
+        let dir = tempfile::tempdir().expect("tempdir");
+        let (cert_path, key_path) = write_pem(dir.path(), "server", &issue("first", &[], None));
+        let config: TlsConfig = toml::from_str(&format!(
+            "listen = \"127.0.0.1:0\"\ncert_path = \"{cert_path}\"\nkey_path = \"{key_path}\"\n"
+        ))
+        .expect("tls config");
+        let listener = ListenerTls::load(&config).expect("listener");
+        let served = |listener: &ListenerTls| {
+            name_string(listener.store.load().default.chain[0].subject_name())
+        };
+
+        // A certificate rotated before its key does not match it yet.
+        write_pem(dir.path(), "other", &issue("second", &[], None));
+        fs::rename(dir.path().join("other.crt"), &cert_path).expect("rotate cert");
+        assert!(listener.reload().is_err());
+        assert_eq!(served(&listener), "CN=first,O=Example");
+
+        fs::rename(dir.path().join("other.key"), &key_path).expect("rotate key");
+        listener.reload().expect("reload");
+        assert_eq!(served(&listener), "CN=second,O=Example");
+    }
+
+    #[test]
+    fn upstream_tls_presents_the_configured_client_certificate() {
+        let dir = tempfile::tempdir().expect("tempdir");
+        let (cert_path, key_path) = write_pem(dir.path(), "client", &issue("client", &[], None));
+        let (_, other_key) = write_pem(dir.path(), "other", &issue("other", &[], None));
+        let mut config: UpstreamConfig =
+            toml::from_str("addr = \"127.0.0.1:8443\"\ntls = true\n").expect("upstream");
+        let mut peer = HttpPeer::new("127.0.0.1:8443", true, "localhost".to_string());
+
+        UpstreamTls::load(&config)
+            .expect("no client cert")
+            .configure(&mut peer);
+        assert!(peer.client_cert_key.is_none());
+
+        config.client_cert_path = Some(cert_path);
+        config.client_key_path = Some(key_path);
+        UpstreamTls::load(&config)
+            .expect("client cert")
+            .configure(&mut peer);
+        let cert = peer.client_cert_key.as_ref().expect("presented");
+        assert_eq!(
+            name_string(cert.leaf().subject_name()),
+            "CN=client,O=Example"
+        );
+
+        config.client_key_path = Some(other_key);
+        assert!(UpstreamTls::load(&config).is_err());
+    }
+
+    #[test]
+    fn upstream_tls_needs_a_pinned_upstream_key() {
+        use pingora::upstreams::peer::Peer;
+
+        let config: UpstreamConfig = toml::from_str(
+            "addr = \"127.0.0.1:8443\"\ntls = true\npinned_cert_sha256 = [\"sha256/n4bQgYhMfWWaL+qgxVrQFaO/TxsrC4Is0V1sFbDwCgg=\"]\n",
+        )
+        .expect("upstream");
+        let pinned = UpstreamTls::load(&config).expect("pins");
+        let mut peer = HttpPeer::new("127.0.0.1:8443", true, "localhost".to_string());
+        let unpinned = peer.reuse_hash();
+
+        pinned.configure(&mut peer);
+        assert!(peer.options.upstream_tls_handshake_complete_hook.is_some());
+        assert_ne!(peer.reuse_hash(), unpinned);
+        // A connection without the key of a verified handshake never matches.
+        let Err(err) = pinned.check(None) else {
+            panic!("an unchecked upstream must be refused");
+        };
+        assert_eq!(err.etype(), &ErrorType::InvalidCert);
+        assert!(UpstreamTls::default().check(None).is_ok());
+    }
+}
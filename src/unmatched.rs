@@ -0,0 +1,168 @@
+use std::{
+    collections::HashMap,
+    sync::{Mutex, MutexGuard},
+};
+
+use once_cell::sync::Lazy;
+use serde::Serialize;
+
+use crate::metrics;
+
+/// Distinct hosts tracked at once; scanners sending random names push out
+/// the rarest.
+const MAX_TRACKED: usize = 256;
+/// Longest host name kept, per RFC 1035.
+const MAX_HOST_LEN: usize = 253;
+
+static UNMATCHED: Lazy<UnmatchedHosts> = Lazy::new(UnmatchedHosts::default);
+
+/// Why a host counts as unmatched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Unmatched {
+    /// No route matched and there was no default route; answered `404`.
+    NoRoute,
+    /// No route covers the host, so the default route served it.
+    DefaultRoute,
+    /// TLS clients sent it as SNI and neither a certificate block nor
+    /// `cert_path` covers it; counted per handshake.
+    #[cfg(feature = "openssl")]
+    NoCertificate,
+}
+
+impl Unmatched {
+    /// The `reason` metric label.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::NoRoute => "no_route",
+            Self::DefaultRoute => "default_route",
+            #[cfg(feature = "openssl")]
+            Self::NoCertificate => "no_certificate",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct UnmatchedHost {
+    pub host: String,
+    /// Whether the requests came over TLS, where the host is what clients
+    /// were sending as SNI.
+    pub tls: bool,
+    pub reason: Unmatched,
+    /// Space-saving estimate: a host that pushed out another one inherits
+    /// its count, so this may overstate rare hosts but never understates.
+    pub requests: u64,
+}
+
+/// Approximate top hosts with the space-saving algorithm, so memory stays
+/// bounded however many names are sent.
+#[derive(Debug, Default)]
+struct UnmatchedHosts {
+    counts: Mutex<HashMap<(String, bool), (Unmatched, u64)>>,
+}
+
+impl UnmatchedHosts {
+    fn counts(&self) -> MutexGuard<'_, HashMap<(String, bool), (Unmatched, u64)>> {
+        self.counts
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn record(&self, host: &str, tls: bool, reason: Unmatched) {
+        let host = match host.char_indices().nth(MAX_HOST_LEN) {
+            Some((end, _)) => &host[..end],
+            None => host,
+        };
+        let mut counts = self.counts();
+        if let Some(entry) = counts.get_mut(&(host.to_string(), tls)) {
+            *entry = (reason, entry.1 + 1);
+            return;
+        }
+        let mut inherited = 0;
+        if counts.len() >= MAX_TRACKED
+            && let Some(rarest) = counts
+                .iter()
+                .min_by_key(|(_, (_, requests))| *requests)
+                .map(|(key, _)| key.clone())
+        {
+            inherited = counts.remove(&rarest).map_or(0, |(_, requests)| requests);
+        }
+        counts.insert((host.to_string(), tls), (reason, inherited + 1));
+    }
+
+    fn top(&self, limit: usize) -> Vec<UnmatchedHost> {
+        let mut hosts: Vec<UnmatchedHost> = self
+            .counts()
+            .iter()
+            .map(|((host, tls), (reason, requests))| UnmatchedHost {
+                host: host.clone(),
+                tls: *tls,
+                reason: *reason,
+                requests: *requests,
+            })
+            .collect();
+        hosts.sort_by(|a, b| {
+            b.requests
+                .cmp(&a.requests)
+                .then_with(|| a.host.cmp(&b.host))
+        });
+        hosts.truncate(limit);
+        hosts
+    }
+}
+
+/// Records a request whose host no route covers, or a handshake whose SNI
+/// no certificate covers.
+pub fn record(host: &str, tls: bool, reason: Unmatched) {
+    metrics::inc_unmatched_host(reason.as_str(), tls);
+    UNMATCHED.record(host, tls, reason);
+}
+
+/// The `limit` hosts seen most, busiest first.
+pub fn top(limit: usize) -> Vec<UnmatchedHost> {
+    UNMATCHED.top(limit)
+}
+
+pub fn reset() {
+    UNMATCHED.counts().clear();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_busiest_hosts_within_bounds() {
+        let hosts = UnmatchedHosts::default();
+        for _ in 0..5 {
+            hosts.record("old.example.com", true, Unmatched::DefaultRoute);
+        }
+        hosts.record("old.example.com", false, Unmatched::NoRoute);
+        for index in 0..MAX_TRACKED * 2 {
+            hosts.record(&format!("scan-{index}.invalid"), false, Unmatched::NoRoute);
+        }
+        hosts.record("old.example.com", true, Unmatched::DefaultRoute);
+
+        assert_eq!(hosts.counts().len(), MAX_TRACKED);
+        let top = hosts.top(2);
+        assert_eq!(
+            top[0],
+            UnmatchedHost {
+                host: "old.example.com".to_string(),
+                tls: true,
+                reason: Unmatched::DefaultRoute,
+                requests: 6,
+            }
+        );
+        assert!(top[1].host.starts_with("scan-") && top[1].requests >= 2);
+        assert!(hosts.top(usize::MAX).iter().all(|host| host.requests <= 6));
+
+        hosts.record(&"a".repeat(300), false, Unmatched::NoRoute);
+        assert!(
+            hosts
+                .counts()
+                .keys()
+                .any(|(host, _)| host.len() == MAX_HOST_LEN)
+        );
+    }
+}
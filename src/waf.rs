@@ -0,0 +1,597 @@
+use std::fs;
+
+use anyhow::{Context, bail};
+use pingora::http::RequestHeader;
+use regex::{Regex, RegexBuilder};
+use tracing::warn;
+
+use crate::config::{WafConfig, WafMode};
+
+/// Request inspection with a ModSecurity/OWASP CRS compatible rule subset.
+///
+/// Supported: `SecRule` with request variables that do not need the body,
+/// the operators `@rx`, `@pm`, `@contains`, `@streq`, `@beginsWith`,
+/// `@endsWith`, `@within` and `@unconditionalMatch` (optionally negated with
+/// `!`), string transformations and the `deny`/`block`/`drop`/`pass`
+/// disruptive actions. Rules relying on anything else — chains, `setvar`
+/// anomaly scoring, `ctl`, body variables or PCRE-only regex syntax — fail
+/// to load, or are skipped with a warning under `skip_unsupported`.
+#[derive(Debug)]
+pub struct RuleSet {
+    mode: WafMode,
+    rules: Vec<Rule>,
+}
+
+/// The first rule that matched a request.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Verdict {
+    pub rule_id: u64,
+    pub msg: String,
+    /// `Some(status)` when the request must be rejected.
+    pub deny_status: Option<u16>,
+}
+
+#[derive(Debug)]
+struct Rule {
+    id: u64,
+    variables: Vec<Variable>,
+    operator: Operator,
+    negated: bool,
+    transforms: Vec<Transform>,
+    deny_status: Option<u16>,
+    msg: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Variable {
+    RequestUri,
+    RequestFilename,
+    QueryString,
+    RequestMethod,
+    Args(Option<String>),
+    ArgsNames,
+    RequestHeaders(Option<String>),
+    RequestHeadersNames,
+    RequestCookies(Option<String>),
+    RequestCookiesNames,
+}
+
+#[derive(Debug)]
+enum Operator {
+    Rx(Regex),
+    Pm(Vec<String>),
+    Contains(String),
+    Streq(String),
+    BeginsWith(String),
+    EndsWith(String),
+    Within(String),
+    Unconditional,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Transform {
+    Lowercase,
+    UrlDecode,
+    Trim,
+    CompressWhitespace,
+    RemoveWhitespace,
+}
+
+impl RuleSet {
+    pub fn load(config: &WafConfig) -> anyhow::Result<Self> {
+        let mut rules = Vec::new();
+        for path in &config.rule_files {
+            let text = fs::read_to_string(path)
+                .with_context(|| format!("failed to read waf rule file {path}"))?;
+            rules.extend(
+                parse_rules(&text, path, config.skip_unsupported)
+                    .with_context(|| format!("invalid waf rule file {path}"))?,
+            );
+        }
+        Ok(Self {
+            mode: config.mode,
+            rules,
+        })
+    }
+
+    pub fn rule_count(&self) -> usize {
+        self.rules.len()
+    }
+
+    /// Runs the rules in file order and returns the first disruptive match,
+    /// or the first `pass` match if none denies. Detect mode never denies.
+    pub fn inspect(&self, req: &RequestHeader) -> Option<Verdict> {
+        let request = InspectedRequest::new(req);
+        let mut first_pass = None;
+        for rule in &self.rules {
+            if !rule.matches(&request) {
+                continue;
+            }
+            let verdict = Verdict {
+                rule_id: rule.id,
+                msg: rule.msg.clone(),
+                deny_status: rule.deny_status.filter(|_| self.mode == WafMode::Block),
+            };
+            if rule.deny_status.is_some() {
+                return Some(verdict);
+            }
+            first_pass.get_or_insert(verdict);
+        }
+        first_pass
+    }
+}
+
+impl Rule {
+    fn matches(&self, request: &InspectedRequest) -> bool {
+        self.variables.iter().any(|variable| {
+            request.values(variable).into_iter().any(|value| {
+                let value = self
+                    .transforms
+                    .iter()
+                    .fold(value, |value, transform| transform.apply(&value));
+                self.operator.matches(&value) != self.negated
+            })
+        })
+    }
+}
+
+impl Operator {
+    fn matches(&self, value: &str) -> bool {
+        match self {
+            Self::Rx(regex) => regex.is_match(value),
+            Self::Pm(phrases) => {
+                let value = value.to_lowercase();
+                phrases.iter().any(|phrase| value.contains(phrase.as_str()))
+            }
+            Self::Contains(needle) => value.contains(needle.as_str()),
+            Self::Streq(expected) => value == expected,
+            Self::BeginsWith(prefix) => value.starts_with(prefix.as_str()),
+            Self::EndsWith(suffix) => value.ends_with(suffix.as_str()),
+            Self::Within(haystack) => haystack.contains(value),
+            Self::Unconditional => true,
+        }
+    }
+}
+
+impl Transform {
+    fn apply(self, value: &str) -> String {
+        match self {
+            Self::Lowercase => value.to_lowercase(),
+            Self::UrlDecode => url_decode(value),
+            Self::Trim => value.trim().to_string(),
+            Self::CompressWhitespace => value.split_whitespace().collect::<Vec<_>>().join(" "),
+            Self::RemoveWhitespace => value.chars().filter(|c| !c.is_whitespace()).collect(),
+        }
+    }
+}
+
+/// Request parts the rules look at, extracted once per request.
+struct InspectedRequest<'a> {
+    req: &'a RequestHeader,
+    args: Vec<(String, String)>,
+    cookies: Vec<(String, String)>,
+}
+
+impl<'a> InspectedRequest<'a> {
+    fn new(req: &'a RequestHeader) -> Self {
+        let args = req
+            .uri
+            .query()
+            .map(|query| {
+                query
+                    .split('&')
+                    .filter(|pair| !pair.is_empty())
+                    .map(|pair| {
+                        let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+                        (url_decode(name), url_decode(value))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        let cookies = req
+            .headers
+            .get_all("cookie")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(';'))
+            .filter_map(|pair| {
+                let (name, value) = pair.trim().split_once('=')?;
+                Some((name.to_string(), value.to_string()))
+            })
+            .collect();
+        Self { req, args, cookies }
+    }
+
+    fn values(&self, variable: &Variable) -> Vec<String> {
+        match variable {
+            Variable::RequestUri => vec![
+                self.req
+                    .uri
+                    .path_and_query()
+                    .map_or_else(|| self.req.uri.path().to_string(), ToString::to_string),
+            ],
+            Variable::RequestFilename => vec![self.req.uri.path().to_string()],
+            Variable::QueryString => vec![self.req.uri.query().unwrap_or_default().to_string()],
+            Variable::RequestMethod => vec![self.req.method.as_str().to_string()],
+            Variable::Args(name) => pair_values(&self.args, name.as_deref()),
+            Variable::ArgsNames => self.args.iter().map(|(name, _)| name.clone()).collect(),
+            Variable::RequestHeaders(name) => self
+                .req
+                .headers
+                .iter()
+                .filter(|(header, _)| {
+                    name.as_deref()
+                        .is_none_or(|name| header.as_str().eq_ignore_ascii_case(name))
+                })
+                .map(|(_, value)| String::from_utf8_lossy(value.as_bytes()).into_owned())
+                .collect(),
+            Variable::RequestHeadersNames => self
+                .req
+                .headers
+                .keys()
+                .map(|name| name.as_str().to_string())
+                .collect(),
+            Variable::RequestCookies(name) => pair_values(&self.cookies, name.as_deref()),
+            Variable::RequestCookiesNames => {
+                self.cookies.iter().map(|(name, _)| name.clone()).collect()
+            }
+        }
+    }
+}
+
+fn pair_values(pairs: &[(String, String)], name: Option<&str>) -> Vec<String> {
+    pairs
+        .iter()
+        .filter(|(candidate, _)| name.is_none_or(|name| candidate.eq_ignore_ascii_case(name)))
+        .map(|(_, value)| value.clone())
+        .collect()
+}
+
+fn url_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut idx = 0;
+    while idx < bytes.len() {
+        match bytes[idx] {
+            b'+' => decoded.push(b' '),
+            b'%' => match bytes
+                .get(idx + 1..idx + 3)
+                .and_then(|hex| std::str::from_utf8(hex).ok())
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            {
+                Some(byte) => {
+                    decoded.push(byte);
+                    idx += 2;
+                }
+                None => decoded.push(b'%'),
+            },
+            byte => decoded.push(byte),
+        }
+        idx += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn parse_rules(text: &str, source: &str, skip_unsupported: bool) -> anyhow::Result<Vec<Rule>> {
+    let mut rules = Vec::new();
+    for (line_no, directive) in logical_lines(text) {
+        let tokens = tokenize(&directive).with_context(|| format!("{source}:{line_no}"))?;
+        let Some(name) = tokens.first() else {
+            continue;
+        };
+        let parsed = match name.as_str() {
+            "SecRule" => parse_rule(&tokens[1..]).map(Some),
+            // Markers and banners carry no behavior on their own.
+            "SecMarker" | "SecComponentSignature" => Ok(None),
+            other => Err(anyhow::anyhow!("unsupported directive {other}")),
+        };
+        match parsed {
+            Ok(Some(rule)) => rules.push(rule),
+            Ok(None) => {}
+            Err(err) if skip_unsupported => {
+                warn!(source, line = line_no, error = %err, "skipping unsupported waf rule");
+            }
+            Err(err) => return Err(err.context(format!("{source}:{line_no}"))),
+        }
+    }
+    Ok(rules)
+}
+
+/// Joins `\`-continued lines and drops comments, keeping the first line number.
+fn logical_lines(text: &str) -> Vec<(usize, String)> {
+    let mut lines = Vec::new();
+    let mut current: Option<(usize, String)> = None;
+    for (idx, raw) in text.lines().enumerate() {
+        let line = raw.trim();
+        if current.is_none() && (line.is_empty() || line.starts_with('#')) {
+            continue;
+        }
+        let (continued, body) = match line.strip_suffix('\\') {
+            Some(body) => (true, body),
+            None => (false, line),
+        };
+        let entry = current.get_or_insert_with(|| (idx + 1, String::new()));
+        if !entry.1.is_empty() {
+            entry.1.push(' ');
+        }
+        entry.1.push_str(body.trim());
+        if !continued {
+            lines.extend(current.take());
+        }
+    }
+    lines.extend(current);
+    lines
+}
+
+/// Splits a directive on whitespace, honoring double quotes and `\` escapes.
+fn tokenize(line: &str) -> anyhow::Result<Vec<String>> {
+    let mut tokens = Vec::new();
+    let mut chars = line.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+        let mut token = String::new();
+        if c == '"' {
+            chars.next();
+            let mut closed = false;
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' if chars.peek() == Some(&'"') => token.push(chars.next().unwrap_or('"')),
+                    '"' => {
+                        closed = true;
+                        break;
+                    }
+                    c => token.push(c),
+                }
+            }
+            if !closed {
+                bail!("unterminated quoted argument");
+            }
+        } else {
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() {
+                    break;
+                }
+                token.push(c);
+                chars.next();
+            }
+        }
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+fn parse_rule(args: &[String]) -> anyhow::Result<Rule> {
+    let [variables, operator, rest @ ..] = args else {
+        bail!("SecRule needs variables and an operator");
+    };
+    let actions = match rest {
+        [] => "",
+        [actions] => actions.as_str(),
+        _ => bail!("SecRule has unexpected trailing arguments"),
+    };
+
+    let mut id = None;
+    let mut msg = String::new();
+    let mut status = 403;
+    let mut disruptive = None;
+    let mut transforms = Vec::new();
+    for action in split_actions(actions) {
+        let (name, value) = match action.split_once(':') {
+            Some((name, value)) => (name.trim(), value.trim().trim_matches('\'')),
+            None => (action.trim(), ""),
+        };
+        match name {
+            "id" => {
+                id = Some(
+                    value
+                        .parse::<u64>()
+                        .with_context(|| format!("invalid rule id {value}"))?,
+                )
+            }
+            "phase" => {
+                if !matches!(value, "1" | "2" | "request") {
+                    bail!("phase {value} is not supported (request phases 1 and 2 only)");
+                }
+            }
+            "msg" => msg = value.to_string(),
+            "status" => {
+                status = value
+                    .parse::<u16>()
+                    .ok()
+                    .filter(|status| (400..600).contains(status))
+                    .with_context(|| format!("invalid status {value}"))?
+            }
+            "deny" | "block" | "drop" => disruptive = Some(true),
+            "pass" => disruptive = Some(false),
+            "t" => match value {
+                "none" => transforms.clear(),
+                "lowercase" => transforms.push(Transform::Lowercase),
+                "urlDecode" | "urlDecodeUni" => transforms.push(Transform::UrlDecode),
+                "trim" => transforms.push(Transform::Trim),
+                "compressWhitespace" => transforms.push(Transform::CompressWhitespace),
+                "removeWhitespace" => transforms.push(Transform::RemoveWhitespace),
+                other => bail!("transformation t:{other} is not supported"),
+            },
+            // Metadata and logging actions do not change the outcome.
+            "log" | "nolog" | "auditlog" | "noauditlog" | "severity" | "tag" | "rev" | "ver"
+            | "maturity" | "accuracy" | "logdata" | "capture" | "multiMatch" => {}
+            other => bail!("action {other} is not supported"),
+        }
+    }
+    let id = id.context("SecRule is missing an id action")?;
+
+    let (negated, operator) = match operator.strip_prefix('!') {
+        Some(operator) => (true, operator),
+        None => (false, operator.as_str()),
+    };
+    Ok(Rule {
+        id,
+        variables: parse_variables(variables).with_context(|| format!("rule {id}"))?,
+        operator: parse_operator(operator).with_context(|| format!("rule {id}"))?,
+        negated,
+        transforms,
+        deny_status: disruptive.unwrap_or(true).then_some(status),
+        msg,
+    })
+}
+
+/// Splits `id:1,msg:'a, b',deny` on commas outside single quotes.
+fn split_actions(actions: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut quoted = false;
+    let mut start = 0;
+    for (idx, c) in actions.char_indices() {
+        match c {
+            '\'' => quoted = !quoted,
+            ',' if !quoted => {
+                parts.push(&actions[start..idx]);
+                start = idx + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(&actions[start..]);
+    parts
+        .into_iter()
+        .filter(|part| !part.trim().is_empty())
+        .collect()
+}
+
+fn parse_variables(spec: &str) -> anyhow::Result<Vec<Variable>> {
+    spec.split('|')
+        .map(|part| {
+            if part.starts_with('!') || part.starts_with('&') {
+                bail!("variable exclusions and counts ({part}) are not supported");
+            }
+            let (name, selector) = match part.split_once(':') {
+                Some((name, selector)) => {
+                    if selector.starts_with('/') {
+                        bail!("regex variable selectors ({part}) are not supported");
+                    }
+                    (name, Some(selector.to_string()))
+                }
+                None => (part, None),
+            };
+            Ok(match (name, selector) {
+                ("REQUEST_URI", None) => Variable::RequestUri,
+                ("REQUEST_FILENAME", None) => Variable::RequestFilename,
+                ("QUERY_STRING", None) => Variable::QueryString,
+                ("REQUEST_METHOD", None) => Variable::RequestMethod,
+                ("ARGS" | "ARGS_GET", selector) => Variable::Args(selector),
+                ("ARGS_NAMES" | "ARGS_GET_NAMES", None) => Variable::ArgsNames,
+                ("REQUEST_HEADERS", selector) => Variable::RequestHeaders(selector),
+                ("REQUEST_HEADERS_NAMES", None) => Variable::RequestHeadersNames,
+                ("REQUEST_COOKIES", selector) => Variable::RequestCookies(selector),
+                ("REQUEST_COOKIES_NAMES", None) => Variable::RequestCookiesNames,
+                _ => bail!("variable {part} is not supported"),
+            })
+        })
+        .collect()
+}
+
+fn parse_operator(spec: &str) -> anyhow::Result<Operator> {
+    let Some(spec) = spec.strip_prefix('@') else {
+        // A bare pattern is an implicit @rx.
+        return compile_regex(spec).map(Operator::Rx);
+    };
+    let (name, arg) = spec.split_once(' ').unwrap_or((spec, ""));
+    let arg = arg.trim().to_string();
+    Ok(match name {
+        "rx" => Operator::Rx(compile_regex(&arg)?),
+        "pm" => Operator::Pm(arg.split_whitespace().map(str::to_lowercase).collect()),
+        "contains" => Operator::Contains(arg),
+        "streq" => Operator::Streq(arg),
+        "beginsWith" => Operator::BeginsWith(arg),
+        "endsWith" => Operator::EndsWith(arg),
+        "within" => Operator::Within(arg),
+        "unconditionalMatch" => Operator::Unconditional,
+        other => bail!("operator @{other} is not supported"),
+    })
+}
+
+fn compile_regex(pattern: &str) -> anyhow::Result<Regex> {
+    RegexBuilder::new(pattern)
+        .size_limit(10 * 1024 * 1024)
+        .build()
+        .with_context(|| format!("regex {pattern:?} is not supported"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(text: &str) -> RuleSet {
+        RuleSet {
+            mode: WafMode::Block,
+            rules: parse_rules(text, "test.conf", false).expect("rules parse"),
+        }
+    }
+
+    fn request(uri: &str, headers: &[(&str, &str)]) -> RequestHeader {
+        let mut req = RequestHeader::build("GET", uri.as_bytes(), None).expect("request");
+        for (name, value) in headers {
+            req.insert_header(name.to_string(), *value).expect("header");
+        }
+        req
+    }
+
+    #[test]
+    fn matches_crs_style_rules_on_args_and_headers() {
+        let set = rules(
+            r#"
+# Scanner detection
+SecRule REQUEST_HEADERS:User-Agent "@pm sqlmap nikto" \
+    "id:913100,phase:1,block,t:none,t:lowercase,msg:'Found User-Agent associated with security scanner',tag:'attack-reputation-scanner',severity:'CRITICAL'"
+SecRule ARGS|REQUEST_COOKIES "@rx (?i)union\s+select" "id:942100,phase:2,deny,status:406,t:urlDecodeUni,msg:'SQL Injection'"
+SecRule REQUEST_FILENAME "@endsWith .bak" "id:920440,phase:1,pass,msg:'backup file'"
+SecMarker "END-REQUEST"
+"#,
+        );
+        assert_eq!(set.rule_count(), 3);
+
+        let scanner = set
+            .inspect(&request("/", &[("user-agent", "Mozilla sqlmap/1.7")]))
+            .expect("scanner match");
+        assert_eq!(scanner.rule_id, 913100);
+        assert_eq!(scanner.deny_status, Some(403));
+
+        let sqli = set
+            .inspect(&request("/search?q=1%20UNION%20%20select+password", &[]))
+            .expect("sqli match");
+        assert_eq!((sqli.rule_id, sqli.deny_status), (942100, Some(406)));
+
+        let backup = set.inspect(&request("/db.bak", &[])).expect("pass match");
+        assert_eq!(backup.deny_status, None);
+        assert_eq!(set.inspect(&request("/search?q=hello", &[])), None);
+    }
+
+    #[test]
+    fn detect_mode_reports_without_denying() {
+        let mut set = rules(r#"SecRule REQUEST_METHOD "!@within GET HEAD" "id:1,deny""#);
+        set.mode = WafMode::Detect;
+        let mut post = request("/", &[]);
+        post.set_method(http::Method::POST);
+        let verdict = set.inspect(&post).expect("match");
+        assert_eq!((verdict.rule_id, verdict.deny_status), (1, None));
+        assert_eq!(set.inspect(&request("/", &[])), None);
+    }
+
+    #[test]
+    fn rejects_or_skips_unsupported_rules() {
+        let text = r#"
+SecRule ARGS "@rx a" "id:1,deny,chain"
+SecRule REQUEST_BODY "@contains x" "id:2,deny"
+SecAction "id:3,setvar:tx.score=1"
+SecRule ARGS "@contains evil" "id:4,deny"
+"#;
+        let err = parse_rules(text, "crs.conf", false).expect_err("chain is unsupported");
+        assert!(format!("{err:#}").contains("crs.conf:2"), "{err:#}");
+        let kept = parse_rules(text, "crs.conf", true).expect("skip unsupported");
+        assert_eq!(kept.iter().map(|rule| rule.id).collect::<Vec<_>>(), [4]);
+
+        assert!(parse_rules(r#"SecRule ARGS "@rx (?<=a)b" "id:5""#, "x", false).is_err());
+    }
+}
@@ -0,0 +1,169 @@
+use std::{env, ffi::OsString, time::Duration};
+
+use anyhow::Context;
+use async_trait::async_trait;
+use pingora::server::{ShutdownSignal, ShutdownSignalWatch};
+use tokio::sync::{Mutex, mpsc};
+use tracing::Level;
+use windows_service::{
+    define_windows_service,
+    service::{
+        ServiceControl, ServiceControlAccept, ServiceExitCode, ServiceState, ServiceStatus,
+        ServiceType,
+    },
+    service_control_handler::{self, ServiceControlHandlerResult, ServiceStatusHandle},
+    service_dispatcher,
+};
+
+use crate::{cli, event_log::EventLog};
+
+/// Name the service has to be created under, e.g. with
+/// `sc.exe create prx binPath= "C:\prx\prx.exe --service"`.
+pub const SERVICE_NAME: &str = "prx";
+/// prx's own flag for running under the service control manager; it is
+/// taken out before pingora parses the command line.
+pub const FLAG: &str = "--service";
+/// How long the control manager is told starting may take.
+const START_WAIT_HINT: Duration = Duration::from_secs(30);
+
+/// Whether the command line asks to run as a Windows Service.
+pub fn requested(mut args: impl Iterator<Item = String>) -> bool {
+    args.any(|arg| arg == FLAG)
+}
+
+/// Hands the calling thread to the service control manager until the service
+/// has stopped. Fails when prx was not started by the control manager.
+pub fn run() -> anyhow::Result<()> {
+    service_dispatcher::start(SERVICE_NAME, ffi_service_main).with_context(|| {
+        format!(
+            "failed to reach the service control manager; {FLAG} is for the prx Windows Service"
+        )
+    })
+}
+
+define_windows_service!(ffi_service_main, service_main);
+
+fn service_main(_arguments: Vec<OsString>) {
+    let (stop, stopped) = mpsc::unbounded_channel();
+    let handler = move |control| match control {
+        ServiceControl::Stop => {
+            let _ = stop.send(ShutdownSignal::GracefulTerminate);
+            ServiceControlHandlerResult::NoError
+        }
+        // Windows gives services only a few seconds when it shuts down.
+        ServiceControl::Shutdown => {
+            let _ = stop.send(ShutdownSignal::FastShutdown);
+            ServiceControlHandlerResult::NoError
+        }
+        ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+        _ => ServiceControlHandlerResult::NotImplemented,
+    };
+    let status = match service_control_handler::register(SERVICE_NAME, handler) {
+        Ok(status) => status,
+        Err(err) => {
+            report_failure(&anyhow::Error::new(err).context("failed to register the service"));
+            return;
+        }
+    };
+    let exit_code = match serve(status, stopped) {
+        Ok(()) => ServiceExitCode::NO_ERROR,
+        Err(err) => {
+            report_failure(&err);
+            ServiceExitCode::ServiceSpecific(1)
+        }
+    };
+    let _ = status.set_service_status(service_status(
+        ServiceState::Stopped,
+        exit_code,
+        Duration::ZERO,
+    ));
+}
+
+fn serve(
+    status: ServiceStatusHandle,
+    stopped: mpsc::UnboundedReceiver<ShutdownSignal>,
+) -> anyhow::Result<()> {
+    status.set_service_status(service_status(
+        ServiceState::StartPending,
+        ServiceExitCode::NO_ERROR,
+        START_WAIT_HINT,
+    ))?;
+    // The control manager starts services in the system directory, so
+    // relative paths such as the default `Prx.toml` resolve next to prx.exe.
+    let exe = env::current_exe().context("failed to locate the prx binary")?;
+    if let Some(dir) = exe.parent() {
+        env::set_current_dir(dir)
+            .with_context(|| format!("failed to change to {}", dir.display()))?;
+    }
+    let prx = cli::server_builder()?.with_event_log().build()?;
+    let stop_wait_hint = prx.shutdown_time();
+    status.set_service_status(service_status(
+        ServiceState::Running,
+        ServiceExitCode::NO_ERROR,
+        Duration::ZERO,
+    ))?;
+    prx.serve_until(Box::new(ServiceStop {
+        stopped: Mutex::new(stopped),
+        status,
+        wait_hint: stop_wait_hint,
+    }));
+    Ok(())
+}
+
+/// Shuts the server down on the control manager's stop or shutdown request.
+struct ServiceStop {
+    stopped: Mutex<mpsc::UnboundedReceiver<ShutdownSignal>>,
+    status: ServiceStatusHandle,
+    wait_hint: Duration,
+}
+
+#[async_trait]
+impl ShutdownSignalWatch for ServiceStop {
+    async fn recv(&self) -> ShutdownSignal {
+        let signal = self
+            .stopped
+            .lock()
+            .await
+            .recv()
+            .await
+            .unwrap_or(ShutdownSignal::FastShutdown);
+        let wait_hint = match signal {
+            ShutdownSignal::FastShutdown => Duration::ZERO,
+            _ => self.wait_hint,
+        };
+        let _ = self.status.set_service_status(service_status(
+            ServiceState::StopPending,
+            ServiceExitCode::NO_ERROR,
+            wait_hint,
+        ));
+        signal
+    }
+}
+
+fn service_status(
+    state: ServiceState,
+    exit_code: ServiceExitCode,
+    wait_hint: Duration,
+) -> ServiceStatus {
+    let controls_accepted = match state {
+        ServiceState::Running => ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+        _ => ServiceControlAccept::empty(),
+    };
+    ServiceStatus {
+        service_type: ServiceType::OWN_PROCESS,
+        current_state: state,
+        controls_accepted,
+        exit_code,
+        checkpoint: 0,
+        wait_hint,
+        process_id: None,
+    }
+}
+
+/// Errors before the server runs happen ahead of its logging, so they go to
+/// the Event Log directly.
+fn report_failure(err: &anyhow::Error) {
+    if let Ok(log) = EventLog::open() {
+        log.report(Level::ERROR, &format!("prx service failed: {err:#}"));
+    }
+}